@@ -1,3 +1,14 @@
+pub const XSYNTH_STATUS_OK: u32 = 0;
+pub const XSYNTH_STATUS_INVALID_ARGUMENT: u32 = 1;
+pub const XSYNTH_STATUS_IO_ERROR: u32 = 2;
+pub const XSYNTH_STATUS_UNSUPPORTED_FORMAT: u32 = 3;
+pub const XSYNTH_STATUS_PARSE_ERROR: u32 = 4;
+pub const XSYNTH_STATUS_SAMPLE_LOAD_ERROR: u32 = 5;
+pub const XSYNTH_STATUS_INTERNAL_ERROR: u32 = 6;
+pub const XSYNTH_STATUS_CANCELLED: u32 = 7;
+pub const XSYNTH_STATUS_INVALID_REGION: u32 = 8;
+pub const XSYNTH_STATUS_DEVICE_ERROR: u32 = 9;
+
 pub const XSYNTH_AUDIO_EVENT_NOTEON: u16 = 0;
 pub const XSYNTH_AUDIO_EVENT_NOTEOFF: u16 = 1;
 pub const XSYNTH_AUDIO_EVENT_ALLNOTESOFF: u16 = 2;
@@ -9,15 +20,44 @@ pub const XSYNTH_AUDIO_EVENT_PITCH: u16 = 7;
 pub const XSYNTH_AUDIO_EVENT_FINETUNE: u16 = 8;
 pub const XSYNTH_AUDIO_EVENT_COARSETUNE: u16 = 9;
 pub const XSYNTH_AUDIO_EVENT_SYSTEMRESET: u16 = 10;
+pub const XSYNTH_AUDIO_EVENT_ALLSOUNDOFF: u16 = 11;
 
 pub const XSYNTH_CONFIG_SETLAYERS: u16 = 0;
 pub const XSYNTH_CONFIG_SETPERCUSSIONMODE: u16 = 1;
+pub const XSYNTH_CONFIG_SETPANLAW: u16 = 2;
+pub const XSYNTH_CONFIG_SETTRANSPOSE: u16 = 3;
+pub const XSYNTH_CONFIG_SETKEYRANGE: u16 = 4;
+pub const XSYNTH_CONFIG_SETVELOCITYRANGE: u16 = 5;
+pub const XSYNTH_CONFIG_SETIGNORENOTEOFF: u16 = 6;
+
+pub const XSYNTH_PAN_LAW_LINEAR: u32 = 0;
+pub const XSYNTH_PAN_LAW_EQUAL_POWER: u32 = 1;
+pub const XSYNTH_PAN_LAW_COMPROMISE: u32 = 2;
 
 pub const XSYNTH_AUDIO_CHANNELS_MONO: u16 = 1;
 pub const XSYNTH_AUDIO_CHANNELS_STEREO: u16 = 2;
 
+pub const XSYNTH_SAMPLE_FORMAT_F32: u16 = 0;
+pub const XSYNTH_SAMPLE_FORMAT_S16: u16 = 1;
+
 pub const XSYNTH_INTERPOLATION_NEAREST: u16 = 0;
 pub const XSYNTH_INTERPOLATION_LINEAR: u16 = 1;
 
+pub const XSYNTH_RESAMPLE_QUALITY_NEAREST: u16 = 0;
+pub const XSYNTH_RESAMPLE_QUALITY_LINEAR: u16 = 1;
+pub const XSYNTH_RESAMPLE_QUALITY_SINC: u16 = 2;
+
 pub const XSYNTH_ENVELOPE_CURVE_LINEAR: u8 = 0;
 pub const XSYNTH_ENVELOPE_CURVE_EXPONENTIAL: u8 = 1;
+pub const XSYNTH_ENVELOPE_CURVE_ANALOG: u8 = 2;
+
+pub const XSYNTH_LOAD_STATE_IN_PROGRESS: u32 = 0;
+pub const XSYNTH_LOAD_STATE_DONE: u32 = 1;
+pub const XSYNTH_LOAD_STATE_ERROR: u32 = 2;
+
+pub const XSYNTH_KEY_MAP_XG_DRUMS: u32 = 0;
+pub const XSYNTH_KEY_MAP_GM2_TO_GM_DRUMS: u32 = 1;
+
+pub const XSYNTH_MPE_ZONE_NONE: u8 = 0;
+pub const XSYNTH_MPE_ZONE_LOWER: u8 = 1;
+pub const XSYNTH_MPE_ZONE_UPPER: u8 = 2;
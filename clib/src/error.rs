@@ -0,0 +1,95 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    ffi::{c_char, CString},
+    panic::{catch_unwind, UnwindSafe},
+};
+
+use crate::consts::*;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Stores the message of an error, to be retrieved with XSynth_GetLastError.
+pub(crate) fn set_last_error(message: impl Into<String>) {
+    let message = message.into().replace('\0', " ");
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Formats an error along with the errors that caused it.
+pub(crate) fn error_chain_message(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(err) = source {
+        let err_message = err.to_string();
+        if !message.contains(&err_message) {
+            message.push_str(": ");
+            message.push_str(&err_message);
+        }
+        source = err.source();
+    }
+    message
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs a fallible function so that neither its errors nor its panics cross
+/// the FFI boundary. Errors are stored as the last error, and their status
+/// code is returned.
+pub(crate) fn ffi_guard(f: impl FnOnce() -> Result<(), (u32, String)> + UnwindSafe) -> u32 {
+    let result = match catch_unwind(f) {
+        Ok(result) => result,
+        Err(payload) => Err((
+            XSYNTH_STATUS_INTERNAL_ERROR,
+            format!("Internal error: {}", panic_message(payload.as_ref())),
+        )),
+    };
+
+    match result {
+        Ok(()) => XSYNTH_STATUS_OK,
+        Err((status, message)) => {
+            set_last_error(message);
+            status
+        }
+    }
+}
+
+/// Retrieves the message of the last error that occurred in the calling
+/// thread. The message is kept until another error occurs.
+///
+/// --Parameters--
+/// - buf: The buffer where the null terminated message will be written.
+///         If the buffer is too small, the message will be truncated.
+///         Can be null to only query the length of the message.
+/// - len: The size of the buffer in bytes
+///
+/// --Returns--
+/// The length of the full message in bytes, excluding the null terminator.
+/// If no error has occurred, 0 is returned and an empty string is written.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_GetLastError(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|e| {
+        let error = e.borrow();
+        let message = error.as_ref().map(|m| m.as_bytes()).unwrap_or_default();
+
+        if !buf.is_null() && len > 0 {
+            let copied = message.len().min(len - 1);
+            unsafe {
+                std::ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buf, copied);
+                *buf.add(copied) = 0;
+            }
+        }
+
+        message.len()
+    })
+}
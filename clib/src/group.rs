@@ -1,6 +1,13 @@
-use crate::{handles::*, utils::*, XSynth_GenDefault_StreamParams, XSynth_StreamParams};
+use crate::{
+    consts::*, error::*, handles::*, utils::*, XSynth_GenDefault_MpeConfig,
+    XSynth_GenDefault_StreamParams, XSynth_MpeConfig, XSynth_StreamParams,
+};
+use std::{
+    ffi::{c_char, CStr},
+    path::PathBuf,
+};
 use xsynth_core::{
-    channel::{ChannelConfigEvent, ChannelEvent, ChannelInitOptions},
+    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ChannelInitOptions},
     channel_group::{ChannelGroup, ChannelGroupConfig, SynthEvent},
     AudioPipe,
 };
@@ -36,14 +43,30 @@ pub extern "C" fn XSynth_GenDefault_ParallelismOptions() -> XSynth_ParallelismOp
 /// - fade_out_killing: If set to true, the voices killed due to the voice limit
 ///         will fade out. If set to false, they will be killed immediately,
 ///         usually causing clicking but improving performance.
+/// - kill_fade_time: The length of the fade out in seconds of the voices
+///         killed due to the voice limit, if fade_out_killing is enabled.
 /// - parallelism: Options about the instance's parallelism
 ///         (see XSynth_ParallelismOptions)
+/// - dc_blocker: If set to true, a DC blocking high-pass filter will be applied
+///         to the output audio, removing the DC offset that some samples carry.
+/// - multi_port_percussion: If set to true and the number of channels is a
+///         multiple of 16, channel 10 of every group of 16 channels will be
+///         configured for percussion, as with multiple MIDI ports.
+/// - mpe: Options for using the first 16 channels as an MPE zone
+///         (see XSynth_MpeConfig)
+/// - master_tuning_hz: The frequency in Hz of A4 that all the channels are
+///         tuned to.
 #[repr(C)]
 pub struct XSynth_GroupOptions {
     pub stream_params: XSynth_StreamParams,
     pub channels: u32,
     pub fade_out_killing: bool,
+    pub kill_fade_time: f32,
     pub parallelism: XSynth_ParallelismOptions,
+    pub dc_blocker: bool,
+    pub multi_port_percussion: bool,
+    pub mpe: XSynth_MpeConfig,
+    pub master_tuning_hz: f32,
 }
 
 /// Generates the default values for the XSynth_GroupOptions struct
@@ -51,14 +74,24 @@ pub struct XSynth_GroupOptions {
 /// - stream_params: Defaults for the XSynth_StreamParams struct
 /// - channels: 16
 /// - fade_out_killing: True
+/// - kill_fade_time: 0.001 (1ms)
 /// - parallelism: Defaults for the XSynth_ParallelismOptions struct
+/// - dc_blocker: True
+/// - multi_port_percussion: True
+/// - mpe: Defaults for the XSynth_MpeConfig struct
+/// - master_tuning_hz: 440.0Hz
 #[no_mangle]
 pub extern "C" fn XSynth_GenDefault_GroupOptions() -> XSynth_GroupOptions {
     XSynth_GroupOptions {
         stream_params: XSynth_GenDefault_StreamParams(),
         channels: 16,
         fade_out_killing: true,
+        kill_fade_time: 0.001,
         parallelism: XSynth_GenDefault_ParallelismOptions(),
+        dc_blocker: true,
+        multi_port_percussion: true,
+        mpe: XSynth_GenDefault_MpeConfig(),
+        master_tuning_hz: 440.0,
     }
 }
 
@@ -78,6 +111,8 @@ pub extern "C" fn XSynth_GenDefault_GroupOptions() -> XSynth_GroupOptions {
 pub extern "C" fn XSynth_ChannelGroup_Create(options: XSynth_GroupOptions) -> XSynth_ChannelGroup {
     let channel_init_options = ChannelInitOptions {
         fade_out_killing: options.fade_out_killing,
+        kill_fade_time: options.kill_fade_time,
+        ..Default::default()
     };
 
     let config = ChannelGroupConfig {
@@ -85,6 +120,10 @@ pub extern "C" fn XSynth_ChannelGroup_Create(options: XSynth_GroupOptions) -> XS
         format: convert_synth_format(options.channels),
         audio_params: convert_streamparams_to_rust(options.stream_params),
         parallelism: convert_parallelism_to_rust(options.parallelism),
+        dc_blocker: options.dc_blocker,
+        multi_port_percussion: options.multi_port_percussion,
+        mpe: convert_mpe_config(options.mpe),
+        master_tuning_hz: options.master_tuning_hz,
     };
 
     let new = ChannelGroup::new(config);
@@ -103,10 +142,13 @@ pub extern "C" fn XSynth_ChannelGroup_Create(options: XSynth_GroupOptions) -> XS
 /// --Events--
 /// - XSYNTH_AUDIO_EVENT_NOTEON: A MIDI note on event,
 ///         params: LOBYTE = key number (0-127), HIBYTE = velocity (0-127)
-/// - XSYNTH_AUDIO_EVENT_NOTEOFF: A MIDI note on event
-///         params: Key number (0-127)
+/// - XSYNTH_AUDIO_EVENT_NOTEOFF: A MIDI note off event
+///         params: LOBYTE = key number (0-127), HIBYTE = note off velocity
+///         (1-127, 0 uses the default of 64)
 /// - XSYNTH_AUDIO_EVENT_ALLNOTESOFF: Release all notes (No parameters)
 /// - XSYNTH_AUDIO_EVENT_ALLNOTESKILLED: Kill all notes (No parameters)
+/// - XSYNTH_AUDIO_EVENT_ALLSOUNDOFF: All Sound Off, the same as sending CC120
+///         (No parameters)
 /// - XSYNTH_AUDIO_EVENT_RESETCONTROL: Reset all control change data (No parameters)
 /// - XSYNTH_AUDIO_EVENT_CONTROL: A MIDI control change event
 ///         params: LOBYTE = controller number, HIBYTE = controller value
@@ -161,9 +203,31 @@ pub extern "C" fn XSynth_ChannelGroup_SendAudioEventAll(
 /// - XSYNTH_CONFIG_SETLAYERS: Sets the layer count for the channel.
 ///         params: The layer limit (0 = no limit, 1-.. = limit)
 /// - XSYNTH_CONFIG_SETPERCUSSIONMODE: Controls whether the channel will be
-///         standard or percussion.
+///         standard or percussion. Also sets XSYNTH_CONFIG_SETIGNORENOTEOFF
+///         to the same value.
 ///         params: 1 = set the channel to only use percussion patches,
 ///                 0 = set the channel to use standard patches
+/// - XSYNTH_CONFIG_SETPANLAW: Sets the pan law used for the channel pan.
+///         params: XSYNTH_PAN_LAW_LINEAR (-6dB at center),
+///                 XSYNTH_PAN_LAW_EQUAL_POWER (-3dB at center, default),
+///                 XSYNTH_PAN_LAW_COMPROMISE (-4.5dB at center)
+/// - XSYNTH_CONFIG_SETTRANSPOSE: Transposes the notes of the channel. Notes
+///         transposed out of the MIDI key range are dropped. The percussion
+///         bank is not transposed.
+///         params: The transpose in semitones, as a signed 32bit integer
+///                 (-127 to 127, 0 = no transpose)
+/// - XSYNTH_CONFIG_SETKEYRANGE: Restricts the notes of the channel to a range
+///         of keys, before the transpose. Other notes are dropped.
+///         params: LOBYTE = first key, HIBYTE = last key (0-127, inclusive)
+/// - XSYNTH_CONFIG_SETVELOCITYRANGE: Restricts the notes of the channel to a
+///         range of velocities. Other notes are dropped.
+///         params: LOBYTE = lowest velocity, HIBYTE = highest velocity
+///                 (0-127, inclusive)
+/// - XSYNTH_CONFIG_SETIGNORENOTEOFF: Controls whether the channel ignores the
+///         note offs and the damper pedal, like GM percussion channels.
+///         The note offs still release the voices with a looping sample.
+///         params: 1 = ignore the note offs and the damper pedal,
+///                 0 = apply them
 #[no_mangle]
 pub extern "C" fn XSynth_ChannelGroup_SendConfigEvent(
     handle: XSynth_ChannelGroup,
@@ -201,23 +265,168 @@ pub extern "C" fn XSynth_ChannelGroup_SendConfigEventAll(
 /// - handle: The handle of the channel group instance
 /// - sf_ids: Pointer to an array of soundfont handles
 /// - count: The length of the above array
+///
+/// --Returns--
+/// The amount of soundfonts that will be used. Soundfonts loaded with stream
+/// parameters different from the ones of the channel group (see
+/// XSynth_ChannelGroup_GetStreamParams) are not used.
 #[no_mangle]
 pub unsafe extern "C" fn XSynth_ChannelGroup_SetSoundfonts(
     handle: XSynth_ChannelGroup,
     sf_ids: *const XSynth_Soundfont,
     count: u64,
-) {
+) -> u64 {
     unsafe {
         let ids = std::slice::from_raw_parts(sf_ids, count as usize);
         let sfvec = sfids_to_vec(ids);
+        let applied = count_matching_soundfonts(&sfvec, handle.as_ref().stream_params());
         handle
             .as_mut()
             .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
                 ChannelConfigEvent::SetSoundfonts(sfvec),
             )));
+        applied
     }
 }
 
+/// Inserts a soundfont at the given position of the soundfont list of
+/// the desired channel group. Unlike XSynth_ChannelGroup_SetSoundfonts, the notes
+/// that are already playing are not affected and only the presets of the
+/// new soundfont are resolved again.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - sf_id: The handle of the soundfont to be added
+/// - position: The position in the list. Soundfonts earlier in the list
+///         have priority. If larger than the list length, the soundfont
+///         is added at the end.
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_AddSoundfont(
+    handle: XSynth_ChannelGroup,
+    sf_id: XSynth_Soundfont,
+    position: u64,
+) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::AddSoundfont {
+                sf: sf_id.clone(),
+                position: position as usize,
+            },
+        )));
+}
+
+/// Removes the soundfont at the given position of the soundfont list of
+/// the desired channel group. Does nothing if there is no soundfont at that
+/// position.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - position: The position of the soundfont in the list
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_RemoveSoundfontAt(
+    handle: XSynth_ChannelGroup,
+    position: u64,
+) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::RemoveSoundfont {
+                position: position as usize,
+            },
+        )));
+}
+
+/// Retunes all the keys of every channel of the desired channel group.
+/// The tuning also applies to the notes that are already playing.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - tuning: Pointer to an array of 128 floats, each being the offset of
+///         the respective key in cents from 12-TET
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_SetKeyTuning(
+    handle: XSynth_ChannelGroup,
+    tuning: *const f32,
+) {
+    unsafe {
+        let tuning = std::slice::from_raw_parts(tuning, 128);
+        let tuning = tuning
+            .iter()
+            .enumerate()
+            .map(|(key, &cents)| (key as u8, cents))
+            .collect();
+        handle
+            .as_mut()
+            .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetKeyTuning(tuning),
+            )));
+    }
+}
+
+/// Remaps the keys of the note events of a specific channel of the desired
+/// channel group, e.g. to play MIDIs made for drum kits with a nonstandard
+/// layout on a GM soundfont. Note offs always release the key their note on
+/// was mapped to, even if the map changed in between.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - channel: The number of the MIDI channel to set the map for
+///         (MIDI channel 1 is 0)
+/// - key_map: Pointer to an array of 128 bytes, each being the key played
+///         for the respective incoming key, or null to remove the map.
+///         See XSynth_GetKeyMapPreset for the built-in maps.
+/// - percussion_only: If true, the map is only applied while the channel
+///         uses the percussion bank
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_SetKeyMap(
+    handle: XSynth_ChannelGroup,
+    channel: u32,
+    key_map: *const u8,
+    percussion_only: bool,
+) {
+    let map = unsafe { convert_key_map(key_map) };
+    handle.as_mut().send_event(SynthEvent::Channel(
+        channel,
+        ChannelEvent::Config(ChannelConfigEvent::SetKeyMap {
+            map,
+            percussion_only,
+        }),
+    ));
+}
+
+/// Sets the master volume of the desired channel group. Changes are
+/// smoothed to avoid zipper noise.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - volume: The gain applied to the output audio, where 1.0 is the
+///         original level. Negative values are treated as 0.0.
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_SetMasterVolume(handle: XSynth_ChannelGroup, volume: f32) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterGain(volume),
+        )));
+}
+
+/// Sets the frequency of A4 that all the channels of the desired channel
+/// group are tuned to. It also applies to the active voices.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - hz: The frequency of A4 in Hz, where 440.0 is the standard tuning.
+///         Values that aren't positive are ignored.
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_SetMasterTuning(handle: XSynth_ChannelGroup, hz: f32) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterTuning(hz),
+        )));
+}
+
 /// Removes all the soundfonts used in the desired channel group.
 ///
 /// --Parameters--
@@ -231,6 +440,51 @@ pub extern "C" fn XSynth_ChannelGroup_ClearSoundfonts(handle: XSynth_ChannelGrou
         )));
 }
 
+/// Releases all active notes of the desired channel group, so that they
+/// fade out with their release envelope.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_ReleaseAll(handle: XSynth_ChannelGroup) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+            ChannelAudioEvent::AllNotesOff,
+        )));
+}
+
+/// Kills all active notes of the desired channel group. They are silenced
+/// within the kill fade time of the channels, without their release
+/// envelope. The control change data is kept.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_KillAll(handle: XSynth_ChannelGroup) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+            ChannelAudioEvent::AllNotesKilled,
+        )));
+}
+
+/// Resets the desired channel group. Kills all active notes and resets
+/// all control change.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_Reset(handle: XSynth_ChannelGroup) {
+    let group = handle.as_mut();
+    group.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+        ChannelAudioEvent::AllNotesKilled,
+    )));
+    group.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+        ChannelAudioEvent::ResetControl,
+    )));
+}
+
 /// Reads audio samples from the desired channel group. The amount of samples
 /// determines the time of the current active MIDI events. For example if we
 /// send a note on event and read 44100 samples (with a 44.1kHz sample rate),
@@ -260,6 +514,130 @@ pub unsafe extern "C" fn XSynth_ChannelGroup_ReadSamples(
     }
 }
 
+/// Reads audio samples from the desired channel group as 16bit signed
+/// integers. Works the same way as XSynth_ChannelGroup_ReadSamples, but
+/// the samples are clamped to the -1.0 to 1.0 range and converted internally.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - buffer: Pointer to a mutable buffer to receive the audio samples. Each
+///         item of the buffer should correspond to an audio sample of type
+///         16bit signed integer.
+/// - length: Number of samples to read in the buffer
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_ReadSamplesS16(
+    handle: XSynth_ChannelGroup,
+    buffer: *mut i16,
+    length: u64,
+) {
+    unsafe {
+        if buffer.is_null() {
+            return;
+        }
+
+        let slc = std::slice::from_raw_parts_mut(buffer, length as usize);
+        let mut samples = vec![0.0; slc.len()];
+        handle.as_mut().read_samples(&mut samples);
+        for (out, sample) in slc.iter_mut().zip(samples) {
+            *out = convert_sample_to_s16(sample);
+        }
+    }
+}
+
+/// Renders audio from the desired channel group for the given amount of
+/// time and writes it to a WAV file. The events sent to the channel group
+/// before calling this function are rendered, in the same way as reading
+/// the samples with XSynth_ChannelGroup_ReadSamples.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - seconds: The length of the audio to be rendered in seconds
+/// - path: The path of the WAV file to be written. If the file exists,
+///         it will be overwritten.
+/// - format: The sample format of the WAV file
+///         Supported: XSYNTH_SAMPLE_FORMAT_F32 (32bit float),
+///                    XSYNTH_SAMPLE_FORMAT_S16 (16bit signed integer)
+///
+/// --Returns--
+/// One of the following status codes. On failure, a message describing
+/// the error can be retrieved with XSynth_GetLastError.
+/// - XSYNTH_STATUS_OK: The audio was written successfully
+/// - XSYNTH_STATUS_INVALID_ARGUMENT: The length, path or format is invalid
+/// - XSYNTH_STATUS_IO_ERROR: The file could not be written
+/// - XSYNTH_STATUS_INTERNAL_ERROR: An unexpected internal error occurred
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_RenderToFile(
+    handle: XSynth_ChannelGroup,
+    seconds: f64,
+    path: *const c_char,
+    format: u16,
+) -> u32 {
+    ffi_guard(move || {
+        if !seconds.is_finite() || seconds < 0.0 {
+            return Err((
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "The length is not a positive number".into(),
+            ));
+        }
+        if path.is_null() {
+            return Err((XSYNTH_STATUS_INVALID_ARGUMENT, "The path is null".into()));
+        }
+        let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+            Ok(path) => PathBuf::from(path),
+            Err(..) => {
+                return Err((
+                    XSYNTH_STATUS_INVALID_ARGUMENT,
+                    "The path is not valid UTF-8".into(),
+                ))
+            }
+        };
+        let (bits_per_sample, sample_format) = match format {
+            XSYNTH_SAMPLE_FORMAT_F32 => (32, hound::SampleFormat::Float),
+            XSYNTH_SAMPLE_FORMAT_S16 => (16, hound::SampleFormat::Int),
+            _ => {
+                return Err((
+                    XSYNTH_STATUS_INVALID_ARGUMENT,
+                    "Invalid sample format".into(),
+                ))
+            }
+        };
+
+        let group = handle.as_mut();
+        let stream_params = *group.stream_params();
+        let spec = hound::WavSpec {
+            channels: stream_params.channels.count(),
+            sample_rate: stream_params.sample_rate,
+            bits_per_sample,
+            sample_format,
+        };
+        let io_error = |e: hound::Error| (XSYNTH_STATUS_IO_ERROR, error_chain_message(&e));
+
+        let mut writer = hound::WavWriter::create(&path, spec).map_err(io_error)?;
+
+        let channels = stream_params.channels.count() as usize;
+        let mut frames_left = (seconds * stream_params.sample_rate as f64).round() as usize;
+        let chunk_frames = (stream_params.sample_rate as usize / 10).max(1);
+        let mut buffer = vec![0.0; chunk_frames * channels];
+
+        while frames_left > 0 {
+            let frames = frames_left.min(chunk_frames);
+            let chunk = &mut buffer[..frames * channels];
+            group.read_samples(chunk);
+
+            for &sample in chunk.iter() {
+                match format {
+                    XSYNTH_SAMPLE_FORMAT_S16 => writer.write_sample(convert_sample_to_s16(sample)),
+                    _ => writer.write_sample(sample),
+                }
+                .map_err(io_error)?;
+            }
+            frames_left -= frames;
+        }
+
+        writer.finalize().map_err(io_error)
+    })
+}
+
 /// Returns the active voice count of the desired channel group.
 ///
 /// --Parameters--
@@ -272,6 +650,88 @@ pub extern "C" fn XSynth_ChannelGroup_VoiceCount(handle: XSynth_ChannelGroup) ->
     handle.as_ref().voice_count()
 }
 
+/// Returns the active voice count of a MIDI channel of the desired
+/// channel group.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - channel: The number of the MIDI channel, starting from 0
+///
+/// --Returns--
+/// The active voice count of the channel as a 64bit unsigned integer,
+/// or 0 if the channel doesn't exist
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_ChannelVoiceCount(
+    handle: XSynth_ChannelGroup,
+    channel: u32,
+) -> u64 {
+    handle.as_ref().channel_voice_count(channel as usize)
+}
+
+/// Returns the keys of a MIDI channel of the desired channel group which
+/// have voices. The masks are updated when the channel group renders.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - channel: The number of the MIDI channel, starting from 0
+/// - sounding: Pointer to a mutable array of 16 bytes to receive the mask of
+///         the keys with any voices, including the released ones. Bit
+///         (key % 8) of byte (key / 8) belongs to each key. Can be null.
+/// - unreleased: Pointer to a mutable array of 16 bytes to receive the mask
+///         of the keys with voices which weren't released yet, in the same
+///         layout. Can be null.
+///
+/// If the channel doesn't exist, the masks are cleared.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_GetKeyOccupancy(
+    handle: XSynth_ChannelGroup,
+    channel: u32,
+    sounding: *mut u8,
+    unreleased: *mut u8,
+) {
+    let occupancy = handle.as_ref().key_occupancy(channel as usize);
+    unsafe {
+        write_key_mask(&occupancy.sounding, sounding);
+        write_key_mask(&occupancy.unreleased, unreleased);
+    }
+}
+
+/// Returns the active voice count of each soundfont of the desired channel
+/// group, summed across all the MIDI channels.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+/// - counts: Pointer to a mutable array to receive the voice counts, in the
+///         order of the soundfont list. Each item of the array should be
+///         a 64bit unsigned integer.
+/// - length: The length of the array
+///
+/// --Returns--
+/// The number of soundfonts. If it is larger than the length of the array,
+/// only the counts that fit in the array are written.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_ChannelGroup_VoicesPerSoundfont(
+    handle: XSynth_ChannelGroup,
+    counts: *mut u64,
+    length: u64,
+) -> u64 {
+    let voices = handle.as_ref().voices_per_soundfont();
+    unsafe { write_counts(&voices, counts, length) };
+    voices.len() as u64
+}
+
+/// Returns the number of MIDI channels of the desired channel group.
+///
+/// --Parameters--
+/// - handle: The handle of the channel group instance
+///
+/// --Returns--
+/// The channel count as a 32bit unsigned integer
+#[no_mangle]
+pub extern "C" fn XSynth_ChannelGroup_GetChannelCount(handle: XSynth_ChannelGroup) -> u32 {
+    handle.as_ref().channel_count()
+}
+
 /// Returns the audio stream parameters of the desired channel group as an
 /// XSynth_StreamParams struct. This may be useful when loading a new soundfont
 /// which is meant to be used in that channel group.
@@ -296,3 +756,295 @@ pub extern "C" fn XSynth_ChannelGroup_GetStreamParams(
 pub extern "C" fn XSynth_ChannelGroup_Drop(handle: XSynth_ChannelGroup) {
     handle.drop();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundfont::{
+        XSynth_GenDefault_SoundfontOptions, XSynth_Soundfont_GetStreamParams,
+        XSynth_Soundfont_LoadNew, XSynth_Soundfont_Remove,
+    };
+    use std::ffi::CString;
+    use xsynth_core::soundfont::test_utils::TestSoundfontDir;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn test_dir(name: &str) -> TestSoundfontDir {
+        TestSoundfontDir::new(&format!("clib_{name}"))
+    }
+
+    /// Loads a soundfont with a single constant sample.
+    fn load_soundfont(dir: &TestSoundfontDir, sample_rate: u32) -> XSynth_Soundfont {
+        load_soundfont_with_opcodes(dir, sample_rate, "")
+    }
+
+    /// Loads a soundfont with a single constant sample and the given
+    /// region opcodes.
+    fn load_soundfont_with_opcodes(
+        dir: &TestSoundfontDir,
+        sample_rate: u32,
+        opcodes: &str,
+    ) -> XSynth_Soundfont {
+        dir.write_wav("sample.wav", SAMPLE_RATE, &[0.5; SAMPLE_RATE as usize]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            &format!("<region> sample=sample.wav pitch_keycenter=60 {opcodes}\n"),
+        );
+
+        let path = CString::new(sfz.to_str().unwrap()).unwrap();
+        let mut options = XSynth_GenDefault_SoundfontOptions();
+        options.stream_params.sample_rate = sample_rate;
+        let mut soundfont = XSynth_Soundfont {
+            soundfont: std::ptr::null_mut(),
+        };
+        let status = unsafe { XSynth_Soundfont_LoadNew(path.as_ptr(), options, &mut soundfont) };
+        assert_eq!(status, XSYNTH_STATUS_OK);
+        soundfont
+    }
+
+    fn new_group() -> XSynth_ChannelGroup {
+        let mut options = XSynth_GenDefault_GroupOptions();
+        options.stream_params.sample_rate = SAMPLE_RATE;
+        options.parallelism.channel = -1;
+        options.parallelism.key = -1;
+        XSynth_ChannelGroup_Create(options)
+    }
+
+    /// Creates a channel group playing a note using a soundfont with
+    /// a single constant sample.
+    fn playing_group(dir: &TestSoundfontDir) -> XSynth_ChannelGroup {
+        let soundfont = load_soundfont(dir, SAMPLE_RATE);
+        let group = new_group();
+        XSynth_ChannelGroup_AddSoundfont(group, soundfont, 0);
+        XSynth_Soundfont_Remove(soundfont);
+        XSynth_ChannelGroup_SendAudioEvent(group, 0, XSYNTH_AUDIO_EVENT_NOTEON, 60 | 127 << 8);
+        group
+    }
+
+    #[test]
+    fn test_read_samples_s16() {
+        let dir = test_dir("read_samples_s16");
+        let float_group = playing_group(&dir);
+        let int_group = playing_group(&dir);
+
+        let mut float_samples = vec![0.0f32; 4800];
+        let mut int_samples = vec![0i16; 4800];
+        unsafe {
+            XSynth_ChannelGroup_ReadSamples(float_group, float_samples.as_mut_ptr(), 4800);
+            XSynth_ChannelGroup_ReadSamplesS16(int_group, int_samples.as_mut_ptr(), 4800);
+        }
+
+        assert!(int_samples.iter().any(|&s| s != 0));
+        for (float, int) in float_samples.into_iter().zip(int_samples) {
+            assert_eq!(convert_sample_to_s16(float), int);
+        }
+        assert_eq!(convert_sample_to_s16(2.0), i16::MAX);
+        assert_eq!(convert_sample_to_s16(-2.0), -i16::MAX);
+
+        XSynth_ChannelGroup_Drop(float_group);
+        XSynth_ChannelGroup_Drop(int_group);
+    }
+
+    #[test]
+    fn test_channel_voice_count() {
+        let dir = test_dir("channel_voice_count");
+        let group = playing_group(&dir);
+        let mut samples = vec![0.0f32; 480];
+        unsafe {
+            XSynth_ChannelGroup_ReadSamples(group, samples.as_mut_ptr(), 480);
+        }
+
+        assert_eq!(XSynth_ChannelGroup_ChannelVoiceCount(group, 0), 1);
+        assert_eq!(XSynth_ChannelGroup_ChannelVoiceCount(group, 1), 0);
+        assert_eq!(XSynth_ChannelGroup_ChannelVoiceCount(group, u32::MAX), 0);
+
+        XSynth_ChannelGroup_Drop(group);
+    }
+
+    #[test]
+    fn test_key_occupancy() {
+        let dir = test_dir("key_occupancy");
+        let group = playing_group(&dir);
+        let mut samples = vec![0.0f32; 480];
+        let mut sounding = [0xFFu8; 16];
+        let mut unreleased = [0xFFu8; 16];
+        let mut expected = [0u8; 16];
+        expected[60 / 8] = 1 << (60 % 8);
+        unsafe {
+            XSynth_ChannelGroup_ReadSamples(group, samples.as_mut_ptr(), 480);
+            XSynth_ChannelGroup_GetKeyOccupancy(
+                group,
+                0,
+                sounding.as_mut_ptr(),
+                unreleased.as_mut_ptr(),
+            );
+        }
+        assert_eq!(sounding, expected);
+        assert_eq!(unreleased, expected);
+
+        unsafe {
+            XSynth_ChannelGroup_GetKeyOccupancy(
+                group,
+                u32::MAX,
+                sounding.as_mut_ptr(),
+                std::ptr::null_mut(),
+            );
+        }
+        assert_eq!(sounding, [0; 16]);
+
+        XSynth_ChannelGroup_Drop(group);
+    }
+
+    #[test]
+    fn test_voices_per_soundfont() {
+        let dir = test_dir("voices_per_soundfont");
+        let group = playing_group(&dir);
+        let mut samples = vec![0.0f32; 480];
+        let mut counts = [u64::MAX; 2];
+        unsafe {
+            XSynth_ChannelGroup_ReadSamples(group, samples.as_mut_ptr(), 480);
+            assert_eq!(
+                XSynth_ChannelGroup_VoicesPerSoundfont(group, counts.as_mut_ptr(), 2),
+                1
+            );
+            assert_eq!(
+                XSynth_ChannelGroup_VoicesPerSoundfont(group, std::ptr::null_mut(), 0),
+                1
+            );
+        }
+        assert_eq!(counts, [1, u64::MAX]);
+
+        XSynth_ChannelGroup_Drop(group);
+    }
+
+    #[test]
+    fn test_render_to_file() {
+        let dir = test_dir("render_to_file");
+
+        for (format, bits_per_sample, sample_format) in [
+            (XSYNTH_SAMPLE_FORMAT_F32, 32, hound::SampleFormat::Float),
+            (XSYNTH_SAMPLE_FORMAT_S16, 16, hound::SampleFormat::Int),
+        ] {
+            let group = playing_group(&dir);
+            let out = dir.path().join(format!("out_{format}.wav"));
+            let path = CString::new(out.to_str().unwrap()).unwrap();
+            let status =
+                unsafe { XSynth_ChannelGroup_RenderToFile(group, 0.25, path.as_ptr(), format) };
+            assert_eq!(status, XSYNTH_STATUS_OK);
+            XSynth_ChannelGroup_Drop(group);
+
+            let reader = hound::WavReader::open(&out).unwrap();
+            let spec = reader.spec();
+            assert_eq!(spec.channels, 2);
+            assert_eq!(spec.sample_rate, SAMPLE_RATE);
+            assert_eq!(spec.bits_per_sample, bits_per_sample);
+            assert_eq!(spec.sample_format, sample_format);
+            assert_eq!(reader.len(), SAMPLE_RATE / 4 * 2);
+        }
+
+        let group = playing_group(&dir);
+        let path =
+            CString::new(dir.path().join("missing").join("out.wav").to_str().unwrap()).unwrap();
+        let status = unsafe {
+            XSynth_ChannelGroup_RenderToFile(group, 0.25, path.as_ptr(), XSYNTH_SAMPLE_FORMAT_F32)
+        };
+        assert_eq!(status, XSYNTH_STATUS_IO_ERROR);
+        let status = unsafe { XSynth_ChannelGroup_RenderToFile(group, 0.25, path.as_ptr(), 2) };
+        assert_eq!(status, XSYNTH_STATUS_INVALID_ARGUMENT);
+        XSynth_ChannelGroup_Drop(group);
+    }
+
+    #[test]
+    fn test_set_soundfonts_mismatch() {
+        let dir = test_dir("set_soundfonts_mismatch");
+        let matched = load_soundfont(&dir, SAMPLE_RATE);
+        let mismatched = load_soundfont(&dir, 44100);
+        assert_eq!(
+            XSynth_Soundfont_GetStreamParams(mismatched).sample_rate,
+            44100
+        );
+
+        let group = new_group();
+        let render = |group| {
+            XSynth_ChannelGroup_SendAudioEvent(group, 0, XSYNTH_AUDIO_EVENT_NOTEON, 60 | 127 << 8);
+            let mut samples = vec![0.0f32; 960];
+            unsafe { XSynth_ChannelGroup_ReadSamples(group, samples.as_mut_ptr(), 960) };
+            XSynth_ChannelGroup_SendAudioEvent(group, 0, XSYNTH_AUDIO_EVENT_ALLNOTESKILLED, 0);
+            samples.iter().any(|&s| s != 0.0)
+        };
+
+        let applied = unsafe { XSynth_ChannelGroup_SetSoundfonts(group, &mismatched, 1) };
+        assert_eq!(applied, 0);
+        assert!(!render(group));
+        assert_eq!(group.as_ref().rejected_soundfonts(), 16);
+
+        let soundfonts = [mismatched, matched];
+        let applied = unsafe { XSynth_ChannelGroup_SetSoundfonts(group, soundfonts.as_ptr(), 2) };
+        assert_eq!(applied, 1);
+        assert!(render(group));
+
+        XSynth_ChannelGroup_Drop(group);
+        XSynth_Soundfont_Remove(matched);
+        XSynth_Soundfont_Remove(mismatched);
+    }
+
+    #[test]
+    fn test_get_channel_count() {
+        for channels in [16, 32, 17] {
+            let mut options = XSynth_GenDefault_GroupOptions();
+            options.channels = channels;
+            options.parallelism.channel = -1;
+            options.parallelism.key = -1;
+            let group = XSynth_ChannelGroup_Create(options);
+            assert_eq!(XSynth_ChannelGroup_GetChannelCount(group), channels);
+            XSynth_ChannelGroup_Drop(group);
+        }
+    }
+
+    #[test]
+    fn test_release_and_kill_all() {
+        let dir = test_dir("release_and_kill_all");
+        let soundfont = load_soundfont_with_opcodes(&dir, SAMPLE_RATE, "ampeg_release=1");
+        let new_group_without_dc_blocker = || {
+            let mut options = XSynth_GenDefault_GroupOptions();
+            options.stream_params.sample_rate = SAMPLE_RATE;
+            options.parallelism.channel = -1;
+            options.parallelism.key = -1;
+            options.dc_blocker = false;
+            XSynth_ChannelGroup_Create(options)
+        };
+
+        let render = |group: XSynth_ChannelGroup| {
+            let mut samples = vec![0.0f32; 4800];
+            unsafe {
+                XSynth_ChannelGroup_ReadSamples(group, samples.as_mut_ptr(), 4800);
+            }
+            samples
+        };
+
+        let released = new_group_without_dc_blocker();
+        let killed = new_group_without_dc_blocker();
+        for group in [released, killed] {
+            XSynth_ChannelGroup_AddSoundfont(group, soundfont, 0);
+            XSynth_ChannelGroup_SendAudioEvent(group, 0, XSYNTH_AUDIO_EVENT_NOTEON, 60 | 127 << 8);
+            render(group);
+        }
+        XSynth_Soundfont_Remove(soundfont);
+
+        // The released note keeps sounding during its one second release
+        XSynth_ChannelGroup_ReleaseAll(released);
+        let samples = render(released);
+        assert!(samples[4000..].iter().all(|s| s.abs() > 0.1));
+        assert_eq!(XSynth_ChannelGroup_VoiceCount(released), 1);
+
+        // The killed note is silent after the 1ms kill fade
+        XSynth_ChannelGroup_KillAll(killed);
+        let samples = render(killed);
+        assert!(samples[..96].iter().any(|s| s.abs() > 0.0));
+        assert!(samples[96..].iter().all(|&s| s == 0.0));
+        assert_eq!(XSynth_ChannelGroup_VoiceCount(killed), 0);
+
+        XSynth_ChannelGroup_Drop(released);
+        XSynth_ChannelGroup_Drop(killed);
+    }
+}
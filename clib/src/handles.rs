@@ -5,6 +5,8 @@ use xsynth_core::{
 };
 use xsynth_realtime::RealtimeSynth;
 
+use crate::soundfont::LoadOperation;
+
 /// Handle of an internal ChannelGroup instance in XSynth.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -64,6 +66,11 @@ impl XSynth_Soundfont {
             sf.clone()
         }
     }
+
+    pub(crate) fn as_ref(&self) -> &SampleSoundfont {
+        let sf = self.soundfont as *mut Arc<SampleSoundfont>;
+        unsafe { &*sf }
+    }
 }
 
 /// Handle of an internal RealtimeSynth instance in XSynth.
@@ -97,3 +104,30 @@ impl XSynth_RealtimeSynth {
         unsafe { &mut *synth }
     }
 }
+
+/// Handle of an in-progress soundfont load in XSynth.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct XSynth_LoadOperation {
+    pub operation: *mut c_void,
+}
+
+impl XSynth_LoadOperation {
+    pub(crate) fn from(operation: LoadOperation) -> Self {
+        let operation = Box::into_raw(Box::new(operation));
+        Self {
+            operation: operation as *mut c_void,
+        }
+    }
+
+    pub(crate) fn take(self) -> LoadOperation {
+        let operation = self.operation as *mut LoadOperation;
+        unsafe { *Box::from_raw(operation) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) fn as_mut(&self) -> &mut LoadOperation {
+        let operation = self.operation as *mut LoadOperation;
+        unsafe { &mut *operation }
+    }
+}
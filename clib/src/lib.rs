@@ -4,6 +4,7 @@
 #![allow(clippy::doc_overindented_list_items)]
 
 pub mod consts;
+pub mod error;
 pub mod group;
 pub mod handles;
 pub mod realtime;
@@ -11,7 +12,9 @@ pub mod soundfont;
 mod utils;
 
 use consts::*;
+use error::ffi_guard;
 use pkg_version::*;
+use xsynth_core::helpers::{gm2_to_gm_drum_key_map, xg_drum_key_map};
 
 const XSYNTH_VERSION: u32 =
     pkg_version_patch!() | (pkg_version_minor!() << 8) | (pkg_version_major!() << 16);
@@ -56,3 +59,80 @@ pub struct XSynth_ByteRange {
     pub start: u8,
     pub end: u8,
 }
+
+/// Options for using the first 16 channels as an MPE zone, where each note
+/// is played on its own member channel with its own pitch bend. The member
+/// channels are never used for percussion, and the controllers sent to the
+/// master channel are also sent to them.
+/// - zone: The zone layout
+///         Supported: XSYNTH_MPE_ZONE_NONE (MPE disabled),
+///                    XSYNTH_MPE_ZONE_LOWER (master channel 1, members
+///                    from channel 2 upwards),
+///                    XSYNTH_MPE_ZONE_UPPER (master channel 16, members
+///                    from channel 15 downwards)
+/// - member_channels: The number of member channels, clamped to 1-15
+/// - pitch_bend_range: The pitch bend range in semitones that the member
+///         channels start with
+#[repr(C)]
+pub struct XSynth_MpeConfig {
+    pub zone: u8,
+    pub member_channels: u32,
+    pub pitch_bend_range: f32,
+}
+
+/// Generates the default values for the XSynth_MpeConfig struct
+/// Default values are:
+/// - zone: XSYNTH_MPE_ZONE_NONE
+/// - member_channels: 15
+/// - pitch_bend_range: 48.0
+#[no_mangle]
+pub extern "C" fn XSynth_GenDefault_MpeConfig() -> XSynth_MpeConfig {
+    XSynth_MpeConfig {
+        zone: XSYNTH_MPE_ZONE_NONE,
+        member_channels: 15,
+        pitch_bend_range: 48.0,
+    }
+}
+
+/// Writes one of the built-in key maps to the given array, to be used with
+/// XSynth_ChannelGroup_SetKeyMap or XSynth_Realtime_SetKeyMap.
+///
+/// --Parameters--
+/// - preset: The key map to write (see below for available options)
+/// - key_map: Pointer to an array of 128 bytes to write the map to
+///
+/// --Presets--
+/// - XSYNTH_KEY_MAP_XG_DRUMS: From the XG standard drum kit to the GM2
+///         drum layout
+/// - XSYNTH_KEY_MAP_GM2_TO_GM_DRUMS: From the GM2 drum layout to the GM one,
+///         for soundfonts which only have the GM drum sounds
+///
+/// --Returns--
+/// XSYNTH_STATUS_OK on success, or XSYNTH_STATUS_INVALID_ARGUMENT if the
+/// preset is unknown or the pointer is null.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_GetKeyMapPreset(preset: u32, key_map: *mut u8) -> u32 {
+    ffi_guard(move || {
+        let map = match preset {
+            XSYNTH_KEY_MAP_XG_DRUMS => xg_drum_key_map(),
+            XSYNTH_KEY_MAP_GM2_TO_GM_DRUMS => gm2_to_gm_drum_key_map(),
+            _ => {
+                return Err((
+                    XSYNTH_STATUS_INVALID_ARGUMENT,
+                    format!("Unknown key map preset: {preset}"),
+                ))
+            }
+        };
+        if key_map.is_null() {
+            return Err((
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "The key map pointer is null".into(),
+            ));
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(map.as_ptr(), key_map, 128);
+        }
+        Ok(())
+    })
+}
@@ -1,9 +1,14 @@
-use crate::{handles::*, utils::*, XSynth_ByteRange, XSynth_StreamParams};
+use std::ffi::{c_char, CStr};
+
+use crate::{
+    consts::*, error::*, handles::*, utils::*, XSynth_ByteRange, XSynth_GenDefault_MpeConfig,
+    XSynth_MpeConfig, XSynth_StreamParams,
+};
 use xsynth_core::{
     channel::{ChannelConfigEvent, ChannelEvent, ChannelInitOptions},
     channel_group::SynthEvent,
 };
-use xsynth_realtime::{RealtimeSynth, XSynthRealtimeConfig};
+use xsynth_realtime::{RealtimeSynth, RealtimeSynthError, XSynthRealtimeConfig};
 
 /// Options for initializing the XSynth Realtime module
 /// - channels: Number of MIDI channels. If this is set to 16 (MIDI standard),
@@ -15,16 +20,31 @@ use xsynth_realtime::{RealtimeSynth, XSynthRealtimeConfig};
 /// - fade_out_killing: If set to true, the voices killed due to the voice limit
 ///         will fade out. If set to false, they will be killed immediately,
 ///         usually causing clicking but improving performance.
+/// - kill_fade_time: The length of the fade out in seconds of the voices
+///         killed due to the voice limit, if fade_out_killing is enabled.
 /// - render_window_ms: The length of the buffer reader in ms
 /// - ignore_range: A range of velocities that will not be played
 ///         (see XSynth_ByteRange)
+/// - dc_blocker: If set to true, a DC blocking high-pass filter will be applied
+///         to the output audio, removing the DC offset that some samples carry.
+/// - fade_in_ms: The length in ms of the fade-in applied to the output audio
+///         when the stream starts or is resumed. A value of 0 disables it.
+/// - mpe: Options for using the first 16 channels as an MPE zone
+///         (see XSynth_MpeConfig)
+/// - master_tuning_hz: The frequency in Hz of A4 that all the channels are
+///         tuned to.
 #[repr(C)]
 pub struct XSynth_RealtimeConfig {
     pub channels: u32,
     pub multithreading: i32,
     pub fade_out_killing: bool,
+    pub kill_fade_time: f32,
     pub render_window_ms: f64,
     pub ignore_range: XSynth_ByteRange,
+    pub dc_blocker: bool,
+    pub fade_in_ms: f64,
+    pub mpe: XSynth_MpeConfig,
+    pub master_tuning_hz: f32,
 }
 
 /// Generates the default values for the XSynth_RealtimeConfig struct
@@ -32,16 +52,26 @@ pub struct XSynth_RealtimeConfig {
 /// - channels: 16
 /// - multithreading: -1
 /// - fade_out_killing: False
+/// - kill_fade_time: 0.001 (1ms)
 /// - render_window_ms: 10.0ms
 /// - ignore_range: 0->0 (Nothing ignored)
+/// - dc_blocker: True
+/// - fade_in_ms: 2.0ms
+/// - mpe: Defaults for the XSynth_MpeConfig struct
+/// - master_tuning_hz: 440.0Hz
 #[no_mangle]
 pub extern "C" fn XSynth_GenDefault_RealtimeConfig() -> XSynth_RealtimeConfig {
     XSynth_RealtimeConfig {
         channels: 16,
         multithreading: -1,
         fade_out_killing: false,
+        kill_fade_time: 0.001,
         render_window_ms: 10.0,
         ignore_range: XSynth_ByteRange { start: 0, end: 0 },
+        dc_blocker: true,
+        fade_in_ms: 2.0,
+        mpe: XSynth_GenDefault_MpeConfig(),
+        master_tuning_hz: 440.0,
     }
 }
 
@@ -50,11 +80,28 @@ pub extern "C" fn XSynth_GenDefault_RealtimeConfig() -> XSynth_RealtimeConfig {
 /// - voice_count: The amount of active voices
 /// - buffer: Number of samples requested in the last read
 /// - render_time: Percentage of the renderer load
+/// - peak: Peak amplitude of the left and right channels over the last
+///         render window (both are the same for mono audio)
+/// - rms: RMS amplitude of the left and right channels over the last
+///         render window (both are the same for mono audio)
 #[repr(C)]
 pub struct XSynth_RealtimeStats {
     pub voice_count: u64,
     pub buffer: i64,
     pub render_time: f64,
+    pub peak: [f32; 2],
+    pub rms: [f32; 2],
+}
+
+/// A struct that holds the statistics of a single MIDI channel of
+/// the realtime module.
+/// - voice_count: The amount of active voices of the channel
+/// - render_time: Approximate percentage (0 to 1) of the last render
+///         window the channel spent rendering
+#[repr(C)]
+pub struct XSynth_ChannelStats {
+    pub voice_count: u64,
+    pub render_time: f64,
 }
 
 /// Initializes the XSynth Realtime module with the given configuration.
@@ -70,14 +117,23 @@ pub struct XSynth_RealtimeStats {
 pub extern "C" fn XSynth_Realtime_Create(config: XSynth_RealtimeConfig) -> XSynth_RealtimeSynth {
     let channel_init_options = ChannelInitOptions {
         fade_out_killing: config.fade_out_killing,
+        kill_fade_time: config.kill_fade_time,
+        ..Default::default()
     };
 
     let options = XSynthRealtimeConfig {
         channel_init_options,
         render_window_ms: config.render_window_ms,
+        internal_block_size: None,
+        adaptive_render_window_ms: None,
         format: convert_synth_format(config.channels),
         multithreading: convert_threadcount(config.multithreading),
         ignore_range: config.ignore_range.start..=config.ignore_range.end,
+        render_sample_rate: None,
+        dc_blocker: config.dc_blocker,
+        fade_in_ms: config.fade_in_ms,
+        mpe: convert_mpe_config(config.mpe),
+        master_tuning_hz: config.master_tuning_hz,
     };
 
     let new = RealtimeSynth::open_with_default_output(options);
@@ -94,6 +150,17 @@ pub extern "C" fn XSynth_Realtime_SendEventU32(handle: XSynth_RealtimeSynth, eve
     handle.as_mut().send_event_u32(event);
 }
 
+/// Sends a 64-bit MIDI 2.0 Universal MIDI Packet to the desired realtime
+/// synth instance. MIDI 2.0 note on events keep their 16-bit velocity.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - event: The packet to be sent, with its first word in the upper 32 bits
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_SendEventU64(handle: XSynth_RealtimeSynth, event: u64) {
+    handle.as_mut().send_event_u64(event);
+}
+
 /// Sends an audio event to a specific channel of the desired realtime synth instance.
 ///
 /// --Parameters--
@@ -207,23 +274,166 @@ pub extern "C" fn XSynth_Realtime_SetIgnoreRange(
 /// - handle: The handle of the realtime synthesizer instance
 /// - sf_ids: Pointer to an array of soundfont handles
 /// - count: The length of the above array
+///
+/// --Returns--
+/// The amount of soundfonts that will be used. Soundfonts loaded with stream
+/// parameters different from the ones of the realtime synth (see
+/// XSynth_Realtime_GetStreamParams) are not used.
 #[no_mangle]
 pub unsafe extern "C" fn XSynth_Realtime_SetSoundfonts(
     handle: XSynth_RealtimeSynth,
     sf_ids: *const XSynth_Soundfont,
     count: u64,
-) {
+) -> u64 {
     unsafe {
         let ids = std::slice::from_raw_parts(sf_ids, count as usize);
         let sfvec = sfids_to_vec(ids);
+        let applied = count_matching_soundfonts(&sfvec, &handle.as_ref().stream_params());
         handle
             .as_mut()
             .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
                 ChannelConfigEvent::SetSoundfonts(sfvec),
             )));
+        applied
     }
 }
 
+/// Inserts a soundfont at the given position of the soundfont list of
+/// the specified realtime synth instance. Unlike XSynth_Realtime_SetSoundfonts, the notes
+/// that are already playing are not affected and only the presets of the
+/// new soundfont are resolved again.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - sf_id: The handle of the soundfont to be added
+/// - position: The position in the list. Soundfonts earlier in the list
+///         have priority. If larger than the list length, the soundfont
+///         is added at the end.
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_AddSoundfont(
+    handle: XSynth_RealtimeSynth,
+    sf_id: XSynth_Soundfont,
+    position: u64,
+) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::AddSoundfont {
+                sf: sf_id.clone(),
+                position: position as usize,
+            },
+        )));
+}
+
+/// Removes the soundfont at the given position of the soundfont list of
+/// the specified realtime synth instance. Does nothing if there is no soundfont at that
+/// position.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - position: The position of the soundfont in the list
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_RemoveSoundfontAt(handle: XSynth_RealtimeSynth, position: u64) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::RemoveSoundfont {
+                position: position as usize,
+            },
+        )));
+}
+
+/// Retunes all the keys of every channel of the specified realtime synth instance.
+/// The tuning also applies to the notes that are already playing.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - tuning: Pointer to an array of 128 floats, each being the offset of
+///         the respective key in cents from 12-TET
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_SetKeyTuning(
+    handle: XSynth_RealtimeSynth,
+    tuning: *const f32,
+) {
+    unsafe {
+        let tuning = std::slice::from_raw_parts(tuning, 128);
+        let tuning = tuning
+            .iter()
+            .enumerate()
+            .map(|(key, &cents)| (key as u8, cents))
+            .collect();
+        handle
+            .as_mut()
+            .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetKeyTuning(tuning),
+            )));
+    }
+}
+
+/// Remaps the keys of the note events of a specific channel of the specified
+/// realtime synth instance, e.g. to play MIDIs made for drum kits with a
+/// nonstandard layout on a GM soundfont. Note offs always release the key
+/// their note on was mapped to, even if the map changed in between.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - channel: The number of the MIDI channel to set the map for
+///         (MIDI channel 1 is 0)
+/// - key_map: Pointer to an array of 128 bytes, each being the key played
+///         for the respective incoming key, or null to remove the map.
+///         See XSynth_GetKeyMapPreset for the built-in maps.
+/// - percussion_only: If true, the map is only applied while the channel
+///         uses the percussion bank
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_SetKeyMap(
+    handle: XSynth_RealtimeSynth,
+    channel: u32,
+    key_map: *const u8,
+    percussion_only: bool,
+) {
+    let map = unsafe { convert_key_map(key_map) };
+    handle.as_mut().send_event(SynthEvent::Channel(
+        channel,
+        ChannelEvent::Config(ChannelConfigEvent::SetKeyMap {
+            map,
+            percussion_only,
+        }),
+    ));
+}
+
+/// Sets the master volume of the specified realtime synth instance. It is
+/// applied before the output limiter, and changes are smoothed to avoid
+/// zipper noise.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - volume: The gain applied to the output audio, where 1.0 is the
+///         original level. Negative values are treated as 0.0.
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_SetMasterVolume(handle: XSynth_RealtimeSynth, volume: f32) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterGain(volume),
+        )));
+}
+
+/// Sets the frequency of A4 that all the channels of the specified realtime
+/// synth instance are tuned to. It also applies to the active voices.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - hz: The frequency of A4 in Hz, where 440.0 is the standard tuning.
+///         Values that aren't positive are ignored.
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_SetMasterTuning(handle: XSynth_RealtimeSynth, hz: f32) {
+    handle
+        .as_mut()
+        .send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterTuning(hz),
+        )));
+}
+
 /// Removes all the soundfonts used in the specified realtime synth instance.
 ///
 /// --Parameters--
@@ -253,6 +463,104 @@ pub extern "C" fn XSynth_Realtime_GetStreamParams(
     convert_streamparams_to_c(&handle.as_ref().stream_params())
 }
 
+/// Information about an audio output device.
+/// - name: The null terminated name of the device, truncated to 255 bytes.
+///         Used to select the device with XSynth_Realtime_SetDevice.
+/// - is_default: Whether the device is the default output device
+/// - stream_params: The parameters of the default configuration of the device
+///         (see XSynth_StreamParams). The sample rate is 0 if they are unknown.
+#[repr(C)]
+pub struct XSynth_DeviceInfo {
+    pub name: [c_char; 256],
+    pub is_default: bool,
+    pub stream_params: XSynth_StreamParams,
+}
+
+/// Lists the audio output devices which can be used by the realtime
+/// synthesizer.
+///
+/// --Parameters--
+/// - devices: The array where the information of each device will be
+///         written (see XSynth_DeviceInfo). If the array is too small, only
+///         the first devices are written. Can be null to only query the
+///         device count.
+/// - count: The length of the above array
+///
+/// --Returns--
+/// The total amount of output devices.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_ListDevices(
+    devices: *mut XSynth_DeviceInfo,
+    count: u64,
+) -> u64 {
+    let list = RealtimeSynth::list_output_devices();
+
+    if !devices.is_null() {
+        let out = unsafe { std::slice::from_raw_parts_mut(devices, count as usize) };
+        for (info, device) in out.iter_mut().zip(&list) {
+            write_c_string(&mut info.name, &device.name);
+            info.is_default = device.is_default;
+            info.stream_params = match &device.default_stream_params {
+                Some(params) => convert_streamparams_to_c(params),
+                None => XSynth_StreamParams {
+                    sample_rate: 0,
+                    audio_channels: 0,
+                },
+            };
+        }
+    }
+
+    list.len() as u64
+}
+
+/// Moves the audio output of the specified realtime synth instance to
+/// another device, keeping the state of all the channels. The audio is
+/// resampled if the sample rate of the device is different, so the
+/// stream parameters of the synthesizer don't change.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - name: The null terminated name of the device, as listed by
+///         XSynth_Realtime_ListDevices
+///
+/// --Returns--
+/// One of the following status codes. If the device can't be used, the
+/// previous device keeps playing. A description of the error can be
+/// retrieved using the XSynth_GetLastError function.
+/// - XSYNTH_STATUS_OK: The output was moved to the device
+/// - XSYNTH_STATUS_INVALID_ARGUMENT: The name is null, not valid UTF-8 or
+///         no device with that name exists
+/// - XSYNTH_STATUS_DEVICE_ERROR: The device could not be opened, or it has
+///         a different channel count than the synthesizer
+/// - XSYNTH_STATUS_INTERNAL_ERROR: An unexpected internal error occurred
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_SetDevice(
+    handle: XSynth_RealtimeSynth,
+    name: *const c_char,
+) -> u32 {
+    ffi_guard(move || {
+        if name.is_null() {
+            return Err((XSYNTH_STATUS_INVALID_ARGUMENT, "The name is null".into()));
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_str().map_err(|_| {
+            (
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "The name is not valid UTF-8".to_string(),
+            )
+        })?;
+
+        handle
+            .as_mut()
+            .switch_output_device(name)
+            .map_err(|e| match e {
+                RealtimeSynthError::DeviceNotFound(..) => {
+                    (XSYNTH_STATUS_INVALID_ARGUMENT, error_chain_message(&e))
+                }
+                e => (XSYNTH_STATUS_DEVICE_ERROR, error_chain_message(&e)),
+            })
+    })
+}
+
 /// Returns the statistics of the specified realtime synth instance.
 ///
 /// --Parameters--
@@ -263,14 +571,122 @@ pub extern "C" fn XSynth_Realtime_GetStreamParams(
 #[no_mangle]
 pub extern "C" fn XSynth_Realtime_GetStats(handle: XSynth_RealtimeSynth) -> XSynth_RealtimeStats {
     let stats = handle.as_ref().get_stats();
+    let levels = stats.output_levels();
 
     XSynth_RealtimeStats {
         voice_count: stats.voice_count(),
         buffer: stats.buffer().last_samples_after_read(),
         render_time: stats.buffer().average_renderer_load(),
+        peak: levels.peak,
+        rms: levels.rms,
     }
 }
 
+/// Returns the statistics of a MIDI channel of the specified realtime
+/// synth instance.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - channel: The number of the MIDI channel, starting from 0
+///
+/// --Returns--
+/// This function returns the statistics as an XSynth_ChannelStats struct.
+/// All the statistics are 0 if the channel doesn't exist.
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_GetChannelStats(
+    handle: XSynth_RealtimeSynth,
+    channel: u32,
+) -> XSynth_ChannelStats {
+    let stats = handle
+        .as_ref()
+        .get_stats()
+        .channel(channel as usize)
+        .unwrap_or_default();
+
+    XSynth_ChannelStats {
+        voice_count: stats.voice_count,
+        render_time: stats.render_load,
+    }
+}
+
+/// Returns the keys of a MIDI channel of the specified realtime synth
+/// instance which have voices. The masks are updated when the synth
+/// renders, so they may lag behind the events sent to it.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - channel: The number of the MIDI channel, starting from 0
+/// - sounding: Pointer to a mutable array of 16 bytes to receive the mask of
+///         the keys with any voices, including the released ones. Bit
+///         (key % 8) of byte (key / 8) belongs to each key. Can be null.
+/// - unreleased: Pointer to a mutable array of 16 bytes to receive the mask
+///         of the keys with voices which weren't released yet, in the same
+///         layout. Can be null.
+///
+/// If the channel doesn't exist, the masks are cleared.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_GetKeyOccupancy(
+    handle: XSynth_RealtimeSynth,
+    channel: u32,
+    sounding: *mut u8,
+    unreleased: *mut u8,
+) {
+    let occupancy = handle
+        .as_ref()
+        .get_stats()
+        .key_occupancy(channel as usize)
+        .unwrap_or_default();
+    unsafe {
+        write_key_mask(&occupancy.sounding, sounding);
+        write_key_mask(&occupancy.unreleased, unreleased);
+    }
+}
+
+/// Returns the active voice count of each soundfont of the specified
+/// realtime synth instance, summed across all the MIDI channels.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+/// - counts: Pointer to a mutable array to receive the voice counts, in the
+///         order of the soundfont list. Each item of the array should be
+///         a 64bit unsigned integer.
+/// - length: The length of the array
+///
+/// --Returns--
+/// The number of soundfonts. If it is larger than the length of the array,
+/// only the counts that fit in the array are written.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Realtime_VoicesPerSoundfont(
+    handle: XSynth_RealtimeSynth,
+    counts: *mut u64,
+    length: u64,
+) -> u64 {
+    let voices = handle.as_ref().get_stats().voices_per_soundfont();
+    unsafe { write_counts(&voices, counts, length) };
+    voices.len() as u64
+}
+
+/// Releases all active notes of the specified realtime synth instance,
+/// so that they fade out with their release envelope.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_ReleaseAll(handle: XSynth_RealtimeSynth) {
+    handle.as_mut().get_sender_mut().release_all();
+}
+
+/// Kills all active notes of the specified realtime synth instance. They
+/// are silenced within the kill fade time of the channels, without their
+/// release envelope. The control change data is kept.
+///
+/// --Parameters--
+/// - handle: The handle of the realtime synthesizer instance
+#[no_mangle]
+pub extern "C" fn XSynth_Realtime_KillAll(handle: XSynth_RealtimeSynth) {
+    handle.as_mut().get_sender_mut().kill_all();
+}
+
 /// Resets the specified realtime synth instance. Kills all active notes
 /// and resets all control change.
 ///
@@ -2,11 +2,20 @@ use std::{
     ffi::{c_char, CStr},
     path::PathBuf,
     sync::Arc,
+    thread::{self, JoinHandle},
 };
 
-use xsynth_core::soundfont::{Interpolator, SampleSoundfont, SoundfontInitOptions};
+use xsynth_core::{
+    soundfont::{
+        Interpolator, LoadSfError, LoadSfzError, ResampleQuality, SampleSoundfont, Sf2ParseError,
+        SfzParseError, SoundfontInitOptions, SoundfontLoadProgress,
+    },
+    AudioStreamParams,
+};
 
-use crate::{consts::*, handles::*, utils::*, XSynth_GenDefault_StreamParams, XSynth_StreamParams};
+use crate::{
+    consts::*, error::*, handles::*, utils::*, XSynth_GenDefault_StreamParams, XSynth_StreamParams,
+};
 
 /// Options for the curves of a specific envelope.
 /// - attack_curve: Controls the type of curve of the attack envelope stage.
@@ -22,6 +31,9 @@ use crate::{consts::*, handles::*, utils::*, XSynth_GenDefault_StreamParams, XSy
 /// - XSYNTH_ENVELOPE_CURVE_EXPONENTIAL: Apply an exponential curve to the
 ///         envelope stage. The decay and release stages will use a concave
 ///         curve, while the attack stage will use a convex curve.
+/// - XSYNTH_ENVELOPE_CURVE_ANALOG: Apply an analog style exponential curve
+///         to the envelope stage, which approaches its target like a charging
+///         capacitor. Supported by the attack, decay and release stages.
 #[repr(C)]
 pub struct XSynth_EnvelopeOptions {
     pub attack_curve: u8,
@@ -57,6 +69,22 @@ pub extern "C" fn XSynth_GenDefault_EnvelopeOptions() -> XSynth_EnvelopeOptions
 /// - interpolator: The type of interpolator to use for the new soundfont
 ///         Available values: INTERPOLATION_NEAREST (Nearest Neighbor interpolation),
 ///                           INTERPOLATION_LINEAR (Linear interpolation)
+/// - resample_on_load: Whether to resample the samples to the output sample rate
+///         when loading. If false, the samples are kept at their native rate and
+///         played back at an adjusted speed, which makes loading much faster.
+/// - resample_quality: The algorithm used to resample the samples on load
+///         Available values: RESAMPLE_QUALITY_NEAREST (Nearest neighbor),
+///                           RESAMPLE_QUALITY_LINEAR (Linear interpolation),
+///                           RESAMPLE_QUALITY_SINC (Windowed sinc, cleanest)
+/// - start_trim_ms: The amount of milliseconds skipped from the start of every
+///         sample of the soundfont, to align the transients of layered soundfonts
+/// - delay_ms: The amount of milliseconds that the voices of the soundfont are
+///         delayed by, to align the transients of layered soundfonts
+/// - humanize_cents: The maximum random detune in cents applied to every voice
+///         of the soundfont, in both directions
+/// - strict_validation: If true, the load fails with XSYNTH_STATUS_INVALID_REGION
+///         when a region has loop points or an offset which don't fit its sample,
+///         instead of repairing them. Useful when developing soundfonts.
 #[repr(C)]
 pub struct XSynth_SoundfontOptions {
     pub stream_params: XSynth_StreamParams,
@@ -65,6 +93,12 @@ pub struct XSynth_SoundfontOptions {
     pub vol_envelope_options: XSynth_EnvelopeOptions,
     pub use_effects: bool,
     pub interpolator: u16,
+    pub resample_on_load: bool,
+    pub resample_quality: u16,
+    pub start_trim_ms: f32,
+    pub delay_ms: f32,
+    pub humanize_cents: f32,
+    pub strict_validation: bool,
 }
 
 /// Generates the default values for the XSynth_SoundfontOptions struct
@@ -75,6 +109,12 @@ pub struct XSynth_SoundfontOptions {
 /// - vol_envelope_options: Defaults for the XSynth_EnvelopeOptions struct
 /// - use_effects: True
 /// - interpolator: INTERPOLATION_NEAREST
+/// - resample_on_load: True
+/// - resample_quality: RESAMPLE_QUALITY_SINC
+/// - start_trim_ms: 0.0
+/// - delay_ms: 0.0
+/// - humanize_cents: 0.0
+/// - strict_validation: False
 #[no_mangle]
 pub extern "C" fn XSynth_GenDefault_SoundfontOptions() -> XSynth_SoundfontOptions {
     XSynth_SoundfontOptions {
@@ -84,7 +124,89 @@ pub extern "C" fn XSynth_GenDefault_SoundfontOptions() -> XSynth_SoundfontOption
         vol_envelope_options: XSynth_GenDefault_EnvelopeOptions(),
         use_effects: true,
         interpolator: XSYNTH_INTERPOLATION_NEAREST,
+        resample_on_load: true,
+        resample_quality: XSYNTH_RESAMPLE_QUALITY_SINC,
+        start_trim_ms: 0.0,
+        delay_ms: 0.0,
+        humanize_cents: 0.0,
+        strict_validation: false,
+    }
+}
+
+fn load_error_status(error: &LoadSfError) -> u32 {
+    match error {
+        LoadSfError::Unsupported => XSYNTH_STATUS_UNSUPPORTED_FORMAT,
+        LoadSfError::Cancelled => XSYNTH_STATUS_CANCELLED,
+        LoadSfError::InvalidRegion(..) => XSYNTH_STATUS_INVALID_REGION,
+        LoadSfError::LoadSfzError(error) => match error {
+            LoadSfzError::IOError(..) => XSYNTH_STATUS_IO_ERROR,
+            LoadSfzError::AudioLoadError(..) => XSYNTH_STATUS_SAMPLE_LOAD_ERROR,
+            LoadSfzError::SfzParseError(SfzParseError::FailedToReadFile(..)) => {
+                XSYNTH_STATUS_IO_ERROR
+            }
+            LoadSfzError::SfzParseError(..) => XSYNTH_STATUS_PARSE_ERROR,
+            LoadSfzError::InvalidRegion(..) => XSYNTH_STATUS_INVALID_REGION,
+        },
+        LoadSfError::LoadSf2Error(error) => match error {
+            Sf2ParseError::FailedToReadFile(..) => XSYNTH_STATUS_IO_ERROR,
+            Sf2ParseError::FailedToParseFile(..) => XSYNTH_STATUS_PARSE_ERROR,
+        },
+    }
+}
+
+type LoadError = (u32, String);
+
+/// Converts the arguments of the soundfont load functions.
+unsafe fn convert_load_args(
+    path: *const c_char,
+    options: XSynth_SoundfontOptions,
+) -> Result<(PathBuf, AudioStreamParams, SoundfontInitOptions), LoadError> {
+    if path.is_null() {
+        return Err((XSYNTH_STATUS_INVALID_ARGUMENT, "The path is null".into()));
     }
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(..) => {
+            return Err((
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "The path is not valid UTF-8".into(),
+            ))
+        }
+    };
+
+    let vol_envelope_options =
+        convert_envelope_to_rust(options.vol_envelope_options).map_err(|_| {
+            (
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "Invalid volume envelope options".to_string(),
+            )
+        })?;
+
+    let sfinit = SoundfontInitOptions {
+        bank: convert_program_value(options.bank.clamp(-1, 128)),
+        preset: convert_program_value(options.preset.clamp(-1, 127)),
+        vol_envelope_options,
+        use_effects: options.use_effects,
+        interpolator: match options.interpolator {
+            XSYNTH_INTERPOLATION_LINEAR => Interpolator::Linear,
+            _ => Interpolator::Nearest,
+        },
+        resample_on_load: options.resample_on_load,
+        resample_quality: match options.resample_quality {
+            XSYNTH_RESAMPLE_QUALITY_NEAREST => ResampleQuality::Nearest,
+            XSYNTH_RESAMPLE_QUALITY_LINEAR => ResampleQuality::Linear,
+            _ => ResampleQuality::Sinc,
+        },
+        random_seed: None,
+        start_trim_ms: options.start_trim_ms,
+        delay_ms: options.delay_ms,
+        humanize_cents: options.humanize_cents,
+        strict_validation: options.strict_validation,
+    };
+
+    let stream_params = convert_streamparams_to_rust(options.stream_params);
+
+    Ok((path, stream_params, sfinit))
 }
 
 /// Loads a new XSynth sample soundfont in memory.
@@ -93,49 +215,311 @@ pub extern "C" fn XSynth_GenDefault_SoundfontOptions() -> XSynth_SoundfontOption
 /// - path: The path of the soundfont to be loaded
 /// - options: The soundfont initialization options
 ///         (XSynth_SoundfontOptions struct)
+/// - soundfont: Pointer where the handle of the loaded soundfont will be
+///         written. The handle can be used to send the soundfont to a
+///         channel group or realtime synth. If the soundfont fails to load,
+///         the written handle will contain a null pointer.
 ///
 /// --Returns--
-/// This function returns the handle of the loaded soundfont, which can be used
-/// to send it to a channel group or realtime synth. If the soundfont fails to
-/// load, the returned handle will contain a null pointer.
+/// XSYNTH_STATUS_OK if the soundfont was loaded successfully, otherwise one
+/// of the following error codes. A description of the error can be retrieved
+/// using the XSynth_GetLastError function.
+/// - XSYNTH_STATUS_INVALID_ARGUMENT: A parameter is null or invalid
+/// - XSYNTH_STATUS_IO_ERROR: The soundfont file could not be read
+/// - XSYNTH_STATUS_UNSUPPORTED_FORMAT: The soundfont format is not supported
+/// - XSYNTH_STATUS_PARSE_ERROR: The soundfont file is invalid
+/// - XSYNTH_STATUS_SAMPLE_LOAD_ERROR: A sample of the soundfont could not be loaded
+/// - XSYNTH_STATUS_INVALID_REGION: A region of the soundfont is invalid, only
+///         reported when strict_validation is enabled
+/// - XSYNTH_STATUS_INTERNAL_ERROR: An unexpected internal error occurred
 #[no_mangle]
 pub unsafe extern "C" fn XSynth_Soundfont_LoadNew(
     path: *const c_char,
     options: XSynth_SoundfontOptions,
-) -> XSynth_Soundfont {
+    soundfont: *mut XSynth_Soundfont,
+) -> u32 {
+    if soundfont.is_null() {
+        set_last_error("The soundfont handle pointer is null");
+        return XSYNTH_STATUS_INVALID_ARGUMENT;
+    }
+
     unsafe {
-        let nullsf = XSynth_Soundfont {
+        *soundfont = XSynth_Soundfont {
             soundfont: std::ptr::null_mut(),
         };
+    }
 
-        let path = match CStr::from_ptr(path).to_str() {
-            Ok(path) => path,
-            Err(..) => return nullsf,
+    ffi_guard(move || {
+        let (path, stream_params, sfinit) = unsafe { convert_load_args(path, options) }?;
+
+        let new = SampleSoundfont::new(path, stream_params, sfinit)
+            .map_err(|e| (load_error_status(&e), error_chain_message(&e)))?;
+
+        unsafe {
+            *soundfont = XSynth_Soundfont::from(Arc::new(new));
+        }
+        Ok(())
+    })
+}
+
+/// A soundfont load running in a worker thread.
+pub(crate) struct LoadOperation {
+    progress: Arc<SoundfontLoadProgress>,
+    worker: Option<JoinHandle<Result<SampleSoundfont, LoadSfError>>>,
+    result: Option<Result<SampleSoundfont, LoadError>>,
+}
+
+impl LoadOperation {
+    fn start(
+        path: PathBuf,
+        stream_params: AudioStreamParams,
+        sfinit: SoundfontInitOptions,
+    ) -> Self {
+        let progress = Arc::new(SoundfontLoadProgress::new());
+        let worker_progress = progress.clone();
+        let worker = thread::Builder::new()
+            .name("xsynth_soundfont_loader".to_string())
+            .spawn(move || {
+                SampleSoundfont::new_with_progress(path, stream_params, sfinit, &worker_progress)
+            })
+            .expect("failed to spawn the soundfont loader thread");
+
+        Self {
+            progress,
+            worker: Some(worker),
+            result: None,
+        }
+    }
+
+    /// Stores the result of the worker, waiting for it if `wait` is true.
+    fn update(&mut self, wait: bool) {
+        let finished = match &self.worker {
+            Some(worker) => wait || worker.is_finished(),
+            None => false,
         };
-        let path = PathBuf::from(path);
-
-        let sfinit = SoundfontInitOptions {
-            bank: convert_program_value(options.bank.clamp(-1, 128)),
-            preset: convert_program_value(options.preset.clamp(-1, 127)),
-            vol_envelope_options: convert_envelope_to_rust(options.vol_envelope_options).unwrap(),
-            use_effects: options.use_effects,
-            interpolator: match options.interpolator {
-                XSYNTH_INTERPOLATION_LINEAR => Interpolator::Linear,
-                _ => Interpolator::Nearest,
-            },
+        if !finished {
+            return;
+        }
+
+        let result = match self.worker.take().unwrap().join() {
+            Ok(result) => result.map_err(|e| (load_error_status(&e), error_chain_message(&e))),
+            Err(..) => Err((
+                XSYNTH_STATUS_INTERNAL_ERROR,
+                "Internal error: the soundfont loader panicked".to_string(),
+            )),
         };
+        self.result = Some(result);
+    }
+}
 
-        let stream_params = convert_streamparams_to_rust(options.stream_params);
+/// Starts loading a new XSynth sample soundfont in a worker thread, so that
+/// the calling thread is not blocked. The load can be monitored with
+/// XSynth_LoadOperation_Poll, cancelled with XSynth_LoadOperation_Cancel,
+/// and its result is retrieved with XSynth_LoadOperation_Finish.
+///
+/// --Parameters--
+/// - path: The path of the soundfont to be loaded
+/// - options: The soundfont initialization options
+///         (XSynth_SoundfontOptions struct)
+/// - operation: Pointer where the handle of the load operation will be
+///         written. Every started operation must be passed to
+///         XSynth_LoadOperation_Finish to free its handle. If the load
+///         can't be started, the written handle will contain a null pointer.
+///
+/// --Returns--
+/// XSYNTH_STATUS_OK if the load was started, otherwise
+/// XSYNTH_STATUS_INVALID_ARGUMENT if a parameter is null or invalid.
+/// Errors of the load itself are returned by XSynth_LoadOperation_Finish.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Soundfont_BeginLoad(
+    path: *const c_char,
+    options: XSynth_SoundfontOptions,
+    operation: *mut XSynth_LoadOperation,
+) -> u32 {
+    if operation.is_null() {
+        set_last_error("The operation handle pointer is null");
+        return XSYNTH_STATUS_INVALID_ARGUMENT;
+    }
 
-        let new = match SampleSoundfont::new(path.clone(), stream_params, sfinit) {
-            Ok(sf) => sf,
-            Err(..) => return nullsf,
+    unsafe {
+        *operation = XSynth_LoadOperation {
+            operation: std::ptr::null_mut(),
         };
+    }
+
+    ffi_guard(move || {
+        let (path, stream_params, sfinit) = unsafe { convert_load_args(path, options) }?;
+
+        let new = LoadOperation::start(path, stream_params, sfinit);
+        unsafe {
+            *operation = XSynth_LoadOperation::from(new);
+        }
+        Ok(())
+    })
+}
+
+/// The state of a soundfont load operation.
+/// - state: The state of the load. See below for available options.
+/// - progress: The progress of the load from 0 to 1. The progress is
+///         coarse: SFZ soundfonts report it per loaded sample file and
+///         SF2 soundfonts per processed preset.
+///
+/// Available states:
+/// - XSYNTH_LOAD_STATE_IN_PROGRESS: The soundfont is still loading
+/// - XSYNTH_LOAD_STATE_DONE: The soundfont was loaded successfully
+/// - XSYNTH_LOAD_STATE_ERROR: The load failed or was cancelled. The error is
+///         returned by XSynth_LoadOperation_Finish.
+#[repr(C)]
+pub struct XSynth_LoadStatus {
+    pub state: u32,
+    pub progress: f32,
+}
 
-        XSynth_Soundfont::from(Arc::new(new))
+/// Returns the state of a soundfont load operation, without blocking.
+///
+/// --Parameters--
+/// - handle: The handle of the load operation
+///
+/// --Returns--
+/// The state of the load (see XSynth_LoadStatus)
+#[no_mangle]
+pub extern "C" fn XSynth_LoadOperation_Poll(handle: XSynth_LoadOperation) -> XSynth_LoadStatus {
+    let operation = handle.as_mut();
+    operation.update(false);
+
+    let state = match &operation.result {
+        None => XSYNTH_LOAD_STATE_IN_PROGRESS,
+        Some(Ok(..)) => XSYNTH_LOAD_STATE_DONE,
+        Some(Err(..)) => XSYNTH_LOAD_STATE_ERROR,
+    };
+
+    XSynth_LoadStatus {
+        state,
+        progress: operation.progress.progress(),
     }
 }
 
+/// Requests a soundfont load operation to be cancelled. This function does
+/// not block: the load stops at the next sample file or preset, after which
+/// XSynth_LoadOperation_Finish returns XSYNTH_STATUS_CANCELLED. If the load
+/// has already finished, this has no effect.
+///
+/// --Parameters--
+/// - handle: The handle of the load operation
+#[no_mangle]
+pub extern "C" fn XSynth_LoadOperation_Cancel(handle: XSynth_LoadOperation) {
+    handle.as_mut().progress.cancel();
+}
+
+/// Finishes a soundfont load operation and frees its handle. If the load
+/// is still in progress, this function blocks until it finishes.
+///
+/// --Parameters--
+/// - handle: The handle of the load operation. It is invalid after this call.
+/// - soundfont: Pointer where the handle of the loaded soundfont will be
+///         written. If the soundfont failed to load, the written handle will
+///         contain a null pointer.
+///
+/// --Returns--
+/// XSYNTH_STATUS_OK if the soundfont was loaded successfully,
+/// XSYNTH_STATUS_CANCELLED if the load was cancelled, otherwise one of the
+/// error codes of XSynth_Soundfont_LoadNew. A description of the error can
+/// be retrieved using the XSynth_GetLastError function.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_LoadOperation_Finish(
+    handle: XSynth_LoadOperation,
+    soundfont: *mut XSynth_Soundfont,
+) -> u32 {
+    let mut operation = handle.take();
+    operation.update(true);
+    let result = operation.result.take().unwrap();
+
+    if !soundfont.is_null() {
+        unsafe {
+            *soundfont = XSynth_Soundfont {
+                soundfont: std::ptr::null_mut(),
+            };
+        }
+    }
+
+    ffi_guard(move || {
+        let new = result?;
+        if soundfont.is_null() {
+            return Err((
+                XSYNTH_STATUS_INVALID_ARGUMENT,
+                "The soundfont handle pointer is null".into(),
+            ));
+        }
+        unsafe {
+            *soundfont = XSynth_Soundfont::from(Arc::new(new));
+        }
+        Ok(())
+    })
+}
+
+/// Returns the audio stream parameters the desired soundfont was loaded
+/// with, as an XSynth_StreamParams struct. A soundfont can only be used by
+/// channel groups and realtime synthesizers with the same parameters.
+///
+/// --Parameters--
+/// - handle: The handle of the soundfont
+///
+/// --Returns--
+/// This function returns an XSynth_StreamParams struct.
+#[no_mangle]
+pub extern "C" fn XSynth_Soundfont_GetStreamParams(
+    handle: XSynth_Soundfont,
+) -> XSynth_StreamParams {
+    convert_streamparams_to_c(handle.clone().stream_params())
+}
+
+/// Information about a preset of a soundfont.
+/// - bank: The bank number of the preset
+/// - preset: The preset number of the preset
+/// - name: The null terminated name of the preset, truncated to 20 bytes.
+///         SFZ soundfonts have no preset names, so it is empty for them.
+#[repr(C)]
+pub struct XSynth_PresetInfo {
+    pub bank: u8,
+    pub preset: u8,
+    pub name: [c_char; 21],
+}
+
+/// Lists the presets available in the desired soundfont. SF2 soundfonts
+/// contain the presets selected with the bank and preset load options,
+/// while SFZ soundfonts contain a single preset at the selected bank and
+/// preset numbers.
+///
+/// --Parameters--
+/// - handle: The handle of the soundfont
+/// - presets: The array where the information of each preset will be
+///         written (see XSynth_PresetInfo). If the array is too small, only
+///         the first presets are written. Can be null to only query the
+///         preset count.
+/// - count: The length of the above array
+///
+/// --Returns--
+/// The total amount of presets in the soundfont.
+#[no_mangle]
+pub unsafe extern "C" fn XSynth_Soundfont_GetPresets(
+    handle: XSynth_Soundfont,
+    presets: *mut XSynth_PresetInfo,
+    count: u64,
+) -> u64 {
+    let list = handle.as_ref().list_presets();
+
+    if !presets.is_null() {
+        let out = unsafe { std::slice::from_raw_parts_mut(presets, count as usize) };
+        for (info, (bank, preset, name)) in out.iter_mut().zip(&list) {
+            info.bank = *bank;
+            info.preset = *preset;
+            write_c_string(&mut info.name, name.as_deref().unwrap_or_default());
+        }
+    }
+
+    list.len() as u64
+}
+
 /// Frees the handle of the desired soundfont.
 ///
 /// Keep in mind that this does not free the memory the soundfont is
@@ -155,3 +539,202 @@ pub unsafe extern "C" fn XSynth_Soundfont_LoadNew(
 pub extern "C" fn XSynth_Soundfont_Remove(handle: XSynth_Soundfont) {
     handle.drop();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        ffi::CString,
+        time::{Duration, Instant},
+    };
+    use xsynth_core::soundfont::test_utils::TestSoundfontDir;
+
+    fn last_error() -> String {
+        let len = unsafe { XSynth_GetLastError(std::ptr::null_mut(), 0) };
+        let mut buf = vec![0u8; len + 1];
+        unsafe { XSynth_GetLastError(buf.as_mut_ptr() as *mut c_char, buf.len()) };
+        CStr::from_bytes_until_nul(&buf)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    fn load(path: &str) -> (u32, XSynth_Soundfont) {
+        let path = CString::new(path).unwrap();
+        let mut handle = XSynth_Soundfont {
+            soundfont: std::ptr::dangling_mut(),
+        };
+        let status = unsafe {
+            XSynth_Soundfont_LoadNew(
+                path.as_ptr(),
+                XSynth_GenDefault_SoundfontOptions(),
+                &mut handle,
+            )
+        };
+        (status, handle)
+    }
+
+    #[test]
+    fn test_load_nonexistent() {
+        let path = std::env::temp_dir().join("xsynth_nonexistent_soundfont.sfz");
+        let (status, handle) = load(path.to_str().unwrap());
+        assert_eq!(status, XSYNTH_STATUS_IO_ERROR);
+        assert!(handle.soundfont.is_null());
+
+        let message = last_error();
+        assert!(message.contains("Failed to read file"));
+        assert!(message.contains("xsynth_nonexistent_soundfont.sfz"));
+
+        let (status, _) = load("soundfont.txt");
+        assert_eq!(status, XSYNTH_STATUS_UNSUPPORTED_FORMAT);
+        assert_eq!(last_error(), "Unsupported format");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let mut handle = XSynth_Soundfont {
+            soundfont: std::ptr::null_mut(),
+        };
+        let status = unsafe {
+            XSynth_Soundfont_LoadNew(
+                std::ptr::null(),
+                XSynth_GenDefault_SoundfontOptions(),
+                &mut handle,
+            )
+        };
+        assert_eq!(status, XSYNTH_STATUS_INVALID_ARGUMENT);
+        assert_eq!(last_error(), "The path is null");
+
+        let mut options = XSynth_GenDefault_SoundfontOptions();
+        options.vol_envelope_options.attack_curve = 100;
+        let path = CString::new("soundfont.sfz").unwrap();
+        let status = unsafe { XSynth_Soundfont_LoadNew(path.as_ptr(), options, &mut handle) };
+        assert_eq!(status, XSYNTH_STATUS_INVALID_ARGUMENT);
+    }
+
+    /// Writes an SFZ soundfont with one region and sample file per key.
+    fn write_multi_sample_sfz(dir: &TestSoundfontDir, samples: usize, length: usize) -> CString {
+        let wave: Vec<f32> = (0..length).map(|i| (i % 100) as f32 / 100.0).collect();
+        let mut sfz = String::new();
+        for key in 0..samples {
+            dir.write_wav(&format!("{key}.wav"), 48000, &wave);
+            sfz.push_str(&format!("<region> sample={key}.wav key={key}\n"));
+        }
+
+        let path = dir.write_sfz("test.sfz", &sfz);
+        CString::new(path.to_str().unwrap()).unwrap()
+    }
+
+    fn begin_load(path: &CString) -> XSynth_LoadOperation {
+        let mut options = XSynth_GenDefault_SoundfontOptions();
+        options.stream_params.sample_rate = 48000;
+        let mut operation = XSynth_LoadOperation {
+            operation: std::ptr::null_mut(),
+        };
+        let status = unsafe { XSynth_Soundfont_BeginLoad(path.as_ptr(), options, &mut operation) };
+        assert_eq!(status, XSYNTH_STATUS_OK);
+        assert!(!operation.operation.is_null());
+        operation
+    }
+
+    #[test]
+    fn test_load_operation_poll() {
+        let dir = TestSoundfontDir::new("clib_load_poll");
+        let path = write_multi_sample_sfz(&dir, 8, 48000);
+        let operation = begin_load(&path);
+
+        let start = Instant::now();
+        let mut last_progress = 0.0;
+        loop {
+            let status = XSynth_LoadOperation_Poll(operation);
+            assert!(status.progress >= last_progress);
+            last_progress = status.progress;
+            if status.state != XSYNTH_LOAD_STATE_IN_PROGRESS {
+                assert_eq!(status.state, XSYNTH_LOAD_STATE_DONE);
+                assert_eq!(status.progress, 1.0);
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut soundfont = XSynth_Soundfont {
+            soundfont: std::ptr::null_mut(),
+        };
+        let status = unsafe { XSynth_LoadOperation_Finish(operation, &mut soundfont) };
+        assert_eq!(status, XSYNTH_STATUS_OK);
+        assert!(!soundfont.soundfont.is_null());
+        XSynth_Soundfont_Remove(soundfont);
+    }
+
+    #[test]
+    fn test_load_operation_cancel() {
+        let dir = TestSoundfontDir::new("clib_load_cancel");
+        let path = write_multi_sample_sfz(&dir, 64, 96000);
+        let operation = begin_load(&path);
+        let progress = Arc::downgrade(&operation.as_mut().progress);
+
+        let start = Instant::now();
+        XSynth_LoadOperation_Cancel(operation);
+        let mut soundfont = XSynth_Soundfont {
+            soundfont: std::ptr::dangling_mut(),
+        };
+        let status = unsafe { XSynth_LoadOperation_Finish(operation, &mut soundfont) };
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(status, XSYNTH_STATUS_CANCELLED);
+        assert_eq!(last_error(), "The load was cancelled");
+        assert!(soundfont.soundfont.is_null());
+
+        // The operation and its worker thread were freed
+        assert!(progress.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_get_presets() {
+        let dir = TestSoundfontDir::new("clib_get_presets");
+        let path = write_multi_sample_sfz(&dir, 1, 100);
+        let mut options = XSynth_GenDefault_SoundfontOptions();
+        options.bank = 3;
+        options.preset = 5;
+        let mut soundfont = XSynth_Soundfont {
+            soundfont: std::ptr::null_mut(),
+        };
+        let status = unsafe { XSynth_Soundfont_LoadNew(path.as_ptr(), options, &mut soundfont) };
+        assert_eq!(status, XSYNTH_STATUS_OK);
+
+        let count = unsafe { XSynth_Soundfont_GetPresets(soundfont, std::ptr::null_mut(), 0) };
+        assert_eq!(count, 1);
+
+        let mut presets = [XSynth_PresetInfo {
+            bank: 0,
+            preset: 0,
+            name: [1; 21],
+        }];
+        let count = unsafe {
+            XSynth_Soundfont_GetPresets(soundfont, presets.as_mut_ptr(), presets.len() as u64)
+        };
+        assert_eq!(count, 1);
+        assert_eq!((presets[0].bank, presets[0].preset), (3, 5));
+        assert_eq!(presets[0].name, [0; 21]);
+
+        XSynth_Soundfont_Remove(soundfont);
+    }
+
+    #[test]
+    fn test_panic_is_caught() {
+        let status = ffi_guard(|| panic!("test panic"));
+        assert_eq!(status, XSYNTH_STATUS_INTERNAL_ERROR);
+        assert_eq!(last_error(), "Internal error: test panic");
+    }
+
+    #[test]
+    fn test_last_error_truncation() {
+        set_last_error("Some error message");
+        let mut buf = [1 as c_char; 5];
+        let len = unsafe { XSynth_GetLastError(buf.as_mut_ptr(), buf.len()) };
+        assert_eq!(len, 18);
+        let message = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        assert_eq!(message.to_str().unwrap(), "Some");
+    }
+}
@@ -1,11 +1,11 @@
 use crate::{
     consts::*, group::XSynth_ParallelismOptions, handles::*, soundfont::XSynth_EnvelopeOptions,
-    XSynth_StreamParams,
+    XSynth_MpeConfig, XSynth_StreamParams,
 };
-use std::sync::Arc;
+use std::{ffi::c_char, sync::Arc};
 use xsynth_core::{
-    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent},
-    channel_group::{ParallelismOptions, SynthFormat, ThreadCount},
+    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent, PanLaw},
+    channel_group::{MpeConfig, MpeZone, ParallelismOptions, SynthFormat, ThreadCount},
     soundfont::{EnvelopeCurveType, EnvelopeOptions, SoundfontBase},
     AudioStreamParams,
 };
@@ -14,6 +14,7 @@ fn convert_envelope_curve(value: u8) -> Result<EnvelopeCurveType, ()> {
     match value {
         XSYNTH_ENVELOPE_CURVE_LINEAR => Ok(EnvelopeCurveType::Linear),
         XSYNTH_ENVELOPE_CURVE_EXPONENTIAL => Ok(EnvelopeCurveType::Exponential),
+        XSYNTH_ENVELOPE_CURVE_ANALOG => Ok(EnvelopeCurveType::Analog),
         _ => Err(()),
     }
 }
@@ -49,6 +50,12 @@ pub(crate) fn convert_envelope_to_rust(
     })
 }
 
+/// Converts a float sample to a 16bit integer sample, clamping it to
+/// the -1.0 to 1.0 range.
+pub(crate) fn convert_sample_to_s16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
 pub(crate) fn convert_streamparams_to_c(params: &AudioStreamParams) -> XSynth_StreamParams {
     XSynth_StreamParams {
         sample_rate: params.sample_rate,
@@ -63,10 +70,16 @@ pub(crate) fn convert_audio_event(event: u16, params: u16) -> Result<ChannelEven
             let vel = (params >> 8) as u8;
             ChannelAudioEvent::NoteOn { key, vel }
         }
-        XSYNTH_AUDIO_EVENT_NOTEOFF => ChannelAudioEvent::NoteOff {
-            key: (params & 255) as u8,
-        },
+        XSYNTH_AUDIO_EVENT_NOTEOFF => {
+            let key = (params & 255) as u8;
+            // Callers which only pass the key get the default velocity
+            let vel = (params >> 8) as u8;
+            ChannelAudioEvent::NoteOff { key, vel }
+        }
         XSYNTH_AUDIO_EVENT_ALLNOTESKILLED => ChannelAudioEvent::AllNotesKilled,
+        // All Sound Off is sent as CC120, which also clears the skipped notes
+        // of the realtime synthesizer
+        XSYNTH_AUDIO_EVENT_ALLSOUNDOFF => ChannelAudioEvent::Control(ControlEvent::Raw(0x78, 0)),
         XSYNTH_AUDIO_EVENT_ALLNOTESOFF => ChannelAudioEvent::AllNotesOff,
         XSYNTH_AUDIO_EVENT_RESETCONTROL => ChannelAudioEvent::ResetControl,
         XSYNTH_AUDIO_EVENT_PROGRAMCHANGE => {
@@ -108,16 +121,104 @@ pub(crate) fn convert_config_event(event: u16, params: u32) -> Result<ChannelEve
         XSYNTH_CONFIG_SETPERCUSSIONMODE => {
             ChannelConfigEvent::SetPercussionMode(matches!(params, 1))
         }
+        XSYNTH_CONFIG_SETPANLAW => ChannelConfigEvent::SetPanLaw(convert_pan_law(params)?),
+        XSYNTH_CONFIG_SETTRANSPOSE => {
+            ChannelConfigEvent::SetTranspose((params as i32).clamp(-127, 127) as i8)
+        }
+        XSYNTH_CONFIG_SETKEYRANGE => {
+            ChannelConfigEvent::SetKeyRange(params as u8, (params >> 8) as u8)
+        }
+        XSYNTH_CONFIG_SETVELOCITYRANGE => {
+            ChannelConfigEvent::SetVelocityRange(params as u8, (params >> 8) as u8)
+        }
+        XSYNTH_CONFIG_SETIGNORENOTEOFF => ChannelConfigEvent::SetIgnoreNoteOff(matches!(params, 1)),
         _ => return Err(()),
     };
 
     Ok(ChannelEvent::Config(ev))
 }
 
+/// Copies a key map from an array of 128 bytes, where null removes the map.
+pub(crate) unsafe fn convert_key_map(key_map: *const u8) -> Option<Box<[u8; 128]>> {
+    if key_map.is_null() {
+        return None;
+    }
+
+    let mut map = Box::new([0u8; 128]);
+    unsafe {
+        map.copy_from_slice(std::slice::from_raw_parts(key_map, 128));
+    }
+    Some(map)
+}
+
+/// Copies the counts to an array of the given length, truncating them
+/// if they don't fit. Does nothing if the array is null.
+pub(crate) unsafe fn write_counts(counts: &[u64], dest: *mut u64, length: u64) {
+    if dest.is_null() {
+        return;
+    }
+
+    let len = counts.len().min(length as usize);
+    unsafe {
+        std::slice::from_raw_parts_mut(dest, len).copy_from_slice(&counts[..len]);
+    }
+}
+
+/// Writes a key occupancy mask as 16 bytes, where bit `key % 8` of byte
+/// `key / 8` belongs to the key. Does nothing if the array is null.
+pub(crate) unsafe fn write_key_mask(mask: &[u64; 2], dest: *mut u8) {
+    if dest.is_null() {
+        return;
+    }
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&mask[0].to_le_bytes());
+    bytes[8..].copy_from_slice(&mask[1].to_le_bytes());
+    unsafe {
+        std::slice::from_raw_parts_mut(dest, 16).copy_from_slice(&bytes);
+    }
+}
+
+/// Writes a string to a fixed size C string buffer, truncating it at a
+/// character boundary so that the null terminator always fits.
+pub(crate) fn write_c_string(dest: &mut [c_char], string: &str) {
+    let max = dest.len().saturating_sub(1);
+    let end = (0..=string.len().min(max))
+        .rev()
+        .find(|&i| string.is_char_boundary(i))
+        .unwrap_or(0);
+
+    dest.fill(0);
+    for (c, b) in dest.iter_mut().zip(string[..end].bytes()) {
+        *c = b as c_char;
+    }
+}
+
 pub(crate) unsafe fn sfids_to_vec(handles: &[XSynth_Soundfont]) -> Vec<Arc<dyn SoundfontBase>> {
     handles.iter().map(|handle| handle.clone()).collect()
 }
 
+/// Counts the soundfonts which a synthesizer with the given stream
+/// parameters will use. The others are rejected by its channels.
+pub(crate) fn count_matching_soundfonts(
+    soundfonts: &[Arc<dyn SoundfontBase>],
+    stream_params: &AudioStreamParams,
+) -> u64 {
+    soundfonts
+        .iter()
+        .filter(|sf| sf.stream_params() == stream_params)
+        .count() as u64
+}
+
+fn convert_pan_law(law: u32) -> Result<PanLaw, ()> {
+    match law {
+        XSYNTH_PAN_LAW_LINEAR => Ok(PanLaw::Linear),
+        XSYNTH_PAN_LAW_EQUAL_POWER => Ok(PanLaw::EqualPower),
+        XSYNTH_PAN_LAW_COMPROMISE => Ok(PanLaw::Compromise),
+        _ => Err(()),
+    }
+}
+
 fn convert_layers(layers: u32) -> Option<usize> {
     match layers {
         0 => None,
@@ -132,6 +233,20 @@ pub(crate) fn convert_synth_format(channels: u32) -> SynthFormat {
     }
 }
 
+pub(crate) fn convert_mpe_config(config: XSynth_MpeConfig) -> Option<MpeConfig> {
+    let zone = match config.zone {
+        XSYNTH_MPE_ZONE_LOWER => MpeZone::Lower,
+        XSYNTH_MPE_ZONE_UPPER => MpeZone::Upper,
+        _ => return None,
+    };
+
+    Some(MpeConfig {
+        zone,
+        member_channels: config.member_channels,
+        pitch_bend_range: config.pitch_bend_range,
+    })
+}
+
 pub(crate) fn convert_program_value(val: i16) -> Option<u8> {
     if val < 0 {
         None
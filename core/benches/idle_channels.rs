@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+
+use xsynth_core::channel::ChannelAudioEvent;
+use xsynth_core::channel::ChannelConfigEvent;
+use xsynth_core::channel::ChannelEvent;
+use xsynth_core::channel::ChannelInitOptions;
+use xsynth_core::channel::VoiceChannel;
+use xsynth_core::channel_group::ChannelGroup;
+use xsynth_core::channel_group::ChannelGroupConfig;
+use xsynth_core::channel_group::ParallelismOptions;
+use xsynth_core::channel_group::SynthEvent;
+use xsynth_core::channel_group::SynthFormat;
+use xsynth_core::channel_group::ThreadCount;
+use xsynth_core::soundfont::SampleSoundfont;
+use xsynth_core::soundfont::SoundfontBase;
+use xsynth_core::AudioPipe;
+use xsynth_core::AudioStreamParams;
+use xsynth_core::ChannelCount;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+
+    let mut channels: Vec<VoiceChannel> = (0..16)
+        .map(|_| VoiceChannel::new(ChannelInitOptions::default(), stream_params, None))
+        .collect();
+
+    // 64 sample quanta, as used by low latency realtime outputs
+    let mut buffer = vec![0.0; 64 * 2];
+
+    c.bench_function("16 idle channels (64 sample quantum)", |f| {
+        f.iter(|| {
+            for channel in channels.iter_mut() {
+                channel.read_samples(&mut buffer);
+            }
+        })
+    });
+
+    // The group benchmark needs a soundfont to play the notes with
+    let Some(sfz) = std::env::var("XSYNTH_EXAMPLE_SFZ").ok() else {
+        return;
+    };
+    let soundfonts: Vec<Arc<dyn SoundfontBase>> = vec![Arc::new(
+        SampleSoundfont::new(sfz, stream_params, Default::default()).unwrap(),
+    )];
+
+    let mut group = ChannelGroup::new(ChannelGroupConfig {
+        channel_init_options: ChannelInitOptions {
+            fade_out_killing: false,
+            ..Default::default()
+        },
+        format: SynthFormat::Custom { channels: 256 },
+        audio_params: stream_params,
+        parallelism: ParallelismOptions {
+            channel: ThreadCount::None,
+            key: ThreadCount::None,
+        },
+        dc_blocker: false,
+        multi_port_percussion: false,
+        mpe: None,
+        master_tuning_hz: 440.0,
+    });
+    group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+        ChannelConfigEvent::SetSoundfonts(soundfonts),
+    )));
+
+    let mut buffer = vec![0.0; 480 * 2];
+    c.bench_function("256 channel group, 4 playing (480 samples)", |f| {
+        f.iter(|| {
+            for channel in 0..4 {
+                for event in [
+                    ChannelAudioEvent::AllNotesKilled,
+                    ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+                ] {
+                    group.send_event(SynthEvent::Channel(channel, ChannelEvent::Audio(event)));
+                }
+            }
+            group.read_samples(&mut buffer);
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -61,6 +61,7 @@ fn criterion_benchmark(c: &mut Criterion) {
                 for i in 0..127 {
                     channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
                         key: i as u8,
+                        vel: 64,
                     }));
                 }
             }
@@ -0,0 +1,97 @@
+use std::{path::PathBuf, sync::Arc};
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+
+use xsynth_core::channel::ChannelAudioEvent;
+use xsynth_core::channel::ChannelConfigEvent;
+use xsynth_core::channel::ChannelEvent;
+use xsynth_core::channel::VoiceChannel;
+use xsynth_core::soundfont::SampleSoundfont;
+use xsynth_core::soundfont::SoundfontBase;
+use xsynth_core::AudioPipe;
+use xsynth_core::AudioStreamParams;
+use xsynth_core::ChannelCount;
+
+/// Writes an SFZ whose regions start with a long silent delay, using a
+/// generated sine wave sample.
+fn write_delay_fixture() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xsynth_bench_delay_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let samples: Vec<i16> = (0..48000)
+        .map(|i| ((i as f32 / 20.0).sin() * 16000.0) as i16)
+        .collect();
+    let data_len = samples.len() as u32 * 2;
+
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&48000u32.to_le_bytes());
+    wav.extend_from_slice(&96000u32.to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for s in samples {
+        wav.extend_from_slice(&s.to_le_bytes());
+    }
+    std::fs::write(dir.join("sine.wav"), wav).unwrap();
+
+    let sfz = dir.join("delay.sfz");
+    std::fs::write(
+        &sfz,
+        "<region> sample=sine.wav ampeg_delay=1 cutoff=4000 loop_mode=loop_continuous \
+         loop_start=0 loop_end=47999\n",
+    )
+    .unwrap();
+    sfz
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+
+    let sfz = write_delay_fixture();
+    let soundfonts: Vec<Arc<dyn SoundfontBase>> = vec![Arc::new(
+        SampleSoundfont::new(&sfz, stream_params, Default::default()).unwrap(),
+    )];
+    std::fs::remove_dir_all(sfz.parent().unwrap()).ok();
+
+    let make_new_channel = || {
+        let mut channel = VoiceChannel::new(Default::default(), stream_params, None);
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            soundfonts.clone(),
+        )));
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(
+            None,
+        )));
+        channel
+    };
+
+    let mut buffer = vec![0.0; 4800];
+
+    // Every voice stays in its delay stage for the whole benchmark
+    c.bench_function("rendering delayed voices", |f| {
+        f.iter(|| {
+            let mut channel = make_new_channel();
+            for _ in 0..4 {
+                for i in 0..127 {
+                    channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                        key: i as u8,
+                        vel: 127,
+                    }));
+                }
+
+                channel.read_samples(&mut buffer);
+            }
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
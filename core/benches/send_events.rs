@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use criterion::criterion_group;
 use criterion::criterion_main;
+use criterion::BatchSize;
 use criterion::Criterion;
 
 use xsynth_core::channel::ChannelAudioEvent;
@@ -27,6 +28,22 @@ fn stress_channel(channel: &mut VoiceChannel) {
         for i in 0..127 {
             channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
                 key: i as u8,
+                vel: 64,
+            }));
+        }
+
+        // Key events get processed when we read samples
+        channel.read_samples(&mut buffer);
+    }
+}
+
+fn note_on_channel(channel: &mut VoiceChannel) {
+    let mut buffer = vec![0.0; 0];
+    for _ in 0..400 {
+        for i in 0..127 {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: i as u8,
+                vel: 127,
             }));
         }
 
@@ -50,14 +67,64 @@ fn criterion_benchmark(c: &mut Criterion) {
 
     println!("Loading soundfont...");
 
-    let soundfonts: Vec<Arc<dyn SoundfontBase>> = vec![Arc::new(
-        SampleSoundfont::new(sfz, stream_params, Default::default()).unwrap(),
-    )];
+    let load = || -> Vec<Arc<dyn SoundfontBase>> {
+        vec![Arc::new(
+            SampleSoundfont::new(&sfz, stream_params, Default::default()).unwrap(),
+        )]
+    };
+    let soundfonts = load();
+
+    c.bench_function("set soundfonts", |f| {
+        f.iter(|| {
+            let mut channel = VoiceChannel::new(Default::default(), stream_params, None);
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel
+        })
+    });
+
+    // Same as above, but with a freshly loaded soundfont each time, to
+    // measure the cost of building the voice spawners
+    c.bench_function("set soundfonts (cold spawner cache)", |f| {
+        f.iter_batched(
+            load,
+            |soundfonts| {
+                let mut channel = VoiceChannel::new(Default::default(), stream_params, None);
+                channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                    soundfonts,
+                )));
+                channel
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    // Only note ons, with the previous voices killed by the layer limit,
+    // to measure the cost of spawning the voices
+    c.bench_function("note ons (1 layer, kill notes)", |f| {
+        f.iter(|| {
+            let init = ChannelInitOptions {
+                fade_out_killing: false,
+                ..Default::default()
+            };
+            let mut channel = VoiceChannel::new(init, stream_params, None);
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(
+                Some(1),
+            )));
+
+            note_on_channel(&mut channel)
+        })
+    });
 
     c.bench_function("send events (4 layers, kill notes)", |f| {
         f.iter(|| {
             let init = ChannelInitOptions {
                 fade_out_killing: false,
+                ..Default::default()
             };
             let mut channel = VoiceChannel::new(init, stream_params, None);
             channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
@@ -75,6 +142,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         f.iter(|| {
             let init = ChannelInitOptions {
                 fade_out_killing: true,
+                ..Default::default()
             };
             let mut channel = VoiceChannel::new(init, stream_params, None);
             channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
@@ -92,6 +160,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         f.iter(|| {
             let init = ChannelInitOptions {
                 fade_out_killing: false,
+                ..Default::default()
             };
             let mut channel = VoiceChannel::new(init, stream_params, None);
             channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
@@ -109,6 +178,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         f.iter(|| {
             let init = ChannelInitOptions {
                 fade_out_killing: true,
+                ..Default::default()
             };
             let mut channel = VoiceChannel::new(init, stream_params, None);
             channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
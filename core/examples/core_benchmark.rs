@@ -99,6 +99,7 @@ fn bench_rendering(make_new_channel: impl FnMut() -> VoiceChannel) {
                 for i in 0..127 {
                     channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
                         key: i as u8,
+                        vel: 64,
                     }));
                 }
             }
@@ -137,8 +138,10 @@ fn bench_random_rendering(make_new_channel: impl FnMut() -> VoiceChannel) {
                         off_events.push(key);
                     } else {
                         let key = off_events.swap_remove(random.gen_range(0..off_events.len()));
-                        channel
-                            .process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key }));
+                        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                            key,
+                            vel: 64,
+                        }));
                     }
                 }
 
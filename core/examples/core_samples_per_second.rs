@@ -3,8 +3,8 @@ use std::{sync::Arc, time::Instant};
 use xsynth_core::{
     channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, VoiceChannel},
     soundfont::{
-        EnvelopeCurveType, EnvelopeOptions, Interpolator, SampleSoundfont, SoundfontBase,
-        SoundfontInitOptions,
+        EnvelopeCurveType, EnvelopeOptions, Interpolator, ResampleQuality, SampleSoundfont,
+        SoundfontBase, SoundfontInitOptions,
     },
     AudioPipe, AudioStreamParams, ChannelCount,
 };
@@ -43,6 +43,13 @@ pub fn main() {
                 },
                 interpolator: Interpolator::Nearest,
                 use_effects: false,
+                resample_on_load: true,
+                resample_quality: ResampleQuality::Sinc,
+                random_seed: None,
+                start_trim_ms: 0.0,
+                delay_ms: 0.0,
+                humanize_cents: 0.0,
+                strict_validation: false,
             },
         )
         .unwrap(),
@@ -1,5 +1,21 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
 use crate::AudioStreamParams;
 
+/// Errors that can be returned when reading from an audio pipe.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AudioPipeError {
+    /// The source of the audio stopped, so no more samples can be read
+    #[error("The audio source was disconnected")]
+    Disconnected,
+
+    /// The audio couldn't be generated
+    #[error("{0}")]
+    Failed(String),
+}
+
 /// An object to read audio samples from.
 pub trait AudioPipe {
     /// The audio stream parameters of the audio pipe.
@@ -13,6 +29,10 @@ pub trait AudioPipe {
     /// note will be audible for 1 second. If after reading those samples we
     /// send a note off event for the same key, then on the next read the key
     /// will be released. If we don't, then the note will keep playing.
+    ///
+    /// The samples are always interleaved when there are multiple audio
+    /// channels. Use `helpers::deinterleave` to split them for planar
+    /// audio backends.
     fn read_samples(&mut self, to: &mut [f32]) {
         assert!((to.len() as u32).is_multiple_of(self.stream_params().channels.count() as u32));
         self.read_samples_unchecked(to);
@@ -20,6 +40,17 @@ pub trait AudioPipe {
 
     /// Reads samples from the pipe without checking the channel count of the output.
     fn read_samples_unchecked(&mut self, to: &mut [f32]);
+
+    /// Reads samples from the pipe like `read_samples`, but returns an error
+    /// if the pipe can't provide them. The content of `to` is unspecified
+    /// after an error.
+    ///
+    /// Pipes which can fail should override this method. The default
+    /// implementation calls `read_samples` and always succeeds.
+    fn try_read_samples(&mut self, to: &mut [f32]) -> Result<(), AudioPipeError> {
+        self.read_samples(to);
+        Ok(())
+    }
 }
 
 pub struct FunctionAudioPipe<F: 'static + FnMut(&mut [f32]) + Send> {
@@ -45,3 +76,313 @@ impl<F: 'static + FnMut(&mut [f32]) + Send> FunctionAudioPipe<F> {
         }
     }
 }
+
+/// An audio pipe reading its samples from a function which can fail, such
+/// as a network or device stream.
+///
+/// The errors are returned by `try_read_samples`. The other read methods
+/// fill the output with silence when the function fails.
+pub struct FallibleFunctionAudioPipe<F>
+where
+    F: 'static + FnMut(&mut [f32]) -> Result<(), AudioPipeError> + Send,
+{
+    func: F,
+    stream_params: AudioStreamParams,
+}
+
+impl<F> AudioPipe for FallibleFunctionAudioPipe<F>
+where
+    F: 'static + FnMut(&mut [f32]) -> Result<(), AudioPipeError> + Send,
+{
+    fn stream_params(&self) -> &'_ AudioStreamParams {
+        &self.stream_params
+    }
+
+    fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+        if (self.func)(to).is_err() {
+            to.fill(0.0);
+        }
+    }
+
+    fn try_read_samples(&mut self, to: &mut [f32]) -> Result<(), AudioPipeError> {
+        assert!((to.len() as u32).is_multiple_of(self.stream_params.channels.count() as u32));
+        (self.func)(to)
+    }
+}
+
+impl<F> FallibleFunctionAudioPipe<F>
+where
+    F: 'static + FnMut(&mut [f32]) -> Result<(), AudioPipeError> + Send,
+{
+    pub fn new(stream_params: AudioStreamParams, func: F) -> Self {
+        FallibleFunctionAudioPipe {
+            func,
+            stream_params,
+        }
+    }
+}
+
+/// Zero crossings of the resampling kernel on each side of its center.
+const RESAMPLER_ZERO_CROSSINGS: usize = 32;
+
+/// Kernel values stored per input sample in the resampling kernel table.
+const RESAMPLER_TABLE_RESOLUTION: usize = 256;
+
+/// Cutoff of the resampling filter relative to the lower Nyquist frequency.
+const RESAMPLER_CUTOFF: f64 = 0.9;
+
+/// An audio pipe which resamples the audio of another pipe to a different
+/// sample rate, using a Blackman-Harris windowed sinc filter.
+///
+/// Useful for rendering a synthesizer at a fixed sample rate while the
+/// output device or host runs at a different one. The audio is continuous
+/// between reads, regardless of their size.
+///
+/// To calculate the samples, the resampler reads ahead of the output by a few
+/// samples of the wrapped pipe. For a MIDI synthesizer, this delays the events
+/// by the time returned from `latency`.
+pub struct ResamplingPipe<P: AudioPipe> {
+    pipe: P,
+    stream_params: AudioStreamParams,
+    step: f64,
+    half_width: usize,
+    kernel: Vec<f32>,
+    weights: Vec<f32>,
+    input: Vec<f32>,
+    position: f64,
+}
+
+impl<P: AudioPipe> ResamplingPipe<P> {
+    /// Creates a new resampling pipe reading from `pipe`, with the output
+    /// at the given sample rate. The channel count is kept.
+    pub fn new(pipe: P, sample_rate: u32) -> Self {
+        let input_params = *pipe.stream_params();
+        let stream_params = AudioStreamParams::new(sample_rate, input_params.channels);
+
+        let step = input_params.sample_rate as f64 / sample_rate as f64;
+        let cutoff = RESAMPLER_CUTOFF * (1.0 / step).min(1.0);
+        let half_width = if step == 1.0 {
+            0
+        } else {
+            (RESAMPLER_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize
+        };
+
+        // The kernel is symmetric, so only the positive half is stored, with an
+        // extra value for the interpolation at the edge
+        let kernel = (0..=half_width * RESAMPLER_TABLE_RESOLUTION + 1)
+            .map(|i| {
+                let d = i as f64 / RESAMPLER_TABLE_RESOLUTION as f64;
+                if d >= half_width as f64 {
+                    return 0.0;
+                }
+                let x = std::f64::consts::PI * cutoff * d;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                let w = std::f64::consts::PI * d / half_width as f64;
+                let window = 0.35875
+                    + 0.48829 * w.cos()
+                    + 0.14128 * (2.0 * w).cos()
+                    + 0.01168 * (3.0 * w).cos();
+                (cutoff * sinc * window) as f32
+            })
+            .collect();
+
+        let channels = input_params.channels.count() as usize;
+        ResamplingPipe {
+            pipe,
+            stream_params,
+            step,
+            half_width,
+            kernel,
+            weights: vec![0.0; half_width * 2],
+            input: vec![0.0; half_width * channels],
+            position: half_width as f64,
+        }
+    }
+
+    /// Returns the delay introduced by the resampler, which is the time
+    /// the wrapped pipe is read ahead of the output.
+    pub fn latency(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.half_width as f64 / self.pipe.stream_params().sample_rate as f64,
+        )
+    }
+
+    /// Returns a reference to the wrapped pipe.
+    pub fn inner(&self) -> &P {
+        &self.pipe
+    }
+
+    /// Returns a mutable reference to the wrapped pipe.
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.pipe
+    }
+
+    fn kernel_at(&self, distance: f64) -> f32 {
+        let pos = distance.abs() * RESAMPLER_TABLE_RESOLUTION as f64;
+        let index = pos as usize;
+        let frac = (pos - index as f64) as f32;
+        let a = self.kernel[index];
+        let b = self.kernel[index + 1];
+        a + (b - a) * frac
+    }
+
+    /// Fills `to` with resampled audio, reading the wrapped pipe with `read`.
+    fn resample(
+        &mut self,
+        to: &mut [f32],
+        mut read: impl FnMut(&mut P, &mut [f32]) -> Result<(), AudioPipeError>,
+    ) -> Result<(), AudioPipeError> {
+        if self.half_width == 0 {
+            return read(&mut self.pipe, to);
+        }
+
+        let channels = self.stream_params.channels.count() as usize;
+        let frames = to.len() / channels;
+        if frames == 0 {
+            return Ok(());
+        }
+
+        // Read the input needed by the last output frame
+        let last = self.position + (frames - 1) as f64 * self.step;
+        let needed_frames = last as usize + self.half_width + 1;
+        let input_frames = self.input.len() / channels;
+        if needed_frames > input_frames {
+            let start = self.input.len();
+            self.input.resize(needed_frames * channels, 0.0);
+            read(&mut self.pipe, &mut self.input[start..])?;
+        }
+
+        for frame in to.chunks_exact_mut(channels) {
+            let base = self.position as usize;
+            let first = base + 1 - self.half_width;
+            for i in 0..self.half_width * 2 {
+                self.weights[i] = self.kernel_at((first + i) as f64 - self.position);
+            }
+
+            let input = &self.input[first * channels..(first + self.half_width * 2) * channels];
+            for (c, out) in frame.iter_mut().enumerate() {
+                *out = input
+                    .iter()
+                    .skip(c)
+                    .step_by(channels)
+                    .zip(self.weights.iter())
+                    .map(|(s, w)| s * w)
+                    .sum();
+            }
+
+            self.position += self.step;
+        }
+
+        // Drop the input frames which are no longer needed
+        let consumed = (self.position as usize + 1).saturating_sub(self.half_width);
+        self.input.drain(..consumed * channels);
+        self.position -= consumed as f64;
+        Ok(())
+    }
+}
+
+impl<P: AudioPipe> AudioPipe for ResamplingPipe<P> {
+    fn stream_params(&self) -> &'_ AudioStreamParams {
+        &self.stream_params
+    }
+
+    fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+        self.resample(to, |pipe, buf| {
+            pipe.read_samples_unchecked(buf);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn try_read_samples(&mut self, to: &mut [f32]) -> Result<(), AudioPipeError> {
+        assert!((to.len() as u32).is_multiple_of(self.stream_params.channels.count() as u32));
+        self.resample(to, |pipe, buf| pipe.try_read_samples(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChannelCount;
+    use std::f64::consts::TAU;
+
+    /// A pipe playing a signal given as a function of time in seconds.
+    fn signal_pipe(
+        sample_rate: u32,
+        signal: impl Fn(f64) -> f64 + Send + 'static,
+    ) -> impl AudioPipe {
+        let mut index = 0u64;
+        FunctionAudioPipe::new(
+            AudioStreamParams::new(sample_rate, ChannelCount::Mono),
+            move |out| {
+                for s in out.iter_mut() {
+                    *s = signal(index as f64 / sample_rate as f64) as f32;
+                    index += 1;
+                }
+            },
+        )
+    }
+
+    /// Reads samples in chunks of varying sizes.
+    fn read_chunked(pipe: &mut impl AudioPipe, len: usize) -> Vec<f32> {
+        let mut out = vec![0.0; len];
+        let mut pos = 0;
+        for size in [1, 333, 1000, 64, 4097].iter().cycle() {
+            let end = (pos + size).min(len);
+            pipe.read_samples(&mut out[pos..end]);
+            pos = end;
+            if pos == len {
+                break;
+            }
+        }
+        out
+    }
+
+    fn sweep(t: f64) -> f64 {
+        // Exponential sweep from 50Hz to 16kHz in one second
+        let (f0, f1): (f64, f64) = (50.0, 16000.0);
+        let k = (f1 / f0).ln();
+        0.5 * (TAU * f0 * ((k * t).exp() - 1.0) / k).sin()
+    }
+
+    #[test]
+    fn test_resample_round_trip() {
+        let down = ResamplingPipe::new(signal_pipe(48000, sweep), 44100);
+        let mut round_trip = ResamplingPipe::new(down, 48000);
+        assert_eq!(round_trip.stream_params().sample_rate, 48000);
+        assert!(round_trip.latency() > Duration::ZERO);
+
+        let out = read_chunked(&mut round_trip, 48000);
+
+        // Skip the start, where the sweep begins after silence
+        let max_error = out
+            .iter()
+            .enumerate()
+            .skip(1000)
+            .map(|(i, s)| (*s as f64 - sweep(i as f64 / 48000.0)).abs())
+            .fold(0.0, f64::max);
+        assert!(20.0 * (max_error / 0.5).log10() < -60.0, "{max_error}");
+    }
+
+    #[test]
+    fn test_resample_aliasing() {
+        // A tone above the Nyquist frequency of the output must be filtered
+        let mut pipe = ResamplingPipe::new(
+            signal_pipe(48000, |t| 0.5 * (TAU * 23000.0 * t).sin()),
+            44100,
+        );
+        let out = read_chunked(&mut pipe, 44100);
+        let peak = out.iter().skip(1000).fold(0.0f32, |a, s| a.max(s.abs()));
+        assert!(20.0 * (peak / 0.5).log10() < -60.0, "{peak}");
+    }
+
+    #[test]
+    fn test_resample_same_rate() {
+        let mut pipe = ResamplingPipe::new(signal_pipe(48000, sweep), 48000);
+        assert_eq!(pipe.latency(), Duration::ZERO);
+        let out = read_chunked(&mut pipe, 4800);
+        for (i, s) in out.iter().enumerate() {
+            assert_eq!(*s, sweep(i as f64 / 48000.0) as f32);
+        }
+    }
+}
@@ -1,18 +1,18 @@
 use std::{
     collections::VecDeque,
     sync::{
-        atomic::{AtomicI64, AtomicUsize, Ordering},
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
         Arc, RwLock,
     },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use crossbeam_channel::{unbounded, Receiver};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
 use crate::AudioStreamParams;
 
-use super::AudioPipe;
+use super::{AudioPipe, AudioPipeError};
 
 /// Holds the statistics for an instance of BufferedRenderer.
 #[derive(Debug, Clone)]
@@ -26,6 +26,14 @@ struct BufferedRendererStats {
     render_time: Arc<RwLock<VecDeque<f64>>>,
 
     render_size: Arc<AtomicUsize>,
+
+    block_size: Arc<AtomicUsize>,
+
+    underruns: Arc<AtomicU64>,
+
+    error: Arc<RwLock<Option<AudioPipeError>>>,
+
+    adaptive: Arc<RwLock<Option<AdaptiveBufferOptions>>>,
 }
 
 /// Reads the statistics of an instance of BufferedRenderer in a usable way.
@@ -55,6 +63,24 @@ impl BufferedRendererStatsReader {
         self.stats.render_size.load(Ordering::Relaxed)
     }
 
+    /// The number of samples rendered at a time within an iteration.
+    /// A value of 0 means that the whole iteration is rendered at once.
+    pub fn block_size(&self) -> usize {
+        self.stats.block_size.load(Ordering::Relaxed)
+    }
+
+    /// The number of non-blocking reads which didn't have enough samples
+    /// buffered and were filled with silence.
+    pub fn underruns(&self) -> u64 {
+        self.stats.underruns.load(Ordering::Relaxed)
+    }
+
+    /// The error returned by the rendered pipe, which stopped the render
+    /// thread. Once it is set, the reads only return silence.
+    pub fn error(&self) -> Option<AudioPipeError> {
+        self.stats.error.read().unwrap().clone()
+    }
+
     /// The average render time percentages (0 to 1)
     /// of how long the render thread spent rendering, from the max allowed time.
     pub fn average_renderer_load(&self) -> f64 {
@@ -71,6 +97,101 @@ impl BufferedRendererStatsReader {
     }
 }
 
+/// Options for the adaptive mode of BufferedRenderer, which grows the
+/// render size when the render thread can't keep up and shrinks it back
+/// when the load drops. A bigger render size reduces stutter under heavy
+/// load, at the cost of latency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveBufferOptions {
+    /// The smallest render size in samples.
+    pub min_size: usize,
+
+    /// The largest render size in samples.
+    pub max_size: usize,
+
+    /// The load (0 to 1, see `BufferedRendererStatsReader::average_renderer_load`)
+    /// above which the render size is doubled.
+    ///
+    /// Default: `1.0`
+    pub grow_load: f64,
+
+    /// The load below which the render size is halved.
+    ///
+    /// Default: `0.5`
+    pub shrink_load: f64,
+
+    /// The number of consecutive iterations that the load has to stay above
+    /// `grow_load` or below `shrink_load` before the render size changes.
+    ///
+    /// Default: `8`
+    pub windows: u32,
+}
+
+impl AdaptiveBufferOptions {
+    /// Creates the options for a render size between `min_size` and
+    /// `max_size` samples, with the default thresholds.
+    pub fn new(min_size: usize, max_size: usize) -> Self {
+        Self {
+            min_size,
+            max_size,
+            grow_load: 1.0,
+            shrink_load: 0.5,
+            windows: 8,
+        }
+    }
+}
+
+/// Counts the consecutive overloaded and idle iterations of the render thread.
+#[derive(Default)]
+struct AdaptiveState {
+    over: u32,
+    under: u32,
+}
+
+impl AdaptiveState {
+    /// Returns the render size for the next iteration, given the load of the last one.
+    fn next_size(&mut self, options: &AdaptiveBufferOptions, load: f64, size: usize) -> usize {
+        if load > options.grow_load {
+            self.over += 1;
+            self.under = 0;
+        } else if load < options.shrink_load {
+            self.under += 1;
+            self.over = 0;
+        } else {
+            *self = Self::default();
+        }
+
+        let next = if self.over >= options.windows {
+            self.over = 0;
+            size.saturating_mul(2)
+        } else if self.under >= options.windows {
+            self.under = 0;
+            size / 2
+        } else {
+            size
+        };
+        next.clamp(options.min_size, options.max_size.max(options.min_size))
+    }
+}
+
+/// The result of a non-blocking read from BufferedRenderer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadResult {
+    /// The number of samples read from the buffer.
+    pub read: usize,
+
+    /// The number of samples which weren't rendered in time,
+    /// filled with silence.
+    pub missing: usize,
+}
+
+impl ReadResult {
+    /// Returns true if the read was missing samples.
+    pub fn is_underrun(&self) -> bool {
+        self.missing > 0
+    }
+}
+
 /// The helper struct for deferred sample rendering.
 /// Helps avoid stutter when the render time is exceding the max time allowed by the audio driver.
 ///
@@ -87,6 +208,10 @@ pub struct BufferedRenderer {
     /// Remainder of samples from the last received samples vec.
     remainder: Vec<f32>,
 
+    /// Sends the emptied sample vecs back to the render thread, which
+    /// reuses them instead of allocating new ones.
+    recycle: Sender<Vec<f32>>,
+
     /// Whether the render thread should be killed.
     killed: Arc<RwLock<bool>>,
 
@@ -109,10 +234,12 @@ impl BufferedRenderer {
         render_size: usize,
     ) -> Self {
         let (tx, rx) = unbounded();
+        let (recycle, recycled) = unbounded::<Vec<f32>>();
 
         let samples = Arc::new(AtomicI64::new(0));
         let last_request_samples = Arc::new(AtomicI64::new(0));
         let render_size = Arc::new(AtomicUsize::new(render_size));
+        let block_size = Arc::new(AtomicUsize::new(0));
 
         let last_samples_after_read = Arc::new(AtomicI64::new(0));
 
@@ -120,12 +247,20 @@ impl BufferedRenderer {
 
         let killed = Arc::new(RwLock::new(false));
 
+        let error = Arc::new(RwLock::new(None));
+
+        let adaptive = Arc::new(RwLock::new(None::<AdaptiveBufferOptions>));
+
         let thread_handle = {
             let samples = samples.clone();
             let last_request_samples = last_request_samples.clone();
             let render_size = render_size.clone();
+            let block_size = block_size.clone();
             let render_time = render_time.clone();
             let killed = killed.clone();
+            let error = error.clone();
+            let adaptive = adaptive.clone();
+            let mut adaptive_state = AdaptiveState::default();
             thread::Builder::new()
                 .name("xsynth_buffered_rendering".to_string())
                 .spawn(move || loop {
@@ -154,20 +289,34 @@ impl BufferedRenderer {
                     let start = Instant::now();
                     let end = start + delay;
 
-                    // Create the vec and write the samples
-                    let mut vec =
-                        vec![Default::default(); size * stream_params.channels.count() as usize];
-                    render.read_samples(&mut vec);
-
-                    // Send the samples, break if the pipe is broken
-                    samples.fetch_add(vec.len() as i64, Ordering::SeqCst);
-                    match tx.send(vec) {
-                        Ok(_) => {}
-                        Err(_) => break,
+                    // Render the iteration in blocks, sending each one as soon as it's
+                    // ready. Stop if the pipe is broken or the render fails.
+                    let channels = stream_params.channels.count() as usize;
+                    let block = match block_size.load(Ordering::SeqCst) {
+                        0 => size,
+                        block => block.min(size),
                     };
+                    let mut remaining = size;
+                    while remaining > 0 {
+                        let len = block.min(remaining);
+                        remaining -= len;
+
+                        let mut vec = recycled.try_recv().unwrap_or_default();
+                        vec.clear();
+                        vec.resize(len * channels, Default::default());
+                        if let Err(e) = render.try_read_samples(&mut vec) {
+                            *error.write().unwrap() = Some(e);
+                            return;
+                        }
+
+                        samples.fetch_add(vec.len() as i64, Ordering::SeqCst);
+                        if tx.send(vec).is_err() {
+                            return;
+                        }
+                    }
 
                     // Write the elapsed render time percentage to the render_time queue
-                    {
+                    let load = {
                         let mut queue = render_time.write().unwrap();
                         let elaspsed = start.elapsed().as_secs_f64();
                         let total = delay.as_secs_f64();
@@ -175,6 +324,15 @@ impl BufferedRenderer {
                         if queue.len() > 100 {
                             queue.pop_back();
                         }
+                        elaspsed / total
+                    };
+
+                    // Adapt the render size to the load
+                    if let Some(options) = *adaptive.read().unwrap() {
+                        let next = adaptive_state.next_size(&options, load, size);
+                        if next != size {
+                            render_size.store(next, Ordering::SeqCst);
+                        }
                     }
 
                     // Sleep until the next iteration
@@ -192,10 +350,15 @@ impl BufferedRenderer {
                 last_request_samples,
                 render_time,
                 render_size,
+                block_size,
                 last_samples_after_read,
+                underruns: Arc::new(AtomicU64::new(0)),
+                error,
+                adaptive,
             },
             receive: rx,
             remainder: Vec::new(),
+            recycle,
             stream_params,
             thread_handle: Some(thread_handle),
             killed,
@@ -203,7 +366,18 @@ impl BufferedRenderer {
     }
 
     /// Reads samples from the remainder and the output queue into the destination array.
+    /// Blocks until enough samples are rendered, see `try_read` for
+    /// a non-blocking alternative.
+    ///
+    /// If the render thread stopped because of an error, the missing samples
+    /// are filled with silence. See `BufferedRendererStatsReader::error`.
     pub fn read(&mut self, dest: &mut [f32]) {
+        self.read_blocking(dest);
+    }
+
+    /// Like `read`, but returns false if the render thread stopped before
+    /// all the samples were read.
+    fn read_blocking(&mut self, dest: &mut [f32]) -> bool {
         dest.fill(0.0);
 
         let mut i: usize = 0;
@@ -224,8 +398,12 @@ impl BufferedRenderer {
         }
 
         // Read from output queue, leave the remainder if there is any
+        let mut complete = true;
         while self.remainder.is_empty() {
-            let mut buf = self.receive.recv().unwrap();
+            let Ok(mut buf) = self.receive.recv() else {
+                complete = i == dest.len();
+                break;
+            };
 
             let len = buf.len().min(dest.len() - i);
             for r in buf.drain(0..len) {
@@ -233,12 +411,65 @@ impl BufferedRenderer {
                 i += 1;
             }
 
-            self.remainder = buf;
+            self.replace_remainder(buf);
         }
 
         self.stats
             .last_samples_after_read
             .store(samples, Ordering::Relaxed);
+        complete
+    }
+
+    /// Replaces the emptied remainder with a new samples vec, and sends the
+    /// old one back to the render thread.
+    fn replace_remainder(&mut self, buf: Vec<f32>) {
+        let old = std::mem::replace(&mut self.remainder, buf);
+        // The render thread may have stopped, in which case the vec is dropped
+        self.recycle.send(old).ok();
+    }
+
+    /// Reads the samples that are already rendered into the destination array,
+    /// without waiting for the render thread. The rest of the array is filled
+    /// with silence, and counted as an underrun in the statistics.
+    ///
+    /// Suited for audio callbacks, which shouldn't block.
+    pub fn try_read(&mut self, dest: &mut [f32]) -> ReadResult {
+        self.stats
+            .last_request_samples
+            .store(dest.len() as i64, Ordering::SeqCst);
+
+        // Read from the remainder, then from the output queue while it
+        // has samples ready
+        let mut i: usize = 0;
+        loop {
+            let len = self.remainder.len().min(dest.len() - i);
+            dest[i..i + len].copy_from_slice(&self.remainder[..len]);
+            self.remainder.drain(0..len);
+            i += len;
+
+            if i == dest.len() {
+                break;
+            }
+            match self.receive.try_recv() {
+                Ok(buf) => self.replace_remainder(buf),
+                Err(_) => break,
+            }
+        }
+        dest[i..].fill(0.0);
+
+        let samples = self.stats.samples.fetch_sub(i as i64, Ordering::SeqCst) - i as i64;
+        self.stats
+            .last_samples_after_read
+            .store(samples, Ordering::Relaxed);
+
+        let result = ReadResult {
+            read: i,
+            missing: dest.len() - i,
+        };
+        if result.is_underrun() {
+            self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        result
     }
 
     /// Sets the number of samples that should be rendered each iteration.
@@ -246,6 +477,26 @@ impl BufferedRenderer {
         self.stats.render_size.store(size, Ordering::SeqCst);
     }
 
+    /// Enables or disables the adaptive render size. While enabled, the
+    /// render size grows under sustained load and shrinks back when the
+    /// load drops, within the bounds of the options.
+    /// See `AdaptiveBufferOptions` for more information.
+    pub fn set_adaptive(&self, options: Option<AdaptiveBufferOptions>) {
+        *self.stats.adaptive.write().unwrap() = options;
+    }
+
+    /// Returns the options of the adaptive render size, if it is enabled.
+    pub fn adaptive(&self) -> Option<AdaptiveBufferOptions> {
+        *self.stats.adaptive.read().unwrap()
+    }
+
+    /// Sets the number of samples rendered at a time within an iteration,
+    /// so that the output can read them before the whole iteration is
+    /// rendered. A value of 0 renders the whole iteration at once.
+    pub fn set_block_size(&self, size: usize) {
+        self.stats.block_size.store(size, Ordering::SeqCst);
+    }
+
     /// Returns a statistics reader.
     /// See the `BufferedRendererStatsReader` documentation for more information.
     pub fn get_buffer_stats(&self) -> BufferedRendererStatsReader {
@@ -270,4 +521,213 @@ impl AudioPipe for BufferedRenderer {
     fn read_samples_unchecked(&mut self, to: &mut [f32]) {
         self.read(to)
     }
+
+    fn try_read_samples(&mut self, to: &mut [f32]) -> Result<(), AudioPipeError> {
+        assert!((to.len() as u32).is_multiple_of(self.stream_params.channels.count() as u32));
+        if self.read_blocking(to) {
+            Ok(())
+        } else {
+            let error = self.stats.error.read().unwrap().clone();
+            Err(error.unwrap_or(AudioPipeError::Disconnected))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    use crate::{
+        channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent},
+        channel_group::{
+            ChannelGroup, ChannelGroupConfig, ParallelismOptions, SynthEvent, SynthFormat,
+            ThreadCount,
+        },
+        soundfont::{
+            test_helpers::{load_test_sfz_with_sample, TEST_SAMPLE_RATE},
+            SoundfontBase,
+        },
+        ChannelCount, FallibleFunctionAudioPipe, FunctionAudioPipe,
+    };
+
+    fn render_buffered(block_size: usize) -> Vec<f32> {
+        let stream_params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+        let mut group = ChannelGroup::new(ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Custom { channels: 1 },
+            audio_params: stream_params,
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe: None,
+            master_tuning_hz: 440.0,
+        });
+
+        let sine: Vec<f32> = (0..4800)
+            .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+            .collect();
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "buffered_block_size",
+            &sine,
+            "loop_mode=loop_continuous loop_start=0 loop_end=4699",
+            ChannelCount::Mono,
+        ));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+        )));
+        for (i, offset) in [0, 350, 1000, 2222, 3100].into_iter().enumerate() {
+            let key = 60 + i as u8 * 3;
+            let event = ChannelAudioEvent::NoteOn { key, vel: 100 };
+            group.send_event_at_offset(SynthEvent::Channel(0, ChannelEvent::Audio(event)), offset);
+        }
+        group.send_event_at_offset(
+            SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 60, vel: 64 }),
+            ),
+            1500,
+        );
+
+        let mut renderer = BufferedRenderer::new(group, stream_params, 480);
+        renderer.set_block_size(block_size);
+        let mut out = vec![0.0; 4800];
+        renderer.read(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_block_size_output() {
+        let whole = render_buffered(0);
+        assert!(whole.iter().any(|&s| s != 0.0));
+        assert_eq!(render_buffered(64), whole);
+        assert_eq!(render_buffered(100), whole);
+    }
+
+    #[test]
+    fn test_try_read_underruns() {
+        // A pipe which takes 50ms to render 10ms of audio
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Mono);
+        let slow = FunctionAudioPipe::new(stream_params, |out: &mut [f32]| {
+            thread::sleep(Duration::from_millis(50));
+            out.fill(1.0);
+        });
+        let mut buffered = BufferedRenderer::new(slow, stream_params, 480);
+        let stats = buffered.get_buffer_stats();
+
+        let mut buffer = vec![0.0; 480];
+        let mut results = Vec::new();
+        for _ in 0..20 {
+            let start = Instant::now();
+            let result = buffered.try_read(&mut buffer);
+            assert!(start.elapsed() < Duration::from_millis(20));
+
+            assert_eq!(result.read + result.missing, buffer.len());
+            assert!(buffer[..result.read].iter().all(|&s| s == 1.0));
+            assert!(buffer[result.read..].iter().all(|&s| s == 0.0));
+            results.push(result);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let underruns = results.iter().filter(|r| r.is_underrun()).count() as u64;
+        assert!(underruns > 0);
+        assert_eq!(stats.underruns(), underruns);
+        assert!(results.iter().any(|r| r.read > 0));
+    }
+
+    #[test]
+    fn test_adaptive_state() {
+        let options = AdaptiveBufferOptions {
+            windows: 2,
+            ..AdaptiveBufferOptions::new(100, 400)
+        };
+        let mut state = AdaptiveState::default();
+
+        assert_eq!(state.next_size(&options, 1.5, 100), 100);
+        assert_eq!(state.next_size(&options, 1.5, 100), 200);
+        assert_eq!(state.next_size(&options, 1.5, 200), 200);
+        assert_eq!(state.next_size(&options, 1.5, 200), 400);
+        assert_eq!(state.next_size(&options, 1.5, 400), 400);
+        assert_eq!(state.next_size(&options, 1.5, 400), 400);
+
+        // A window within the thresholds resets the count
+        assert_eq!(state.next_size(&options, 0.1, 400), 400);
+        assert_eq!(state.next_size(&options, 0.7, 400), 400);
+        assert_eq!(state.next_size(&options, 0.1, 400), 400);
+        assert_eq!(state.next_size(&options, 0.1, 400), 200);
+        assert_eq!(state.next_size(&options, 0.1, 200), 200);
+        assert_eq!(state.next_size(&options, 0.1, 200), 100);
+        assert_eq!(state.next_size(&options, 0.1, 100), 100);
+        assert_eq!(state.next_size(&options, 0.1, 100), 100);
+    }
+
+    #[test]
+    fn test_adaptive_render_size() {
+        // A pipe which renders at half the real time speed while overloaded
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Mono);
+        let overloaded = Arc::new(AtomicBool::new(true));
+        let pipe = {
+            let overloaded = overloaded.clone();
+            FunctionAudioPipe::new(stream_params, move |out: &mut [f32]| {
+                if overloaded.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_secs(2) * out.len() as u32 / 48000);
+                }
+                out.fill(1.0);
+            })
+        };
+        let mut buffered = BufferedRenderer::new(pipe, stream_params, 480);
+        buffered.set_adaptive(Some(AdaptiveBufferOptions {
+            windows: 3,
+            ..AdaptiveBufferOptions::new(480, 1920)
+        }));
+        let stats = buffered.get_buffer_stats();
+
+        let mut read_until = |done: &dyn Fn(usize) -> bool| {
+            let mut buffer = vec![0.0; 480];
+            let start = Instant::now();
+            while !done(stats.render_size()) {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                buffered.try_read(&mut buffer);
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        read_until(&|size| size == 1920);
+        overloaded.store(false, Ordering::Relaxed);
+        read_until(&|size| size == 480);
+    }
+
+    #[test]
+    fn test_render_error() {
+        // A pipe which fails after rendering 4800 samples
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Mono);
+        let mut rendered = 0;
+        let failing = FallibleFunctionAudioPipe::new(stream_params, move |out: &mut [f32]| {
+            if rendered >= 4800 {
+                return Err(AudioPipeError::Failed("device lost".to_string()));
+            }
+            rendered += out.len();
+            out.fill(1.0);
+            Ok(())
+        });
+        let mut buffered = BufferedRenderer::new(failing, stream_params, 480);
+        let stats = buffered.get_buffer_stats();
+
+        let mut buffer = vec![0.0; 480];
+        for _ in 0..10 {
+            buffered.try_read_samples(&mut buffer).unwrap();
+            assert!(buffer.iter().all(|&s| s == 1.0));
+        }
+
+        let error = AudioPipeError::Failed("device lost".to_string());
+        assert_eq!(buffered.try_read_samples(&mut buffer), Err(error.clone()));
+        assert_eq!(stats.error(), Some(error));
+
+        // The render thread stopped, so the reads return silence
+        buffered.read(&mut buffer);
+        assert!(buffer.iter().all(|&s| s == 0.0));
+    }
 }
@@ -1,8 +1,14 @@
-use std::{iter, ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
     helpers::are_arc_vecs_equal,
-    soundfont::SoundfontBase,
+    soundfont::{SoundfontBase, VoiceSpawner},
     voice::{Voice, VoiceControlData},
 };
 
@@ -17,7 +23,12 @@ pub struct ProgramDescriptor {
 pub struct ChannelSoundfont {
     soundfonts: Vec<Arc<dyn SoundfontBase>>,
     matrix: VoiceSpawnerMatrix,
+    attack_sources: SourceMap,
+    release_sources: SourceMap,
     curr_program: ProgramDescriptor,
+    /// The number of note-ons of each key, which selects
+    /// the regions of round-robin sequences
+    sequence_counters: Vec<AtomicU32>,
 }
 
 impl Deref for ChannelSoundfont {
@@ -29,12 +40,62 @@ impl Deref for ChannelSoundfont {
     }
 }
 
+/// The soundfont that the spawners of a key/velocity pair were taken from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct SpawnerSource {
+    /// Index of the soundfont in the list
+    index: usize,
+
+    /// If the soundfont didn't have the current program and
+    /// the spawners were taken from its replacement
+    replacement: bool,
+}
+
+type SpawnerGetter = fn(&dyn SoundfontBase, u8, u8, u8, u8) -> Spawners;
+
+fn get_attack(sf: &dyn SoundfontBase, bank: u8, preset: u8, key: u8, vel: u8) -> Spawners {
+    sf.get_attack_voice_spawners_at(bank, preset, key, vel)
+}
+
+fn get_release(sf: &dyn SoundfontBase, bank: u8, preset: u8, key: u8, vel: u8) -> Spawners {
+    sf.get_release_voice_spawners_at(bank, preset, key, vel)
+}
+
+type Spawners = Arc<[Arc<dyn VoiceSpawner>]>;
+
+/// Keeps track of the source of the spawners of every key/velocity pair,
+/// so that the matrix can be updated incrementally.
+struct SourceMap {
+    get: SpawnerGetter,
+    sources: Vec<Option<SpawnerSource>>,
+}
+
+impl SourceMap {
+    fn new(get: SpawnerGetter) -> Self {
+        Self {
+            get,
+            sources: vec![None; 128 * 128],
+        }
+    }
+
+    fn index(key: u8, vel: u8) -> usize {
+        key as usize + vel as usize * 128
+    }
+
+    fn source_index(&self, key: u8, vel: u8) -> Option<usize> {
+        self.sources[Self::index(key, vel)].map(|source| source.index)
+    }
+}
+
 impl ChannelSoundfont {
     pub fn new() -> Self {
         ChannelSoundfont {
             soundfonts: Vec::new(),
             matrix: VoiceSpawnerMatrix::new(),
+            attack_sources: SourceMap::new(get_attack),
+            release_sources: SourceMap::new(get_release),
             curr_program: Default::default(),
+            sequence_counters: (0..128).map(|_| AtomicU32::new(0)).collect(),
         }
     }
 
@@ -45,6 +106,56 @@ impl ChannelSoundfont {
         }
     }
 
+    /// Inserts a soundfont at the given position of the list, clamped to
+    /// the list length. Only the soundfont being added is searched, so
+    /// the cost doesn't depend on the size of the list.
+    pub fn add_soundfont(&mut self, sf: Arc<dyn SoundfontBase>, position: usize) {
+        let position = position.min(self.soundfonts.len());
+        self.soundfonts.insert(position, sf);
+
+        let program = self.curr_program;
+        let sf = self.soundfonts[position].as_ref();
+        for k in 0..128u8 {
+            for v in 0..128u8 {
+                let attack = self.attack_sources.insert(sf, position, program, k, v);
+                if let Some(spawners) = attack {
+                    self.matrix.set_spawners_attack(k, v, spawners);
+                }
+                let release = self.release_sources.insert(sf, position, program, k, v);
+                if let Some(spawners) = release {
+                    self.matrix.set_spawners_release(k, v, spawners);
+                }
+            }
+        }
+    }
+
+    /// Removes the soundfont at the given position of the list. Only the
+    /// key/velocity pairs which used the removed soundfont are resolved again.
+    pub fn remove_soundfont(&mut self, position: usize) {
+        if position >= self.soundfonts.len() {
+            return;
+        }
+        self.soundfonts.remove(position);
+
+        let program = self.curr_program;
+        for k in 0..128u8 {
+            for v in 0..128u8 {
+                let attack = self
+                    .attack_sources
+                    .remove(&self.soundfonts, position, program, k, v);
+                if let Some(spawners) = attack {
+                    self.matrix.set_spawners_attack(k, v, spawners);
+                }
+                let release =
+                    self.release_sources
+                        .remove(&self.soundfonts, position, program, k, v);
+                if let Some(spawners) = release {
+                    self.matrix.set_spawners_release(k, v, spawners);
+                }
+            }
+        }
+    }
+
     pub fn change_program(&mut self, program: ProgramDescriptor) {
         if self.curr_program != program {
             self.curr_program = program;
@@ -53,59 +164,14 @@ impl ChannelSoundfont {
     }
 
     fn rebuild_matrix(&mut self) {
-        // If a preset/instr. is missing from all banks it will be muted,
-        // if a preset/instr. has regions in bank 0, all missing banks will be replaced by 0,
-        // if a preset/instr. has regions in any bank other than 0, all missing banks will be muted.
-        // For drum patches the same applies with bank and preset switched.
-
-        let bank = self.curr_program.bank;
-        let preset = self.curr_program.preset;
+        let program = self.curr_program;
 
         for k in 0..128u8 {
             for v in 0..128u8 {
-                let find_replacement_attack = || {
-                    if bank == 128 {
-                        self.soundfonts
-                            .iter()
-                            .map(|sf| sf.get_attack_voice_spawners_at(bank, 0, k, v))
-                            .find(|vec| !vec.is_empty())
-                    } else {
-                        self.soundfonts
-                            .iter()
-                            .map(|sf| sf.get_attack_voice_spawners_at(0, preset, k, v))
-                            .find(|vec| !vec.is_empty())
-                    }
-                };
-
-                let attack_spawners = self
-                    .soundfonts
-                    .iter()
-                    .map(|sf| sf.get_attack_voice_spawners_at(bank, preset, k, v))
-                    .chain(iter::once_with(find_replacement_attack).flatten())
-                    .find(|vec| !vec.is_empty())
-                    .unwrap_or_default();
-
-                let find_replacement_release = || {
-                    if bank == 128 {
-                        self.soundfonts
-                            .iter()
-                            .map(|sf| sf.get_release_voice_spawners_at(bank, 0, k, v))
-                            .find(|vec| !vec.is_empty())
-                    } else {
-                        self.soundfonts
-                            .iter()
-                            .map(|sf| sf.get_release_voice_spawners_at(0, preset, k, v))
-                            .find(|vec| !vec.is_empty())
-                    }
-                };
-
-                let release_spawners = self
-                    .soundfonts
-                    .iter()
-                    .map(|sf| sf.get_release_voice_spawners_at(bank, preset, k, v))
-                    .chain(iter::once_with(find_replacement_release).flatten())
-                    .find(|vec| !vec.is_empty())
-                    .unwrap_or_default();
+                let attack_spawners = self.attack_sources.resolve(&self.soundfonts, program, k, v);
+                let release_spawners =
+                    self.release_sources
+                        .resolve(&self.soundfonts, program, k, v);
 
                 self.matrix.set_spawners_attack(k, v, attack_spawners);
                 self.matrix.set_spawners_release(k, v, release_spawners);
@@ -113,21 +179,321 @@ impl ChannelSoundfont {
         }
     }
 
+    /// Spawns the attack voices of a key/velocity pair. Each call counts
+    /// as a note-on of the key, advancing its round-robin sequence.
     pub fn spawn_voices_attack<'a>(
         &'a self,
         control: &'a VoiceControlData,
         key: u8,
         vel: u8,
     ) -> impl Iterator<Item = Box<dyn Voice>> + 'a {
-        self.matrix.spawn_voices_attack(control, key, vel)
+        let counter = self.sequence_counters[key as usize].fetch_add(1, Ordering::Relaxed);
+        self.matrix.spawn_voices_attack(control, key, vel, counter)
     }
 
-    pub fn spawn_voices_release<'a>(
-        &'a self,
-        control: &'a VoiceControlData,
+    /// Returns the position in the soundfont list of the soundfont which
+    /// the attack voices of a key/velocity pair are spawned from.
+    pub fn attack_source(&self, key: u8, vel: u8) -> Option<usize> {
+        self.attack_sources.source_index(key, vel)
+    }
+
+    /// Spawns the release voices of a key/velocity pair for the program
+    /// that was active when the released voices were spawned. The matrix
+    /// is only used if it still holds that program.
+    ///
+    /// Returns the position of the soundfont the voices were spawned
+    /// from, along with the voices.
+    pub fn spawn_voices_release(
+        &self,
+        control: &VoiceControlData,
+        program: ProgramDescriptor,
         key: u8,
         vel: u8,
-    ) -> impl Iterator<Item = Box<dyn Voice>> + 'a {
-        self.matrix.spawn_voices_release(control, key, vel)
+    ) -> (Option<usize>, Vec<Box<dyn Voice>>) {
+        if program == self.curr_program {
+            let voices = self
+                .matrix
+                .spawn_voices_release(control, key, vel)
+                .collect();
+            (self.release_sources.source_index(key, vel), voices)
+        } else {
+            let spawners = self
+                .release_sources
+                .find(&self.soundfonts, program, key, vel);
+            spawners.map_or_else(Default::default, |(source, spawners)| {
+                let voices = spawners.iter().map(|s| s.spawn_voice(control)).collect();
+                (Some(source.index), voices)
+            })
+        }
+    }
+
+    /// Returns the number of soundfonts in the list.
+    pub fn soundfont_count(&self) -> usize {
+        self.soundfonts.len()
+    }
+
+    /// Returns the soundfont list.
+    pub fn soundfonts(&self) -> &[Arc<dyn SoundfontBase>] {
+        &self.soundfonts
+    }
+
+    /// Returns the program the voices are currently spawned with.
+    pub fn program(&self) -> ProgramDescriptor {
+        self.curr_program
+    }
+}
+
+impl SourceMap {
+    /// Searches a single soundfont for the spawners of a key/velocity pair.
+    fn lookup(
+        &self,
+        sf: &dyn SoundfontBase,
+        program: ProgramDescriptor,
+        replacement: bool,
+        key: u8,
+        vel: u8,
+    ) -> Spawners {
+        // If a preset/instr. is missing from all banks it will be muted,
+        // if a preset/instr. has regions in bank 0, all missing banks will be replaced by 0,
+        // if a preset/instr. has regions in any bank other than 0, all missing banks will be muted.
+        // For drum patches the same applies with bank and preset switched.
+        let ProgramDescriptor { bank, preset } = program;
+        if !replacement {
+            (self.get)(sf, bank, preset, key, vel)
+        } else if bank == 128 {
+            (self.get)(sf, bank, 0, key, vel)
+        } else {
+            (self.get)(sf, 0, preset, key, vel)
+        }
+    }
+
+    /// Searches the whole list for the spawners of a key/velocity pair,
+    /// without recording their source.
+    fn find(
+        &self,
+        soundfonts: &[Arc<dyn SoundfontBase>],
+        program: ProgramDescriptor,
+        key: u8,
+        vel: u8,
+    ) -> Option<(SpawnerSource, Spawners)> {
+        for replacement in [false, true] {
+            for (i, sf) in soundfonts.iter().enumerate() {
+                let spawners = self.lookup(sf.as_ref(), program, replacement, key, vel);
+                if !spawners.is_empty() {
+                    let source = SpawnerSource {
+                        index: i,
+                        replacement,
+                    };
+                    return Some((source, spawners));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Resolves the spawners of a key/velocity pair from the whole list.
+    fn resolve(
+        &mut self,
+        soundfonts: &[Arc<dyn SoundfontBase>],
+        program: ProgramDescriptor,
+        key: u8,
+        vel: u8,
+    ) -> Spawners {
+        let found = self.find(soundfonts, program, key, vel);
+        let (source, spawners) = found.unzip();
+        self.sources[Self::index(key, vel)] = source;
+        spawners.unwrap_or_default()
+    }
+
+    /// Updates the source of a key/velocity pair after a soundfont was
+    /// inserted at `position`. Returns the new spawners if they changed.
+    fn insert(
+        &mut self,
+        sf: &dyn SoundfontBase,
+        position: usize,
+        program: ProgramDescriptor,
+        key: u8,
+        vel: u8,
+    ) -> Option<Spawners> {
+        let index = Self::index(key, vel);
+        let mut source = self.sources[index];
+        if let Some(source) = &mut source {
+            if source.index >= position {
+                source.index += 1;
+            }
+        }
+        self.sources[index] = source;
+
+        // The new soundfont only takes priority over replacements and
+        // over the soundfonts after it
+        let outranks = |source: Option<SpawnerSource>, replacement: bool| match source {
+            None => true,
+            Some(source) => (source.replacement, source.index) > (replacement, position),
+        };
+
+        for replacement in [false, true] {
+            if !outranks(source, replacement) {
+                return None;
+            }
+
+            let spawners = self.lookup(sf, program, replacement, key, vel);
+            if !spawners.is_empty() {
+                self.sources[index] = Some(SpawnerSource {
+                    index: position,
+                    replacement,
+                });
+                return Some(spawners);
+            }
+        }
+
+        None
+    }
+
+    /// Updates the source of a key/velocity pair after the soundfont at
+    /// `position` was removed. Returns the new spawners if they changed.
+    fn remove(
+        &mut self,
+        soundfonts: &[Arc<dyn SoundfontBase>],
+        position: usize,
+        program: ProgramDescriptor,
+        key: u8,
+        vel: u8,
+    ) -> Option<Spawners> {
+        let index = Self::index(key, vel);
+        match &mut self.sources[index] {
+            Some(source) if source.index == position => {
+                Some(self.resolve(soundfonts, program, key, vel))
+            }
+            Some(source) if source.index > position => {
+                source.index -= 1;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        soundfont::{test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontInitOptions},
+        AudioStreamParams, ChannelCount,
+    };
+
+    fn load_sf(name: &str, opcodes: &str, bank: u8) -> Arc<dyn SoundfontBase> {
+        let dir = TestSoundfontDir::new(name);
+        dir.write_wav("sample.wav", 48000, &[0.5; 64]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            &format!("<region> sample=sample.wav {opcodes}\n"),
+        );
+
+        let options = SoundfontInitOptions {
+            bank: Some(bank),
+            ..Default::default()
+        };
+        let sf = SampleSoundfont::new_sfz(
+            sfz,
+            AudioStreamParams::new(48000, ChannelCount::Mono),
+            options,
+        )
+        .unwrap();
+        Arc::new(sf)
+    }
+
+    fn assert_same_resolution(channel_sf: &ChannelSoundfont) {
+        let mut rebuilt = ChannelSoundfont::new();
+        rebuilt.change_program(channel_sf.curr_program);
+        rebuilt.set_soundfonts(channel_sf.soundfonts.clone());
+
+        assert_eq!(
+            channel_sf.attack_sources.sources,
+            rebuilt.attack_sources.sources
+        );
+        let control = VoiceControlData::new_defaults();
+        for k in 0..128 {
+            for v in [0, 63, 64, 127] {
+                assert_eq!(
+                    channel_sf.spawn_voices_attack(&control, k, v).count(),
+                    rebuilt.spawn_voices_attack(&control, k, v).count()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_matches_rebuild() {
+        let soundfonts = [
+            load_sf("sf_low", "lokey=0 hikey=63", 0),
+            load_sf("sf_mid", "lokey=32 hikey=95", 0),
+            load_sf("sf_high_bank1", "lokey=60 hikey=127", 1),
+            load_sf("sf_high_vel", "lovel=64", 0),
+        ];
+
+        for bank in [0, 1] {
+            let mut channel_sf = ChannelSoundfont::new();
+            channel_sf.change_program(ProgramDescriptor { bank, preset: 0 });
+
+            channel_sf.add_soundfont(soundfonts[0].clone(), 0);
+            assert_same_resolution(&channel_sf);
+            channel_sf.add_soundfont(soundfonts[1].clone(), 0);
+            assert_same_resolution(&channel_sf);
+            channel_sf.add_soundfont(soundfonts[2].clone(), 1);
+            assert_same_resolution(&channel_sf);
+            channel_sf.add_soundfont(soundfonts[3].clone(), 10);
+            assert_same_resolution(&channel_sf);
+            assert_eq!(channel_sf.soundfonts.len(), 4);
+
+            channel_sf.remove_soundfont(0);
+            assert_same_resolution(&channel_sf);
+            channel_sf.remove_soundfont(10);
+            assert_same_resolution(&channel_sf);
+            channel_sf.remove_soundfont(1);
+            assert_same_resolution(&channel_sf);
+            channel_sf.remove_soundfont(0);
+            channel_sf.remove_soundfont(0);
+            assert_same_resolution(&channel_sf);
+            assert!(channel_sf.soundfonts.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let dir = TestSoundfontDir::new("round_robin");
+        let mut sfz = String::new();
+        for i in 1..=4 {
+            dir.write_wav(&format!("sample{i}.wav"), 48000, &[i as f32 / 10.0; 256]);
+            sfz += &format!("<region> sample=sample{i}.wav key=60 seq_length=4 seq_position={i}\n");
+        }
+        let sfz = dir.write_sfz("test.sfz", &sfz);
+        let sf = SampleSoundfont::new_sfz(
+            sfz,
+            AudioStreamParams::new(48000, ChannelCount::Mono),
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut channel_sf = ChannelSoundfont::new();
+        channel_sf.set_soundfonts(vec![Arc::new(sf)]);
+
+        let control = VoiceControlData::new_defaults();
+        let levels: Vec<f32> = (0..6)
+            .map(|_| {
+                let voices: Vec<_> = channel_sf.spawn_voices_attack(&control, 60, 127).collect();
+                assert_eq!(voices.len(), 1);
+
+                let mut out = vec![0.0; 128];
+                for mut voice in voices {
+                    voice.render_to(&mut out);
+                }
+                out[127]
+            })
+            .collect();
+
+        // The samples are scaled by their position in the sequence
+        let positions: Vec<f32> = levels.iter().map(|l| (l / levels[0]).round()).collect();
+        assert_eq!(positions, [1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
     }
 }
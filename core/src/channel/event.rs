@@ -1,16 +1,29 @@
 use std::sync::Arc;
 
-use crate::soundfont::SoundfontBase;
+use crate::{effects::EqParams, soundfont::SoundfontBase, voice::PortamentoControlData};
+
+/// The note off velocity which keeps the release time of the voices
+/// unchanged, used when the velocity of a release isn't known.
+pub const DEFAULT_NOTE_OFF_VELOCITY: u8 = 64;
+
+#[cfg(feature = "serde")]
+fn default_note_off_velocity() -> u8 {
+    DEFAULT_NOTE_OFF_VELOCITY
+}
 
 /// MIDI events for a single key in a channel.
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum KeyNoteEvent {
-    /// Starts a new note voice with a velocity
-    On(u8),
+    /// Starts a new note voice with a 16-bit MIDI 2.0 velocity
+    On(u16),
+
+    /// Starts a new note voice with a 16-bit MIDI 2.0 velocity, gliding
+    /// from the pitch of the previous note
+    OnGlide(u16, PortamentoControlData),
 
-    /// Signals off to a note voice
-    Off,
+    /// Signals off to a note voice with a MIDI note off velocity
+    Off(u8),
 
     /// Signals off to all note voices
     AllOff,
@@ -23,16 +36,156 @@ pub enum KeyNoteEvent {
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum ChannelConfigEvent {
-    /// Sets the soundfonts for the channel
+    /// Sets the soundfonts for the channel.
+    ///
+    /// Soundfonts must be loaded with the stream parameters of the channel.
+    /// The ones loaded with different parameters are skipped, and counted
+    /// by `VoiceChannelStatsReader::rejected_soundfonts`.
     #[cfg_attr(feature = "serde", serde(skip))]
     SetSoundfonts(Vec<Arc<dyn SoundfontBase>>),
 
+    /// Inserts a soundfont at the given position of the soundfont list.
+    /// The position is clamped to the length of the list. Unlike
+    /// `SetSoundfonts`, only the presets affected by the new soundfont
+    /// are resolved again. The soundfont is skipped if its stream parameters
+    /// don't match the channel, like in `SetSoundfonts`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    AddSoundfont {
+        sf: Arc<dyn SoundfontBase>,
+        position: usize,
+    },
+
+    /// Removes the soundfont at the given position of the soundfont list.
+    /// Does nothing if there is no soundfont at that position.
+    RemoveSoundfont { position: usize },
+
     /// Sets the layer count for the soundfont
     SetLayerCount(Option<usize>),
 
     /// Controls whether the channel will be standard or percussion.
     /// Setting to `true` will make the channel only use percussion patches.
+    ///
+    /// It also sets `SetIgnoreNoteOff` to the same value, as drums are
+    /// one-shots on GM devices. Send `SetIgnoreNoteOff` afterwards to
+    /// override it.
     SetPercussionMode(bool),
+
+    /// Controls whether the channel ignores the note offs and the damper
+    /// pedal (`CC64`), so that the notes play until their sample or envelope
+    /// ends, like the percussion channel of GM devices. The note offs still
+    /// release the voices with a looping sample, as they would never end
+    /// otherwise. `AllNotesOff` and `AllNotesKilled` still stop the notes,
+    /// and enabling it releases the damper pedal.
+    ///
+    /// Enabled by `SetPercussionMode(true)`, disabled by default otherwise.
+    SetIgnoreNoteOff(bool),
+
+    /// Sets the pan law used for the voices, panned by the channel pan
+    /// added to their own pan. See the `PanLaw`
+    /// documentation for the available options.
+    SetPanLaw(PanLaw),
+
+    /// Retunes keys of the channel. Each item is a key and its offset in
+    /// cents from 12-TET. The keys not in the list keep their tuning.
+    /// The tuning is combined with the fine/coarse tune and pitch bend,
+    /// and also applies to the notes that are already playing.
+    ///
+    /// See `key_tuning_from_scala` for loading the tuning from a scale.
+    SetKeyTuning(Vec<(u8, f32)>),
+
+    /// Remaps the keys of the incoming note events, e.g. to play MIDIs made
+    /// for drum kits with a nonstandard layout on a GM soundfont. Each item
+    /// of the map is the key played for the respective incoming key, and
+    /// `None` removes the map.
+    ///
+    /// If `percussion_only` is true, the map is only applied while the channel
+    /// uses the percussion bank. Note offs always release the key their note
+    /// on was mapped to, even if the map changed in between.
+    ///
+    /// See `helpers::xg_drum_key_map` for a built-in map.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    SetKeyMap {
+        map: Option<Box<[u8; 128]>>,
+        percussion_only: bool,
+    },
+
+    /// Transposes the note events of the channel by the given number of
+    /// semitones, after the key map. Notes transposed out of the 0-127 range
+    /// are dropped. Note offs release the key their note on was transposed
+    /// to, even if the transpose changed in between.
+    ///
+    /// The percussion bank is not transposed, unless enabled with
+    /// `ChannelInitOptions::transpose_percussion`.
+    SetTranspose(i8),
+
+    /// Restricts the note ons of the channel to the given inclusive range
+    /// of keys, before the key map and transpose. The note ons outside of
+    /// it are dropped with their note offs, even if the range changed in
+    /// between. The default range is `(0, 127)`, and ranges where the start
+    /// is after the end drop all notes.
+    SetKeyRange(u8, u8),
+
+    /// Restricts the note ons of the channel to the given inclusive range
+    /// of velocities, like `SetKeyRange`. High resolution velocities are
+    /// compared by their 7-bit velocity.
+    SetVelocityRange(u8, u8),
+
+    /// Sets the gain applied to the output of the channel, on top of the
+    /// volume and expression controllers. The gain is kept when the
+    /// controllers are reset. Sent to all the channels, it acts as the
+    /// master volume of the synthesizer.
+    ///
+    /// Changes are smoothed over 10ms to avoid zipper noise.
+    /// Negative values are treated as `0.0`.
+    SetMasterGain(f32),
+
+    /// Sets the frequency in Hz of A4 that the channel is tuned to, for
+    /// example `442.0` or `415.0`. It applies on top of the fine and coarse
+    /// tune of the channel, and to the active voices. The default is `440.0`,
+    /// and values that aren't positive are ignored.
+    ///
+    /// The percussion bank is also tuned, unless disabled with
+    /// `ChannelInitOptions::percussion_ignores_tuning`.
+    SetMasterTuning(f32),
+
+    /// Sets the parameters of the 3-band equalizer applied to the output
+    /// of the channel, after the volume and cutoff. The parameters are kept
+    /// when the controllers are reset. See the `EqParams` documentation
+    /// for more information.
+    SetEq(EqParams),
+}
+
+/// The pan law applied by a channel's pan control.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum PanLaw {
+    /// Linear gain crossfade. Each side is at -6dB when centered.
+    Linear,
+
+    /// Sine/cosine crossfade which keeps a constant power.
+    /// Each side is at -3dB when centered.
+    #[default]
+    EqualPower,
+
+    /// A compromise between the linear and equal power laws.
+    /// Each side is at -4.5dB when centered.
+    Compromise,
+}
+
+impl PanLaw {
+    /// Returns the left and right channel gains for a pan value,
+    /// where 0.0 is left, 0.5 is center and 1.0 is right.
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let angle = pan * std::f32::consts::PI / 2.0;
+        match self {
+            PanLaw::Linear => (1.0 - pan, pan),
+            PanLaw::EqualPower => (angle.cos().min(1.0), angle.sin().min(1.0)),
+            PanLaw::Compromise => (
+                ((1.0 - pan) * angle.cos()).max(0.0).sqrt(),
+                (pan * angle.sin()).max(0.0).sqrt(),
+            ),
+        }
+    }
 }
 
 /// MIDI events for a channel.
@@ -42,8 +195,23 @@ pub enum ChannelAudioEvent {
     /// Starts a new note voice
     NoteOn { key: u8, vel: u8 },
 
-    /// Signals off to a note voice
-    NoteOff { key: u8 },
+    /// Starts a new note voice with a 16-bit MIDI 2.0 velocity. A velocity
+    /// of 0 starts the note with the lowest velocity instead of stopping it.
+    NoteOnHighRes { key: u8, vel: u16 },
+
+    /// Signals off to a note voice. The velocity scales the release time
+    /// of the soundfont regions which track it (see the `ampeg_offveltrack`
+    /// SFZ opcode), and `DEFAULT_NOTE_OFF_VELOCITY` keeps it unchanged.
+    ///
+    /// A velocity of 0, sent by MIDI devices without release velocity, is
+    /// treated as `DEFAULT_NOTE_OFF_VELOCITY`, so the slowest release is at
+    /// velocity 1. This applies to every entry point, including the raw MIDI
+    /// events of the realtime synthesizer and the C API.
+    NoteOff {
+        key: u8,
+        #[cfg_attr(feature = "serde", serde(default = "default_note_off_velocity"))]
+        vel: u8,
+    },
 
     /// Signal off to all voices
     AllNotesOff,
@@ -97,3 +265,32 @@ pub enum ControlEvent {
     /// Coarse tune value in semitones
     CoarseTune(f32),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::db_to_amp;
+
+    fn assert_center_gain(law: PanLaw, db: f32) {
+        let (left, right) = law.gains(0.5);
+        assert!((left - db_to_amp(db)).abs() < 0.01, "{law:?}: {left}");
+        assert!((right - db_to_amp(db)).abs() < 0.01, "{law:?}: {right}");
+    }
+
+    #[test]
+    fn test_pan_law_center() {
+        assert_center_gain(PanLaw::Linear, -6.0);
+        assert_center_gain(PanLaw::EqualPower, -3.0);
+        assert_center_gain(PanLaw::Compromise, -4.5);
+    }
+
+    #[test]
+    fn test_pan_law_edges() {
+        for law in [PanLaw::Linear, PanLaw::EqualPower, PanLaw::Compromise] {
+            let (left, right) = law.gains(0.0);
+            assert!((left - 1.0).abs() < 1e-6 && right.abs() < 1e-6);
+            let (left, right) = law.gains(1.0);
+            assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+        }
+    }
+}
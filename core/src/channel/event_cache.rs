@@ -0,0 +1,285 @@
+use std::{collections::VecDeque, ops::Range};
+
+use super::event::KeyNoteEvent;
+
+/// The note events of a key waiting for the next render.
+///
+/// All the events are applied at the start of the render, so if the cache
+/// grows beyond its limit because nothing is rendered, it can be compacted
+/// while keeping mostly the same notes playing afterwards.
+pub(super) struct KeyEventCache {
+    events: Vec<KeyNoteEvent>,
+    /// The amount of leading note ons which were already deferred by a
+    /// previous render, so that each deferred note on is counted once
+    deferred_ons: usize,
+}
+
+fn is_note_on(event: &KeyNoteEvent) -> bool {
+    matches!(event, KeyNoteEvent::On(_) | KeyNoteEvent::OnGlide(..))
+}
+
+impl KeyEventCache {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            deferred_ons: 0,
+        }
+    }
+
+    /// Adds an event to the cache. If the cache grows beyond the limit,
+    /// it is compacted to half of it and the amount of dropped events is
+    /// returned.
+    pub fn push(&mut self, event: KeyNoteEvent, limit: usize) -> usize {
+        self.events.push(event);
+        if self.events.len() > limit {
+            self.compact(limit / 2)
+        } else {
+            0
+        }
+    }
+
+    /// Removes the first `count` events, or all of them if there are fewer.
+    pub fn drain(&mut self, count: usize) -> std::vec::Drain<'_, KeyNoteEvent> {
+        let count = count.min(self.events.len());
+        if count == self.events.len() {
+            self.deferred_ons = 0;
+        }
+        self.events.drain(..count)
+    }
+
+    /// Counts the leading events which fit in a budget of note ons, taking
+    /// their note ons from it. The events from the first note on beyond the
+    /// budget onwards are kept for a later render, so that the note offs
+    /// stay after the notes they release. Returns the amount of events which
+    /// fit, and the amount of note ons which didn't and weren't deferred
+    /// by a previous render already.
+    ///
+    /// The kills and releases aren't kept waiting behind the deferred note
+    /// ons: the events before the last `AllKilled` are dropped, and so are
+    /// the deferred note events before the last `AllOff`.
+    pub fn split_spawn_budget(&mut self, budget: &mut usize) -> (usize, usize) {
+        if let Some(pos) = self
+            .events
+            .iter()
+            .rposition(|e| *e == KeyNoteEvent::AllKilled)
+        {
+            let dropped = self.remove_events(0..pos);
+            self.deferred_ons = self.deferred_ons.saturating_sub(dropped);
+        }
+
+        // The note ons already counted by a previous render come first
+        let mut counted = self.deferred_ons;
+        let mut ready = None;
+        let mut deferred = 0;
+        let mut new_deferred = 0;
+        let mut i = 0;
+        while i < self.events.len() {
+            if is_note_on(&self.events[i]) {
+                if ready.is_none() && *budget == 0 {
+                    if let Some(pos) = self.events[i..]
+                        .iter()
+                        .rposition(|e| *e == KeyNoteEvent::AllOff)
+                    {
+                        let dropped = self.remove_events(i..i + pos);
+                        counted = counted.saturating_sub(dropped);
+                        continue;
+                    }
+                    ready = Some(i);
+                }
+
+                if ready.is_some() {
+                    deferred += 1;
+                    if counted > 0 {
+                        counted -= 1;
+                    } else {
+                        new_deferred += 1;
+                    }
+                } else {
+                    *budget -= 1;
+                    counted = counted.saturating_sub(1);
+                }
+            }
+            i += 1;
+        }
+
+        self.deferred_ons = deferred;
+        (ready.unwrap_or(self.events.len()), new_deferred)
+    }
+
+    /// Removes a range of events, and returns the amount of note ons in it.
+    fn remove_events(&mut self, range: Range<usize>) -> usize {
+        self.events.drain(range).filter(is_note_on).count()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.deferred_ons = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Reduces the cache to about `target` events, and returns the amount
+    /// of dropped events.
+    ///
+    /// First, the events before the last `AllKilled` are dropped, as well as
+    /// the note ons released within the cache, along with their note off. The
+    /// releases which have nothing left to release are dropped too. If there
+    /// are still too many events, the oldest note ons and offs are dropped.
+    /// `AllOff` and `AllKilled` events are only dropped when they are redundant.
+    fn compact(&mut self, target: usize) -> usize {
+        let len = self.events.len();
+
+        if let Some(pos) = self
+            .events
+            .iter()
+            .rposition(|e| *e == KeyNoteEvent::AllKilled)
+        {
+            self.events.drain(..pos);
+        }
+
+        let mut keep = vec![true; self.events.len()];
+        let mut pending_ons = VecDeque::new();
+        let mut all_released = false;
+        for (i, event) in self.events.iter().enumerate() {
+            match event {
+                KeyNoteEvent::On(_) | KeyNoteEvent::OnGlide(..) => {
+                    pending_ons.push_back(i);
+                    all_released = false;
+                }
+                KeyNoteEvent::Off(_) => {
+                    // The voices are released in order, so the off releases
+                    // the oldest note on
+                    if let Some(on) = pending_ons.pop_front() {
+                        keep[on] = false;
+                        keep[i] = false;
+                    } else if all_released {
+                        keep[i] = false;
+                    }
+                }
+                KeyNoteEvent::AllOff => {
+                    if all_released {
+                        keep[i] = false;
+                    }
+                    for on in pending_ons.drain(..) {
+                        keep[on] = false;
+                    }
+                    all_released = true;
+                }
+                KeyNoteEvent::AllKilled => {}
+            }
+        }
+
+        let mut excess = keep.iter().filter(|k| **k).count().saturating_sub(target);
+        let mut keep = keep.into_iter();
+        self.events.retain(|event| {
+            let keep = keep.next().unwrap();
+            if !keep {
+                return false;
+            }
+
+            let is_note = matches!(
+                event,
+                KeyNoteEvent::On(_) | KeyNoteEvent::OnGlide(..) | KeyNoteEvent::Off(_)
+            );
+            if is_note && excess > 0 {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+
+        let ons = self.events.iter().filter(|e| is_note_on(e)).count();
+        self.deferred_ons = self.deferred_ons.min(ons);
+
+        len - self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyNoteEvent::*;
+
+    fn compacted(events: &[KeyNoteEvent], target: usize) -> (Vec<KeyNoteEvent>, usize) {
+        let mut cache = KeyEventCache::new();
+        cache.events = events.to_vec();
+        let dropped = cache.compact(target);
+        (cache.events, dropped)
+    }
+
+    #[test]
+    fn test_compact_released_notes() {
+        // The remaining off releases a note played before the events
+        let (events, dropped) = compacted(&[On(10), Off(64), On(20), Off(64), Off(64), On(30)], 10);
+        assert_eq!(events, [Off(64), On(30)]);
+        assert_eq!(dropped, 4);
+
+        let (events, _) = compacted(
+            &[On(10), AllOff, Off(64), AllOff, On(20), Off(64), On(30)],
+            10,
+        );
+        assert_eq!(events, [AllOff, On(30)]);
+    }
+
+    #[test]
+    fn test_compact_kills_and_excess() {
+        let (events, _) = compacted(&[On(10), AllOff, On(20), AllKilled, On(30)], 10);
+        assert_eq!(events, [AllKilled, On(30)]);
+
+        let (events, dropped) = compacted(&[On(10), On(20), AllKilled, On(30), On(40), On(50)], 2);
+        assert_eq!(events, [AllKilled, On(50)]);
+        assert_eq!(dropped, 4);
+    }
+
+    #[test]
+    fn test_split_spawn_budget() {
+        let mut cache = KeyEventCache::new();
+        cache.events = vec![Off(64), On(10), Off(64), On(20), AllOff, On(30)];
+
+        // The note on released by the AllOff is dropped instead of deferred
+        let mut budget = 1;
+        assert_eq!(cache.split_spawn_budget(&mut budget), (4, 1));
+        assert_eq!(budget, 0);
+        assert_eq!(
+            cache.drain(4).collect::<Vec<_>>(),
+            [Off(64), On(10), Off(64), AllOff]
+        );
+        assert_eq!(cache.events, [On(30)]);
+
+        // The note on deferred before isn't counted again
+        cache.events.extend([On(40), Off(64)]);
+        let mut budget = 0;
+        assert_eq!(cache.split_spawn_budget(&mut budget), (0, 1));
+
+        let mut budget = 5;
+        assert_eq!(cache.split_spawn_budget(&mut budget), (3, 0));
+        assert_eq!(budget, 3);
+    }
+
+    #[test]
+    fn test_split_spawn_budget_kills() {
+        let mut cache = KeyEventCache::new();
+        cache.events = vec![On(10), On(20), Off(64), AllKilled, On(30), On(40)];
+
+        // The events before the kill are dropped, so it applies right away
+        let mut budget = 1;
+        assert_eq!(cache.split_spawn_budget(&mut budget), (2, 1));
+        assert_eq!(cache.drain(2).collect::<Vec<_>>(), [AllKilled, On(30)]);
+        assert_eq!(cache.events, [On(40)]);
+
+        // Killing the deferred note on drops it from the count
+        cache.events.push(AllKilled);
+        let mut budget = 0;
+        assert_eq!(cache.split_spawn_budget(&mut budget), (1, 0));
+        cache.events.push(On(50));
+        cache.drain(1);
+        assert_eq!(cache.split_spawn_budget(&mut budget), (0, 1));
+    }
+}
@@ -3,8 +3,13 @@ use std::sync::{
     Arc,
 };
 
+use crate::helpers::{splitmix64, velocity_from_16bit, velocity_to_16bit};
+
 use super::{
-    channel_sf::ChannelSoundfont, event::KeyNoteEvent, voice_buffer::VoiceBuffer,
+    channel_sf::{ChannelSoundfont, ProgramDescriptor},
+    event::{KeyNoteEvent, DEFAULT_NOTE_OFF_VELOCITY},
+    params::SharedKeyOccupancy,
+    voice_buffer::VoiceBuffer,
     ChannelInitOptions, VoiceControlData,
 };
 
@@ -13,12 +18,37 @@ pub struct KeyData {
     voices: VoiceBuffer,
     last_voice_count: usize,
     shared_voice_counter: Arc<AtomicU64>,
+    key_occupancy: Arc<SharedKeyOccupancy>,
+    /// The sounding and unreleased state last stored in `key_occupancy`
+    last_occupancy: (bool, bool),
+    tuning: f32,
+    /// The state of the generator of the voice random values
+    random: u64,
+    /// Whether the note offs only release the looping voices
+    ignore_note_off: bool,
+}
+
+/// Splits a 16-bit note velocity into the 7-bit velocity used to select
+/// the voices, and the voice control data with the exact velocity when
+/// it falls between two 7-bit velocities.
+fn split_velocity(vel: u16, control: &VoiceControlData) -> (u8, VoiceControlData) {
+    let coarse = (vel >> 9) as u8;
+    if velocity_to_16bit(coarse) == vel {
+        return (coarse, *control);
+    }
+
+    let control = VoiceControlData {
+        velocity: Some(velocity_from_16bit(vel)),
+        ..*control
+    };
+    (coarse.max(1), control)
 }
 
 impl KeyData {
     pub fn new(
         key: u8,
         shared_voice_counter: Arc<AtomicU64>,
+        key_occupancy: Arc<SharedKeyOccupancy>,
         options: ChannelInitOptions,
     ) -> KeyData {
         KeyData {
@@ -26,6 +56,11 @@ impl KeyData {
             voices: VoiceBuffer::new(options),
             last_voice_count: 0,
             shared_voice_counter,
+            key_occupancy,
+            last_occupancy: (false, false),
+            tuning: 1.0,
+            random: key as u64,
+            ignore_note_off: false,
         }
     }
 
@@ -36,22 +71,43 @@ impl KeyData {
         channel_sf: &ChannelSoundfont,
         max_layers: Option<usize>,
     ) {
+        let mut control = self.tuned_control(control);
+        control.random = splitmix64(&mut self.random);
+        let control = &control;
         match event {
             KeyNoteEvent::On(vel) => {
+                let (vel, control) = split_velocity(vel, control);
+                let control = &control;
                 let voices = channel_sf.spawn_voices_attack(control, self.key, vel);
-                self.voices.push_voices(voices, max_layers);
+                let soundfont = channel_sf.attack_source(self.key, vel);
+                self.voices
+                    .push_voices(voices, channel_sf.program(), soundfont, max_layers);
+            }
+            KeyNoteEvent::OnGlide(vel, portamento) => {
+                let (vel, control) = split_velocity(vel, control);
+                let control = VoiceControlData {
+                    portamento: Some(portamento),
+                    ..control
+                };
+                let voices = channel_sf.spawn_voices_attack(&control, self.key, vel);
+                let soundfont = channel_sf.attack_source(self.key, vel);
+                self.voices
+                    .push_voices(voices, channel_sf.program(), soundfont, max_layers);
             }
-            KeyNoteEvent::Off => {
-                let vel = self.voices.release_next_voice();
-                if let Some(vel) = vel {
-                    let voices = channel_sf.spawn_voices_release(control, self.key, vel);
-                    self.voices.push_voices(voices, max_layers);
+            KeyNoteEvent::Off(off_vel) => {
+                let released = self
+                    .voices
+                    .release_next_voice(off_vel, self.ignore_note_off);
+                if let Some((vel, program)) = released {
+                    self.spawn_release(control, channel_sf, vel, program, max_layers);
                 }
             }
             KeyNoteEvent::AllOff => {
-                while let Some(vel) = self.voices.release_next_voice() {
-                    let voices = channel_sf.spawn_voices_release(control, self.key, vel);
-                    self.voices.push_voices(voices, max_layers);
+                while let Some((vel, program)) = self
+                    .voices
+                    .release_next_voice(DEFAULT_NOTE_OFF_VELOCITY, false)
+                {
+                    self.spawn_release(control, channel_sf, vel, program, max_layers);
                 }
             }
             KeyNoteEvent::AllKilled => {
@@ -60,7 +116,34 @@ impl KeyData {
         }
     }
 
+    /// Spawns the release voices of a note, using the program its
+    /// voices were spawned with rather than the current one.
+    fn spawn_release(
+        &mut self,
+        control: &VoiceControlData,
+        channel_sf: &ChannelSoundfont,
+        vel: u8,
+        program: ProgramDescriptor,
+        max_layers: Option<usize>,
+    ) {
+        let control = &VoiceControlData {
+            random: splitmix64(&mut self.random),
+            ..*control
+        };
+        let (soundfont, voices) = channel_sf.spawn_voices_release(control, program, self.key, vel);
+        self.voices
+            .push_voices(voices.into_iter(), program, soundfont, max_layers);
+    }
+
+    /// Adds the voice count of each soundfont to the respective item of `counts`.
+    pub fn add_soundfont_voice_counts(&self, counts: &mut [u64]) {
+        if self.has_voices() {
+            self.voices.add_soundfont_voice_counts(counts);
+        }
+    }
+
     pub fn process_controls(&mut self, control: &VoiceControlData) {
+        let control = &self.tuned_control(control);
         for voice in &mut self.voices.iter_voices_mut() {
             voice.process_controls(control);
         }
@@ -84,13 +167,49 @@ impl KeyData {
                 .fetch_add(change as u64, Ordering::SeqCst);
         }
         self.last_voice_count = voice_count;
+
+        let occupancy = (voice_count > 0, self.voices.has_unreleased_voices());
+        if occupancy != self.last_occupancy {
+            self.key_occupancy.set(self.key, occupancy.0, occupancy.1);
+            self.last_occupancy = occupancy;
+        }
+    }
+
+    /// Returns the pitch multiplier of the key's tuning.
+    pub fn tuning(&self) -> f32 {
+        self.tuning
+    }
+
+    /// Sets the pitch multiplier of the key's tuning. The voices
+    /// only use it after `process_controls` is called.
+    pub fn set_tuning(&mut self, tuning: f32) {
+        self.tuning = tuning;
+    }
+
+    /// Applies the tuning of the key to the control data of the channel.
+    fn tuned_control(&self, control: &VoiceControlData) -> VoiceControlData {
+        VoiceControlData {
+            voice_pitch_multiplier: control.voice_pitch_multiplier * self.tuning,
+            ..*control
+        }
     }
 
     pub fn has_voices(&self) -> bool {
         self.voices.has_voices()
     }
 
+    #[cfg(test)]
+    pub fn voice_count(&self) -> usize {
+        self.voices.voice_count()
+    }
+
     pub fn set_damper(&mut self, damper: bool) {
         self.voices.set_damper(damper);
     }
+
+    /// Sets whether the note offs only release the looping voices, letting
+    /// the others play until they end by themselves.
+    pub fn set_ignore_note_off(&mut self, ignore: bool) {
+        self.ignore_note_off = ignore;
+    }
 }
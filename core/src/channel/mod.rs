@@ -1,15 +1,23 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::{
+    ops::RangeInclusive,
+    sync::{atomic::AtomicU64, Arc},
+};
 
 use crate::{
-    effects::MultiChannelBiQuad,
-    helpers::{db_to_amp, prepapre_cache_vec, sum_simd, FREQS},
-    voice::VoiceControlData,
+    effects::{Equalizer, MultiChannelBiQuad},
+    helpers::{db_to_amp, prepapre_cache_vec, sum_simd, velocity_to_16bit, FREQS},
+    voice::{EnvelopeControlData, PortamentoControlData, VoiceControlData},
     AudioStreamParams, ChannelCount,
 };
 
 use xsynth_soundfonts::FilterType;
 
-use self::{key::KeyData, params::VoiceChannelParams};
+use self::{
+    channel_sf::ProgramDescriptor,
+    event_cache::KeyEventCache,
+    key::KeyData,
+    params::{SharedKeyOccupancy, VoiceChannelParams, VoiceChannelStats},
+};
 
 use super::AudioPipe;
 
@@ -18,6 +26,7 @@ use biquad::Q_BUTTERWORTH_F32;
 use rayon::prelude::*;
 
 mod channel_sf;
+mod event_cache;
 mod key;
 mod params;
 mod voice_buffer;
@@ -26,8 +35,16 @@ mod voice_spawner;
 mod event;
 pub use event::*;
 
-pub use params::VoiceChannelStatsReader;
+mod tuning;
+pub use tuning::*;
+
+pub use params::{KeyOccupancy, VoiceChannelStatsReader};
+
+/// The level (about -180 dB) below which the effect tail of a channel with
+/// no voices is considered over.
+const SILENCE_THRESHOLD: f32 = 1e-9;
 
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct ValueLerp {
     lerp_length: f32,
     step: f32,
@@ -50,6 +67,10 @@ impl ValueLerp {
         self.end = end;
     }
 
+    pub fn is_settled(&self) -> bool {
+        self.current == self.end
+    }
+
     pub fn get_next(&mut self) -> f32 {
         if self.end > self.current {
             self.current = (self.current + self.step).min(self.end);
@@ -63,19 +84,39 @@ impl ValueLerp {
 struct Key {
     data: KeyData,
     audio_cache: Vec<f32>,
-    event_cache: Vec<KeyNoteEvent>,
+    event_cache: KeyEventCache,
+
+    /// The amount of cached events applied on the next render, see
+    /// `ChannelInitOptions::max_spawns_per_quantum`
+    ready_events: usize,
 }
 
 impl Key {
-    pub fn new(key: u8, shared_voice_counter: Arc<AtomicU64>, options: ChannelInitOptions) -> Self {
+    pub fn new(
+        key: u8,
+        shared_voice_counter: Arc<AtomicU64>,
+        key_occupancy: Arc<SharedKeyOccupancy>,
+        options: ChannelInitOptions,
+    ) -> Self {
         Key {
-            data: KeyData::new(key, shared_voice_counter, options),
+            data: KeyData::new(key, shared_voice_counter, key_occupancy, options),
             audio_cache: Vec::new(),
-            event_cache: Vec::new(),
+            event_cache: KeyEventCache::new(),
+            ready_events: usize::MAX,
+        }
+    }
+
+    /// Caches an event for the next render, counting the events dropped
+    /// if the cache grows beyond its limit.
+    fn push_event(&mut self, event: KeyNoteEvent, limit: usize, stats: &VoiceChannelStats) {
+        let dropped = self.event_cache.push(event, limit);
+        if dropped > 0 {
+            stats.add_dropped_events(dropped as u64);
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 struct ControlEventData {
     selected_lsb: i8,
     selected_msb: i8,
@@ -88,32 +129,56 @@ struct ControlEventData {
     fine_tune_value: f32,
     coarse_tune_value: f32,
     volume: ValueLerp, // 0.0 = silent, 1.0 = max volume
-    pan: ValueLerp,    // 0.0 = left, 0.5 = center, 1.0 = right
     cutoff: Option<f32>,
     resonance: Option<f32>,
     expression: ValueLerp,
+    portamento: bool,
+    portamento_time: u8,
 }
 
 impl ControlEventData {
-    pub fn new_defaults(sample_rate: u32) -> Self {
+    pub fn new_defaults(sample_rate: u32, pitch_bend_range: f32) -> Self {
+        let pitch_bend_range = pitch_bend_range.clamp(0.0, 127.99);
         ControlEventData {
             selected_lsb: -1,
             selected_msb: -1,
-            pitch_bend_sensitivity_lsb: 0,
-            pitch_bend_sensitivity_msb: 2,
-            pitch_bend_sensitivity: 2.0,
+            pitch_bend_sensitivity_lsb: (pitch_bend_range.fract() * 100.0).round() as u8,
+            pitch_bend_sensitivity_msb: pitch_bend_range.trunc() as u8,
+            pitch_bend_sensitivity: pitch_bend_range,
             pitch_bend_value: 0.0,
             fine_tune_lsb: 0,
             fine_tune_msb: 0,
             fine_tune_value: 0.0,
             coarse_tune_value: 0.0,
             volume: ValueLerp::new(1.0, sample_rate),
-            pan: ValueLerp::new(0.5, sample_rate),
             cutoff: None,
             resonance: None,
             expression: ValueLerp::new(1.0, sample_rate),
+            portamento: false,
+            portamento_time: 0,
         }
     }
+
+    /// Returns a copy with the smoothed values at their target values.
+    fn settled(&self, sample_rate: u32) -> Self {
+        Self {
+            volume: ValueLerp::new(self.volume.end, sample_rate),
+            expression: ValueLerp::new(self.expression.end, sample_rate),
+            ..self.clone()
+        }
+    }
+}
+
+/// The state of the controllers and the program of a VoiceChannel,
+/// captured with `VoiceChannel::controller_state`.
+///
+/// The voices and the sustain pedal are not part of the state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelControllerState {
+    control: ControlEventData,
+    pan: f32,
+    envelope: EnvelopeControlData,
+    program: ProgramDescriptor,
 }
 
 /// Options for initializing a new VoiceChannel.
@@ -130,6 +195,60 @@ pub struct ChannelInitOptions {
     ///
     /// Default: `false`
     pub fade_out_killing: bool,
+
+    /// The length of the fade out in seconds of the voices killed due to
+    /// the voice limit, if `fade_out_killing` is enabled. Longer fades
+    /// click less on low frequency sounds, but keep more voices active.
+    ///
+    /// Default: `0.001`
+    pub kill_fade_time: f32,
+
+    /// If set to true, `CC121` (Reset All Controllers) only resets the
+    /// controllers listed by the MIDI specification, keeping the volume,
+    /// pan and bank. If set to false, it resets the whole channel state,
+    /// like `ChannelAudioEvent::ResetControl`.
+    ///
+    /// Default: `true`
+    pub strict_cc121: bool,
+
+    /// If set to true, `ChannelConfigEvent::SetTranspose` also transposes
+    /// the notes played in the percussion bank.
+    ///
+    /// Default: `false`
+    pub transpose_percussion: bool,
+
+    /// If set to true, `ChannelConfigEvent::SetMasterTuning` doesn't apply
+    /// to the notes played in the percussion bank.
+    ///
+    /// Default: `false`
+    pub percussion_ignores_tuning: bool,
+
+    /// The pitch bend range in semitones that the channel starts with,
+    /// and returns to when its whole state is reset. It can be changed
+    /// with RPN 0 (Pitch Bend Sensitivity).
+    ///
+    /// Default: `2.0`
+    pub pitch_bend_range: f32,
+
+    /// The maximum amount of note events cached for each key until the
+    /// channel is rendered. Beyond it, the released notes are collapsed
+    /// and the oldest note events are dropped, which are counted by
+    /// `VoiceChannelStatsReader::dropped_events`. This only happens when
+    /// events are sent without rendering, for example if the audio
+    /// thread stalls.
+    ///
+    /// Default: `65536`
+    pub key_event_limit: usize,
+
+    /// The maximum amount of note ons which spawn voices in a single
+    /// render of the channel. The note events following the note ons
+    /// beyond it are kept in order for the next renders, so that large
+    /// chords are spread over a few renders instead of spawning all their
+    /// voices at once. The note ons deferred in each render are counted
+    /// by `VoiceChannelStatsReader::deferred_spawns`.
+    ///
+    /// Default: `None`
+    pub max_spawns_per_quantum: Option<usize>,
 }
 
 #[allow(clippy::derivable_impls)]
@@ -137,6 +256,13 @@ impl Default for ChannelInitOptions {
     fn default() -> Self {
         Self {
             fade_out_killing: false,
+            kill_fade_time: 0.001,
+            strict_cc121: true,
+            transpose_percussion: false,
+            percussion_ignores_tuning: false,
+            pitch_bend_range: 2.0,
+            key_event_limit: 65536,
+            max_spawns_per_quantum: None,
         }
     }
 }
@@ -146,20 +272,25 @@ impl Default for ChannelInitOptions {
 /// Keeps track and manages MIDI events and the active voices of a channel.
 ///
 /// MIDI CC Support Chart:
-/// - `CC0`: Bank Select
+/// - `CC0`: Bank Select (`120` and `127` select the percussion bank, as in GM2 and XG)
+/// - `CC5`: Portamento time (see `portamento_time`)
 /// - `CC6`, `CC38`, `CC100`, `CC101`: RPN & NRPN
 /// - `CC7`: Volume
 /// - `CC8`: Balance
 /// - `CC10`: Pan
 /// - `CC11`: Expression
-/// - `CC64`: Damper pedal
-/// - `CC71`: Cutoff resonance
+/// - `CC64`: Damper pedal, ignored with `ChannelConfigEvent::SetIgnoreNoteOff`
+/// - `CC65`: Portamento on/off, ignored in the percussion bank
+/// - `CC71`: Cutoff resonance, used while `CC74` lowers the cutoff
 /// - `CC72`: Release time multiplier
 /// - `CC73`: Attack time multiplier
 /// - `CC74`: Cutoff frequency
 /// - `CC120`: All sounds off
-/// - `CC121`: Reset all controllers
+/// - `CC121`: Reset all controllers (see `ChannelInitOptions::strict_cc121`)
 /// - `CC123`: All notes off
+///
+/// The cutoff of `CC74` is a low pass filter on the output of the channel,
+/// applied on top of the filters of the soundfont regions, whatever their type.
 pub struct VoiceChannel {
     key_voices: Vec<Key>,
 
@@ -168,14 +299,58 @@ pub struct VoiceChannel {
 
     stream_params: AudioStreamParams,
 
+    options: ChannelInitOptions,
+
     /// The helper struct for keeping track of MIDI control event data
     control_event_data: ControlEventData,
 
     /// Processed control data, ready to feed to voices
     voice_control_data: VoiceControlData,
 
+    /// The last key which received a note on, where portamento glides from
+    last_key: Option<u8>,
+
+    /// The key map set with `ChannelConfigEvent::SetKeyMap`
+    key_map: Option<Box<[u8; 128]>>,
+    key_map_percussion_only: bool,
+
+    /// The transpose in semitones set with `ChannelConfigEvent::SetTranspose`
+    transpose: i8,
+
+    /// Whether the note offs (except for looping voices) and damper pedal
+    /// are ignored, set with
+    /// `ChannelConfigEvent::SetIgnoreNoteOff`
+    ignore_note_off: bool,
+
+    /// The key range set with `ChannelConfigEvent::SetKeyRange`
+    key_range: RangeInclusive<u8>,
+
+    /// The velocity range set with `ChannelConfigEvent::SetVelocityRange`
+    velocity_range: RangeInclusive<u8>,
+
+    /// The key each incoming key was mapped to on its last note on, so the
+    /// note offs release the same key
+    note_on_keys: [u8; 128],
+
+    /// The gain set with `ChannelConfigEvent::SetMasterGain`
+    master_gain: ValueLerp,
+
+    /// The A4 frequency in Hz set with `ChannelConfigEvent::SetMasterTuning`
+    master_tuning: f32,
+
+    /// Buffer for counting the voices of each soundfont after rendering
+    soundfont_voice_counts: Vec<u64>,
+
+    /// The key which gets the spawn budget first on the next render, so
+    /// that the deferred note ons of every key get their turn
+    spawn_start_key: usize,
+
     /// Effects
     cutoff: MultiChannelBiQuad,
+    eq: Equalizer,
+
+    /// Whether the last render left the effects without a tail, see `is_idle`
+    effects_silent: bool,
 }
 
 impl VoiceChannel {
@@ -201,18 +376,45 @@ impl VoiceChannel {
 
         let params = VoiceChannelParams::new(stream_params);
         let shared_voice_counter = params.stats.voice_counter.clone();
+        let key_occupancy = params.stats.key_occupancy.clone();
 
         VoiceChannel {
             params,
-            key_voices: fill_key_array(|i| Key::new(i, shared_voice_counter.clone(), options)),
+            key_voices: fill_key_array(|i| {
+                Key::new(
+                    i,
+                    shared_voice_counter.clone(),
+                    key_occupancy.clone(),
+                    options,
+                )
+            }),
 
             threadpool,
 
             stream_params,
 
-            control_event_data: ControlEventData::new_defaults(stream_params.sample_rate),
+            options,
+
+            control_event_data: ControlEventData::new_defaults(
+                stream_params.sample_rate,
+                options.pitch_bend_range,
+            ),
             voice_control_data: VoiceControlData::new_defaults(),
 
+            last_key: None,
+
+            key_map: None,
+            key_map_percussion_only: false,
+            transpose: 0,
+            ignore_note_off: false,
+            key_range: 0..=127,
+            velocity_range: 0..=127,
+            note_on_keys: std::array::from_fn(|i| i as u8),
+            master_gain: ValueLerp::new(1.0, stream_params.sample_rate),
+            master_tuning: 440.0,
+            soundfont_voice_counts: Vec::new(),
+            spawn_start_key: 0,
+
             cutoff: MultiChannelBiQuad::new(
                 stream_params.channels.count() as usize,
                 FilterType::LowPass,
@@ -220,18 +422,53 @@ impl VoiceChannel {
                 stream_params.sample_rate as f32,
                 None,
             ),
+            eq: Equalizer::new(
+                stream_params.channels.count(),
+                stream_params.sample_rate,
+                Default::default(),
+            ),
+            effects_silent: true,
+        }
+    }
+
+    /// Returns true if the channel has nothing to render: no voices, no
+    /// pending note events, no volume or cutoff fades and no effect tail.
+    /// Idle channels output silence without rendering anything, and can be
+    /// skipped by the callers which mix several channels.
+    pub fn is_idle(&self) -> bool {
+        let control = &self.control_event_data;
+        self.effects_silent
+            && control.volume.is_settled()
+            && control.expression.is_settled()
+            && self.master_gain.is_settled()
+            && self.cutoff.is_settled()
+            && self
+                .key_voices
+                .iter()
+                .all(|key| !key.data.has_voices() && key.event_cache.is_empty())
+    }
+
+    /// Checks whether the effect tail ended after a render, flushing the
+    /// leftover filter state once the output is inaudible.
+    fn update_effects_silent(&mut self, out: &[f32]) {
+        self.effects_silent = out.iter().all(|s| s.abs() < SILENCE_THRESHOLD)
+            && !self.key_voices.iter().any(|key| key.data.has_voices());
+        if self.effects_silent {
+            self.cutoff.reset();
+            self.eq.reset();
         }
     }
 
     fn apply_channel_effects(&mut self, out: &mut [f32]) {
         let control = &mut self.control_event_data;
+        let master_gain = &mut self.master_gain;
 
         match self.stream_params.channels {
             ChannelCount::Mono => {
                 // Volume
                 for sample in out.iter_mut() {
                     let vol = control.volume.get_next() * control.expression.get_next();
-                    let vol = vol.powi(2);
+                    let vol = vol.powi(2) * master_gain.get_next();
                     *sample *= vol;
                 }
             }
@@ -239,17 +476,10 @@ impl VoiceChannel {
                 // Volume
                 for sample in out.chunks_mut(2) {
                     let vol = control.volume.get_next() * control.expression.get_next();
-                    let vol = vol.powi(2);
+                    let vol = vol.powi(2) * master_gain.get_next();
                     sample[0] *= vol;
                     sample[1] *= vol;
                 }
-
-                // Pan
-                for sample in out.chunks_mut(2) {
-                    let pan = control.pan.get_next();
-                    sample[0] *= ((pan * std::f32::consts::PI / 2.0).cos()).min(1.0);
-                    sample[1] *= ((pan * std::f32::consts::PI / 2.0).sin()).min(1.0);
-                }
             }
         }
 
@@ -259,10 +489,17 @@ impl VoiceChannel {
                 .set_filter_type(FilterType::LowPass, cutoff, control.resonance);
             self.cutoff.process(out);
         }
+
+        self.eq.process(out);
     }
 
     fn push_key_events_and_render(&mut self, out: &mut [f32]) {
-        self.params.load_program();
+        if self.params.load_program() && self.options.percussion_ignores_tuning {
+            // The master tuning depends on whether the percussion bank is used
+            self.process_pitch();
+        }
+
+        self.split_spawn_budget();
 
         out.fill(0.0);
         match self.threadpool.as_ref() {
@@ -273,7 +510,7 @@ impl VoiceChannel {
                 let control_data = &self.voice_control_data;
                 pool.install(|| {
                     key_voices.par_iter_mut().for_each(move |key| {
-                        for e in key.event_cache.drain(..) {
+                        for e in key.event_cache.drain(key.ready_events) {
                             key.data
                                 .send_event(e, control_data, &params.channel_sf, params.layers);
                         }
@@ -289,7 +526,7 @@ impl VoiceChannel {
             }
             None => {
                 for key in self.key_voices.iter_mut() {
-                    for e in key.event_cache.drain(..) {
+                    for e in key.event_cache.drain(key.ready_events) {
                         key.data.send_event(
                             e,
                             &self.voice_control_data,
@@ -303,7 +540,47 @@ impl VoiceChannel {
             }
         }
 
+        self.update_soundfont_voice_counts();
         self.apply_channel_effects(out);
+        self.update_effects_silent(out);
+    }
+
+    /// Sets the amount of events each key applies on the next render, within
+    /// `ChannelInitOptions::max_spawns_per_quantum`. The keys take from the
+    /// budget in turns, starting after the first key deferred last time.
+    fn split_spawn_budget(&mut self) {
+        let Some(mut budget) = self.options.max_spawns_per_quantum else {
+            return;
+        };
+
+        let start = self.spawn_start_key;
+        let mut deferred = 0;
+        let mut first_deferred = None;
+        for i in 0..self.key_voices.len() {
+            let index = (start + i) % self.key_voices.len();
+            let key = &mut self.key_voices[index];
+            let (ready, deferred_ons) = key.event_cache.split_spawn_budget(&mut budget);
+            key.ready_events = ready;
+            if deferred_ons > 0 {
+                deferred += deferred_ons;
+                first_deferred.get_or_insert(index);
+            }
+        }
+
+        if let Some(index) = first_deferred {
+            self.spawn_start_key = index;
+            self.params.stats.add_deferred_spawns(deferred as u64);
+        }
+    }
+
+    fn update_soundfont_voice_counts(&mut self) {
+        let counts = &mut self.soundfont_voice_counts;
+        counts.clear();
+        counts.resize(self.params.channel_sf.soundfont_count(), 0);
+        for key in self.key_voices.iter() {
+            key.data.add_soundfont_voice_counts(counts);
+        }
+        self.params.stats.store_soundfont_voices(counts);
     }
 
     fn propagate_voice_controls(&mut self) {
@@ -318,9 +595,17 @@ impl VoiceChannel {
         match event {
             ControlEvent::Raw(controller, value) => match controller {
                 0x00 => {
-                    // Bank select
+                    // Bank select MSB
                     self.params.set_bank(value);
                 }
+                0x05 => {
+                    // Portamento time
+                    self.control_event_data.portamento_time = value;
+                }
+                0x41 => {
+                    // Portamento on/off
+                    self.control_event_data.portamento = value >= 64;
+                }
                 0x64 => {
                     self.control_event_data.selected_lsb = value as i8;
                 }
@@ -369,13 +654,11 @@ impl VoiceChannel {
                                 let val = (val as f32 - 4096.0) / 4096.0 * 100.0;
                                 self.process_control_event(ControlEvent::FineTune(val));
                             }
-                            2 => {
+                            2 if controller == 0x06 => {
                                 // Coarse tune
-                                if controller == 0x06 {
-                                    self.process_control_event(ControlEvent::CoarseTune(
-                                        value as f32 - 64.0,
-                                    ))
-                                }
+                                self.process_control_event(ControlEvent::CoarseTune(
+                                    value as f32 - 64.0,
+                                ))
                             }
                             _ => {}
                         }
@@ -387,15 +670,16 @@ impl VoiceChannel {
                     self.control_event_data.volume.set_end(vol);
                 }
                 0x0A | 0x08 => {
-                    // Pan
-                    let pan: f32 = value as f32 / 128.0;
-                    self.control_event_data.pan.set_end(pan);
+                    // Pan, applied by the voices as it adds to their own pan
+                    self.voice_control_data.pan = value as f32 / 128.0;
+                    self.propagate_voice_controls();
                 }
                 0x0B => {
                     // Expression
                     let expr = value as f32 / 128.0;
                     self.control_event_data.expression.set_end(expr);
                 }
+                0x40 if self.ignore_note_off => {}
                 0x40 => {
                     // Damper / Sustain
                     let damper = match value {
@@ -444,23 +728,21 @@ impl VoiceChannel {
                         self.control_event_data.cutoff = None;
                     }
                 }
-                0x78 => {
+                0x78 if value == 0 => {
                     // All Sounds Off
-                    if value == 0 {
-                        self.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
-                    }
+                    self.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
                 }
-                0x79 => {
+                0x79 if value == 0 => {
                     // Reset All Controllers
-                    if value == 0 {
-                        self.reset_control();
+                    if self.options.strict_cc121 {
+                        self.reset_controllers_spec();
+                    } else {
+                        self.reset_all_state();
                     }
                 }
-                0x7B => {
+                0x7B if value == 0 => {
                     // All Notes Off
-                    if value == 0 {
-                        self.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesOff));
-                    }
+                    self.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesOff));
                 }
                 _ => {}
             },
@@ -502,10 +784,29 @@ impl VoiceChannel {
         let coarse_tune = data.coarse_tune_value;
         let combined = pitch_bend + coarse_tune + fine_tune / 100.0;
 
-        self.voice_control_data.voice_pitch_multiplier = 2.0f32.powf(combined / 12.0);
+        let percussion = self.params.program.bank == 128;
+        let tuning = if percussion && self.options.percussion_ignores_tuning {
+            1.0
+        } else {
+            self.master_tuning / 440.0
+        };
+
+        self.voice_control_data.voice_pitch_multiplier = 2.0f32.powf(combined / 12.0) * tuning;
         self.propagate_voice_controls();
     }
 
+    fn note_on(&mut self, key: u8, vel: u16) {
+        let key = self.map_note_on_key(key, (vel >> 9) as u8);
+        let portamento = self.next_portamento(key);
+        if let Some(key) = self.key_voices.get_mut(key as usize) {
+            let ev = match portamento {
+                Some(portamento) => KeyNoteEvent::OnGlide(vel, portamento),
+                None => KeyNoteEvent::On(vel),
+            };
+            key.push_event(ev, self.options.key_event_limit, &self.params.stats);
+        }
+    }
+
     /// Sends a ChannelEvent to the channel.
     /// See the `ChannelEvent` documentation for more information.
     pub fn process_event(&mut self, event: ChannelEvent) {
@@ -514,35 +815,44 @@ impl VoiceChannel {
 
     /// Sends multiple ChannelEvent items to the channel as an iterator.
     pub fn push_events_iter<T: Iterator<Item = ChannelEvent>>(&mut self, iter: T) {
+        let limit = self.options.key_event_limit;
         for e in iter {
             match e {
                 ChannelEvent::Audio(audio) => match audio {
                     ChannelAudioEvent::NoteOn { key, vel } => {
-                        if let Some(key) = self.key_voices.get_mut(key as usize) {
-                            let ev = KeyNoteEvent::On(vel);
-                            key.event_cache.push(ev);
-                        }
+                        self.note_on(key, velocity_to_16bit(vel));
+                    }
+                    ChannelAudioEvent::NoteOnHighRes { key, vel } => {
+                        // Unlike MIDI 1.0, a velocity of 0 isn't a note off
+                        self.note_on(key, vel.max(1));
                     }
-                    ChannelAudioEvent::NoteOff { key } => {
+                    ChannelAudioEvent::NoteOff { key, vel } => {
+                        // Sent by MIDI devices without release velocity
+                        let vel = if vel == 0 {
+                            DEFAULT_NOTE_OFF_VELOCITY
+                        } else {
+                            vel
+                        };
+                        let key = self.note_on_keys.get(key as usize).copied().unwrap_or(key);
                         if let Some(key) = self.key_voices.get_mut(key as usize) {
-                            let ev = KeyNoteEvent::Off;
-                            key.event_cache.push(ev);
+                            let ev = KeyNoteEvent::Off(vel);
+                            key.push_event(ev, limit, &self.params.stats);
                         }
                     }
                     ChannelAudioEvent::AllNotesOff => {
                         for key in self.key_voices.iter_mut() {
                             let ev = KeyNoteEvent::AllOff;
-                            key.event_cache.push(ev);
+                            key.push_event(ev, limit, &self.params.stats);
                         }
                     }
                     ChannelAudioEvent::AllNotesKilled => {
                         for key in self.key_voices.iter_mut() {
                             let ev = KeyNoteEvent::AllKilled;
-                            key.event_cache.push(ev);
+                            key.push_event(ev, limit, &self.params.stats);
                         }
                     }
                     ChannelAudioEvent::ResetControl => {
-                        self.reset_control();
+                        self.reset_all_state();
                     }
                     ChannelAudioEvent::Control(control) => {
                         self.process_control_event(control);
@@ -553,17 +863,132 @@ impl VoiceChannel {
                     ChannelAudioEvent::SystemReset => {
                         for key in self.key_voices.iter_mut() {
                             key.event_cache.clear();
-                            key.event_cache.push(KeyNoteEvent::AllKilled);
+                            key.push_event(KeyNoteEvent::AllKilled, limit, &self.params.stats);
                         }
-                        self.reset_control();
+                        self.reset_all_state();
                         self.reset_program();
+                        self.eq.reset();
                     }
                 },
+                ChannelEvent::Config(ChannelConfigEvent::SetKeyTuning(tuning)) => {
+                    self.set_key_tuning(&tuning);
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetKeyMap {
+                    map,
+                    percussion_only,
+                }) => {
+                    self.key_map = map;
+                    self.key_map_percussion_only = percussion_only;
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetTranspose(transpose)) => {
+                    self.transpose = transpose;
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetKeyRange(start, end)) => {
+                    self.key_range = start..=end;
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetVelocityRange(start, end)) => {
+                    self.velocity_range = start..=end;
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetMasterGain(gain)) => {
+                    self.master_gain.set_end(gain.max(0.0));
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetMasterTuning(hz)) => {
+                    if hz.is_finite() && hz > 0.0 {
+                        self.master_tuning = hz;
+                        self.process_pitch();
+                    }
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetEq(params)) => {
+                    self.eq.set_params(params);
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetPanLaw(law)) => {
+                    self.voice_control_data.pan_law = law;
+                    self.propagate_voice_controls();
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetIgnoreNoteOff(ignore)) => {
+                    self.set_ignore_note_off(ignore);
+                }
+                ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(set)) => {
+                    let event = ChannelConfigEvent::SetPercussionMode(set);
+                    self.params.process_config_event(event);
+                    self.set_ignore_note_off(set);
+                    if self.options.percussion_ignores_tuning {
+                        self.process_pitch();
+                    }
+                }
                 ChannelEvent::Config(config) => self.params.process_config_event(config),
             }
         }
     }
 
+    /// Returns the current state of the controllers and the program of the
+    /// channel, which can be restored with `set_controller_state`. Smoothed
+    /// values, like the volume, are captured at their target values.
+    pub fn controller_state(&self) -> ChannelControllerState {
+        ChannelControllerState {
+            control: self
+                .control_event_data
+                .settled(self.stream_params.sample_rate),
+            pan: self.voice_control_data.pan,
+            envelope: self.voice_control_data.envelope,
+            program: self.params.program,
+        }
+    }
+
+    /// Returns the config events which give a new channel the configuration
+    /// of this one: the soundfonts, layer count, percussion mode, whether
+    /// the note offs are ignored, pan law,
+    /// key tuning, key map, transpose, key and velocity ranges, master gain,
+    /// master tuning and equalizer. Sent before `set_controller_state`, they
+    /// let another channel continue with the setup of this one.
+    ///
+    /// The master gain fades to its value on the new channel, like when it
+    /// is changed with `ChannelConfigEvent::SetMasterGain`.
+    pub fn config_events(&self) -> Vec<ChannelConfigEvent> {
+        let key_tuning = self
+            .key_voices
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| key.data.tuning() != 1.0)
+            .map(|(i, key)| (i as u8, key.data.tuning().log2() * 1200.0))
+            .collect();
+
+        vec![
+            ChannelConfigEvent::SetSoundfonts(self.params.channel_sf.soundfonts().to_vec()),
+            ChannelConfigEvent::SetLayerCount(self.params.layers),
+            ChannelConfigEvent::SetPercussionMode(self.params.percussion_mode),
+            ChannelConfigEvent::SetIgnoreNoteOff(self.ignore_note_off),
+            ChannelConfigEvent::SetPanLaw(self.voice_control_data.pan_law),
+            ChannelConfigEvent::SetKeyTuning(key_tuning),
+            ChannelConfigEvent::SetKeyMap {
+                map: self.key_map.clone(),
+                percussion_only: self.key_map_percussion_only,
+            },
+            ChannelConfigEvent::SetTranspose(self.transpose),
+            ChannelConfigEvent::SetKeyRange(*self.key_range.start(), *self.key_range.end()),
+            ChannelConfigEvent::SetVelocityRange(
+                *self.velocity_range.start(),
+                *self.velocity_range.end(),
+            ),
+            ChannelConfigEvent::SetMasterGain(self.master_gain.end),
+            ChannelConfigEvent::SetMasterTuning(self.master_tuning),
+            ChannelConfigEvent::SetEq(self.eq.params()),
+        ]
+    }
+
+    /// Restores a state captured with `controller_state`. The values apply
+    /// immediately, and the active voices keep playing with them.
+    ///
+    /// Channels in percussion mode keep the percussion bank.
+    pub fn set_controller_state(&mut self, state: &ChannelControllerState) {
+        self.control_event_data = state.control.settled(self.stream_params.sample_rate);
+        self.voice_control_data.pan = state.pan;
+        self.voice_control_data.envelope = state.envelope;
+        self.params.set_program(state.program);
+        self.params.load_program();
+        self.process_pitch();
+    }
+
     /// Returns a reader for the VoiceChannel statistics.
     /// See the `VoiceChannelStatsReader` documentation for more information.
     pub fn get_channel_stats(&self) -> VoiceChannelStatsReader {
@@ -571,9 +996,114 @@ impl VoiceChannel {
         VoiceChannelStatsReader::new(stats)
     }
 
-    fn reset_control(&mut self) {
-        self.control_event_data = ControlEventData::new_defaults(self.stream_params.sample_rate);
-        self.voice_control_data = VoiceControlData::new_defaults();
+    /// Returns the keys of the channel which have voices.
+    /// See `VoiceChannelStatsReader::key_occupancy` for more information.
+    pub fn key_occupancy(&self) -> KeyOccupancy {
+        self.params.stats.key_occupancy.load()
+    }
+
+    fn set_ignore_note_off(&mut self, ignore: bool) {
+        self.ignore_note_off = ignore;
+        for key in self.key_voices.iter_mut() {
+            key.data.set_ignore_note_off(ignore);
+            if ignore {
+                // The damper could otherwise never be released
+                key.data.set_damper(false);
+            }
+        }
+    }
+
+    fn set_key_tuning(&mut self, tuning: &[(u8, f32)]) {
+        for &(key, cents) in tuning {
+            if let Some(key) = self.key_voices.get_mut(key as usize) {
+                key.data.set_tuning(2.0f32.powf(cents / 1200.0));
+                key.data.process_controls(&self.voice_control_data);
+            }
+        }
+    }
+
+    /// Returns the key played for a note on of the given key, and keeps it
+    /// for the matching note off. Notes outside of the key and velocity
+    /// ranges, and keys transposed out of range are returned as `u8::MAX`,
+    /// so both the note on and off are dropped.
+    fn map_note_on_key(&mut self, key: u8, vel: u8) -> u8 {
+        let Some(slot) = self.note_on_keys.get_mut(key as usize) else {
+            return key;
+        };
+
+        if !self.key_range.contains(&key) || !self.velocity_range.contains(&vel) {
+            *slot = u8::MAX;
+            return *slot;
+        }
+
+        let percussion = self.params.program.bank == 128;
+        let mapped = match &self.key_map {
+            Some(map) if !self.key_map_percussion_only || percussion => map[key as usize],
+            _ => key,
+        };
+
+        let transpose = if !percussion || self.options.transpose_percussion {
+            self.transpose
+        } else {
+            0
+        };
+        let transposed = mapped as i16 + transpose as i16;
+
+        *slot = if (0..128).contains(&transposed) {
+            transposed as u8
+        } else {
+            u8::MAX
+        };
+        *slot
+    }
+
+    /// Returns the pitch glide of a new note if portamento is enabled, and
+    /// keeps its key as the starting point of the next one.
+    fn next_portamento(&mut self, key: u8) -> Option<PortamentoControlData> {
+        if key > 127 {
+            return None;
+        }
+        let last_key = self.last_key.replace(key)?;
+
+        let data = &self.control_event_data;
+        if !data.portamento || self.params.program.bank == 128 || last_key == key {
+            return None;
+        }
+
+        let tuning = self.key_voices[last_key as usize].data.tuning()
+            / self.key_voices[key as usize].data.tuning();
+        Some(PortamentoControlData {
+            start_multiplier: 2.0f32.powf((last_key as f32 - key as f32) / 12.0) * tuning,
+            time: portamento_time(data.portamento_time),
+        })
+    }
+
+    /// Resets the controllers covered by `CC121` in the MIDI specification.
+    /// The volume, pan, bank and sound controllers are kept.
+    fn reset_controllers_spec(&mut self) {
+        let data = &mut self.control_event_data;
+        data.selected_lsb = -1;
+        data.selected_msb = -1;
+        data.pitch_bend_value = 0.0;
+        data.expression.set_end(1.0);
+        data.portamento = false;
+        self.process_pitch();
+
+        for key in self.key_voices.iter_mut() {
+            key.data.set_damper(false);
+        }
+    }
+
+    /// Resets all the controllers and the processed voice control data.
+    fn reset_all_state(&mut self) {
+        self.control_event_data = ControlEventData::new_defaults(
+            self.stream_params.sample_rate,
+            self.options.pitch_bend_range,
+        );
+        self.voice_control_data = VoiceControlData {
+            pan_law: self.voice_control_data.pan_law,
+            ..VoiceControlData::new_defaults()
+        };
         self.propagate_voice_controls();
 
         self.control_event_data.cutoff = None;
@@ -589,12 +1119,1704 @@ impl VoiceChannel {
     }
 }
 
+/// Converts a portamento time (`CC5`) value to the glide duration in seconds.
+/// The duration grows exponentially from 1ms at 0 to 10s at 127, which
+/// makes 64 about 100ms.
+pub fn portamento_time(value: u8) -> f32 {
+    0.001 * 10000.0f32.powf(value.min(127) as f32 / 127.0)
+}
+
 impl AudioPipe for VoiceChannel {
     fn stream_params(&self) -> &AudioStreamParams {
         &self.params.constant.stream_params
     }
 
     fn read_samples_unchecked(&mut self, out: &mut [f32]) {
-        self.push_key_events_and_render(out);
+        if self.is_idle() {
+            out.fill(0.0);
+        } else {
+            self.push_key_events_and_render(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_channel(options: ChannelInitOptions) -> VoiceChannel {
+        VoiceChannel::new(
+            options,
+            AudioStreamParams::new(48000, ChannelCount::Stereo),
+            None,
+        )
+    }
+
+    fn send_cc(channel: &mut VoiceChannel, controller: u8, value: u8) {
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::Control(
+            ControlEvent::Raw(controller, value),
+        )));
+    }
+
+    fn bend_pitch(channel: &mut VoiceChannel) {
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::Control(
+            ControlEvent::PitchBendValue(0.5),
+        )));
+    }
+
+    #[test]
+    fn test_cc121_keeps_volume() {
+        let mut channel = new_channel(Default::default());
+        send_cc(&mut channel, 0x07, 30);
+        send_cc(&mut channel, 0x0A, 10);
+        send_cc(&mut channel, 0x0B, 20);
+        bend_pitch(&mut channel);
+        assert_ne!(channel.voice_control_data.voice_pitch_multiplier, 1.0);
+
+        send_cc(&mut channel, 0x79, 0);
+
+        let data = &channel.control_event_data;
+        assert_eq!(data.volume.end, 30.0 / 128.0);
+        assert_eq!(channel.voice_control_data.pan, 10.0 / 128.0);
+        assert_eq!(data.expression.end, 1.0);
+        assert_eq!(data.pitch_bend_value, 0.0);
+        assert_eq!(channel.voice_control_data.voice_pitch_multiplier, 1.0);
+    }
+
+    #[test]
+    fn test_cc121_full_reset() {
+        let mut channel = new_channel(ChannelInitOptions {
+            strict_cc121: false,
+            ..Default::default()
+        });
+        send_cc(&mut channel, 0x07, 30);
+        bend_pitch(&mut channel);
+
+        send_cc(&mut channel, 0x79, 0);
+
+        let data = &channel.control_event_data;
+        assert_eq!(data.volume.end, 1.0);
+        assert_eq!(data.pitch_bend_value, 0.0);
+        assert_eq!(channel.voice_control_data.voice_pitch_multiplier, 1.0);
+
+        // The ResetControl event always resets the whole state
+        let mut channel = new_channel(Default::default());
+        send_cc(&mut channel, 0x07, 30);
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::ResetControl));
+        assert_eq!(channel.control_event_data.volume.end, 1.0);
+    }
+
+    #[test]
+    fn test_all_sound_off_and_all_notes_off() {
+        use crate::soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase};
+
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "all_sound_off",
+            &[0.5; 4800],
+            "ampeg_attack=0 ampeg_release=1 loop_mode=loop_continuous loop_start=0 \
+             loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let play = |cc| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            for key in 60..64 {
+                channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                    key,
+                    vel: 127,
+                }));
+            }
+            let mut out = vec![0.0; 960];
+            channel.read_samples(&mut out);
+            assert_eq!(channel.get_channel_stats().voice_count(), 4);
+
+            send_cc(&mut channel, cc, 0);
+            channel.read_samples(&mut out);
+            (channel.get_channel_stats().voice_count(), out[958])
+        };
+
+        // CC120 kills the voices on the next render
+        let (voices, level) = play(0x78);
+        assert_eq!(voices, 0);
+        assert_eq!(level, 0.0);
+
+        // CC123 lets them play their release
+        let (voices, level) = play(0x7B);
+        assert_eq!(voices, 4);
+        assert!(level > 0.1);
+    }
+
+    #[test]
+    fn test_note_off_velocity() {
+        use crate::soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase};
+
+        let load = |name, opcodes| -> Arc<dyn SoundfontBase> {
+            Arc::new(load_test_sfz_with_sample(
+                name,
+                &[0.5; 4800],
+                &format!(
+                    "ampeg_attack=0 ampeg_release=0.2 loop_mode=loop_continuous \
+                     loop_start=0 loop_end=4799 {opcodes}"
+                ),
+                ChannelCount::Stereo,
+            ))
+        };
+        let tracked = load("note_off_velocity", "ampeg_offveltrack=100");
+        let untracked = load("note_off_velocity_untracked", "");
+
+        // Returns the output after a note off with the given velocity, and
+        // the length of the release in ms
+        let release = |sf: &Arc<dyn SoundfontBase>, vel| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 60,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 960];
+            channel.read_samples(&mut out);
+
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                key: 60,
+                vel,
+            }));
+            let mut rendered = Vec::new();
+            while channel.get_channel_stats().voice_count() > 0 {
+                channel.read_samples(&mut out);
+                rendered.extend_from_slice(&out);
+            }
+            let ms = rendered.len() / 96;
+            (rendered, ms)
+        };
+
+        // The default velocity keeps the release unchanged
+        let (default, ms) = release(&tracked, DEFAULT_NOTE_OFF_VELOCITY);
+        assert_eq!(release(&untracked, DEFAULT_NOTE_OFF_VELOCITY).0, default);
+        assert!((200..=210).contains(&ms));
+
+        // Faster note offs shorten it, and slower ones lengthen it
+        assert!((100..=110).contains(&release(&tracked, 127).1));
+        assert!((400..=410).contains(&release(&tracked, 1).1));
+
+        // A velocity of 0 means that the device has no release velocity
+        assert_eq!(release(&tracked, 0).0, default);
+
+        // Soundfonts which don't opt in ignore the velocity
+        assert_eq!(release(&untracked, 127).0, default);
+        assert_eq!(release(&untracked, 1).0, default);
+    }
+
+    #[test]
+    fn test_add_soundfont_mid_note() {
+        use crate::soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase};
+
+        let load = |name, level| -> Arc<dyn SoundfontBase> {
+            Arc::new(load_test_sfz_with_sample(
+                name,
+                &[level; 4800],
+                "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+                ChannelCount::Stereo,
+            ))
+        };
+        let note_on = |key| ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 127 });
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![load("add_first", 0.5)],
+        )));
+        channel.process_event(note_on(60));
+        let mut before = vec![0.0; 960];
+        channel.read_samples(&mut before);
+        let level = before[958];
+        assert!(level > 0.0);
+
+        // The playing note keeps its voice without any gap
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::AddSoundfont {
+            sf: load("add_second", 0.25),
+            position: 0,
+        }));
+        let mut after = vec![0.0; 960];
+        channel.read_samples(&mut after);
+        assert!(after.iter().all(|&s| (s - level).abs() < 1e-4));
+
+        // New notes use the new soundfont, which has priority
+        channel.process_event(note_on(62));
+        channel.read_samples(&mut after);
+        assert!((after[958] - level * 1.5).abs() < 1e-4);
+
+        // Removing it makes new notes use the first one again
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::RemoveSoundfont {
+            position: 0,
+        }));
+        channel.process_event(note_on(64));
+        channel.read_samples(&mut after);
+        assert!((after[958] - level * 2.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_mismatched_soundfonts_rejected() {
+        use crate::soundfont::{
+            test_helpers::{load_test_sfz_with_sample, TestSoundfontDir},
+            SampleSoundfont, SoundfontBase,
+        };
+
+        let opcodes = "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4799";
+        let matched: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "sf_matched",
+            &[0.5; 4800],
+            opcodes,
+            ChannelCount::Stereo,
+        ));
+        let mono: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "sf_mono",
+            &[0.5; 4800],
+            opcodes,
+            ChannelCount::Mono,
+        ));
+        let dir = TestSoundfontDir::new("sf_44100");
+        dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            &format!("<region> sample=sample.wav {opcodes}\n"),
+        );
+        let other_rate: Arc<dyn SoundfontBase> = Arc::new(
+            SampleSoundfont::new_sfz(
+                sfz,
+                AudioStreamParams::new(44100, ChannelCount::Stereo),
+                Default::default(),
+            )
+            .unwrap(),
+        );
+
+        let play = |channel: &mut VoiceChannel| {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 60,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 480];
+            channel.read_samples(&mut out);
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
+            out[478]
+        };
+
+        // Mismatched soundfonts are skipped and counted
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![other_rate.clone(), mono.clone()],
+        )));
+        assert_eq!(play(&mut channel), 0.0);
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::AddSoundfont {
+            sf: other_rate.clone(),
+            position: 0,
+        }));
+        assert_eq!(play(&mut channel), 0.0);
+        assert_eq!(channel.get_channel_stats().rejected_soundfonts(), 3);
+
+        // Matching soundfonts are used, while the mismatched ones in the
+        // same list are still skipped
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![other_rate, matched],
+        )));
+        assert!(play(&mut channel) > 0.0);
+        assert_eq!(channel.get_channel_stats().rejected_soundfonts(), 4);
+    }
+
+    #[test]
+    fn test_xg_drum_bank_select() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        let load = |name, level, bank, preset| -> Arc<dyn SoundfontBase> {
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &[level; 4800]);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav ampeg_attack=0 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4799\n",
+            );
+            let options = SoundfontInitOptions {
+                bank: Some(bank),
+                preset: Some(preset),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+        let soundfonts = vec![
+            load("xg_piano", 0.1, 0, 0),
+            load("xg_standard_kit", 0.2, 128, 0),
+            load("xg_room_kit", 0.3, 128, 8),
+        ];
+
+        // Plays the channel 2 events of a type 0 MIDI track and returns the output level
+        let play = |channel: &mut VoiceChannel, track: &[&[u8]]| {
+            for message in track {
+                let event = match (message[0] & 0xF0, message[0] & 0x0F) {
+                    (_, ch) if ch != 1 => continue,
+                    (0xB0, _) => {
+                        ChannelAudioEvent::Control(ControlEvent::Raw(message[1], message[2]))
+                    }
+                    (0xC0, _) => ChannelAudioEvent::ProgramChange(message[1]),
+                    (0x90, _) => ChannelAudioEvent::NoteOn {
+                        key: message[1],
+                        vel: message[2],
+                    },
+                    _ => continue,
+                };
+                channel.process_event(ChannelEvent::Audio(event));
+            }
+
+            let mut out = vec![0.0; 960];
+            channel.read_samples(&mut out);
+            out[958]
+        };
+        let new_drum_channel = |percussion| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+                percussion,
+            )));
+            channel
+        };
+
+        let mut channel = new_drum_channel(false);
+        let piano = play(&mut channel, &[&[0xC1, 0], &[0x91, 36, 127]]);
+        assert!(piano > 0.0);
+
+        // XG drum setup on channel 2, the channel 1 events must not affect it
+        let mut channel = new_drum_channel(false);
+        let track: &[&[u8]] = &[
+            &[0xB0, 0x00, 127],
+            &[0xB1, 0x00, 127],
+            &[0xB1, 0x20, 0],
+            &[0xC1, 0],
+            &[0x91, 36, 127],
+        ];
+        let level = play(&mut channel, track);
+        assert!((level - piano * 2.0).abs() < 1e-4);
+
+        // A program change selects another drum kit
+        let mut channel = new_drum_channel(false);
+        let level = play(
+            &mut channel,
+            &[&[0xB1, 0x00, 127], &[0xC1, 8], &[0x91, 36, 127]],
+        );
+        assert!((level - piano * 3.0).abs() < 1e-4);
+
+        // GM2 drums use MSB 120, and other values go back to melodic
+        let mut channel = new_drum_channel(false);
+        let level = play(
+            &mut channel,
+            &[&[0xB1, 0x00, 120], &[0xC1, 0], &[0x91, 36, 127]],
+        );
+        assert!((level - piano * 2.0).abs() < 1e-4);
+        let level = play(
+            &mut channel,
+            &[&[0xB1, 0x00, 0], &[0xC1, 0], &[0x91, 38, 127]],
+        );
+        assert!((level - piano * 3.0).abs() < 1e-4);
+
+        // Channels in percussion mode stay on the drum bank
+        let mut channel = new_drum_channel(true);
+        let level = play(
+            &mut channel,
+            &[&[0xB1, 0x00, 0], &[0xC1, 0], &[0x91, 36, 127]],
+        );
+        assert!((level - piano * 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_portamento_glide() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        // A looped 480Hz sine wave, played at its original pitch on key 60
+        let load = |name, bank| -> Arc<dyn SoundfontBase> {
+            let sine: Vec<f32> = (0..4800)
+                .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+                .collect();
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &sine);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav pitch_keycenter=60 ampeg_attack=0 ampeg_release=0 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4699\n",
+            );
+            let options = SoundfontInitOptions {
+                bank: Some(bank),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+
+        let note = |channel: &mut VoiceChannel, key, on| {
+            let event = if on {
+                ChannelAudioEvent::NoteOn { key, vel: 127 }
+            } else {
+                ChannelAudioEvent::NoteOff { key, vel: 64 }
+            };
+            channel.process_event(ChannelEvent::Audio(event));
+        };
+        // Estimates the frequency of the next 20ms from the zero crossings
+        let frequency = |channel: &mut VoiceChannel| {
+            let mut out = vec![0.0; 1920];
+            channel.read_samples(&mut out);
+            let left: Vec<f32> = out.iter().step_by(2).copied().collect();
+            let crossings = left
+                .windows(2)
+                .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+                .count();
+            crossings as f32 / 2.0 / 0.02
+        };
+        let play_octave = |channel: &mut VoiceChannel| {
+            note(channel, 60, true);
+            frequency(channel);
+            note(channel, 60, false);
+            frequency(channel);
+            note(channel, 72, true);
+            (0..20).map(|_| frequency(channel)).collect::<Vec<_>>()
+        };
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![load("portamento", 0)],
+        )));
+        send_cc(&mut channel, 0x41, 127);
+        send_cc(&mut channel, 0x05, 73);
+        let time = portamento_time(73);
+        assert!((time - 0.2).abs() < 0.005);
+
+        // The glide takes the 10 windows of 20ms, from 480Hz to 960Hz
+        let freqs = play_octave(&mut channel);
+        assert!(freqs[0] < 550.0);
+        assert!((freqs[4] - 480.0 * 2.0f32.powf(0.45)).abs() < 50.0);
+        assert!(freqs[..10].windows(2).all(|w| w[1] > w[0]));
+        assert!(freqs[8] < 930.0);
+        assert!(freqs[10..].iter().all(|f| (f - 960.0).abs() <= 25.0));
+
+        // Reset All Controllers turns it off
+        send_cc(&mut channel, 0x79, 0);
+        let freqs = play_octave(&mut channel);
+        assert!(freqs.iter().all(|f| (f - 960.0).abs() <= 25.0));
+
+        // Percussion channels ignore it
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![load("portamento_drums", 128)],
+        )));
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+            true,
+        )));
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetIgnoreNoteOff(
+            false,
+        )));
+        send_cc(&mut channel, 0x41, 127);
+        send_cc(&mut channel, 0x05, 73);
+        let freqs = play_octave(&mut channel);
+        assert!(freqs.iter().all(|f| (f - 960.0).abs() <= 25.0));
+    }
+
+    #[test]
+    fn test_key_tuning() {
+        use crate::soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase};
+
+        let sine: Vec<f32> = (0..4800)
+            .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+            .collect();
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "key_tuning",
+            &sine,
+            "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4800",
+            ChannelCount::Stereo,
+        ));
+        let render_key = |tuning: Vec<(u8, f32)>, key| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetKeyTuning(
+                tuning,
+            )));
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 9600];
+            channel.read_samples(&mut out);
+            out
+        };
+
+        let untuned = render_key(Vec::new(), 70);
+        let tuned = render_key(vec![(69, 100.0)], 69);
+        assert!(untuned.iter().any(|&s| s.abs() > 0.1));
+        assert!(untuned
+            .iter()
+            .zip(&tuned)
+            .all(|(a, b)| (a - b).abs() < 1e-3));
+
+        // Other keys keep their pitch
+        let other = render_key(vec![(69, 100.0)], 70);
+        assert_eq!(other, untuned);
+    }
+
+    #[test]
+    fn test_master_tuning() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        // A looped 480Hz sine wave, played at its original pitch on key 60
+        let load = |name, bank| -> Arc<dyn SoundfontBase> {
+            let sine: Vec<f32> = (0..4800)
+                .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+                .collect();
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &sine);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav pitch_keycenter=60 ampeg_attack=0 ampeg_release=0 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4699\n",
+            );
+            let options = SoundfontInitOptions {
+                bank: Some(bank),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+        let soundfonts = vec![load("master_tuning", 0), load("master_tuning_drums", 128)];
+
+        // Renders key 60 for 0.5s and returns its pitch in cents relative to
+        // the sample, measured between the first and last rising zero crossings
+        let cents = |options, percussion, hz| {
+            let mut channel = new_channel(options);
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetMasterTuning(
+                hz,
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+                percussion,
+            )));
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 60,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 48000];
+            channel.read_samples(&mut out);
+
+            let left: Vec<f32> = out.iter().step_by(2).copied().collect();
+            let rising: Vec<f32> = left
+                .windows(2)
+                .enumerate()
+                .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+                .map(|(i, w)| i as f32 + w[0] / (w[0] - w[1]))
+                .collect();
+            let periods = (rising.len() - 1) as f32;
+            let frequency = periods / (rising[rising.len() - 1] - rising[0]) * 48000.0;
+            1200.0 * (frequency / 480.0).log2()
+        };
+        let exempt = ChannelInitOptions {
+            percussion_ignores_tuning: true,
+            ..Default::default()
+        };
+
+        assert!(cents(Default::default(), false, 440.0).abs() < 0.5);
+        for (hz, expected) in [(442.0, 7.85), (415.0, -101.27)] {
+            assert!((cents(Default::default(), false, hz) - expected).abs() < 0.5);
+            assert!((cents(exempt, false, hz) - expected).abs() < 0.5);
+
+            // Percussion follows the tuning unless it is exempted
+            assert!((cents(Default::default(), true, hz) - expected).abs() < 0.5);
+            assert!(cents(exempt, true, hz).abs() < 0.5);
+        }
+
+        // Invalid frequencies are ignored
+        assert!(cents(Default::default(), false, 0.0).abs() < 0.5);
+        assert!(cents(Default::default(), false, f32::NAN).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_key_map() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        let load = |name, bank| -> Arc<dyn SoundfontBase> {
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav ampeg_attack=0 ampeg_release=0.01 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4799\n",
+            );
+            let options = SoundfontInitOptions {
+                bank: Some(bank),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+        let soundfonts = vec![load("key_map", 0), load("key_map_drums", 128)];
+
+        let mut map = crate::helpers::identity_key_map();
+        map[42] = 46;
+
+        let new_mapped_channel = |percussion_only| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetKeyMap {
+                map: Some(Box::new(map)),
+                percussion_only,
+            }));
+            channel
+        };
+        let note = |channel: &mut VoiceChannel, on| {
+            let event = if on {
+                ChannelAudioEvent::NoteOn { key: 42, vel: 127 }
+            } else {
+                ChannelAudioEvent::NoteOff { key: 42, vel: 64 }
+            };
+            channel.process_event(ChannelEvent::Audio(event));
+            let mut out = vec![0.0; 4800];
+            channel.read_samples(&mut out);
+        };
+
+        let mut channel = new_mapped_channel(false);
+        note(&mut channel, true);
+        assert!(channel.key_voices[46].data.has_voices());
+        assert!(!channel.key_voices[42].data.has_voices());
+
+        // The release matches the note on, even after the map is removed
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetKeyMap {
+            map: None,
+            percussion_only: false,
+        }));
+        note(&mut channel, false);
+        assert!(!channel.key_voices[46].data.has_voices());
+        note(&mut channel, true);
+        assert!(channel.key_voices[42].data.has_voices());
+
+        // Percussion only maps are ignored by melodic channels
+        let mut channel = new_mapped_channel(true);
+        note(&mut channel, true);
+        assert!(channel.key_voices[42].data.has_voices());
+        note(&mut channel, false);
+
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+            true,
+        )));
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetIgnoreNoteOff(
+            false,
+        )));
+        note(&mut channel, true);
+        assert!(channel.key_voices[46].data.has_voices());
+        note(&mut channel, false);
+        assert!(!channel.key_voices[46].data.has_voices());
+    }
+
+    #[test]
+    fn test_transpose() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        let load = |name, bank| -> Arc<dyn SoundfontBase> {
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav ampeg_attack=0 ampeg_release=0.01 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4699\n",
+            );
+            let options = SoundfontInitOptions {
+                bank: Some(bank),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+        let soundfonts = vec![load("transpose", 0), load("transpose_drums", 128)];
+
+        let new_transposed_channel = |options, transpose| {
+            let mut channel = new_channel(options);
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetTranspose(
+                transpose,
+            )));
+            channel
+        };
+        let note = |channel: &mut VoiceChannel, key, on| {
+            let event = if on {
+                ChannelAudioEvent::NoteOn { key, vel: 127 }
+            } else {
+                ChannelAudioEvent::NoteOff { key, vel: 64 }
+            };
+            channel.process_event(ChannelEvent::Audio(event));
+            let mut out = vec![0.0; 4800];
+            channel.read_samples(&mut out);
+        };
+        let playing_keys = |channel: &VoiceChannel| {
+            (0..128)
+                .filter(|&k| channel.key_voices[k].data.has_voices())
+                .collect::<Vec<_>>()
+        };
+
+        let mut channel = new_transposed_channel(Default::default(), 12);
+        note(&mut channel, 60, true);
+        assert_eq!(playing_keys(&channel), [72]);
+
+        // The release matches the note on, even after the transpose changes
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetTranspose(-5)));
+        note(&mut channel, 60, false);
+        assert_eq!(playing_keys(&channel), []);
+        note(&mut channel, 60, true);
+        assert_eq!(playing_keys(&channel), [55]);
+        note(&mut channel, 60, false);
+
+        // Notes transposed out of range are dropped, along with their note off,
+        // which must not release the key it would map to after a change
+        note(&mut channel, 7, true);
+        note(&mut channel, 2, true);
+        assert_eq!(playing_keys(&channel), [2]);
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetTranspose(0)));
+        note(&mut channel, 2, false);
+        assert_eq!(playing_keys(&channel), [2]);
+        note(&mut channel, 7, false);
+        assert_eq!(playing_keys(&channel), []);
+
+        // Percussion is only transposed if enabled
+        for (transpose_percussion, key) in [(false, 60), (true, 72)] {
+            let options = ChannelInitOptions {
+                transpose_percussion,
+                ..Default::default()
+            };
+            let mut channel = new_transposed_channel(options, 12);
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+                true,
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetIgnoreNoteOff(
+                false,
+            )));
+            note(&mut channel, 60, true);
+            assert_eq!(playing_keys(&channel), [key]);
+            note(&mut channel, 60, false);
+            assert_eq!(playing_keys(&channel), []);
+        }
+    }
+
+    #[test]
+    fn test_cutoff_over_region_filter() {
+        use crate::soundfont::{test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase};
+
+        let mut seed = 1u32;
+        let noise: Vec<f32> = (0..48000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        let dir = TestSoundfontDir::new("cutoff_over_region_filter");
+        dir.write_wav("sample.wav", 48000, &noise);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav ampeg_attack=0 cutoff=500 fil_type=hpf_2p\n",
+        );
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let soundfont: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, Default::default()).unwrap());
+
+        let render = |controls: &[(u8, u8)]| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![soundfont.clone()],
+            )));
+            for &(controller, value) in controls {
+                send_cc(&mut channel, controller, value);
+            }
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 60,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 19200];
+            channel.read_samples(&mut out);
+            out[9600..].iter().map(|s| s * s).sum::<f32>()
+        };
+
+        // The resonance only applies to the cutoff of CC74
+        let plain = render(&[]);
+        assert_eq!(render(&[(0x47, 127)]), plain);
+
+        // CC74 adds a low pass on top of the high pass of the region, and
+        // the resonance boosts the band around its cutoff
+        let cutoff = render(&[(0x4A, 40)]);
+        assert!(cutoff < plain * 0.5, "{cutoff} {plain}");
+        let resonant = render(&[(0x4A, 40), (0x47, 127)]);
+        assert!(resonant > cutoff * 1.5, "{resonant} {cutoff}");
+    }
+
+    #[test]
+    fn test_region_pan_adds_to_channel_pan() {
+        use crate::soundfont::{test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase};
+
+        let dir = TestSoundfontDir::new("region_pan");
+        dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav ampeg_attack=0 pan=-100 \
+             loop_mode=loop_continuous loop_start=0 loop_end=4699\n",
+        );
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let soundfont: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, Default::default()).unwrap());
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![soundfont],
+        )));
+        let render = |channel: &mut VoiceChannel| {
+            let mut out = vec![0.0; 4800];
+            channel.read_samples(&mut out);
+            (out[4798], out[4799])
+        };
+
+        // A hard left region on a centered channel stays hard left
+        send_cc(&mut channel, 0x0A, 64);
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+            key: 60,
+            vel: 127,
+        }));
+        let (left, right) = render(&mut channel);
+        assert!(left > 0.1);
+        assert_eq!(right, 0.0);
+
+        // Panning the channel right moves the playing voice to the center
+        send_cc(&mut channel, 0x0A, 127);
+        let (left, right) = render(&mut channel);
+        assert!(right > 0.1);
+        assert!((left - right).abs() < left * 0.05);
+    }
+
+    #[test]
+    fn test_release_uses_note_on_program() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, VoiceSpawner,
+        };
+
+        /// Plays preset 0 for every preset, and its attack regions
+        /// as release regions of preset 0 only.
+        #[derive(Debug)]
+        struct ReleaseSoundfont(SampleSoundfont);
+
+        impl SoundfontBase for ReleaseSoundfont {
+            fn stream_params(&self) -> &AudioStreamParams {
+                self.0.stream_params()
+            }
+
+            fn get_attack_voice_spawners_at(
+                &self,
+                bank: u8,
+                _preset: u8,
+                key: u8,
+                vel: u8,
+            ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+                self.0.get_attack_voice_spawners_at(bank, 0, key, vel)
+            }
+
+            fn get_release_voice_spawners_at(
+                &self,
+                bank: u8,
+                preset: u8,
+                key: u8,
+                vel: u8,
+            ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+                if preset == 0 {
+                    self.0.get_attack_voice_spawners_at(bank, preset, key, vel)
+                } else {
+                    Arc::default()
+                }
+            }
+        }
+
+        let dir = TestSoundfontDir::new("release_program");
+        dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav ampeg_attack=0 ampeg_release=1 \
+             loop_mode=loop_continuous loop_start=0 loop_end=4699\n",
+        );
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let sf = SampleSoundfont::new_sfz(sfz, stream_params, Default::default()).unwrap();
+        let soundfonts: Vec<Arc<dyn SoundfontBase>> = vec![Arc::new(ReleaseSoundfont(sf))];
+
+        let voice_count = |events: &[ChannelAudioEvent]| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                soundfonts.clone(),
+            )));
+            let mut out = vec![0.0; 480];
+            for event in events {
+                channel.process_event(ChannelEvent::Audio(*event));
+                channel.read_samples(&mut out);
+            }
+            channel.get_channel_stats().voice_count()
+        };
+        let note_on = ChannelAudioEvent::NoteOn { key: 60, vel: 127 };
+        let note_off = ChannelAudioEvent::NoteOff { key: 60, vel: 64 };
+
+        // The released voice and its release voice
+        assert_eq!(voice_count(&[note_on, note_off]), 2);
+
+        // The release is resolved against the program of the note on
+        let program_change = ChannelAudioEvent::ProgramChange(1);
+        assert_eq!(voice_count(&[note_on, program_change, note_off]), 2);
+
+        // Notes started after the program change have no release voices
+        assert_eq!(voice_count(&[program_change, note_on, note_off]), 1);
+    }
+
+    #[test]
+    fn test_program_change_before_note_on() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        let load = |name, level, preset| -> Arc<dyn SoundfontBase> {
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, &[level; 4800]);
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                "<region> sample=sample.wav ampeg_attack=0 \
+                 loop_mode=loop_continuous loop_start=0 loop_end=4799\n",
+            );
+            let options = SoundfontInitOptions {
+                preset: Some(preset),
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap())
+        };
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![
+                load("program_piano", 0.1, 0),
+                load("program_organ", 0.3, 16),
+            ],
+        )));
+        let mut out = vec![0.0; 960];
+        let mut play = |channel: &mut VoiceChannel, events: &[ChannelAudioEvent]| {
+            for event in events {
+                channel.process_event(ChannelEvent::Audio(*event));
+            }
+            channel.read_samples(&mut out);
+            out[958]
+        };
+        let note_on = |key| ChannelAudioEvent::NoteOn { key, vel: 127 };
+
+        let piano = play(&mut channel, &[note_on(60)]);
+        assert!(piano > 0.0);
+
+        // Both events are processed in the same render
+        let level = play(
+            &mut channel,
+            &[ChannelAudioEvent::ProgramChange(16), note_on(62)],
+        );
+        assert!((level - piano * 4.0).abs() < 1e-4);
+
+        // Without any change the program stays loaded
+        let level = play(&mut channel, &[note_on(64)]);
+        assert!((level - piano * 7.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_voices_per_soundfont() {
+        use crate::soundfont::{test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase};
+
+        let load = |name, samples: &[f32], regions: &str| -> Arc<dyn SoundfontBase> {
+            let dir = TestSoundfontDir::new(name);
+            dir.write_wav("sample.wav", 48000, samples);
+            let sfz = dir.write_sfz("test.sfz", regions);
+            let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, Default::default()).unwrap())
+        };
+
+        // The piano has two layered regions on the lower keys, and the
+        // strings play a short sample on all the keys
+        let piano_region = "<region> sample=sample.wav lokey=0 hikey=63 ampeg_attack=0 \
+                            ampeg_release=0.03 loop_mode=loop_continuous loop_start=0 loop_end=4799\n";
+        let piano = load("layers_piano", &[0.5; 4800], &piano_region.repeat(2));
+        let strings = load(
+            "layers_strings",
+            &[0.5; 1440],
+            "<region> sample=sample.wav pitch_keycenter=80 ampeg_attack=0\n",
+        );
+        let piano_spawners = piano.get_attack_voice_spawners_at(0, 0, 40, 127).len() as u64;
+        let strings_spawners = strings.get_attack_voice_spawners_at(0, 0, 80, 127).len() as u64;
+        assert_eq!((piano_spawners, strings_spawners), (2, 1));
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![piano, strings],
+        )));
+        let stats = channel.get_channel_stats();
+        let play = |channel: &mut VoiceChannel, events: &[ChannelAudioEvent]| {
+            for event in events {
+                channel.process_event(ChannelEvent::Audio(*event));
+            }
+            let mut out = vec![0.0; 1920];
+            channel.read_samples(&mut out);
+            stats.voices_per_soundfont()
+        };
+        let note_on = |key| ChannelAudioEvent::NoteOn { key, vel: 127 };
+
+        let counts = play(&mut channel, &[note_on(40), note_on(41), note_on(80)]);
+        assert_eq!(counts, [piano_spawners * 2, strings_spawners]);
+
+        // The strings voice ends with its sample
+        let counts = play(&mut channel, &[]);
+        assert_eq!(counts, [piano_spawners * 2, 0]);
+
+        // Released voices are counted until their release finishes
+        let counts = play(
+            &mut channel,
+            &[ChannelAudioEvent::NoteOff { key: 41, vel: 64 }],
+        );
+        assert_eq!(counts, [piano_spawners * 2, 0]);
+        let counts = play(&mut channel, &[]);
+        assert_eq!(counts, [piano_spawners, 0]);
+
+        let counts = play(&mut channel, &[ChannelAudioEvent::AllNotesKilled]);
+        assert_eq!(counts, [0, 0]);
+        assert_eq!(stats.voice_count(), 0);
+    }
+
+    #[test]
+    fn test_key_event_limit() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "key_event_limit",
+            &[0.5; 4800],
+            "ampeg_attack=0 ampeg_release=0.001 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let new = || {
+            let mut channel = new_channel(ChannelInitOptions {
+                key_event_limit: 1000,
+                ..Default::default()
+            });
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            channel
+        };
+        let note_on = |key| ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 127 });
+        let note_off = |key| ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key, vel: 64 });
+
+        // A burst of events without rendering, leaving one note held on a key
+        // and more notes than the layer limit on another
+        let mut channel = new();
+        let stats = channel.get_channel_stats();
+        for _ in 0..500_000 {
+            channel.process_event(note_on(60));
+            channel.process_event(note_off(60));
+            channel.process_event(note_on(62));
+        }
+        channel.process_event(note_on(60));
+        assert!(channel.key_voices[60].event_cache.len() <= 1000);
+        assert!(channel.key_voices[62].event_cache.len() <= 1000);
+        assert!(stats.dropped_events() > 1_000_000);
+
+        let mut reference = new();
+        reference.process_event(note_on(60));
+        for _ in 0..4 {
+            reference.process_event(note_on(62));
+        }
+
+        // Once rendered, the same notes are left playing
+        let mut out = vec![0.0; 960];
+        let mut expected = vec![0.0; 960];
+        for _ in 0..2 {
+            channel.read_samples(&mut out);
+            reference.read_samples(&mut expected);
+        }
+        assert_eq!(
+            stats.voice_count(),
+            reference.get_channel_stats().voice_count()
+        );
+        assert!(out.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn test_max_spawns_per_quantum() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "max_spawns_per_quantum",
+            &[0.5; 4800],
+            "ampeg_attack=0 ampeg_release=0.001 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let new = || {
+            let mut channel = new_channel(ChannelInitOptions {
+                max_spawns_per_quantum: Some(1000),
+                ..Default::default()
+            });
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(
+                None,
+            )));
+            channel
+        };
+        let keys = (0..10_000).map(|i| (i % 128) as u8);
+        let mut out = vec![0.0; 960];
+
+        // The note ons are spread over 10 renders
+        let mut channel = new();
+        let stats = channel.get_channel_stats();
+        for key in keys.clone() {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key,
+                vel: 127,
+            }));
+        }
+        for i in 1..=12 {
+            channel.read_samples(&mut out);
+            assert_eq!(stats.voice_count(), 1000 * i.min(10));
+        }
+        // Each deferred note on is counted once
+        assert_eq!(stats.deferred_spawns(), 9000);
+
+        // The note offs sent while the note ons are deferred release them
+        let mut channel = new();
+        let stats = channel.get_channel_stats();
+        for key in keys.clone() {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key,
+                vel: 127,
+            }));
+        }
+        channel.read_samples(&mut out);
+        for key in keys.clone() {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                key,
+                vel: 64,
+            }));
+        }
+        for _ in 0..12 {
+            channel.read_samples(&mut out);
+        }
+        assert_eq!(stats.voice_count(), 0);
+        assert_eq!(stats.key_occupancy(), KeyOccupancy::default());
+        assert!(channel.is_idle());
+
+        // A kill sent after the deferred note ons silences the next render
+        for (cc, released) in [(0x78, false), (0x7B, true)] {
+            let mut channel = new();
+            let stats = channel.get_channel_stats();
+            for key in keys.clone() {
+                channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                    key,
+                    vel: 127,
+                }));
+            }
+            channel.read_samples(&mut out);
+            assert_eq!(stats.voice_count(), 1000);
+
+            send_cc(&mut channel, cc, 0);
+            channel.read_samples(&mut out);
+            if released {
+                // The release of the playing voices is 1 ms long
+                channel.read_samples(&mut out);
+            }
+            assert_eq!(stats.voice_count(), 0);
+            assert!(out.iter().all(|s| *s == 0.0));
+            channel.read_samples(&mut out);
+            assert_eq!(stats.voice_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_high_res_velocity() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "high_res_velocity",
+            &[0.5; 4800],
+            "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let level = |event: ChannelAudioEvent| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            channel.process_event(ChannelEvent::Audio(event));
+            let mut out = vec![0.0; 960];
+            channel.read_samples(&mut out);
+            out[958]
+        };
+        let high_res = |vel| level(ChannelAudioEvent::NoteOnHighRes { key: 60, vel });
+        let low_res = |vel| level(ChannelAudioEvent::NoteOn { key: 60, vel });
+
+        // The 16-bit velocities equivalent to 7-bit ones have the same gain
+        let full = low_res(127);
+        assert!(full > 0.0);
+        assert_eq!(high_res(0xFFFF), full);
+        assert_eq!(high_res(0x8000), low_res(64));
+        assert_eq!(high_res(velocity_to_16bit(100)), low_res(100));
+
+        // Velocities between two 7-bit velocities fall between their gains
+        let between = high_res(0x8000 + 0x100);
+        assert!(between > low_res(64) && between < low_res(65));
+
+        // A 16-bit velocity of 0 still plays the note
+        assert!(high_res(0) > 0.0);
+    }
+
+    #[test]
+    fn test_key_occupancy() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let new =
+            |name, samples: &[f32], opcodes| {
+                let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(
+                    load_test_sfz_with_sample(name, samples, opcodes, ChannelCount::Stereo),
+                );
+                let mut channel = new_channel(Default::default());
+                channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                    vec![sf],
+                )));
+                channel
+            };
+        let send = |channel: &mut VoiceChannel, event| {
+            channel.process_event(ChannelEvent::Audio(event));
+        };
+        let render = |channel: &mut VoiceChannel, len| {
+            let mut out = vec![0.0; len];
+            channel.read_samples(&mut out);
+            channel.key_occupancy()
+        };
+
+        let mut channel = new(
+            "key_occupancy_loop",
+            &[0.5; 4800],
+            "ampeg_attack=0 ampeg_release=0.01 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+        );
+        assert_eq!(channel.key_occupancy(), KeyOccupancy::default());
+
+        // Events only update the masks once they are rendered
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 100, vel: 127 },
+        );
+        assert!(!channel.key_occupancy().is_sounding(60));
+        let occupancy = render(&mut channel, 960);
+        assert_eq!(occupancy.sounding, [1 << 60, 1 << (100 - 64)]);
+        assert_eq!(occupancy.unreleased, occupancy.sounding);
+        assert_eq!(channel.get_channel_stats().key_occupancy(), occupancy);
+
+        // Released voices are sounding until their release ends
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOff { key: 60, vel: 64 },
+        );
+        let occupancy = render(&mut channel, 240);
+        assert!(occupancy.is_sounding(60) && !occupancy.is_unreleased(60));
+        assert!(occupancy.is_unreleased(100));
+        let occupancy = render(&mut channel, 1920);
+        assert!(!occupancy.is_sounding(60) && !occupancy.is_unreleased(60));
+
+        // Killed voices are not unreleased, and stop sounding once removed
+        send(&mut channel, ChannelAudioEvent::AllNotesKilled);
+        let occupancy = render(&mut channel, 960);
+        assert!(!occupancy.is_unreleased(100));
+        let occupancy = render(&mut channel, 960);
+        assert_eq!(occupancy, KeyOccupancy::default());
+
+        // Voices which reach the end of their sample stop sounding while held
+        let mut channel = new("key_occupancy_end", &[0.5; 480], "ampeg_attack=0");
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 64, vel: 127 },
+        );
+        let occupancy = render(&mut channel, 240);
+        assert!(occupancy.is_sounding(64) && occupancy.is_unreleased(64));
+        let occupancy = render(&mut channel, 960);
+        assert_eq!(occupancy, KeyOccupancy::default());
+    }
+
+    #[test]
+    fn test_key_and_velocity_range() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "key_velocity_range",
+            &[0.5; 4800],
+            "ampeg_attack=0 ampeg_release=0.01 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![sf],
+        )));
+        let config = |channel: &mut VoiceChannel, event| {
+            channel.process_event(ChannelEvent::Config(event));
+        };
+        let send = |channel: &mut VoiceChannel, event| {
+            channel.process_event(ChannelEvent::Audio(event));
+            let mut out = vec![0.0; 4800];
+            channel.read_samples(&mut out);
+        };
+        let playing_keys = |channel: &VoiceChannel| {
+            (0..128)
+                .filter(|&k| channel.key_voices[k].data.has_voices())
+                .collect::<Vec<_>>()
+        };
+
+        // A split playing the upper half of the keyboard an octave higher
+        config(&mut channel, ChannelConfigEvent::SetKeyRange(60, 127));
+        config(&mut channel, ChannelConfigEvent::SetTranspose(12));
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 59, vel: 127 },
+        );
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        assert_eq!(playing_keys(&channel), [72]);
+
+        // The note offs match their note ons after the range changes
+        config(&mut channel, ChannelConfigEvent::SetKeyRange(0, 59));
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOff { key: 60, vel: 64 },
+        );
+        assert_eq!(playing_keys(&channel), []);
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 59, vel: 127 },
+        );
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        config(&mut channel, ChannelConfigEvent::SetKeyRange(60, 127));
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOff { key: 60, vel: 64 },
+        );
+        assert_eq!(playing_keys(&channel), [71]);
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOff { key: 59, vel: 64 },
+        );
+        assert_eq!(playing_keys(&channel), []);
+
+        // Velocities outside of the range are dropped
+        config(&mut channel, ChannelConfigEvent::SetKeyRange(0, 127));
+        config(&mut channel, ChannelConfigEvent::SetTranspose(0));
+        config(&mut channel, ChannelConfigEvent::SetVelocityRange(1, 100));
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 101 },
+        );
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOnHighRes {
+                key: 61,
+                vel: 0xFFFF,
+            },
+        );
+        send(
+            &mut channel,
+            ChannelAudioEvent::NoteOn { key: 62, vel: 100 },
+        );
+        assert_eq!(playing_keys(&channel), [62]);
+    }
+
+    #[test]
+    fn test_effect_tail_before_idle() {
+        use crate::soundfont::test_helpers::load_test_sfz_with_sample;
+
+        let sf: Arc<dyn crate::soundfont::SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "effect_tail_idle",
+            &[0.5; 4800],
+            "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4799",
+            ChannelCount::Stereo,
+        ));
+        let mut channel = new_channel(ChannelInitOptions {
+            fade_out_killing: false,
+            ..Default::default()
+        });
+        assert!(channel.is_idle());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![sf],
+        )));
+
+        // A resonant cutoff, which keeps ringing after the voice is killed
+        send_cc(&mut channel, 0x4A, 20);
+        send_cc(&mut channel, 0x47, 127);
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+            key: 60,
+            vel: 127,
+        }));
+        assert!(!channel.is_idle());
+        let mut out = vec![0.0; 4800];
+        channel.read_samples(&mut out);
+
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
+        let mut block = vec![0.0; 128];
+        let mut tail = Vec::new();
+        while !channel.is_idle() {
+            assert!(tail.len() < 48000 * 10, "the channel never became idle");
+            channel.read_samples(&mut block);
+            tail.extend_from_slice(&block);
+        }
+
+        // The tail is rendered until it fades below the silence threshold
+        assert!(tail[..128].iter().any(|s| s.abs() > 0.01));
+        let last = &tail[tail.len() - 128..];
+        assert!(last.iter().all(|s| s.abs() < SILENCE_THRESHOLD));
+        assert!(channel.cutoff.is_settled());
+
+        channel.read_samples(&mut block);
+        assert!(block.iter().all(|&s| s == 0.0));
+
+        // A new note wakes the channel up
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+            key: 60,
+            vel: 127,
+        }));
+        assert!(!channel.is_idle());
+        channel.read_samples(&mut out);
+        assert!(out.iter().any(|s| s.abs() > 0.01));
+
+        // Volume fades are rendered even without voices
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
+        while !channel.is_idle() {
+            channel.read_samples(&mut block);
+        }
+        send_cc(&mut channel, 0x07, 10);
+        assert!(!channel.is_idle());
+    }
+
+    #[test]
+    fn test_config_events() {
+        use crate::soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase};
+
+        let sine: Vec<f32> = (0..4800)
+            .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+            .collect();
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz_with_sample(
+            "config_events",
+            &sine,
+            "ampeg_attack=0 loop_mode=loop_continuous loop_start=0 loop_end=4800",
+            ChannelCount::Stereo,
+        ));
+
+        let mut channel = new_channel(Default::default());
+        for event in [
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+            ChannelConfigEvent::SetPanLaw(PanLaw::Linear),
+            ChannelConfigEvent::SetKeyTuning(vec![(62, 30.0)]),
+            ChannelConfigEvent::SetTranspose(2),
+            ChannelConfigEvent::SetMasterTuning(442.0),
+        ] {
+            channel.process_event(ChannelEvent::Config(event));
+        }
+        send_cc(&mut channel, 0x07, 100);
+        send_cc(&mut channel, 0x0A, 30);
+        // Lets the volume settle, as the copy starts at the settled values
+        channel.read_samples(&mut vec![0.0; 4800]);
+
+        let mut copy = new_channel(Default::default());
+        for event in channel.config_events() {
+            copy.process_event(ChannelEvent::Config(event));
+        }
+        copy.set_controller_state(&channel.controller_state());
+
+        let render = |channel: &mut VoiceChannel| {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 60,
+                vel: 127,
+            }));
+            let mut out = vec![0.0; 9600];
+            channel.read_samples(&mut out);
+            out
+        };
+        let original = render(&mut channel);
+        let copied = render(&mut copy);
+        assert!(original.iter().any(|&s| s.abs() > 0.1));
+        assert!(original
+            .iter()
+            .zip(&copied)
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+
+        // The copy doesn't share anything with the original
+        let mut fresh = new_channel(Default::default());
+        assert!(render(&mut fresh).iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_percussion_ignores_note_off() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        // A decaying crash cymbal one-shot
+        let crash: Vec<f32> = (0..24000)
+            .map(|i| (i as f32 * 0.37).sin() * 0.5 * (-(i as f32) / 6000.0).exp())
+            .collect();
+        let dir = TestSoundfontDir::new("percussion_note_off");
+        dir.write_wav("crash.wav", 48000, &crash);
+        let sfz = dir.write_sfz("test.sfz", "<region> sample=crash.wav ampeg_attack=0\n");
+        let options = SoundfontInitOptions {
+            bank: Some(128),
+            preset: Some(0),
+            ..Default::default()
+        };
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let sf: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap());
+
+        let render = |config: &[ChannelConfigEvent], note_off: bool| {
+            let mut channel = new_channel(Default::default());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                vec![sf.clone()],
+            )));
+            for event in config {
+                channel.process_event(ChannelEvent::Config(event.clone()));
+            }
+            send_cc(&mut channel, 0x40, 127);
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: 49,
+                vel: 127,
+            }));
+            if note_off {
+                channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                    key: 49,
+                    vel: 64,
+                }));
+            }
+            send_cc(&mut channel, 0x40, 0);
+            let mut out = vec![0.0; 48000];
+            channel.read_samples(&mut out);
+            out
+        };
+        let tail = |out: &[f32]| out.iter().rposition(|s| s.abs() > 1e-3).unwrap();
+
+        let percussion = [ChannelConfigEvent::SetPercussionMode(true)];
+        let full = render(&percussion, false);
+        assert!(tail(&full) > 30000);
+        assert_eq!(render(&percussion, true), full);
+
+        // The note offs can be applied again
+        let overridden = [
+            ChannelConfigEvent::SetPercussionMode(true),
+            ChannelConfigEvent::SetIgnoreNoteOff(false),
+        ];
+        assert!(tail(&render(&overridden, true)) < 1000);
+
+        // All notes killed still stops the notes
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![sf.clone()],
+        )));
+        channel.process_event(ChannelEvent::Config(percussion[0].clone()));
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+            key: 49,
+            vel: 127,
+        }));
+        channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
+        let mut out = vec![0.0; 4800];
+        channel.read_samples(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn test_percussion_releases_looping_voices() {
+        use crate::soundfont::{
+            test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        };
+
+        // A looped snare roll on key 38, a sustain looped one on key 40, and
+        // a one-shot crash layered with a looped swell on key 49
+        let dir = TestSoundfontDir::new("percussion_looping");
+        dir.write_wav("tone.wav", 48000, &[0.5; 24000]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<group> sample=tone.wav ampeg_attack=0 ampeg_release=0.01
+             <region> key=38 loop_mode=loop_continuous loop_start=0 loop_end=4799
+             <region> key=40 loop_mode=loop_sustain loop_start=0 loop_end=4799
+             <region> key=49
+             <region> key=49 loop_mode=loop_continuous loop_start=0 loop_end=4799
+",
+        );
+        let options = SoundfontInitOptions {
+            bank: Some(128),
+            preset: Some(0),
+            ..Default::default()
+        };
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let sf: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap());
+
+        let mut channel = new_channel(Default::default());
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![sf],
+        )));
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+            true,
+        )));
+        let voices = |channel: &VoiceChannel| {
+            [38, 40, 49].map(|key| channel.key_voices[key].data.voice_count())
+        };
+
+        for key in [38, 40, 49] {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key,
+                vel: 127,
+            }));
+        }
+        let mut out = vec![0.0; 4800];
+        channel.read_samples(&mut out);
+        assert_eq!(voices(&channel), [1, 1, 2]);
+
+        for key in [38, 40, 49] {
+            channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                key,
+                vel: 64,
+            }));
+        }
+        channel.read_samples(&mut out);
+
+        // The one-shot keeps playing until the end of its sample
+        assert_eq!(voices(&channel), [0, 0, 1]);
+        let mut out = vec![0.0; 48000];
+        channel.read_samples(&mut out);
+        assert_eq!(voices(&channel), [0, 0, 0]);
     }
 }
@@ -1,6 +1,9 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
 
-use crate::AudioStreamParams;
+use crate::{soundfont::SoundfontBase, AudioStreamParams};
 
 use super::{
     channel_sf::{ChannelSoundfont, ProgramDescriptor},
@@ -11,6 +14,71 @@ use super::{
 #[derive(Debug, Clone)]
 pub struct VoiceChannelStats {
     pub(super) voice_counter: Arc<AtomicU64>,
+    pub(super) rejected_soundfonts: Arc<AtomicU64>,
+    pub(super) soundfont_voices: Arc<RwLock<Vec<AtomicU64>>>,
+    pub(super) dropped_events: Arc<AtomicU64>,
+    pub(super) deferred_spawns: Arc<AtomicU64>,
+    pub(super) key_occupancy: Arc<SharedKeyOccupancy>,
+}
+
+/// The keys of a VoiceChannel which have voices, as one bit per key.
+/// Bit `key % 64` of item `key / 64` of each mask belongs to the key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyOccupancy {
+    /// The keys with any voices, including the released ones
+    pub sounding: [u64; 2],
+
+    /// The keys with voices which weren't released yet. The voices held
+    /// by the damper pedal are not released until the pedal is lifted.
+    pub unreleased: [u64; 2],
+}
+
+impl KeyOccupancy {
+    /// Returns whether the key has any voices.
+    pub fn is_sounding(&self, key: u8) -> bool {
+        mask_has_key(&self.sounding, key)
+    }
+
+    /// Returns whether the key has voices which weren't released yet.
+    pub fn is_unreleased(&self, key: u8) -> bool {
+        mask_has_key(&self.unreleased, key)
+    }
+}
+
+fn mask_has_key(mask: &[u64; 2], key: u8) -> bool {
+    mask.get(key as usize / 64)
+        .is_some_and(|bits| bits & (1 << (key % 64)) != 0)
+}
+
+/// The key occupancy masks, updated by each key after it renders.
+#[derive(Debug, Default)]
+pub(super) struct SharedKeyOccupancy {
+    sounding: [AtomicU64; 2],
+    unreleased: [AtomicU64; 2],
+}
+
+impl SharedKeyOccupancy {
+    pub fn set(&self, key: u8, sounding: bool, unreleased: bool) {
+        let index = key as usize / 64;
+        let bit = 1 << (key % 64);
+        let update = |mask: &[AtomicU64; 2], set: bool| {
+            if set {
+                mask[index].fetch_or(bit, Ordering::Relaxed);
+            } else {
+                mask[index].fetch_and(!bit, Ordering::Relaxed);
+            }
+        };
+        update(&self.sounding, sounding);
+        update(&self.unreleased, unreleased);
+    }
+
+    pub fn load(&self) -> KeyOccupancy {
+        let load = |mask: &[AtomicU64; 2]| mask.each_ref().map(|m| m.load(Ordering::Relaxed));
+        KeyOccupancy {
+            sounding: load(&self.sounding),
+            unreleased: load(&self.unreleased),
+        }
+    }
 }
 
 /// Reads the statistics of an instance of VoiceChannel in a usable way.
@@ -28,13 +96,45 @@ pub struct VoiceChannelParams {
     pub layers: Option<usize>,
     pub channel_sf: ChannelSoundfont,
     pub program: ProgramDescriptor,
+    pub percussion_mode: bool,
+    program_changed: bool,
     pub constant: VoiceChannelConst,
 }
 
 impl VoiceChannelStats {
     pub fn new() -> Self {
-        let voice_counter = Arc::new(AtomicU64::new(0));
-        Self { voice_counter }
+        Self {
+            voice_counter: Arc::new(AtomicU64::new(0)),
+            rejected_soundfonts: Arc::new(AtomicU64::new(0)),
+            soundfont_voices: Arc::new(RwLock::new(Vec::new())),
+            dropped_events: Arc::new(AtomicU64::new(0)),
+            deferred_spawns: Arc::new(AtomicU64::new(0)),
+            key_occupancy: Arc::new(SharedKeyOccupancy::default()),
+        }
+    }
+
+    /// Resizes the per-soundfont voice counters to the soundfont count.
+    fn set_soundfont_count(&self, count: usize) {
+        let mut voices = self.soundfont_voices.write().unwrap();
+        voices.resize_with(count, Default::default);
+    }
+
+    /// Counts the events dropped from the key event caches.
+    pub(super) fn add_dropped_events(&self, count: u64) {
+        self.dropped_events.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Counts the note ons deferred to a later render.
+    pub(super) fn add_deferred_spawns(&self, count: u64) {
+        self.deferred_spawns.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Stores the voice count of each soundfont.
+    pub(super) fn store_soundfont_voices(&self, counts: &[u64]) {
+        let voices = self.soundfont_voices.read().unwrap();
+        for (voices, count) in voices.iter().zip(counts) {
+            voices.store(*count, Ordering::Relaxed);
+        }
     }
 }
 
@@ -53,41 +153,117 @@ impl VoiceChannelParams {
             layers: Some(4),
             channel_sf,
             program: Default::default(),
+            percussion_mode: false,
+            program_changed: false,
             constant: VoiceChannelConst { stream_params },
         }
     }
 
     pub fn process_config_event(&mut self, event: ChannelConfigEvent) {
         match event {
-            ChannelConfigEvent::SetSoundfonts(soundfonts) => {
-                self.channel_sf.set_soundfonts(soundfonts)
+            ChannelConfigEvent::SetSoundfonts(mut soundfonts) => {
+                soundfonts.retain(|sf| self.accepts_soundfont(sf.as_ref()));
+                self.channel_sf.set_soundfonts(soundfonts);
+                self.update_soundfont_count();
+            }
+            ChannelConfigEvent::AddSoundfont { sf, position } => {
+                if self.accepts_soundfont(sf.as_ref()) {
+                    self.channel_sf.add_soundfont(sf, position);
+                    self.update_soundfont_count();
+                }
+            }
+            ChannelConfigEvent::RemoveSoundfont { position } => {
+                self.channel_sf.remove_soundfont(position);
+                self.update_soundfont_count();
             }
             ChannelConfigEvent::SetLayerCount(count) => {
                 self.layers = count;
             }
             ChannelConfigEvent::SetPercussionMode(set) => {
+                self.percussion_mode = set;
                 if set {
                     self.program.bank = 128;
                 } else {
                     self.program.bank = 0;
                 }
-                self.channel_sf.change_program(self.program);
+                self.program_changed = true;
+                self.load_program();
+            }
+            ChannelConfigEvent::SetKeyTuning(_)
+            | ChannelConfigEvent::SetIgnoreNoteOff(_)
+            | ChannelConfigEvent::SetPanLaw(_)
+            | ChannelConfigEvent::SetKeyMap { .. }
+            | ChannelConfigEvent::SetTranspose(_)
+            | ChannelConfigEvent::SetKeyRange(..)
+            | ChannelConfigEvent::SetVelocityRange(..)
+            | ChannelConfigEvent::SetMasterGain(_)
+            | ChannelConfigEvent::SetMasterTuning(_)
+            | ChannelConfigEvent::SetEq(_) => {
+                // Handled by the channel, as they apply to the keys and voices
             }
         }
     }
 
+    fn update_soundfont_count(&self) {
+        self.stats
+            .set_soundfont_count(self.channel_sf.soundfont_count());
+    }
+
+    /// Checks that the soundfont was loaded with the stream parameters of the
+    /// channel, as its samples would otherwise play at the wrong pitch.
+    /// Mismatched soundfonts are counted in the stats.
+    fn accepts_soundfont(&self, sf: &dyn SoundfontBase) -> bool {
+        if *sf.stream_params() == self.constant.stream_params {
+            return true;
+        }
+
+        self.stats
+            .rejected_soundfonts
+            .fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Sets the bank from a bank select MSB. The values 120 (GM2) and 127 (XG)
+    /// select the percussion bank (128) and any other value selects a melodic
+    /// bank. Channels in percussion mode always stay on the percussion bank.
     pub fn set_bank(&mut self, bank: u8) {
-        if self.program.bank != 128 {
-            self.program.bank = bank.min(127);
+        if self.percussion_mode {
+            return;
         }
+
+        self.program.bank = match bank {
+            120 | 127 => 128,
+            bank => bank.min(127),
+        };
+        self.program_changed = true;
     }
 
     pub fn set_preset(&mut self, preset: u8) {
         self.program.preset = preset.min(127);
+        self.program_changed = true;
     }
 
-    pub fn load_program(&mut self) {
-        self.channel_sf.change_program(self.program);
+    /// Sets the bank and preset at once. Channels in percussion mode keep
+    /// the percussion bank.
+    pub fn set_program(&mut self, program: ProgramDescriptor) {
+        if !self.percussion_mode {
+            self.program.bank = program.bank;
+        }
+        self.program.preset = program.preset;
+        self.program_changed = true;
+    }
+
+    /// Applies the program selected with `set_bank` and `set_preset`.
+    /// Does nothing if they weren't called since the last load.
+    /// Returns true if the program was applied.
+    pub fn load_program(&mut self) -> bool {
+        if self.program_changed {
+            self.channel_sf.change_program(self.program);
+            self.program_changed = false;
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -98,8 +274,49 @@ impl VoiceChannelStatsReader {
 
     /// The active voice count of the VoiceChannel.
     pub fn voice_count(&self) -> u64 {
-        self.stats
-            .voice_counter
-            .load(std::sync::atomic::Ordering::Relaxed)
+        self.stats.voice_counter.load(Ordering::Relaxed)
+    }
+
+    /// The amount of soundfonts which were not used by the VoiceChannel
+    /// because they were loaded with different stream parameters.
+    pub fn rejected_soundfonts(&self) -> u64 {
+        self.stats.rejected_soundfonts.load(Ordering::Relaxed)
+    }
+
+    /// The amount of note events which were dropped because too many of
+    /// them were sent to a key without rendering.
+    /// See `ChannelInitOptions::key_event_limit`.
+    pub fn dropped_events(&self) -> u64 {
+        self.stats.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// The amount of note ons which were deferred to a later render. Each
+    /// note on is counted once, even if it waits for several renders.
+    /// See `ChannelInitOptions::max_spawns_per_quantum`.
+    pub fn deferred_spawns(&self) -> u64 {
+        self.stats.deferred_spawns.load(Ordering::Relaxed)
+    }
+
+    /// The active voice count of each soundfont used by the VoiceChannel,
+    /// in the order of its soundfont list. The rejected soundfonts are not
+    /// part of the list.
+    ///
+    /// The voices keep the position of their soundfont from when they were
+    /// spawned, so the counts are only accurate if no soundfonts were added
+    /// or removed before the position while they were playing.
+    pub fn voices_per_soundfont(&self) -> Vec<u64> {
+        let voices = self.stats.soundfont_voices.read().unwrap();
+        voices.iter().map(|v| v.load(Ordering::Relaxed)).collect()
+    }
+
+    /// The keys of the VoiceChannel which have voices, and the ones which
+    /// have voices that weren't released yet.
+    ///
+    /// The masks are updated by each key after it renders, so the note
+    /// events sent since the last render aren't included yet. When read
+    /// from another thread while rendering, some of the keys may already
+    /// be updated while the others aren't.
+    pub fn key_occupancy(&self) -> KeyOccupancy {
+        self.stats.key_occupancy.load()
     }
 }
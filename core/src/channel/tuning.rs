@@ -0,0 +1,129 @@
+use thiserror::Error;
+
+/// Errors that can be generated when parsing a scala scale.
+#[derive(Debug, Error, PartialEq)]
+pub enum ScalaError {
+    #[error("The scale has no note count")]
+    MissingNoteCount,
+
+    #[error("Invalid note count: {0}")]
+    InvalidNoteCount(String),
+
+    #[error("Invalid pitch: {0}")]
+    InvalidPitch(String),
+
+    #[error("Expected {expected} pitches but found {found}")]
+    MissingPitches { expected: usize, found: usize },
+}
+
+/// Parses a pitch line of a scala file to cents. Pitches with a period
+/// are in cents, while the others are ratios (e.g. `3/2` or `2`).
+fn parse_scala_pitch(line: &str) -> Result<f32, ScalaError> {
+    let invalid = || ScalaError::InvalidPitch(line.to_string());
+    let pitch = line.split_whitespace().next().ok_or_else(invalid)?;
+
+    if pitch.contains('.') {
+        return pitch.parse().map_err(|_| invalid());
+    }
+
+    let (num, den) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let num: f32 = num.parse().map_err(|_| invalid())?;
+    let den: f32 = den.parse().map_err(|_| invalid())?;
+    if num <= 0.0 || den <= 0.0 {
+        return Err(invalid());
+    }
+
+    Ok(1200.0 * (num / den).log2())
+}
+
+/// Generates the key tuning of a scala (`.scl`) scale, to be used with
+/// `ChannelConfigEvent::SetKeyTuning`.
+///
+/// The scale is mapped linearly to the keyboard, where `base_key` plays
+/// the first degree of the scale at its 12-TET pitch, and the last pitch
+/// of the scale is the period (usually the octave).
+pub fn key_tuning_from_scala(scl: &str, base_key: u8) -> Result<Vec<(u8, f32)>, ScalaError> {
+    let mut lines = scl
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('!'));
+
+    // The first line is the description, which can be empty
+    lines.next();
+
+    let count = lines.next().ok_or(ScalaError::MissingNoteCount)?;
+    let count: usize = count
+        .split_whitespace()
+        .next()
+        .and_then(|c| c.parse().ok())
+        .filter(|&c| c > 0)
+        .ok_or_else(|| ScalaError::InvalidNoteCount(count.to_string()))?;
+
+    let pitches = lines
+        .filter(|l| !l.is_empty())
+        .take(count)
+        .map(parse_scala_pitch)
+        .collect::<Result<Vec<_>, _>>()?;
+    if pitches.len() < count {
+        return Err(ScalaError::MissingPitches {
+            expected: count,
+            found: pitches.len(),
+        });
+    }
+    let period = pitches[count - 1];
+
+    Ok((0..128u8)
+        .map(|key| {
+            let steps = key as i32 - base_key as i32;
+            let octave = steps.div_euclid(count as i32);
+            let degree = steps.rem_euclid(count as i32) as usize;
+
+            let mut cents = octave as f32 * period;
+            if degree > 0 {
+                cents += pitches[degree - 1];
+            }
+            (key, cents - steps as f32 * 100.0)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scala_tuning() {
+        let edo12 = "! 12-TET\n\n12\n100.\n200.\n300.\n400.\n500.\n600.\n\
+            700.\n800.\n900.\n1000.\n1100.\n2/1\n";
+        let tuning = key_tuning_from_scala(edo12, 60).unwrap();
+        assert_eq!(tuning.len(), 128);
+        assert!(tuning.iter().all(|(_, cents)| cents.abs() < 1e-3));
+
+        // A 5 note scale with a ratio and a cents pitch
+        let scale = "Test scale\n 5 notes\n! comment\n200.0\n3/2 fifth\n700.0\n900.0\n2\n";
+        let tuning = key_tuning_from_scala(scale, 60).unwrap();
+        let cents = |key: u8| tuning[key as usize].1 + (key as f32 - 60.0) * 100.0;
+        assert!(cents(60).abs() < 1e-3);
+        assert!((cents(61) - 200.0).abs() < 1e-3);
+        assert!((cents(62) - 701.955).abs() < 1e-3);
+        assert!((cents(65) - 1200.0).abs() < 1e-3);
+        assert!((cents(59) + 300.0).abs() < 1e-3);
+        assert!((cents(55) + 1200.0).abs() < 1e-3);
+
+        assert_eq!(
+            key_tuning_from_scala("desc\n3\n100.\n", 60),
+            Err(ScalaError::MissingPitches {
+                expected: 3,
+                found: 1
+            })
+        );
+        assert!(matches!(
+            key_tuning_from_scala("desc\n2\n100.\n-1/2\n", 60),
+            Err(ScalaError::InvalidPitch(_))
+        ));
+        assert_eq!(
+            key_tuning_from_scala("desc\n", 60),
+            Err(ScalaError::MissingNoteCount)
+        );
+    }
+}
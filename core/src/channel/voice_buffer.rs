@@ -1,4 +1,4 @@
-use super::ChannelInitOptions;
+use super::{channel_sf::ProgramDescriptor, ChannelInitOptions, DEFAULT_NOTE_OFF_VELOCITY};
 use crate::voice::{ReleaseType, Voice};
 use std::{
     collections::VecDeque,
@@ -9,6 +9,16 @@ use std::{
 struct GroupVoice {
     pub id: usize,
     pub voice: Box<dyn Voice>,
+
+    /// The program the voice was spawned with
+    pub program: ProgramDescriptor,
+
+    /// The position of the soundfont the voice was spawned from in
+    /// the soundfont list of the channel
+    pub soundfont: Option<usize>,
+
+    /// Whether the note off of the voice was ignored, as it ends by itself
+    pub off_ignored: bool,
 }
 
 impl Deref for GroupVoice {
@@ -33,6 +43,8 @@ impl Debug for GroupVoice {
             .field(&self.id)
             .field(&self.voice.velocity())
             .field(&self.voice.is_killed())
+            .field(&self.program)
+            .field(&self.soundfont)
             .finish()
     }
 }
@@ -106,7 +118,9 @@ impl VoiceBuffer {
     fn kill_voice_fade_out(&mut self, index: usize) {
         self.buffer[index]
             .deref_mut()
-            .signal_release(ReleaseType::Kill);
+            .signal_release(ReleaseType::Kill {
+                fade_time: self.options.kill_fade_time,
+            });
     }
 
     pub fn kill_all_voices(&mut self) {
@@ -135,13 +149,21 @@ impl VoiceBuffer {
     pub fn push_voices(
         &mut self,
         voices: impl Iterator<Item = Box<dyn Voice>>,
+        program: ProgramDescriptor,
+        soundfont: Option<usize>,
         max_voices: Option<usize>,
     ) {
         let mut len = 0;
 
         let id = self.get_id();
         for voice in voices {
-            self.buffer.push_back(GroupVoice { id, voice });
+            self.buffer.push_back(GroupVoice {
+                id,
+                voice,
+                program,
+                soundfont,
+                off_ignored: false,
+            });
             len += 1;
         }
 
@@ -160,31 +182,44 @@ impl VoiceBuffer {
         }
     }
 
-    /// Releases the next voice, and all subsequent voices that have the same ID.
-    pub fn release_next_voice(&mut self) -> Option<u8> {
+    /// Releases the next voice, and all subsequent voices that have the same ID,
+    /// with the given note off velocity. Voices held by the damper are released
+    /// with the default velocity when it is lifted. With `ignore_one_shots`,
+    /// only the looping voices are released, as the others end by themselves.
+    /// Returns the velocity and program of the released voices.
+    pub fn release_next_voice(
+        &mut self,
+        off_vel: u8,
+        ignore_one_shots: bool,
+    ) -> Option<(u8, ProgramDescriptor)> {
         if !self.damper_held {
             let mut id: Option<usize> = None;
-            let mut vel = None;
+            let mut released = None;
 
             // Find the first non releasing voice, get its id and release all voices with that id
             for voice in self.buffer.iter_mut() {
-                if voice.is_releasing() {
+                if voice.is_releasing() || (ignore_one_shots && voice.off_ignored) {
                     continue;
                 }
 
                 if id.is_none() {
                     id = Some(voice.id);
-                    vel = Some(voice.velocity())
                 }
 
                 if id != Some(voice.id) {
                     break;
                 }
 
-                voice.signal_release(ReleaseType::Standard);
+                if ignore_one_shots && !voice.is_looping() {
+                    voice.off_ignored = true;
+                    continue;
+                }
+
+                released.get_or_insert((voice.velocity(), voice.program));
+                voice.signal_release(ReleaseType::Standard { vel: off_vel });
             }
 
-            vel
+            released
         } else {
             // Find the first non releasing voice which also isn't being held in the release buffer, and add it to the release buffer
             for voice in self.buffer.iter_mut() {
@@ -227,16 +262,36 @@ impl VoiceBuffer {
         !self.buffer.is_empty()
     }
 
+    /// Returns whether any voice wasn't released yet, including the
+    /// voices held by the damper.
+    pub fn has_unreleased_voices(&self) -> bool {
+        self.buffer
+            .iter()
+            .any(|voice| !voice.is_releasing() && !voice.is_killed())
+    }
+
     pub fn voice_count(&self) -> usize {
         self.buffer.len()
     }
 
+    /// Adds the voice count of each soundfont to the respective item of
+    /// `counts`. Voices from soundfonts past the end of `counts` are skipped.
+    pub fn add_soundfont_voice_counts(&self, counts: &mut [u64]) {
+        for voice in self.buffer.iter() {
+            if let Some(count) = voice.soundfont.and_then(|sf| counts.get_mut(sf)) {
+                *count += 1;
+            }
+        }
+    }
+
     pub fn set_damper(&mut self, damper: bool) {
         if self.damper_held && !damper {
             // Release all voices that are held by the damper
             for voice in self.buffer.iter_mut() {
                 if self.held_by_damper.contains(&voice.id) {
-                    voice.signal_release(ReleaseType::Standard);
+                    voice.signal_release(ReleaseType::Standard {
+                        vel: DEFAULT_NOTE_OFF_VELOCITY,
+                    });
                 }
             }
             self.held_by_damper.clear();
@@ -1,14 +1,16 @@
+use std::sync::Arc;
+
 use crate::soundfont::VoiceSpawner;
 
 use crate::voice::{Voice, VoiceControlData};
 
 pub struct VoiceSpawnerMatrix {
-    voice_spawners_attack: Vec<Vec<Box<dyn VoiceSpawner>>>,
-    voice_spawners_release: Vec<Vec<Box<dyn VoiceSpawner>>>,
+    voice_spawners_attack: Vec<Arc<[Arc<dyn VoiceSpawner>]>>,
+    voice_spawners_release: Vec<Arc<[Arc<dyn VoiceSpawner>]>>,
 }
 
 fn voice_iter_from_vec<'a>(
-    vec: &'a [Box<dyn VoiceSpawner>],
+    vec: &'a [Arc<dyn VoiceSpawner>],
     control: &'a VoiceControlData,
 ) -> impl Iterator<Item = Box<dyn Voice>> + 'a {
     vec.iter().map(move |voice| voice.spawn_voice(control))
@@ -20,8 +22,8 @@ impl VoiceSpawnerMatrix {
         let mut voice_spawners_release = Vec::new();
 
         for _ in 0..(128 * 128) {
-            voice_spawners_attack.push(Vec::new());
-            voice_spawners_release.push(Vec::new());
+            voice_spawners_attack.push(Arc::default());
+            voice_spawners_release.push(Arc::default());
         }
 
         voice_spawners_attack.shrink_to_fit();
@@ -44,23 +46,29 @@ impl VoiceSpawnerMatrix {
     }
 
     #[inline(always)]
-    fn get_attack_spawners_vec_at(&self, key: u8, vel: u8) -> &Vec<Box<dyn VoiceSpawner>> {
+    fn get_attack_spawners_vec_at(&self, key: u8, vel: u8) -> &[Arc<dyn VoiceSpawner>] {
         &self.voice_spawners_attack[self.get_spawners_index_at_attack(key, vel)]
     }
 
     #[inline(always)]
-    fn get_release_spawners_vec_at(&self, key: u8, vel: u8) -> &Vec<Box<dyn VoiceSpawner>> {
+    fn get_release_spawners_vec_at(&self, key: u8, vel: u8) -> &[Arc<dyn VoiceSpawner>] {
         &self.voice_spawners_release[self.get_spawners_index_at_release(key, vel)]
     }
 
+    /// Spawns the attack voices of a key/velocity pair, skipping the
+    /// spawners which aren't part of the round-robin position `counter`.
     #[inline(always)]
     pub fn spawn_voices_attack<'a>(
         &'a self,
         control: &'a VoiceControlData,
         key: u8,
         vel: u8,
+        counter: u32,
     ) -> impl Iterator<Item = Box<dyn Voice>> + 'a {
-        voice_iter_from_vec(self.get_attack_spawners_vec_at(key, vel), control)
+        self.get_attack_spawners_vec_at(key, vel)
+            .iter()
+            .filter(move |spawner| spawner.is_in_sequence(counter))
+            .map(move |spawner| spawner.spawn_voice(control))
     }
 
     #[inline(always)]
@@ -74,13 +82,23 @@ impl VoiceSpawnerMatrix {
     }
 
     #[inline(always)]
-    pub fn set_spawners_attack(&mut self, key: u8, vel: u8, spawners: Vec<Box<dyn VoiceSpawner>>) {
+    pub fn set_spawners_attack(
+        &mut self,
+        key: u8,
+        vel: u8,
+        spawners: Arc<[Arc<dyn VoiceSpawner>]>,
+    ) {
         let index = self.get_spawners_index_at_attack(key, vel);
         self.voice_spawners_attack[index] = spawners;
     }
 
     #[inline(always)]
-    pub fn set_spawners_release(&mut self, key: u8, vel: u8, spawners: Vec<Box<dyn VoiceSpawner>>) {
+    pub fn set_spawners_release(
+        &mut self,
+        key: u8,
+        vel: u8,
+        spawners: Arc<[Arc<dyn VoiceSpawner>]>,
+    ) {
         let index = self.get_spawners_index_at_release(key, vel);
         self.voice_spawners_release[index] = spawners;
     }
@@ -1,4 +1,9 @@
-use crate::{channel::ChannelInitOptions, AudioStreamParams};
+use std::ops::RangeInclusive;
+
+use crate::{
+    channel::{ChannelAudioEvent, ChannelInitOptions, ControlEvent},
+    AudioStreamParams,
+};
 
 /// Controls the channel format that will be used in the synthesizer.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -9,9 +14,23 @@ pub enum SynthFormat {
     Midi,
 
     /// Creates a custom number of channels with the default settings.
+    ///
+    /// If the number of channels is a multiple of 16, each group of 16
+    /// channels can be treated as a MIDI port with its own percussion
+    /// channel. See `ChannelGroupConfig::multi_port_percussion`.
     Custom { channels: u32 },
 }
 
+impl SynthFormat {
+    /// Returns the number of channels used by the format.
+    pub fn channel_count(&self) -> u32 {
+        match self {
+            SynthFormat::Midi => 16,
+            SynthFormat::Custom { channels } => *channels,
+        }
+    }
+}
+
 /// Defines the multithreading options for each task that supports it.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
@@ -77,6 +96,104 @@ impl Default for ParallelismOptions {
     }
 }
 
+/// The zone of an MPE (MIDI Polyphonic Expression) layout.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum MpeZone {
+    /// Channel 1 is the master channel, and the member channels start
+    /// from channel 2 upwards.
+    #[default]
+    Lower,
+
+    /// Channel 16 is the master channel, and the member channels start
+    /// from channel 15 downwards.
+    Upper,
+}
+
+/// Options for treating the first 16 channels as an MPE zone, where each
+/// note is played on its own member channel with its own pitch bend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(default)
+)]
+pub struct MpeConfig {
+    /// The zone layout. See the `MpeZone` documentation for more information.
+    ///
+    /// Default: `MpeZone::Lower`
+    pub zone: MpeZone,
+
+    /// The number of member channels of the zone, clamped to 1-15.
+    ///
+    /// Default: `15`
+    pub member_channels: u32,
+
+    /// The pitch bend range in semitones that the member channels start with.
+    /// It replaces `ChannelInitOptions::pitch_bend_range` for them.
+    ///
+    /// Default: `48.0`
+    pub pitch_bend_range: f32,
+}
+
+impl MpeConfig {
+    /// Returns the index of the master channel of the zone.
+    pub fn master_channel(&self) -> u32 {
+        match self.zone {
+            MpeZone::Lower => 0,
+            MpeZone::Upper => 15,
+        }
+    }
+
+    /// Returns the indexes of the member channels of the zone.
+    pub fn member_channels(&self) -> RangeInclusive<u32> {
+        let count = self.member_channels.clamp(1, 15);
+        match self.zone {
+            MpeZone::Lower => 1..=count,
+            MpeZone::Upper => 15 - count..=14,
+        }
+    }
+
+    /// Returns whether an event sent to the master channel is mirrored to
+    /// the member channels. All the controllers are mirrored, except for the
+    /// RPN/NRPN ones, so that the master and member channels keep their own
+    /// pitch bend range.
+    pub fn is_mirrored(&self, event: &ChannelAudioEvent) -> bool {
+        matches!(
+            event,
+            ChannelAudioEvent::Control(ControlEvent::Raw(cc, _))
+                if !matches!(cc, 0x06 | 0x26 | 0x62..=0x65)
+        )
+    }
+
+    /// Returns the initialization options of a channel, replacing the pitch
+    /// bend range of the member channels.
+    pub fn channel_init_options(
+        &self,
+        channel: u32,
+        options: ChannelInitOptions,
+    ) -> ChannelInitOptions {
+        if self.member_channels().contains(&channel) {
+            ChannelInitOptions {
+                pitch_bend_range: self.pitch_bend_range,
+                ..options
+            }
+        } else {
+            options
+        }
+    }
+}
+
+impl Default for MpeConfig {
+    fn default() -> Self {
+        Self {
+            zone: MpeZone::Lower,
+            member_channels: 15,
+            pitch_bend_range: 48.0,
+        }
+    }
+}
+
 /// Options for initializing a new ChannelGroup.
 #[derive(Clone, Debug, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
@@ -98,4 +215,32 @@ pub struct ChannelGroupConfig {
     /// Options about the `ChannelGroup` instance's parallelism. See the `ParallelismOptions`
     /// documentation for more information.
     pub parallelism: ParallelismOptions,
+
+    /// If set to true, a DC blocking high-pass filter will be applied to the
+    /// output audio, removing the DC offset that some samples carry.
+    ///
+    /// Default: `true`
+    pub dc_blocker: bool,
+
+    /// If set to true and a custom format with a multiple of 16 channels is
+    /// used, channel 10 of every group of 16 channels will be used for
+    /// percussion, as with multiple MIDI ports. Otherwise, all the channels
+    /// of custom formats are standard channels.
+    ///
+    /// Default: `true`
+    pub multi_port_percussion: bool,
+
+    /// If set, the first 16 channels are used as an MPE zone. The member
+    /// channels are never used for percussion and start with the MPE pitch
+    /// bend range, and the controllers sent to the master channel are also
+    /// sent to them. See the `MpeConfig` documentation for more information.
+    ///
+    /// Default: `None`
+    pub mpe: Option<MpeConfig>,
+
+    /// The frequency in Hz of A4 that all the channels are tuned to.
+    /// It can be changed later with `ChannelConfigEvent::SetMasterTuning`.
+    ///
+    /// Default: `440.0`
+    pub master_tuning_hz: f32,
 }
@@ -1,8 +1,13 @@
-use std::sync::Arc;
+use std::{collections::VecDeque, mem, sync::Arc};
 
 use crate::{
-    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, VoiceChannel},
+    channel::{
+        ChannelAudioEvent, ChannelConfigEvent, ChannelControllerState, ChannelEvent,
+        ChannelInitOptions, KeyOccupancy, VoiceChannel,
+    },
+    effects::{DcBlocker, LevelMeter, OutputLevels},
     helpers::{prepapre_cache_vec, sum_simd},
+    soundfont::SoundfontBase,
     AudioPipe, AudioStreamParams,
 };
 
@@ -14,6 +19,27 @@ use rayon::prelude::*;
 
 const MAX_EVENT_CACHE_SIZE: u32 = 1024 * 1024;
 
+/// The controller state of every channel of a ChannelGroup, captured with
+/// `ChannelGroup::capture_controller_state`. See the
+/// `ChannelControllerState` documentation for more information.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControllerSnapshot {
+    channels: Vec<ChannelControllerState>,
+}
+
+impl ControllerSnapshot {
+    /// Creates a snapshot from the state of each channel, in channel order,
+    /// for example captured with `VoiceChannel::controller_state`.
+    pub fn new(channels: Vec<ChannelControllerState>) -> Self {
+        Self { channels }
+    }
+
+    /// Returns the state of each channel, in channel order.
+    pub fn channels(&self) -> &[ChannelControllerState] {
+        &self.channels
+    }
+}
+
 /// Represents a MIDI synthesizer within XSynth.
 ///
 /// Manages multiple VoiceChannel objects at once. For info about MIDI CC
@@ -21,20 +47,30 @@ const MAX_EVENT_CACHE_SIZE: u32 = 1024 * 1024;
 pub struct ChannelGroup {
     thread_pool: Option<rayon::ThreadPool>,
     cached_event_count: u32,
-    channel_events_cache: Box<[Vec<ChannelAudioEvent>]>,
-    sample_cache_vecs: Box<[Vec<f32>]>,
-    channels: Box<[VoiceChannel]>,
+    channel_events_cache: Vec<Vec<ChannelAudioEvent>>,
+    sample_cache_vecs: Vec<Vec<f32>>,
+    channels: Vec<VoiceChannel>,
+    channel_pool: Option<Arc<rayon::ThreadPool>>,
+    channel_init_options: ChannelInitOptions,
     audio_params: AudioStreamParams,
+    format: SynthFormat,
+    multi_port_percussion: bool,
+    dc_blocker: Option<DcBlocker>,
+    scheduled_events: VecDeque<(usize, SynthEvent)>,
+    meter: LevelMeter,
+    mpe: Option<MpeConfig>,
+    /// The soundfont list sent to all channels, with the added and
+    /// removed soundfonts applied, for the channels added later
+    shared_soundfonts: Option<Vec<Arc<dyn SoundfontBase>>>,
+    /// The last config event of each kind sent to all channels,
+    /// other than the soundfont events, for the channels added later
+    shared_config: Vec<ChannelConfigEvent>,
 }
 
 impl ChannelGroup {
     /// Creates a new ChannelGroup with the given configuration.
     /// See the `ChannelGroupConfig` documentation for the available options.
     pub fn new(config: ChannelGroupConfig) -> Self {
-        let mut channels = Vec::new();
-        let mut channel_events_cache = Vec::new();
-        let mut sample_cache_vecs = Vec::new();
-
         // Thread pool for individual channels to split between keys
         let channel_pool = match config.parallelism.key {
             ThreadCount::None => None,
@@ -59,45 +95,211 @@ impl ChannelGroup {
             ),
         };
 
-        let channel_count = match config.format {
-            SynthFormat::Midi => 16,
-            SynthFormat::Custom { channels } => channels,
+        let mut group = Self {
+            thread_pool: group_pool,
+            cached_event_count: 0,
+            channel_events_cache: Vec::new(),
+            channels: Vec::new(),
+            sample_cache_vecs: Vec::new(),
+            channel_pool,
+            channel_init_options: config.channel_init_options,
+            audio_params: config.audio_params,
+            format: config.format,
+            multi_port_percussion: config.multi_port_percussion,
+            dc_blocker: config.dc_blocker.then(|| {
+                DcBlocker::new(
+                    config.audio_params.channels.count(),
+                    config.audio_params.sample_rate,
+                )
+            }),
+            scheduled_events: VecDeque::new(),
+            meter: LevelMeter::new(),
+            mpe: config.mpe,
+            shared_soundfonts: None,
+            shared_config: Vec::new(),
         };
+        group.add_channels(config.format.channel_count());
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterTuning(config.master_tuning_hz),
+        )));
+        group
+    }
+
+    /// Creates a new ChannelGroup with the given configuration, whose channels
+    /// continue from the state of other channels, in channel order: the
+    /// events of `VoiceChannel::config_events` and the state of
+    /// `VoiceChannel::controller_state`. The voices are not transferred.
+    ///
+    /// If the channel counts differ, only the channels present in both are
+    /// configured.
+    pub fn new_with_channel_states(
+        config: ChannelGroupConfig,
+        states: impl IntoIterator<Item = (Vec<ChannelConfigEvent>, ChannelControllerState)>,
+    ) -> Self {
+        let mut group = Self::new(config);
+        let mut controllers = Vec::new();
+        let count = group.channels.len();
+        for (channel, (events, state)) in states.into_iter().take(count).enumerate() {
+            for event in events {
+                group.send_event(SynthEvent::Channel(
+                    channel as u32,
+                    ChannelEvent::Config(event),
+                ));
+            }
+            controllers.push(state);
+        }
+        group.restore_controller_state(&ControllerSnapshot::new(controllers));
+        group
+    }
 
-        for _ in 0..channel_count {
-            channels.push(VoiceChannel::new(
-                config.channel_init_options,
-                config.audio_params,
-                channel_pool.clone(),
-            ));
-            channel_events_cache.push(Vec::new());
-            sample_cache_vecs.push(Vec::new());
+    /// Changes the number of channels of the synthesizer, which also
+    /// changes its format to `SynthFormat::Custom` with that number.
+    ///
+    /// The existing channels keep their state, and the voices of the
+    /// removed channels are killed. The new channels start with the config
+    /// events sent to all channels (e.g. the soundfonts and layer count),
+    /// but not the ones sent to single channels or the controller state,
+    /// which have to be sent to them again. If the change makes channel 10 of every
+    /// group of 16 channels start or stop being used for percussion (see
+    /// `ChannelGroupConfig::multi_port_percussion`), the percussion mode of
+    /// those channels is updated, otherwise it is only set on the new channels.
+    pub fn set_channel_count(&mut self, count: u32) {
+        let old_count = self.channels.len() as u32;
+        if count == old_count {
+            return;
         }
 
-        if config.format == SynthFormat::Midi {
-            channels[9].push_events_iter(std::iter::once(ChannelEvent::Config(
-                ChannelConfigEvent::SetPercussionMode(true),
-            )));
+        // Events already sent to the removed channels are dropped
+        self.flush_events();
+        self.scheduled_events.retain(|(_, event)| match event {
+            SynthEvent::Channel(channel, _) => *channel < count,
+            SynthEvent::AllChannels(_) => true,
+        });
+
+        let old_percussion = self.has_percussion();
+        self.format = SynthFormat::Custom { channels: count };
+        let percussion = self.has_percussion();
+        if percussion != old_percussion {
+            for channel in 0..old_count.min(count) {
+                if self.is_percussion_channel(channel) {
+                    self.channels[channel as usize].process_event(ChannelEvent::Config(
+                        ChannelConfigEvent::SetPercussionMode(percussion),
+                    ));
+                }
+            }
         }
 
-        Self {
-            thread_pool: group_pool,
-            cached_event_count: 0,
-            channel_events_cache: channel_events_cache.into_boxed_slice(),
-            channels: channels.into_boxed_slice(),
-            sample_cache_vecs: sample_cache_vecs.into_boxed_slice(),
-            audio_params: config.audio_params,
+        if count > old_count {
+            self.add_channels(count);
+        } else {
+            for mut channel in self.channels.drain(count as usize..) {
+                channel.process_event(ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled));
+            }
+            self.channel_events_cache.truncate(count as usize);
+            self.sample_cache_vecs.truncate(count as usize);
         }
     }
 
+    /// Adds channels until there are `count` of them, with the
+    /// percussion mode of the current format.
+    fn add_channels(&mut self, count: u32) {
+        let percussion = self.has_percussion();
+        for channel in self.channels.len() as u32..count {
+            let options = match &self.mpe {
+                Some(mpe) => mpe.channel_init_options(channel, self.channel_init_options),
+                None => self.channel_init_options,
+            };
+            let mut new = VoiceChannel::new(options, self.audio_params, self.channel_pool.clone());
+            if percussion && self.is_percussion_channel(channel) {
+                new.push_events_iter(std::iter::once(ChannelEvent::Config(
+                    ChannelConfigEvent::SetPercussionMode(true),
+                )));
+            }
+            if let Some(soundfonts) = &self.shared_soundfonts {
+                new.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+                    soundfonts.clone(),
+                )));
+            }
+            for event in &self.shared_config {
+                new.process_event(ChannelEvent::Config(event.clone()));
+            }
+            self.channels.push(new);
+            self.channel_events_cache.push(Vec::new());
+            self.sample_cache_vecs.push(Vec::new());
+        }
+    }
+
+    /// Remembers a config event sent to all channels, so that it can be
+    /// sent to the channels added by `set_channel_count`.
+    fn remember_shared_config(&mut self, event: &ChannelConfigEvent) {
+        match event {
+            ChannelConfigEvent::SetSoundfonts(soundfonts) => {
+                self.shared_soundfonts = Some(soundfonts.clone());
+            }
+            ChannelConfigEvent::AddSoundfont { sf, position } => {
+                let soundfonts = self.shared_soundfonts.get_or_insert_with(Vec::new);
+                soundfonts.insert((*position).min(soundfonts.len()), sf.clone());
+            }
+            ChannelConfigEvent::RemoveSoundfont { position } => {
+                if let Some(soundfonts) = &mut self.shared_soundfonts {
+                    if *position < soundfonts.len() {
+                        soundfonts.remove(*position);
+                    }
+                }
+            }
+            // The percussion mode of the new channels depends on the format
+            ChannelConfigEvent::SetPercussionMode(_) => {}
+            event => {
+                let kind = mem::discriminant(event);
+                self.shared_config.retain(|e| mem::discriminant(e) != kind);
+                self.shared_config.push(event.clone());
+            }
+        }
+    }
+
+    /// Returns whether channel 10 of every group of 16 channels is used
+    /// for percussion with the current format.
+    fn has_percussion(&self) -> bool {
+        match self.format {
+            SynthFormat::Midi => true,
+            SynthFormat::Custom { channels } => self.multi_port_percussion && channels % 16 == 0,
+        }
+    }
+
+    /// Returns whether a channel is used for percussion if the format has
+    /// percussion channels. MPE member channels are never used for percussion.
+    fn is_percussion_channel(&self, channel: u32) -> bool {
+        channel % 16 == 9
+            && !self
+                .mpe
+                .is_some_and(|mpe| mpe.member_channels().contains(&channel))
+    }
+
     /// Sends a SynthEvent to the ChannelGroup.
     /// See the `SynthEvent` documentation for more information.
+    ///
+    /// If an MPE zone is configured, the controllers sent to its master
+    /// channel are also sent to its member channels.
     pub fn send_event(&mut self, event: SynthEvent) {
         match event {
             SynthEvent::Channel(channel, event) => match event {
                 ChannelEvent::Audio(e) => {
                     self.channel_events_cache[channel as usize].push(e);
                     self.cached_event_count += 1;
+
+                    if let Some(mpe) = self.mpe {
+                        if channel == mpe.master_channel() && mpe.is_mirrored(&e) {
+                            for member in mpe.member_channels() {
+                                if let Some(cache) =
+                                    self.channel_events_cache.get_mut(member as usize)
+                                {
+                                    cache.push(e);
+                                    self.cached_event_count += 1;
+                                }
+                            }
+                        }
+                    }
+
                     if self.cached_event_count > MAX_EVENT_CACHE_SIZE {
                         self.flush_events();
                     }
@@ -114,15 +316,28 @@ impl ChannelGroup {
                         self.flush_events();
                     }
                 }
-                ChannelEvent::Config(_) => {
+                ChannelEvent::Config(config) => {
+                    self.remember_shared_config(&config);
                     for channel in self.channels.iter_mut() {
-                        channel.process_event(event.clone());
+                        channel.process_event(ChannelEvent::Config(config.clone()));
                     }
                 }
             },
         }
     }
 
+    /// Sends a SynthEvent to the ChannelGroup, to be applied at the given
+    /// sample offset (per audio channel) from the start of the next buffer
+    /// that is read. The buffer is split at the offsets of the events, so
+    /// that they are applied sample-accurately.
+    ///
+    /// Events with an offset past the end of the next buffer are applied in
+    /// the following buffers. Events sent with `send_event` are applied at
+    /// the start of the next buffer.
+    pub fn send_event_at_offset(&mut self, event: SynthEvent, sample_offset: usize) {
+        self.scheduled_events.push_back((sample_offset, event));
+    }
+
     fn flush_events(&mut self) {
         if self.cached_event_count == 0 {
             return;
@@ -157,6 +372,51 @@ impl ChannelGroup {
     }
 
     fn render_to(&mut self, buffer: &mut [f32]) {
+        if self.scheduled_events.is_empty() {
+            self.render_segment(buffer);
+        } else {
+            self.render_scheduled(buffer);
+        }
+
+        if let Some(dc_blocker) = &mut self.dc_blocker {
+            dc_blocker.process(buffer);
+        }
+
+        self.meter
+            .measure(buffer, self.audio_params.channels.count() as usize);
+    }
+
+    fn render_scheduled(&mut self, buffer: &mut [f32]) {
+        let channels = self.audio_params.channels.count() as usize;
+        let frames = buffer.len() / channels;
+
+        let mut scheduled = std::mem::take(&mut self.scheduled_events);
+        scheduled
+            .make_contiguous()
+            .sort_by_key(|(offset, _)| *offset);
+
+        let mut pos = 0;
+        while pos < frames {
+            while scheduled.front().is_some_and(|(offset, _)| *offset <= pos) {
+                let (_, event) = scheduled.pop_front().unwrap();
+                self.send_event(event);
+            }
+
+            let end = match scheduled.front() {
+                Some((offset, _)) => (*offset).min(frames),
+                None => frames,
+            };
+            self.render_segment(&mut buffer[pos * channels..end * channels]);
+            pos = end;
+        }
+
+        for (offset, _) in scheduled.iter_mut() {
+            *offset -= frames;
+        }
+        self.scheduled_events = scheduled;
+    }
+
+    fn render_segment(&mut self, buffer: &mut [f32]) {
         self.flush_events();
         buffer.fill(0.0);
 
@@ -165,16 +425,14 @@ impl ChannelGroup {
                 let len = buffer.len();
                 let channels = &mut self.channels;
                 let sample_cache_vecs = &mut self.sample_cache_vecs;
+                let buffer = &mut *buffer;
                 pool.install(move || {
                     channels
                         .par_iter_mut()
                         .zip(sample_cache_vecs.par_iter_mut())
-                        .for_each(|(channel, samples)| {
-                            prepapre_cache_vec(samples, len, 0.0);
-                            channel.read_samples(samples.as_mut_slice());
-                        });
+                        .for_each(|(channel, samples)| render_channel(channel, samples, len));
 
-                    for vec in sample_cache_vecs.iter_mut() {
+                    for vec in sample_cache_vecs.iter().filter(|vec| !vec.is_empty()) {
                         sum_simd(vec, buffer);
                     }
                 });
@@ -187,17 +445,59 @@ impl ChannelGroup {
                     .iter_mut()
                     .zip(self.sample_cache_vecs.iter_mut())
                 {
-                    prepapre_cache_vec(samples, len, 0.0);
-                    channel.read_samples(samples.as_mut_slice());
+                    render_channel(channel, samples, len);
                 }
 
-                for vec in self.sample_cache_vecs.iter_mut() {
+                for vec in self.sample_cache_vecs.iter().filter(|vec| !vec.is_empty()) {
                     sum_simd(vec, buffer);
                 }
             }
         }
     }
 
+    /// Captures the controller state of all the channels, like the volume,
+    /// pan, pitch bend, program and cutoff, which can be restored later with
+    /// `restore_controller_state` to seek without replaying all the events.
+    ///
+    /// The events sent so far are applied first. Scheduled events and the
+    /// active voices are not captured.
+    pub fn capture_controller_state(&mut self) -> ControllerSnapshot {
+        self.flush_events();
+        ControllerSnapshot {
+            channels: self.channels.iter().map(|c| c.controller_state()).collect(),
+        }
+    }
+
+    /// Restores the controller state of the channels from a snapshot taken
+    /// with `capture_controller_state`, after applying the events sent so far.
+    /// The voices are not affected, so the caller may want to kill them first
+    /// when seeking.
+    ///
+    /// If the channel counts differ, only the channels present in both are
+    /// restored.
+    pub fn restore_controller_state(&mut self, snapshot: &ControllerSnapshot) {
+        self.flush_events();
+        for (channel, state) in self.channels.iter_mut().zip(snapshot.channels.iter()) {
+            channel.set_controller_state(state);
+        }
+    }
+
+    /// Returns the peak and RMS levels of the last rendered buffer.
+    /// See the `OutputLevels` documentation for more information.
+    pub fn output_levels(&self) -> OutputLevels {
+        self.meter.levels()
+    }
+
+    /// Returns the channel format of the synthesizer.
+    pub fn format(&self) -> SynthFormat {
+        self.format
+    }
+
+    /// Returns the number of channels of the synthesizer.
+    pub fn channel_count(&self) -> u32 {
+        self.channels.len() as u32
+    }
+
     /// Returns the active voice count of the synthesizer.
     pub fn voice_count(&self) -> u64 {
         self.channels
@@ -205,6 +505,62 @@ impl ChannelGroup {
             .map(|c| c.get_channel_stats().voice_count())
             .sum()
     }
+
+    /// Returns the active voice count of the given channel of the synthesizer,
+    /// or 0 if the channel doesn't exist.
+    pub fn channel_voice_count(&self, channel: usize) -> u64 {
+        self.channels
+            .get(channel)
+            .map_or(0, |c| c.get_channel_stats().voice_count())
+    }
+
+    /// Returns the keys of the given channel which have voices, or no keys
+    /// if the channel doesn't exist.
+    /// See `VoiceChannelStatsReader::key_occupancy` for more information.
+    pub fn key_occupancy(&self, channel: usize) -> KeyOccupancy {
+        self.channels
+            .get(channel)
+            .map_or_else(Default::default, |c| c.key_occupancy())
+    }
+
+    /// Returns the active voice count of each soundfont, in the order of the
+    /// soundfont list, summed across all the channels.
+    ///
+    /// See `VoiceChannelStatsReader::voices_per_soundfont` for more information.
+    pub fn voices_per_soundfont(&self) -> Vec<u64> {
+        let mut counts = Vec::new();
+        for channel in self.channels.iter() {
+            let voices = channel.get_channel_stats().voices_per_soundfont();
+            if counts.len() < voices.len() {
+                counts.resize(voices.len(), 0);
+            }
+            for (count, voices) in counts.iter_mut().zip(voices) {
+                *count += voices;
+            }
+        }
+        counts
+    }
+
+    /// Returns the amount of soundfonts rejected by the channels of the
+    /// synthesizer, because they were loaded with different stream parameters.
+    /// A soundfont sent to all the channels is counted once per channel.
+    pub fn rejected_soundfonts(&self) -> u64 {
+        self.channels
+            .iter()
+            .map(|c| c.get_channel_stats().rejected_soundfonts())
+            .sum()
+    }
+}
+
+/// Renders a channel into its sample cache, leaving the cache empty if the
+/// channel is idle so that it can be skipped when mixing.
+fn render_channel(channel: &mut VoiceChannel, samples: &mut Vec<f32>, len: usize) {
+    if channel.is_idle() {
+        samples.clear();
+    } else {
+        prepapre_cache_vec(samples, len, 0.0);
+        channel.read_samples(samples.as_mut_slice());
+    }
 }
 
 impl AudioPipe for ChannelGroup {
@@ -216,3 +572,650 @@ impl AudioPipe for ChannelGroup {
         self.render_to(to);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::{ChannelAudioEvent, ControlEvent},
+        effects::{EqBand, EqParams},
+        helpers::db_to_amp,
+        soundfont::{
+            test_helpers::{
+                load_test_sfz, load_test_sfz_with_sample, TestSoundfontDir, TEST_SAMPLE_RATE,
+            },
+            SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        },
+        ChannelCount,
+    };
+
+    fn test_group() -> ChannelGroup {
+        let mut group = ChannelGroup::new(ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Custom { channels: 1 },
+            audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe: None,
+            master_tuning_hz: 440.0,
+        });
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz("group_offsets", ""));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+        )));
+        group
+    }
+
+    fn note_on(key: u8) -> SynthEvent {
+        SynthEvent::Channel(
+            0,
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 127 }),
+        )
+    }
+
+    /// Returns the positions where the level of the constant test sample
+    /// starts rising, which are the onsets of the notes.
+    fn onsets(buffer: &[f32]) -> Vec<usize> {
+        let rising: Vec<bool> = buffer.windows(2).map(|w| w[1] - w[0] > 1e-4).collect();
+        (0..rising.len())
+            .filter(|&i| rising[i] && (i == 0 || !rising[i - 1]))
+            .collect()
+    }
+
+    #[test]
+    fn test_event_offsets() {
+        let offsets = [0, 700, 1400, 2100, 2800];
+
+        let mut group = test_group();
+        for i in 0..offsets.len() {
+            group.send_event(note_on(60 + i as u8 * 4));
+        }
+        let mut buffer = vec![0.0; 4096];
+        group.read_samples(&mut buffer);
+        assert_eq!(onsets(&buffer), vec![0]);
+
+        let mut group = test_group();
+        for (i, offset) in offsets.iter().enumerate() {
+            group.send_event_at_offset(note_on(60 + i as u8 * 4), *offset);
+        }
+        group.read_samples(&mut buffer);
+        assert_eq!(onsets(&buffer), offsets);
+    }
+
+    #[test]
+    fn test_event_offsets_across_buffers() {
+        let mut group = test_group();
+        group.send_event_at_offset(note_on(64), 1500);
+        group.send_event_at_offset(note_on(60), 300);
+
+        let mut buffer = vec![0.0; 1000];
+        group.read_samples(&mut buffer);
+        assert_eq!(onsets(&buffer), vec![300]);
+        group.read_samples(&mut buffer);
+        assert_eq!(onsets(&buffer), vec![500]);
+    }
+
+    #[test]
+    fn test_output_levels() {
+        let mut group = test_group();
+        assert_eq!(group.output_levels(), OutputLevels::default());
+
+        group.send_event(note_on(60));
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        group.read_samples(&mut buffer);
+
+        // The constant test sample plays at half scale
+        let levels = group.output_levels();
+        for i in 0..2 {
+            assert!((levels.peak[i] - 0.5).abs() < 1e-3);
+            assert!((levels.rms[i] - 0.5).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_master_gain() {
+        let mut group = test_group();
+        group.send_event(note_on(60));
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        let level = buffer[4799];
+        assert!(level > 0.0);
+
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetMasterGain(0.5),
+        )));
+        group.read_samples(&mut buffer);
+
+        // The change is smoothed, then the level is halved
+        assert!(buffer.windows(2).all(|w| (w[1] - w[0]).abs() < 1e-3));
+        assert!((buffer[4799] - level * 0.5).abs() < 1e-4);
+
+        // The gain is kept when the controllers are reset
+        group.send_event(SynthEvent::Channel(
+            0,
+            ChannelEvent::Audio(ChannelAudioEvent::ResetControl),
+        ));
+        group.read_samples(&mut buffer);
+        assert!((buffer[4799] - level * 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_channel_eq() {
+        let mut group = test_group();
+        group.send_event(note_on(60));
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        let level = buffer[4799];
+
+        // The test sample is constant, so the low shelf applies its full gain
+        let params = EqParams {
+            low: EqBand {
+                freq: 200.0,
+                gain: -12.0,
+                q: 0.707,
+            },
+            ..Default::default()
+        };
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetEq(params),
+        )));
+        group.read_samples(&mut buffer);
+        assert!((buffer[4799] - level * db_to_amp(-12.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_channel_voice_count() {
+        let mut group = test_group();
+        group.send_event(note_on(60));
+        let mut buffer = vec![0.0; 480];
+        group.read_samples(&mut buffer);
+
+        assert_eq!(group.channel_voice_count(0), 1);
+        assert_eq!(group.channel_voice_count(0), group.voice_count());
+        assert_eq!(group.channel_voice_count(1), 0);
+        assert_eq!(group.channel_voice_count(usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_multi_port_percussion() {
+        // Only the percussion channels play the drum bank soundfont
+        let dir = TestSoundfontDir::new("multi_port_percussion");
+        dir.write_wav("sample.wav", TEST_SAMPLE_RATE, &[0.5; 4800]);
+        let sfz = dir.write_sfz("test.sfz", "<region> sample=sample.wav\n");
+        let options = SoundfontInitOptions {
+            bank: Some(128),
+            ..Default::default()
+        };
+        let stream_params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+        let sf: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap());
+        let percussion_channels = |format, multi_port_percussion, mpe| {
+            let mut group = ChannelGroup::new(ChannelGroupConfig {
+                channel_init_options: Default::default(),
+                format,
+                audio_params: stream_params,
+                parallelism: ParallelismOptions {
+                    channel: ThreadCount::None,
+                    key: ThreadCount::None,
+                },
+                dc_blocker: false,
+                multi_port_percussion,
+                mpe,
+                master_tuning_hz: 440.0,
+            });
+            assert_eq!(group.format(), format);
+            assert_eq!(group.channel_count(), format.channel_count());
+            group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetSoundfonts(vec![sf.clone()]),
+            )));
+
+            let mut buffer = vec![0.0; 480];
+            (0..group.channel_count())
+                .filter(|&channel| {
+                    group.send_event(SynthEvent::Channel(
+                        channel,
+                        ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key: 60, vel: 127 }),
+                    ));
+                    group.read_samples(&mut buffer);
+                    group.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+                        ChannelAudioEvent::AllNotesKilled,
+                    )));
+                    let playing = buffer.iter().any(|&s| s != 0.0);
+                    group.read_samples(&mut buffer);
+                    playing
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(percussion_channels(SynthFormat::Midi, true, None), [9]);
+        assert_eq!(
+            percussion_channels(SynthFormat::Custom { channels: 16 }, true, None),
+            [9]
+        );
+        assert_eq!(
+            percussion_channels(SynthFormat::Custom { channels: 32 }, true, None),
+            [9, 25]
+        );
+        assert_eq!(
+            percussion_channels(SynthFormat::Custom { channels: 17 }, true, None),
+            [] as [u32; 0]
+        );
+        assert_eq!(
+            percussion_channels(SynthFormat::Custom { channels: 32 }, false, None),
+            [] as [u32; 0]
+        );
+
+        // MPE member channels are never used for percussion
+        let lower = MpeConfig::default();
+        let upper = MpeConfig {
+            zone: MpeZone::Upper,
+            member_channels: 4,
+            ..Default::default()
+        };
+        assert_eq!(
+            percussion_channels(SynthFormat::Midi, true, Some(lower)),
+            [] as [u32; 0]
+        );
+        assert_eq!(
+            percussion_channels(SynthFormat::Midi, true, Some(upper)),
+            [9]
+        );
+        assert_eq!(
+            percussion_channels(SynthFormat::Custom { channels: 32 }, true, Some(lower)),
+            [25]
+        );
+    }
+
+    #[test]
+    fn test_set_channel_count() {
+        let mut group = ChannelGroup::new(ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Midi,
+            audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe: None,
+            master_tuning_hz: 440.0,
+        });
+        let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz("set_channel_count", ""));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf.clone()]),
+        )));
+
+        group.send_event(note_on(60));
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        let level = buffer[4799];
+        assert!(level > 0.0);
+
+        // Growing keeps channel 0 playing, and the new channels can be used
+        group.set_channel_count(32);
+        assert_eq!(group.channel_count(), 32);
+        assert_eq!(group.format(), SynthFormat::Custom { channels: 32 });
+        group.send_event(SynthEvent::Channel(
+            31,
+            ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(vec![sf])),
+        ));
+        send_audio(
+            &mut group,
+            31,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        group.read_samples(&mut buffer);
+        assert_eq!(group.channel_voice_count(0), 1);
+        assert_eq!(group.channel_voice_count(31), 1);
+        assert!(buffer[0] >= level - 1e-3);
+
+        // Shrinking kills the voices of the removed channels
+        group.set_channel_count(16);
+        group.read_samples(&mut buffer);
+        assert_eq!(group.channel_count(), 16);
+        assert_eq!(group.voice_count(), 1);
+        assert!((buffer[4799] - level).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_idle_channels_skipped() {
+        let render = |channels: u32| {
+            let mut group = ChannelGroup::new(ChannelGroupConfig {
+                channel_init_options: Default::default(),
+                format: SynthFormat::Custom { channels },
+                audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+                parallelism: ParallelismOptions {
+                    channel: ThreadCount::None,
+                    key: ThreadCount::None,
+                },
+                dc_blocker: false,
+                multi_port_percussion: true,
+                mpe: None,
+                master_tuning_hz: 440.0,
+            });
+            let sf: Arc<dyn SoundfontBase> = Arc::new(load_test_sfz("idle_channels", ""));
+            group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetSoundfonts(vec![sf]),
+            )));
+            for channel in 0..4 {
+                let event = ChannelAudioEvent::NoteOn {
+                    key: 60 + channel as u8,
+                    vel: 127,
+                };
+                send_audio(&mut group, channel, event);
+            }
+
+            let mut buffer = vec![0.0; 4800];
+            group.read_samples(&mut buffer);
+            let idle = group.channels.iter().filter(|c| c.is_idle()).count();
+            (buffer, idle)
+        };
+
+        // The idle channels add nothing to the output
+        let (playing, idle) = render(4);
+        assert_eq!(idle, 0);
+        let (mixed, idle) = render(256);
+        assert_eq!(idle, 252);
+        assert_eq!(mixed, playing);
+    }
+
+    #[test]
+    fn test_set_channel_count_shared_config() {
+        let mut group = ChannelGroup::new(ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Midi,
+            audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe: None,
+            master_tuning_hz: 440.0,
+        });
+        let sf = |name| -> Arc<dyn SoundfontBase> { Arc::new(load_test_sfz(name, "")) };
+        let first = sf("shared_config_first");
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf("shared_config_replaced")]),
+        )));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::AddSoundfont {
+                sf: first.clone(),
+                position: 0,
+            },
+        )));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::RemoveSoundfont { position: 1 },
+        )));
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetLayerCount(Some(1)),
+        )));
+
+        // The new channels get the soundfonts and layer count of the group
+        group.set_channel_count(32);
+        for _ in 0..2 {
+            send_audio(
+                &mut group,
+                20,
+                ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+            );
+        }
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        assert!(buffer[4799] > 0.0);
+        assert_eq!(group.channel_voice_count(20), 1);
+        assert_eq!(group.voice_count(), 1);
+        assert_eq!(
+            group.channels[20]
+                .get_channel_stats()
+                .voices_per_soundfont(),
+            [1]
+        );
+
+        // Shrinking kills the voices of the removed channel
+        group.set_channel_count(16);
+        group.read_samples(&mut buffer);
+        assert_eq!(group.voice_count(), 0);
+    }
+
+    #[test]
+    fn test_controller_snapshot() {
+        let sf = || load_test_sfz("controller_snapshot", "");
+        let mut group = mpe_group(sf(), None);
+        let raw =
+            |controller, value| ChannelAudioEvent::Control(ControlEvent::Raw(controller, value));
+        send_audio(&mut group, 0, raw(7, 40));
+        send_audio(&mut group, 0, raw(11, 100));
+        send_audio(&mut group, 1, raw(10, 20));
+        send_audio(&mut group, 2, ChannelAudioEvent::ProgramChange(5));
+        send_audio(
+            &mut group,
+            3,
+            ChannelAudioEvent::Control(ControlEvent::PitchBendValue(0.5)),
+        );
+        send_audio(&mut group, 4, raw(74, 10));
+        send_audio(
+            &mut group,
+            0,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+
+        let snapshot = group.capture_controller_state();
+        assert_eq!(snapshot.channels().len(), 16);
+
+        let mut restored = mpe_group(sf(), None);
+        assert_ne!(restored.capture_controller_state(), snapshot);
+        restored.restore_controller_state(&snapshot);
+        assert_eq!(restored.capture_controller_state(), snapshot);
+
+        // The voices are not captured, and new notes use the restored volume
+        assert_eq!(restored.voice_count(), 0);
+        send_audio(&mut group, 0, ChannelAudioEvent::AllNotesKilled);
+        group.read_samples(&mut buffer);
+        let mut expected = vec![0.0; 4800];
+        let mut output = vec![0.0; 4800];
+        for (group, buffer) in [(&mut group, &mut expected), (&mut restored, &mut output)] {
+            send_audio(group, 0, ChannelAudioEvent::NoteOn { key: 60, vel: 127 });
+            group.read_samples(buffer);
+        }
+        assert!(expected[4799] > 0.0);
+        for (a, b) in expected.iter().zip(output.iter()) {
+            assert!((a - b).abs() < 1e-6, "{a} {b}");
+        }
+    }
+
+    #[test]
+    fn test_new_with_channel_states() {
+        let sf = || load_test_sfz("channel_states", "");
+        let mut group = mpe_group(sf(), None);
+        let raw =
+            |controller, value| ChannelAudioEvent::Control(ControlEvent::Raw(controller, value));
+        group.send_event(SynthEvent::Channel(
+            0,
+            ChannelEvent::Config(ChannelConfigEvent::SetTranspose(5)),
+        ));
+        group.send_event(SynthEvent::Channel(
+            1,
+            ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(Some(1))),
+        ));
+        group.send_event(SynthEvent::Channel(
+            1,
+            ChannelEvent::Config(ChannelConfigEvent::SetMasterGain(0.5)),
+        ));
+        send_audio(&mut group, 0, raw(7, 60));
+        send_audio(&mut group, 1, raw(11, 90));
+        send_audio(&mut group, 1, ChannelAudioEvent::ProgramChange(3));
+        send_audio(
+            &mut group,
+            0,
+            ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+        );
+        // Let the volume changes settle before copying the state
+        let mut buffer = vec![0.0; 4800];
+        group.read_samples(&mut buffer);
+        send_audio(&mut group, 0, ChannelAudioEvent::AllNotesKilled);
+        group.read_samples(&mut buffer);
+
+        let states: Vec<_> = group
+            .channels
+            .iter()
+            .map(|c| (c.config_events(), c.controller_state()))
+            .collect();
+        let config = ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Midi,
+            audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe: None,
+            master_tuning_hz: 440.0,
+        };
+        let mut copy = ChannelGroup::new_with_channel_states(config, states);
+        assert_eq!(
+            copy.capture_controller_state(),
+            group.capture_controller_state()
+        );
+
+        // The voices are not transferred, and new notes sound the same
+        assert_eq!(copy.voice_count(), 0);
+        let mut expected = vec![0.0; 4800];
+        let mut output = vec![0.0; 4800];
+        for (group, buffer) in [(&mut group, &mut expected), (&mut copy, &mut output)] {
+            for channel in 0..2 {
+                send_audio(
+                    group,
+                    channel,
+                    ChannelAudioEvent::NoteOn { key: 60, vel: 100 },
+                );
+            }
+            group.read_samples(buffer);
+        }
+        assert!(expected[4799] > 0.0);
+        for (a, b) in expected.iter().zip(output.iter()) {
+            assert!((a - b).abs() < 1e-6, "{a} {b}");
+        }
+    }
+
+    fn mpe_group(sf: SampleSoundfont, mpe: Option<MpeConfig>) -> ChannelGroup {
+        let mut group = ChannelGroup::new(ChannelGroupConfig {
+            channel_init_options: Default::default(),
+            format: SynthFormat::Midi,
+            audio_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::None,
+                key: ThreadCount::None,
+            },
+            dc_blocker: false,
+            multi_port_percussion: true,
+            mpe,
+            master_tuning_hz: 440.0,
+        });
+        let sf: Arc<dyn SoundfontBase> = Arc::new(sf);
+        group.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+        )));
+        group
+    }
+
+    fn send_audio(group: &mut ChannelGroup, channel: u32, event: ChannelAudioEvent) {
+        group.send_event(SynthEvent::Channel(channel, ChannelEvent::Audio(event)));
+    }
+
+    #[test]
+    fn test_mpe_pitch_bend_range() {
+        let ramp: Vec<f32> = (0..48000).map(|i| i as f32 / 48000.0).collect();
+        let sf = load_test_sfz_with_sample(
+            "mpe_bend_range",
+            &ramp,
+            "ampeg_attack=0",
+            ChannelCount::Mono,
+        );
+        let mut group = mpe_group(sf, Some(MpeConfig::default()));
+
+        // The playback speed is visible in the slope of the ramp
+        let mut bent_slope = |channel| {
+            send_audio(
+                &mut group,
+                channel,
+                ChannelAudioEvent::Control(ControlEvent::PitchBendValue(1.0)),
+            );
+            send_audio(
+                &mut group,
+                channel,
+                ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+            );
+            let mut buffer = vec![0.0; 1024];
+            group.read_samples(&mut buffer);
+            send_audio(&mut group, channel, ChannelAudioEvent::AllNotesKilled);
+            group.read_samples(&mut vec![0.0; 480]);
+            buffer[1000] - buffer[100]
+        };
+
+        // The master channel keeps the default range of 2 semitones,
+        // and the members use the MPE range of 48 semitones
+        let master = bent_slope(0);
+        for member in 1..16 {
+            let shift = 12.0 * (bent_slope(member) / master).log2();
+            assert!((shift - 46.0).abs() < 0.1, "{member}: {shift}");
+        }
+    }
+
+    #[test]
+    fn test_mpe_sustain_mirroring() {
+        let sustained = |mpe| {
+            let mut group = mpe_group(load_test_sfz("mpe_sustain", "ampeg_release=0"), mpe);
+            let mut buffer = vec![0.0; 480];
+
+            send_audio(
+                &mut group,
+                0,
+                ChannelAudioEvent::Control(ControlEvent::Raw(0x40, 127)),
+            );
+            send_audio(
+                &mut group,
+                3,
+                ChannelAudioEvent::NoteOn { key: 60, vel: 127 },
+            );
+            group.read_samples(&mut buffer);
+            send_audio(
+                &mut group,
+                3,
+                ChannelAudioEvent::NoteOff { key: 60, vel: 64 },
+            );
+            group.read_samples(&mut buffer);
+            group.read_samples(&mut buffer);
+            let held = buffer[479] != 0.0;
+
+            send_audio(
+                &mut group,
+                0,
+                ChannelAudioEvent::Control(ControlEvent::Raw(0x40, 0)),
+            );
+            group.read_samples(&mut buffer);
+            group.read_samples(&mut buffer);
+            assert_eq!(buffer[479], 0.0);
+            held
+        };
+
+        // The damper pedal of the master channel holds the notes of the members
+        assert!(sustained(Some(MpeConfig::default())));
+        assert!(!sustained(None));
+        assert!(!sustained(Some(MpeConfig {
+            zone: MpeZone::Upper,
+            ..Default::default()
+        })));
+    }
+}
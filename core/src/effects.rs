@@ -2,3 +2,9 @@ mod limiter;
 pub use limiter::*;
 mod filter;
 pub use filter::*;
+mod dc_blocker;
+pub use dc_blocker::*;
+mod meter;
+pub use meter::*;
+mod eq;
+pub use eq::*;
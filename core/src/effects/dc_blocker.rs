@@ -0,0 +1,92 @@
+use std::f32::consts::PI;
+
+/// The cutoff frequency of the DC blocker in Hz.
+const DC_BLOCKER_CUTOFF: f32 = 10.0;
+
+#[derive(Clone, Copy, Default)]
+struct SingleChannelDcBlocker {
+    last_input: f32,
+    last_output: f32,
+}
+
+impl SingleChannelDcBlocker {
+    #[inline(always)]
+    fn process(&mut self, val: f32, pole: f32) -> f32 {
+        let out = val - self.last_input + pole * self.last_output;
+        self.last_input = val;
+        self.last_output = out;
+        out
+    }
+}
+
+/// A multi-channel one-pole DC blocking high-pass filter.
+///
+/// Removes the DC offset that some samples carry, which would otherwise add
+/// up across many simultaneous voices and waste headroom.
+pub struct DcBlocker {
+    channels: Vec<SingleChannelDcBlocker>,
+    pole: f32,
+}
+
+impl DcBlocker {
+    /// Initializes a new DC blocker with a specified audio channel count
+    /// and sample rate.
+    pub fn new(channel_count: u16, sample_rate: u32) -> DcBlocker {
+        DcBlocker {
+            channels: vec![Default::default(); channel_count as usize],
+            pole: (-2.0 * PI * DC_BLOCKER_CUTOFF / sample_rate as f32).exp(),
+        }
+    }
+
+    /// Removes the DC offset from the given interleaved sample buffer.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let pole = self.pole;
+        for frame in samples.chunks_mut(self.channels.len()) {
+            for (s, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                *s = channel.process(*s, pole);
+            }
+        }
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        self.channels.fill(Default::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dc_removed() {
+        let sample_rate = 48000;
+        let mut blocker = DcBlocker::new(2, sample_rate);
+
+        // Many voices with a small DC offset and a 440 Hz tone
+        let mut buffer = vec![0.0; sample_rate as usize * 2 * 2];
+        for (i, s) in buffer.iter_mut().enumerate() {
+            let t = (i / 2) as f32 / sample_rate as f32;
+            *s = 1000.0 * 0.01 + (2.0 * PI * 440.0 * t).sin();
+        }
+        blocker.process(&mut buffer);
+
+        let tail = &buffer[buffer.len() - sample_rate as usize / 10 * 2..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 1e-3, "mean {mean}");
+
+        let peak = tail.iter().fold(0.0f32, |p, s| p.max(s.abs()));
+        assert!((peak - 1.0).abs() < 0.01, "peak {peak}");
+    }
+
+    #[test]
+    fn test_transient_passes() {
+        let mut blocker = DcBlocker::new(1, 48000);
+        let mut buffer = vec![0.0; 64];
+        buffer[0] = 1.0;
+        blocker.process(&mut buffer);
+
+        assert_eq!(buffer[0], 1.0);
+        assert!(buffer[1] > -0.01 && buffer[1] < 0.0);
+    }
+}
@@ -0,0 +1,227 @@
+use biquad::*;
+
+/// The parameters of a single band of the equalizer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct EqBand {
+    /// The center frequency of the band in Hz, or the corner
+    /// frequency for the shelves.
+    pub freq: f32,
+
+    /// The gain of the band in dB.
+    pub gain: f32,
+
+    /// The Q parameter of the band. For the shelves, it sets the steepness
+    /// of the slope.
+    pub q: f32,
+}
+
+impl EqBand {
+    fn flat(freq: f32) -> Self {
+        Self {
+            freq,
+            gain: 0.0,
+            q: Q_BUTTERWORTH_F32,
+        }
+    }
+}
+
+/// The parameters of the 3-band equalizer.
+///
+/// The default values leave the audio unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct EqParams {
+    /// The low shelf band.
+    ///
+    /// Default: 200Hz, 0dB
+    pub low: EqBand,
+
+    /// The peak band.
+    ///
+    /// Default: 1kHz, 0dB
+    pub mid: EqBand,
+
+    /// The high shelf band.
+    ///
+    /// Default: 5kHz, 0dB
+    pub high: EqBand,
+}
+
+impl EqParams {
+    /// Returns true if none of the bands change the audio.
+    pub fn is_flat(&self) -> bool {
+        [self.low, self.mid, self.high]
+            .iter()
+            .all(|band| band.gain == 0.0)
+    }
+
+    fn coefficients(&self, sample_rate: f32) -> [Coefficients<f32>; 3] {
+        let coeffs = |band: EqBand, fil_type: fn(f32) -> Type<f32>| {
+            // Frequencies above the Nyquist frequency are rejected by the biquad crate
+            let freq = band.freq.clamp(1.0, sample_rate * 0.49);
+            let q = band.q.max(0.01);
+            Coefficients::<f32>::from_params(fil_type(band.gain), sample_rate.hz(), freq.hz(), q)
+                .unwrap()
+        };
+
+        [
+            coeffs(self.low, Type::LowShelf),
+            coeffs(self.mid, Type::PeakingEQ),
+            coeffs(self.high, Type::HighShelf),
+        ]
+    }
+}
+
+impl Default for EqParams {
+    fn default() -> Self {
+        Self {
+            low: EqBand::flat(200.0),
+            mid: EqBand::flat(1000.0),
+            high: EqBand::flat(5000.0),
+        }
+    }
+}
+
+/// A multi-channel 3-band equalizer, made of a low shelf, a peak
+/// and a high shelf filter.
+///
+/// Uses the `biquad` crate for signal processing.
+pub struct Equalizer {
+    channels: Vec<[DirectForm1<f32>; 3]>,
+    params: EqParams,
+    sample_rate: f32,
+}
+
+impl Equalizer {
+    /// Initializes a new equalizer with a specified audio channel count,
+    /// sample rate and parameters.
+    pub fn new(channel_count: u16, sample_rate: u32, params: EqParams) -> Self {
+        let sample_rate = sample_rate as f32;
+        let coeffs = params.coefficients(sample_rate);
+
+        Self {
+            channels: vec![coeffs.map(DirectForm1::<f32>::new); channel_count as usize],
+            params,
+            sample_rate,
+        }
+    }
+
+    /// Returns the parameters of the equalizer.
+    pub fn params(&self) -> EqParams {
+        self.params
+    }
+
+    /// Changes the parameters of the equalizer. The filter state is kept,
+    /// unless the new parameters are flat.
+    pub fn set_params(&mut self, params: EqParams) {
+        let coeffs = params.coefficients(self.sample_rate);
+        for filters in self.channels.iter_mut() {
+            for (filter, coeffs) in filters.iter_mut().zip(coeffs) {
+                filter.replace_coefficients(coeffs);
+            }
+        }
+
+        self.params = params;
+        if params.is_flat() {
+            self.reset();
+        }
+    }
+
+    /// Applies the equalizer to the given interleaved sample buffer.
+    /// Does nothing if the parameters are flat.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.params.is_flat() {
+            return;
+        }
+
+        for frame in samples.chunks_mut(self.channels.len()) {
+            for (s, filters) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                for filter in filters.iter_mut() {
+                    *s = filter.run(*s);
+                }
+            }
+        }
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        for filters in self.channels.iter_mut() {
+            for filter in filters.iter_mut() {
+                filter.reset_state();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// Returns the peak level of a sine after the equalizer settles.
+    fn sine_peak(eq: &mut Equalizer, channels: usize, freq: f32) -> f32 {
+        eq.reset();
+        let mut buffer = vec![0.0; SAMPLE_RATE as usize * channels];
+        for (i, s) in buffer.iter_mut().enumerate() {
+            let t = (i / channels) as f32 / SAMPLE_RATE as f32;
+            *s = (2.0 * PI * freq * t).sin() * 0.25;
+        }
+        eq.process(&mut buffer);
+
+        let tail = &buffer[buffer.len() / 2..];
+        tail.iter().fold(0.0f32, |p, s| p.max(s.abs()))
+    }
+
+    #[test]
+    fn test_peak_boost() {
+        let params = EqParams {
+            mid: EqBand {
+                freq: 1000.0,
+                gain: 6.0,
+                q: 1.0,
+            },
+            ..Default::default()
+        };
+
+        for channels in [1, 2] {
+            let mut eq = Equalizer::new(channels, SAMPLE_RATE, params);
+            let mid = sine_peak(&mut eq, channels as usize, 1000.0);
+            let low = sine_peak(&mut eq, channels as usize, 100.0);
+
+            // +6dB is about double the amplitude
+            assert!((mid / 0.25 - 2.0).abs() < 0.05, "{channels}: {mid}");
+            assert!((low / 0.25 - 1.0).abs() < 0.05, "{channels}: {low}");
+        }
+    }
+
+    #[test]
+    fn test_flat_and_reset() {
+        let mut eq = Equalizer::new(2, SAMPLE_RATE, Default::default());
+        let mut buffer: Vec<f32> = (0..256).map(|i| (i as f32 * 0.1).sin()).collect();
+        let original = buffer.clone();
+        eq.process(&mut buffer);
+        assert_eq!(buffer, original);
+
+        // A reset equalizer gives the same output as a new one
+        let params = EqParams {
+            low: EqBand {
+                freq: 200.0,
+                gain: -12.0,
+                q: Q_BUTTERWORTH_F32,
+            },
+            ..Default::default()
+        };
+        eq.set_params(params);
+        eq.process(&mut buffer);
+        eq.reset();
+        let mut reset = original.clone();
+        eq.process(&mut reset);
+
+        let mut new = original.clone();
+        Equalizer::new(2, SAMPLE_RATE, params).process(&mut new);
+        assert_eq!(reset, new);
+    }
+}
@@ -3,6 +3,11 @@ use biquad::*;
 use simdeez::prelude::*;
 pub use xsynth_soundfonts::FilterType;
 
+/// The lowest cutoff frequency of the filters. Lower cutoffs put the poles
+/// of the high pass filter too close to DC for 32-bit coefficients, which
+/// makes it unstable.
+const MIN_FILTER_FREQ: f32 = 10.0;
+
 #[derive(Clone)]
 pub(crate) struct BiQuadFilter {
     filter: DirectForm1<f32>,
@@ -17,7 +22,7 @@ impl BiQuadFilter {
         }
     }
 
-    fn get_coeffs(
+    pub fn get_coeffs(
         fil_type: FilterType,
         freq: f32,
         sample_rate: f32,
@@ -28,6 +33,10 @@ impl BiQuadFilter {
             None => Q_BUTTERWORTH_F32,
         };
 
+        // Cutoffs above the Nyquist frequency are rejected by the biquad crate,
+        // and the ones right at it make the filter unstable
+        let freq = freq.clamp(MIN_FILTER_FREQ, sample_rate * 0.49);
+
         match fil_type {
             FilterType::LowPass => {
                 Coefficients::<f32>::from_params(Type::LowPass, sample_rate.hz(), freq.hz(), q)
@@ -45,8 +54,19 @@ impl BiQuadFilter {
                     .unwrap()
             }
             FilterType::BandPass => {
-                Coefficients::<f32>::from_params(Type::BandPass, sample_rate.hz(), freq.hz(), q)
-                    .unwrap()
+                // The band pass of the biquad crate has a peak gain of Q, while
+                // this one keeps the peak at 0 dB so the resonance only narrows
+                // the band
+                let omega = 2.0 * std::f32::consts::PI * freq / sample_rate;
+                let alpha = omega.sin() / (2.0 * q);
+                let a0 = 1.0 + alpha;
+                Coefficients {
+                    a1: -2.0 * omega.cos() / a0,
+                    a2: (1.0 - alpha) / a0,
+                    b0: alpha / a0,
+                    b1: 0.0,
+                    b2: -alpha / a0,
+                }
             }
         }
     }
@@ -59,6 +79,10 @@ impl BiQuadFilter {
         self.filter.run(input)
     }
 
+    pub fn reset(&mut self) {
+        self.filter.reset_state();
+    }
+
     #[inline(always)]
     pub fn process_simd<S: Simd>(&mut self, input: S::Vf32) -> S::Vf32 {
         let mut out = input;
@@ -125,6 +149,18 @@ impl MultiChannelBiQuad {
         }
     }
 
+    /// Returns true if the cutoff frequency isn't fading to a new value.
+    pub fn is_settled(&self) -> bool {
+        self.value.is_settled()
+    }
+
+    /// Clears the filter state.
+    pub fn reset(&mut self) {
+        for filter in self.channels.iter_mut() {
+            filter.reset();
+        }
+    }
+
     /// Filters the audio of the given sample buffer.
     pub fn process(&mut self, sample: &mut [f32]) {
         let channel_count = self.channels.len();
@@ -137,3 +173,147 @@ impl MultiChannelBiQuad {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [FilterType; 4] = [
+        FilterType::LowPassPole,
+        FilterType::LowPass,
+        FilterType::HighPass,
+        FilterType::BandPass,
+    ];
+
+    /// Returns the gain of the filter at the given frequency.
+    fn gain(coeffs: &Coefficients<f32>, freq: f32, sample_rate: f32) -> f32 {
+        let omega = 2.0 * std::f64::consts::PI * freq as f64 / sample_rate as f64;
+        // Evaluates a polynomial of z^-1 = e^(-j * omega)
+        let eval = |c0: f32, c1: f32, c2: f32| {
+            let re = c0 as f64 + c1 as f64 * omega.cos() + c2 as f64 * (2.0 * omega).cos();
+            let im = -(c1 as f64 * omega.sin() + c2 as f64 * (2.0 * omega).sin());
+            re.hypot(im)
+        };
+        let num = eval(coeffs.b0, coeffs.b1, coeffs.b2);
+        let den = eval(1.0, coeffs.a1, coeffs.a2);
+        (num / den) as f32
+    }
+
+    #[test]
+    fn test_stable_at_edge_frequencies() {
+        for sample_rate in [44100.0, 48000.0, 192000.0] {
+            for fil_type in TYPES {
+                for freq in [0.0, 1.0, 10.0, 1000.0, sample_rate * 0.49, sample_rate] {
+                    let c = BiQuadFilter::get_coeffs(fil_type, freq, sample_rate, None);
+                    let all = [c.a1, c.a2, c.b0, c.b1, c.b2];
+                    assert!(all.iter().all(|c| c.is_finite()), "{fil_type:?} {freq}");
+
+                    // The poles are inside the unit circle
+                    assert!(
+                        c.a2.abs() < 1.0 && c.a1.abs() < 1.0 + c.a2,
+                        "{fil_type:?} {freq}: {} {}",
+                        c.a1,
+                        c.a2
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_frequency_response() {
+        let sample_rate = 48000.0;
+        let nyquist = sample_rate / 2.0;
+        for freq in [10.0, 1000.0, sample_rate * 0.49] {
+            let coeffs = |fil_type| BiQuadFilter::get_coeffs(fil_type, freq, sample_rate, None);
+
+            for fil_type in [FilterType::LowPassPole, FilterType::LowPass] {
+                let c = coeffs(fil_type);
+                assert!((gain(&c, 0.0, sample_rate) - 1.0).abs() < 1e-3);
+                assert!(gain(&c, nyquist, sample_rate) < 1e-3);
+            }
+
+            let c = coeffs(FilterType::HighPass);
+            assert!(gain(&c, 0.0, sample_rate) < 1e-3);
+            assert!((gain(&c, nyquist, sample_rate) - 1.0).abs() < 1e-3);
+
+            let c = coeffs(FilterType::BandPass);
+            assert!(gain(&c, 0.0, sample_rate) < 1e-3);
+            assert!(gain(&c, nyquist, sample_rate) < 1e-3);
+            let peak = gain(&c, freq, sample_rate);
+            assert!((peak - 1.0).abs() < 1e-2, "{freq}: {peak}");
+        }
+
+        // The cutoff gain of the second order filters is -3 dB
+        for fil_type in [FilterType::LowPass, FilterType::HighPass] {
+            let c = BiQuadFilter::get_coeffs(fil_type, 1000.0, sample_rate, None);
+            let cutoff = gain(&c, 1000.0, sample_rate);
+            assert!((cutoff - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        }
+
+        // Resonance narrows the band without raising the peak
+        let wide = BiQuadFilter::get_coeffs(FilterType::BandPass, 1000.0, sample_rate, None);
+        let narrow = BiQuadFilter::get_coeffs(FilterType::BandPass, 1000.0, sample_rate, Some(8.0));
+        assert!((gain(&narrow, 1000.0, sample_rate) - 1.0).abs() < 1e-2);
+        assert!(gain(&narrow, 2000.0, sample_rate) < gain(&wide, 2000.0, sample_rate) / 4.0);
+    }
+
+    #[test]
+    fn test_filter_types() {
+        use crate::{
+            soundfont::test_helpers::{
+                band_power, load_test_sfz_with_sample, render_voices, TEST_SAMPLE_RATE,
+            },
+            ChannelCount,
+        };
+
+        // Deterministic white noise
+        let mut seed = 1u32;
+        let noise: Vec<f32> = (0..48000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+
+        // The ratios of the power of a band around the cutoff and of the bands
+        // above and below it, to the power of the unfiltered noise
+        let render = |fil_type: &str| {
+            let opcodes = match fil_type {
+                "none" => "ampeg_attack=0".to_string(),
+                fil_type => format!("ampeg_attack=0 cutoff=1000 fil_type={fil_type}"),
+            };
+            let sf = load_test_sfz_with_sample(
+                &format!("filter_{fil_type}"),
+                &noise,
+                &opcodes,
+                ChannelCount::Mono,
+            );
+            let out = render_voices(&sf, 60, 127, 8192);
+            let out = &out[4096..];
+            [
+                band_power(out, TEST_SAMPLE_RATE, 50.0, 200.0),
+                band_power(out, TEST_SAMPLE_RATE, 800.0, 1250.0),
+                band_power(out, TEST_SAMPLE_RATE, 8000.0, 16000.0),
+            ]
+        };
+        let plain = render("none");
+        let ratios = |fil_type| {
+            let bands = render(fil_type);
+            [0, 1, 2].map(|i| bands[i] / plain[i])
+        };
+
+        let [low, _, high] = ratios("lpf_2p");
+        assert!(low > 0.8 && high < 1e-3, "lpf_2p: {low} {high}");
+
+        // A first order filter has a gentler slope
+        let [low, _, pole_high] = ratios("lpf_1p");
+        assert!(low > 0.8 && pole_high < 0.02 && pole_high > high * 10.0);
+
+        let [low, _, high] = ratios("hpf_2p");
+        assert!(low < 2e-3 && high > 0.8, "hpf_2p: {low} {high}");
+
+        let [low, mid, high] = ratios("bpf_2p");
+        assert!(mid > 0.8 && low < mid / 10.0 && high < mid / 10.0);
+    }
+}
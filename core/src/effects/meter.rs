@@ -0,0 +1,96 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+/// Peak and RMS amplitude of the audio channels over the last rendered
+/// buffer. Mono audio has the same values for both channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutputLevels {
+    /// Peak amplitude of the left and right channels.
+    pub peak: [f32; 2],
+
+    /// RMS amplitude of the left and right channels.
+    pub rms: [f32; 2],
+}
+
+/// Measures the peak and RMS levels of interleaved audio.
+///
+/// Clones share the same values, so a clone can be used to read the levels
+/// from another thread while the audio is measured.
+#[derive(Clone, Debug, Default)]
+pub struct LevelMeter {
+    peak: Arc<[AtomicU32; 2]>,
+    rms: Arc<[AtomicU32; 2]>,
+}
+
+impl LevelMeter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Measures the levels of the given interleaved sample buffer, replacing
+    /// the previous levels. Only mono and stereo audio is supported.
+    pub fn measure(&self, samples: &[f32], channels: usize) {
+        let mut peak = [0.0f32; 2];
+        let mut sum = [0.0f32; 2];
+        for frame in samples.chunks_exact(channels) {
+            for (i, s) in frame.iter().take(2).enumerate() {
+                peak[i] = peak[i].max(s.abs());
+                sum[i] += s * s;
+            }
+        }
+
+        let frames = (samples.len() / channels).max(1) as f32;
+        let rms = sum.map(|sum| (sum / frames).sqrt());
+        let right = if channels == 1 { 0 } else { 1 };
+
+        for (i, channel) in [0, right].into_iter().enumerate() {
+            self.peak[i].store(peak[channel].to_bits(), Ordering::Relaxed);
+            self.rms[i].store(rms[channel].to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the levels of the last measured buffer.
+    pub fn levels(&self) -> OutputLevels {
+        let load = |values: &[AtomicU32; 2]| {
+            [0, 1].map(|i| f32::from_bits(values[i].load(Ordering::Relaxed)))
+        };
+
+        OutputLevels {
+            peak: load(&self.peak),
+            rms: load(&self.rms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sine_levels() {
+        let meter = LevelMeter::new();
+        let reader = meter.clone();
+
+        // A full scale sine on the left channel and a half scale one on the right
+        let mut samples = Vec::new();
+        for i in 0..4800 {
+            let s = (i as f32 * std::f32::consts::TAU / 48.0).sin();
+            samples.push(s);
+            samples.push(s * 0.5);
+        }
+        meter.measure(&samples, 2);
+
+        let levels = reader.levels();
+        assert!((levels.peak[0] - 1.0).abs() < 1e-3);
+        assert!((levels.rms[0] - 0.5f32.sqrt()).abs() < 1e-3);
+        assert!((levels.peak[1] - 0.5).abs() < 1e-3);
+        assert!((levels.rms[1] - 0.5f32.sqrt() / 2.0).abs() < 1e-3);
+
+        meter.measure(&samples[..4800], 1);
+        let levels = reader.levels();
+        assert_eq!(levels.peak[0], levels.peak[1]);
+        assert_eq!(levels.rms[0], levels.rms[1]);
+    }
+}
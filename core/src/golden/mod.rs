@@ -0,0 +1,342 @@
+//! Golden audio regression tests.
+//!
+//! Each scenario renders a scripted sequence of events with a small test
+//! soundfont, and compares the output with a reference WAV file checked in
+//! at `core/tests/golden`. The comparison is tolerant, so that harmless
+//! floating point differences between platforms don't fail the tests, while
+//! audible changes to envelopes, filters or interpolation do.
+//!
+//! See `core/tests/golden/README.md` for how to update the references.
+
+use std::{
+    f64::consts::PI,
+    fs,
+    path::{Path, PathBuf},
+};
+
+mod scenarios;
+
+/// Sample rate of the golden renders. Kept low so the references stay small.
+const GOLDEN_SAMPLE_RATE: u32 = 24000;
+
+/// Set this environment variable to write the references instead of
+/// comparing against them.
+const UPDATE_ENV_VAR: &str = "XSYNTH_UPDATE_GOLDEN";
+
+/// Length and hop of the frames used for the spectral comparison.
+const FFT_SIZE: usize = 1024;
+const FFT_HOP: usize = FFT_SIZE / 2;
+
+/// Frequency bands in Hz compared by the spectral metric.
+const BANDS: [(f64, f64); 4] = [
+    (20.0, 250.0),
+    (250.0, 2000.0),
+    (2000.0, 6000.0),
+    (6000.0, 12000.0),
+];
+
+/// Frames where the band is quieter than this in both renders are skipped,
+/// as their level difference is inaudible and mostly rounding noise.
+const BAND_FLOOR_DB: f64 = -80.0;
+
+/// The allowed difference between a render and its reference.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// The largest allowed absolute difference of a single sample.
+    pub max_sample_error: f32,
+
+    /// The largest allowed RMS over all frames of the level difference
+    /// of a frequency band, in dB.
+    pub max_band_rms_db: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            max_sample_error: 1e-3,
+            max_band_rms_db: 0.5,
+        }
+    }
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{name}.wav"))
+}
+
+/// Compares a render with the reference of the scenario, or writes the
+/// reference if the update environment variable is set. Panics with a report
+/// of every failed metric if they don't match.
+pub fn check_golden(name: &str, rendered: &[f32], tolerance: Tolerance) {
+    let path = reference_path(name);
+
+    if std::env::var_os(UPDATE_ENV_VAR).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_wav(&path, rendered);
+        return;
+    }
+
+    let Some(reference) = read_wav(&path) else {
+        panic!(
+            "golden scenario `{name}`: missing or invalid reference {}\n\
+             Run `{UPDATE_ENV_VAR}=1 cargo test -p xsynth-core golden` to create it.",
+            path.display()
+        );
+    };
+
+    let failures = compare(rendered, &reference, tolerance);
+    if failures.is_empty() {
+        return;
+    }
+
+    let actual = std::env::temp_dir().join(format!("xsynth_golden_{name}.wav"));
+    write_wav(&actual, rendered);
+
+    let mut report = format!("golden scenario `{name}` does not match its reference:\n");
+    for failure in failures {
+        report += &format!("  - {failure}\n");
+    }
+    report += &format!(
+        "The render was written to {}\n\
+         If the change is intended, update the references with \
+         `{UPDATE_ENV_VAR}=1 cargo test -p xsynth-core golden`.",
+        actual.display()
+    );
+    panic!("{report}");
+}
+
+/// Returns a description of every metric exceeding the tolerance.
+fn compare(rendered: &[f32], reference: &[f32], tolerance: Tolerance) -> Vec<String> {
+    if rendered.len() != reference.len() {
+        return vec![format!(
+            "length: {} samples, expected {}",
+            rendered.len(),
+            reference.len()
+        )];
+    }
+
+    let mut failures = Vec::new();
+
+    let (index, error) = rendered
+        .iter()
+        .zip(reference)
+        .map(|(a, b)| (a - b).abs())
+        .enumerate()
+        .fold(
+            (0, 0.0f32),
+            |max, (i, e)| if e > max.1 { (i, e) } else { max },
+        );
+    if error > tolerance.max_sample_error {
+        failures.push(format!(
+            "max sample error: {error:.6} at {:.4}s, tolerance {}",
+            index as f32 / GOLDEN_SAMPLE_RATE as f32,
+            tolerance.max_sample_error
+        ));
+    }
+
+    let rendered_bands = band_levels(rendered);
+    let reference_bands = band_levels(reference);
+    for (band, &(low, high)) in BANDS.iter().enumerate() {
+        let diffs: Vec<f64> = rendered_bands
+            .iter()
+            .zip(&reference_bands)
+            .map(|(a, b)| (a[band], b[band]))
+            .filter(|(a, b)| a.max(*b) > BAND_FLOOR_DB)
+            .map(|(a, b)| a.max(BAND_FLOOR_DB) - b.max(BAND_FLOOR_DB))
+            .collect();
+        if diffs.is_empty() {
+            continue;
+        }
+
+        let rms = (diffs.iter().map(|d| d * d).sum::<f64>() / diffs.len() as f64).sqrt();
+        if rms > tolerance.max_band_rms_db {
+            failures.push(format!(
+                "spectral RMS difference in {low}-{high}Hz: {rms:.3}dB, tolerance {}dB",
+                tolerance.max_band_rms_db
+            ));
+        }
+    }
+
+    failures
+}
+
+/// Returns the level in dB of each band for every frame of the audio.
+fn band_levels(samples: &[f32]) -> Vec<[f64; BANDS.len()]> {
+    let window: Vec<f64> = (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FFT_SIZE as f64).cos())
+        .collect();
+    let bin_width = GOLDEN_SAMPLE_RATE as f64 / FFT_SIZE as f64;
+
+    let mut levels = Vec::new();
+    let mut start = 0;
+    while start + FFT_SIZE <= samples.len() {
+        let mut bins: Vec<(f64, f64)> = samples[start..start + FFT_SIZE]
+            .iter()
+            .zip(&window)
+            .map(|(s, w)| (*s as f64 * w, 0.0))
+            .collect();
+        fft(&mut bins);
+
+        levels.push(BANDS.map(|(low, high)| {
+            let energy: f64 = bins[..FFT_SIZE / 2]
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| (low..high).contains(&(*i as f64 * bin_width)))
+                .map(|(_, (re, im))| re * re + im * im)
+                .sum();
+            10.0 * (energy / FFT_SIZE as f64 + 1e-20).log10()
+        }));
+        start += FFT_HOP;
+    }
+    levels
+}
+
+/// An in-place radix-2 FFT. The length must be a power of two.
+fn fft(data: &mut [(f64, f64)]) {
+    let n = data.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (re, im) = data[start + k + len / 2];
+                let odd = (re * cos - im * sin, re * sin + im * cos);
+                let even = data[start + k];
+                data[start + k] = (even.0 + odd.0, even.1 + odd.1);
+                data[start + k + len / 2] = (even.0 - odd.0, even.1 - odd.1);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Writes a mono 32-bit float WAV file.
+fn write_wav(path: &Path, samples: &[f32]) {
+    let data_len = samples.len() as u32 * 4;
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&3u16.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&GOLDEN_SAMPLE_RATE.to_le_bytes());
+    bytes.extend_from_slice(&(GOLDEN_SAMPLE_RATE * 4).to_le_bytes());
+    bytes.extend_from_slice(&4u16.to_le_bytes());
+    bytes.extend_from_slice(&32u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for s in samples {
+        bytes.extend_from_slice(&s.to_le_bytes());
+    }
+
+    fs::write(path, bytes).unwrap();
+}
+
+/// Reads the samples of a WAV file written by `write_wav`.
+fn read_wav(path: &Path) -> Option<Vec<f32>> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let chunk = bytes.get(pos + 8..pos + 8 + len)?;
+
+        if id == b"fmt " {
+            let format = u16::from_le_bytes(chunk.get(..2)?.try_into().ok()?);
+            let channels = u16::from_le_bytes(chunk.get(2..4)?.try_into().ok()?);
+            if format != 3 || channels != 1 {
+                return None;
+            }
+        } else if id == b"data" {
+            return Some(
+                chunk
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect(),
+            );
+        }
+
+        pos += 8 + len + len % 2;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amp: f32) -> Vec<f32> {
+        (0..GOLDEN_SAMPLE_RATE)
+            .map(|i| {
+                let t = i as f64 / GOLDEN_SAMPLE_RATE as f64;
+                (2.0 * PI * freq * t).sin() as f32 * amp
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fft_bin() {
+        let mut data: Vec<(f64, f64)> = (0..64)
+            .map(|i| ((2.0 * PI * 4.0 * i as f64 / 64.0).cos(), 0.0))
+            .collect();
+        fft(&mut data);
+        for (i, (re, im)) in data.iter().enumerate() {
+            let expected = if i == 4 || i == 60 { 32.0 } else { 0.0 };
+            assert!((re.hypot(*im) - expected).abs() < 1e-9, "bin {i}");
+        }
+    }
+
+    #[test]
+    fn test_compare_metrics() {
+        let reference = sine(440.0, 0.5);
+        assert!(compare(&reference, &reference, Tolerance::default()).is_empty());
+
+        // A tiny difference passes, and an audible level change in one band fails
+        let close = sine(440.0, 0.5002);
+        assert!(compare(&close, &reference, Tolerance::default()).is_empty());
+
+        let louder = sine(440.0, 0.6);
+        let failures = compare(&louder, &reference, Tolerance::default());
+        assert!(failures[0].starts_with("max sample error"), "{failures:?}");
+        assert!(failures.iter().any(|f| f.contains("250-2000Hz")));
+        assert!(!failures.iter().any(|f| f.contains("6000-12000Hz")));
+
+        let failures = compare(&reference[1..], &reference, Tolerance::default());
+        assert!(failures[0].starts_with("length"));
+    }
+
+    #[test]
+    fn test_wav_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("xsynth_golden_wav_{}.wav", std::process::id()));
+        let samples = sine(1000.0, 0.25);
+        write_wav(&path, &samples);
+        let read = read_wav(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), samples);
+    }
+}
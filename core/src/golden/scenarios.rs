@@ -0,0 +1,230 @@
+use std::sync::Arc;
+
+use super::{check_golden, Tolerance, GOLDEN_SAMPLE_RATE};
+use crate::{
+    channel::{
+        ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ChannelInitOptions, ControlEvent,
+        VoiceChannel,
+    },
+    soundfont::{test_helpers::TestSoundfontDir, SampleSoundfont, SoundfontBase},
+    AudioPipe, AudioStreamParams, ChannelCount,
+};
+
+fn stream_params() -> AudioStreamParams {
+    AudioStreamParams::new(GOLDEN_SAMPLE_RATE, ChannelCount::Mono)
+}
+
+/// Builds the test soundfont of the scenarios:
+/// - Keys 0-71 play a looped 240Hz saw wave, with its key center at 60.
+/// - Keys 72-127 play a decaying 480Hz sine wave followed by a short loop
+///   of a 120Hz sine wave, with its key center at 72.
+fn golden_soundfont() -> Arc<dyn SoundfontBase> {
+    let rate = GOLDEN_SAMPLE_RATE as f32;
+    let saw: Vec<f32> = (0..GOLDEN_SAMPLE_RATE)
+        .map(|i| ((i % 100) as f32 / 50.0 - 1.0) * 0.5)
+        .collect();
+    let loop_sample: Vec<f32> = (0..2600)
+        .map(|i| {
+            let t = i as f32 / rate;
+            if i < 2400 {
+                (t * 480.0 * std::f32::consts::TAU).sin() * (1.0 - i as f32 / 2400.0) * 0.5
+            } else {
+                (t * 120.0 * std::f32::consts::TAU).sin() * 0.25
+            }
+        })
+        .collect();
+
+    let dir = TestSoundfontDir::new("golden");
+    dir.write_wav("saw.wav", GOLDEN_SAMPLE_RATE, &saw);
+    dir.write_wav("loop.wav", GOLDEN_SAMPLE_RATE, &loop_sample);
+    let sfz = dir.write_sfz(
+        "golden.sfz",
+        "<group> ampeg_attack=0.005 ampeg_release=0.05 loop_mode=loop_continuous\n\
+         <region> sample=saw.wav lokey=0 hikey=71 pitch_keycenter=60 \
+         loop_start=0 loop_end=23999\n\
+         <region> sample=loop.wav lokey=72 hikey=127 pitch_keycenter=72 \
+         loop_start=2400 loop_end=2599\n",
+    );
+
+    Arc::new(SampleSoundfont::new_sfz(sfz, stream_params(), Default::default()).unwrap())
+}
+
+/// Renders the events of a scenario script into a single buffer.
+struct GoldenRender {
+    channel: VoiceChannel,
+    out: Vec<f32>,
+}
+
+impl GoldenRender {
+    fn new(options: ChannelInitOptions) -> Self {
+        let mut channel = VoiceChannel::new(options, stream_params(), None);
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![golden_soundfont()],
+        )));
+        Self {
+            channel,
+            out: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, event: ChannelAudioEvent) {
+        self.channel.process_event(ChannelEvent::Audio(event));
+    }
+
+    fn note_on(&mut self, key: u8, vel: u8) {
+        self.send(ChannelAudioEvent::NoteOn { key, vel });
+    }
+
+    fn note_off(&mut self, key: u8) {
+        self.send(ChannelAudioEvent::NoteOff { key, vel: 64 });
+    }
+
+    fn cc(&mut self, controller: u8, value: u8) {
+        self.send(ChannelAudioEvent::Control(ControlEvent::Raw(
+            controller, value,
+        )));
+    }
+
+    fn render(&mut self, seconds: f32) {
+        let start = self.out.len();
+        let len = (seconds * GOLDEN_SAMPLE_RATE as f32) as usize;
+        self.out.resize(start + len, 0.0);
+        self.channel.read_samples(&mut self.out[start..]);
+    }
+}
+
+fn run_scenario(
+    name: &str,
+    options: ChannelInitOptions,
+    tolerance: Tolerance,
+    script: impl FnOnce(&mut GoldenRender),
+) {
+    let mut render = GoldenRender::new(options);
+    script(&mut render);
+    check_golden(name, &render.out, tolerance);
+}
+
+#[test]
+fn golden_sustained_note() {
+    run_scenario(
+        "sustained_note",
+        Default::default(),
+        Default::default(),
+        |r| {
+            r.note_on(60, 127);
+            r.render(0.4);
+            r.note_off(60);
+            r.render(0.1);
+        },
+    );
+}
+
+#[test]
+fn golden_pitch_bend_sweep() {
+    run_scenario(
+        "pitch_bend_sweep",
+        Default::default(),
+        Default::default(),
+        |r| {
+            r.note_on(60, 127);
+            for step in 0..=40 {
+                let value = step as f32 / 20.0 - 1.0;
+                r.send(ChannelAudioEvent::Control(ControlEvent::PitchBendValue(
+                    value,
+                )));
+                r.render(0.01);
+            }
+            r.note_off(60);
+            r.render(0.1);
+        },
+    );
+}
+
+#[test]
+fn golden_damper_pedal() {
+    run_scenario(
+        "damper_pedal",
+        Default::default(),
+        Default::default(),
+        |r| {
+            r.cc(0x40, 127);
+            r.note_on(60, 127);
+            r.render(0.1);
+            r.note_off(60);
+            r.render(0.2);
+            r.cc(0x40, 0);
+            r.render(0.1);
+        },
+    );
+}
+
+#[test]
+fn golden_cutoff_sweep() {
+    run_scenario(
+        "cutoff_sweep",
+        Default::default(),
+        Default::default(),
+        |r| {
+            r.note_on(48, 127);
+            for step in 0..=40 {
+                r.cc(0x4A, 127 - step * 3);
+                r.render(0.01);
+            }
+            r.note_off(48);
+            r.render(0.1);
+        },
+    );
+}
+
+/// Plays a note which gets killed by a second one due to the layer limit.
+fn voice_limit_kill(r: &mut GoldenRender) {
+    r.channel
+        .process_event(ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(
+            Some(1),
+        )));
+    r.note_on(60, 127);
+    r.render(0.1);
+    r.note_on(60, 64);
+    r.render(0.2);
+    r.note_off(60);
+    r.render(0.1);
+}
+
+#[test]
+fn golden_voice_limit_kill() {
+    run_scenario(
+        "voice_limit_kill",
+        Default::default(),
+        Default::default(),
+        voice_limit_kill,
+    );
+}
+
+#[test]
+fn golden_voice_limit_kill_fade() {
+    let options = ChannelInitOptions {
+        fade_out_killing: true,
+        ..Default::default()
+    };
+    run_scenario(
+        "voice_limit_kill_fade",
+        options,
+        Default::default(),
+        voice_limit_kill,
+    );
+}
+
+#[test]
+fn golden_loop_crossing() {
+    run_scenario(
+        "loop_crossing",
+        Default::default(),
+        Default::default(),
+        |r| {
+            r.note_on(72, 127);
+            r.render(0.3);
+            r.note_off(72);
+            r.render(0.1);
+        },
+    );
+}
@@ -3,6 +3,9 @@ use std::sync::Arc;
 mod frequencies;
 pub use frequencies::*;
 
+mod key_maps;
+pub use key_maps::*;
+
 mod simd;
 pub use simd::*;
 
@@ -22,6 +25,40 @@ pub fn db_to_amp(db: f32) -> f32 {
     10f32.powf(db / 20.0)
 }
 
+/// Converts a 7-bit MIDI 1.0 velocity to a 16-bit MIDI 2.0 velocity, using
+/// the min-center-max scaling of the MIDI 2.0 specification.
+pub fn velocity_to_16bit(vel: u8) -> u16 {
+    let vel = vel.min(127) as u16;
+    let scaled = vel << 9;
+    if vel <= 64 {
+        return scaled;
+    }
+
+    // Repeat the bits above the center to fill the lower bits
+    let repeat = vel & 0x3f;
+    scaled | (repeat << 3) | (repeat >> 3)
+}
+
+/// Converts a 16-bit MIDI 2.0 velocity to the 0.0-127.0 range of MIDI 1.0
+/// velocities, keeping its fractional part. This follows the same scaling
+/// as `velocity_to_16bit`, so 0x8000 is 64.0 and 0xFFFF is 127.0.
+pub fn velocity_from_16bit(vel: u16) -> f32 {
+    if vel <= 0x8000 {
+        vel as f32 / 512.0
+    } else {
+        64.0 + (vel - 0x8000) as f32 * 63.0 / 0x7fff as f32
+    }
+}
+
+/// Advances a SplitMix64 generator and returns its next random value.
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
 /// Checks if two `Arc<T>` vecs are equal based on `Arc::ptr_eq`.
 pub fn are_arc_vecs_equal<T: ?Sized>(old: &[Arc<T>], new: &[Arc<T>]) -> bool {
     // First, check if the lengths are the same
@@ -38,3 +75,65 @@ pub fn are_arc_vecs_equal<T: ?Sized>(old: &[Arc<T>], new: &[Arc<T>]) -> bool {
 
     true
 }
+
+/// Splits an interleaved sample buffer into one buffer per audio channel,
+/// for output backends which use planar audio (e.g. JACK).
+///
+/// XSynth always processes and outputs interleaved audio internally, so
+/// this should be applied to the samples read from an `AudioPipe`. The
+/// channel count is the length of `planar`, and each of its buffers should
+/// hold `interleaved.len() / planar.len()` samples.
+pub fn deinterleave(interleaved: &[f32], planar: &mut [&mut [f32]]) {
+    let channels = planar.len();
+    assert!(interleaved.len().is_multiple_of(channels));
+    for (i, frame) in interleaved.chunks_exact(channels).enumerate() {
+        for (channel, sample) in planar.iter_mut().zip(frame) {
+            channel[i] = *sample;
+        }
+    }
+}
+
+/// Merges one buffer per audio channel into an interleaved sample buffer.
+/// This is the inverse of `deinterleave`.
+pub fn interleave(planar: &[&[f32]], interleaved: &mut [f32]) {
+    let channels = planar.len();
+    assert!(interleaved.len().is_multiple_of(channels));
+    for (i, frame) in interleaved.chunks_exact_mut(channels).enumerate() {
+        for (sample, channel) in frame.iter_mut().zip(planar) {
+            *sample = channel[i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_velocity_to_16bit() {
+        assert_eq!(velocity_to_16bit(0), 0);
+        assert_eq!(velocity_to_16bit(1), 0x0200);
+        assert_eq!(velocity_to_16bit(64), 0x8000);
+        assert_eq!(velocity_to_16bit(127), 0xffff);
+
+        for vel in 0..=127 {
+            let wide = velocity_to_16bit(vel);
+            assert_eq!((wide >> 9) as u8, vel);
+            assert!((velocity_from_16bit(wide) - vel as f32).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_deinterleave_stereo() {
+        let interleaved = [0.1, -0.1, 0.2, -0.2, 0.3, -0.3];
+        let mut left = [0.0; 3];
+        let mut right = [0.0; 3];
+        deinterleave(&interleaved, &mut [&mut left, &mut right]);
+        assert_eq!(left, [0.1, 0.2, 0.3]);
+        assert_eq!(right, [-0.1, -0.2, -0.3]);
+
+        let mut restored = [0.0; 6];
+        interleave(&[&left, &right], &mut restored);
+        assert_eq!(restored, interleaved);
+    }
+}
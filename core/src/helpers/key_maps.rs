@@ -0,0 +1,65 @@
+/// Returns a key map which plays every key unchanged. Useful as a base
+/// for custom maps used with `ChannelConfigEvent::SetKeyMap`.
+pub fn identity_key_map() -> [u8; 128] {
+    std::array::from_fn(|key| key as u8)
+}
+
+fn key_map_with(pairs: &[(u8, u8)]) -> [u8; 128] {
+    let mut map = identity_key_map();
+    for &(from, to) in pairs {
+        map[from as usize] = to;
+    }
+    map
+}
+
+/// Returns a key map from the XG standard drum kit to the GM2 drum layout.
+///
+/// The kits share the layout from key 27 upwards, so only the XG sounds
+/// on keys 13 to 34 are moved, to the closest GM2 sound.
+pub fn xg_drum_key_map() -> [u8; 128] {
+    key_map_with(&[
+        (13, 86), // Surdo Mute -> Mute Surdo
+        (14, 87), // Surdo Open -> Open Surdo
+        (15, 27), // Hi Q -> High Q
+        (16, 28), // Whip Slap -> Slap
+        (17, 29), // Scratch Push
+        (18, 30), // Scratch Pull
+        (19, 39), // Finger Snap -> Hand Clap
+        (20, 32), // Click Noise -> Square Click
+        (21, 33), // Metronome Click
+        (22, 34), // Metronome Bell
+        (23, 33), // Seq Click L -> Metronome Click
+        (24, 32), // Seq Click H -> Square Click
+        (25, 38), // Brush Tap -> Acoustic Snare
+        (26, 38), // Brush Swirl L -> Acoustic Snare
+        (27, 38), // Brush Slap -> Acoustic Snare
+        (28, 40), // Brush Swirl H -> Electric Snare
+        (29, 38), // Snare Roll -> Acoustic Snare
+        (30, 85), // Castanet -> Castanets
+        (31, 40), // Snare L -> Electric Snare
+        (32, 31), // Sticks
+        (33, 35), // Bass Drum L -> Acoustic Bass Drum
+        (34, 37), // Open Rim Shot -> Side Stick
+    ])
+}
+
+/// Returns a key map from the GM2 drum layout to the GM one, for
+/// soundfonts which only have the GM drum sounds (keys 35 to 81).
+pub fn gm2_to_gm_drum_key_map() -> [u8; 128] {
+    key_map_with(&[
+        (27, 75), // High Q -> Claves
+        (28, 39), // Slap -> Hand Clap
+        (29, 69), // Scratch Push -> Cabasa
+        (30, 69), // Scratch Pull -> Cabasa
+        (31, 37), // Sticks -> Side Stick
+        (32, 75), // Square Click -> Claves
+        (33, 76), // Metronome Click -> Hi Wood Block
+        (34, 53), // Metronome Bell -> Ride Bell
+        (82, 70), // Shaker -> Maracas
+        (83, 54), // Jingle Bell -> Tambourine
+        (84, 81), // Bell Tree -> Open Triangle
+        (85, 75), // Castanets -> Claves
+        (86, 41), // Mute Surdo -> Low Floor Tom
+        (87, 43), // Open Surdo -> High Floor Tom
+    ])
+}
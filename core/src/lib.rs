@@ -20,3 +20,6 @@ pub mod effects;
 pub mod helpers;
 
 pub mod channel_group;
+
+#[cfg(test)]
+mod golden;
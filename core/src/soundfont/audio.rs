@@ -8,7 +8,7 @@ use symphonia::core::{codecs::DecoderOptions, errors::Error};
 
 use crate::{AudioStreamParams, ChannelCount};
 use thiserror::Error;
-use xsynth_soundfonts::resample::resample_vecs;
+use xsynth_soundfonts::resample::{resample_vecs, ResampleQuality};
 
 /// Errors that can be generated when loading an audio file.
 #[derive(Debug, Error)]
@@ -28,11 +28,15 @@ pub enum AudioLoadError {
 
 type ProcessedSample = (Arc<[Arc<[f32]>]>, u32);
 
+/// Loads an audio file and returns its samples with their native sample rate.
+/// The samples are resampled to the stream sample rate with the given quality
+/// if `resample` is set, otherwise they are kept at the native rate of the file.
 pub(super) fn load_audio_file(
     path: &PathBuf,
     stream_params: AudioStreamParams,
+    resample: Option<ResampleQuality>,
 ) -> Result<ProcessedSample, AudioLoadError> {
-    let new_sample_rate = stream_params.sample_rate as f32;
+    let resample_to = resample.map(|quality| (stream_params.sample_rate as f32, quality));
 
     let extension = path.extension().and_then(|ext| ext.to_str());
 
@@ -108,7 +112,7 @@ pub(super) fn load_audio_file(
         }
     }
 
-    let built = builder.finish(sample_rate as f32, new_sample_rate, stream_params.channels);
+    let built = builder.finish(sample_rate as f32, resample_to, stream_params.channels);
 
     Ok((built, sample_rate))
 }
@@ -157,7 +161,7 @@ impl BuilderVecs {
     fn finish(
         self,
         sample_rate: f32,
-        new_sample_rate: f32,
+        resample_to: Option<(f32, ResampleQuality)>,
         channels: ChannelCount,
     ) -> Arc<[Arc<[f32]>]> {
         let mut vecs = self.vecs;
@@ -178,6 +182,100 @@ impl BuilderVecs {
             chan.shrink_to_fit();
         }
 
-        resample_vecs(vecs, sample_rate, new_sample_rate)
+        match resample_to {
+            Some((new_sample_rate, quality)) => {
+                resample_vecs(vecs, sample_rate, new_sample_rate, quality)
+            }
+            None => vecs.into_iter().map(|chan| chan.into()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundfont::{test_helpers::*, Interpolator, SampleSoundfont, SoundfontInitOptions};
+
+    #[test]
+    fn test_native_rate_playback() {
+        // A 200Hz sine at half the stream sample rate, looping over whole periods
+        let sine = |pos: f32| (pos / 24000.0 * 200.0 * std::f32::consts::TAU).sin() * 0.5;
+        let samples: Vec<f32> = (0..4800).map(|i| sine(i as f32)).collect();
+        let dir = TestSoundfontDir::new("native_rate");
+        dir.write_wav("sample.wav", 24000, &samples);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav pitch_keycenter=60 offset=60 \
+             loop_mode=loop_continuous loop_start=2400 loop_end=4799\n",
+        );
+        let params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+
+        let load = |resample_on_load| {
+            let options = SoundfontInitOptions {
+                interpolator: Interpolator::Linear,
+                resample_on_load,
+                ..Default::default()
+            };
+            SampleSoundfont::new_sfz(&sfz, params, options).unwrap()
+        };
+        let native = render_voices(&load(false), 60, 127, 12000);
+
+        // The loop is crossed once, so the offset and the loop points must be in
+        // native sample rate indices for the sine to continue seamlessly
+        let attack = 480;
+        for (i, s) in native.iter().enumerate().skip(attack) {
+            let expected = sine(60.0 + i as f32 / 2.0);
+            assert!(
+                (s - expected).abs() < 1e-3,
+                "sample {i}: {s}, expected {expected}"
+            );
+        }
+
+        // The resampled sample is close to the native one before reaching the loop,
+        // allowing for the slight delay of the resampler
+        let resampled = render_voices(&load(true), 60, 127, 12000);
+        let error = resampled[attack..9000]
+            .iter()
+            .zip(&native[attack..9000])
+            .fold(0.0f32, |a, (r, n)| a.max((r - n).abs()));
+        assert!(error < 0.02, "{error}");
+    }
+
+    #[test]
+    fn test_resample_quality() {
+        // A 1 kHz sine at 22050 Hz, resampled to 96 kHz on load
+        let sine: Vec<f32> = (0..22050)
+            .map(|i| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 22050.0).sin() * 0.5)
+            .collect();
+        let dir = TestSoundfontDir::new("resample_quality");
+        dir.write_wav("sample.wav", 22050, &sine);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav pitch_keycenter=60 ampeg_attack=0\n",
+        );
+
+        // The power of the images above the original Nyquist frequency,
+        // relative to the power of the sine
+        let aliasing = |quality| {
+            let options = SoundfontInitOptions {
+                resample_quality: quality,
+                ..Default::default()
+            };
+            let sf = SampleSoundfont::new_sfz(
+                sfz.clone(),
+                AudioStreamParams::new(96000, ChannelCount::Mono),
+                options,
+            )
+            .unwrap();
+            let out = render_voices(&sf, 60, 127, 8192);
+            let out = &out[4096..];
+            band_power(out, 96000, 15000.0, 40000.0) / band_power(out, 96000, 900.0, 1100.0)
+        };
+
+        let nearest = aliasing(ResampleQuality::Nearest);
+        let linear = aliasing(ResampleQuality::Linear);
+        let sinc = aliasing(ResampleQuality::Sinc);
+        assert!(linear < nearest, "{linear} {nearest}");
+        assert!(sinc < nearest / 100.0, "{sinc} {nearest}");
     }
 }
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use super::{SoundfontBase, SoundfontInitOptions, VoiceSpawner};
+use crate::AudioStreamParams;
+
+/// A soundfont entry configuration following the semantics of BASSMIDI's
+/// `BASS_MIDI_FONTEX` structure. Useful for migrating existing BASSMIDI or
+/// OmniMIDI font lists to XSynth.
+///
+/// Negative values follow the BASSMIDI conventions described in each field.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(default)
+)]
+pub struct BassFontEx {
+    /// The source preset number in the soundfont. `-1` means all presets.
+    ///
+    /// Default: `-1`
+    pub spreset: i32,
+
+    /// The source bank number in the soundfont. `-1` means all banks.
+    ///
+    /// Default: `-1`
+    pub sbank: i32,
+
+    /// The destination preset number. `-1` means to keep the source
+    /// preset number.
+    ///
+    /// Default: `-1`
+    pub dpreset: i32,
+
+    /// The destination bank number. If `sbank` is `-1`, this is used as
+    /// a base bank number which is added to the source bank numbers.
+    ///
+    /// Default: `0`
+    pub dbank: i32,
+
+    /// The destination bank LSB number. XSynth only handles the bank MSB,
+    /// so entries with a non-zero LSB can never be selected.
+    ///
+    /// Default: `0`
+    pub dbanklsb: i32,
+}
+
+impl Default for BassFontEx {
+    fn default() -> Self {
+        Self {
+            spreset: -1,
+            sbank: -1,
+            dpreset: -1,
+            dbank: 0,
+            dbanklsb: 0,
+        }
+    }
+}
+
+fn to_midi_number(value: i32, max: u8) -> Option<u8> {
+    if (0..=max as i32).contains(&value) {
+        Some(value as u8)
+    } else {
+        None
+    }
+}
+
+impl BassFontEx {
+    /// Applies the source bank/preset filters of the entry to the given
+    /// soundfont options.
+    pub fn apply_to_options(&self, options: SoundfontInitOptions) -> SoundfontInitOptions {
+        SoundfontInitOptions {
+            bank: to_midi_number(self.sbank, 128).or(options.bank),
+            preset: to_midi_number(self.spreset, 127).or(options.preset),
+            ..options
+        }
+    }
+
+    /// Returns `true` if the entry moves presets to different bank/preset
+    /// numbers, and therefore the soundfont needs to be wrapped in a
+    /// `RemappedSoundfont`.
+    pub fn needs_remap(&self) -> bool {
+        let bank_moved = if self.sbank < 0 {
+            self.dbank > 0
+        } else {
+            self.dbank >= 0 && self.dbank != self.sbank
+        };
+        let preset_moved = self.spreset >= 0 && self.dpreset >= 0 && self.dpreset != self.spreset;
+
+        bank_moved || preset_moved || self.dbanklsb != 0
+    }
+
+    /// Wraps the given soundfont in a `RemappedSoundfont` if the entry
+    /// requires remapping, otherwise returns it unchanged.
+    pub fn wrap_soundfont(&self, soundfont: Arc<dyn SoundfontBase>) -> Arc<dyn SoundfontBase> {
+        if self.needs_remap() {
+            Arc::new(RemappedSoundfont::new(soundfont, *self))
+        } else {
+            soundfont
+        }
+    }
+
+    /// Translates a requested destination bank/preset to the bank/preset
+    /// of the inner soundfont. Returns `None` if the request is not covered
+    /// by this entry.
+    pub fn source_of(&self, bank: u8, preset: u8) -> Option<(u8, u8)> {
+        if self.dbanklsb != 0 {
+            return None;
+        }
+
+        let bank = bank as i32;
+        let sbank = if self.sbank < 0 {
+            bank - self.dbank.max(0)
+        } else if (self.dbank >= 0 && bank == self.dbank) || (self.dbank < 0 && bank == self.sbank)
+        {
+            self.sbank
+        } else {
+            return None;
+        };
+
+        let preset = preset as i32;
+        let spreset = if self.spreset < 0 {
+            preset
+        } else if preset == self.dpreset || (self.dpreset < 0 && preset == self.spreset) {
+            self.spreset
+        } else {
+            return None;
+        };
+
+        Some((to_midi_number(sbank, 128)?, to_midi_number(spreset, 127)?))
+    }
+}
+
+/// A thin wrapper around a soundfont which exposes its presets at different
+/// bank/preset numbers, as described by a `BassFontEx` entry.
+#[derive(Debug)]
+pub struct RemappedSoundfont {
+    inner: Arc<dyn SoundfontBase>,
+    fontex: BassFontEx,
+}
+
+impl RemappedSoundfont {
+    /// Creates a new remapped soundfont.
+    ///
+    /// Parameters:
+    /// - `inner`: The soundfont to be remapped.
+    /// - `fontex`: The remapping configuration. See the `BassFontEx`
+    ///   documentation for more information.
+    pub fn new(inner: Arc<dyn SoundfontBase>, fontex: BassFontEx) -> Self {
+        Self { inner, fontex }
+    }
+}
+
+impl SoundfontBase for RemappedSoundfont {
+    fn stream_params(&self) -> &'_ AudioStreamParams {
+        self.inner.stream_params()
+    }
+
+    fn get_attack_voice_spawners_at(
+        &self,
+        bank: u8,
+        preset: u8,
+        key: u8,
+        vel: u8,
+    ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+        match self.fontex.source_of(bank, preset) {
+            Some((bank, preset)) => self
+                .inner
+                .get_attack_voice_spawners_at(bank, preset, key, vel),
+            None => Arc::default(),
+        }
+    }
+
+    fn get_release_voice_spawners_at(
+        &self,
+        bank: u8,
+        preset: u8,
+        key: u8,
+        vel: u8,
+    ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+        match self.fontex.source_of(bank, preset) {
+            Some((bank, preset)) => self
+                .inner
+                .get_release_voice_spawners_at(bank, preset, key, vel),
+            None => Arc::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        voice::{
+            ReleaseType, Voice, VoiceBase, VoiceControlData, VoiceGeneratorBase,
+            VoiceSampleGenerator,
+        },
+        ChannelCount,
+    };
+
+    /// A voice generator which renders nothing and ends right away.
+    struct SilentGenerator;
+
+    impl VoiceGeneratorBase for SilentGenerator {
+        fn ended(&self) -> bool {
+            true
+        }
+
+        fn signal_release(&mut self, _rel_type: ReleaseType) {}
+
+        fn process_controls(&mut self, _control: &VoiceControlData) {}
+    }
+
+    impl VoiceSampleGenerator for SilentGenerator {
+        fn render_to(&mut self, _buffer: &mut [f32]) {}
+    }
+
+    struct DummySpawner;
+
+    impl VoiceSpawner for DummySpawner {
+        fn spawn_voice(&self, _control: &VoiceControlData) -> Box<dyn Voice> {
+            Box::new(VoiceBase::new(100, SilentGenerator))
+        }
+    }
+
+    /// A soundfont with a single piano at bank 0, preset 0.
+    #[derive(Debug)]
+    struct PianoSoundfont {
+        stream_params: AudioStreamParams,
+    }
+
+    impl SoundfontBase for PianoSoundfont {
+        fn stream_params(&self) -> &'_ AudioStreamParams {
+            &self.stream_params
+        }
+
+        fn get_attack_voice_spawners_at(
+            &self,
+            bank: u8,
+            preset: u8,
+            _key: u8,
+            _vel: u8,
+        ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+            if bank == 0 && preset == 0 {
+                Arc::new([Arc::new(DummySpawner) as Arc<dyn VoiceSpawner>])
+            } else {
+                Arc::default()
+            }
+        }
+
+        fn get_release_voice_spawners_at(
+            &self,
+            _bank: u8,
+            _preset: u8,
+            _key: u8,
+            _vel: u8,
+        ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+            Arc::default()
+        }
+    }
+
+    fn piano() -> Arc<dyn SoundfontBase> {
+        Arc::new(PianoSoundfont {
+            stream_params: AudioStreamParams::new(48000, ChannelCount::Stereo),
+        })
+    }
+
+    fn count(sf: &Arc<dyn SoundfontBase>, bank: u8, preset: u8) -> usize {
+        sf.get_attack_voice_spawners_at(bank, preset, 60, 100).len()
+    }
+
+    #[test]
+    fn test_remap_piano() {
+        let fontex = BassFontEx {
+            spreset: 0,
+            sbank: 0,
+            dpreset: 10,
+            dbank: 5,
+            dbanklsb: 0,
+        };
+        let sf = fontex.wrap_soundfont(piano());
+
+        assert_eq!(count(&sf, 5, 10), 1);
+        assert_eq!(count(&sf, 0, 0), 0);
+        assert_eq!(count(&sf, 5, 0), 0);
+        assert_eq!(count(&sf, 0, 10), 0);
+
+        let spawners = sf.get_attack_voice_spawners_at(5, 10, 60, 100);
+        let voice = spawners[0].spawn_voice(&VoiceControlData::new_defaults());
+        assert!(voice.ended());
+    }
+
+    #[test]
+    fn test_source_filter() {
+        let fontex = BassFontEx {
+            spreset: 0,
+            sbank: 0,
+            dpreset: 10,
+            dbank: 5,
+            dbanklsb: 0,
+        };
+        let options = fontex.apply_to_options(Default::default());
+        assert_eq!(options.bank, Some(0));
+        assert_eq!(options.preset, Some(0));
+
+        for bank in 0..=128 {
+            for preset in 0..128 {
+                let expected = if (bank, preset) == (5, 10) {
+                    Some((0, 0))
+                } else {
+                    None
+                };
+                assert_eq!(fontex.source_of(bank, preset), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_identity() {
+        let fontex = BassFontEx::default();
+        assert!(!fontex.needs_remap());
+        assert_eq!(
+            fontex.apply_to_options(Default::default()),
+            Default::default()
+        );
+        assert_eq!(fontex.source_of(3, 7), Some((3, 7)));
+
+        let offset = BassFontEx {
+            dbank: 2,
+            ..Default::default()
+        };
+        assert!(offset.needs_remap());
+        assert_eq!(offset.source_of(2, 7), Some((0, 7)));
+        assert_eq!(offset.source_of(1, 7), None);
+    }
+
+    #[test]
+    fn test_source_bank_only() {
+        let fontex = BassFontEx {
+            sbank: 3,
+            dbank: -1,
+            ..Default::default()
+        };
+        assert_eq!(fontex.source_of(3, 7), Some((3, 7)));
+        assert_eq!(fontex.source_of(0, 7), None);
+        assert_eq!(fontex.source_of(4, 7), None);
+    }
+}
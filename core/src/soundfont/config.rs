@@ -1,3 +1,5 @@
+pub use xsynth_soundfonts::resample::ResampleQuality;
+
 /// Type of the audio sample interpolation algorithm.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
@@ -25,6 +27,13 @@ pub enum EnvelopeCurveType {
     /// The decay and release stages will use a concave curve, while the
     /// attack stage will use a convex curve.
     Exponential,
+
+    /// Apply an analog style exponential curve to the envelope stage, in
+    /// amplitude units. The stage approaches its target like a charging
+    /// capacitor, reaching about 63% of the way after one time constant,
+    /// with the stage duration being five time constants.
+    /// This option is supported by the attack, decay and release stages.
+    Analog,
 }
 
 /// Options for the curves of a specific envelope.
@@ -100,6 +109,63 @@ pub struct SoundfontInitOptions {
     ///
     /// Default: `Nearest`
     pub interpolator: Interpolator,
+
+    /// If set to true, the samples are resampled to the output sample rate
+    /// when the soundfont is loaded. If set to false, they are kept at their
+    /// native sample rate and the rate difference is applied to the playback
+    /// speed of the voices instead, which makes loading large soundfonts much
+    /// faster. The output is very close, but lower quality interpolators can
+    /// produce slightly more aliasing without the load time resampling.
+    ///
+    /// Default: `true`
+    pub resample_on_load: bool,
+
+    /// The algorithm used to resample the samples when `resample_on_load`
+    /// is enabled. See the documentation of the `ResampleQuality` enum for
+    /// available options.
+    ///
+    /// Default: `Sinc`
+    pub resample_quality: ResampleQuality,
+
+    /// The seed of the random variations applied to the voices by the
+    /// `offset_random`, `pitch_random` and `amp_random` SFZ opcodes. With
+    /// the same seed, the same MIDI events render the same output, which
+    /// is useful for offline rendering. `None` uses a different seed every
+    /// time the soundfont is loaded.
+    ///
+    /// Default: `None`
+    pub random_seed: Option<u64>,
+
+    /// The amount of milliseconds skipped from the start of every sample
+    /// of the soundfont. Used to align the transients of soundfonts which
+    /// are layered together, when some of their samples start with silence.
+    ///
+    /// Default: `0.0`
+    pub start_trim_ms: f32,
+
+    /// The amount of milliseconds that the voices of the soundfont are
+    /// delayed by, which is added to the delay of their volume envelope.
+    /// Used to align the transients of soundfonts which are layered together.
+    ///
+    /// Default: `0.0`
+    pub delay_ms: f32,
+
+    /// The maximum random detune in cents applied to every voice of the
+    /// soundfont, in both directions. Each voice draws its own detune, so
+    /// that retriggered notes and layered regions vary independently,
+    /// which thickens unison and acoustic patches. It is drawn on top of
+    /// the `pitch_random` SFZ opcode, and follows `random_seed` as well.
+    ///
+    /// Default: `0.0`
+    pub humanize_cents: f32,
+
+    /// If set to `true`, the load fails with an `InvalidRegion` error when a
+    /// region has loop points or an offset which don't fit its sample, or
+    /// other problems which are otherwise reported by `load_warnings` and
+    /// repaired. Useful when developing soundfonts.
+    ///
+    /// Default: `false`
+    pub strict_validation: bool,
 }
 
 impl Default for SoundfontInitOptions {
@@ -110,6 +176,13 @@ impl Default for SoundfontInitOptions {
             vol_envelope_options: Default::default(),
             use_effects: true,
             interpolator: Interpolator::Nearest,
+            resample_on_load: true,
+            resample_quality: ResampleQuality::Sinc,
+            random_seed: None,
+            start_trim_ms: 0.0,
+            delay_ms: 0.0,
+            humanize_cents: 0.0,
+            strict_validation: false,
         }
     }
 }
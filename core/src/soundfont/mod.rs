@@ -1,38 +1,67 @@
 #![allow(non_camel_case_types)]
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt,
+    hash::{BuildHasher, Hasher},
     io,
+    ops::RangeInclusive,
+    panic::AssertUnwindSafe,
     path::PathBuf,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
 };
 
 use biquad::Q_BUTTERWORTH_F32;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use simdeez::Simd;
 use thiserror::Error;
-use xsynth_soundfonts::{convert_sample_index, FilterType, LoopMode};
+use xsynth_soundfonts::{convert_sample_index_u64, FilterType, LoopMode};
 
 use self::audio::load_audio_file;
 pub use self::audio::AudioLoadError;
 
 use super::{
     voice::VoiceControlData,
-    voice::{EnvelopeParameters, Voice},
+    voice::{EnvelopeParameters, LfoParameters, Voice},
+};
+use crate::{
+    helpers::{db_to_amp, splitmix64},
+    AudioStreamParams, ChannelCount,
 };
-use crate::{helpers::db_to_amp, AudioStreamParams, ChannelCount};
 
 pub use xsynth_soundfonts::{sf2::Sf2ParseError, sfz::SfzParseError};
 
 mod audio;
+mod compat;
 mod config;
+mod progress;
+mod spawner_list;
 mod utils;
 mod voice_spawners;
+
+#[cfg(test)]
+pub(crate) mod test_helpers;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+use spawner_list::*;
 use utils::*;
 use voice_spawners::*;
 
+pub use compat::*;
 pub use config::*;
+pub use progress::*;
 
 pub trait VoiceSpawner: Sync + Send {
     fn spawn_voice(&self, control: &VoiceControlData) -> Box<dyn Voice>;
+
+    /// Returns whether the spawner is used by a note-on, where `counter` is
+    /// the number of previous note-ons of the same key. Used for round-robin
+    /// regions, see the `seq_length` and `seq_position` SFZ opcodes.
+    fn is_in_sequence(&self, _counter: u32) -> bool {
+        true
+    }
 }
 
 pub trait SoundfontBase: Sync + Send + std::fmt::Debug {
@@ -44,26 +73,101 @@ pub trait SoundfontBase: Sync + Send + std::fmt::Debug {
         preset: u8,
         key: u8,
         vel: u8,
-    ) -> Vec<Box<dyn VoiceSpawner>>;
+    ) -> Arc<[Arc<dyn VoiceSpawner>]>;
     fn get_release_voice_spawners_at(
         &self,
         bank: u8,
         preset: u8,
         key: u8,
         vel: u8,
-    ) -> Vec<Box<dyn VoiceSpawner>>;
+    ) -> Arc<[Arc<dyn VoiceSpawner>]>;
 }
 
 #[derive(Clone)]
 pub(super) struct LoopParams {
     pub mode: LoopMode,
+    pub offset: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The random variations of the voices of a region, as set by the
+/// `offset_random`, `pitch_random` and `amp_random` SFZ opcodes and
+/// the `humanize_cents` soundfont option.
+#[derive(Clone, Copy, Default)]
+pub(super) struct RandomParams {
+    /// Maximum amount of samples added to the offset
     pub offset: u32,
-    pub start: u32,
-    pub end: u32,
+    /// Maximum detune in cents, in both directions
+    pub pitch: f32,
+    /// Maximum gain in dB
+    pub amp: f32,
+    /// Maximum detune in cents of `humanize_cents`, drawn separately
+    pub humanize: f32,
+    /// Mixed with the random value of each voice, so that the regions
+    /// spawned by the same note vary independently
+    pub seed: u64,
+}
+
+/// The random variations drawn for a single voice.
+pub(super) struct VoiceRandom {
+    pub offset: u32,
+    pub speed_mult: f32,
+    pub amp: f32,
+}
+
+impl RandomParams {
+    pub fn is_enabled(&self) -> bool {
+        self.offset != 0 || self.pitch != 0.0 || self.amp != 0.0 || self.humanize != 0.0
+    }
+
+    /// Draws the variations of a voice from its random value.
+    /// See `VoiceControlData::random`.
+    pub fn draw(&self, random: u64) -> VoiceRandom {
+        let mut state = random ^ self.seed;
+        let mut next = || (splitmix64(&mut state) >> 40) as f32 / (1 << 24) as f32;
+
+        let offset = ((next() * (self.offset as f32 + 1.0)) as u32).min(self.offset);
+        let cents = (next() * 2.0 - 1.0) * self.pitch;
+        let db = next() * self.amp;
+        let cents = cents + (next() * 2.0 - 1.0) * self.humanize;
+
+        VoiceRandom {
+            offset,
+            speed_mult: cents_factor(cents),
+            amp: db_to_amp(db),
+        }
+    }
+}
+
+/// The round-robin position of a region, as set by the `seq_length`
+/// and `seq_position` SFZ opcodes.
+#[derive(Clone, Copy)]
+pub(super) struct SequenceParams {
+    pub length: u8,
+    /// 1-based position in the sequence
+    pub position: u8,
+}
+
+impl Default for SequenceParams {
+    fn default() -> Self {
+        Self {
+            length: 1,
+            position: 1,
+        }
+    }
+}
+
+impl SequenceParams {
+    pub fn contains(&self, counter: u32) -> bool {
+        counter % self.length as u32 + 1 == self.position as u32
+    }
 }
 
 struct SampleVoiceSpawnerParams {
     volume: f32,
+    amp_veltrack: f32,
+    amp_velcurve: Option<Arc<[f32; 128]>>,
     pan: f32,
     speed_mult: f32,
     cutoff: Option<f32>,
@@ -71,14 +175,127 @@ struct SampleVoiceSpawnerParams {
     filter_type: FilterType,
     loop_params: LoopParams,
     envelope: Arc<EnvelopeParameters>,
+    release_veltrack: f32,
+    pitch_envelope: Option<Arc<EnvelopeParameters>>,
+    pitch_envelope_depth: f32,
+    filter_envelope: Option<Arc<EnvelopeParameters>>,
+    filter_envelope_depth: f32,
+    mod_lfo: Option<LfoParameters>,
+    mod_lfo_to_cutoff: f32,
+    mod_lfo_to_volume: f32,
+    random: RandomParams,
+    sequence: SequenceParams,
     sample: Arc<[Arc<[f32]>]>,
     interpolator: Interpolator,
 }
 
+type SpawnerList = Arc<[Arc<dyn VoiceSpawner>]>;
+
+/// The spawner lists of a key, indexed by velocity. Each list is built
+/// the first time its velocity is requested.
+type KeySpawners = Box<[OnceLock<SpawnerList>]>;
+
+/// Creates the voice spawner of a region for a velocity.
+type SpawnerConstructor =
+    fn(&SampleVoiceSpawnerParams, u8, AudioStreamParams) -> Arc<dyn VoiceSpawner>;
+
+fn new_stereo_spawner<S: 'static + Simd + Send + Sync>(
+    params: &SampleVoiceSpawnerParams,
+    vel: u8,
+    stream_params: AudioStreamParams,
+) -> Arc<dyn VoiceSpawner> {
+    Arc::new(StereoSampledVoiceSpawner::<S>::new(
+        params,
+        vel,
+        stream_params,
+    ))
+}
+
+fn new_mono_spawner<S: 'static + Simd + Send + Sync>(
+    params: &SampleVoiceSpawnerParams,
+    vel: u8,
+    stream_params: AudioStreamParams,
+) -> Arc<dyn VoiceSpawner> {
+    Arc::new(MonoSampledVoiceSpawner::<S>::new(
+        params,
+        vel,
+        stream_params,
+    ))
+}
+
+/// Picks the spawner constructor for the SIMD level of the CPU, so that
+/// the runtime detection only happens once per soundfont.
+fn spawner_constructor(channels: ChannelCount) -> SpawnerConstructor {
+    use simdeez::*; // nuts
+
+    use simdeez::prelude::*;
+
+    simd_runtime_generate!(
+        fn get(channels: ChannelCount) -> SpawnerConstructor {
+            match channels {
+                ChannelCount::Stereo => new_stereo_spawner::<S>,
+                ChannelCount::Mono => new_mono_spawner::<S>,
+            }
+        }
+    );
+
+    get(channels)
+}
+
 pub(super) struct SoundfontInstrument {
     bank: u8,
     preset: u8,
-    spawner_params_list: Vec<Vec<Arc<SampleVoiceSpawnerParams>>>,
+    name: Option<String>,
+    spawner_params_list: Vec<KeySpawnerList<SampleVoiceSpawnerParams>>,
+    /// The spawners of each key and velocity. Built from the spawner
+    /// parameters the first time the key and velocity are requested. A panic
+    /// while building them leaves the entry uninitialized, so the cache can't
+    /// be observed in a broken state.
+    spawners: Box<[OnceLock<AssertUnwindSafe<KeySpawners>>]>,
+}
+
+impl SoundfontInstrument {
+    fn new(
+        bank: u8,
+        preset: u8,
+        name: Option<String>,
+        spawner_params_list: Vec<KeySpawnerList<SampleVoiceSpawnerParams>>,
+    ) -> Self {
+        Self {
+            bank,
+            preset,
+            name,
+            spawners: spawner_params_list
+                .iter()
+                .map(|_| OnceLock::new())
+                .collect(),
+            spawner_params_list,
+        }
+    }
+
+    fn spawners_at(
+        &self,
+        key: u8,
+        vel: u8,
+        new_spawner: SpawnerConstructor,
+        stream_params: AudioStreamParams,
+    ) -> SpawnerList {
+        let Some(params) = self.spawner_params_list.get(key as usize) else {
+            return SpawnerList::default();
+        };
+
+        let lists = self.spawners[key as usize]
+            .get_or_init(|| AssertUnwindSafe((0..128).map(|_| OnceLock::new()).collect()));
+        lists[vel as usize]
+            .get_or_init(|| match params.get(vel) {
+                [] => SpawnerList::default(),
+                params => params
+                    .iter()
+                    .map(|p| new_spawner(p, vel, stream_params))
+                    .collect(),
+            })
+            .clone()
+    }
 }
 
 /// Represents a sample soundfont to be used within XSynth.
@@ -88,9 +305,16 @@ pub(super) struct SoundfontInstrument {
 /// ## SFZ specification support (opcodes)
 /// - `lovel` & `hivel`
 /// - `lokey` & `hikey`
-/// - `pitch_keycenter`
+/// - `pitch_keycenter` (including fractional microtonal values)
 /// - `volume`
+/// - `amp_keycenter`
+/// - `amp_keytrack`
+/// - `amp_veltrack`
+/// - `amp_velcurve_N`
 /// - `pan`
+/// - `pan_keycenter`
+/// - `pan_keytrack`
+/// - `pan_veltrack`
 /// - `sample`
 /// - `default_path`
 /// - `loop_mode`
@@ -102,8 +326,19 @@ pub(super) struct SoundfontInstrument {
 /// - `fil_veltrack`
 /// - `fil_keycenter`
 /// - `fil_keytrack`
-/// - `filter_type`
+/// - `fil_type` (`lpf_1p`, `lpf_2p`, `hpf_2p` and `bpf_2p`, with the other
+///   pole counts using the closest of these)
 /// - `tune`
+/// - `transpose`
+/// - `note_offset`
+/// - `pitch_veltrack`
+/// - `offset_random`
+/// - `pitch_random`
+/// - `amp_random`
+/// - `seq_length`
+/// - `seq_position`
+/// - `xfin_lovel` & `xfin_hivel`
+/// - `xfout_lovel` & `xfout_hivel`
 /// - `ampeg_start`
 /// - `ampeg_delay`
 /// - `ampeg_attack`
@@ -111,6 +346,17 @@ pub(super) struct SoundfontInstrument {
 /// - `ampeg_decay`
 /// - `ampeg_sustain`
 /// - `ampeg_release`
+/// - `ampeg_vel2release`
+/// - `ampeg_offveltrack` (XSynth extension: note off velocity tracking of the
+///   release time in percent, where 100 halves it at velocity 127)
+/// - `pitcheg_start`
+/// - `pitcheg_delay`
+/// - `pitcheg_attack`
+/// - `pitcheg_hold`
+/// - `pitcheg_decay`
+/// - `pitcheg_sustain`
+/// - `pitcheg_release`
+/// - `pitcheg_depth`
 ///
 /// ## SF2 specification support
 /// ### Generators
@@ -126,6 +372,18 @@ pub(super) struct SoundfontInstrument {
 /// - `decayVolEnv`
 /// - `sustainVolEnv`
 /// - `releaseVolEnv`
+/// - `delayModEnv`
+/// - `attackModEnv`
+/// - `holdModEnv`
+/// - `decayModEnv`
+/// - `sustainModEnv`
+/// - `releaseModEnv`
+/// - `modEnvToPitch`
+/// - `modEnvToFilterFc`
+/// - `delayModLFO`
+/// - `freqModLFO`
+/// - `modLfoToFilterFc`
+/// - `modLfoToVolume`
 /// - `instrument`
 /// - `keyRange`
 /// - `velRange`
@@ -141,6 +399,84 @@ pub(super) struct SoundfontInstrument {
 pub struct SampleSoundfont {
     instruments: Vec<SoundfontInstrument>,
     stream_params: AudioStreamParams,
+    new_spawner: SpawnerConstructor,
+    warnings: Vec<LoadWarning>,
+}
+
+/// Identifies the region of a soundfont that a load warning refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionId {
+    /// The bank and preset numbers of the SF2 preset which contains the
+    /// region, or `None` for SFZ soundfonts.
+    pub preset: Option<(u16, u16)>,
+
+    /// The index of the region, in the order of the SFZ file or of its SF2 preset.
+    pub index: usize,
+
+    /// The path of the sample used by an SFZ region.
+    pub sample: Option<PathBuf>,
+}
+
+impl fmt::Display for RegionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "region {}", self.index)?;
+        if let Some((bank, preset)) = self.preset {
+            write!(f, " of preset {bank}:{preset}")?;
+        }
+        if let Some(sample) = &self.sample {
+            write!(f, " ({})", sample.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Problems found in a soundfont while loading it, which were worked
+/// around instead of failing the load. Positions and lengths are in
+/// samples of the loaded audio, after any resampling.
+///
+/// With `SoundfontInitOptions::strict_validation`, the first warning
+/// fails the load instead.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LoadWarning {
+    #[error(
+        "The loop {start}..={end} of {region} exceeds the sample length of {length} and was clamped"
+    )]
+    LoopClamped {
+        region: RegionId,
+        start: u64,
+        end: u64,
+        length: u64,
+    },
+
+    #[error(
+        "The loop {start}..={end} of {region} is invalid for the sample length of {length} and was disabled"
+    )]
+    LoopDisabled {
+        region: RegionId,
+        start: u64,
+        end: u64,
+        length: u64,
+    },
+
+    #[error(
+        "The offset {offset} of {region} exceeds the sample length of {length} and was ignored"
+    )]
+    OffsetOutOfRange {
+        region: RegionId,
+        offset: u64,
+        length: u64,
+    },
+
+    #[error(
+        "The key center {keycenter} of {region} is far outside of its key range {}..={}",
+        keyrange.start(),
+        keyrange.end()
+    )]
+    KeycenterOutOfRange {
+        region: RegionId,
+        keycenter: u8,
+        keyrange: RangeInclusive<u8>,
+    },
 }
 
 /// Errors that can be generated when loading an SFZ soundfont.
@@ -154,6 +490,9 @@ pub enum LoadSfzError {
 
     #[error("Error parsing the SFZ: {0}")]
     SfzParseError(#[from] SfzParseError),
+
+    #[error("Invalid region: {0}")]
+    InvalidRegion(LoadWarning),
 }
 
 /// Errors that can be generated when loading a soundfont
@@ -166,8 +505,14 @@ pub enum LoadSfError {
     #[error("Error loading the SF2: {0}")]
     LoadSf2Error(#[from] Sf2ParseError),
 
+    #[error("Invalid region: {0}")]
+    InvalidRegion(LoadWarning),
+
     #[error("Unsupported format")]
     Unsupported,
+
+    #[error("The load was cancelled")]
+    Cancelled,
 }
 
 impl SampleSoundfont {
@@ -184,21 +529,33 @@ impl SampleSoundfont {
         path: impl Into<PathBuf>,
         stream_params: AudioStreamParams,
         options: SoundfontInitOptions,
+    ) -> Result<Self, LoadSfError> {
+        Self::new_with_progress(path, stream_params, options, &SoundfontLoadProgress::new())
+    }
+
+    /// Loads a new sample soundfont of an unspecified type, reporting the
+    /// progress of the load to `progress`. The load can be cancelled from
+    /// another thread using `SoundfontLoadProgress::cancel`.
+    ///
+    /// See `new` for the other parameters.
+    pub fn new_with_progress(
+        path: impl Into<PathBuf>,
+        stream_params: AudioStreamParams,
+        options: SoundfontInitOptions,
+        progress: &SoundfontLoadProgress,
     ) -> Result<Self, LoadSfError> {
         let path: PathBuf = path.into();
-        if let Some(ext) = path.extension() {
+        let soundfont = if let Some(ext) = path.extension() {
             match ext.to_str().unwrap_or("").to_lowercase().as_str() {
-                "sfz" => {
-                    Self::new_sfz(path, stream_params, options).map_err(LoadSfError::LoadSfzError)
-                }
-                "sf2" => {
-                    Self::new_sf2(path, stream_params, options).map_err(LoadSfError::LoadSf2Error)
-                }
-                _ => Err(LoadSfError::Unsupported),
+                "sfz" => Self::load_sfz(path, stream_params, options, progress)?,
+                "sf2" => Self::load_sf2(path, stream_params, options, progress)?,
+                _ => return Err(LoadSfError::Unsupported),
             }
         } else {
-            Err(LoadSfError::Unsupported)
-        }
+            return Err(LoadSfError::Unsupported);
+        };
+
+        soundfont.ok_or(LoadSfError::Cancelled)
     }
 
     /// Loads a new SFZ soundfont
@@ -214,6 +571,22 @@ impl SampleSoundfont {
         stream_params: AudioStreamParams,
         options: SoundfontInitOptions,
     ) -> Result<Self, LoadSfzError> {
+        Self::load_sfz(
+            sfz_path,
+            stream_params,
+            options,
+            &SoundfontLoadProgress::new(),
+        )
+        .map(|sf| sf.expect("the load can't be cancelled"))
+    }
+
+    /// Returns `None` if the load was cancelled.
+    fn load_sfz(
+        sfz_path: impl Into<PathBuf>,
+        stream_params: AudioStreamParams,
+        options: SoundfontInitOptions,
+        progress: &SoundfontLoadProgress,
+    ) -> Result<Option<Self>, LoadSfzError> {
         let regions = xsynth_soundfonts::sfz::parse_soundfont(sfz_path.into())?;
 
         // Find the unique samples that we need to parse and convert
@@ -223,14 +596,25 @@ impl SampleSoundfont {
             .collect();
 
         // Parse and convert them in parallel
-        let samples: Result<HashMap<_, _>, _> = unique_sample_params
+        let sample_count = unique_sample_params.len();
+        let loaded_samples = AtomicUsize::new(0);
+        let samples: Result<Vec<_>, _> = unique_sample_params
             .into_par_iter()
-            .map(|params| -> Result<(_, _), LoadSfzError> {
-                let sample = load_audio_file(&params.path, stream_params)?;
-                Ok((params, sample))
+            .map(|params| -> Result<Option<(_, _)>, LoadSfzError> {
+                if progress.is_cancelled() {
+                    return Ok(None);
+                }
+                let resample = options.resample_on_load.then_some(options.resample_quality);
+                let sample = load_audio_file(&params.path, stream_params, resample)?;
+                let loaded = loaded_samples.fetch_add(1, Ordering::Relaxed) + 1;
+                progress.set_progress(loaded as f32 / sample_count as f32);
+                Ok(Some((params, sample)))
             })
             .collect();
-        let samples = samples?;
+        if progress.is_cancelled() {
+            return Ok(None);
+        }
+        let samples: HashMap<_, _> = samples?.into_iter().flatten().collect();
 
         // Generate region params
         let mut spawner_params_list = Vec::<Vec<Arc<SampleVoiceSpawnerParams>>>::new();
@@ -238,22 +622,109 @@ impl SampleSoundfont {
             spawner_params_list.push(Vec::new());
         }
 
+        let random_seed = options
+            .random_seed
+            .unwrap_or_else(|| RandomState::new().build_hasher().finish());
+
         // Write region params
-        for region in regions {
+        let mut warnings = Vec::new();
+        for (i, region) in regions.into_iter().enumerate() {
             let params = sample_cache_from_region_params(&region);
-            let envelope = envelope_descriptor_from_region_params(&region.ampeg_envelope);
+            let mut envelope = envelope_descriptor_from_region_params(&region.ampeg_envelope);
+            envelope.delay += options.delay_ms.max(0.0) / 1000.0;
+            let pitch_envelope = modulation_envelope_from_region_params(
+                &region.pitcheg_envelope,
+                region.pitcheg_envelope.pitcheg_depth,
+                stream_params.sample_rate,
+            );
 
             // Key value -1 is used for CC triggered regions which are not supported by XSynth
             if region.keyrange.contains(&-1) {
                 continue;
             }
 
+            let amp_velcurve = velocity_curve_from_points(&region.amp_velcurve);
+
+            // Samples kept at their native rate are played back faster or slower
+            // instead, and their loop points stay at native rate indices
+            let sample_rate = samples[&params].1;
+            let rate_mult = native_rate_speed_mult(sample_rate, stream_params, options);
+            let convert_index = |idx: u32| {
+                if options.resample_on_load {
+                    convert_sample_index_u64(idx as u64, sample_rate, stream_params.sample_rate)
+                } else {
+                    idx as u64
+                }
+            };
+
+            let region_id = RegionId {
+                preset: None,
+                index: i,
+                sample: Some(region.sample_path.clone()),
+            };
+            let sample_length = samples[&params].0[0].len();
+            let (offset, offset_warning) =
+                validate_offset(convert_index(region.offset), sample_length, &region_id);
+            let (loop_params, loop_warning) = validate_loop_params(
+                LoopParams {
+                    mode: region.loop_mode,
+                    offset: offset.saturating_add(convert_index(ms_to_samples(
+                        options.start_trim_ms,
+                        sample_rate,
+                    ))),
+                    start: convert_index(region.loop_start),
+                    end: convert_index(region.loop_end),
+                },
+                sample_length,
+                &region_id,
+            );
+            let keyrange = *region.keyrange.start() as u8..=*region.keyrange.end() as u8;
+            let keycenter = region.pitch_keycenter.round().clamp(0.0, 127.0) as u8;
+            warnings.extend(offset_warning);
+            warnings.extend(loop_warning);
+            warnings.extend(validate_keycenter(keycenter, &keyrange, &region_id));
+
+            let random = RandomParams {
+                offset: u32::try_from(convert_index(region.offset_random)).unwrap_or(u32::MAX),
+                pitch: region.pitch_random,
+                amp: region.amp_random,
+                humanize: options.humanize_cents.abs(),
+                seed: splitmix64(&mut random_seed.wrapping_add(i as u64)),
+            };
+
+            let sequence = SequenceParams {
+                length: region.seq_length,
+                position: region.seq_position,
+            };
+
+            // Regions without velocity tracking share the same parameters for all velocities
+            let crossfade =
+                |vel| velocity_crossfade(&region.xfin_velrange, &region.xfout_velrange, vel);
+            let vel_dependent = region.ampeg_envelope.ampeg_vel2release != 0.0
+                || region.fil_veltrack != 0
+                || region.pan_veltrack != 0.0
+                || region.pitch_veltrack != 0
+                || region.velrange.clone().any(|vel| crossfade(vel) != 1.0);
+
             for key in region.keyrange.clone() {
+                let mut shared_params = None;
+
                 for vel in region.velrange.clone() {
                     let index = key_vel_to_index(key as u8, vel);
+                    let crossfade = crossfade(vel);
+                    if crossfade == 0.0 {
+                        continue;
+                    }
+                    if let Some(spawner_params) = &shared_params {
+                        spawner_params_list[index].push(Arc::clone(spawner_params));
+                        continue;
+                    }
+
                     let speed_mult =
-                        get_speed_mult_from_keys(key as u8, region.pitch_keycenter as u8)
-                            * cents_factor(region.tune as f32);
+                        get_speed_mult_from_fractional_keys(key as u8, region.pitch_keycenter)
+                            * rate_mult
+                            * cents_factor(region.tune as f32 + region.transpose as f32 * 100.0)
+                            * cents_factor(region.pitch_veltrack as f32 * vel as f32 / 127.0);
 
                     let mut envelope = envelope;
                     envelope.release +=
@@ -286,45 +757,10 @@ impl SampleSoundfont {
                     let pan = (region.pan as f32 + pan_mult).clamp(-100.0, 100.0) / 100.0;
                     let pan = (pan + 1.0) / 2.0;
 
-                    let vol_vel = {
-                        let a = region.amp_veltrack / 100.0;
-                        let aabs = a.abs();
-                        let vel = vel as f32;
-
-                        127.0 * (1.0 - aabs)
-                            + vel * (a + aabs) / 2.0
-                            + (127.0 - vel) * (aabs - a) / 2.0
-                    };
-                    let vol_mult = (vol_vel / 127.0).powi(2);
                     let vol_db_add =
                         (key as f32 - region.amp_keycenter as f32) * region.amp_keytrack;
                     let vol_db = (region.volume as f32 + vol_db_add).clamp(-96.0, 12.0);
-                    let volume = vol_mult * db_to_amp(vol_db);
-
-                    let sample_rate = samples[&params].1;
-
-                    let loop_params = LoopParams {
-                        mode: if region.loop_start == region.loop_end {
-                            LoopMode::NoLoop
-                        } else {
-                            region.loop_mode
-                        },
-                        offset: convert_sample_index(
-                            region.offset,
-                            sample_rate,
-                            stream_params.sample_rate,
-                        ),
-                        start: convert_sample_index(
-                            region.loop_start,
-                            sample_rate,
-                            stream_params.sample_rate,
-                        ),
-                        end: convert_sample_index(
-                            region.loop_end,
-                            sample_rate,
-                            stream_params.sample_rate,
-                        ),
-                    };
+                    let volume = db_to_amp(vol_db) * crossfade;
 
                     let mut region_samples = samples[&params].0.clone();
                     if stream_params.channels == ChannelCount::Stereo && region_samples.len() == 1 {
@@ -335,29 +771,54 @@ impl SampleSoundfont {
                     let spawner_params = Arc::new(SampleVoiceSpawnerParams {
                         pan,
                         volume,
+                        amp_veltrack: region.amp_veltrack,
+                        amp_velcurve: amp_velcurve.clone(),
                         envelope: envelope_params,
+                        release_veltrack: region.ampeg_envelope.ampeg_offveltrack,
+                        pitch_envelope: pitch_envelope.clone(),
+                        pitch_envelope_depth: region.pitcheg_envelope.pitcheg_depth,
+                        filter_envelope: None,
+                        filter_envelope_depth: 0.0,
+                        mod_lfo: None,
+                        mod_lfo_to_cutoff: 0.0,
+                        mod_lfo_to_volume: 0.0,
                         speed_mult,
                         cutoff,
                         resonance: db_to_amp(region.resonance) * Q_BUTTERWORTH_F32,
                         filter_type: region.filter_type,
                         interpolator: options.interpolator,
-                        loop_params,
+                        loop_params: loop_params.clone(),
+                        random,
+                        sequence,
                         sample: region_samples,
                     });
 
-                    spawner_params_list[index].push(spawner_params.clone());
+                    if !vel_dependent {
+                        shared_params = Some(spawner_params.clone());
+                    }
+                    spawner_params_list[index].push(spawner_params);
                 }
             }
         }
 
-        Ok(SampleSoundfont {
-            instruments: vec![SoundfontInstrument {
-                bank: options.bank.unwrap_or(0),
-                preset: options.preset.unwrap_or(0),
-                spawner_params_list,
-            }],
+        if options.strict_validation {
+            if let Some(warning) = warnings.first() {
+                return Err(LoadSfzError::InvalidRegion(warning.clone()));
+            }
+        }
+
+        progress.set_progress(1.0);
+        Ok(Some(SampleSoundfont {
+            instruments: vec![SoundfontInstrument::new(
+                options.bank.unwrap_or(0),
+                options.preset.unwrap_or(0),
+                None,
+                compact_spawner_params(spawner_params_list),
+            )],
             stream_params,
-        })
+            new_spawner: spawner_constructor(stream_params.channels),
+            warnings,
+        }))
     }
 
     /// Loads a new SF2 soundfont
@@ -372,13 +833,41 @@ impl SampleSoundfont {
         sf2_path: impl Into<PathBuf>,
         stream_params: AudioStreamParams,
         options: SoundfontInitOptions,
-    ) -> Result<Self, Sf2ParseError> {
-        let presets =
-            xsynth_soundfonts::sf2::load_soundfont(sf2_path.into(), stream_params.sample_rate)?;
+    ) -> Result<Self, LoadSfError> {
+        Self::load_sf2(
+            sf2_path,
+            stream_params,
+            options,
+            &SoundfontLoadProgress::new(),
+        )
+        .map(|sf| sf.expect("the load can't be cancelled"))
+    }
+
+    /// Returns `None` if the load was cancelled.
+    fn load_sf2(
+        sf2_path: impl Into<PathBuf>,
+        stream_params: AudioStreamParams,
+        options: SoundfontInitOptions,
+        progress: &SoundfontLoadProgress,
+    ) -> Result<Option<Self>, LoadSfError> {
+        let presets = xsynth_soundfonts::sf2::load_soundfont(
+            sf2_path.into(),
+            options
+                .resample_on_load
+                .then_some(stream_params.sample_rate),
+            options.resample_quality,
+        )?;
 
         let mut instruments = Vec::new();
+        let mut warnings = Vec::new();
+
+        let preset_count = presets.len();
+        for (i, preset) in presets.into_iter().enumerate() {
+            if progress.is_cancelled() {
+                return Ok(None);
+            }
+            progress.set_progress(i as f32 / preset_count as f32);
 
-        for preset in presets {
             if let Some(bank) = options.bank {
                 if bank != preset.bank as u8 {
                     continue;
@@ -395,87 +884,177 @@ impl SampleSoundfont {
                 spawner_params_list.push(Vec::new());
             }
 
-            for region in preset.regions {
-                let envelope_params = Arc::new(
-                    envelope_descriptor_from_region_params(&region.ampeg_envelope)
-                        .to_envelope_params(
-                            stream_params.sample_rate,
-                            options.vol_envelope_options,
-                        ),
+            for (index, region) in preset.regions.into_iter().enumerate() {
+                let region_id = RegionId {
+                    preset: Some((preset.bank, preset.preset)),
+                    index,
+                    sample: None,
+                };
+
+                let mut envelope = envelope_descriptor_from_region_params(&region.ampeg_envelope);
+                envelope.delay += options.delay_ms.max(0.0) / 1000.0;
+                let envelope_params =
+                    Arc::new(envelope.to_envelope_params(
+                        stream_params.sample_rate,
+                        options.vol_envelope_options,
+                    ));
+                let pitch_envelope = modulation_envelope_from_region_params(
+                    &region.pitcheg_envelope,
+                    region.pitcheg_envelope.pitcheg_depth,
+                    stream_params.sample_rate,
+                );
+                // The modulation envelope also drives the filter cutoff
+                let filter_envelope = modulation_envelope_from_region_params(
+                    &region.pitcheg_envelope,
+                    region.mod_env_to_filter_fc,
+                    stream_params.sample_rate,
+                );
+                let mod_lfo = (region.mod_lfo_to_filter_fc != 0.0
+                    || region.mod_lfo_to_volume != 0.0)
+                    .then(|| {
+                        LfoParameters::new(
+                            region.mod_lfo_delay,
+                            region.mod_lfo_freq,
+                            stream_params.sample_rate as f32,
+                        )
+                    });
+
+                let rate_mult = native_rate_speed_mult(region.sample_rate, stream_params, options);
+                let loaded_rate = if options.resample_on_load {
+                    stream_params.sample_rate
+                } else {
+                    region.sample_rate
+                };
+                let sample_length = region.sample[0].len();
+                let (offset, offset_warning) =
+                    validate_offset(region.offset as u64, sample_length, &region_id);
+                let (loop_params, loop_warning) = validate_loop_params(
+                    LoopParams {
+                        mode: region.loop_mode,
+                        offset: offset
+                            .saturating_add(
+                                ms_to_samples(options.start_trim_ms, loaded_rate) as u64
+                            ),
+                        start: region.loop_start as u64,
+                        end: region.loop_end as u64,
+                    },
+                    sample_length,
+                    &region_id,
                 );
+                warnings.extend(offset_warning);
+                warnings.extend(loop_warning);
+                warnings.extend(validate_keycenter(
+                    region.root_key,
+                    &region.keyrange,
+                    &region_id,
+                ));
 
                 for key in region.keyrange.clone() {
-                    for vel in region.velrange.clone() {
-                        let index = key_vel_to_index(key, vel);
-                        let speed_mult = get_speed_mult_from_keys(key, region.root_key)
-                            * cents_factor(
-                                region.fine_tune as f32 + region.coarse_tune as f32 * 100.0,
-                            );
-
-                        let mut cutoff = None;
-                        if options.use_effects {
-                            if let Some(cutoff_t) = region.cutoff {
-                                if cutoff_t >= 1.0 {
-                                    cutoff = Some(cutoff_t.clamp(
-                                        1.0,
-                                        stream_params.sample_rate as f32 / 2.0 - 100.0,
-                                    ));
-                                }
+                    let speed_mult = get_speed_mult_from_keys(key, region.root_key)
+                        * rate_mult
+                        * cents_factor(region.fine_tune as f32 + region.coarse_tune as f32 * 100.0);
+
+                    let mut cutoff = None;
+                    if options.use_effects {
+                        if let Some(cutoff_t) = region.cutoff {
+                            if cutoff_t >= 1.0 {
+                                cutoff = Some(
+                                    cutoff_t
+                                        .clamp(1.0, stream_params.sample_rate as f32 / 2.0 - 100.0),
+                                );
                             }
                         }
+                    }
 
-                        let pan = ((region.pan as f32 / 500.0) + 1.0) / 2.0;
-                        let volume = region.volume * (vel as f32 / 127.0).powi(2);
-
-                        let loop_params = LoopParams {
-                            mode: if region.loop_start == region.loop_end {
-                                LoopMode::NoLoop
-                            } else {
-                                region.loop_mode
-                            },
-                            offset: region.offset,
-                            start: region.loop_start,
-                            end: region.loop_end,
-                        };
-
-                        let mut region_samples = region.sample.clone();
-                        if stream_params.channels == ChannelCount::Stereo
-                            && region_samples.len() == 1
-                        {
-                            region_samples =
-                                Arc::new([region_samples[0].clone(), region_samples[0].clone()]);
-                        }
+                    let pan = ((region.pan as f32 / 500.0) + 1.0) / 2.0;
+
+                    let mut region_samples = region.sample.clone();
+                    if stream_params.channels == ChannelCount::Stereo && region_samples.len() == 1 {
+                        region_samples =
+                            Arc::new([region_samples[0].clone(), region_samples[0].clone()]);
+                    }
 
-                        let spawner_params = Arc::new(SampleVoiceSpawnerParams {
-                            pan,
-                            volume,
-                            envelope: envelope_params.clone(),
-                            speed_mult,
-                            cutoff,
-                            resonance: db_to_amp(region.resonance) * Q_BUTTERWORTH_F32,
-                            filter_type: FilterType::LowPass,
-                            interpolator: options.interpolator,
-                            loop_params,
-                            sample: region_samples,
-                        });
+                    // SF2 regions have no velocity dependent parameters other than
+                    // the volume, so the same parameters are shared for all velocities
+                    let spawner_params = Arc::new(SampleVoiceSpawnerParams {
+                        pan,
+                        volume: region.volume,
+                        amp_veltrack: 100.0,
+                        amp_velcurve: None,
+                        envelope: envelope_params.clone(),
+                        release_veltrack: 0.0,
+                        pitch_envelope: pitch_envelope.clone(),
+                        pitch_envelope_depth: region.pitcheg_envelope.pitcheg_depth,
+                        filter_envelope: filter_envelope.clone(),
+                        filter_envelope_depth: region.mod_env_to_filter_fc,
+                        mod_lfo,
+                        mod_lfo_to_cutoff: region.mod_lfo_to_filter_fc,
+                        mod_lfo_to_volume: region.mod_lfo_to_volume,
+                        speed_mult,
+                        cutoff,
+                        resonance: db_to_amp(region.resonance) * Q_BUTTERWORTH_F32,
+                        // SF2 only defines a resonant low pass filter
+                        filter_type: FilterType::LowPass,
+                        interpolator: options.interpolator,
+                        loop_params: loop_params.clone(),
+                        // The regions of a note share their detune, as SF2
+                        // stereo samples are split over two regions
+                        random: RandomParams {
+                            humanize: options.humanize_cents.abs(),
+                            ..Default::default()
+                        },
+                        sequence: Default::default(),
+                        sample: region_samples,
+                    });
 
+                    for vel in region.velrange.clone() {
+                        let index = key_vel_to_index(key, vel);
                         spawner_params_list[index].push(spawner_params.clone());
                     }
                 }
             }
 
-            let new = SoundfontInstrument {
-                bank: preset.bank as u8,
-                preset: preset.preset as u8,
-                spawner_params_list,
-            };
+            let new = SoundfontInstrument::new(
+                preset.bank as u8,
+                preset.preset as u8,
+                Some(preset.name),
+                compact_spawner_params(spawner_params_list),
+            );
             instruments.push(new);
         }
 
-        Ok(SampleSoundfont {
+        if options.strict_validation {
+            if let Some(warning) = warnings.first() {
+                return Err(LoadSfError::InvalidRegion(warning.clone()));
+            }
+        }
+
+        progress.set_progress(1.0);
+        Ok(Some(SampleSoundfont {
             instruments,
             stream_params,
-        })
+            new_spawner: spawner_constructor(stream_params.channels),
+            warnings,
+        }))
+    }
+
+    /// Returns the problems found in the soundfont while loading it.
+    /// See `LoadWarning` for the possible warnings.
+    pub fn load_warnings(&self) -> &[LoadWarning] {
+        &self.warnings
+    }
+
+    /// Returns the bank and preset numbers of every instrument in the
+    /// soundfont, along with the preset name if the format provides one.
+    ///
+    /// SF2 presets are named from their preset headers, while SFZ soundfonts
+    /// contain a single unnamed instrument at the bank and preset selected
+    /// in `SoundfontInitOptions`.
+    pub fn list_presets(&self) -> Vec<(u8, u8, Option<String>)> {
+        self.instruments
+            .iter()
+            .map(|i| (i.bank, i.preset, i.name.clone()))
+            .collect()
     }
 }
 
@@ -496,51 +1075,12 @@ impl SoundfontBase for SampleSoundfont {
         preset: u8,
         key: u8,
         vel: u8,
-    ) -> Vec<Box<dyn VoiceSpawner>> {
-        use simdeez::*; // nuts
-
-        use simdeez::prelude::*;
-
-        simd_runtime_generate!(
-            fn get(
-                key: u8,
-                vel: u8,
-                sf: &SoundfontInstrument,
-                stream_params: &AudioStreamParams,
-            ) -> Vec<Box<dyn VoiceSpawner>> {
-                if sf.spawner_params_list.is_empty() {
-                    return Vec::new();
-                }
-
-                let index = key_vel_to_index(key, vel);
-                let mut vec = Vec::<Box<dyn VoiceSpawner>>::new();
-                for spawner in &sf.spawner_params_list[index] {
-                    match stream_params.channels {
-                        ChannelCount::Stereo => vec.push(Box::new(
-                            StereoSampledVoiceSpawner::<S>::new(spawner, vel, *stream_params),
-                        )),
-                        ChannelCount::Mono => vec.push(Box::new(
-                            MonoSampledVoiceSpawner::<S>::new(spawner, vel, *stream_params),
-                        )),
-                    }
-                }
-                vec
-            }
-        );
-
-        let empty = SoundfontInstrument {
-            bank: 0,
-            preset: 0,
-            spawner_params_list: Vec::new(),
-        };
-
-        let instrument = self
-            .instruments
+    ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+        self.instruments
             .iter()
             .find(|i| i.bank == bank && i.preset == preset)
-            .unwrap_or(&empty);
-
-        get(key, vel, instrument, self.stream_params())
+            .map(|i| i.spawners_at(key, vel, self.new_spawner, self.stream_params))
+            .unwrap_or_default()
     }
 
     fn get_release_voice_spawners_at(
@@ -549,7 +1089,683 @@ impl SoundfontBase for SampleSoundfont {
         _preset: u8,
         _key: u8,
         _vel: u8,
-    ) -> Vec<Box<dyn VoiceSpawner>> {
-        vec![]
+    ) -> Arc<[Arc<dyn VoiceSpawner>]> {
+        Arc::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_helpers::*;
+    use super::*;
+    use crate::voice::{EnvelopeDescriptor, ReleaseType};
+    use xsynth_soundfonts::sfz::PitchegEnvelopeParams;
+
+    #[test]
+    fn test_amp_veltrack() {
+        let sf = load_test_sfz("amp_veltrack_default", "");
+        let low = peak(&render_voices(&sf, 60, 1, 1024));
+        let high = peak(&render_voices(&sf, 60, 127, 1024));
+        assert!(high > 0.0);
+        assert!((low / high - (1.0f32 / 127.0).powi(2)).abs() < 1e-4);
+
+        let sf = load_test_sfz("amp_veltrack_0", "amp_veltrack=0");
+        let low = peak(&render_voices(&sf, 60, 1, 1024));
+        let high = peak(&render_voices(&sf, 60, 127, 1024));
+        assert!(high > 0.0);
+        assert!((low - high).abs() < 1e-6);
+
+        let sf = load_test_sfz("amp_veltrack_inverted", "amp_veltrack=-100");
+        let low = peak(&render_voices(&sf, 60, 1, 1024));
+        let high = peak(&render_voices(&sf, 60, 127, 1024));
+        assert!(low > high);
+    }
+
+    #[test]
+    fn test_amp_keytrack() {
+        let sf = load_test_sfz("amp_keytrack", "amp_keycenter=60 amp_keytrack=-6");
+        let center = peak(&render_voices(&sf, 60, 127, 1024));
+        let above = peak(&render_voices(&sf, 61, 127, 1024));
+        assert!((above / center - db_to_amp(-6.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_pitch_envelope() {
+        // A ramp makes the playback position visible in the output
+        let ramp: Vec<f32> = (0..4096).map(|i| i as f32 / 4096.0).collect();
+
+        let plain =
+            load_test_sfz_with_sample("pitcheg_plain", &ramp, "ampeg_attack=0", ChannelCount::Mono);
+        let plain = render_voices(&plain, 60, 127, 2048);
+
+        // Zero depth keeps the original code path
+        let zero = load_test_sfz_with_sample(
+            "pitcheg_zero",
+            &ramp,
+            "ampeg_attack=0 pitcheg_sustain=100 pitcheg_depth=0",
+            ChannelCount::Mono,
+        );
+        assert_eq!(render_voices(&zero, 60, 127, 2048), plain);
+
+        // An envelope held at full depth plays the sample an octave down
+        let octave = load_test_sfz_with_sample(
+            "pitcheg_octave",
+            &ramp,
+            "ampeg_attack=0 pitcheg_sustain=100 pitcheg_depth=-1200",
+            ChannelCount::Mono,
+        );
+        let octave = render_voices(&octave, 60, 127, 2048);
+        for i in 1..1024 {
+            assert!((octave[i * 2] - plain[i]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_pitch_veltrack() {
+        let ramp: Vec<f32> = (0..16384).map(|i| i as f32 / 16384.0).collect();
+
+        for cents in [1200.0f32, -700.0] {
+            let sf = load_test_sfz_with_sample(
+                &format!("pitch_veltrack_{cents}"),
+                &ramp,
+                &format!("ampeg_attack=0 amp_veltrack=0 pitch_veltrack={cents}"),
+                ChannelCount::Mono,
+            );
+            let low = render_voices(&sf, 60, 1, 4096);
+            let high = render_voices(&sf, 60, 127, 4096);
+
+            // The playback speed is visible in the slope of the ramp
+            let slope = |buf: &[f32]| buf[4000] - buf[100];
+            let shift = 1200.0 * (slope(&high) / slope(&low)).log2();
+            assert!((shift - cents * 126.0 / 127.0).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_fractional_keycenter() {
+        let ramp: Vec<f32> = (0..16384).map(|i| i as f32 / 16384.0).collect();
+        let slope = |buf: &[f32]| buf[4000] - buf[100];
+
+        let sf = load_test_sfz_with_sample("keycenter_whole", &ramp, "", ChannelCount::Mono);
+        let standard = slope(&render_voices(&sf, 60, 127, 4096));
+
+        // `key` also sets the key range, which uses the whole key
+        for (opcodes, plays_other_keys) in [("pitch_keycenter=60.5", true), ("key=60.5", false)] {
+            let sf = load_test_sfz_with_sample(
+                &format!("keycenter_{}", opcodes.replace('=', "_")),
+                &ramp,
+                opcodes,
+                ChannelCount::Mono,
+            );
+            assert_eq!(
+                !sf.get_attack_voice_spawners_at(0, 0, 61, 127).is_empty(),
+                plays_other_keys
+            );
+
+            // A quarter-tone below the standard pitch
+            let shift = 1200.0 * (slope(&render_voices(&sf, 60, 127, 4096)) / standard).log2();
+            assert!((shift + 50.0).abs() < 2.0, "{opcodes}: {shift}");
+        }
+    }
+
+    #[test]
+    fn test_transpose() {
+        // A sine with a period of 48 samples at the key center
+        let sine: Vec<f32> = (0..48000)
+            .map(|i| (i as f32 / 48.0 * std::f32::consts::TAU).sin())
+            .collect();
+        let period = |buf: &[f32]| {
+            let crossings: Vec<usize> = (1..buf.len())
+                .filter(|&i| buf[i - 1] < 0.0 && buf[i] >= 0.0)
+                .collect();
+            (crossings[crossings.len() - 1] - crossings[0]) as f32 / (crossings.len() - 1) as f32
+        };
+
+        let sf = load_test_sfz_with_sample(
+            "transpose",
+            &sine,
+            "ampeg_attack=0 transpose=-12",
+            ChannelCount::Mono,
+        );
+
+        // The region still plays on all keys, an octave lower
+        let out = render_voices(&sf, 60, 127, 9600);
+        assert!((period(&out) - 96.0).abs() < 0.1, "{}", period(&out));
+        let out = render_voices(&sf, 72, 127, 9600);
+        assert!((period(&out) - 48.0).abs() < 0.1, "{}", period(&out));
+    }
+
+    #[test]
+    fn test_note_offset() {
+        let ramp: Vec<f32> = (0..16384).map(|i| i as f32 / 16384.0).collect();
+        let opcodes = "ampeg_attack=0 lokey=60 hikey=72 amp_keytrack=1";
+
+        let reference =
+            load_test_sfz_with_sample("note_offset_0", &ramp, opcodes, ChannelCount::Mono);
+        let sf = load_test_sfz_with_sample(
+            "note_offset_2",
+            &ramp,
+            &format!("{opcodes} note_offset=2"),
+            ChannelCount::Mono,
+        );
+
+        // Key 58 plays what key 60 used to, including its pitch and key tracking
+        for key in 58..=70 {
+            let out = render_voices(&sf, key, 127, 4096);
+            let expected = render_voices(&reference, key + 2, 127, 4096);
+            assert!(out[4000] > 0.2);
+            assert!(
+                out.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-3),
+                "{key}"
+            );
+        }
+        assert!(sf.get_attack_voice_spawners_at(0, 0, 71, 127).is_empty());
+        assert!(!sf.get_attack_voice_spawners_at(0, 0, 58, 127).is_empty());
+        assert!(reference
+            .get_attack_voice_spawners_at(0, 0, 58, 127)
+            .is_empty());
+
+        // Regions offset out of the MIDI range aren't loaded
+        let sf = load_test_sfz_with_sample(
+            "note_offset_out",
+            &ramp,
+            &format!("{opcodes} note_offset=-100"),
+            ChannelCount::Mono,
+        );
+        assert!((0..128).all(|key| sf.get_attack_voice_spawners_at(0, 0, key, 127).is_empty()));
+    }
+
+    #[test]
+    fn test_one_shot_ignores_release() {
+        let control = VoiceControlData::new_defaults();
+
+        for (name, loop_mode, plays_to_end) in [
+            ("one_shot", "one_shot", true),
+            ("no_loop", "no_loop", false),
+        ] {
+            let sf = load_test_sfz_with_sample(
+                name,
+                &[0.5; 4096],
+                &format!("ampeg_attack=0 loop_mode={loop_mode}"),
+                ChannelCount::Mono,
+            );
+            let mut voice = sf.get_attack_voice_spawners_at(0, 0, 60, 127)[0].spawn_voice(&control);
+
+            // Release one sample after the note starts
+            let mut out = vec![0.0; 4200];
+            voice.render_to(&mut out[..1]);
+            voice.signal_release(ReleaseType::Standard { vel: 64 });
+            assert!(voice.is_releasing());
+            voice.render_to(&mut out[1..4000]);
+
+            assert_eq!((out[3999] - 0.5).abs() < 1e-3, plays_to_end);
+            assert_eq!(voice.ended(), !plays_to_end);
+
+            voice.render_to(&mut out[4000..]);
+            assert!(voice.ended());
+            assert!(out[4100..].iter().all(|&s| s == 0.0));
+        }
+    }
+
+    /// Builds a soundfont with a single mono region on key 60 from spawner
+    /// parameters, for the SF2 features which can't be set through SFZ.
+    fn soundfont_from_params(
+        samples: &[f32],
+        modify: impl FnOnce(&mut SampleVoiceSpawnerParams),
+    ) -> SampleSoundfont {
+        let envelope = EnvelopeDescriptor {
+            start_percent: 0.0,
+            delay: 0.0,
+            attack: 0.0,
+            hold: 0.0,
+            decay: 0.0,
+            sustain_percent: 1.0,
+            release: 0.01,
+        };
+
+        let mut params = SampleVoiceSpawnerParams {
+            volume: 1.0,
+            amp_veltrack: 100.0,
+            amp_velcurve: None,
+            pan: 0.5,
+            speed_mult: 1.0,
+            cutoff: None,
+            resonance: Q_BUTTERWORTH_F32,
+            filter_type: FilterType::LowPass,
+            loop_params: LoopParams {
+                mode: LoopMode::NoLoop,
+                offset: 0,
+                start: 0,
+                end: 0,
+            },
+            envelope: Arc::new(envelope.to_envelope_params(TEST_SAMPLE_RATE, Default::default())),
+            release_veltrack: 0.0,
+            pitch_envelope: None,
+            pitch_envelope_depth: 0.0,
+            filter_envelope: None,
+            filter_envelope_depth: 0.0,
+            mod_lfo: None,
+            mod_lfo_to_cutoff: 0.0,
+            mod_lfo_to_volume: 0.0,
+            random: Default::default(),
+            sequence: Default::default(),
+            sample: Arc::new([samples.into()]),
+            interpolator: Interpolator::Nearest,
+        };
+        modify(&mut params);
+
+        let params = Arc::new(params);
+        let mut spawner_params_list = vec![Vec::new(); 128 * 128];
+        for vel in 0..128 {
+            spawner_params_list[key_vel_to_index(60, vel)].push(params.clone());
+        }
+
+        SampleSoundfont {
+            instruments: vec![SoundfontInstrument::new(
+                0,
+                0,
+                None,
+                compact_spawner_params(spawner_params_list),
+            )],
+            stream_params: AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            new_spawner: spawner_constructor(ChannelCount::Mono),
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_filter_modulation() {
+        // A 6kHz tone, which is removed as the cutoff goes down
+        let tone: Vec<f32> = (0..TEST_SAMPLE_RATE)
+            .map(|i| (i as f32 * std::f32::consts::PI / 4.0).sin() * 0.5)
+            .collect();
+        let held_envelope = PitchegEnvelopeParams {
+            pitcheg_sustain: 100.0,
+            ..Default::default()
+        };
+
+        let plain = soundfont_from_params(&tone, |p| p.cutoff = Some(19000.0));
+        let plain = peak(&render_voices(&plain, 60, 127, 4800)[2400..]);
+
+        // The envelope lowers the cutoff to ~1.2kHz
+        let envelope = soundfont_from_params(&tone, |p| {
+            p.cutoff = Some(19000.0);
+            p.filter_envelope =
+                modulation_envelope_from_region_params(&held_envelope, -4800.0, TEST_SAMPLE_RATE);
+            p.filter_envelope_depth = -4800.0;
+        });
+        let envelope = peak(&render_voices(&envelope, 60, 127, 4800)[2400..]);
+        assert!(plain > 0.4);
+        assert!(envelope < plain * 0.1);
+
+        // The LFO moves the cutoff between 750Hz and 12kHz
+        let lfo = soundfont_from_params(&tone, |p| {
+            p.cutoff = Some(3000.0);
+            p.mod_lfo = Some(LfoParameters::new(0.0, 10.0, TEST_SAMPLE_RATE as f32));
+            p.mod_lfo_to_cutoff = 2400.0;
+        });
+        let out = render_voices(&lfo, 60, 127, 4800);
+        let high = peak(&out[1000..1400]);
+        let low = peak(&out[3400..3800]);
+        assert!(high > plain * 0.8);
+        assert!(low < plain * 0.1);
+    }
+
+    #[test]
+    fn test_lfo_volume() {
+        let lfo = soundfont_from_params(&[0.5; 48000], |p| {
+            p.mod_lfo = Some(LfoParameters::new(0.0, 10.0, TEST_SAMPLE_RATE as f32));
+            p.mod_lfo_to_volume = 60.0;
+        });
+        let out = render_voices(&lfo, 60, 127, 4800);
+
+        assert!((out[0] - 0.5).abs() < 1e-3);
+        assert!((out[1200] - 0.5 * db_to_amp(6.0)).abs() < 1e-3);
+        assert!((out[3600] - 0.5 * db_to_amp(-6.0)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_velocity_crossfade() {
+        let sf = load_test_sfz(
+            "velocity_crossfade",
+            "amp_veltrack=0 xfin_lovel=20 xfin_hivel=40 xfout_lovel=80 xfout_hivel=100",
+        );
+        let level = |vel| peak(&render_voices(&sf, 60, vel, 1024)) / 0.5;
+
+        assert_eq!(level(10), 0.0);
+        assert!((level(30) - 0.5f32.sqrt()).abs() < 1e-3);
+        assert!((level(60) - 1.0).abs() < 1e-3);
+        assert!((level(85) - 0.75f32.sqrt()).abs() < 1e-3);
+        assert_eq!(level(110), 0.0);
+    }
+
+    #[test]
+    fn test_layer_alignment() {
+        // The transient of the piano is at the start, while the pad has 10ms of silence
+        let dir = TestSoundfontDir::new("layer_alignment");
+        let mut pad = vec![0.0; 480];
+        pad.extend([0.5; 4800]);
+        dir.write_wav("piano.wav", TEST_SAMPLE_RATE, &[0.5; 4800]);
+        dir.write_wav("pad.wav", TEST_SAMPLE_RATE, &pad);
+
+        // The samples aren't resampled, which would smear the transients
+        let load = |sample: &str, options: SoundfontInitOptions| {
+            let options = SoundfontInitOptions {
+                resample_on_load: false,
+                ..options
+            };
+            let sfz = dir.write_sfz(
+                &format!("{sample}.sfz"),
+                &format!("<region> sample={sample}.wav pitch_keycenter=60\n"),
+            );
+            let stream_params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+            SampleSoundfont::new_sfz(sfz, stream_params, options).unwrap()
+        };
+        let onset = |sf: &SampleSoundfont| {
+            let out = render_voices(sf, 60, 127, 2400);
+            out.iter().position(|s| s.abs() > 1e-4).unwrap()
+        };
+
+        let piano = load("piano", Default::default());
+        let pad = load("pad", Default::default());
+        assert!(onset(&piano) <= 1);
+        assert!(onset(&pad).abs_diff(480) <= 1);
+
+        // Trimming the pad aligns it with the piano
+        let trimmed = SoundfontInitOptions {
+            start_trim_ms: 10.0,
+            ..Default::default()
+        };
+        assert!(onset(&load("pad", trimmed)).abs_diff(onset(&piano)) <= 1);
+
+        // Delaying the piano aligns it with the pad
+        let delayed = SoundfontInitOptions {
+            delay_ms: 10.0,
+            ..Default::default()
+        };
+        assert!(onset(&load("piano", delayed)).abs_diff(onset(&pad)) <= 1);
+    }
+
+    #[test]
+    fn test_pitch_random() {
+        // A ramp, so the playback speed is the slope of the output
+        let ramp: Vec<f32> = (0..TEST_SAMPLE_RATE)
+            .map(|i| i as f32 / TEST_SAMPLE_RATE as f32)
+            .collect();
+        let sf =
+            load_test_sfz_with_sample("pitch_random", &ramp, "pitch_random=50", ChannelCount::Mono);
+        let spawners = sf.get_attack_voice_spawners_at(0, 0, 60, 127);
+
+        let speed_mult = |random| {
+            let control = VoiceControlData {
+                random,
+                ..VoiceControlData::new_defaults()
+            };
+            let mut out = vec![0.0; 3000];
+            spawners[0].spawn_voice(&control).render_to(&mut out);
+            (out[2999] - out[999]) / 2000.0 * TEST_SAMPLE_RATE as f32
+        };
+
+        let speeds: Vec<f32> = (0..100).map(speed_mult).collect();
+        let range = cents_factor(-50.0) - 0.003..=cents_factor(50.0) + 0.003;
+        assert!(speeds.iter().all(|s| range.contains(s)), "{speeds:?}");
+
+        let min = speeds.iter().copied().fold(f32::MAX, f32::min);
+        let max = speeds.iter().copied().fold(f32::MIN, f32::max);
+        assert!(max - min > 0.03, "{min} {max}");
+
+        // The same random value always gives the same voice
+        assert_eq!(speed_mult(7), speeds[7]);
+    }
+
+    #[test]
+    fn test_humanize_cents() {
+        let ramp: Vec<f32> = (0..TEST_SAMPLE_RATE)
+            .map(|i| i as f32 / TEST_SAMPLE_RATE as f32)
+            .collect();
+        let dir = TestSoundfontDir::new("humanize_cents");
+        dir.write_wav("sample.wav", TEST_SAMPLE_RATE, &ramp);
+        let sfz = dir.write_sfz("test.sfz", "<region> sample=sample.wav\n");
+        let load = |humanize_cents| {
+            let options = SoundfontInitOptions {
+                humanize_cents,
+                ..Default::default()
+            };
+            let stream_params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+            SampleSoundfont::new_sfz(sfz.clone(), stream_params, options).unwrap()
+        };
+
+        let speed_mult = |sf: &SampleSoundfont, random| {
+            let control = VoiceControlData {
+                random,
+                ..VoiceControlData::new_defaults()
+            };
+            let mut out = vec![0.0; 3000];
+            let spawners = sf.get_attack_voice_spawners_at(0, 0, 60, 127);
+            spawners[0].spawn_voice(&control).render_to(&mut out);
+            (out[2999] - out[999]) / 2000.0 * TEST_SAMPLE_RATE as f32
+        };
+
+        // Two voices on the same key get independent detunes
+        let sf = load(20.0);
+        let speeds: Vec<f32> = (0..100).map(|random| speed_mult(&sf, random)).collect();
+        let range = cents_factor(-20.0) - 0.003..=cents_factor(20.0) + 0.003;
+        assert!(speeds.iter().all(|s| range.contains(s)), "{speeds:?}");
+        assert!((speeds[0] - speeds[1]).abs() > 1e-4, "{speeds:?}");
+
+        let min = speeds.iter().copied().fold(f32::MAX, f32::min);
+        let max = speeds.iter().copied().fold(f32::MIN, f32::max);
+        assert!(max - min > 0.01, "{min} {max}");
+
+        // Without humanize, every voice plays at the same speed
+        let sf = load(0.0);
+        assert_eq!(speed_mult(&sf, 0), speed_mult(&sf, 1));
+    }
+
+    #[test]
+    fn test_list_sf2_presets() {
+        let dir = TestSoundfontDir::new("list_sf2_presets");
+        let sf2 = dir.write_sf2(
+            "test.sf2",
+            TEST_SAMPLE_RATE,
+            &[0.5; 480],
+            None,
+            &[(0, 0, "Piano"), (0, 1, "Bright Piano"), (128, 0, "Drums")],
+        );
+
+        let sf = SampleSoundfont::new_sf2(
+            sf2,
+            AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            SoundfontInitOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            sf.list_presets(),
+            vec![
+                (0, 0, Some("Piano".to_string())),
+                (0, 1, Some("Bright Piano".to_string())),
+                (128, 0, Some("Drums".to_string())),
+            ]
+        );
+
+        // The presets are playable
+        let out = render_voices(&sf, 60, 127, 100);
+        assert!(peak(&out) > 0.1);
+    }
+
+    #[test]
+    fn test_list_sfz_presets() {
+        let sf = load_test_sfz("list_sfz_presets", "");
+        assert_eq!(sf.list_presets(), vec![(0, 0, None)]);
+    }
+
+    #[test]
+    fn test_spawners_cached() {
+        let sf = load_test_sfz("spawners_cached", "lokey=60 hikey=61");
+        let first = sf.get_attack_voice_spawners_at(0, 0, 60, 100);
+        let second = sf.get_attack_voice_spawners_at(0, 0, 60, 100);
+        assert_eq!(first.len(), 1);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // The spawners of each velocity stay separate, as they store its amplitude
+        let other_vel = sf.get_attack_voice_spawners_at(0, 0, 60, 50);
+        assert!(!Arc::ptr_eq(&first[0], &other_vel[0]));
+
+        let control = VoiceControlData::new_defaults();
+        let render = |spawners: &[Arc<dyn VoiceSpawner>]| {
+            let mut out = vec![0.0; 256];
+            spawners[0].spawn_voice(&control).render_to(&mut out);
+            out
+        };
+        assert_eq!(render(&first), render(&second));
+        assert!(sf.get_attack_voice_spawners_at(0, 1, 60, 100).is_empty());
+    }
+
+    #[test]
+    fn test_sfz_missing_sample() {
+        let dir = TestSoundfontDir::new("sfz_missing_sample");
+        dir.write_wav("sample.wav", TEST_SAMPLE_RATE, &[0.5; 480]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav key=60\n\
+             <region> sample=samples/missing.wav key=61\n",
+        );
+
+        let result = SampleSoundfont::new_sfz(
+            sfz,
+            AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            Default::default(),
+        );
+        match result {
+            Err(LoadSfzError::SfzParseError(SfzParseError::MissingSample(path))) => {
+                assert!(path.ends_with("samples/missing.wav"), "{path:?}");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_sfz_offset_out_of_range() {
+        let sf = load_test_sfz_with_sample(
+            "sfz_offset_out_of_range",
+            &[0.5; 480],
+            "offset=1000",
+            ChannelCount::Mono,
+        );
+        match sf.load_warnings() {
+            [LoadWarning::OffsetOutOfRange {
+                region,
+                offset: 1000,
+                length: 480,
+            }] => {
+                assert_eq!(region.preset, None);
+                assert_eq!(region.index, 0);
+                assert!(region.sample.as_ref().unwrap().ends_with("sample.wav"));
+            }
+            other => panic!("unexpected warnings: {other:?}"),
+        }
+
+        // The offset is ignored instead of silencing the region
+        let out = render_voices(&sf, 60, 127, 400);
+        assert!(peak(&out[..400]) > 0.1);
+    }
+
+    #[test]
+    fn test_sfz_keycenter_out_of_range() {
+        let sf = load_test_sfz_with_sample(
+            "sfz_keycenter_out_of_range",
+            &[0.5; 480],
+            "lokey=60 hikey=72 pitch_keycenter=6",
+            ChannelCount::Mono,
+        );
+        assert!(matches!(
+            sf.load_warnings(),
+            [LoadWarning::KeycenterOutOfRange {
+                keycenter: 6,
+                keyrange,
+                ..
+            }] if *keyrange == (60..=72)
+        ));
+
+        // Key centers close to the key range are common and not reported
+        let sf = load_test_sfz_with_sample(
+            "sfz_keycenter_in_range",
+            &[0.5; 480],
+            "lokey=60 hikey=72 pitch_keycenter=48",
+            ChannelCount::Mono,
+        );
+        assert!(sf.load_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_sfz_strict_validation() {
+        let dir = TestSoundfontDir::new("sfz_strict_validation");
+        dir.write_wav("sample.wav", TEST_SAMPLE_RATE, &[0.5; 480]);
+        let load = |opcodes: &str| {
+            let sfz = dir.write_sfz(
+                "test.sfz",
+                &format!("<region> sample=sample.wav {opcodes}\n"),
+            );
+            SampleSoundfont::new_sfz(
+                sfz,
+                AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+                SoundfontInitOptions {
+                    strict_validation: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(load("offset=100 loop_mode=loop_continuous loop_start=0 loop_end=479").is_ok());
+        assert!(matches!(
+            load("offset=480"),
+            Err(LoadSfzError::InvalidRegion(LoadWarning::OffsetOutOfRange {
+                offset: 480,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            load("loop_mode=loop_continuous loop_start=0 loop_end=480"),
+            Err(LoadSfzError::InvalidRegion(LoadWarning::LoopClamped {
+                end: 480,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            load("loop_mode=loop_continuous loop_start=200 loop_end=100"),
+            Err(LoadSfzError::InvalidRegion(LoadWarning::LoopDisabled {
+                start: 200,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_sf2_strict_validation() {
+        let dir = TestSoundfontDir::new("sf2_strict_validation");
+        let sf2 = dir.write_sf2(
+            "test.sf2",
+            TEST_SAMPLE_RATE,
+            &[0.5; 480],
+            Some((400, 402)),
+            &[(0, 3, "Preset")],
+        );
+
+        let result = SampleSoundfont::new_sf2(
+            sf2,
+            AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            SoundfontInitOptions {
+                strict_validation: true,
+                ..Default::default()
+            },
+        );
+        match result {
+            Err(LoadSfError::InvalidRegion(LoadWarning::LoopDisabled { region, .. })) => {
+                assert_eq!(region.preset, Some((0, 3)));
+                assert_eq!(region.sample, None);
+            }
+            other => panic!("unexpected result: {:?}", other.err()),
+        }
     }
 }
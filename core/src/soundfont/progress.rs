@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Tracks the progress of a soundfont load and allows cancelling it from
+/// another thread. See `SampleSoundfont::new_with_progress`.
+///
+/// The progress is coarse: SFZ soundfonts report it per loaded sample file
+/// and SF2 soundfonts per processed preset.
+#[derive(Debug, Default)]
+pub struct SoundfontLoadProgress {
+    progress: AtomicU32,
+    cancelled: AtomicBool,
+}
+
+impl SoundfontLoadProgress {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the progress of the load, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        f32::from_bits(self.progress.load(Ordering::Relaxed))
+    }
+
+    /// Requests the load to be cancelled. The load stops at the next
+    /// sample file or preset and returns `LoadSfError::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the load was requested to be cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_progress(&self, progress: f32) {
+        self.progress
+            .store(progress.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        soundfont::{test_helpers::*, LoadSfError, SampleSoundfont},
+        AudioStreamParams, ChannelCount,
+    };
+
+    #[test]
+    fn test_load_progress() {
+        let dir = TestSoundfontDir::new("load_progress");
+        dir.write_wav("sample.wav", TEST_SAMPLE_RATE, &[0.5; 4800]);
+        let sfz = dir.write_sfz("test.sfz", "<region> sample=sample.wav\n");
+        let params = AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono);
+
+        let progress = SoundfontLoadProgress::new();
+        SampleSoundfont::new_with_progress(&sfz, params, Default::default(), &progress).unwrap();
+        assert_eq!(progress.progress(), 1.0);
+
+        let progress = SoundfontLoadProgress::new();
+        progress.cancel();
+        let result =
+            SampleSoundfont::new_with_progress(&sfz, params, Default::default(), &progress);
+        assert!(matches!(result, Err(LoadSfError::Cancelled)));
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+/// The spawner parameters of a single key.
+///
+/// Most soundfonts don't split their regions by velocity, so keys whose
+/// per-velocity lists are all identical store a single shared list instead
+/// of 128 copies of it.
+pub(super) enum KeySpawnerList<T> {
+    Shared(Vec<Arc<T>>),
+    PerVelocity(Box<[Vec<Arc<T>>]>),
+}
+
+impl<T> KeySpawnerList<T> {
+    fn from_velocity_lists(lists: Vec<Vec<Arc<T>>>) -> Self {
+        let first = &lists[0];
+        let identical = lists.iter().skip(1).all(|list| {
+            list.len() == first.len() && list.iter().zip(first).all(|(a, b)| Arc::ptr_eq(a, b))
+        });
+
+        if identical {
+            KeySpawnerList::Shared(lists.into_iter().next().unwrap())
+        } else {
+            KeySpawnerList::PerVelocity(lists.into_boxed_slice())
+        }
+    }
+
+    pub fn get(&self, vel: u8) -> &[Arc<T>] {
+        match self {
+            KeySpawnerList::Shared(list) => list,
+            KeySpawnerList::PerVelocity(lists) => &lists[vel as usize],
+        }
+    }
+}
+
+/// Converts a flat list of 128 * 128 spawner parameter lists (indexed
+/// with `key_vel_to_index`) to a compact list of 128 keys.
+pub(super) fn compact_spawner_params<T>(list: Vec<Vec<Arc<T>>>) -> Vec<KeySpawnerList<T>> {
+    assert_eq!(list.len(), 128 * 128);
+
+    let mut list = list.into_iter();
+    (0..128)
+        .map(|_| KeySpawnerList::from_velocity_lists(list.by_ref().take(128).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soundfont::utils::key_vel_to_index;
+
+    fn flat_list(velocity_split: bool) -> Vec<Vec<Arc<u32>>> {
+        let mut list = vec![Vec::new(); 128 * 128];
+        for key in 0..128u8 {
+            let low = Arc::new(key as u32);
+            let high = Arc::new(key as u32 + 1000);
+            let layer = Arc::new(key as u32 + 2000);
+            for vel in 0..128u8 {
+                let index = key_vel_to_index(key, vel);
+                if velocity_split && vel >= 64 {
+                    list[index].push(high.clone());
+                } else {
+                    list[index].push(low.clone());
+                }
+                if key % 2 == 0 {
+                    list[index].push(layer.clone());
+                }
+            }
+        }
+        list
+    }
+
+    fn assert_same_lookups(flat: Vec<Vec<Arc<u32>>>) -> Vec<KeySpawnerList<u32>> {
+        let compact = compact_spawner_params(flat.clone());
+        for key in 0..128u8 {
+            for vel in 0..128u8 {
+                let expected = &flat[key_vel_to_index(key, vel)];
+                let actual = compact[key as usize].get(vel);
+                assert_eq!(expected.len(), actual.len());
+                assert!(expected.iter().zip(actual).all(|(a, b)| Arc::ptr_eq(a, b)));
+            }
+        }
+        compact
+    }
+
+    #[test]
+    fn test_compact_non_split() {
+        let compact = assert_same_lookups(flat_list(false));
+        assert!(compact
+            .iter()
+            .all(|k| matches!(k, KeySpawnerList::Shared(_))));
+    }
+
+    #[test]
+    fn test_compact_velocity_split() {
+        let compact = assert_same_lookups(flat_list(true));
+        assert!(compact
+            .iter()
+            .all(|k| matches!(k, KeySpawnerList::PerVelocity(_))));
+    }
+
+    #[test]
+    fn test_compact_equal_values() {
+        // Lists with equal values but different allocations are not merged
+        let mut flat = vec![Vec::new(); 128 * 128];
+        for list in flat.iter_mut() {
+            list.push(Arc::new(0));
+        }
+        let compact = assert_same_lookups(flat);
+        assert!(compact
+            .iter()
+            .all(|k| matches!(k, KeySpawnerList::PerVelocity(_))));
+    }
+}
@@ -0,0 +1,207 @@
+use std::{fs, path::PathBuf};
+
+use super::*;
+use crate::voice::VoiceControlData;
+
+pub(crate) use super::test_utils::TestSoundfontDir;
+
+impl TestSoundfontDir {
+    /// Writes a minimal 16-bit SF2 file with a single sample and instrument,
+    /// and one preset per `(bank, preset, name)` entry using that instrument.
+    /// The sample loops continuously over `loop_points` if they are given.
+    pub fn write_sf2(
+        &self,
+        name: &str,
+        sample_rate: u32,
+        samples: &[f32],
+        loop_points: Option<(u32, u32)>,
+        presets: &[(u16, u16, &str)],
+    ) -> PathBuf {
+        fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+            let mut bytes = id.to_vec();
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+            bytes
+        }
+
+        fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+            let mut data = kind.to_vec();
+            for c in chunks {
+                data.extend_from_slice(c);
+            }
+            chunk(b"LIST", &data)
+        }
+
+        fn name20(name: &str) -> [u8; 20] {
+            let mut bytes = [0u8; 20];
+            bytes[..name.len()].copy_from_slice(name.as_bytes());
+            bytes
+        }
+
+        fn phdr(name: &str, preset: u16, bank: u16, bag: u16) -> Vec<u8> {
+            let mut bytes = name20(name).to_vec();
+            for v in [preset, bank, bag] {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            bytes.extend_from_slice(&[0; 12]);
+            bytes
+        }
+
+        fn bag(gen: u16, modulator: u16) -> Vec<u8> {
+            [gen.to_le_bytes(), modulator.to_le_bytes()].concat()
+        }
+
+        fn gen(oper: u16, amount: u16) -> Vec<u8> {
+            [oper.to_le_bytes(), amount.to_le_bytes()].concat()
+        }
+
+        fn inst(name: &str, bag: u16) -> Vec<u8> {
+            [&name20(name)[..], &bag.to_le_bytes()].concat()
+        }
+
+        fn shdr(name: &str, end: u32, loop_points: (u32, u32), sample_rate: u32) -> Vec<u8> {
+            let mut bytes = name20(name).to_vec();
+            for v in [0, end, loop_points.0, loop_points.1, sample_rate] {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            // Root key 60, no pitch correction, mono sample
+            bytes.extend_from_slice(&[60, 0, 0, 0, 1, 0]);
+            bytes
+        }
+
+        // Samples are followed by 46 zero points, as required by the spec
+        let mut smpl = Vec::new();
+        for s in samples.iter().chain(&[0.0; 46]) {
+            let s = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            smpl.extend_from_slice(&s.to_le_bytes());
+        }
+
+        // Each preset has a single zone pointing to instrument 0
+        let count = presets.len() as u16;
+        let mut phdrs = Vec::new();
+        let mut pbags = Vec::new();
+        let mut pgens = Vec::new();
+        for (i, (bank, preset, name)) in presets.iter().enumerate() {
+            phdrs.extend(phdr(name, *preset, *bank, i as u16));
+            pbags.extend(bag(i as u16, 0));
+            pgens.extend(gen(41, 0));
+        }
+        phdrs.extend(phdr("EOP", 0, 0, count));
+        pbags.extend(bag(count, 0));
+        pgens.extend(gen(0, 0));
+
+        // The instrument zone optionally sets the sample mode, followed
+        // by the sample ID which must be its last generator
+        let end = samples.len() as u32;
+        let mut igens = Vec::new();
+        if loop_points.is_some() {
+            igens.extend(gen(54, 1));
+        }
+        igens.extend(gen(53, 0));
+        let igen_count = igens.len() as u16 / 4;
+        igens.extend(gen(0, 0));
+        let pdta = list(
+            b"pdta",
+            &[
+                chunk(b"phdr", &phdrs),
+                chunk(b"pbag", &pbags),
+                chunk(b"pmod", &[0; 10]),
+                chunk(b"pgen", &pgens),
+                chunk(b"inst", &[inst("Instrument", 0), inst("EOI", 1)].concat()),
+                chunk(b"ibag", &[bag(0, 0), bag(igen_count, 0)].concat()),
+                chunk(b"imod", &[0; 10]),
+                chunk(b"igen", &igens),
+                chunk(
+                    b"shdr",
+                    &[
+                        shdr("Sample", end, loop_points.unwrap_or((0, end)), sample_rate),
+                        shdr("EOS", 0, (0, 0), 0),
+                    ]
+                    .concat(),
+                ),
+            ],
+        );
+
+        let mut ifil = [0u8; 4];
+        ifil[..2].copy_from_slice(&2u16.to_le_bytes());
+        ifil[2..].copy_from_slice(&1u16.to_le_bytes());
+        let info = list(b"INFO", &[chunk(b"ifil", &ifil)]);
+        let sdta = list(b"sdta", &[chunk(b"smpl", &smpl)]);
+
+        let contents = chunk(b"RIFF", &[&b"sfbk"[..], &info, &sdta, &pdta].concat());
+        let path = self.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+pub(crate) const TEST_SAMPLE_RATE: u32 = 48000;
+
+/// Loads an SFZ soundfont using a single region playing a constant
+/// signal, with the given extra region opcodes.
+pub(crate) fn load_test_sfz(name: &str, opcodes: &str) -> SampleSoundfont {
+    load_test_sfz_with_sample(name, &[0.5; 48000], opcodes, ChannelCount::Mono)
+}
+
+/// Loads an SFZ soundfont using a single region playing the given
+/// samples, with the given extra region opcodes.
+pub(crate) fn load_test_sfz_with_sample(
+    name: &str,
+    samples: &[f32],
+    opcodes: &str,
+    channels: ChannelCount,
+) -> SampleSoundfont {
+    let dir = TestSoundfontDir::new(name);
+    dir.write_wav("sample.wav", TEST_SAMPLE_RATE, samples);
+    let sfz = dir.write_sfz(
+        "test.sfz",
+        &format!("<region> sample=sample.wav pitch_keycenter=60 {opcodes}\n"),
+    );
+
+    SampleSoundfont::new_sfz(
+        sfz,
+        AudioStreamParams::new(TEST_SAMPLE_RATE, channels),
+        Default::default(),
+    )
+    .unwrap()
+}
+
+/// Renders the voices spawned by a soundfont for a key and velocity.
+pub(crate) fn render_voices(sf: &SampleSoundfont, key: u8, vel: u8, len: usize) -> Vec<f32> {
+    let control = VoiceControlData::new_defaults();
+    let mut out = vec![0.0; len];
+    for spawner in sf.get_attack_voice_spawners_at(0, 0, key, vel).iter() {
+        let mut voice = spawner.spawn_voice(&control);
+        voice.render_to(&mut out);
+    }
+    out
+}
+
+/// Returns the peak absolute level of a buffer.
+pub(crate) fn peak(buf: &[f32]) -> f32 {
+    buf.iter().fold(0.0f32, |a, b| a.max(b.abs()))
+}
+
+/// Returns the mean power of the bins of the given frequency range, using
+/// a Hann window over the buffer.
+pub(crate) fn band_power(buf: &[f32], sample_rate: u32, low: f32, high: f32) -> f32 {
+    use std::f32::consts::PI;
+
+    let n = buf.len();
+    let bin_width = sample_rate as f32 / n as f32;
+    let bins = (low / bin_width).ceil() as usize..=(high / bin_width) as usize;
+    let count = bins.clone().count();
+    let power: f32 = bins
+        .map(|bin| {
+            let (mut re, mut im) = (0.0f32, 0.0f32);
+            for (i, s) in buf.iter().enumerate() {
+                let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos();
+                let phase = 2.0 * PI * (bin * i % n) as f32 / n as f32;
+                re += s * window * phase.cos();
+                im -= s * window * phase.sin();
+            }
+            re * re + im * im
+        })
+        .sum();
+    power / count as f32
+}
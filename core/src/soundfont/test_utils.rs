@@ -0,0 +1,56 @@
+//! Helpers to write the files of test soundfonts, shared by the tests of
+//! the XSynth crates. Enabled with the `test-utils` feature.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A temporary directory holding the files of a test soundfont, removed
+/// when dropped.
+pub struct TestSoundfontDir {
+    path: PathBuf,
+}
+
+impl TestSoundfontDir {
+    /// Creates the directory, named after the test and the process ID.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("xsynth_test_{name}_{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes a mono 16-bit PCM WAV file.
+    pub fn write_wav(&self, name: &str, sample_rate: u32, samples: &[f32]) -> PathBuf {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let path = self.path.join(name);
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for s in samples {
+            let s = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            writer.write_sample(s).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    pub fn write_sfz(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestSoundfontDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
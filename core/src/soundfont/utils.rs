@@ -1,6 +1,17 @@
-use crate::{helpers::FREQS, voice::EnvelopeDescriptor};
-use std::path::PathBuf;
-use xsynth_soundfonts::sfz::{AmpegEnvelopeParams, RegionParams};
+use crate::{
+    helpers::FREQS,
+    voice::{EnvelopeDescriptor, EnvelopeParameters},
+    AudioStreamParams,
+};
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc};
+use xsynth_soundfonts::{
+    sfz::{AmpegEnvelopeParams, PitchegEnvelopeParams, RegionParams},
+    LoopMode,
+};
+
+use super::{
+    EnvelopeCurveType, EnvelopeOptions, LoadWarning, LoopParams, RegionId, SoundfontInitOptions,
+};
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub(super) struct SampleCache {
@@ -19,10 +30,245 @@ pub(super) fn get_speed_mult_from_keys(key: u8, base_key: u8) -> f32 {
     freq / base_freq
 }
 
+/// Like `get_speed_mult_from_keys`, but with a fractional base key
+/// for microtonal key centers.
+pub(super) fn get_speed_mult_from_fractional_keys(key: u8, base_key: f32) -> f32 {
+    let whole = base_key.floor();
+    get_speed_mult_from_keys(key, whole as u8) * 2.0f32.powf((whole - base_key) / 12.0)
+}
+
+/// Returns the speed multiplier which plays a sample of the given native
+/// sample rate at its original pitch. Samples resampled on load already
+/// match the stream sample rate.
+pub(super) fn native_rate_speed_mult(
+    sample_rate: u32,
+    stream_params: AudioStreamParams,
+    options: SoundfontInitOptions,
+) -> f32 {
+    if options.resample_on_load {
+        1.0
+    } else {
+        sample_rate as f32 / stream_params.sample_rate as f32
+    }
+}
+
+/// Converts a duration in milliseconds to a sample count at the given
+/// sample rate. Negative durations are treated as zero.
+pub(super) fn ms_to_samples(ms: f32, sample_rate: u32) -> u32 {
+    (ms.max(0.0) / 1000.0 * sample_rate as f32) as u32
+}
+
 pub(super) fn key_vel_to_index(key: u8, vel: u8) -> usize {
+    debug_assert!(
+        key < 128 && vel < 128,
+        "key {key} or velocity {vel} out of range"
+    );
     (key as usize) * 128 + (vel as usize)
 }
 
+/// Calculates the amplitude multiplier of a velocity, where `veltrack`
+/// is the velocity tracking percentage as described by the SFZ `amp_veltrack` opcode.
+/// If the region has an `amp_velcurve`, its gain is used instead of the default
+/// quadratic curve and `veltrack` scales its depth.
+///
+/// The velocity is in the 0.0-127.0 range, and fractional velocities from
+/// high resolution note events are interpolated between the curve points.
+pub(super) fn velocity_amp(veltrack: f32, velcurve: Option<&[f32; 128]>, vel: f32) -> f32 {
+    let vel = vel.clamp(0.0, 127.0);
+
+    if let Some(curve) = velcurve {
+        let index = vel as usize;
+        let next = (index + 1).min(127);
+        let fract = vel - index as f32;
+        let point = curve[index] + (curve[next] - curve[index]) * fract;
+
+        let a = veltrack / 100.0;
+        let gain = a.abs() * (1.0 - point);
+        return if a < 0.0 { gain } else { 1.0 - gain };
+    }
+
+    let a = veltrack / 100.0;
+    let aabs = a.abs();
+
+    let vol_vel = 127.0 * (1.0 - aabs) + vel * (a + aabs) / 2.0 + (127.0 - vel) * (aabs - a) / 2.0;
+    (vol_vel / 127.0).powi(2)
+}
+
+/// Calculates the gain of a velocity in the velocity crossfade of a region,
+/// as described by the SFZ `xfin_lovel`/`xfin_hivel` and `xfout_lovel`/`xfout_hivel`
+/// opcodes, using the default equal power curve. The region fades in over
+/// `xfin` and fades out over `xfout`, so the default ranges of `0..=0` and
+/// `127..=127` keep the full gain at all velocities.
+pub(super) fn velocity_crossfade(
+    xfin: &RangeInclusive<u8>,
+    xfout: &RangeInclusive<u8>,
+    vel: u8,
+) -> f32 {
+    // Only called with velocities strictly inside the range
+    let position = |range: &RangeInclusive<u8>| {
+        (vel - range.start()) as f32 / (range.end() - range.start()) as f32
+    };
+
+    let fade_in = if vel >= *xfin.end() {
+        1.0
+    } else if vel <= *xfin.start() {
+        0.0
+    } else {
+        position(xfin)
+    };
+    let fade_out = if vel <= *xfout.start() {
+        1.0
+    } else if vel >= *xfout.end() {
+        0.0
+    } else {
+        1.0 - position(xfout)
+    };
+    (fade_in * fade_out).sqrt()
+}
+
+/// Builds the gain of every velocity from the points of the SFZ `amp_velcurve_N`
+/// opcodes. The points are linearly interpolated, with implicit points at
+/// velocity 0 (silent) and 127 (full gain) unless they are defined.
+/// Returns `None` if there are no points.
+pub(super) fn velocity_curve_from_points(points: &[(u8, f32)]) -> Option<Arc<[f32; 128]>> {
+    if points.is_empty() {
+        return None;
+    }
+
+    let mut all_points = Vec::with_capacity(points.len() + 2);
+    if points[0].0 != 0 {
+        all_points.push((0u8, 0.0f32));
+    }
+    all_points.extend_from_slice(points);
+    if all_points[all_points.len() - 1].0 != 127 {
+        all_points.push((127, 1.0));
+    }
+
+    let mut curve = [0.0; 128];
+    for pair in all_points.windows(2) {
+        let (start_vel, start_gain) = pair[0];
+        let (end_vel, end_gain) = pair[1];
+        let len = (end_vel - start_vel) as f32;
+        for vel in start_vel..=end_vel {
+            let t = (vel - start_vel) as f32 / len;
+            curve[vel as usize] = start_gain + (end_gain - start_gain) * t;
+        }
+    }
+    Some(Arc::new(curve))
+}
+
+/// The shortest loop that is played, in samples. Shorter loops almost
+/// always come from broken loop metadata and only produce a buzz.
+const MIN_LOOP_LENGTH: u64 = 4;
+
+/// Returns the loop parameters to be used for a sample of the given length.
+///
+/// Looping modes without a loop region play the sample once, while `OneShot`
+/// is always kept. Loop points past the end of the sample are clamped, and
+/// loops that are reversed or too short to be played are disabled, which is
+/// reported with the returned warning.
+pub(super) fn validate_loop_params(
+    params: LoopParams,
+    sample_length: usize,
+    region: &RegionId,
+) -> (LoopParams, Option<LoadWarning>) {
+    if !matches!(
+        params.mode,
+        LoopMode::LoopContinuous | LoopMode::LoopSustain
+    ) {
+        return (params, None);
+    }
+    if params.start == params.end {
+        let params = LoopParams {
+            mode: LoopMode::NoLoop,
+            ..params
+        };
+        return (params, None);
+    }
+
+    let length = sample_length as u64;
+    let last = length.saturating_sub(1);
+    let start = params.start.min(last);
+    let end = params.end.min(last);
+
+    if end <= start || end - start < MIN_LOOP_LENGTH {
+        let warning = LoadWarning::LoopDisabled {
+            region: region.clone(),
+            start: params.start,
+            end: params.end,
+            length,
+        };
+        let params = LoopParams {
+            mode: LoopMode::NoLoop,
+            ..params
+        };
+        (params, Some(warning))
+    } else if start != params.start || end != params.end {
+        let warning = LoadWarning::LoopClamped {
+            region: region.clone(),
+            start: params.start,
+            end: params.end,
+            length,
+        };
+        (
+            LoopParams {
+                start,
+                end,
+                ..params
+            },
+            Some(warning),
+        )
+    } else {
+        (params, None)
+    }
+}
+
+/// Returns the offset to be used for a sample of the given length. Offsets
+/// past the end of the sample would make the region silent, so they are
+/// ignored instead, which is reported with the returned warning.
+pub(super) fn validate_offset(
+    offset: u64,
+    sample_length: usize,
+    region: &RegionId,
+) -> (u64, Option<LoadWarning>) {
+    let length = sample_length as u64;
+    if offset < length {
+        (offset, None)
+    } else {
+        let warning = LoadWarning::OffsetOutOfRange {
+            region: region.clone(),
+            offset,
+            length,
+        };
+        (0, Some(warning))
+    }
+}
+
+/// How far the key center of a region can be from its key range, in
+/// semitones, before it is reported as a likely mistake.
+const MAX_KEYCENTER_DISTANCE: u8 = 24;
+
+/// Reports key centers which are far away from the key range of their
+/// region, which usually come from a typo and play the samples at the
+/// wrong pitch. They are still used as they are.
+pub(super) fn validate_keycenter(
+    keycenter: u8,
+    keyrange: &RangeInclusive<u8>,
+    region: &RegionId,
+) -> Option<LoadWarning> {
+    let distance = if keycenter < *keyrange.start() {
+        keyrange.start() - keycenter
+    } else {
+        keycenter.saturating_sub(*keyrange.end())
+    };
+
+    (distance > MAX_KEYCENTER_DISTANCE).then(|| LoadWarning::KeycenterOutOfRange {
+        region: region.clone(),
+        keycenter,
+        keyrange: keyrange.clone(),
+    })
+}
+
 pub(super) fn cents_factor(cents: f32) -> f32 {
     2.0f32.powf(cents / 1200.0)
 }
@@ -45,3 +291,155 @@ pub(super) fn envelope_descriptor_from_region_params(
         release: env.ampeg_release,
     }
 }
+
+/// Generates the parameters of a modulation envelope, where 1 is the full depth.
+/// Returns `None` if the envelope has no effect, i.e. its depth is 0.
+pub(super) fn modulation_envelope_from_region_params(
+    region_params: &PitchegEnvelopeParams,
+    depth: f32,
+    sample_rate: u32,
+) -> Option<Arc<EnvelopeParameters>> {
+    let env = region_params;
+    if depth == 0.0 {
+        return None;
+    }
+
+    let descriptor = EnvelopeDescriptor {
+        start_percent: env.pitcheg_start / 100.0,
+        delay: env.pitcheg_delay,
+        attack: env.pitcheg_attack,
+        hold: env.pitcheg_hold,
+        decay: env.pitcheg_decay,
+        sustain_percent: env.pitcheg_sustain / 100.0,
+        release: env.pitcheg_release,
+    };
+
+    // The envelope is linear in cents, so every stage uses a plain lerp
+    let options = EnvelopeOptions {
+        attack_curve: EnvelopeCurveType::Exponential,
+        decay_curve: EnvelopeCurveType::Exponential,
+        release_curve: EnvelopeCurveType::Exponential,
+    };
+
+    Some(Arc::new(
+        descriptor.to_envelope_params(sample_rate, options),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        soundfont::{
+            test_helpers::*, LoadWarning, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+        },
+        AudioStreamParams, ChannelCount,
+    };
+
+    #[test]
+    fn test_amp_velcurve() {
+        let sf = load_test_sfz("amp_velcurve", "amp_velcurve_64=1");
+        let full = peak(&render_voices(&sf, 60, 64, 1024));
+        let half = peak(&render_voices(&sf, 60, 32, 1024));
+        assert!(full > 0.0);
+        assert!((peak(&render_voices(&sf, 60, 127, 1024)) - full).abs() < 1e-6);
+        assert!((half / full - 0.5).abs() < 1e-4);
+
+        let sf = load_test_sfz(
+            "amp_velcurve_veltrack_0",
+            "amp_velcurve_64=1 amp_veltrack=0",
+        );
+        let low = peak(&render_voices(&sf, 60, 1, 1024));
+        let high = peak(&render_voices(&sf, 60, 127, 1024));
+        assert!((low - high).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sfz_hivel_128_clamped() {
+        // Velocity 128 would be stored in the slot of the next key's velocity 0
+        let sf = load_test_sfz("sfz_hivel_128", "lokey=60 hikey=60 lovel=100 hivel=128");
+        assert!(!sf.get_attack_voice_spawners_at(0, 0, 60, 127).is_empty());
+        assert!(!sf.get_attack_voice_spawners_at(0, 0, 60, 100).is_empty());
+        assert!(sf.get_attack_voice_spawners_at(0, 0, 60, 99).is_empty());
+        assert!(sf.get_attack_voice_spawners_at(0, 0, 61, 0).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_sfz_loop_disabled() {
+        // A reversed loop, which would wrap with a negative span
+        let sf = load_test_sfz_with_sample(
+            "invalid_sfz_loop",
+            &[0.5; 480],
+            "loop_mode=loop_continuous loop_start=400 loop_end=100",
+            ChannelCount::Mono,
+        );
+        assert!(matches!(
+            sf.load_warnings(),
+            [LoadWarning::LoopDisabled {
+                start: 400,
+                end: 100,
+                ..
+            }]
+        ));
+
+        // The sample is played once instead
+        let out = render_voices(&sf, 60, 127, 2000);
+        assert!(peak(&out[..400]) > 0.1);
+        assert_eq!(peak(&out[600..]), 0.0);
+    }
+
+    #[test]
+    fn test_out_of_range_sfz_loop_clamped() {
+        let samples: Vec<f32> = (0..480).map(|i| (i % 48) as f32 / 48.0).collect();
+        let sf = load_test_sfz_with_sample(
+            "out_of_range_sfz_loop",
+            &samples,
+            "loop_mode=loop_continuous loop_start=96 loop_end=100000",
+            ChannelCount::Mono,
+        );
+        assert!(matches!(
+            sf.load_warnings(),
+            [LoadWarning::LoopClamped {
+                start: 96,
+                end: 100000,
+                ..
+            }]
+        ));
+
+        // The loop keeps playing the sample data instead of silence
+        let out = render_voices(&sf, 60, 127, 4800);
+        for chunk in out[480..].chunks(480) {
+            assert!(peak(chunk) > 0.5);
+        }
+    }
+
+    #[test]
+    fn test_invalid_sf2_loop_disabled() {
+        let dir = TestSoundfontDir::new("invalid_sf2_loop");
+        let sf2 = dir.write_sf2(
+            "test.sf2",
+            TEST_SAMPLE_RATE,
+            &[0.5; 480],
+            Some((400, 402)),
+            &[(0, 0, "Preset")],
+        );
+
+        let sf = SampleSoundfont::new_sf2(
+            sf2,
+            AudioStreamParams::new(TEST_SAMPLE_RATE, ChannelCount::Mono),
+            SoundfontInitOptions::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            sf.load_warnings(),
+            [LoadWarning::LoopDisabled {
+                start: 400,
+                end: 402,
+                ..
+            }]
+        ));
+
+        let out = render_voices(&sf, 60, 127, 2000);
+        assert!(peak(&out[..400]) > 0.1);
+        assert_eq!(peak(&out[600..]), 0.0);
+    }
+}
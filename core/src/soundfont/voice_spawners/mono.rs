@@ -13,25 +13,44 @@ use crate::{
 use crate::{
     voice::VoiceControlData,
     voice::{
-        BufferSamplers, EnvelopeParameters, SIMDConstant, SIMDLinearSampleGrabber, SIMDMonoVoice,
-        SIMDMonoVoiceSampler, SIMDNearestSampleGrabber, SIMDVoiceControl, SIMDVoiceEnvelope,
-        SampleReader, SampleReaderLoop, SampleReaderLoopSustain, SampleReaderNoLoop, Voice,
-        VoiceBase, VoiceCombineSIMD,
+        BufferSamplers, EnvelopeParameters, LfoParameters, ModulatedFilterParams, SIMDConstant,
+        SIMDCutoffModulator, SIMDLfoVolume, SIMDLinearSampleGrabber, SIMDMonoVoice,
+        SIMDMonoVoiceModCutoff, SIMDMonoVoiceSampler, SIMDNearestSampleGrabber, SIMDPitchEnvelope,
+        SIMDPortamento, SIMDVoiceControl, SIMDVoiceEnvelope, SampleReader, SampleReaderLoop,
+        SampleReaderLoopSustain, SampleReaderNoLoop, Voice, VoiceBase, VoiceCombineSIMD,
     },
 };
 
 use xsynth_soundfonts::LoopMode;
 
-use crate::soundfont::{Interpolator, LoopParams, SampleVoiceSpawnerParams, VoiceSpawner};
+use crate::soundfont::{
+    utils::velocity_amp, Interpolator, LoopParams, RandomParams, SampleVoiceSpawnerParams,
+    SequenceParams, VoiceRandom, VoiceSpawner,
+};
 
 pub struct MonoSampledVoiceSpawner<S: 'static + Simd + Send + Sync> {
     speed_mult: f32,
     filter: Option<BiQuadFilter>,
     loop_params: LoopParams,
-    amp: f32,
+    volume: f32,
+    amp_veltrack: f32,
+    amp_velcurve: Option<Arc<[f32; 128]>>,
+    vel_amp: f32,
     volume_envelope_params: Arc<EnvelopeParameters>,
+    release_veltrack: f32,
+    pitch_envelope_params: Option<Arc<EnvelopeParameters>>,
+    pitch_envelope_depth: f32,
+    base_cutoff: f32,
+    filter_params: ModulatedFilterParams,
+    filter_envelope_params: Option<Arc<EnvelopeParameters>>,
+    filter_envelope_depth: f32,
+    mod_lfo_params: Option<LfoParameters>,
+    mod_lfo_to_cutoff: f32,
+    mod_lfo_to_volume: f32,
     samples: Arc<[Arc<[f32]>]>,
     interpolator: Interpolator,
+    random: RandomParams,
+    sequence: SequenceParams,
     vel: u8,
     stream_params: AudioStreamParams,
     _s: PhantomData<S>,
@@ -43,7 +62,11 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
         vel: u8,
         stream_params: AudioStreamParams,
     ) -> Self {
-        let amp = params.volume;
+        let vel_amp = velocity_amp(
+            params.amp_veltrack,
+            params.amp_velcurve.as_deref(),
+            vel as f32,
+        );
 
         let filter = params.cutoff.map(|cutoff| {
             BiQuadFilter::new(
@@ -58,17 +81,76 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
             speed_mult: params.speed_mult,
             filter,
             loop_params: params.loop_params.clone(),
-            amp,
+            volume: params.volume,
+            amp_veltrack: params.amp_veltrack,
+            amp_velcurve: params.amp_velcurve.clone(),
+            vel_amp,
             volume_envelope_params: params.envelope.clone(),
+            release_veltrack: params.release_veltrack,
+            pitch_envelope_params: params.pitch_envelope.clone(),
+            pitch_envelope_depth: params.pitch_envelope_depth,
+            base_cutoff: params.cutoff.unwrap_or(0.0),
+            filter_params: ModulatedFilterParams {
+                fil_type: params.filter_type,
+                q: Some(params.resonance),
+                sample_rate: stream_params.sample_rate as f32,
+            },
+            filter_envelope_params: params.filter_envelope.clone(),
+            filter_envelope_depth: params.filter_envelope_depth,
+            mod_lfo_params: params.mod_lfo,
+            mod_lfo_to_cutoff: params.mod_lfo_to_cutoff,
+            mod_lfo_to_volume: params.mod_lfo_to_volume,
             samples: params.sample.clone(),
             interpolator: params.interpolator,
+            random: params.random,
+            sequence: params.sequence,
             vel,
             stream_params,
             _s: PhantomData,
         }
     }
 
+    /// Returns a copy of the spawner with the random variations of a voice
+    /// applied to it.
+    fn randomized(&self, random: VoiceRandom) -> Self {
+        Self {
+            speed_mult: self.speed_mult * random.speed_mult,
+            filter: self.filter.clone(),
+            loop_params: LoopParams {
+                offset: self.loop_params.offset.saturating_add(random.offset as u64),
+                ..self.loop_params.clone()
+            },
+            volume: self.volume * random.amp,
+            amp_veltrack: self.amp_veltrack,
+            amp_velcurve: self.amp_velcurve.clone(),
+            vel_amp: self.vel_amp,
+            volume_envelope_params: self.volume_envelope_params.clone(),
+            release_veltrack: self.release_veltrack,
+            pitch_envelope_params: self.pitch_envelope_params.clone(),
+            pitch_envelope_depth: self.pitch_envelope_depth,
+            base_cutoff: self.base_cutoff,
+            filter_params: self.filter_params,
+            filter_envelope_params: self.filter_envelope_params.clone(),
+            filter_envelope_depth: self.filter_envelope_depth,
+            mod_lfo_params: self.mod_lfo_params,
+            mod_lfo_to_cutoff: self.mod_lfo_to_cutoff,
+            mod_lfo_to_volume: self.mod_lfo_to_volume,
+            samples: self.samples.clone(),
+            interpolator: self.interpolator,
+            random: Default::default(),
+            sequence: self.sequence,
+            vel: self.vel,
+            stream_params: self.stream_params,
+            _s: PhantomData,
+        }
+    }
+
     fn begin_voice(&self, control: &VoiceControlData) -> Box<dyn Voice> {
+        if self.random.is_enabled() {
+            let random = self.random.draw(control.random);
+            return self.randomized(random).begin_voice(control);
+        }
+
         // Currently there's only the f32 buffer samples, more could be added in the future.
         #[allow(clippy::redundant_closure)]
         self.make_sample_reader(control, |s| BufferSamplers::new_f32(s))
@@ -116,17 +198,48 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
 
         let pitch_fac = self.create_pitch_fac(control);
 
-        let sampler = SIMDMonoVoiceSampler::new(sample, pitch_fac);
-        self.apply_voice_params(sampler, control)
+        // Voices without portamento skip it entirely
+        if let Some(portamento) = self.create_portamento(control) {
+            let pitch_fac = VoiceCombineSIMD::mult(portamento, pitch_fac);
+            self.apply_pitch_envelope(sample, pitch_fac, control)
+        } else {
+            self.apply_pitch_envelope(sample, pitch_fac, control)
+        }
+    }
+
+    fn apply_pitch_envelope<SG: 'static + SIMDSampleGrabber<S>>(
+        &self,
+        sample: SG,
+        pitch_fac: impl 'static + SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+        control: &VoiceControlData,
+    ) -> Box<dyn Voice> {
+        // Voices without a pitch envelope skip it entirely
+        if let Some(pitch_envelope) = self.create_pitch_envelope() {
+            let pitch_fac = VoiceCombineSIMD::mult(pitch_envelope, pitch_fac);
+            let sampler = SIMDMonoVoiceSampler::new(sample, pitch_fac);
+            self.apply_voice_params(sampler, control)
+        } else {
+            let sampler = SIMDMonoVoiceSampler::new(sample, pitch_fac);
+            self.apply_voice_params(sampler, control)
+        }
     }
 
-    fn apply_velocity<Gen, Sample>(&self, gen: Gen) -> impl SIMDVoiceGenerator<S, Sample>
+    fn apply_velocity<Gen, Sample>(
+        &self,
+        gen: Gen,
+        control: &VoiceControlData,
+    ) -> impl SIMDVoiceGenerator<S, Sample>
     where
         Sample: SIMDSample<S>,
         SIMDSampleMono<S>: Mul<Sample, Output = Sample>,
         Gen: SIMDVoiceGenerator<S, Sample>,
     {
-        let amp = SIMDConstant::<S>::new(self.amp);
+        // High resolution velocities between two 7-bit velocities use the exact curve gain
+        let vel_amp = match control.velocity {
+            Some(vel) => velocity_amp(self.amp_veltrack, self.amp_velcurve.as_deref(), vel),
+            None => self.vel_amp,
+        };
+        let amp = SIMDConstant::<S>::new(self.volume * vel_amp);
         let amp = VoiceCombineSIMD::mult(amp, gen);
         amp
     }
@@ -141,6 +254,60 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
         pitch_fac
     }
 
+    fn create_portamento(&self, control: &VoiceControlData) -> Option<SIMDPortamento<S>> {
+        let portamento = control.portamento?;
+        Some(SIMDPortamento::new(
+            portamento,
+            self.stream_params.sample_rate as f32,
+        ))
+    }
+
+    fn create_pitch_envelope(&self) -> Option<SIMDPitchEnvelope<S>> {
+        let params = self.pitch_envelope_params.as_ref()?;
+        let allow_release = self.loop_params.mode != LoopMode::OneShot;
+
+        Some(SIMDPitchEnvelope::new(
+            **params,
+            self.pitch_envelope_depth,
+            allow_release,
+            self.stream_params.sample_rate as f32,
+        ))
+    }
+
+    fn create_lfo_volume(&self) -> Option<SIMDLfoVolume<S>> {
+        if self.mod_lfo_to_volume == 0.0 {
+            return None;
+        }
+
+        let params = self.mod_lfo_params?;
+        Some(SIMDLfoVolume::new(params, self.mod_lfo_to_volume))
+    }
+
+    fn create_cutoff_modulator(&self) -> Option<SIMDCutoffModulator<S>> {
+        let envelope = self
+            .filter_envelope_params
+            .as_ref()
+            .map(|params| (**params, self.filter_envelope_depth));
+        let lfo = self
+            .mod_lfo_params
+            .filter(|_| self.mod_lfo_to_cutoff != 0.0)
+            .map(|params| (params, self.mod_lfo_to_cutoff));
+
+        if envelope.is_none() && lfo.is_none() {
+            return None;
+        }
+
+        let allow_release = self.loop_params.mode != LoopMode::OneShot;
+
+        Some(SIMDCutoffModulator::new(
+            self.base_cutoff,
+            envelope,
+            lfo,
+            allow_release,
+            self.stream_params.sample_rate as f32,
+        ))
+    }
+
     fn apply_envelope<Gen, Sample>(
         &self,
         gen: Gen,
@@ -164,7 +331,8 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
             modified_params,
             allow_release,
             self.stream_params.sample_rate as f32,
-        );
+        )
+        .with_release_veltrack(self.release_veltrack);
 
         let amp = VoiceCombineSIMD::mult(volume_envelope, gen);
         amp
@@ -175,7 +343,11 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
         Gen: 'static + SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
     {
         let flattened = SIMDMonoVoice::new(gen);
-        let base = VoiceBase::new(self.vel, flattened);
+        let looping = matches!(
+            self.loop_params.mode,
+            LoopMode::LoopContinuous | LoopMode::LoopSustain
+        );
+        let base = VoiceBase::new(self.vel, flattened).with_looping(looping);
 
         Box::new(base)
     }
@@ -184,10 +356,16 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
     where
         Gen: 'static + SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
     {
-        let gen = self.apply_velocity(gen);
+        let gen = self.apply_velocity(gen, control);
         let gen = self.apply_envelope(gen, control);
 
-        self.apply_cutoff_effect(gen)
+        // Voices without a volume LFO skip it entirely
+        if let Some(lfo_volume) = self.create_lfo_volume() {
+            let gen = VoiceCombineSIMD::mult(lfo_volume, gen);
+            self.apply_cutoff_effect(gen)
+        } else {
+            self.apply_cutoff_effect(gen)
+        }
     }
 
     fn apply_cutoff_effect(
@@ -195,8 +373,13 @@ impl<S: Simd + Send + Sync> MonoSampledVoiceSpawner<S> {
         gen: impl 'static + SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
     ) -> Box<dyn Voice> {
         if let Some(filter) = &self.filter {
-            let gen = SIMDMonoVoiceCutoff::new(gen, filter);
-            self.convert_to_voice(gen)
+            if let Some(cutoff_gen) = self.create_cutoff_modulator() {
+                let gen = SIMDMonoVoiceModCutoff::new(gen, cutoff_gen, filter, self.filter_params);
+                self.convert_to_voice(gen)
+            } else {
+                let gen = SIMDMonoVoiceCutoff::new(gen, filter);
+                self.convert_to_voice(gen)
+            }
         } else {
             self.convert_to_voice(gen)
         }
@@ -207,4 +390,8 @@ impl<S: 'static + Sync + Send + Simd> VoiceSpawner for MonoSampledVoiceSpawner<S
     fn spawn_voice(&self, control: &VoiceControlData) -> Box<dyn Voice> {
         self.begin_voice(control)
     }
+
+    fn is_in_sequence(&self, counter: u32) -> bool {
+        self.sequence.contains(counter)
+    }
 }
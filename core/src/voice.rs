@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)] // For the SIMD library
 
+use crate::channel::PanLaw;
+
 mod envelopes;
 pub(crate) use envelopes::*;
 
@@ -33,8 +35,17 @@ pub(crate) use control::*;
 mod cutoff;
 pub(crate) use cutoff::*;
 
+mod lfo;
+pub(crate) use lfo::*;
+
+mod portamento;
+pub(crate) use portamento::*;
+
+mod pan;
+pub(crate) use pan::*;
+
 /// Options to modify the envelope of a voice.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct EnvelopeControlData {
     /// Controls the attack. Can take values from 0 to 128
     /// according to the MIDI CC spec.
@@ -45,14 +56,27 @@ pub struct EnvelopeControlData {
     pub release: Option<u8>,
 }
 
+/// The pitch glide of a newly spawned voice, used for portamento.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct PortamentoControlData {
+    /// Pitch multiplier at the start of the glide, relative to
+    /// the pitch of the voice.
+    pub start_multiplier: f32,
+
+    /// Duration of the glide in seconds.
+    pub time: f32,
+}
+
 /// How a voice should be released.
 #[derive(Copy, Clone, PartialEq)]
 pub enum ReleaseType {
-    /// Standard release. Uses the voice's envelope.
-    Standard,
+    /// Standard release. Uses the voice's envelope, with the release time
+    /// scaled by the note off velocity if the voice tracks it.
+    Standard { vel: u8 },
 
-    /// Kills the voice with a fadeout of 1ms.
-    Kill,
+    /// Kills the voice with a linear fadeout of the given time in seconds.
+    Kill { fade_time: f32 },
 }
 
 /// Options to control the parameters of a voice.
@@ -63,6 +87,25 @@ pub struct VoiceControlData {
 
     /// Envelope control
     pub envelope: EnvelopeControlData,
+
+    /// Pitch glide of the voices being spawned
+    pub portamento: Option<PortamentoControlData>,
+
+    /// Channel pan, where 0.0 is left, 0.5 is center and 1.0 is right.
+    /// It is added to the pan of each voice.
+    pub pan: f32,
+
+    /// Pan law used for the combined pan of the voices
+    pub pan_law: PanLaw,
+
+    /// Random value of the voices being spawned, drawn by the key for each
+    /// note. Used by the random SFZ opcodes.
+    pub random: u64,
+
+    /// Velocity of the voices being spawned in the 0.0-127.0 range, when the
+    /// note was started with a high resolution velocity that isn't exactly
+    /// a 7-bit one. Used by the velocity amplitude curve.
+    pub velocity: Option<f32>,
 }
 
 impl VoiceControlData {
@@ -73,6 +116,11 @@ impl VoiceControlData {
                 attack: None,
                 release: None,
             },
+            portamento: None,
+            pan: 0.5,
+            pan_law: PanLaw::default(),
+            random: 0,
+            velocity: None,
         }
     }
 }
@@ -91,5 +139,9 @@ pub trait Voice: VoiceSampleGenerator + Send + Sync {
     fn is_releasing(&self) -> bool;
     fn is_killed(&self) -> bool;
 
+    /// Returns whether the voice loops until it is released, so that it
+    /// never ends by itself.
+    fn is_looping(&self) -> bool;
+
     fn velocity(&self) -> u8;
 }
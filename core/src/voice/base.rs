@@ -7,6 +7,7 @@ pub struct VoiceBase<T: Send + Sync + VoiceSampleGenerator> {
     sample_generator: T,
     releasing: bool,
     killed: bool,
+    looping: bool,
     velocity: u8,
 }
 
@@ -16,9 +17,16 @@ impl<T: Send + Sync + VoiceSampleGenerator> VoiceBase<T> {
             sample_generator,
             releasing: false,
             killed: false,
+            looping: false,
             velocity,
         }
     }
+
+    /// Marks the voice as looping until it is released.
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
 }
 
 impl<T> VoiceGeneratorBase for VoiceBase<T>
@@ -33,8 +41,8 @@ where
     #[inline(always)]
     fn signal_release(&mut self, rel_type: ReleaseType) {
         match rel_type {
-            ReleaseType::Standard => self.releasing = true,
-            ReleaseType::Kill => self.killed = true,
+            ReleaseType::Standard { .. } => self.releasing = true,
+            ReleaseType::Kill { .. } => self.killed = true,
         }
         self.sample_generator.signal_release(rel_type)
     }
@@ -69,6 +77,11 @@ where
         self.killed
     }
 
+    #[inline(always)]
+    fn is_looping(&self) -> bool {
+        self.looping
+    }
+
     #[inline(always)]
     fn velocity(&self) -> u8 {
         self.velocity
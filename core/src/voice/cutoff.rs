@@ -3,11 +3,14 @@ use std::marker::PhantomData;
 use simdeez::prelude::*;
 
 use crate::{
-    effects::BiQuadFilter,
+    effects::{BiQuadFilter, FilterType},
     voice::{ReleaseType, SIMDVoiceGenerator, VoiceControlData},
 };
 
-use super::{SIMDSampleMono, SIMDSampleStereo, VoiceGeneratorBase};
+use super::{
+    EnvelopeParameters, LfoParameters, SIMDModLfo, SIMDSampleMono, SIMDSampleStereo,
+    SIMDVoiceEnvelope, VoiceGeneratorBase,
+};
 
 pub struct SIMDMonoVoiceCutoff<S, V>
 where
@@ -16,6 +19,7 @@ where
 {
     v: V,
     cutoff: BiQuadFilter,
+    at_rest: bool,
     _s: PhantomData<S>,
 }
 
@@ -28,6 +32,7 @@ where
         SIMDMonoVoiceCutoff {
             v,
             cutoff: filter.clone(),
+            at_rest: true,
             _s: PhantomData,
         }
     }
@@ -62,11 +67,34 @@ where
     #[inline(always)]
     fn next_sample(&mut self) -> SIMDSampleMono<S> {
         simd_invoke!(S, {
+            self.at_rest = false;
             let mut next_sample = self.v.next_sample();
             next_sample.0 = self.cutoff.process_simd::<S>(next_sample.0);
             next_sample
         })
     }
+
+    // A filter which hasn't processed any samples yet outputs silence for
+    // silent input, so silence can be skipped until the first real sample.
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        if self.at_rest {
+            self.v.silent_arrays()
+        } else {
+            0
+        }
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        if self.at_rest && count <= self.v.silent_arrays() {
+            self.v.skip_arrays(count);
+        } else {
+            for _ in 0..count {
+                self.next_sample();
+            }
+        }
+    }
 }
 
 pub struct SIMDStereoVoiceCutoff<S, V>
@@ -77,6 +105,7 @@ where
     v: V,
     cutoff1: BiQuadFilter,
     cutoff2: BiQuadFilter,
+    at_rest: bool,
     _s: PhantomData<S>,
 }
 
@@ -90,6 +119,7 @@ where
             v,
             cutoff1: filter.clone(),
             cutoff2: filter.clone(),
+            at_rest: true,
             _s: PhantomData,
         }
     }
@@ -124,10 +154,330 @@ where
     #[inline(always)]
     fn next_sample(&mut self) -> SIMDSampleStereo<S> {
         simd_invoke!(S, {
+            self.at_rest = false;
             let mut next_sample = self.v.next_sample();
             next_sample.0 = self.cutoff1.process_simd::<S>(next_sample.0);
             next_sample.1 = self.cutoff2.process_simd::<S>(next_sample.1);
             next_sample
         })
     }
+
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        if self.at_rest {
+            self.v.silent_arrays()
+        } else {
+            0
+        }
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        if self.at_rest && count <= self.v.silent_arrays() {
+            self.v.skip_arrays(count);
+        } else {
+            for _ in 0..count {
+                self.next_sample();
+            }
+        }
+    }
+}
+
+/// The parameters of a voice filter with a time-varying cutoff.
+#[derive(Copy, Clone)]
+pub struct ModulatedFilterParams {
+    pub fil_type: FilterType,
+    pub q: Option<f32>,
+    pub sample_rate: f32,
+}
+
+impl ModulatedFilterParams {
+    fn update_filter(&self, filter: &mut BiQuadFilter, freq: f32) {
+        filter.set_coefficients(BiQuadFilter::get_coeffs(
+            self.fil_type,
+            freq,
+            self.sample_rate,
+            self.q,
+        ));
+    }
+}
+
+/// Generates the cutoff frequency of a voice filter, modulated by an
+/// envelope and an LFO. The depths are in cents.
+pub struct SIMDCutoffModulator<S: Simd> {
+    base: f32,
+    max: f32,
+    envelope: Option<(SIMDVoiceEnvelope<S>, f32)>,
+    lfo: Option<(SIMDModLfo<S>, f32)>,
+}
+
+impl<S: Simd> SIMDCutoffModulator<S> {
+    pub fn new(
+        base: f32,
+        envelope: Option<(EnvelopeParameters, f32)>,
+        lfo: Option<(LfoParameters, f32)>,
+        allow_release: bool,
+        sample_rate: f32,
+    ) -> Self {
+        Self {
+            base,
+            max: sample_rate / 2.0 - 100.0,
+            envelope: envelope.map(|(params, depth)| {
+                let envelope = SIMDVoiceEnvelope::new(params, params, allow_release, sample_rate);
+                (envelope, depth)
+            }),
+            lfo: lfo.map(|(params, depth)| (SIMDModLfo::new(params), depth)),
+        }
+    }
+}
+
+impl<S: Simd> VoiceGeneratorBase for SIMDCutoffModulator<S> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, rel_type: ReleaseType) {
+        if matches!(rel_type, ReleaseType::Standard { .. }) {
+            if let Some((envelope, _)) = &mut self.envelope {
+                envelope.signal_release(rel_type);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn process_controls(&mut self, _control: &VoiceControlData) {}
+}
+
+impl<S: Simd> SIMDVoiceGenerator<S, SIMDSampleMono<S>> for SIMDCutoffModulator<S> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<S> {
+        simd_invoke!(S, {
+            let mut cents = S::Vf32::zeroes();
+            if let Some((envelope, depth)) = &mut self.envelope {
+                cents += envelope.next_sample().0 * S::Vf32::set1(*depth);
+            }
+            if let Some((lfo, depth)) = &mut self.lfo {
+                cents += lfo.next_sample().0 * S::Vf32::set1(*depth);
+            }
+
+            let mut values = cents;
+            for i in 0..S::Vf32::WIDTH {
+                values[i] = (self.base * 2.0f32.powf(cents[i] / 1200.0)).clamp(10.0, self.max);
+            }
+            SIMDSampleMono(values)
+        })
+    }
+}
+
+/// A voice filter whose cutoff frequency is given by a generator. The
+/// coefficients are updated once per SIMD array, and only if the cutoff
+/// has changed.
+pub struct SIMDMonoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    v: V,
+    cutoff_gen: C,
+    params: ModulatedFilterParams,
+    cutoff: BiQuadFilter,
+    last_freq: f32,
+    at_rest: bool,
+    _s: PhantomData<S>,
+}
+
+impl<S, V, C> SIMDMonoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    pub fn new(v: V, cutoff_gen: C, filter: &BiQuadFilter, params: ModulatedFilterParams) -> Self {
+        SIMDMonoVoiceModCutoff {
+            v,
+            cutoff_gen,
+            params,
+            cutoff: filter.clone(),
+            last_freq: f32::NAN,
+            at_rest: true,
+            _s: PhantomData,
+        }
+    }
+}
+
+impl<S, V, C> VoiceGeneratorBase for SIMDMonoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        self.v.ended()
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, rel_type: ReleaseType) {
+        self.v.signal_release(rel_type);
+        self.cutoff_gen.signal_release(rel_type);
+    }
+
+    #[inline(always)]
+    fn process_controls(&mut self, control: &VoiceControlData) {
+        self.v.process_controls(control);
+        self.cutoff_gen.process_controls(control);
+    }
+}
+
+impl<S, V, C> SIMDVoiceGenerator<S, SIMDSampleMono<S>> for SIMDMonoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<S> {
+        simd_invoke!(S, {
+            self.at_rest = false;
+            let freq = self.cutoff_gen.next_sample().0[0];
+            if freq != self.last_freq {
+                self.params.update_filter(&mut self.cutoff, freq);
+                self.last_freq = freq;
+            }
+
+            let mut next_sample = self.v.next_sample();
+            next_sample.0 = self.cutoff.process_simd::<S>(next_sample.0);
+            next_sample
+        })
+    }
+
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        if self.at_rest {
+            self.v.silent_arrays()
+        } else {
+            0
+        }
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        if self.at_rest && count <= self.v.silent_arrays() {
+            self.v.skip_arrays(count);
+            self.cutoff_gen.skip_arrays(count);
+        } else {
+            for _ in 0..count {
+                self.next_sample();
+            }
+        }
+    }
+}
+
+pub struct SIMDStereoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleStereo<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    v: V,
+    cutoff_gen: C,
+    params: ModulatedFilterParams,
+    cutoff1: BiQuadFilter,
+    cutoff2: BiQuadFilter,
+    last_freq: f32,
+    at_rest: bool,
+    _s: PhantomData<S>,
+}
+
+impl<S, V, C> SIMDStereoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleStereo<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    pub fn new(v: V, cutoff_gen: C, filter: &BiQuadFilter, params: ModulatedFilterParams) -> Self {
+        SIMDStereoVoiceModCutoff {
+            v,
+            cutoff_gen,
+            params,
+            cutoff1: filter.clone(),
+            cutoff2: filter.clone(),
+            last_freq: f32::NAN,
+            at_rest: true,
+            _s: PhantomData,
+        }
+    }
+}
+
+impl<S, V, C> VoiceGeneratorBase for SIMDStereoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleStereo<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        self.v.ended()
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, rel_type: ReleaseType) {
+        self.v.signal_release(rel_type);
+        self.cutoff_gen.signal_release(rel_type);
+    }
+
+    #[inline(always)]
+    fn process_controls(&mut self, control: &VoiceControlData) {
+        self.v.process_controls(control);
+        self.cutoff_gen.process_controls(control);
+    }
+}
+
+impl<S, V, C> SIMDVoiceGenerator<S, SIMDSampleStereo<S>> for SIMDStereoVoiceModCutoff<S, V, C>
+where
+    S: Simd,
+    V: SIMDVoiceGenerator<S, SIMDSampleStereo<S>>,
+    C: SIMDVoiceGenerator<S, SIMDSampleMono<S>>,
+{
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleStereo<S> {
+        simd_invoke!(S, {
+            self.at_rest = false;
+            let freq = self.cutoff_gen.next_sample().0[0];
+            if freq != self.last_freq {
+                self.params.update_filter(&mut self.cutoff1, freq);
+                self.params.update_filter(&mut self.cutoff2, freq);
+                self.last_freq = freq;
+            }
+
+            let mut next_sample = self.v.next_sample();
+            next_sample.0 = self.cutoff1.process_simd::<S>(next_sample.0);
+            next_sample.1 = self.cutoff2.process_simd::<S>(next_sample.1);
+            next_sample
+        })
+    }
+
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        if self.at_rest {
+            self.v.silent_arrays()
+        } else {
+            0
+        }
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        if self.at_rest && count <= self.v.silent_arrays() {
+            self.v.skip_arrays(count);
+            self.cutoff_gen.skip_arrays(count);
+        } else {
+            for _ in 0..count {
+                self.next_sample();
+            }
+        }
+    }
 }
@@ -135,6 +135,47 @@ impl<T: Simd> SIMDLerperConvex<T> {
     }
 }
 
+// The exponential curve equation is
+// `start + (end - start) * (1 - e^(-curvature * factor)) / (1 - e^(-curvature))`
+// We store: start, length (= (end - start) / (1 - e^(-curvature))), curvature
+struct SIMDLerperExp<T: Simd> {
+    start_simd: T::Vf32,
+    length_simd: T::Vf32,
+    start: f32,
+    length: f32,
+    curvature: f32,
+}
+
+impl<T: Simd> SIMDLerperExp<T> {
+    fn new(start: f32, end: f32, curvature: f32) -> Self {
+        let length = (end - start) / (1.0 - (-curvature).exp());
+        simd_invoke!(T, {
+            SIMDLerperExp {
+                start_simd: T::Vf32::set1(start),
+                length_simd: T::Vf32::set1(length),
+                start,
+                length,
+                curvature,
+            }
+        })
+    }
+
+    fn lerp(&self, factor: f32) -> f32 {
+        let mult = 1.0 - (-self.curvature * factor).exp();
+        self.length * mult + self.start
+    }
+
+    fn lerp_simd(&self, factor: T::Vf32) -> T::Vf32 {
+        simd_invoke!(T, {
+            let mut mult = T::Vf32::set1(0.0);
+            for i in 0..T::Vf32::WIDTH {
+                mult[i] = 1.0 - (-self.curvature * factor[i]).exp();
+            }
+            self.length_simd * mult + self.start_simd
+        })
+    }
+}
+
 struct StageTime<T: Simd> {
     stage_time_simd: T::Vf32,
     stage_end_time_f32: f32,
@@ -228,6 +269,11 @@ pub enum EnvelopePart {
         target: f32,
         duration: u32,
     },
+    ExpCurve {
+        target: f32,
+        duration: u32,
+        curvature: f32, // Time constants in the duration
+    },
     Hold(f32),
 }
 
@@ -244,11 +290,22 @@ impl EnvelopePart {
         EnvelopePart::LerpConvex { target, duration }
     }
 
+    pub fn exp_curve(target: f32, duration: u32, curvature: f32) -> EnvelopePart {
+        EnvelopePart::ExpCurve {
+            target,
+            duration,
+            curvature,
+        }
+    }
+
     pub fn hold(value: f32) -> EnvelopePart {
         EnvelopePart::Hold(value)
     }
 }
 
+/// Time constants spanned by the stages using the analog curve.
+const ANALOG_CURVE_TIME_CONSTANTS: f32 = 5.0;
+
 /// The original envelope descriptor
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct EnvelopeDescriptor {
@@ -281,6 +338,11 @@ impl EnvelopeDescriptor {
             EnvelopeCurveType::Exponential => {
                 EnvelopePart::lerp(1.0, (self.attack * samplerate) as u32)
             }
+            EnvelopeCurveType::Analog => EnvelopePart::exp_curve(
+                1.0,
+                (self.attack * samplerate) as u32,
+                ANALOG_CURVE_TIME_CONSTANTS,
+            ),
         };
 
         let decay = match options.decay_curve {
@@ -290,6 +352,11 @@ impl EnvelopeDescriptor {
             EnvelopeCurveType::Linear => {
                 EnvelopePart::lerp_concave(self.sustain_percent, (self.decay * samplerate) as u32)
             }
+            EnvelopeCurveType::Analog => EnvelopePart::exp_curve(
+                self.sustain_percent,
+                (self.decay * samplerate) as u32,
+                ANALOG_CURVE_TIME_CONSTANTS,
+            ),
         };
 
         let release = match options.release_curve {
@@ -299,6 +366,11 @@ impl EnvelopeDescriptor {
             EnvelopeCurveType::Linear => {
                 EnvelopePart::lerp_concave(0.0, (self.release * samplerate) as u32)
             }
+            EnvelopeCurveType::Analog => EnvelopePart::exp_curve(
+                0.0,
+                (self.release * samplerate) as u32,
+                ANALOG_CURVE_TIME_CONSTANTS,
+            ),
         };
 
         EnvelopeParameters {
@@ -389,6 +461,26 @@ impl EnvelopeParameters {
                         }
                     }
                 }
+                EnvelopePart::ExpCurve {
+                    target,
+                    duration,
+                    curvature,
+                } => {
+                    let duration = *duration;
+                    let target = *target;
+                    if duration == 0 {
+                        self.get_stage_data(stage.next_stage(), target)
+                    } else {
+                        let data = StageData::ExpCurve(
+                            SIMDLerperExp::new(start_amp, target, *curvature),
+                            StageTime::new(0, duration),
+                        );
+                        VoiceEnvelopeState {
+                            current_stage: stage,
+                            stage_data: data,
+                        }
+                    }
+                }
                 EnvelopePart::Hold(value) => {
                     let data = StageData::Constant(T::Vf32::set1(*value));
                     VoiceEnvelopeState {
@@ -415,6 +507,7 @@ impl EnvelopeParameters {
                 target: _,
                 duration,
             } => *duration,
+            EnvelopePart::ExpCurve { duration, .. } => *duration,
             EnvelopePart::Hold(_) => 0,
         }
     }
@@ -428,6 +521,7 @@ enum StageData<T: Simd> {
     Lerp(SIMDLerper<T>, StageTime<T>),
     LerpConcave(SIMDLerperConcave<T>, StageTime<T>),
     LerpConvex(SIMDLerperConvex<T>, StageTime<T>),
+    ExpCurve(SIMDLerperExp<T>, StageTime<T>),
     Constant(T::Vf32),
 }
 
@@ -443,6 +537,10 @@ pub struct SIMDVoiceEnvelope<T: Simd> {
     state: VoiceEnvelopeState<T>,
     sample_rate: f32,
     killed: bool,
+    /// The percentage set with `with_release_veltrack`
+    release_veltrack: f32,
+    /// The multiplier of the release time given by the note off velocity
+    release_scale: f32,
 }
 
 impl<T: Simd> SIMDVoiceEnvelope<T> {
@@ -461,9 +559,20 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
             state,
             sample_rate,
             killed: false,
+            release_veltrack: 0.0,
+            release_scale: 1.0,
         }
     }
 
+    /// Makes the release time depend on the note off velocity, as with the
+    /// `ampeg_offveltrack` SFZ opcode. At 100%, velocity 127 halves the
+    /// release time and velocity 1 doubles it, along an exponential
+    /// curve which keeps it unchanged at velocity 64.
+    pub fn with_release_veltrack(mut self, veltrack: f32) -> Self {
+        self.release_veltrack = veltrack;
+        self
+    }
+
     pub fn get_value_at_current_time(&self) -> f32 {
         match &self.state.stage_data {
             StageData::Lerp(lerper, stage_time) => {
@@ -475,6 +584,9 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
             StageData::LerpConvex(lerper, stage_time) => {
                 lerper.lerp(stage_time.simd_array_start_f32() / stage_time.stage_end_time_f32)
             }
+            StageData::ExpCurve(lerper, stage_time) => {
+                lerper.lerp(stage_time.simd_array_start_f32() / stage_time.stage_end_time_f32)
+            }
             StageData::Constant(constant) => constant[0],
         }
     }
@@ -506,6 +618,9 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
             StageData::LerpConvex(_, stage_time) => {
                 stage_time.increment_by(increment);
             }
+            StageData::ExpCurve(_, stage_time) => {
+                stage_time.increment_by(increment);
+            }
             StageData::Constant(_) => {}
         }
     }
@@ -520,7 +635,8 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
                 let should_progress = match &mut self.state.stage_data {
                     StageData::Lerp(_, stage_time)
                     | StageData::LerpConcave(_, stage_time)
-                    | StageData::LerpConvex(_, stage_time) => {
+                    | StageData::LerpConvex(_, stage_time)
+                    | StageData::ExpCurve(_, stage_time) => {
                         stage_time.is_ending() && !stage_time.is_intersecting_end()
                     }
                     StageData::Constant(_) => false,
@@ -561,6 +677,10 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
                     target,
                     duration: _,
                 } => params.modify_stage_data(part, EnvelopePart::lerp_convex(target, duration)),
+                EnvelopePart::ExpCurve {
+                    target, curvature, ..
+                } => params
+                    .modify_stage_data(part, EnvelopePart::exp_curve(target, duration, curvature)),
                 _ => {}
             }
         }
@@ -579,6 +699,10 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
                     target,
                     duration: _,
                 } => params.modify_stage_data(part, EnvelopePart::lerp_concave(target, duration)),
+                EnvelopePart::ExpCurve {
+                    target, curvature, ..
+                } => params
+                    .modify_stage_data(part, EnvelopePart::exp_curve(target, duration, curvature)),
                 _ => {}
             }
         }
@@ -590,9 +714,36 @@ impl<T: Simd> SIMDVoiceEnvelope<T> {
         if !self.killed {
             self.params =
                 Self::get_modified_envelope(self.original_params, envelope, self.sample_rate);
+            Self::scale_release(&mut self.params, self.release_scale);
             self.update_stage();
         }
     }
+
+    fn scale_release(params: &mut EnvelopeParameters, scale: f32) {
+        if scale == 1.0 {
+            return;
+        }
+
+        let part = EnvelopeStage::Release.as_usize();
+        let scaled = |duration: u32| (duration as f32 * scale) as u32;
+        match params.parts[part] {
+            EnvelopePart::Lerp { target, duration } => {
+                params.modify_stage_data(part, EnvelopePart::lerp(target, scaled(duration)))
+            }
+            EnvelopePart::LerpConcave { target, duration } => {
+                params.modify_stage_data(part, EnvelopePart::lerp_concave(target, scaled(duration)))
+            }
+            EnvelopePart::ExpCurve {
+                target,
+                duration,
+                curvature,
+            } => params.modify_stage_data(
+                part,
+                EnvelopePart::exp_curve(target, scaled(duration), curvature),
+            ),
+            _ => {}
+        }
+    }
 }
 
 impl<T: Simd> VoiceGeneratorBase for SIMDVoiceEnvelope<T> {
@@ -603,13 +754,26 @@ impl<T: Simd> VoiceGeneratorBase for SIMDVoiceEnvelope<T> {
 
     #[inline(always)]
     fn signal_release(&mut self, rel_type: ReleaseType) {
-        if rel_type == ReleaseType::Kill {
-            self.params.modify_stage_data(
-                5,
-                EnvelopePart::lerp(0.0, (0.001 * self.sample_rate) as u32),
-            );
-            self.update_stage();
-            self.killed = true;
+        match rel_type {
+            ReleaseType::Standard { vel } => {
+                let released = matches!(
+                    self.current_stage(),
+                    EnvelopeStage::Release | EnvelopeStage::Finished
+                );
+                if self.release_veltrack != 0.0 && !released && !self.killed {
+                    let octaves = self.release_veltrack / 100.0 * (64.0 - vel as f32) / 63.0;
+                    self.release_scale = 2.0f32.powf(octaves);
+                    Self::scale_release(&mut self.params, self.release_scale);
+                }
+            }
+            ReleaseType::Kill { fade_time } => {
+                self.params.modify_stage_data(
+                    5,
+                    EnvelopePart::lerp(0.0, (fade_time * self.sample_rate) as u32),
+                );
+                self.update_stage();
+                self.killed = true;
+            }
         }
         if self.allow_release || self.killed {
             let amp = self.get_value_at_current_time();
@@ -675,10 +839,103 @@ impl<T: Simd> SIMDVoiceGenerator<T, SIMDSampleMono<T>> for SIMDVoiceEnvelope<T>
                         SIMDSampleMono(values)
                     }
                 }
+                StageData::ExpCurve(lerper, stage_time) => {
+                    if stage_time.is_ending() {
+                        if stage_time.is_intersecting_end() {
+                            self.manually_build_simd_sample()
+                        } else {
+                            self.switch_to_next_stage();
+                            self.next_sample()
+                        }
+                    } else {
+                        let values = lerper.lerp_simd(stage_time.progress_simd_array());
+                        stage_time.increment();
+                        SIMDSampleMono(values)
+                    }
+                }
                 StageData::Constant(constant) => SIMDSampleMono(*constant),
             }
         })
     }
+
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        match &self.state.stage_data {
+            StageData::Lerp(lerper, stage_time)
+                if self.state.current_stage == EnvelopeStage::Delay
+                    && lerper.start == 0.0
+                    && lerper.length == 0.0 =>
+            {
+                // Only the arrays which are fully inside the delay stage
+                let remaining = stage_time.stage_end_time_f32 - stage_time.simd_array_start_f32();
+                (remaining.max(0.0) as usize) / T::Vf32::WIDTH
+            }
+            _ => 0,
+        }
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        let silent = self.silent_arrays().min(count);
+        self.increment_time_by((silent * T::Vf32::WIDTH) as u32);
+        for _ in silent..count {
+            self.next_sample();
+        }
+    }
+}
+
+/// A pitch envelope, which converts the output of a volume-style envelope
+/// (0 to 1) to a pitch multiplier, where 1 equals the given depth in cents.
+///
+/// The envelope only follows the standard release of the voice, while the
+/// end of the voice is left to the volume envelope.
+pub struct SIMDPitchEnvelope<T: Simd> {
+    envelope: SIMDVoiceEnvelope<T>,
+    depth: f32,
+}
+
+impl<T: Simd> SIMDPitchEnvelope<T> {
+    pub fn new(
+        params: EnvelopeParameters,
+        depth: f32,
+        allow_release: bool,
+        sample_rate: f32,
+    ) -> Self {
+        SIMDPitchEnvelope {
+            envelope: SIMDVoiceEnvelope::new(params, params, allow_release, sample_rate),
+            depth,
+        }
+    }
+}
+
+impl<T: Simd> VoiceGeneratorBase for SIMDPitchEnvelope<T> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, rel_type: ReleaseType) {
+        if matches!(rel_type, ReleaseType::Standard { .. }) {
+            self.envelope.signal_release(rel_type);
+        }
+    }
+
+    #[inline(always)]
+    fn process_controls(&mut self, _control: &VoiceControlData) {}
+}
+
+impl<T: Simd> SIMDVoiceGenerator<T, SIMDSampleMono<T>> for SIMDPitchEnvelope<T> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<T> {
+        simd_invoke!(T, {
+            let mut values = self.envelope.next_sample().0;
+            for i in 0..T::Vf32::WIDTH {
+                values[i] = 2.0f32.powf(values[i] * self.depth / 1200.0);
+            }
+            SIMDSampleMono(values)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -720,6 +977,51 @@ mod tests {
         run();
     }
 
+    #[test]
+    fn test_analog_attack() {
+        simd_runtime_generate!(
+            fn run() {
+                let descriptor = EnvelopeDescriptor {
+                    start_percent: 0.0,
+                    delay: 0.0,
+                    attack: 1.0,
+                    hold: 0.0,
+                    decay: 0.0,
+                    sustain_percent: 1.0,
+                    release: 1.0,
+                };
+                let render = |attack_curve| {
+                    let options = EnvelopeOptions {
+                        attack_curve,
+                        ..Default::default()
+                    };
+                    let params = descriptor.to_envelope_params(1000, options);
+                    let mut env = SIMDVoiceEnvelope::<S>::new(params, params, true, 1000.0);
+                    let mut vec = Vec::new();
+                    while vec.len() < 1100 {
+                        let values = env.next_sample().0;
+                        for i in 0..S::Vf32::WIDTH {
+                            vec.push(values[i]);
+                        }
+                    }
+                    vec
+                };
+
+                let analog = render(EnvelopeCurveType::Analog);
+                let linear = render(EnvelopeCurveType::Exponential);
+
+                // One time constant is a fifth of the attack
+                let expected = (1.0 - (-1.0f32).exp()) / (1.0 - (-5.0f32).exp());
+                assert!((analog[200] - expected).abs() < 1e-3);
+                assert!((linear[200] - 0.2).abs() < 1e-3);
+                assert!((analog[999] - 1.0).abs() < 1e-2);
+                assert_eq!(analog[1050], 1.0);
+            }
+        );
+
+        run();
+    }
+
     #[test]
     fn test_stage_time() {
         fn simd_from_range<S: Simd>(range: std::ops::Range<usize>) -> S::Vf32 {
@@ -809,7 +1111,11 @@ mod tests {
                     sustain_percent: 0.4,
                     release: 16.0,
                 };
-                let params = descriptor.to_envelope_params(1, Default::default());
+                let options = EnvelopeOptions {
+                    decay_curve: EnvelopeCurveType::Exponential,
+                    ..Default::default()
+                };
+                let params = descriptor.to_envelope_params(1, options);
 
                 let mut env = SIMDVoiceEnvelope::<S>::new(params, params, true, 1.0);
 
@@ -818,7 +1124,7 @@ mod tests {
                     push_simd_to_vec::<S>(&mut vec, env.next_sample().0);
                     i += S::Vf32::WIDTH;
                 }
-                env.signal_release(ReleaseType::Standard);
+                env.signal_release(ReleaseType::Standard { vel: 64 });
                 assert_eq!(env.current_stage(), &EnvelopeStage::Release);
                 while i < 48 + 32 {
                     push_simd_to_vec::<S>(&mut vec, env.next_sample().0);
@@ -854,4 +1160,167 @@ mod tests {
 
         run();
     }
+
+    #[test]
+    fn test_kill_fade_time() {
+        simd_runtime_generate!(
+            fn run() {
+                let descriptor = EnvelopeDescriptor {
+                    start_percent: 1.0,
+                    delay: 0.0,
+                    attack: 0.0,
+                    hold: 0.0,
+                    decay: 0.0,
+                    sustain_percent: 1.0,
+                    release: 1.0,
+                };
+                let params = descriptor.to_envelope_params(48000, Default::default());
+
+                for fade_time in [0.001, 0.01] {
+                    let mut env = SIMDVoiceEnvelope::<S>::new(params, params, true, 48000.0);
+                    for _ in 0..16 {
+                        env.next_sample();
+                    }
+
+                    env.signal_release(ReleaseType::Kill { fade_time });
+                    let mut samples = 0;
+                    while !env.ended() {
+                        env.next_sample();
+                        samples += S::Vf32::WIDTH;
+                        assert!(samples < 48000);
+                    }
+
+                    let expected = (fade_time * 48000.0) as usize;
+                    assert!(samples >= expected && samples <= expected + S::Vf32::WIDTH);
+                }
+            }
+        );
+
+        run();
+    }
+
+    #[test]
+    fn test_pitch_envelope() {
+        simd_runtime_generate!(
+            fn run() {
+                let descriptor = EnvelopeDescriptor {
+                    start_percent: 1.0,
+                    delay: 0.0,
+                    attack: 0.0,
+                    hold: 0.0,
+                    decay: 16.0,
+                    sustain_percent: 0.0,
+                    release: 8.0,
+                };
+                let options = EnvelopeOptions {
+                    attack_curve: EnvelopeCurveType::Exponential,
+                    decay_curve: EnvelopeCurveType::Exponential,
+                    release_curve: EnvelopeCurveType::Exponential,
+                };
+                let params = descriptor.to_envelope_params(1, options);
+
+                let mut env = SIMDPitchEnvelope::<S>::new(params, -1200.0, true, 1.0);
+
+                let mut vec = Vec::new();
+                while vec.len() < 32 {
+                    let sample = env.next_sample().0;
+                    for i in 0..S::Vf32::WIDTH {
+                        vec.push(sample[i]);
+                    }
+                }
+
+                // Starts an octave down and glides linearly in cents to the original pitch
+                for (i, v) in vec.iter().enumerate() {
+                    let cents = -1200.0 * (1.0 - (i as f32 / 16.0).min(1.0));
+                    assert!((v - 2.0f32.powf(cents / 1200.0)).abs() < 1e-4);
+                }
+
+                env.signal_release(ReleaseType::Kill { fade_time: 0.001 });
+                assert_eq!(env.next_sample().0[0], 1.0);
+                assert!(!env.ended());
+            }
+        );
+
+        run();
+    }
+
+    #[test]
+    fn test_silent_delay() {
+        simd_runtime_generate!(
+            fn run() {
+                let width = S::Vf32::WIDTH;
+                let descriptor = EnvelopeDescriptor {
+                    start_percent: 0.0,
+                    delay: (width * 10 + 1) as f32,
+                    attack: 4.0,
+                    hold: 0.0,
+                    decay: 0.0,
+                    sustain_percent: 1.0,
+                    release: 4.0,
+                };
+                let params = descriptor.to_envelope_params(1, Default::default());
+
+                let mut env = SIMDVoiceEnvelope::<S>::new(params, params, true, 1.0);
+                assert_eq!(env.silent_arrays(), 10);
+                env.skip_arrays(4);
+                assert_eq!(env.silent_arrays(), 6);
+                env.skip_arrays(6);
+                assert_eq!(env.silent_arrays(), 0);
+                assert_eq!(env.current_stage(), &EnvelopeStage::Delay);
+
+                // The array intersecting the end of the delay is generated normally
+                let sample = env.next_sample().0;
+                assert_eq!(sample[0], 0.0);
+                assert_eq!(env.silent_arrays(), 0);
+
+                // Envelopes that don't start silent are never skipped
+                let descriptor = EnvelopeDescriptor {
+                    start_percent: 0.5,
+                    ..descriptor
+                };
+                let params = descriptor.to_envelope_params(1, Default::default());
+                let env = SIMDVoiceEnvelope::<S>::new(params, params, true, 1.0);
+                assert_eq!(env.silent_arrays(), 0);
+            }
+        );
+
+        run();
+    }
+
+    #[test]
+    fn test_delay_skip_matches_full_render() {
+        use crate::{
+            soundfont::{test_helpers::load_test_sfz_with_sample, SoundfontBase},
+            voice::VoiceControlData,
+            ChannelCount,
+        };
+
+        // Rendering one sample at a time never skips the delay, so it can be
+        // compared with a single render where the delay ends mid-buffer.
+        let ramp: Vec<f32> = (0..4096).map(|i| (i as f32 / 37.0).sin()).collect();
+        let opcodes = "ampeg_delay=0.0101 ampeg_attack=0.001 cutoff=2000 \
+            loop_mode=loop_sustain loop_start=100 loop_end=300 \
+            pitcheg_attack=0.005 pitcheg_depth=700";
+        let control = VoiceControlData::new_defaults();
+
+        for channels in [ChannelCount::Mono, ChannelCount::Stereo] {
+            let len = 2048 * channels.count() as usize;
+            let sf = load_test_sfz_with_sample("delay_skip", &ramp, opcodes, channels);
+            let spawner = &sf.get_attack_voice_spawners_at(0, 0, 64, 100)[0];
+
+            let mut skipped = vec![0.0; len];
+            let mut voice = spawner.spawn_voice(&control);
+            voice.render_to(&mut skipped);
+
+            let mut full = vec![0.0; len];
+            let mut voice = spawner.spawn_voice(&control);
+            for chunk in full.chunks_mut(channels.count() as usize) {
+                voice.render_to(chunk);
+            }
+
+            assert!(skipped[..480].iter().all(|&s| s == 0.0));
+            assert!(skipped.iter().any(|&s| s != 0.0));
+            assert_eq!(skipped, full);
+        }
+    }
 }
@@ -0,0 +1,176 @@
+use std::marker::PhantomData;
+
+use simdeez::prelude::*;
+
+use crate::voice::{ReleaseType, VoiceControlData};
+
+use super::{SIMDSampleMono, SIMDVoiceGenerator, VoiceGeneratorBase};
+
+/// Parameters of a voice's modulation LFO.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LfoParameters {
+    /// Delay before the LFO starts, in samples
+    pub delay: u32,
+
+    /// Phase increment per sample
+    pub step: f32,
+}
+
+impl LfoParameters {
+    pub fn new(delay: f32, freq: f32, sample_rate: f32) -> Self {
+        Self {
+            delay: (delay * sample_rate) as u32,
+            step: freq / sample_rate,
+        }
+    }
+}
+
+/// A triangle wave LFO as described by the SF2 spec. Outputs 0 during
+/// the delay, then starts at 0 rising towards 1.
+pub struct SIMDModLfo<S: Simd> {
+    params: LfoParameters,
+    time: u32,
+    phase: f32,
+    _s: PhantomData<S>,
+}
+
+impl<S: Simd> SIMDModLfo<S> {
+    pub fn new(params: LfoParameters) -> Self {
+        Self {
+            params,
+            time: 0,
+            phase: 0.0,
+            _s: PhantomData,
+        }
+    }
+
+    fn next_value(&mut self) -> f32 {
+        if self.time < self.params.delay {
+            self.time += 1;
+            return 0.0;
+        }
+
+        let phase = self.phase;
+        self.phase = (self.phase + self.params.step).fract();
+
+        if phase < 0.25 {
+            phase * 4.0
+        } else if phase < 0.75 {
+            2.0 - phase * 4.0
+        } else {
+            phase * 4.0 - 4.0
+        }
+    }
+}
+
+impl<S: Simd> VoiceGeneratorBase for SIMDModLfo<S> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, _rel_type: ReleaseType) {}
+
+    #[inline(always)]
+    fn process_controls(&mut self, _control: &VoiceControlData) {}
+}
+
+impl<S: Simd> SIMDVoiceGenerator<S, SIMDSampleMono<S>> for SIMDModLfo<S> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<S> {
+        simd_invoke!(S, {
+            let mut values = S::Vf32::zeroes();
+            for i in 0..S::Vf32::WIDTH {
+                values[i] = self.next_value();
+            }
+            SIMDSampleMono(values)
+        })
+    }
+}
+
+/// Converts the output of a modulation LFO to a volume multiplier,
+/// where `depth` is the change in centibels at the LFO peak.
+pub struct SIMDLfoVolume<S: Simd> {
+    lfo: SIMDModLfo<S>,
+    depth: f32,
+}
+
+impl<S: Simd> SIMDLfoVolume<S> {
+    pub fn new(params: LfoParameters, depth: f32) -> Self {
+        Self {
+            lfo: SIMDModLfo::new(params),
+            depth,
+        }
+    }
+}
+
+impl<S: Simd> VoiceGeneratorBase for SIMDLfoVolume<S> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, _rel_type: ReleaseType) {}
+
+    #[inline(always)]
+    fn process_controls(&mut self, _control: &VoiceControlData) {}
+}
+
+impl<S: Simd> SIMDVoiceGenerator<S, SIMDSampleMono<S>> for SIMDLfoVolume<S> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<S> {
+        simd_invoke!(S, {
+            let mut values = self.lfo.next_sample().0;
+            for i in 0..S::Vf32::WIDTH {
+                values[i] = 10.0f32.powf(values[i] * self.depth / 200.0);
+            }
+            SIMDSampleMono(values)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use simdeez::simd_runtime_generate;
+
+    use super::*;
+
+    fn collect<S: Simd>(gen: &mut impl SIMDVoiceGenerator<S, SIMDSampleMono<S>>) -> Vec<f32> {
+        let mut out = Vec::new();
+        while out.len() < 16 {
+            let values = gen.next_sample().0;
+            for i in 0..S::Vf32::WIDTH {
+                out.push(values[i]);
+            }
+        }
+        out.truncate(16);
+        out
+    }
+
+    #[test]
+    fn test_triangle_lfo() {
+        simd_runtime_generate!(
+            fn run() {
+                let params = LfoParameters::new(0.5, 1.0, 8.0);
+                let mut lfo = SIMDModLfo::<S>::new(params);
+                assert_eq!(
+                    collect::<S>(&mut lfo),
+                    [
+                        0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5, 0.0, 0.5,
+                        1.0, 0.5
+                    ]
+                );
+
+                let mut volume = SIMDLfoVolume::<S>::new(params, 60.0);
+                let values = collect::<S>(&mut volume);
+                assert_eq!(values[4], 1.0);
+                assert!((values[6] - 10.0f32.powf(0.3)).abs() < 1e-5);
+                assert!((values[10] - 10.0f32.powf(-0.3)).abs() < 1e-5);
+            }
+        );
+
+        run();
+    }
+}
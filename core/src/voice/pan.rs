@@ -0,0 +1,101 @@
+use simdeez::prelude::*;
+
+use crate::{
+    channel::PanLaw,
+    voice::{ReleaseType, VoiceControlData},
+};
+
+use super::{SIMDSampleStereo, SIMDVoiceGenerator, VoiceGeneratorBase};
+
+/// Stereo gains of a voice. The pan of the voice is added to the channel pan
+/// of the voice control data, and changes of the channel pan are smoothed
+/// over 10ms.
+pub struct SIMDStereoPan<S: Simd> {
+    pan: f32,
+    law: PanLaw,
+    lerp_length: f32,
+    step: f32,
+    current: f32,
+    end: f32,
+    left: S::Vf32,
+    right: S::Vf32,
+}
+
+impl<S: Simd> SIMDStereoPan<S> {
+    /// Creates the gains for a voice pan, where 0.0 is left, 0.5 is center
+    /// and 1.0 is right.
+    pub fn new(pan: f32, control: &VoiceControlData, sample_rate: f32) -> Self {
+        let current = Self::combine(pan, control.pan);
+        let (left, right) = control.pan_law.gains(current);
+
+        simd_invoke!(S, {
+            Self {
+                pan,
+                law: control.pan_law,
+                lerp_length: sample_rate * 0.01,
+                step: 0.0,
+                current,
+                end: current,
+                left: S::Vf32::set1(left),
+                right: S::Vf32::set1(right),
+            }
+        })
+    }
+
+    fn combine(pan: f32, channel_pan: f32) -> f32 {
+        (pan + channel_pan - 0.5).clamp(0.0, 1.0)
+    }
+
+    fn next_pan(&mut self) -> f32 {
+        if self.end > self.current {
+            self.current = (self.current + self.step).min(self.end);
+        } else if self.end < self.current {
+            self.current = (self.current + self.step).max(self.end);
+        }
+        self.current
+    }
+}
+
+impl<S: Simd> VoiceGeneratorBase for SIMDStereoPan<S> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, _rel_type: ReleaseType) {}
+
+    #[inline(always)]
+    fn process_controls(&mut self, control: &VoiceControlData) {
+        self.end = Self::combine(self.pan, control.pan);
+        self.step = (self.end - self.current) / self.lerp_length;
+
+        if self.law != control.pan_law {
+            self.law = control.pan_law;
+            let (left, right) = self.law.gains(self.current);
+            simd_invoke!(S, {
+                self.left = S::Vf32::set1(left);
+                self.right = S::Vf32::set1(right);
+            })
+        }
+    }
+}
+
+impl<S: Simd> SIMDVoiceGenerator<S, SIMDSampleStereo<S>> for SIMDStereoPan<S> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleStereo<S> {
+        if self.current == self.end {
+            return SIMDSampleStereo(self.left, self.right);
+        }
+
+        simd_invoke!(S, {
+            for i in 0..S::Vf32::WIDTH {
+                let pan = self.next_pan();
+                let (left, right) = self.law.gains(pan);
+                self.left[i] = left;
+                self.right[i] = right;
+            }
+            SIMDSampleStereo(self.left, self.right)
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use std::marker::PhantomData;
+
+use simdeez::prelude::*;
+
+use crate::voice::{PortamentoControlData, ReleaseType, VoiceControlData};
+
+use super::{SIMDSampleMono, SIMDVoiceGenerator, VoiceGeneratorBase};
+
+/// A pitch multiplier gliding from the start multiplier of the portamento
+/// to 1. The glide is linear in cents.
+pub struct SIMDPortamento<S: Simd> {
+    value: f32,
+    step: f32,
+    remaining: u32,
+    _s: PhantomData<S>,
+}
+
+impl<S: Simd> SIMDPortamento<S> {
+    pub fn new(data: PortamentoControlData, sample_rate: f32) -> Self {
+        let remaining = (data.time * sample_rate) as u32;
+        let step = data.start_multiplier.powf(-1.0 / remaining.max(1) as f32);
+
+        Self {
+            value: data.start_multiplier,
+            step,
+            remaining,
+            _s: PhantomData,
+        }
+    }
+
+    fn next_value(&mut self) -> f32 {
+        if self.remaining == 0 {
+            return 1.0;
+        }
+
+        let value = self.value;
+        self.value *= self.step;
+        self.remaining -= 1;
+        value
+    }
+}
+
+impl<S: Simd> VoiceGeneratorBase for SIMDPortamento<S> {
+    #[inline(always)]
+    fn ended(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn signal_release(&mut self, _rel_type: ReleaseType) {}
+
+    #[inline(always)]
+    fn process_controls(&mut self, _control: &VoiceControlData) {}
+}
+
+impl<S: Simd> SIMDVoiceGenerator<S, SIMDSampleMono<S>> for SIMDPortamento<S> {
+    #[inline(always)]
+    fn next_sample(&mut self) -> SIMDSampleMono<S> {
+        simd_invoke!(S, {
+            let mut values = S::Vf32::zeroes();
+            for i in 0..S::Vf32::WIDTH {
+                values[i] = self.next_value();
+            }
+            SIMDSampleMono(values)
+        })
+    }
+}
@@ -39,6 +39,10 @@ pub trait SIMDSampleGrabber<S: Simd>: Send + Sync {
     fn is_past_end(&self, pos: f64) -> bool;
 
     fn signal_release(&mut self);
+
+    /// Updates the reader state as if the samples up to the given
+    /// position were read, without reading them.
+    fn advance_to(&mut self, pos: f64);
 }
 
 // F32 sampler
@@ -93,6 +97,11 @@ pub trait SampleReader: Send + Sync {
     fn get(&mut self, pos: usize) -> f32;
     fn is_past_end(&self, pos: usize) -> bool;
     fn signal_release(&mut self);
+
+    /// Updates the reader state as if the samples up to the given
+    /// position were read. Only readers that track their read position
+    /// need to implement this.
+    fn advance_to(&mut self, _pos: usize) {}
 }
 
 pub struct SampleReaderNoLoop<Sampler: BufferSampler> {
@@ -168,17 +177,24 @@ impl<Sampler: BufferSampler> SampleReader for SampleReaderLoop<Sampler> {
 
 pub struct SampleReaderLoopSustain<Sampler: BufferSampler> {
     buffer: Sampler,
-    length: Option<usize>,
+    length: usize,
     offset: usize,
     loop_start: usize,
     loop_end: usize,
+
+    /// The furthest position read, including the offset
     last: usize,
-    is_released: bool,
+
+    /// The position at the time of the release and the distance it was
+    /// wrapped back by the loop. Positions after the release are moved back
+    /// by the same distance, so the playback continues from the same point
+    /// of the loop towards the end of the sample.
+    release: Option<(usize, usize)>,
 }
 
 impl<Sampler: BufferSampler> SampleReaderLoopSustain<Sampler> {
     pub fn new(buffer: Sampler, loop_params: LoopParams) -> Self {
-        let length = Some(buffer.length());
+        let length = buffer.length();
         Self {
             buffer,
             length,
@@ -186,39 +202,49 @@ impl<Sampler: BufferSampler> SampleReaderLoopSustain<Sampler> {
             loop_start: loop_params.start as usize,
             loop_end: loop_params.end as usize,
             last: 0,
-            is_released: false,
+            release: None,
         }
     }
-}
 
-impl<Sampler: BufferSampler> SampleReader for SampleReaderLoopSustain<Sampler> {
-    fn get(&mut self, pos: usize) -> f32 {
-        let mut pos = pos + self.offset;
+    fn wrap(&self, pos: usize) -> usize {
         let end = self.loop_end;
         let start = self.loop_start;
 
-        if !self.is_released {
-            if pos > end {
-                pos = (pos - end - 1) % (end - start) + start;
-                self.last = pos;
-            }
+        if pos > end {
+            (pos - end - 1) % (end - start) + start
         } else {
-            pos -= self.last;
+            pos
         }
+    }
 
-        self.buffer.get(pos)
+    /// Returns the index in the buffer of a position including the offset.
+    fn buffer_index(&self, pos: usize) -> usize {
+        match self.release {
+            Some((released_at, shift)) if pos > released_at => pos - shift,
+            _ => self.wrap(pos),
+        }
+    }
+}
+
+impl<Sampler: BufferSampler> SampleReader for SampleReaderLoopSustain<Sampler> {
+    fn get(&mut self, pos: usize) -> f32 {
+        let pos = pos + self.offset;
+        self.last = self.last.max(pos);
+        self.buffer.get(self.buffer_index(pos))
     }
 
     fn is_past_end(&self, pos: usize) -> bool {
-        if let Some(len) = self.length {
-            pos - (self.last - self.offset).min(pos) >= len
-        } else {
-            false
-        }
+        self.buffer_index(pos + self.offset) >= self.length
     }
 
     fn signal_release(&mut self) {
-        self.is_released = true;
+        if self.release.is_none() {
+            self.release = Some((self.last, self.last - self.wrap(self.last)));
+        }
+    }
+
+    fn advance_to(&mut self, pos: usize) {
+        self.last = self.last.max(pos + self.offset);
     }
 }
 
@@ -263,6 +289,14 @@ impl<S: Simd, Reader: SampleReader> SIMDSampleGrabber<S> for SIMDSampleGrabbers<
             SIMDSampleGrabbers::Nearest(grabber) => grabber.signal_release(),
         }
     }
+
+    #[inline(always)]
+    fn advance_to(&mut self, pos: f64) {
+        match self {
+            SIMDSampleGrabbers::Linear(grabber) => grabber.advance_to(pos),
+            SIMDSampleGrabbers::Nearest(grabber) => grabber.advance_to(pos),
+        }
+    }
 }
 
 // Sampler generator
@@ -354,6 +388,24 @@ where
             SIMDSampleMono(sample)
         })
     }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        // The pitch still needs to be generated to keep the playback position correct
+        simd_invoke!(S, {
+            let mut last = None;
+            for _ in 0..count {
+                let speed = self.pitch_gen.next_sample().0;
+                for i in 0..S::Vf32::WIDTH {
+                    last = Some(self.increment_time(speed[i] as f64));
+                }
+            }
+
+            if let Some(last) = last {
+                self.grabber.advance_to(last);
+            }
+        })
+    }
 }
 
 pub struct SIMDStereoVoiceSampler<S, Pitch, Grabber>
@@ -447,4 +499,102 @@ where
             SIMDSampleStereo(left, right)
         })
     }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        // The pitch still needs to be generated to keep the playback position correct
+        simd_invoke!(S, {
+            let mut last = None;
+            for _ in 0..count {
+                let speed = self.pitch_gen.next_sample().0;
+                for i in 0..S::Vf32::WIDTH {
+                    last = Some(self.increment_time(speed[i] as f64));
+                }
+            }
+
+            if let Some(last) = last {
+                self.grabber_left.advance_to(last);
+                self.grabber_right.advance_to(last);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xsynth_soundfonts::LoopMode;
+
+    /// A loop sustain reader of a buffer where each sample is its own index.
+    fn loop_sustain_reader(
+        len: usize,
+        offset: u64,
+        start: u64,
+        end: u64,
+    ) -> SampleReaderLoopSustain<F32BufferSampler> {
+        let buffer: Arc<[f32]> = (0..len).map(|i| i as f32).collect();
+        SampleReaderLoopSustain::new(
+            F32BufferSampler(buffer),
+            LoopParams {
+                mode: LoopMode::LoopSustain,
+                offset,
+                start,
+                end,
+            },
+        )
+    }
+
+    fn read(reader: &mut impl SampleReader, positions: std::ops::Range<usize>) -> Vec<f32> {
+        positions.map(|pos| reader.get(pos)).collect()
+    }
+
+    #[test]
+    fn test_loop_sustain_release_mid_loop() {
+        let mut reader = loop_sustain_reader(20, 0, 5, 10);
+        assert_eq!(read(&mut reader, 8..14), [8.0, 9.0, 10.0, 5.0, 6.0, 7.0]);
+        assert!(!reader.is_past_end(1000));
+
+        // Continues from the position within the loop, through the loop end
+        // and into the rest of the sample
+        reader.signal_release();
+        assert_eq!(reader.get(13), 7.0);
+        assert_eq!(read(&mut reader, 14..19), [8.0, 9.0, 10.0, 11.0, 12.0]);
+        assert_eq!(reader.get(25), 19.0);
+        assert!(!reader.is_past_end(25));
+        assert!(reader.is_past_end(26));
+    }
+
+    #[test]
+    fn test_loop_sustain_release_before_loop() {
+        let mut reader = loop_sustain_reader(20, 2, 10, 15);
+        assert!(!reader.is_past_end(0));
+        assert_eq!(read(&mut reader, 0..3), [2.0, 3.0, 4.0]);
+
+        // The loop is never played
+        reader.signal_release();
+        assert_eq!(read(&mut reader, 12..16), [14.0, 15.0, 16.0, 17.0]);
+        assert!(!reader.is_past_end(17));
+        assert!(reader.is_past_end(18));
+    }
+
+    #[test]
+    fn test_loop_sustain_loop_at_end() {
+        let mut reader = loop_sustain_reader(10, 0, 4, 9);
+        assert_eq!(read(&mut reader, 8..13), [8.0, 9.0, 4.0, 5.0, 6.0]);
+        assert!(!reader.is_past_end(1000));
+
+        reader.signal_release();
+        assert_eq!(read(&mut reader, 13..17), [7.0, 8.0, 9.0, 0.0]);
+        assert!(!reader.is_past_end(15));
+        assert!(reader.is_past_end(16));
+    }
+
+    #[test]
+    fn test_loop_sustain_release_after_skip() {
+        // Skipped samples count as read, like in SIMD voices skipping a delay
+        let mut reader = loop_sustain_reader(20, 0, 5, 10);
+        reader.advance_to(12);
+        reader.signal_release();
+        assert_eq!(read(&mut reader, 12..15), [6.0, 7.0, 8.0]);
+    }
 }
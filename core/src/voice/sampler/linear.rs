@@ -46,4 +46,9 @@ impl<S: Simd, Reader: SampleReader> SIMDSampleGrabber<S> for SIMDLinearSampleGra
     fn signal_release(&mut self) {
         self.sampler_reader.signal_release();
     }
+
+    fn advance_to(&mut self, pos: f64) {
+        // The interpolation reads one sample ahead
+        self.sampler_reader.advance_to(pos as usize + 1);
+    }
 }
@@ -41,4 +41,8 @@ impl<S: Simd, Reader: SampleReader> SIMDSampleGrabber<S> for SIMDNearestSampleGr
     fn signal_release(&mut self) {
         self.sampler_reader.signal_release();
     }
+
+    fn advance_to(&mut self, pos: f64) {
+        self.sampler_reader.advance_to(pos as usize);
+    }
 }
@@ -104,6 +104,22 @@ impl<T: Simd> SIMDSample<T> for SIMDSampleStereo<T> {
 /// The base SIMD voice generator trait
 pub trait SIMDVoiceGenerator<T: Simd, TO: SIMDSample<T>>: VoiceGeneratorBase {
     fn next_sample(&mut self) -> TO;
+
+    /// The number of upcoming SIMD arrays which are known to be silent,
+    /// and can therefore be skipped with `skip_arrays`.
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        0
+    }
+
+    /// Advances the generator by the given number of SIMD arrays without
+    /// generating their samples, if the generator supports it.
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        for _ in 0..count {
+            self.next_sample();
+        }
+    }
 }
 
 /// SIMD voice generator combiner based on a passed in function
@@ -119,6 +135,7 @@ where
     v1: V1,
     v2: V2,
     func: F,
+    silence: fn(usize, usize) -> usize,
     _t: PhantomData<T>,
     _ti: PhantomData<TI>,
     _to: PhantomData<TO>,
@@ -133,11 +150,14 @@ where
     V2: SIMDVoiceGenerator<T, TO>,
     F: Fn(TI, TO) -> TO,
 {
-    pub fn new(v1: V1, v2: V2, func: F) -> Self {
+    /// Creates a new combiner. The `silence` function gets the silent array
+    /// counts of both generators, and returns the silent array count of the result.
+    pub fn new(v1: V1, v2: V2, func: F, silence: fn(usize, usize) -> usize) -> Self {
         SIMDVoiceCombine {
             v1,
             v2,
             func,
+            silence,
             _t: PhantomData,
             _ti: PhantomData,
             _to: PhantomData,
@@ -187,6 +207,17 @@ where
             (self.func)(self.v1.next_sample(), self.v2.next_sample())
         })
     }
+
+    #[inline(always)]
+    fn silent_arrays(&self) -> usize {
+        (self.silence)(self.v1.silent_arrays(), self.v2.silent_arrays())
+    }
+
+    #[inline(always)]
+    fn skip_arrays(&mut self, count: usize) {
+        self.v1.skip_arrays(count);
+        self.v2.skip_arrays(count);
+    }
 }
 
 /// Parent struct for base SIMD voice combination functions
@@ -210,7 +241,8 @@ impl<T: Simd> VoiceCombineSIMD<T> {
             a * b
         }
 
-        SIMDVoiceCombine::new(voice1, voice2, mult)
+        // Either side being silent silences the product
+        SIMDVoiceCombine::new(voice1, voice2, mult, usize::max)
     }
 
     pub fn sum<TI, TO, V1, V2>(voice1: V1, voice2: V2) -> impl SIMDVoiceGenerator<T, TO>
@@ -230,7 +262,7 @@ impl<T: Simd> VoiceCombineSIMD<T> {
             a + b
         }
 
-        SIMDVoiceCombine::new(voice1, voice2, add)
+        SIMDVoiceCombine::new(voice1, voice2, add, usize::min)
     }
 }
 
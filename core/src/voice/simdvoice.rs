@@ -9,6 +9,35 @@ use super::{
     VoiceSampleGenerator,
 };
 
+/// Skips the silent SIMD arrays of the generator which fit in the buffer,
+/// and returns the rest of the buffer. Silence can only be skipped if there
+/// are no samples left over from the previous SIMD array.
+#[inline(always)]
+fn skip_silence<'a, S, TO, G>(
+    generator: &mut G,
+    buffer: &'a mut [f32],
+    channels: usize,
+    remainder_pos: usize,
+) -> &'a mut [f32]
+where
+    S: Simd,
+    TO: SIMDSample<S>,
+    G: SIMDVoiceGenerator<S, TO>,
+{
+    if remainder_pos != S::Vf32::WIDTH {
+        return buffer;
+    }
+
+    let fitting = buffer.len() / channels / S::Vf32::WIDTH;
+    let count = generator.silent_arrays().min(fitting);
+    if count == 0 {
+        return buffer;
+    }
+
+    generator.skip_arrays(count);
+    &mut buffer[count * S::Vf32::WIDTH * channels..]
+}
+
 pub struct SIMDStereoVoice<S: Simd, T: SIMDVoiceGenerator<S, SIMDSampleStereo<S>>> {
     generator: T,
     remainder: SIMDSampleStereo<S>,
@@ -55,6 +84,8 @@ where
 {
     fn render_to(&mut self, buffer: &mut [f32]) {
         simd_invoke!(S, {
+            let buffer = skip_silence(&mut self.generator, buffer, 2, self.remainder_pos);
+
             for chunk in buffer.chunks_exact_mut(2) {
                 if self.remainder_pos == S::Vf32::WIDTH {
                     self.remainder = self.generator.next_sample();
@@ -120,6 +151,8 @@ where
 {
     fn render_to(&mut self, buffer: &mut [f32]) {
         simd_invoke!(S, {
+            let buffer = skip_silence(&mut self.generator, buffer, 1, self.remainder_pos);
+
             let mut i = 0;
             while i < buffer.len() {
                 if self.remainder_pos == S::Vf32::WIDTH {
@@ -1,16 +1,23 @@
 #![allow(non_snake_case)]
 #![allow(static_mut_refs)]
 
+use cpal::traits::HostTrait;
 use hotwatch::{Event, EventKind, Hotwatch};
 use std::{
+    cell::RefCell,
     ffi::c_void,
     os::raw::c_ulong,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::Arc,
     thread,
     time::Duration,
 };
-use xsynth_core::channel::{ChannelConfigEvent, ChannelEvent};
-use xsynth_realtime::{RealtimeEventSender, RealtimeSynth, SynthEvent};
+use xsynth_core::{
+    channel::{ChannelConfigEvent, ChannelEvent},
+    soundfont::SoundfontBase,
+    AudioStreamParams,
+};
+use xsynth_realtime::{RealtimeEventSender, RealtimeSynth, RealtimeSynthStatsReader, SynthEvent};
 
 #[cfg(windows)]
 use winapi::{
@@ -28,18 +35,38 @@ use winapi::{
 mod parsers;
 use parsers::*;
 
+mod state;
+use state::{LocalSender, SynthSlot};
+
 struct Synth {
-    killed: Arc<Mutex<bool>>,
-    stats_join_handle: thread::JoinHandle<()>,
+    stats: RealtimeSynthStatsReader,
     senders: RealtimeEventSender,
+    stream_params: AudioStreamParams,
     hotwatch: Hotwatch,
 
     // This field is necessary to keep the synth loaded
     _synth: RealtimeSynth,
 }
 
-static mut GLOBAL_SYNTH: Option<Synth> = None;
-static mut CURRENT_VOICE_COUNT: u64 = 0;
+static GLOBAL_SYNTH: SynthSlot<Synth> = SynthSlot::new();
+
+thread_local! {
+    // Each calling thread keeps its own sender clone, so sending events doesn't lock
+    static LOCAL_SENDER: RefCell<LocalSender<RealtimeEventSender>> =
+        const { RefCell::new(LocalSender::new()) };
+}
+
+fn with_sender(f: impl FnOnce(&mut RealtimeEventSender)) -> bool {
+    LOCAL_SENDER.with_borrow_mut(|local| {
+        match local.get(&GLOBAL_SYNTH, |synth| synth.senders.clone()) {
+            Some(sender) => {
+                f(sender);
+                true
+            }
+            None => false,
+        }
+    })
+}
 
 // region: Custom XSynth KDMAPI functions
 
@@ -47,7 +74,9 @@ static mut CURRENT_VOICE_COUNT: u64 = 0;
 /// the KDMAPI standard. Its basically just for testing.
 #[no_mangle]
 pub extern "C" fn GetVoiceCount() -> u64 {
-    unsafe { CURRENT_VOICE_COUNT }
+    GLOBAL_SYNTH
+        .with(|synth| synth.stats.voice_count())
+        .unwrap_or(0)
 }
 
 // endregion
@@ -56,34 +85,34 @@ pub extern "C" fn GetVoiceCount() -> u64 {
 
 #[no_mangle]
 pub extern "C" fn InitializeKDMAPIStream() -> i32 {
-    let config = Config::<Settings>::new().load().unwrap();
-    let sflist = Config::<SFList>::new().load().unwrap();
+    GLOBAL_SYNTH.init(|| Some(create_synth())) as i32
+}
 
-    let realtime_synth = RealtimeSynth::open_with_default_output(config.get_synth_config());
+fn create_synth() -> Synth {
+    let config = Config::<Settings>::new().load_or_default();
+    let sflist = Config::<SFList>::new().load_or_default();
+
+    let device = cpal::default_host()
+        .default_output_device()
+        .expect("failed to find output device");
+    let realtime_synth = RealtimeSynth::open_with_config_preferences(
+        config.get_synth_config(),
+        &device,
+        config.get_stream_preferences(),
+    );
     let mut sender = realtime_synth.get_sender_ref().clone();
     let params = realtime_synth.stream_params();
 
     sender.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
         ChannelConfigEvent::SetLayerCount(config.get_layers()),
     )));
+    sender.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+        ChannelConfigEvent::SetTranspose(config.get_transpose()),
+    )));
     sender.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
         ChannelConfigEvent::SetSoundfonts(sflist.create_sfbase_vector(params)),
     )));
 
-    let killed = Arc::new(Mutex::new(false));
-
-    let stats = realtime_synth.get_stats();
-
-    let killed_thread = killed.clone();
-    let stats_join_handle = thread::spawn(move || {
-        while !*killed_thread.lock().unwrap() {
-            unsafe {
-                CURRENT_VOICE_COUNT = stats.voice_count();
-            }
-            thread::sleep(Duration::from_millis(10));
-        }
-    });
-
     let mut hotwatch = Hotwatch::new_with_custom_delay(Duration::from_millis(500)).unwrap();
 
     // Watch for config changes and apply them
@@ -92,9 +121,15 @@ pub extern "C" fn InitializeKDMAPIStream() -> i32 {
         .watch(Config::<Settings>::path(), move |event: Event| {
             if let EventKind::Modify(_) = event.kind {
                 thread::sleep(Duration::from_millis(10));
-                let layers = Config::<Settings>::new().load().unwrap().get_layers();
+                let settings = Config::<Settings>::new().load_or_default();
+                sender_thread.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                    ChannelConfigEvent::SetLayerCount(settings.get_layers()),
+                )));
+                sender_thread.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                    ChannelConfigEvent::SetTranspose(settings.get_transpose()),
+                )));
                 sender_thread.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
-                    ChannelConfigEvent::SetLayerCount(layers),
+                    ChannelConfigEvent::SetMasterTuning(settings.get_master_tuning()),
                 )));
             }
         })
@@ -107,8 +142,7 @@ pub extern "C" fn InitializeKDMAPIStream() -> i32 {
             if let EventKind::Modify(_) = event.kind {
                 thread::sleep(Duration::from_millis(10));
                 let sfs = Config::<SFList>::new()
-                    .load()
-                    .unwrap()
+                    .load_or_default()
                     .create_sfbase_vector(params);
                 sender_thread.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
                     ChannelConfigEvent::SetSoundfonts(sfs),
@@ -117,57 +151,40 @@ pub extern "C" fn InitializeKDMAPIStream() -> i32 {
         })
         .unwrap();
 
-    unsafe {
-        GLOBAL_SYNTH = Some(Synth {
-            killed,
-            senders: sender,
-            stats_join_handle,
-            hotwatch,
-            _synth: realtime_synth,
-        });
+    Synth {
+        stats: realtime_synth.get_stats(),
+        senders: sender,
+        stream_params: params,
+        hotwatch,
+        _synth: realtime_synth,
     }
-    1
 }
 
 #[no_mangle]
 pub extern "C" fn TerminateKDMAPIStream() -> i32 {
-    unsafe {
-        if let Some(mut synth) = GLOBAL_SYNTH.take() {
-            *synth.killed.lock().unwrap() = true;
-            synth.stats_join_handle.join().ok();
-
-            synth.hotwatch.unwatch(Config::<Settings>::path()).unwrap();
-            synth.hotwatch.unwatch(Config::<SFList>::path()).unwrap();
-            Config::<Settings>::new()
-                .repair()
-                .expect("Error while saving settings");
-            Config::<SFList>::new()
-                .repair()
-                .expect("Error while saving sf list");
-            return 1;
+    if let Some(mut synth) = GLOBAL_SYNTH.terminate() {
+        synth.hotwatch.unwatch(Config::<Settings>::path()).unwrap();
+        synth.hotwatch.unwatch(Config::<SFList>::path()).unwrap();
+        // Files of a newer version are left untouched
+        if let Err(e) = Config::<Settings>::new().repair() {
+            println!("Error while saving settings: {e}");
+        }
+        if let Err(e) = Config::<SFList>::new().repair() {
+            println!("Error while saving sf list: {e}");
         }
-        0
+        return 1;
     }
+    0
 }
 
 #[no_mangle]
 pub extern "C" fn ResetKDMAPIStream() {
-    unsafe {
-        if let Some(synth) = GLOBAL_SYNTH.as_mut() {
-            synth.senders.reset_synth();
-        }
-    }
+    with_sender(|sender| sender.reset_synth());
 }
 
 #[no_mangle]
 pub extern "C" fn SendDirectData(dwMsg: u32) -> u32 {
-    unsafe {
-        if let Some(sender) = GLOBAL_SYNTH.as_mut() {
-            sender.senders.send_event_u32(dwMsg);
-            return 1;
-        }
-        0
-    }
+    with_sender(|sender| sender.send_event_u32(dwMsg)) as u32
 }
 
 #[no_mangle]
@@ -177,7 +194,7 @@ pub extern "C" fn SendDirectDataNoBuf(dwMsg: u32) -> u32 {
 
 #[no_mangle]
 pub extern "C" fn IsKDMAPIAvailable() -> u32 {
-    unsafe { GLOBAL_SYNTH.is_some() as u32 }
+    GLOBAL_SYNTH.is_initialized() as u32
 }
 
 #[no_mangle]
@@ -195,6 +212,63 @@ pub unsafe extern "C" fn ReturnKDMAPIVer(
     1
 }
 
+/// Replaces the active soundfonts with the ones of the given soundfont list,
+/// which can be in the XSynth or the OmniMIDI format. As in OmniMIDI, the path
+/// is a null terminated UTF-16 string. The active soundfonts are kept if the
+/// path is null or the list can't be read.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn LoadCustomSoundFontsList(Directory: *const u16) {
+    let Some(path) = (unsafe { path_from_wide(Directory) }) else {
+        return;
+    };
+    let Some(params) = GLOBAL_SYNTH.with(|synth| synth.stream_params) else {
+        return;
+    };
+
+    if let Some(sfs) = load_soundfont_list(&path, params) {
+        with_sender(|sender| {
+            sender.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetSoundfonts(sfs),
+            )))
+        });
+    }
+}
+
+/// Converts a null terminated UTF-16 string to a path.
+unsafe fn path_from_wide(ptr: *const u16) -> Option<PathBuf> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    let len = (0..).take_while(|&i| unsafe { *ptr.add(i) } != 0).count();
+    let wide = unsafe { std::slice::from_raw_parts(ptr, len) };
+
+    #[cfg(windows)]
+    {
+        use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+        Some(OsString::from_wide(wide).into())
+    }
+
+    #[cfg(not(windows))]
+    String::from_utf16(wide).ok().map(PathBuf::from)
+}
+
+/// Loads the soundfonts of a soundfont list file. Returns `None` if
+/// the list can't be read.
+fn load_soundfont_list(
+    path: &Path,
+    params: AudioStreamParams,
+) -> Option<Vec<Arc<dyn SoundfontBase>>> {
+    match Config::<SFList>::load_path(path.to_path_buf()) {
+        Ok(list) => Some(list.create_sfbase_vector(params)),
+        Err(e) => {
+            println!("Error loading soundfont list: {e}");
+            None
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn timeGetTime64() -> u64 {
     std::time::SystemTime::now()
@@ -245,9 +319,6 @@ pub extern "C" fn DriverSettings(
     1
 }
 
-#[no_mangle]
-pub extern "C" fn LoadCustomSoundFontsList(_Directory: u16) {}
-
 #[no_mangle]
 pub extern "C" fn GetDriverDebugInfo() {}
 
@@ -317,3 +388,58 @@ cfg_if::cfg_if! {
 }
 
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xsynth_core::soundfont::test_utils::TestSoundfontDir;
+
+    #[test]
+    fn test_uninitialized_stream() {
+        assert!(!GLOBAL_SYNTH.is_initialized());
+        assert_eq!(GetVoiceCount(), 0);
+        assert_eq!(SendDirectData(0x7F3C90), 0);
+        assert_eq!(TerminateKDMAPIStream(), 0);
+        unsafe { LoadCustomSoundFontsList(std::ptr::null()) };
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    #[test]
+    fn test_path_from_wide() {
+        unsafe {
+            assert_eq!(path_from_wide(std::ptr::null()), None);
+            assert_eq!(
+                path_from_wide(wide("sf/list é.json").as_ptr()),
+                Some(PathBuf::from("sf/list é.json"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_soundfont_list() {
+        let dir = TestSoundfontDir::new("kdmapi_list");
+        dir.write_wav("sample.wav", 48000, &[0.0; 100]);
+        let sfz = dir.write_sfz("test.sfz", "<region> sample=sample.wav\n");
+
+        let list = dir.path().join("list.json");
+        let json = serde_json::json!({
+            "soundfonts": [
+                { "path": sfz },
+                { "path": sfz, "enabled": false },
+            ]
+        });
+        std::fs::write(&list, json.to_string()).unwrap();
+
+        let params = AudioStreamParams::new(48000, xsynth_core::ChannelCount::Stereo);
+        let sfs = load_soundfont_list(&list, params).unwrap();
+        assert_eq!(sfs.len(), 1);
+
+        // Missing and invalid lists keep the active soundfonts
+        assert!(load_soundfont_list(&dir.path().join("missing.json"), params).is_none());
+        std::fs::write(&list, "not json").unwrap();
+        assert!(load_soundfont_list(&list, params).is_none());
+    }
+}
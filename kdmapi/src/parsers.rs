@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::prelude::*, marker::PhantomData, path::PathBuf};
+use serde_json::{Map, Value};
+use std::{fmt, marker::PhantomData, path::PathBuf};
 
 mod soundfonts;
 pub use soundfonts::SFList;
@@ -13,9 +14,66 @@ pub trait ConfigPath {
     fn filename() -> PathBuf;
 }
 
+/// A migration of a config file, applied to its JSON object.
+pub type Migration = fn(&mut Map<String, Value>);
+
+/// The versions of the on-disk format of a config file.
+pub trait ConfigVersion {
+    /// The migrations of the format, in order. The migration at index `n`
+    /// upgrades a file from version `n` to `n + 1`, so the latest version
+    /// is the number of migrations. Files without a `version` field are
+    /// version 0.
+    const MIGRATIONS: &'static [Migration];
+
+    fn latest_version() -> u64 {
+        Self::MIGRATIONS.len() as u64
+    }
+}
+
+/// Errors that can be generated when loading a config file.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(String),
+    UnsupportedVersion { version: u64, latest: u64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "IO error: {e}"),
+            ConfigError::Parse(e) => write!(f, "Parsing error: {e}"),
+            ConfigError::UnsupportedVersion { version, latest } => write!(
+                f,
+                "The file uses version {version} of the format, but this build only supports versions up to {latest}"
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(e: serde_json::Error) -> Self {
+        ConfigError::Parse(e.to_string())
+    }
+}
+
+/// The layout of a saved config file, with the version before the fields.
+#[derive(Serialize)]
+struct VersionedConfig<'a, T> {
+    version: u64,
+    #[serde(flatten)]
+    config: &'a T,
+}
+
 pub struct Config<T>
 where
-    T: Default + Serialize + for<'a> Deserialize<'a> + ConfigPath,
+    T: Default + Serialize + for<'a> Deserialize<'a> + ConfigPath + ConfigVersion,
 {
     path: PathBuf,
     _config: PhantomData<T>,
@@ -23,7 +81,7 @@ where
 
 impl<T> Config<T>
 where
-    T: Default + Serialize + for<'a> Deserialize<'a> + ConfigPath,
+    T: Default + Serialize + for<'a> Deserialize<'a> + ConfigPath + ConfigVersion,
 {
     pub fn path() -> PathBuf {
         match directories::BaseDirs::new() {
@@ -39,43 +97,236 @@ where
     }
 
     pub fn new() -> Self {
+        Self::with_path(Config::<T>::path())
+    }
+
+    /// Uses the config file at the given path instead of the default one.
+    pub fn with_path(path: PathBuf) -> Self {
         Self {
-            path: Config::<T>::path(),
+            path,
             _config: PhantomData,
         }
     }
 
-    fn load_from_file(&self) -> Result<T, String> {
-        let mut file = File::open(&self.path).map_err(|e| format!("IO error: {e}"))?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .map_err(|e| format!("Loading error: {e}"))?;
-        serde_json::from_str(&contents).map_err(|e| format!("Parsing error: {e}"))
+    /// Loads the config from the given file. Unlike `load`, the file
+    /// isn't created if it doesn't exist, and it isn't rewritten if it
+    /// uses an older version of the format.
+    pub fn load_path(path: PathBuf) -> Result<T, ConfigError> {
+        Self::with_path(path).read().map(|(config, _)| config)
+    }
+
+    /// Reads the file and applies the migrations from its version.
+    /// Returns the config and the version of the file.
+    fn read(&self) -> Result<(T, u64), ConfigError> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let mut value: Value = serde_json::from_str(&contents)?;
+        let object = value
+            .as_object_mut()
+            .ok_or_else(|| ConfigError::Parse("expected a JSON object".to_string()))?;
+
+        let version = match object.remove("version") {
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| ConfigError::Parse(format!("invalid version {version}")))?,
+            None => 0,
+        };
+        let latest = T::latest_version();
+        if version > latest {
+            return Err(ConfigError::UnsupportedVersion { version, latest });
+        }
+        for migration in &T::MIGRATIONS[version as usize..] {
+            migration(object);
+        }
+
+        Ok((T::deserialize(value)?, version))
+    }
+
+    fn save(&self, config: &T) -> Result<(), ConfigError> {
+        let contents = serde_json::to_string_pretty(&VersionedConfig {
+            version: T::latest_version(),
+            config,
+        })?;
+        std::fs::write(&self.path, contents)?;
+
+        Ok(())
     }
 
-    fn save(&self, config: &T) -> Result<(), String> {
-        let contents =
-            serde_json::to_string_pretty(config).map_err(|e| format!("Parsing error: {e}"))?;
-        let mut file = File::create(&self.path).map_err(|e| format!("IO error: {e}"))?;
-        file.write_all(contents.as_bytes())
-            .map_err(|e| format!("Saving error: {e}"))?;
+    /// Copies the file next to it, with the given extension appended.
+    fn backup(&self, extension: &str) -> Result<(), ConfigError> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(extension);
+        std::fs::copy(&self.path, path)?;
 
         Ok(())
     }
 
-    fn create_empty(&self) -> Result<(), String> {
+    fn create_empty(&self) -> Result<(), ConfigError> {
         self.save(&T::default())
     }
 
-    pub fn load(&self) -> Result<T, String> {
+    /// Loads the config, creating the file if it doesn't exist.
+    ///
+    /// Files using an older version of the format are migrated and
+    /// rewritten, keeping the original as `<file>.v<version>.bak`. Files
+    /// that can't be parsed are moved aside as `<file>.corrupted` and
+    /// replaced with the defaults. Files using a newer version of the
+    /// format are left untouched and produce an error.
+    pub fn load(&self) -> Result<T, ConfigError> {
         let path = &self.path;
         if !path.exists() {
             self.create_empty()?;
         }
-        self.load_from_file()
+
+        match self.read() {
+            Ok((config, version)) => {
+                if version < T::latest_version() {
+                    self.backup(&format!("v{version}.bak"))?;
+                    self.save(&config)?;
+                }
+                Ok(config)
+            }
+            Err(ConfigError::Parse(e)) => {
+                println!(
+                    "Error parsing {}, restoring the defaults: {e}",
+                    path.display()
+                );
+                self.backup("corrupted")?;
+                self.create_empty()?;
+                Ok(T::default())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Loads the config like `load`, but falls back to the defaults
+    /// if the file can't be used.
+    pub fn load_or_default(&self) -> T {
+        self.load().unwrap_or_else(|e| {
+            println!("Error loading {}: {e}", self.path.display());
+            T::default()
+        })
     }
 
-    pub fn repair(&self) -> Result<(), String> {
+    pub fn repair(&self) -> Result<(), ConfigError> {
         self.save(&self.load()?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "xsynth_kdmapi_config_{name}_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_json(path: &PathBuf) -> Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_v0_settings() {
+        let dir = test_dir("migrate");
+        let path = dir.join("settings.json");
+        let original = json!({
+            "layers": 8,
+            "transpose": -3,
+            "future_option": { "enabled": true },
+        })
+        .to_string();
+        std::fs::write(&path, &original).unwrap();
+
+        let settings = Config::<Settings>::with_path(path.clone()).load().unwrap();
+        assert_eq!(settings.get_layers(), Some(8));
+        assert_eq!(settings.get_transpose(), -3);
+
+        // The file is rewritten with the latest version, keeping the unknown
+        // fields, and the original is backed up
+        let saved = read_json(&path);
+        assert_eq!(saved["version"], Settings::latest_version());
+        assert_eq!(saved["layers"], 8);
+        assert_eq!(saved["future_option"], json!({ "enabled": true }));
+        let backup = std::fs::read_to_string(dir.join("settings.json.v0.bak")).unwrap();
+        assert_eq!(backup, original);
+
+        // Loading the migrated file doesn't rewrite it again
+        std::fs::remove_file(dir.join("settings.json.v0.bak")).unwrap();
+        Config::<Settings>::with_path(path.clone()).load().unwrap();
+        assert!(!dir.join("settings.json.v0.bak").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_soundfont_fields_kept() {
+        let dir = test_dir("sflist");
+        let path = dir.join("soundfonts.json");
+        let json = json!({
+            "version": SFList::latest_version(),
+            "soundfonts": [{
+                "path": "test.sfz",
+                "label": "Piano",
+                "options": { "delay_ms": 12.5 },
+            }],
+            "profile": "default",
+        });
+        std::fs::write(&path, json.to_string()).unwrap();
+
+        Config::<SFList>::with_path(path.clone()).repair().unwrap();
+        let saved = read_json(&path);
+        assert_eq!(saved["profile"], "default");
+        assert_eq!(saved["soundfonts"][0]["label"], "Piano");
+        assert_eq!(saved["soundfonts"][0]["enabled"], true);
+        assert_eq!(saved["soundfonts"][0]["options"]["delay_ms"], 12.5);
+        assert_eq!(saved["soundfonts"][0]["options"]["start_trim_ms"], 0.0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_newer_version_rejected() {
+        let dir = test_dir("newer");
+        let path = dir.join("settings.json");
+        let original = json!({ "version": 1000, "layers": 8 }).to_string();
+        std::fs::write(&path, &original).unwrap();
+
+        let config = Config::<Settings>::with_path(path.clone());
+        assert!(matches!(
+            config.load(),
+            Err(ConfigError::UnsupportedVersion { version: 1000, .. })
+        ));
+        assert!(config.repair().is_err());
+        assert_eq!(config.load_or_default().get_layers(), Some(4));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_file_recovery() {
+        let dir = test_dir("corrupted");
+        let path = dir.join("settings.json");
+        for original in ["{ \"layers\": 8,", "[1, 2]", "{ \"version\": \"one\" }"] {
+            std::fs::write(&path, original).unwrap();
+
+            let settings = Config::<Settings>::with_path(path.clone()).load().unwrap();
+            assert_eq!(settings.get_layers(), Some(4));
+
+            // The defaults are saved, and the corrupted file is kept aside
+            let saved = read_json(&path);
+            assert_eq!(saved["version"], Settings::latest_version());
+            assert_eq!(saved["layers"], 4);
+            let backup = std::fs::read_to_string(dir.join("settings.json.corrupted")).unwrap();
+            assert_eq!(backup, original);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,8 +1,9 @@
-use super::ConfigPath;
+use super::{ConfigPath, ConfigVersion, Migration};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{ops::RangeInclusive, path::PathBuf};
 use xsynth_core::channel::ChannelInitOptions;
-use xsynth_realtime::{SynthFormat, ThreadCount, XSynthRealtimeConfig};
+use xsynth_realtime::{StreamConfigPreferences, SynthFormat, ThreadCount, XSynthRealtimeConfig};
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -10,11 +11,25 @@ pub struct Settings {
     // Channel options
     layers: Option<usize>,
     fade_out_killing: bool,
+    transpose: i8,
+    transpose_percussion: bool,
+    master_tuning_hz: f32,
+    percussion_ignores_tuning: bool,
 
     // Realtime synth options
     render_window_ms: f64,
     multithreading: ThreadCount,
     ignore_range: RangeInclusive<u8>,
+    dc_blocker: bool,
+    fade_in_ms: f64,
+
+    // Output options (applied on initialization only)
+    sample_rate: Option<u32>,
+    audio_channels: Option<u16>,
+
+    // Fields unknown to this version, kept when the file is rewritten
+    #[serde(flatten)]
+    unknown: Map<String, Value>,
 }
 
 impl Default for Settings {
@@ -24,9 +39,18 @@ impl Default for Settings {
         Self {
             layers: Some(4),
             fade_out_killing: chandef.fade_out_killing,
+            transpose: 0,
+            transpose_percussion: chandef.transpose_percussion,
+            master_tuning_hz: 440.0,
+            percussion_ignores_tuning: chandef.percussion_ignores_tuning,
             render_window_ms: 10.0,
             multithreading: ThreadCount::None,
             ignore_range: 0..=0,
+            dc_blocker: true,
+            fade_in_ms: 2.0,
+            sample_rate: None,
+            audio_channels: None,
+            unknown: Map::new(),
         }
     }
 }
@@ -36,15 +60,42 @@ impl Settings {
         self.layers
     }
 
+    pub fn get_transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    pub fn get_master_tuning(&self) -> f32 {
+        self.master_tuning_hz
+    }
+
     pub fn get_synth_config(&self) -> XSynthRealtimeConfig {
         XSynthRealtimeConfig {
             channel_init_options: ChannelInitOptions {
                 fade_out_killing: self.fade_out_killing,
+                transpose_percussion: self.transpose_percussion,
+                percussion_ignores_tuning: self.percussion_ignores_tuning,
+                ..Default::default()
             },
             render_window_ms: self.render_window_ms,
+            internal_block_size: None,
+            adaptive_render_window_ms: None,
             format: SynthFormat::Midi,
             multithreading: self.multithreading,
             ignore_range: self.ignore_range.clone(),
+            render_sample_rate: None,
+            dc_blocker: self.dc_blocker,
+            fade_in_ms: self.fade_in_ms,
+            mpe: None,
+            master_tuning_hz: self.master_tuning_hz,
+        }
+    }
+}
+
+impl Settings {
+    pub fn get_stream_preferences(&self) -> StreamConfigPreferences {
+        StreamConfigPreferences {
+            sample_rate: self.sample_rate,
+            channels: self.audio_channels,
         }
     }
 }
@@ -54,3 +105,10 @@ impl ConfigPath for Settings {
         "settings.json".into()
     }
 }
+
+impl ConfigVersion for Settings {
+    const MIGRATIONS: &'static [Migration] = &[
+        // 0 -> 1: Adds the version field, the settings are unchanged
+        |_| {},
+    ];
+}
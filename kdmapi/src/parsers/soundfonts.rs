@@ -1,8 +1,11 @@
-use super::ConfigPath;
+use super::{ConfigPath, ConfigVersion, Migration};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{path::PathBuf, sync::Arc};
 use xsynth_core::{
-    soundfont::{SampleSoundfont, SoundfontBase, SoundfontInitOptions},
+    soundfont::{
+        BassFontEx, EnvelopeCurveType, SampleSoundfont, SoundfontBase, SoundfontInitOptions,
+    },
     AudioStreamParams,
 };
 
@@ -12,6 +15,12 @@ pub struct SFDescriptor {
     pub path: PathBuf,
     pub enabled: bool,
     pub options: SoundfontInitOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fontex: Option<BassFontEx>,
+
+    /// Fields unknown to this version, kept when the list is rewritten
+    #[serde(flatten)]
+    pub unknown: Map<String, Value>,
 }
 
 impl Default for SFDescriptor {
@@ -20,6 +29,8 @@ impl Default for SFDescriptor {
             path: PathBuf::new(),
             enabled: true,
             options: Default::default(),
+            fontex: None,
+            unknown: Map::new(),
         }
     }
 }
@@ -35,16 +46,105 @@ impl SFDescriptor {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A soundfont entry of an OmniMIDI soundfont list.
+#[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
+struct OmniMIDISFDescriptor {
+    path: PathBuf,
+    enabled: bool,
+    linattmod: bool,
+    lindecvol: bool,
+    nofx: bool,
+    #[serde(flatten)]
+    fontex: BassFontEx,
+}
+
+impl Default for OmniMIDISFDescriptor {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            enabled: true,
+            linattmod: false,
+            lindecvol: false,
+            nofx: false,
+            fontex: Default::default(),
+        }
+    }
+}
+
+impl From<OmniMIDISFDescriptor> for SFDescriptor {
+    fn from(sf: OmniMIDISFDescriptor) -> Self {
+        let mut options = SoundfontInitOptions {
+            use_effects: !sf.nofx,
+            ..Default::default()
+        };
+        if sf.linattmod {
+            options.vol_envelope_options.attack_curve = EnvelopeCurveType::Linear;
+        }
+        if sf.lindecvol {
+            options.vol_envelope_options.decay_curve = EnvelopeCurveType::Linear;
+        }
+
+        Self {
+            path: sf.path,
+            enabled: sf.enabled,
+            options,
+            fontex: Some(sf.fontex),
+            unknown: Map::new(),
+        }
+    }
+}
+
+/// The soundfont list formats accepted when loading the configuration.
+/// OmniMIDI lists are detected by their `SoundFonts` key and converted.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SFListFormat {
+    OmniMIDI {
+        #[serde(rename = "SoundFonts")]
+        soundfonts: Vec<OmniMIDISFDescriptor>,
+    },
+    XSynth {
+        #[serde(default)]
+        soundfonts: Vec<SFDescriptor>,
+        #[serde(flatten)]
+        unknown: Map<String, Value>,
+    },
+}
+
+impl From<SFListFormat> for SFList {
+    fn from(format: SFListFormat) -> Self {
+        match format {
+            SFListFormat::OmniMIDI { soundfonts } => Self {
+                soundfonts: soundfonts.into_iter().map(SFDescriptor::from).collect(),
+                unknown: Map::new(),
+            },
+            SFListFormat::XSynth {
+                soundfonts,
+                unknown,
+            } => Self {
+                soundfonts,
+                unknown,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SFListFormat")]
 pub struct SFList {
     soundfonts: Vec<SFDescriptor>,
+
+    // Fields unknown to this version, kept when the file is rewritten
+    #[serde(flatten)]
+    unknown: Map<String, Value>,
 }
 
 impl Default for SFList {
     fn default() -> Self {
         Self {
             soundfonts: vec![SFDescriptor::default()],
+            unknown: Map::new(),
         }
     }
 }
@@ -57,8 +157,10 @@ impl SFList {
         let mut out: Vec<Arc<dyn SoundfontBase>> = Vec::new();
         for sf in self.soundfonts {
             if let Some(path) = sf.path() {
-                match SampleSoundfont::new(path, stream_params, sf.options) {
-                    Ok(sf) => out.push(Arc::new(sf)),
+                let fontex = sf.fontex.unwrap_or_default();
+                let options = fontex.apply_to_options(sf.options);
+                match SampleSoundfont::new(path, stream_params, options) {
+                    Ok(sf) => out.push(fontex.wrap_soundfont(Arc::new(sf))),
                     Err(e) => println!("Error loading soundfont: {e}"),
                 }
             }
@@ -72,3 +174,10 @@ impl ConfigPath for SFList {
         "soundfonts.json".into()
     }
 }
+
+impl ConfigVersion for SFList {
+    const MIGRATIONS: &'static [Migration] = &[
+        // 0 -> 1: Adds the version field, the soundfont list is unchanged
+        |_| {},
+    ];
+}
@@ -0,0 +1,266 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, MutexGuard,
+};
+
+/// Holds the global synth instance.
+///
+/// Every successful initialization is given a new generation number, which
+/// lets the per-thread sender clones (see `LocalSender`) find out if they are
+/// stale without locking. A generation of 0 means that there is no synth.
+pub struct SynthSlot<S> {
+    synth: Mutex<Option<S>>,
+    generation: AtomicU64,
+    last_generation: AtomicU64,
+}
+
+impl<S> SynthSlot<S> {
+    pub const fn new() -> Self {
+        Self {
+            synth: Mutex::new(None),
+            generation: AtomicU64::new(0),
+            last_generation: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<S>> {
+        // A panic while holding the lock can't leave the slot in an invalid state
+        self.synth.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.generation.load(Ordering::Acquire) != 0
+    }
+
+    /// Stores the synth created by `create`. Returns `false` without calling
+    /// `create` if a synth is already initialized, or if `create` fails.
+    pub fn init(&self, create: impl FnOnce() -> Option<S>) -> bool {
+        let mut synth = self.lock();
+        if synth.is_some() {
+            return false;
+        }
+
+        match create() {
+            Some(new) => {
+                *synth = Some(new);
+                let generation = self.last_generation.fetch_add(1, Ordering::Relaxed) + 1;
+                self.generation.store(generation, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the synth from the slot and returns it, so it can be
+    /// shut down without holding the lock.
+    pub fn terminate(&self) -> Option<S> {
+        let mut synth = self.lock();
+        self.generation.store(0, Ordering::Release);
+        synth.take()
+    }
+
+    /// Calls `f` with the initialized synth while holding the lock. Returns
+    /// `None` if no synth is initialized.
+    pub fn with<T>(&self, f: impl FnOnce(&S) -> T) -> Option<T> {
+        self.lock().as_ref().map(f)
+    }
+
+    fn clone_with<T>(&self, clone: impl FnOnce(&S) -> T) -> Option<(u64, T)> {
+        let synth = self.lock();
+        let generation = self.generation.load(Ordering::Acquire);
+        synth.as_ref().map(|s| (generation, clone(s)))
+    }
+}
+
+/// A per-thread clone of a value of the synth (the event sender), so that
+/// sending events doesn't need to lock the global synth.
+pub struct LocalSender<T> {
+    generation: u64,
+    sender: Option<T>,
+}
+
+impl<T> LocalSender<T> {
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            sender: None,
+        }
+    }
+
+    /// Returns the sender of the currently initialized synth, cloning it with
+    /// `clone` if the cached one belongs to a previous synth. Returns `None`
+    /// if no synth is initialized.
+    pub fn get<S>(&mut self, slot: &SynthSlot<S>, clone: impl FnOnce(&S) -> T) -> Option<&mut T> {
+        let generation = slot.generation.load(Ordering::Acquire);
+        if generation == 0 {
+            self.generation = 0;
+            self.sender = None;
+        } else if generation != self.generation {
+            match slot.clone_with(clone) {
+                Some((generation, sender)) => {
+                    self.generation = generation;
+                    self.sender = Some(sender);
+                }
+                None => {
+                    self.generation = 0;
+                    self.sender = None;
+                }
+            }
+        }
+
+        self.sender.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+    };
+
+    /// A fake synth which counts the events it receives. The counter is
+    /// closed when the synth is dropped, like the channel of a real sender.
+    struct MockSynth {
+        id: usize,
+        received: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl MockSynth {
+        fn close(self) -> usize {
+            self.received.lock().unwrap().take().unwrap()
+        }
+    }
+
+    struct MockSender {
+        id: usize,
+        received: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl MockSender {
+        fn send(&self) -> bool {
+            match self.received.lock().unwrap().as_mut() {
+                Some(received) => {
+                    *received += 1;
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    fn mock(id: usize) -> MockSynth {
+        MockSynth {
+            id,
+            received: Arc::new(Mutex::new(Some(0))),
+        }
+    }
+
+    fn clone_sender(s: &MockSynth) -> MockSender {
+        MockSender {
+            id: s.id,
+            received: s.received.clone(),
+        }
+    }
+
+    #[test]
+    fn test_double_init() {
+        let slot = SynthSlot::new();
+        assert!(!slot.is_initialized());
+        assert!(slot.init(|| Some(mock(0))));
+        assert!(slot.is_initialized());
+
+        let mut called = false;
+        assert!(!slot.init(|| {
+            called = true;
+            Some(mock(1))
+        }));
+        assert!(!called);
+        assert_eq!(slot.terminate().map(|s| s.id), Some(0));
+        assert!(slot.terminate().is_none());
+    }
+
+    #[test]
+    fn test_failed_init() {
+        let slot = SynthSlot::<MockSynth>::new();
+        assert!(!slot.init(|| None));
+        assert!(!slot.is_initialized());
+        assert!(slot.init(|| Some(mock(0))));
+    }
+
+    #[test]
+    fn test_with() {
+        let slot = SynthSlot::new();
+        assert!(slot.with(|s: &MockSynth| s.id).is_none());
+
+        slot.init(|| Some(mock(3)));
+        let sender = slot.with(clone_sender).unwrap();
+        assert_eq!(slot.with(|s| *s.received.lock().unwrap()), Some(Some(0)));
+        sender.send();
+        sender.send();
+        assert_eq!(slot.with(|s| *s.received.lock().unwrap()), Some(Some(2)));
+
+        slot.terminate();
+        assert!(slot.with(|s| s.id).is_none());
+    }
+
+    #[test]
+    fn test_stale_sender() {
+        let slot = SynthSlot::new();
+        let mut local = LocalSender::new();
+        assert!(local.get(&slot, clone_sender).is_none());
+
+        slot.init(|| Some(mock(0)));
+        assert_eq!(local.get(&slot, clone_sender).map(|s| s.id), Some(0));
+
+        slot.terminate();
+        assert!(local.get(&slot, clone_sender).is_none());
+
+        slot.init(|| Some(mock(1)));
+        assert_eq!(local.get(&slot, clone_sender).map(|s| s.id), Some(1));
+
+        slot.terminate();
+        slot.init(|| Some(mock(2)));
+        assert_eq!(local.get(&slot, clone_sender).map(|s| s.id), Some(2));
+    }
+
+    #[test]
+    fn test_concurrent_init_terminate_send() {
+        let slot = Arc::new(SynthSlot::new());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let slot = slot.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    let mut local = LocalSender::new();
+                    let mut sent = 0;
+                    while !stop.load(Ordering::SeqCst) {
+                        if let Some(sender) = local.get(&slot, clone_sender) {
+                            if sender.send() {
+                                sent += 1;
+                            }
+                        }
+                    }
+                    sent
+                })
+            })
+            .collect();
+
+        let mut received = 0;
+        for id in 0..200 {
+            assert!(slot.init(|| Some(mock(id))));
+            thread::yield_now();
+            let synth = slot.terminate().unwrap();
+            assert_eq!(synth.id, id);
+            received += synth.close();
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        let sent: usize = senders.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(sent, received);
+        assert!(!slot.is_initialized());
+    }
+}
@@ -18,7 +18,7 @@ fn main() {
             for _ in 0..100 {
                 synth.send_event(SynthEvent::Channel(
                     0,
-                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 0 }),
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 0, vel: 64 }),
                 ));
             }
         }
@@ -14,7 +14,10 @@ use midi_toolkit::{
     },
 };
 use xsynth_core::{
-    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent},
+    channel::{
+        ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent,
+        DEFAULT_NOTE_OFF_VELOCITY,
+    },
     soundfont::{SampleSoundfont, SoundfontBase},
 };
 use xsynth_realtime::{RealtimeSynth, SynthEvent};
@@ -108,7 +111,10 @@ fn main() {
                 Event::NoteOff(e) => {
                     sender.send_event(SynthEvent::Channel(
                         e.channel as u32,
-                        ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: e.key }),
+                        ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                            key: e.key,
+                            vel: DEFAULT_NOTE_OFF_VELOCITY,
+                        }),
                     ));
                 }
                 Event::ControlChange(e) => {
@@ -0,0 +1,73 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use xsynth_core::{
+    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent},
+    soundfont::{SampleSoundfont, SoundfontBase},
+};
+use xsynth_realtime::{RealtimeSynth, SynthEvent};
+
+/// Plays an arpeggio while moving the output through all the available
+/// devices, to check that no notes are dropped when switching.
+fn main() {
+    let args = std::env::args().collect::<Vec<String>>();
+    let Some(sf) = args
+        .get(1)
+        .cloned()
+        .or_else(|| std::env::var("XSYNTH_EXAMPLE_SF").ok())
+    else {
+        println!(
+            "Usage: {} [sfz/sf2]",
+            std::env::current_exe()
+                .unwrap_or("example".into())
+                .display()
+        );
+        return;
+    };
+
+    let devices = RealtimeSynth::list_output_devices();
+    for device in &devices {
+        println!(
+            "{}{}: {:?}",
+            device.name,
+            if device.is_default { " (default)" } else { "" },
+            device.default_stream_params
+        );
+    }
+
+    let mut synth = RealtimeSynth::open_with_all_defaults();
+    let mut sender = synth.get_sender_ref().clone();
+
+    let soundfonts: Vec<Arc<dyn SoundfontBase>> = vec![Arc::new(
+        SampleSoundfont::new(sf, synth.stream_params(), Default::default()).unwrap(),
+    )];
+    sender.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+        ChannelConfigEvent::SetSoundfonts(soundfonts),
+    )));
+
+    thread::spawn(move || loop {
+        for key in [60, 64, 67, 72, 67, 64] {
+            sender.send_event(SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 100 }),
+            ));
+            thread::sleep(Duration::from_millis(150));
+            sender.send_event(SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key, vel: 64 }),
+            ));
+        }
+    });
+
+    for device in devices.iter().cycle().take(devices.len() * 2) {
+        thread::sleep(Duration::from_secs(3));
+        match synth.switch_output_device(&device.name) {
+            Ok(()) => println!(
+                "Switched to {} at {:?}",
+                device.name,
+                synth.output_stream_params()
+            ),
+            Err(err) => println!("Failed to switch to {}: {err}", device.name),
+        }
+    }
+    thread::sleep(Duration::from_secs(3));
+}
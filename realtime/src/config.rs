@@ -1,7 +1,7 @@
 use std::ops::RangeInclusive;
 pub use xsynth_core::{
     channel::ChannelInitOptions,
-    channel_group::{SynthFormat, ThreadCount},
+    channel_group::{MpeConfig, MpeZone, SynthFormat, ThreadCount},
 };
 
 /// Options for initializing a new RealtimeSynth.
@@ -21,6 +21,24 @@ pub struct XSynthRealtimeConfig {
     /// Default: `10.0`
     pub render_window_ms: f64,
 
+    /// The number of samples the render thread renders at a time. Each render
+    /// window is rendered in blocks of this size, which are available to the
+    /// output as soon as they are ready, reducing stutter under heavy load.
+    /// If set to `None`, the whole render window is rendered at once.
+    ///
+    /// Default: `None`
+    pub internal_block_size: Option<usize>,
+
+    /// If set, the length of the buffer reader adapts to the render load
+    /// within this range in ms, starting at `render_window_ms`. It doubles
+    /// when the synthesizer can't keep up for several render windows, which
+    /// reduces stutter at the cost of latency, and halves when the load
+    /// drops. See the `AdaptiveBufferOptions` documentation of the core
+    /// crate for more information.
+    ///
+    /// Default: `None`
+    pub adaptive_render_window_ms: Option<RangeInclusive<f64>>,
+
     /// Defines the format that the synthesizer will use. See the `SynthFormat`
     /// documentation for more information.
     ///
@@ -38,6 +56,41 @@ pub struct XSynthRealtimeConfig {
     ///
     /// Default: `0..=0`
     pub ignore_range: RangeInclusive<u8>,
+
+    /// The sample rate in Hz at which the synthesizer renders. If it is
+    /// different from the sample rate of the audio output device, the audio
+    /// is resampled, which adds a small latency. If set to `None`, the
+    /// sample rate of the device is used.
+    ///
+    /// Default: `None`
+    pub render_sample_rate: Option<u32>,
+
+    /// If set to true, a DC blocking high-pass filter will be applied to the
+    /// output audio, removing the DC offset that some samples carry.
+    ///
+    /// Default: `true`
+    pub dc_blocker: bool,
+
+    /// The length in ms of the fade-in applied to the output audio when the
+    /// stream starts and after `RealtimeSynth::resume`, which avoids a pop
+    /// in the first buffer. Set to `0.0` to disable.
+    ///
+    /// Default: `2.0`
+    pub fade_in_ms: f64,
+
+    /// If set, the first 16 channels are used as an MPE zone. The member
+    /// channels are never used for percussion and start with the MPE pitch
+    /// bend range, and the controllers sent to the master channel are also
+    /// sent to them. See the `MpeConfig` documentation for more information.
+    ///
+    /// Default: `None`
+    pub mpe: Option<MpeConfig>,
+
+    /// The frequency in Hz of A4 that all the channels are tuned to.
+    /// It can be changed later with `ChannelConfigEvent::SetMasterTuning`.
+    ///
+    /// Default: `440.0`
+    pub master_tuning_hz: f32,
 }
 
 impl Default for XSynthRealtimeConfig {
@@ -45,9 +98,36 @@ impl Default for XSynthRealtimeConfig {
         Self {
             channel_init_options: Default::default(),
             render_window_ms: 10.0,
+            internal_block_size: None,
+            adaptive_render_window_ms: None,
             format: Default::default(),
             multithreading: ThreadCount::None,
             ignore_range: 0..=0,
+            render_sample_rate: None,
+            dc_blocker: true,
+            fade_in_ms: 2.0,
+            mpe: None,
+            master_tuning_hz: 440.0,
         }
     }
 }
+
+/// Preferred output stream configuration for a RealtimeSynth. Fields set to
+/// `None` use the value of the device's default configuration.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize, serde::Serialize),
+    serde(default)
+)]
+pub struct StreamConfigPreferences {
+    /// The preferred output sample rate in Hz.
+    ///
+    /// Default: `None`
+    pub sample_rate: Option<u32>,
+
+    /// The preferred number of output audio channels (1 or 2).
+    ///
+    /// Default: `None`
+    pub channels: Option<u16>,
+}
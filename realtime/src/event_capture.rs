@@ -0,0 +1,370 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use thiserror::Error;
+
+use xsynth_core::channel::{ChannelAudioEvent, ChannelEvent, ControlEvent};
+
+use crate::SynthEvent;
+
+/// The amount of events that can wait for the writer thread before
+/// new events start getting dropped.
+const CAPTURE_QUEUE_SIZE: usize = 65536;
+
+/// Ticks per quarter note of the captured MIDI file. Together with the
+/// tempo below, a tick is one millisecond.
+const CAPTURE_PPQ: u16 = 1000;
+
+/// Tempo of the captured MIDI file in microseconds per quarter note.
+const CAPTURE_TEMPO: u32 = 1_000_000;
+
+/// Errors that can be generated when starting or stopping an event capture.
+#[derive(Debug, Error)]
+pub enum EventCaptureError {
+    #[error("An event capture is already running")]
+    AlreadyCapturing,
+
+    #[error("No event capture is running")]
+    NotCapturing,
+
+    #[error("Failed to write the captured MIDI file: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// The result of a finished event capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventCaptureSummary {
+    /// The amount of MIDI events written to the file.
+    pub events: u64,
+
+    /// The amount of MIDI events which were dropped because the writer
+    /// thread could not keep up.
+    pub dropped: u64,
+}
+
+enum CaptureMessage {
+    Event {
+        time: Instant,
+        data: [u8; 3],
+        len: u8,
+    },
+    Stop,
+}
+
+/// Records the events passing through the event senders into a MIDI file.
+/// Shared between all the clones of a `RealtimeEventSender`.
+///
+/// Recording an event only checks an atomic flag and does a non-blocking
+/// send to a bounded queue, so the send path never waits on the writer.
+pub(crate) struct EventCapture {
+    active: AtomicBool,
+    dropped: AtomicU64,
+    sender: Sender<CaptureMessage>,
+    receiver: Receiver<CaptureMessage>,
+    writer: Mutex<Option<JoinHandle<io::Result<u64>>>>,
+}
+
+impl EventCapture {
+    pub fn new() -> Self {
+        let (sender, receiver) = bounded(CAPTURE_QUEUE_SIZE);
+        EventCapture {
+            active: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            sender,
+            receiver,
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Records the event if a capture is running. Events for all channels
+    /// are recorded once per channel, up to the 16 channels of a MIDI file.
+    pub fn record(&self, event: &SynthEvent, channel_count: u32) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let time = Instant::now();
+        match event {
+            SynthEvent::Channel(channel, ChannelEvent::Audio(event)) => {
+                self.record_audio(time, *channel, event);
+            }
+            SynthEvent::AllChannels(ChannelEvent::Audio(event)) => {
+                for channel in 0..channel_count {
+                    self.record_audio(time, channel, event);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record_audio(&self, time: Instant, channel: u32, event: &ChannelAudioEvent) {
+        if channel > 0xF {
+            return;
+        }
+
+        if let Some((data, len)) = audio_event_to_midi(channel as u8, event) {
+            let message = CaptureMessage::Event { time, data, len };
+            if self.sender.try_send(message).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Starts capturing the events into a type 0 MIDI file at the given path.
+    /// The file is created immediately, but only written when the capture
+    /// is stopped.
+    pub fn start(&self, path: PathBuf) -> Result<(), EventCaptureError> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_some() {
+            return Err(EventCaptureError::AlreadyCapturing);
+        }
+
+        let file = BufWriter::new(File::create(path)?);
+
+        // Events recorded by senders which raced the end of a previous capture
+        for _ in self.receiver.try_iter() {}
+        self.dropped.store(0, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let receiver = self.receiver.clone();
+        *writer = Some(
+            thread::Builder::new()
+                .name("xsynth_event_capture".to_string())
+                .spawn(move || write_capture(file, start, receiver))?,
+        );
+
+        self.active.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops the running capture and writes the MIDI file.
+    pub fn stop(&self) -> Result<EventCaptureSummary, EventCaptureError> {
+        let Some(writer) = self.writer.lock().unwrap().take() else {
+            return Err(EventCaptureError::NotCapturing);
+        };
+
+        self.active.store(false, Ordering::Relaxed);
+        self.sender.send(CaptureMessage::Stop).ok();
+
+        let events = writer.join().unwrap()?;
+        Ok(EventCaptureSummary {
+            events,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl Drop for EventCapture {
+    fn drop(&mut self) {
+        // Finish the file of a capture which was never stopped
+        self.stop().ok();
+    }
+}
+
+/// Converts a channel audio event to a MIDI message. Returns `None` for
+/// the events which have no MIDI equivalent.
+fn audio_event_to_midi(channel: u8, event: &ChannelAudioEvent) -> Option<([u8; 3], u8)> {
+    let message = match *event {
+        ChannelAudioEvent::NoteOn { key, vel } => ([0x90 | channel, key, vel], 3),
+        ChannelAudioEvent::NoteOnHighRes { key, vel } => {
+            ([0x90 | channel, key, ((vel >> 9) as u8).max(1)], 3)
+        }
+        ChannelAudioEvent::NoteOff { key, vel } => ([0x80 | channel, key, vel], 3),
+        ChannelAudioEvent::AllNotesOff => ([0xB0 | channel, 0x7B, 0], 3),
+        ChannelAudioEvent::AllNotesKilled => ([0xB0 | channel, 0x78, 0], 3),
+        ChannelAudioEvent::ResetControl => ([0xB0 | channel, 0x79, 0], 3),
+        ChannelAudioEvent::Control(ControlEvent::Raw(controller, value)) => {
+            ([0xB0 | channel, controller, value], 3)
+        }
+        ChannelAudioEvent::Control(ControlEvent::PitchBendValue(value)) => {
+            let value = ((value * 8192.0).round() as i32 + 8192).clamp(0, 0x3FFF);
+            (
+                [0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8],
+                3,
+            )
+        }
+        ChannelAudioEvent::ProgramChange(program) => ([0xC0 | channel, program, 0], 2),
+        _ => return None,
+    };
+
+    // Data bytes must not have the status bit set
+    if message.0[1..message.1].iter().any(|b| b & 0x80 != 0) {
+        return None;
+    }
+
+    Some((message.0, message.1 as u8))
+}
+
+fn write_var_len(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = [0u8; 4];
+    let mut len = 0;
+    loop {
+        bytes[len] = (value & 0x7F) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+
+    for i in (0..len).rev() {
+        let more = if i > 0 { 0x80 } else { 0 };
+        out.push(bytes[i] | more);
+    }
+}
+
+/// Receives the captured events until the stop message and writes them
+/// into a type 0 MIDI file. Returns the amount of written events.
+fn write_capture(
+    mut file: impl Write,
+    start: Instant,
+    receiver: Receiver<CaptureMessage>,
+) -> io::Result<u64> {
+    let mut track = Vec::new();
+
+    // Tempo meta event
+    track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&CAPTURE_TEMPO.to_be_bytes()[1..]);
+
+    let mut last_tick = 0;
+    let mut events = 0;
+    for message in receiver.iter() {
+        let CaptureMessage::Event { time, data, len } = message else {
+            break;
+        };
+
+        // Events sent from different threads may arrive slightly out of order
+        let tick = (time.saturating_duration_since(start).as_millis() as u64).max(last_tick);
+        write_var_len(&mut track, (tick - last_tick) as u32);
+        track.extend_from_slice(&data[..len as usize]);
+        last_tick = tick;
+        events += 1;
+    }
+
+    // End of track meta event
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    file.write_all(b"MThd")?;
+    file.write_all(&6u32.to_be_bytes())?;
+    file.write_all(&0u16.to_be_bytes())?;
+    file.write_all(&1u16.to_be_bytes())?;
+    file.write_all(&CAPTURE_PPQ.to_be_bytes())?;
+    file.write_all(b"MTrk")?;
+    file.write_all(&(track.len() as u32).to_be_bytes())?;
+    file.write_all(&track)?;
+    file.flush()?;
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi_toolkit::{events::Event, io::MIDIFile};
+
+    #[test]
+    fn test_var_len() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            write_var_len(&mut out, value);
+            out
+        };
+
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7F), [0x7F]);
+        assert_eq!(encode(0x80), [0x81, 0x00]);
+        assert_eq!(encode(0x3FFF), [0xFF, 0x7F]);
+        assert_eq!(encode(0x200000), [0x81, 0x80, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_capture_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("xsynth_capture_test_{}.mid", std::process::id()));
+
+        let capture = EventCapture::new();
+
+        // Not recorded, the capture hasn't started yet
+        let note_on = |key| {
+            SynthEvent::Channel(
+                1,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 100 }),
+            )
+        };
+        capture.record(&note_on(10), 16);
+
+        capture.start(path.clone()).unwrap();
+        assert!(matches!(
+            capture.start(path.clone()),
+            Err(EventCaptureError::AlreadyCapturing)
+        ));
+
+        capture.record(&note_on(60), 16);
+        thread::sleep(std::time::Duration::from_millis(50));
+        capture.record(
+            &SynthEvent::Channel(
+                1,
+                ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::PitchBendValue(
+                    0.5,
+                ))),
+            ),
+            16,
+        );
+        capture.record(
+            &SynthEvent::Channel(
+                1,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 60, vel: 64 }),
+            ),
+            16,
+        );
+        capture.record(
+            &SynthEvent::AllChannels(ChannelEvent::Audio(ChannelAudioEvent::AllNotesOff)),
+            2,
+        );
+
+        let summary = capture.stop().unwrap();
+        assert_eq!(
+            summary,
+            EventCaptureSummary {
+                events: 5,
+                dropped: 0
+            }
+        );
+        assert!(matches!(
+            capture.stop(),
+            Err(EventCaptureError::NotCapturing)
+        ));
+
+        let midi = MIDIFile::open(&path, None).unwrap();
+        assert_eq!(midi.format(), 0);
+        assert_eq!(midi.track_count(), 1);
+
+        let events: Vec<_> = midi.iter_track(0).map(|e| e.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        // The tempo and the five captured events
+        assert_eq!(events.len(), 6);
+        assert!(matches!(&events[1].event, Event::NoteOn(e) if e.channel == 1 && e.key == 60));
+        assert!(events[2].delta >= 50);
+        assert!(
+            matches!(&events[2].event, Event::PitchWheelChange(e) if e.channel == 1 && e.pitch == 4096)
+        );
+        assert!(matches!(&events[3].event, Event::NoteOff(e) if e.key == 60));
+        for (channel, event) in events[4..6].iter().enumerate() {
+            assert!(matches!(
+                &event.event,
+                Event::ControlChange(e) if e.channel == channel as u8 && e.controller == 0x7B
+            ));
+        }
+    }
+}
@@ -1,16 +1,23 @@
 use std::{
     collections::VecDeque,
     ops::RangeInclusive,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use crossbeam_channel::Sender;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
-use xsynth_core::channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent};
+use xsynth_core::{
+    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent},
+    channel_group::MpeConfig,
+    soundfont::SoundfontBase,
+};
 
-use crate::{util::ReadWriteAtomicU64, SynthEvent};
+use crate::{event_capture::EventCapture, util::ReadWriteAtomicU64, SynthEvent};
 
 static NPS_WINDOW_MILLISECONDS: u64 = 20;
 
@@ -70,7 +77,7 @@ impl RoughNpsTracker {
         self.check_time();
 
         loop {
-            let cutoff = self.last_time - 1000;
+            let cutoff = self.last_time.saturating_sub(1000);
             if let Some(window) = self.windows.front() {
                 if window.time < cutoff {
                     self.total_window_sum -= window.notes;
@@ -115,69 +122,229 @@ impl Drop for RoughNpsTracker {
 }
 
 fn should_send_for_vel_and_nps(vel: u8, nps: u64, max: u64) -> bool {
-    (vel as u64) * max / 127 > nps
+    (vel as u64).saturating_mul(max) / 127 > nps
+}
+
+/// The NPS limit each channel starts with.
+pub(crate) const DEFAULT_MAX_NPS: u64 = 10000;
+
+/// Per-key counters of note on events which were skipped by the NPS limiter
+/// or the ignore range, and whose note off events must therefore also be skipped.
+/// Shared between all the clones of a channel's sender.
+pub(crate) struct SkippedNotes([AtomicU64; 128]);
+
+impl SkippedNotes {
+    fn new() -> Self {
+        SkippedNotes(std::array::from_fn(|_| AtomicU64::new(0)))
+    }
+
+    fn skip(&self, key: u8) {
+        self.0[key as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decrements the counter of the key, returning `false` if there
+    /// were no skipped notes left to match.
+    fn take(&self, key: u8) -> bool {
+        self.0[key as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1))
+            .is_ok()
+    }
+
+    fn get(&self) -> [u64; 128] {
+        std::array::from_fn(|i| self.0[i].load(Ordering::Relaxed))
+    }
+
+    /// Returns the skipped notes of all the keys.
+    pub fn total(&self) -> u64 {
+        self.0
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    fn clear(&self) {
+        for count in self.0.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An event waiting in one of the queues of a channel thread.
+pub(crate) enum QueuedEvent {
+    Event(ChannelEvent),
+
+    /// Marks the position of a priority event or a `reset_synth` call in
+    /// the normal lane. When the priority event (or a marker, for the resets)
+    /// is received, the events queued in the normal lane before the matching
+    /// marker are applied first, except for the note events, which are dropped.
+    Flush,
+}
+
+/// Creates the normal and priority event lanes of a channel thread.
+pub(crate) fn channel_event_lanes() -> (EventLanes, EventLaneReceiver) {
+    let (normal, normal_receiver) = unbounded();
+    let (priority, priority_receiver) = unbounded();
+
+    (
+        EventLanes { normal, priority },
+        EventLaneReceiver {
+            normal: normal_receiver,
+            priority: priority_receiver,
+        },
+    )
+}
+
+/// The sending side of the event lanes of a channel thread.
+#[derive(Clone)]
+pub(crate) struct EventLanes {
+    normal: Sender<QueuedEvent>,
+    priority: Sender<QueuedEvent>,
+}
+
+impl EventLanes {
+    pub fn send(&self, event: ChannelEvent) {
+        self.normal.send(QueuedEvent::Event(event)).ok();
+    }
+
+    /// Sends an event which skips the queued events. The note events queued
+    /// before it are dropped, and the other events are applied before it.
+    pub fn send_priority(&self, event: ChannelEvent) {
+        self.normal.send(QueuedEvent::Flush).ok();
+        self.priority.send(QueuedEvent::Event(event)).ok();
+    }
+
+    /// Drops the note events which are still queued in the normal lane.
+    /// The marker has to be queued before the priority one, so that the
+    /// channel thread always finds it.
+    fn flush(&self) {
+        self.normal.send(QueuedEvent::Flush).ok();
+        self.priority.send(QueuedEvent::Flush).ok();
+    }
+}
+
+/// The receiving side of the event lanes of a channel thread.
+pub(crate) struct EventLaneReceiver {
+    normal: Receiver<QueuedEvent>,
+    priority: Receiver<QueuedEvent>,
+}
+
+impl EventLaneReceiver {
+    /// Passes all the queued events to `f`, with the priority lane
+    /// being drained before the normal one. Each priority event is preceded
+    /// by the events queued before it in the normal lane, without their
+    /// note events.
+    pub fn drain(&self, mut f: impl FnMut(ChannelEvent)) {
+        for event in self.priority.try_iter() {
+            self.drain_until_flush(&mut f);
+            if let QueuedEvent::Event(event) = event {
+                f(event);
+            }
+        }
+
+        for event in self.normal.try_iter() {
+            if let QueuedEvent::Event(event) = event {
+                f(event);
+            }
+        }
+    }
+
+    fn drain_until_flush(&self, f: &mut impl FnMut(ChannelEvent)) {
+        for event in self.normal.try_iter() {
+            match event {
+                QueuedEvent::Flush => break,
+                QueuedEvent::Event(ChannelEvent::Audio(
+                    ChannelAudioEvent::NoteOn { .. } | ChannelAudioEvent::NoteOff { .. },
+                )) => {}
+                QueuedEvent::Event(event) => f(event),
+            }
+        }
+    }
+}
+
+/// Returns true for the events which silence the channel, and therefore
+/// skip the queued note events through the priority lane.
+fn is_priority_event(event: &ChannelAudioEvent) -> bool {
+    matches!(
+        event,
+        ChannelAudioEvent::AllNotesOff
+            | ChannelAudioEvent::AllNotesKilled
+            | ChannelAudioEvent::Control(ControlEvent::Raw(0x78 | 0x7B, _))
+    )
 }
 
 struct EventSender {
-    sender: Sender<ChannelEvent>,
+    sender: EventLanes,
     nps: RoughNpsTracker,
     max_nps: Arc<ReadWriteAtomicU64>,
-    skipped_notes: [u64; 128],
+    skipped_notes: Arc<SkippedNotes>,
     ignore_range: RangeInclusive<u8>,
 }
 
 impl EventSender {
-    pub fn new(
-        max_nps: Arc<ReadWriteAtomicU64>,
-        sender: Sender<ChannelEvent>,
-        ignore_range: RangeInclusive<u8>,
-    ) -> Self {
+    pub fn new(max_nps: u64, sender: EventLanes, ignore_range: RangeInclusive<u8>) -> Self {
         EventSender {
             sender,
             nps: RoughNpsTracker::new(),
-            max_nps,
-            skipped_notes: [0; 128],
+            max_nps: Arc::new(ReadWriteAtomicU64::new(max_nps)),
+            skipped_notes: Arc::new(SkippedNotes::new()),
             ignore_range,
         }
     }
 
     pub fn send_audio(&mut self, event: ChannelAudioEvent) {
         match &event {
-            ChannelAudioEvent::NoteOn { vel, key } => {
+            ChannelAudioEvent::NoteOn { key, .. }
+            | ChannelAudioEvent::NoteOnHighRes { key, .. } => {
                 if *key > 127 {
                     return;
                 }
 
+                // The limits use the 7-bit velocity of high resolution note ons,
+                // which start the note even below 1 on the 7-bit scale
+                let vel = match event {
+                    ChannelAudioEvent::NoteOnHighRes { vel, .. } => ((vel >> 9) as u8).max(1),
+                    ChannelAudioEvent::NoteOn { vel, .. } => vel,
+                    _ => unreachable!(),
+                };
                 let nps = self.nps.calculate_nps();
 
-                if should_send_for_vel_and_nps(*vel, nps, self.max_nps.read())
-                    && !self.ignore_range.contains(vel)
+                if should_send_for_vel_and_nps(vel, nps, self.max_nps.read())
+                    && !self.ignore_range.contains(&vel)
                 {
-                    self.sender.send(ChannelEvent::Audio(event)).ok();
+                    self.sender.send(ChannelEvent::Audio(event));
                     self.nps.add_note();
                 } else {
-                    self.skipped_notes[*key as usize] += 1;
+                    self.skipped_notes.skip(*key);
                 }
             }
-            ChannelAudioEvent::NoteOff { key } => {
+            ChannelAudioEvent::NoteOff { key, .. } => {
                 if *key > 127 {
                     return;
                 }
 
-                if self.skipped_notes[*key as usize] > 0 {
-                    self.skipped_notes[*key as usize] -= 1;
-                } else {
-                    self.sender.send(ChannelEvent::Audio(event)).ok();
+                // A note off either matches a skipped note on, or gets forwarded
+                if !self.skipped_notes.take(*key) {
+                    self.sender.send(ChannelEvent::Audio(event));
                 }
             }
+            ChannelAudioEvent::Control(ControlEvent::Raw(0x78, 0)) => {
+                // All Sound Off from the event stream also clears the skipped notes, as the note offs
+                // of some of them may never arrive (e.g. when a player stops),
+                // and would otherwise swallow the note offs of the next notes
+                self.skipped_notes.clear();
+                self.sender.send_priority(ChannelEvent::Audio(event));
+            }
+            event if is_priority_event(event) => {
+                self.sender.send_priority(ChannelEvent::Audio(*event));
+            }
             _ => {
-                self.sender.send(ChannelEvent::Audio(event)).ok();
+                self.sender.send(ChannelEvent::Audio(event));
             }
         }
     }
 
     pub fn send_config(&mut self, event: ChannelConfigEvent) {
-        self.sender.send(ChannelEvent::Config(event)).ok();
+        self.sender.send(ChannelEvent::Config(event));
     }
 
     pub fn set_ignore_range(&mut self, ignore_range: RangeInclusive<u8>) {
@@ -189,14 +356,17 @@ impl Clone for EventSender {
     fn clone(&self) -> Self {
         EventSender {
             sender: self.sender.clone(),
+
+            // The NPS limit is shared so that it can be changed for all clones
             max_nps: self.max_nps.clone(),
 
             // Rough nps tracker is only used for very extreme spam situations,
             // so creating a new one when cloning shouldn't be an issue
             nps: RoughNpsTracker::new(),
 
-            // Skipped notes is related to nps limiter, therefore it's also not cloned
-            skipped_notes: [0; 128],
+            // Skipped notes are shared so that note offs sent through any clone
+            // are matched against the note ons skipped by the others
+            skipped_notes: self.skipped_notes.clone(),
 
             ignore_range: self.ignore_range.clone(),
         }
@@ -207,29 +377,51 @@ impl Clone for EventSender {
 #[derive(Clone)]
 pub struct RealtimeEventSender {
     senders: Vec<EventSender>,
+    mpe: Option<MpeConfig>,
+    pub(crate) capture: Arc<EventCapture>,
 }
 
 impl RealtimeEventSender {
     pub(super) fn new(
-        senders: Vec<Sender<ChannelEvent>>,
-        max_nps: Arc<ReadWriteAtomicU64>,
+        senders: Vec<EventLanes>,
+        max_nps: u64,
         ignore_range: RangeInclusive<u8>,
+        mpe: Option<MpeConfig>,
     ) -> RealtimeEventSender {
         RealtimeEventSender {
             senders: senders
                 .into_iter()
-                .map(|s| EventSender::new(max_nps.clone(), s, ignore_range.clone()))
+                .map(|s| EventSender::new(max_nps, s, ignore_range.clone()))
                 .collect(),
+            mpe,
+            capture: Arc::new(EventCapture::new()),
         }
     }
 
     /// Sends a SynthEvent to the realtime synthesizer.
     ///
     /// See the `SynthEvent` documentation for more information.
+    ///
+    /// If an MPE zone is configured, the controllers sent to its master
+    /// channel are also sent to its member channels.
     pub fn send_event(&mut self, event: SynthEvent) {
+        self.capture.record(&event, self.senders.len() as u32);
+
         match event {
             SynthEvent::Channel(channel, event) => match event {
-                ChannelEvent::Audio(e) => self.senders[channel as usize].send_audio(e),
+                ChannelEvent::Audio(e) => {
+                    self.senders[channel as usize].send_audio(e);
+
+                    if let Some(mpe) = self.mpe {
+                        if channel == mpe.master_channel() && mpe.is_mirrored(&e) {
+                            for member in mpe.member_channels() {
+                                if let Some(sender) = self.senders.get_mut(member as usize) {
+                                    sender.send_audio(e);
+                                }
+                            }
+                        }
+                    }
+                }
                 ChannelEvent::Config(e) => self.senders[channel as usize].send_config(e),
             },
             SynthEvent::AllChannels(event) => match event {
@@ -269,7 +461,10 @@ impl RealtimeEventSender {
             0x8 => {
                 self.send_event(SynthEvent::Channel(
                     channel,
-                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: val1!() }),
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                        key: val1!(),
+                        vel: val2!(),
+                    }),
                 ));
             }
             0x9 => {
@@ -311,23 +506,167 @@ impl RealtimeEventSender {
         }
     }
 
-    /// Resets all note and control change data of the realtime synthesizer.
-    pub fn reset_synth(&mut self) {
+    /// Sends a 64-bit MIDI 2.0 Universal MIDI Packet, with the first word
+    /// of the packet in the upper 32 bits.
+    ///
+    /// MIDI 2.0 channel voice messages keep the 16-bit velocity of note ons.
+    /// The controllers and pitch bend are reduced to the MIDI 1.0 resolution.
+    /// MIDI 1.0 channel voice messages in the upper word are forwarded to
+    /// `send_event_u32`, and all the other message types are ignored.
+    /// Groups are ignored, so channels are addressed as in MIDI 1.0.
+    pub fn send_event_u64(&mut self, event: u64) {
+        let word0 = (event >> 32) as u32;
+        let data = event as u32;
+
+        let message_type = word0 >> 28;
+        let code = (word0 >> 20) & 0xF;
+        let channel = (word0 >> 16) & 0xF;
+        let index = (word0 >> 8) as u8 & 0x7F;
+
+        if message_type == 0x2 {
+            let status = (word0 >> 16) & 0xFF;
+            let val1 = (word0 >> 8) & 0xFF;
+            let val2 = word0 & 0xFF;
+            self.send_event_u32(status | val1 << 8 | val2 << 16);
+            return;
+        } else if message_type != 0x4 {
+            return;
+        }
+
+        let event = match code {
+            0x8 => ChannelAudioEvent::NoteOff {
+                key: index,
+                vel: (data >> 25) as u8,
+            },
+            0x9 => ChannelAudioEvent::NoteOnHighRes {
+                key: index,
+                vel: (data >> 16) as u16,
+            },
+            0xB => ChannelAudioEvent::Control(ControlEvent::Raw(index, (data >> 25) as u8)),
+            0xC => ChannelAudioEvent::ProgramChange((data >> 24) as u8 & 0x7F),
+            0xE => {
+                let value = (data as i64 - 0x8000_0000) as f64 / 0x8000_0000u32 as f64;
+                ChannelAudioEvent::Control(ControlEvent::PitchBendValue(value as f32))
+            }
+            _ => return,
+        };
+        self.send_event(SynthEvent::Channel(channel, ChannelEvent::Audio(event)));
+    }
+
+    /// Releases the notes of all channels of the realtime synthesizer, so
+    /// that they fade out with their release envelope. The events which are
+    /// still queued are applied before it.
+    pub fn release_all(&mut self) {
         self.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
-            ChannelAudioEvent::AllNotesKilled,
+            ChannelAudioEvent::AllNotesOff,
         )));
+    }
 
-        for sender in &mut self.senders {
-            for i in 0..128 {
-                sender.skipped_notes[i] = 0;
-            }
+    /// Kills the voices of all channels of the realtime synthesizer, which
+    /// are silenced within the kill fade time if `fade_out_killing` is
+    /// enabled, or immediately otherwise.
+    ///
+    /// Like `reset_synth`, the kill skips the events which are still queued,
+    /// and the note events queued before it are dropped.
+    ///
+    /// Unlike CC120 (All Sound Off), the skipped note counters are kept.
+    pub fn kill_all(&mut self) {
+        for sender in self.senders.iter() {
+            sender.sender.flush();
         }
 
+        self.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+            ChannelAudioEvent::AllNotesKilled,
+        )));
+    }
+
+    /// Resets all note and control change data of the realtime synthesizer.
+    ///
+    /// The reset skips the events which are still queued, and the note
+    /// events queued before it are dropped, so the output is silenced
+    /// on the next render even if the event queue is flooded.
+    ///
+    /// The skipped note counters are kept, because the note off events of
+    /// the skipped notes may still arrive after the reset. Use
+    /// `clear_skipped_notes` if they are known to never arrive.
+    pub fn reset_synth(&mut self) {
+        self.kill_all();
+
         self.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
             ChannelAudioEvent::ResetControl,
         )));
     }
 
+    /// Returns the number of note on events per key of the given channel
+    /// which were skipped (due to the NPS limiter or the ignore range)
+    /// and are still waiting for their matching note off event.
+    pub fn skipped_notes(&self, channel: u32) -> [u64; 128] {
+        self.senders[channel as usize].skipped_notes.get()
+    }
+
+    /// Returns the skipped note counters of each channel, for the statistics.
+    pub(crate) fn skipped_note_counters(&self) -> Vec<Arc<SkippedNotes>> {
+        self.senders
+            .iter()
+            .map(|sender| sender.skipped_notes.clone())
+            .collect()
+    }
+
+    /// Clears the skipped note counters of all channels. The next note off
+    /// events will be forwarded to the synthesizer regardless.
+    pub fn clear_skipped_notes(&mut self) {
+        for sender in self.senders.iter() {
+            sender.skipped_notes.clear();
+        }
+    }
+
+    /// Inserts a soundfont at the given position of the soundfont list of
+    /// every channel, without affecting the notes that are already playing.
+    ///
+    /// See `ChannelConfigEvent::AddSoundfont` for more information.
+    pub fn add_soundfont(&mut self, sf: Arc<dyn SoundfontBase>, position: usize) {
+        self.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::AddSoundfont { sf, position },
+        )));
+    }
+
+    /// Removes the soundfont at the given position of the soundfont list
+    /// of every channel.
+    ///
+    /// See `ChannelConfigEvent::RemoveSoundfont` for more information.
+    pub fn remove_soundfont(&mut self, position: usize) {
+        self.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::RemoveSoundfont { position },
+        )));
+    }
+
+    /// Sets the NPS limit of the given channel, above which the note ons
+    /// are skipped depending on their velocity. The limit is shared with
+    /// the clones of the sender. `u64::MAX` disables the limit, for example
+    /// for a melody channel which should never drop notes.
+    ///
+    /// The NPS is tracked separately for each channel, so a flooded channel
+    /// doesn't cause the notes of the others to be skipped. The channels
+    /// start with a limit of 10000.
+    pub fn set_max_nps_for_channel(&mut self, channel: u32, max_nps: u64) {
+        if let Some(sender) = self.senders.get(channel as usize) {
+            sender.max_nps.write(max_nps);
+        }
+    }
+
+    /// Sets the layer limit of the given channel, where one layer is one
+    /// voice per key. `None` means unlimited layers.
+    ///
+    /// See `ChannelConfigEvent::SetLayerCount` for more information.
+    pub fn set_layer_count_for_channel(&mut self, channel: u32, layers: Option<usize>) {
+        if (channel as usize) < self.senders.len() {
+            self.send_event(SynthEvent::Channel(
+                channel,
+                ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(layers)),
+            ));
+        }
+    }
+
     /// Changes the range of velocities that will be ignored for the
     /// specific sender instance.
     pub fn set_ignore_range(&mut self, ignore_range: RangeInclusive<u8>) {
@@ -336,3 +675,428 @@ impl RealtimeEventSender {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tiny LCG, so the test is reproducible without extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+    }
+
+    #[test]
+    fn test_skipped_note_off_after_reset() {
+        let (tx, rx) = channel_event_lanes();
+        let max_nps = 10000;
+        let mut sender = RealtimeEventSender::new(vec![tx], max_nps, 1..=10, None);
+
+        // A note on in the ignore range, whose note off arrives after a reset
+        // and after a new note on the same key
+        sender.send_event_u32(0x90 | 60 << 8 | 5 << 16);
+        sender.reset_synth();
+        assert_eq!(sender.skipped_notes(0)[60], 1);
+        sender.send_event_u32(0x90 | 60 << 8 | 100 << 16);
+        rx.drain(|_| {});
+
+        // The stale note off is swallowed instead of releasing the new note
+        sender.send_event_u32(0x80 | 60 << 8 | 64 << 16);
+        assert_eq!(sender.skipped_notes(0)[60], 0);
+        let mut events = Vec::new();
+        rx.drain(|event| events.push(event));
+        assert!(events.is_empty());
+
+        // The note off of the new note is forwarded
+        sender.send_event_u32(0x80 | 60 << 8 | 64 << 16);
+        rx.drain(|event| events.push(event));
+        assert!(matches!(
+            events[..],
+            [ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                key: 60,
+                ..
+            })]
+        ));
+    }
+
+    #[test]
+    fn test_skipped_notes_stress() {
+        let (tx, rx) = channel_event_lanes();
+        let max_nps = 50;
+        let mut sender = RealtimeEventSender::new(vec![tx], max_nps, 0..=0, None);
+        let mut other = sender.clone();
+
+        let mut rng = Rng(1);
+        let mut held: Vec<u8> = Vec::new();
+        // The passed note ons still waiting for their note off. The resets
+        // kill the voices, but the note offs of the held notes arrive later.
+        let mut pending = [0i64; 128];
+        let mut total = 0;
+
+        let drain = |pending: &mut [i64; 128]| {
+            rx.drain(|event| match event {
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, .. }) => {
+                    pending[key as usize] += 1
+                }
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key, .. }) => {
+                    // A note off without a passed note on would end another voice
+                    assert!(pending[key as usize] > 0, "unmatched note off for {key}");
+                    pending[key as usize] -= 1;
+                }
+                _ => {}
+            });
+        };
+
+        while total < 100_000 || !held.is_empty() {
+            let sender = if rng.next().is_multiple_of(2) {
+                &mut sender
+            } else {
+                &mut other
+            };
+
+            if total < 100_000 && (held.is_empty() || rng.next().is_multiple_of(2)) {
+                let key = (rng.next() % 128) as u8;
+                let vel = (rng.next() % 127 + 1) as u8;
+                sender.send_event(SynthEvent::Channel(
+                    0,
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel }),
+                ));
+                held.push(key);
+                total += 1;
+            } else {
+                let i = rng.next() as usize % held.len();
+                let key = held.swap_remove(i);
+                sender.send_event(SynthEvent::Channel(
+                    0,
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key, vel: 64 }),
+                ));
+            }
+
+            if rng.next().is_multiple_of(10_000) {
+                sender.reset_synth();
+            }
+
+            drain(&mut pending);
+        }
+
+        assert_eq!(sender.skipped_notes(0), [0; 128]);
+        assert_eq!(pending, [0; 128]);
+    }
+
+    #[test]
+    fn test_all_sound_off_clears_skipped_notes() {
+        let (tx, rx) = channel_event_lanes();
+        let max_nps = 10000;
+        let mut sender = RealtimeEventSender::new(vec![tx], max_nps, 1..=10, None);
+
+        // The raw CC120 and the control event behave the same
+        let all_sound_off: [&dyn Fn(&mut RealtimeEventSender); 2] = [
+            &|sender| sender.send_event_u32(0xB0 | 0x78 << 8),
+            &|sender| {
+                sender.send_event(SynthEvent::Channel(
+                    0,
+                    ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(0x78, 0))),
+                ))
+            },
+        ];
+        for all_sound_off in all_sound_off {
+            // A note on in the ignore range whose note off never arrives
+            sender.send_event_u32(0x90 | 60 << 8 | 5 << 16);
+            assert_eq!(sender.skipped_notes(0)[60], 1);
+
+            all_sound_off(&mut sender);
+            assert_eq!(sender.skipped_notes(0), [0; 128]);
+
+            // The next note off of the key isn't swallowed
+            sender.send_event_u32(0x90 | 60 << 8 | 100 << 16);
+            sender.send_event_u32(0x80 | 60 << 8 | 64 << 16);
+            let mut events = Vec::new();
+            rx.drain(|event| events.push(event));
+            assert!(matches!(
+                events[..],
+                [
+                    ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(0x78, 0))),
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key: 60, vel: 100 }),
+                    ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 60, vel: 64 }),
+                ]
+            ));
+        }
+
+        // CC123, AllNotesKilled and kill_all don't clear them
+        sender.send_event_u32(0x90 | 60 << 8 | 5 << 16);
+        sender.send_event_u32(0xB0 | 0x7B << 8);
+        sender.send_event(SynthEvent::Channel(
+            0,
+            ChannelEvent::Audio(ChannelAudioEvent::AllNotesKilled),
+        ));
+        sender.kill_all();
+        assert_eq!(sender.skipped_notes(0)[60], 1);
+    }
+
+    /// Creates a channel with a soundfont playing a constant looped sample.
+    fn new_test_channel() -> xsynth_core::channel::VoiceChannel {
+        use xsynth_core::{
+            channel::VoiceChannel,
+            soundfont::{test_utils::TestSoundfontDir, SampleSoundfont},
+            AudioStreamParams, ChannelCount,
+        };
+
+        let dir = TestSoundfontDir::new("realtime_sender");
+        dir.write_wav("sample.wav", 48000, &[0.5; 4800]);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav ampeg_attack=0 ampeg_release=1 \
+             loop_mode=loop_continuous loop_start=0 loop_end=4799\n",
+        );
+
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let sf = SampleSoundfont::new_sfz(sfz, stream_params, Default::default()).unwrap();
+
+        let mut channel = VoiceChannel::new(Default::default(), stream_params, None);
+        channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetSoundfonts(
+            vec![Arc::new(sf)],
+        )));
+        channel
+    }
+
+    #[test]
+    fn test_reset_synth_flooded_queue() {
+        use xsynth_core::AudioPipe;
+
+        let mut channel = new_test_channel();
+        let (tx, rx) = channel_event_lanes();
+        let max_nps = 1 << 40;
+        let mut sender = RealtimeEventSender::new(vec![tx], max_nps, 0..=0, None);
+        let note_on = |key| {
+            SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 127 }),
+            )
+        };
+
+        // One render window of 10ms
+        let mut out = vec![0.0; 960];
+        sender.send_event(note_on(60));
+        rx.drain(|e| channel.process_event(e));
+        channel.read_samples(&mut out);
+        assert!(out[958] > 0.0);
+
+        for i in 0..1_000_000 {
+            sender.send_event(note_on((i % 128) as u8));
+        }
+        sender.reset_synth();
+        sender.send_event(SynthEvent::Channel(
+            0,
+            ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(0x07, 64))),
+        ));
+
+        rx.drain(|e| channel.process_event(e));
+        channel.read_samples(&mut out);
+        assert!(out.iter().skip(480).all(|&s| s == 0.0));
+        assert_eq!(channel.get_channel_stats().voice_count(), 0);
+
+        // Events sent after the reset are kept
+        sender.send_event(note_on(62));
+        rx.drain(|e| channel.process_event(e));
+        channel.read_samples(&mut out);
+        assert!(out[958] > 0.0);
+    }
+
+    #[test]
+    fn test_priority_events_keep_order() {
+        use xsynth_core::{channel::VoiceChannel, AudioPipe, AudioStreamParams, ChannelCount};
+
+        let mut channel = new_test_channel();
+        let (tx, rx) = channel_event_lanes();
+        let mut sender = RealtimeEventSender::new(vec![tx], DEFAULT_MAX_NPS, 0..=0, None);
+        let send = |sender: &mut RealtimeEventSender, event| {
+            sender.send_event(SynthEvent::Channel(0, ChannelEvent::Audio(event)));
+        };
+
+        // A note on queued before All Notes Off must not outlive it
+        send(&mut sender, ChannelAudioEvent::NoteOn { key: 60, vel: 127 });
+        send(&mut sender, ChannelAudioEvent::AllNotesOff);
+        rx.drain(|e| channel.process_event(e));
+        let mut out = vec![0.0; 960];
+        channel.read_samples(&mut out);
+        assert_eq!(channel.get_channel_stats().voice_count(), 0);
+
+        send(&mut sender, ChannelAudioEvent::NoteOn { key: 60, vel: 127 });
+        send(
+            &mut sender,
+            ChannelAudioEvent::Control(ControlEvent::Raw(0x7B, 0)),
+        );
+        rx.drain(|e| channel.process_event(e));
+        channel.read_samples(&mut out);
+        assert_eq!(channel.get_channel_stats().voice_count(), 0);
+
+        // The controllers sent before a reset don't undo it
+        send(
+            &mut sender,
+            ChannelAudioEvent::Control(ControlEvent::Raw(0x07, 10)),
+        );
+        send(&mut sender, ChannelAudioEvent::ResetControl);
+        rx.drain(|e| channel.process_event(e));
+        let stream_params = AudioStreamParams::new(48000, ChannelCount::Stereo);
+        let fresh = VoiceChannel::new(Default::default(), stream_params, None);
+        assert_eq!(channel.controller_state(), fresh.controller_state());
+    }
+
+    #[test]
+    fn test_per_channel_limits() {
+        let (lanes, receivers): (Vec<_>, Vec<_>) = (0..16).map(|_| channel_event_lanes()).unzip();
+        let mut sender = RealtimeEventSender::new(lanes, 10000, 0..=0, None);
+        sender.set_max_nps_for_channel(0, u64::MAX);
+        sender.set_max_nps_for_channel(1, 100);
+        sender.set_layer_count_for_channel(1, Some(2));
+
+        // The other clones share the limits
+        let mut other = sender.clone();
+        let note = |channel, key, on| {
+            let event = if on {
+                ChannelAudioEvent::NoteOn { key, vel: 127 }
+            } else {
+                ChannelAudioEvent::NoteOff { key, vel: 64 }
+            };
+            SynthEvent::Channel(channel, ChannelEvent::Audio(event))
+        };
+
+        // Flood the second channel while playing a melody on the first one,
+        // which is flooded too at times
+        for i in 0..100_000 {
+            let key = (i % 128) as u8;
+            other.send_event(note(1, key, true));
+            other.send_event(note(1, key, false));
+            if i % 10 == 0 {
+                sender.send_event(note(0, key, true));
+                sender.send_event(note(0, key, false));
+            }
+        }
+
+        let count = |rx: &EventLaneReceiver| {
+            let mut ons = 0;
+            let mut offs = 0;
+            let mut layers = None;
+            rx.drain(|event| match event {
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { .. }) => ons += 1,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOff { .. }) => offs += 1,
+                ChannelEvent::Config(ChannelConfigEvent::SetLayerCount(count)) => {
+                    layers = Some(count)
+                }
+                _ => {}
+            });
+            (ons, offs, layers)
+        };
+
+        // The melody channel drops nothing, while the skipped notes of the
+        // flooded one have their note offs skipped as well
+        assert_eq!(count(&receivers[0]), (10_000, 10_000, None));
+        let (ons, offs, layers) = count(&receivers[1]);
+        assert!(ons < 1000, "{ons}");
+        assert_eq!(ons, offs);
+        assert_eq!(layers, Some(Some(2)));
+        assert_eq!(sender.skipped_notes(0), [0; 128]);
+        assert_eq!(sender.skipped_notes(1), [0; 128]);
+        assert!(receivers[2..].iter().all(|rx| count(rx) == (0, 0, None)));
+
+        // Held notes skipped by the limiter are counted
+        for key in 0..10 {
+            other.send_event(note(1, key, true));
+        }
+        let skipped: u64 = sender.skipped_note_counters()[1].total();
+        assert_eq!(skipped, 10 - count(&receivers[1]).0);
+        assert!(skipped > 0);
+    }
+
+    #[test]
+    fn test_mpe_master_controllers_mirrored() {
+        let (lanes, receivers): (Vec<_>, Vec<_>) = (0..16).map(|_| channel_event_lanes()).unzip();
+        let max_nps = 10000;
+        let mpe = MpeConfig {
+            member_channels: 3,
+            ..Default::default()
+        };
+        let mut sender = RealtimeEventSender::new(lanes, max_nps, 0..=0, Some(mpe));
+        let control = |cc, value| {
+            SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(cc, value))),
+            )
+        };
+
+        // The sustain is mirrored, but the pitch bend range RPN is not
+        sender.send_event(control(0x40, 127));
+        sender.send_event(control(0x65, 0));
+        sender.send_event(control(0x64, 0));
+        sender.send_event(control(0x06, 12));
+
+        let counts: Vec<usize> = receivers
+            .iter()
+            .map(|rx| {
+                let mut count = 0;
+                rx.drain(|_| count += 1);
+                count
+            })
+            .collect();
+        assert_eq!(counts[0], 4);
+        assert_eq!(counts[1..4], [1, 1, 1]);
+        assert!(counts[4..].iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn test_send_event_u64() {
+        let (lanes, receivers): (Vec<_>, Vec<_>) = (0..16).map(|_| channel_event_lanes()).unzip();
+        let max_nps = 10000;
+        let mut sender = RealtimeEventSender::new(lanes, max_nps, 0..=0, None);
+
+        // MIDI 2.0 note on and note off on channel 2
+        sender.send_event_u64(0x4292_3C00_ABCD_0000);
+        sender.send_event_u64(0x4282_3C00_8000_0000);
+        // MIDI 1.0 note on and note off wrapped in the upper word
+        sender.send_event_u64(0x2092_3C7F_0000_0000);
+        sender.send_event_u64(0x2082_3C10_0000_0000);
+
+        let mut events = Vec::new();
+        receivers[2].drain(|e| events.push(e));
+        assert!(matches!(
+            events[0],
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOnHighRes {
+                key: 60,
+                vel: 0xABCD
+            })
+        ));
+        assert!(matches!(
+            events[1],
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 60, vel: 64 })
+        ));
+        assert!(matches!(
+            events[2],
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key: 60, vel: 127 })
+        ));
+        assert!(matches!(
+            events[3],
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key: 60, vel: 16 })
+        ));
+        assert_eq!(events.len(), 4);
+
+        // Quiet MIDI 2.0 note ons aren't skipped by the default ignore range
+        sender.send_event_u64(0x4293_3C00_0100_0000);
+        let mut events = Vec::new();
+        receivers[3].drain(|e| events.push(e));
+        assert!(matches!(
+            events[..],
+            [ChannelEvent::Audio(ChannelAudioEvent::NoteOnHighRes {
+                key: 60,
+                vel: 0x0100
+            })]
+        ));
+        assert_eq!(sender.skipped_notes(3), [0; 128]);
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A short linear fade-in applied to the interleaved output audio, which
+/// avoids the pop of the first buffer when the stream starts or resumes.
+pub(crate) struct FadeIn {
+    length: usize,
+    channels: usize,
+    position: usize,
+    restart: Arc<AtomicBool>,
+}
+
+impl FadeIn {
+    /// Creates a fade-in of the given length in frames. A length of 0
+    /// disables the fade.
+    pub fn new(length: usize, channels: usize) -> Self {
+        Self {
+            length,
+            channels,
+            position: 0,
+            restart: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns a flag which restarts the fade on the next processed buffer
+    /// when set, so it can be triggered from outside the audio callback.
+    pub fn restart_flag(&self) -> Arc<AtomicBool> {
+        self.restart.clone()
+    }
+
+    pub fn process(&mut self, out: &mut [f32]) {
+        if self.restart.swap(false, Ordering::Relaxed) {
+            self.position = 0;
+        }
+
+        if self.position >= self.length {
+            return;
+        }
+
+        for frame in out.chunks_mut(self.channels) {
+            if self.position >= self.length {
+                break;
+            }
+
+            let gain = self.position as f32 / self.length as f32;
+            for s in frame {
+                *s *= gain;
+            }
+            self.position += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade_in_ramp() {
+        let mut fade = FadeIn::new(4, 2);
+
+        let mut out = vec![0.5; 12];
+        fade.process(&mut out);
+        assert_eq!(
+            out,
+            [0.0, 0.0, 0.125, 0.125, 0.25, 0.25, 0.375, 0.375, 0.5, 0.5, 0.5, 0.5]
+        );
+
+        // Already faded in
+        let mut out = vec![0.5; 4];
+        fade.process(&mut out);
+        assert_eq!(out, [0.5; 4]);
+
+        // The ramp continues across buffers after a restart
+        fade.restart_flag().store(true, Ordering::Relaxed);
+        let mut out = vec![1.0; 4];
+        fade.process(&mut out);
+        assert_eq!(out, [0.0, 0.0, 0.25, 0.25]);
+        let mut out = vec![1.0; 6];
+        fade.process(&mut out);
+        assert_eq!(out, [0.5, 0.5, 0.75, 0.75, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_fade_in_disabled() {
+        let mut fade = FadeIn::new(0, 1);
+        let mut out = vec![0.5; 4];
+        fade.process(&mut out);
+        assert_eq!(out, [0.5; 4]);
+    }
+}
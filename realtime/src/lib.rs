@@ -1,6 +1,8 @@
 mod config;
 pub use config::*;
 
+mod fade_in;
+mod stream_config;
 mod util;
 
 pub use xsynth_core::channel_group::SynthEvent;
@@ -10,3 +12,9 @@ pub use realtime_synth::*;
 
 mod event_senders;
 pub use event_senders::*;
+
+mod event_capture;
+pub use event_capture::{EventCaptureError, EventCaptureSummary};
+
+mod offline_bounce;
+pub use offline_bounce::*;
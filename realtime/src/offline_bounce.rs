@@ -0,0 +1,59 @@
+use xsynth_core::{
+    channel_group::{ChannelGroup, SynthEvent},
+    AudioPipe, AudioStreamParams,
+};
+
+/// An offline synthesizer with the configuration of a realtime synthesizer,
+/// created with `RealtimeSynth::freeze_to_offline`.
+///
+/// It renders as fast as it's read from, independently of the realtime
+/// synthesizer, which keeps playing. The channels start with the soundfonts,
+/// layer count, controllers and program of the realtime channels, but
+/// without their active voices.
+///
+/// The events are sent directly to the channels, so the ignore range and
+/// the NPS limits of the realtime event sender don't apply. The output is
+/// also read without the volume limiter of the realtime output, and it isn't
+/// measured by `RealtimeSynthStatsReader::output_levels`, so loud passages
+/// may clip unless they are limited after rendering.
+pub struct OfflineBounceHandle {
+    group: ChannelGroup,
+}
+
+impl OfflineBounceHandle {
+    pub(crate) fn new(group: ChannelGroup) -> Self {
+        Self { group }
+    }
+
+    /// Sends a SynthEvent, applied at the start of the next read.
+    ///
+    /// See the `SynthEvent` documentation for more information.
+    pub fn send_event(&mut self, event: SynthEvent) {
+        self.group.send_event(event);
+    }
+
+    /// Sends a SynthEvent to be applied at the given sample offset (per audio
+    /// channel) from the start of the next read.
+    ///
+    /// See `ChannelGroup::send_event_at_offset` for more information.
+    pub fn send_event_at_offset(&mut self, event: SynthEvent, sample_offset: usize) {
+        self.group.send_event_at_offset(event, sample_offset);
+    }
+
+    /// Renders the next samples into the buffer, interleaved by the audio
+    /// channels of `stream_params`.
+    pub fn read_samples(&mut self, to: &mut [f32]) {
+        self.group.read_samples(to);
+    }
+
+    /// Returns the stream parameters of the rendered audio, which are the
+    /// render parameters of the realtime synthesizer.
+    pub fn stream_params(&self) -> AudioStreamParams {
+        *self.group.stream_params()
+    }
+
+    /// Returns the underlying ChannelGroup.
+    pub fn into_channel_group(self) -> ChannelGroup {
+        self.group
+    }
+}
@@ -1,45 +1,133 @@
 use std::{
     collections::VecDeque,
+    ops::RangeInclusive,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc, Mutex,
     },
     thread::{self},
+    time::Instant,
 };
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, PauseStreamError, PlayStreamError, SizedSample, Stream, SupportedStreamConfig,
+    BuildStreamError, DefaultStreamConfigError, Device, PauseStreamError, PlayStreamError,
+    SampleFormat, SizedSample, Stream, SupportedStreamConfig,
 };
-use crossbeam_channel::{bounded, unbounded};
+use crossbeam_channel::{bounded, Sender};
+use thiserror::Error;
 
 use xsynth_core::{
-    buffered_renderer::{BufferedRenderer, BufferedRendererStatsReader},
-    channel::{ChannelConfigEvent, ChannelEvent, VoiceChannel},
-    channel_group::SynthFormat,
-    effects::VolumeLimiter,
+    buffered_renderer::{AdaptiveBufferOptions, BufferedRenderer, BufferedRendererStatsReader},
+    channel::{
+        ChannelConfigEvent, ChannelControllerState, ChannelEvent, KeyOccupancy, VoiceChannel,
+        VoiceChannelStatsReader,
+    },
+    channel_group::{ChannelGroup, ChannelGroupConfig, ParallelismOptions, SynthFormat},
+    effects::{DcBlocker, LevelMeter, OutputLevels, VolumeLimiter},
     helpers::{prepapre_cache_vec, sum_simd},
-    AudioPipe, AudioStreamParams, FunctionAudioPipe,
+    AudioPipe, AudioStreamParams, ChannelCount, FunctionAudioPipe, ResamplingPipe,
 };
 
 use crate::{
-    util::ReadWriteAtomicU64, RealtimeEventSender, SynthEvent, ThreadCount, XSynthRealtimeConfig,
+    event_senders::{channel_event_lanes, SkippedNotes, DEFAULT_MAX_NPS},
+    fade_in::FadeIn,
+    stream_config::select_stream_config,
+    EventCaptureError, EventCaptureSummary, OfflineBounceHandle, RealtimeEventSender,
+    StreamConfigPreferences, SynthEvent, ThreadCount, XSynthRealtimeConfig,
 };
 
+/// Errors that can be generated when opening a realtime synthesizer.
+#[derive(Debug, Error)]
+pub enum RealtimeSynthError {
+    #[error("No audio output device was found")]
+    NoOutputDevice,
+
+    #[error("Failed to get the default output config: {0}")]
+    DefaultStreamConfig(#[from] DefaultStreamConfigError),
+
+    #[error("Unsupported sample format: {0}")]
+    UnsupportedSampleFormat(SampleFormat),
+
+    #[error("Unsupported channel count: {0}, only mono and stereo are supported")]
+    UnsupportedChannelCount(u16),
+
+    #[error("Failed to create the thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("Failed to build the output stream: {0}")]
+    BuildStream(#[from] BuildStreamError),
+
+    #[error("Failed to start the output stream: {0}")]
+    PlayStream(#[from] PlayStreamError),
+
+    #[error("The output device \"{0}\" was not found")]
+    DeviceNotFound(String),
+
+    #[error("The output device has {0} channels, which doesn't match the synthesizer")]
+    ChannelCountMismatch(u16),
+}
+
+/// Information about an audio output device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// The name of the device, used to select it with `switch_output_device`.
+    pub name: String,
+
+    /// Whether the device is the default output device of the system.
+    pub is_default: bool,
+
+    /// The stream parameters of the default configuration of the device,
+    /// or `None` if they couldn't be queried or aren't supported.
+    pub default_stream_params: Option<AudioStreamParams>,
+}
+
 /// Holds the statistics for an instance of RealtimeSynth.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct RealtimeSynthStats {
     voice_count: Arc<AtomicU64>,
+    meter: LevelMeter,
+    channels: Arc<Vec<ChannelStats>>,
 }
 
 impl RealtimeSynthStats {
-    pub fn new() -> RealtimeSynthStats {
+    pub fn new(channels: Vec<ChannelStats>) -> RealtimeSynthStats {
         RealtimeSynthStats {
             voice_count: Arc::new(AtomicU64::new(0)),
+            meter: LevelMeter::new(),
+            channels: Arc::new(channels),
         }
     }
 }
 
+/// Holds the statistics for a MIDI channel of RealtimeSynth.
+struct ChannelStats {
+    voices: VoiceChannelStatsReader,
+    // The render load of the last window, stored as the bits of an f64
+    render_load: Arc<AtomicU64>,
+    skipped_notes: Arc<SkippedNotes>,
+}
+
+/// The statistics of a single MIDI channel of RealtimeSynth.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RealtimeChannelStats {
+    /// The active voice count of the channel.
+    pub voice_count: u64,
+
+    /// The time the channel spent rendering the last render window,
+    /// as a fraction (0 to 1) of the length of the window. This is an
+    /// approximation of the cost of the channel, as the channels are
+    /// rendered in parallel.
+    pub render_load: f64,
+
+    /// The note ons of the channel which were skipped by the NPS limiter or
+    /// the ignore range, and are still waiting for their note off.
+    ///
+    /// See `RealtimeEventSender::skipped_notes` for the counts of each key.
+    pub skipped_notes: u64,
+}
+
 /// Reads the statistics of an instance of RealtimeSynth in a usable way.
 pub struct RealtimeSynthStatsReader {
     buffered_stats: BufferedRendererStatsReader,
@@ -62,6 +150,57 @@ impl RealtimeSynthStatsReader {
         self.stats.voice_count.load(Ordering::Relaxed)
     }
 
+    /// Returns the peak and RMS levels of the last rendered window.
+    ///
+    /// See the OutputLevels documentation for more information.
+    pub fn output_levels(&self) -> OutputLevels {
+        self.stats.meter.levels()
+    }
+
+    /// Returns the active voice count of each soundfont, in the order of the
+    /// soundfont list, summed across all the MIDI channels.
+    ///
+    /// See `VoiceChannelStatsReader::voices_per_soundfont` for more information.
+    pub fn voices_per_soundfont(&self) -> Vec<u64> {
+        let mut counts = Vec::new();
+        for channel in self.stats.channels.iter() {
+            let voices = channel.voices.voices_per_soundfont();
+            if counts.len() < voices.len() {
+                counts.resize(voices.len(), 0);
+            }
+            for (count, voices) in counts.iter_mut().zip(voices) {
+                *count += voices;
+            }
+        }
+        counts
+    }
+
+    /// Returns the statistics of the given MIDI channel, or `None` if
+    /// the channel doesn't exist.
+    ///
+    /// See the RealtimeChannelStats documentation for more information.
+    pub fn channel(&self, channel: usize) -> Option<RealtimeChannelStats> {
+        self.stats
+            .channels
+            .get(channel)
+            .map(|c| RealtimeChannelStats {
+                voice_count: c.voices.voice_count(),
+                render_load: f64::from_bits(c.render_load.load(Ordering::Relaxed)),
+                skipped_notes: c.skipped_notes.total(),
+            })
+    }
+
+    /// Returns the keys of the given MIDI channel which have voices, or
+    /// `None` if the channel doesn't exist.
+    ///
+    /// See `VoiceChannelStatsReader::key_occupancy` for more information.
+    pub fn key_occupancy(&self, channel: usize) -> Option<KeyOccupancy> {
+        self.stats
+            .channels
+            .get(channel)
+            .map(|c| c.voices.key_occupancy())
+    }
+
     /// Returns the statistics of the buffered renderer used.
     ///
     /// See the BufferedRendererStatsReader documentation for more information.
@@ -76,12 +215,149 @@ struct SendSyncStream(Stream);
 unsafe impl Sync for SendSyncStream {}
 unsafe impl Send for SendSyncStream {}
 
-struct RealtimeSynthThreadSharedData {
+/// The audio rendered by the channel threads. It is shared so that it
+/// outlives the output when the output device is switched.
+#[derive(Clone)]
+struct SharedRenderPipe {
+    pipe: Arc<Mutex<dyn AudioPipe + Send>>,
+    stream_params: AudioStreamParams,
+}
+
+impl AudioPipe for SharedRenderPipe {
+    fn stream_params(&self) -> &'_ AudioStreamParams {
+        &self.stream_params
+    }
+
+    fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+        self.pipe.lock().unwrap().read_samples_unchecked(to);
+    }
+}
+
+/// The audio output of the synthesizer, reading from the render pipe.
+struct RealtimeOutput {
     buffered_renderer: Arc<Mutex<BufferedRenderer>>,
 
     stream: SendSyncStream,
 
+    fade_in_restart: Arc<AtomicBool>,
+
+    stream_params: AudioStreamParams,
+}
+
+impl RealtimeOutput {
+    /// Builds and starts an output stream on the given device, resampling
+    /// the render pipe to the sample rate of the device.
+    fn open(
+        device: &Device,
+        stream_config: SupportedStreamConfig,
+        render: SharedRenderPipe,
+        render_window_ms: f64,
+        block_size: Option<usize>,
+        fade_in_ms: f64,
+        adaptive_ms: Option<RangeInclusive<f64>>,
+    ) -> Result<Self, RealtimeSynthError> {
+        let stream_params = validate_stream_config(&stream_config)?;
+        if stream_params.channels != render.stream_params.channels {
+            return Err(RealtimeSynthError::ChannelCountMismatch(
+                stream_config.channels(),
+            ));
+        }
+        let sample_rate = stream_params.sample_rate;
+
+        let buffered = Arc::new(Mutex::new(BufferedRenderer::new(
+            ResamplingPipe::new(render, sample_rate),
+            stream_params,
+            calculate_render_size(sample_rate, render_window_ms),
+        )));
+        if let Some(block_size) = block_size {
+            buffered.lock().unwrap().set_block_size(block_size);
+        }
+        if let Some(range) = adaptive_ms {
+            buffered
+                .lock()
+                .unwrap()
+                .set_adaptive(Some(AdaptiveBufferOptions::new(
+                    calculate_render_size(sample_rate, *range.start()),
+                    calculate_render_size(sample_rate, *range.end()),
+                )));
+        }
+
+        let fade_in = FadeIn::new(
+            calculate_render_size(sample_rate, fade_in_ms),
+            stream_params.channels.count() as usize,
+        );
+        let fade_in_restart = fade_in.restart_flag();
+
+        fn build_stream<T: SizedSample + ConvertSample>(
+            device: &Device,
+            stream_config: SupportedStreamConfig,
+            buffered: Arc<Mutex<BufferedRenderer>>,
+            mut fade_in: FadeIn,
+        ) -> Result<Stream, BuildStreamError> {
+            let err_fn = |err| eprintln!("an error occurred on stream: {err}");
+            let mut output_vec = Vec::new();
+
+            let mut limiter = VolumeLimiter::new(stream_config.channels());
+
+            device.build_output_stream(
+                &stream_config.into(),
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    output_vec.resize(data.len(), 0.0);
+                    // Starved reads are filled with silence instead of blocking the device
+                    buffered.lock().unwrap().try_read(&mut output_vec);
+                    fade_in.process(&mut output_vec);
+                    for (i, s) in limiter.limit_iter(output_vec.drain(0..)).enumerate() {
+                        data[i] = ConvertSample::from_f32(s);
+                    }
+                },
+                err_fn,
+                None,
+            )
+        }
+
+        let stream = match stream_config.sample_format() {
+            SampleFormat::F32 => {
+                build_stream::<f32>(device, stream_config, buffered.clone(), fade_in)
+            }
+            SampleFormat::I16 => {
+                build_stream::<i16>(device, stream_config, buffered.clone(), fade_in)
+            }
+            SampleFormat::U16 => {
+                build_stream::<u16>(device, stream_config, buffered.clone(), fade_in)
+            }
+            // Already rejected by validate_stream_config
+            format => unreachable!("unsupported sample format {format}"),
+        }?;
+        stream.play()?;
+
+        Ok(Self {
+            buffered_renderer: buffered,
+            stream: SendSyncStream(stream),
+            fade_in_restart,
+            stream_params,
+        })
+    }
+}
+
+/// A request to the thread of a realtime channel.
+enum ChannelCommand {
+    /// Renders the channel into the buffer and sends it back to the mixer
+    Render(Vec<f32>),
+
+    /// Sends back the config events and controller state of the channel,
+    /// after applying the events sent so far
+    Snapshot(Sender<(Vec<ChannelConfigEvent>, ChannelControllerState)>),
+}
+
+struct RealtimeSynthThreadSharedData {
+    output: RealtimeOutput,
+
     event_senders: RealtimeEventSender,
+
+    channel_commands: Vec<Sender<ChannelCommand>>,
+
+    // Dropped last, which stops the channel threads
+    render: SharedRenderPipe,
 }
 
 /// A realtime MIDI synthesizer using an audio device for output.
@@ -92,44 +368,117 @@ pub struct RealtimeSynth {
     stats: RealtimeSynthStats,
 
     stream_params: AudioStreamParams,
+    fade_in_ms: f64,
+    adaptive_render_window_ms: Option<RangeInclusive<f64>>,
+
+    /// The config of the ChannelGroup created by `freeze_to_offline`
+    offline_config: ChannelGroupConfig,
 }
 
 impl RealtimeSynth {
     /// Initializes a new realtime synthesizer using the default config and
     /// the default audio output.
+    ///
+    /// Panics if the synthesizer cannot be opened. See `try_open_with_all_defaults`
+    /// for a non-panicking alternative.
     pub fn open_with_all_defaults() -> Self {
-        let host = cpal::default_host();
-
-        let device = host
-            .default_output_device()
-            .expect("failed to find output device");
-        println!("Output device: {}", device.name().unwrap());
-
-        let stream_config = device.default_output_config().unwrap();
+        Self::try_open_with_all_defaults().unwrap_or_else(|err| panic!("{err}"))
+    }
 
-        RealtimeSynth::open(Default::default(), &device, stream_config)
+    /// Initializes a new realtime synthesizer using the default config and
+    /// the default audio output, returning an error if it cannot be opened.
+    pub fn try_open_with_all_defaults() -> Result<Self, RealtimeSynthError> {
+        Self::try_open_with_default_output(Default::default())
     }
 
     /// Initializes as new realtime synthesizer using a given config and
     /// the default audio output.
     ///
+    /// Panics if the synthesizer cannot be opened. See `try_open_with_default_output`
+    /// for a non-panicking alternative.
+    ///
     /// See the `XSynthRealtimeConfig` documentation for the available options.
     pub fn open_with_default_output(config: XSynthRealtimeConfig) -> Self {
+        Self::try_open_with_default_output(config).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Initializes as new realtime synthesizer using a given config and
+    /// the default audio output, returning an error if it cannot be opened.
+    ///
+    /// See the `XSynthRealtimeConfig` documentation for the available options.
+    pub fn try_open_with_default_output(
+        config: XSynthRealtimeConfig,
+    ) -> Result<Self, RealtimeSynthError> {
         let host = cpal::default_host();
 
         let device = host
             .default_output_device()
-            .expect("failed to find output device");
-        println!("Output device: {}", device.name().unwrap());
+            .ok_or(RealtimeSynthError::NoOutputDevice)?;
+        if let Ok(name) = device.name() {
+            println!("Output device: {name}");
+        }
 
-        let stream_config = device.default_output_config().unwrap();
+        let stream_config = device.default_output_config()?;
 
-        RealtimeSynth::open(config, &device, stream_config)
+        RealtimeSynth::try_open(config, &device, stream_config)
+    }
+
+    /// Initializes a new realtime synthesizer using a given config, a
+    /// specified audio output device and the preferred output stream configuration.
+    ///
+    /// If the device doesn't support the preferred configuration, the default
+    /// configuration of the device will be used instead.
+    ///
+    /// Panics if the synthesizer cannot be opened. See `try_open_with_config_preferences`
+    /// for a non-panicking alternative.
+    ///
+    /// See the `XSynthRealtimeConfig` and `StreamConfigPreferences` documentation
+    /// for the available options.
+    pub fn open_with_config_preferences(
+        config: XSynthRealtimeConfig,
+        device: &Device,
+        preferences: StreamConfigPreferences,
+    ) -> Self {
+        Self::try_open_with_config_preferences(config, device, preferences)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Initializes a new realtime synthesizer using a given config, a
+    /// specified audio output device and the preferred output stream configuration,
+    /// returning an error if it cannot be opened.
+    ///
+    /// See `open_with_config_preferences` for more information.
+    pub fn try_open_with_config_preferences(
+        config: XSynthRealtimeConfig,
+        device: &Device,
+        preferences: StreamConfigPreferences,
+    ) -> Result<Self, RealtimeSynthError> {
+        let default_config = device.default_output_config()?;
+
+        let stream_config = device
+            .supported_output_configs()
+            .ok()
+            .and_then(|supported| select_stream_config(supported, &default_config, &preferences));
+
+        let stream_config = match stream_config {
+            Some(stream_config) => stream_config,
+            None => {
+                eprintln!(
+                    "Output device does not support {preferences:?}, using the default config"
+                );
+                default_config
+            }
+        };
+
+        RealtimeSynth::try_open(config, device, stream_config)
     }
 
     /// Initializes a new realtime synthesizer using a given config and a
     /// specified audio output device.
     ///
+    /// Panics if the synthesizer cannot be opened. See `try_open` for a
+    /// non-panicking alternative.
+    ///
     /// See the `XSynthRealtimeConfig` documentation for the available options.
     /// See the `cpal` crate documentation for the `device` and `stream_config` parameters.
     pub fn open(
@@ -137,43 +486,68 @@ impl RealtimeSynth {
         device: &Device,
         stream_config: SupportedStreamConfig,
     ) -> Self {
+        Self::try_open(config, device, stream_config).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Initializes a new realtime synthesizer using a given config and a
+    /// specified audio output device, returning an error if the stream
+    /// configuration is unsupported or the output stream cannot be started.
+    ///
+    /// See the `XSynthRealtimeConfig` documentation for the available options.
+    /// See the `cpal` crate documentation for the `device` and `stream_config` parameters.
+    pub fn try_open(
+        config: XSynthRealtimeConfig,
+        device: &Device,
+        stream_config: SupportedStreamConfig,
+    ) -> Result<Self, RealtimeSynthError> {
+        let output_stream_params = validate_stream_config(&stream_config)?;
+        let sample_rate = output_stream_params.sample_rate;
+        let stream_params = AudioStreamParams::new(
+            config.render_sample_rate.unwrap_or(sample_rate),
+            output_stream_params.channels,
+        );
+
         let mut channel_stats = Vec::new();
+        let mut stats_channels = Vec::new();
         let mut senders = Vec::new();
         let mut command_senders = Vec::new();
 
-        let sample_rate = stream_config.sample_rate().0;
-        let stream_params = AudioStreamParams::new(sample_rate, stream_config.channels().into());
-
         let pool = match config.multithreading {
             ThreadCount::None => None,
-            ThreadCount::Auto => Some(Arc::new(rayon::ThreadPoolBuilder::new().build().unwrap())),
+            ThreadCount::Auto => Some(Arc::new(rayon::ThreadPoolBuilder::new().build()?)),
             ThreadCount::Manual(threads) => Some(Arc::new(
                 rayon::ThreadPoolBuilder::new()
                     .num_threads(threads)
-                    .build()
-                    .unwrap(),
+                    .build()?,
             )),
         };
 
-        let channel_count = match config.format {
-            SynthFormat::Midi => 16,
-            SynthFormat::Custom { channels } => channels,
-        };
+        let channel_count = config.format.channel_count();
 
         let (output_sender, output_receiver) = bounded::<Vec<f32>>(channel_count as usize);
 
         let mut thread_handles = vec![];
 
-        for _ in 0u32..channel_count {
-            let mut channel =
-                VoiceChannel::new(config.channel_init_options, stream_params, pool.clone());
+        for i in 0u32..channel_count {
+            let options = match &config.mpe {
+                Some(mpe) => mpe.channel_init_options(i, config.channel_init_options),
+                None => config.channel_init_options,
+            };
+            let mut channel = VoiceChannel::new(options, stream_params, pool.clone());
+            channel.process_event(ChannelEvent::Config(ChannelConfigEvent::SetMasterTuning(
+                config.master_tuning_hz,
+            )));
             let stats = channel.get_channel_stats();
             channel_stats.push(stats);
+            let render_load = Arc::new(AtomicU64::new(0.0f64.to_bits()));
+            stats_channels.push((channel.get_channel_stats(), render_load.clone()));
+            let samples_per_second =
+                stream_params.sample_rate as f64 * stream_params.channels.count() as f64;
 
-            let (event_sender, event_receiver) = unbounded();
+            let (event_sender, event_receiver) = channel_event_lanes();
             senders.push(event_sender);
 
-            let (command_sender, command_receiver) = bounded::<Vec<f32>>(1);
+            let (command_sender, command_receiver) = bounded::<ChannelCommand>(1);
 
             command_senders.push(command_sender);
 
@@ -181,13 +555,26 @@ impl RealtimeSynth {
             let join_handle = thread::Builder::new()
                 .name("xsynth_channel_handler".to_string())
                 .spawn(move || loop {
-                    channel.push_events_iter(event_receiver.try_iter());
+                    event_receiver.drain(|e| channel.process_event(e));
                     let mut vec = match command_receiver.recv() {
-                        Ok(vec) => vec,
+                        Ok(ChannelCommand::Render(vec)) => vec,
+                        Ok(ChannelCommand::Snapshot(reply)) => {
+                            event_receiver.drain(|e| channel.process_event(e));
+                            reply
+                                .send((channel.config_events(), channel.controller_state()))
+                                .ok();
+                            continue;
+                        }
                         Err(_) => break,
                     };
-                    channel.push_events_iter(event_receiver.try_iter());
+                    event_receiver.drain(|e| channel.process_event(e));
+                    let start = Instant::now();
                     channel.read_samples(&mut vec);
+                    let window = vec.len() as f64 / samples_per_second;
+                    if window > 0.0 {
+                        let load = start.elapsed().as_secs_f64() / window;
+                        render_load.store(load.to_bits(), Ordering::Relaxed);
+                    }
                     output_sender.send(vec).unwrap();
                 })
                 .unwrap();
@@ -195,12 +582,15 @@ impl RealtimeSynth {
             thread_handles.push(join_handle);
         }
 
-        if config.format == SynthFormat::Midi {
-            senders[9]
-                .send(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
-                    true,
-                )))
-                .unwrap();
+        // MPE member channels are never used for percussion
+        let percussion = config.format == SynthFormat::Midi
+            && !config
+                .mpe
+                .is_some_and(|mpe| mpe.member_channels().contains(&9));
+        if percussion {
+            senders[9].send(ChannelEvent::Config(ChannelConfigEvent::SetPercussionMode(
+                true,
+            )));
         }
 
         let mut vec_cache: VecDeque<Vec<f32>> = VecDeque::new();
@@ -208,16 +598,34 @@ impl RealtimeSynth {
             vec_cache.push_front(Vec::new());
         }
 
-        let stats = RealtimeSynthStats::new();
+        let event_senders =
+            RealtimeEventSender::new(senders, DEFAULT_MAX_NPS, config.ignore_range, config.mpe);
+        let stats_channels = stats_channels
+            .into_iter()
+            .zip(event_senders.skipped_note_counters())
+            .map(|((voices, render_load), skipped_notes)| ChannelStats {
+                voices,
+                render_load,
+                skipped_notes,
+            })
+            .collect();
+        let stats = RealtimeSynthStats::new(stats_channels);
 
         let total_voice_count = stats.voice_count.clone();
+        let meter = stats.meter.clone();
+        let meter_channels = stream_params.channels.count() as usize;
 
+        let mut dc_blocker = config
+            .dc_blocker
+            .then(|| DcBlocker::new(stream_params.channels.count(), stream_params.sample_rate));
+
+        let channel_commands = command_senders.clone();
         let render = FunctionAudioPipe::new(stream_params, move |out| {
             for sender in command_senders.iter() {
                 let mut buf = vec_cache.pop_front().unwrap();
                 prepapre_cache_vec(&mut buf, out.len(), 0.0);
 
-                sender.send(buf).unwrap();
+                sender.send(ChannelCommand::Render(buf)).unwrap();
             }
 
             for _ in 0..channel_count {
@@ -226,65 +634,72 @@ impl RealtimeSynth {
                 vec_cache.push_front(buf);
             }
 
+            if let Some(dc_blocker) = &mut dc_blocker {
+                dc_blocker.process(out);
+            }
+            meter.measure(out, meter_channels);
+
             let total_voices = channel_stats.iter().map(|c| c.voice_count()).sum();
             total_voice_count.store(total_voices, Ordering::SeqCst);
         });
 
-        let buffered = Arc::new(Mutex::new(BufferedRenderer::new(
-            render,
+        let render = SharedRenderPipe {
+            pipe: Arc::new(Mutex::new(render)),
             stream_params,
-            calculate_render_size(sample_rate, config.render_window_ms),
-        )));
-
-        fn build_stream<T: SizedSample + ConvertSample>(
-            device: &Device,
-            stream_config: SupportedStreamConfig,
-            buffered: Arc<Mutex<BufferedRenderer>>,
-        ) -> Stream {
-            let err_fn = |err| eprintln!("an error occurred on stream: {err}");
-            let mut output_vec = Vec::new();
-
-            let mut limiter = VolumeLimiter::new(stream_config.channels());
-
-            device
-                .build_output_stream(
-                    &stream_config.into(),
-                    move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                        output_vec.resize(data.len(), 0.0);
-                        buffered.lock().unwrap().read(&mut output_vec);
-                        for (i, s) in limiter.limit_iter(output_vec.drain(0..)).enumerate() {
-                            data[i] = ConvertSample::from_f32(s);
-                        }
-                    },
-                    err_fn,
-                    None,
-                )
-                .unwrap()
-        }
-
-        let stream = match stream_config.sample_format() {
-            cpal::SampleFormat::F32 => build_stream::<f32>(device, stream_config, buffered.clone()),
-            cpal::SampleFormat::I16 => build_stream::<i16>(device, stream_config, buffered.clone()),
-            cpal::SampleFormat::U16 => build_stream::<u16>(device, stream_config, buffered.clone()),
-            _ => panic!("unsupported sample format"), // I hate when crates use #[non_exhaustive]
         };
 
-        stream.play().unwrap();
+        let output = RealtimeOutput::open(
+            device,
+            stream_config,
+            render.clone(),
+            config.render_window_ms,
+            config.internal_block_size,
+            config.fade_in_ms,
+            config.adaptive_render_window_ms.clone(),
+        );
+        let output = match output {
+            Ok(output) => output,
+            Err(err) => {
+                // Drops the command senders, which lets the channel threads exit
+                drop(channel_commands);
+                drop(render);
+                for handle in thread_handles {
+                    handle.join().unwrap();
+                }
+                return Err(err);
+            }
+        };
 
-        let max_nps = Arc::new(ReadWriteAtomicU64::new(10000));
+        let offline_config = ChannelGroupConfig {
+            channel_init_options: config.channel_init_options,
+            format: config.format,
+            audio_params: stream_params,
+            parallelism: ParallelismOptions {
+                channel: ThreadCount::Auto,
+                key: config.multithreading,
+            },
+            dc_blocker: config.dc_blocker,
+            // The percussion mode of the channels is copied from the realtime ones
+            multi_port_percussion: false,
+            mpe: config.mpe,
+            master_tuning_hz: config.master_tuning_hz,
+        };
 
-        Self {
+        Ok(Self {
             data: Some(RealtimeSynthThreadSharedData {
-                buffered_renderer: buffered,
-
-                event_senders: RealtimeEventSender::new(senders, max_nps, config.ignore_range),
-                stream: SendSyncStream(stream),
+                output,
+                event_senders,
+                channel_commands,
+                render,
             }),
             join_handles: thread_handles,
 
             stats,
             stream_params,
-        }
+            fade_in_ms: config.fade_in_ms,
+            adaptive_render_window_ms: config.adaptive_render_window_ms,
+            offline_config,
+        })
     }
 
     /// Sends a SynthEvent to the realtime synthesizer.
@@ -301,6 +716,60 @@ impl RealtimeSynth {
         data.event_senders.send_event_u32(event);
     }
 
+    /// Sends a 64-bit MIDI 2.0 Universal MIDI Packet to the realtime synthesizer.
+    ///
+    /// See `RealtimeEventSender::send_event_u64` for the supported messages.
+    pub fn send_event_u64(&mut self, event: u64) {
+        let data = self.data.as_mut().unwrap();
+        data.event_senders.send_event_u64(event);
+    }
+
+    /// Starts recording the MIDI events sent to the synthesizer into a type 0
+    /// MIDI file at the given path, including the events sent through any
+    /// clone of the event sender. The file is written when the capture is
+    /// stopped with `stop_event_capture`, or when the synthesizer and all the
+    /// clones of its event sender are dropped.
+    ///
+    /// The delta times are derived from the time the events were sent.
+    /// Config events and the audio events without a MIDI equivalent (eg.
+    /// `ControlEvent::FineTune`) are not recorded.
+    pub fn start_event_capture(&self, path: PathBuf) -> Result<(), EventCaptureError> {
+        let data = self.data.as_ref().unwrap();
+        data.event_senders.capture.start(path)
+    }
+
+    /// Stops the running event capture and writes the MIDI file.
+    ///
+    /// Events are dropped instead of blocking the senders if the writer thread
+    /// falls behind. The amount of dropped events is returned in the summary.
+    pub fn stop_event_capture(&self) -> Result<EventCaptureSummary, EventCaptureError> {
+        let data = self.data.as_ref().unwrap();
+        data.event_senders.capture.stop()
+    }
+
+    /// Creates an offline synthesizer with the current configuration of the
+    /// channels, which renders independently of the realtime one and as
+    /// fast as it's read from, for example to bounce a passage to a file.
+    ///
+    /// The soundfonts, layer count, percussion mode, program, controllers and
+    /// other channel config (see `VoiceChannel::config_events`) are captured
+    /// from each channel after the events sent to it so far. The active
+    /// voices are not transferred, so the offline synthesizer starts silent.
+    ///
+    /// See the `OfflineBounceHandle` documentation for more information.
+    pub fn freeze_to_offline(&self) -> OfflineBounceHandle {
+        let data = self.data.as_ref().unwrap();
+
+        let states = data.channel_commands.iter().map(|sender| {
+            let (reply, snapshot) = bounded(1);
+            sender.send(ChannelCommand::Snapshot(reply)).unwrap();
+            snapshot.recv().unwrap()
+        });
+        let group = ChannelGroup::new_with_channel_states(self.offline_config.clone(), states);
+
+        OfflineBounceHandle::new(group)
+    }
+
     /// Returns a reference to the event sender of the realtime synthesizer.
     /// This can be used to clone the sender so it can be passed in threads.
     ///
@@ -328,34 +797,161 @@ impl RealtimeSynth {
     /// on how to use.
     pub fn get_stats(&self) -> RealtimeSynthStatsReader {
         let data = self.data.as_ref().unwrap();
-        let buffered_stats = data.buffered_renderer.lock().unwrap().get_buffer_stats();
+        let buffered_stats = data
+            .output
+            .buffered_renderer
+            .lock()
+            .unwrap()
+            .get_buffer_stats();
 
         RealtimeSynthStatsReader::new(self.stats.clone(), buffered_stats)
     }
 
-    /// Returns the stream parameters of the audio output device.
+    /// Returns the stream parameters the synthesizer renders at. Soundfonts
+    /// used by the synthesizer should be loaded with these parameters.
+    ///
+    /// These are the same as the parameters of the audio output device,
+    /// unless `render_sample_rate` is set in the config.
     pub fn stream_params(&self) -> AudioStreamParams {
         self.stream_params
     }
 
+    /// Returns the stream parameters of the audio output device.
+    pub fn output_stream_params(&self) -> AudioStreamParams {
+        let data = self.data.as_ref().unwrap();
+        data.output.stream_params
+    }
+
+    /// Lists the audio output devices of the default host.
+    pub fn list_output_devices() -> Vec<DeviceInfo> {
+        let host = cpal::default_host();
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+
+        let Ok(devices) = host.output_devices() else {
+            return Vec::new();
+        };
+        devices
+            .filter_map(|device| {
+                let name = device.name().ok()?;
+                let default_stream_params = device
+                    .default_output_config()
+                    .ok()
+                    .and_then(|config| validate_stream_config(&config).ok());
+                Some(DeviceInfo {
+                    is_default: default_name.as_ref() == Some(&name),
+                    name,
+                    default_stream_params,
+                })
+            })
+            .collect()
+    }
+
+    /// Moves the audio output to the device with the given name, as listed
+    /// by `list_output_devices`, using its default configuration with the
+    /// channel count of the synthesizer.
+    ///
+    /// See `switch_output` for more information.
+    pub fn switch_output_device(&mut self, name: &str) -> Result<(), RealtimeSynthError> {
+        let device = cpal::default_host()
+            .output_devices()
+            .ok()
+            .and_then(|mut devices| devices.find(|d| d.name().is_ok_and(|n| n == name)))
+            .ok_or_else(|| RealtimeSynthError::DeviceNotFound(name.to_string()))?;
+
+        let default_config = device.default_output_config()?;
+        let preferences = StreamConfigPreferences {
+            sample_rate: None,
+            channels: Some(self.stream_params.channels.count()),
+        };
+        let stream_config = device
+            .supported_output_configs()
+            .ok()
+            .and_then(|supported| select_stream_config(supported, &default_config, &preferences))
+            .unwrap_or(default_config);
+
+        self.switch_output(&device, stream_config)
+    }
+
+    /// Moves the audio output to a specified audio output device, keeping
+    /// the state of the MIDI channels and the event senders. Only the output
+    /// stream and its buffered renderer are rebuilt.
+    ///
+    /// If the sample rate of the device differs from the current one, the
+    /// rendered audio is resampled to it, so the synthesizer keeps rendering
+    /// with the same `stream_params` and the loaded soundfonts stay usable.
+    /// The channel count can't change, devices with a different channel
+    /// count are rejected with `ChannelCountMismatch`. Reopen the
+    /// synthesizer to use them.
+    ///
+    /// If the new output can't be opened, the previous one keeps playing.
+    /// Statistics readers returned by `get_stats` before the switch don't
+    /// report the buffer statistics of the new output.
+    pub fn switch_output(
+        &mut self,
+        device: &Device,
+        stream_config: SupportedStreamConfig,
+    ) -> Result<(), RealtimeSynthError> {
+        let data = self.data.as_mut().unwrap();
+
+        // Keep the buffer length of the current output, which may have been
+        // changed with set_buffer
+        let old = &data.output;
+        let buffer_stats = old.buffered_renderer.lock().unwrap().get_buffer_stats();
+        let render_window_ms =
+            buffer_stats.render_size() as f64 * 1000.0 / old.stream_params.sample_rate as f64;
+        let block_size = Some(buffer_stats.block_size()).filter(|&size| size != 0);
+
+        // A paused output stops reading, so its renderer stops taking audio
+        // from the channels shortly after
+        old.stream.0.pause().ok();
+
+        let output = RealtimeOutput::open(
+            device,
+            stream_config,
+            data.render.clone(),
+            render_window_ms,
+            block_size,
+            self.fade_in_ms,
+            self.adaptive_render_window_ms.clone(),
+        );
+        match output {
+            Ok(output) => {
+                data.output = output;
+                Ok(())
+            }
+            Err(err) => {
+                data.output.stream.0.play().ok();
+                Err(err)
+            }
+        }
+    }
+
     /// Pauses the playback of the audio output device.
     pub fn pause(&mut self) -> Result<(), PauseStreamError> {
         let data = self.data.as_mut().unwrap();
-        data.stream.0.pause()
+        data.output.stream.0.pause()
     }
 
-    /// Resumes the playback of the audio output device.
+    /// Resumes the playback of the audio output device, fading the audio
+    /// in as configured by `fade_in_ms` in the config.
     pub fn resume(&mut self) -> Result<(), PlayStreamError> {
         let data = self.data.as_mut().unwrap();
-        data.stream.0.play()
+        data.output.fade_in_restart.store(true, Ordering::Relaxed);
+        data.output.stream.0.play()
     }
 
-    /// Changes the length of the buffer reader.
+    /// Changes the length of the buffer reader. If the adaptive buffer is
+    /// enabled, see `adaptive_render_window_ms` in the config, the length
+    /// keeps adapting to the load from the new value.
     pub fn set_buffer(&self, render_window_ms: f64) {
         let data = self.data.as_ref().unwrap();
-        let sample_rate = self.stream_params.sample_rate;
+        let sample_rate = data.output.stream_params.sample_rate;
         let size = calculate_render_size(sample_rate, render_window_ms);
-        data.buffered_renderer.lock().unwrap().set_render_size(size);
+        data.output
+            .buffered_renderer
+            .lock()
+            .unwrap()
+            .set_render_size(size);
     }
 }
 
@@ -392,6 +988,176 @@ impl ConvertSample for u16 {
     }
 }
 
+/// Checks that the stream configuration can be used by the synthesizer
+/// and returns the matching audio stream parameters.
+fn validate_stream_config(
+    stream_config: &SupportedStreamConfig,
+) -> Result<AudioStreamParams, RealtimeSynthError> {
+    match stream_config.sample_format() {
+        SampleFormat::F32 | SampleFormat::I16 | SampleFormat::U16 => {}
+        format => return Err(RealtimeSynthError::UnsupportedSampleFormat(format)),
+    }
+
+    let channels = ChannelCount::from_count(stream_config.channels()).ok_or(
+        RealtimeSynthError::UnsupportedChannelCount(stream_config.channels()),
+    )?;
+
+    Ok(AudioStreamParams::new(
+        stream_config.sample_rate().0,
+        channels,
+    ))
+}
+
 fn calculate_render_size(sample_rate: u32, buffer_ms: f64) -> usize {
     (sample_rate as f64 * buffer_ms / 1000.0) as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{SampleRate, SupportedBufferSize};
+
+    fn stream_config(channels: u16, format: SampleFormat) -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            channels,
+            SampleRate(48000),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    #[test]
+    fn test_validate_stream_config() {
+        let params = validate_stream_config(&stream_config(2, SampleFormat::F32)).unwrap();
+        assert_eq!(params, AudioStreamParams::new(48000, ChannelCount::Stereo));
+
+        assert!(matches!(
+            validate_stream_config(&stream_config(2, SampleFormat::I8)),
+            Err(RealtimeSynthError::UnsupportedSampleFormat(
+                SampleFormat::I8
+            ))
+        ));
+        assert!(matches!(
+            validate_stream_config(&stream_config(6, SampleFormat::F32)),
+            Err(RealtimeSynthError::UnsupportedChannelCount(6))
+        ));
+    }
+
+    #[test]
+    fn test_try_open_bogus_config() {
+        // Requires an audio output device to be present
+        let Some(device) = cpal::default_host().default_output_device() else {
+            return;
+        };
+
+        let result = RealtimeSynth::try_open(
+            Default::default(),
+            &device,
+            stream_config(6, SampleFormat::I8),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_switch_output() {
+        // Requires an audio output device which can be opened
+        let Some(device) = cpal::default_host().default_output_device() else {
+            return;
+        };
+        let Ok(mut synth) = RealtimeSynth::try_open_with_default_output(Default::default()) else {
+            return;
+        };
+        let params = synth.output_stream_params();
+
+        assert!(matches!(
+            synth.switch_output_device("xsynth missing device"),
+            Err(RealtimeSynthError::DeviceNotFound(_))
+        ));
+
+        let mono = SupportedStreamConfig::new(
+            1,
+            SampleRate(params.sample_rate),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        if params.channels == ChannelCount::Stereo {
+            assert!(matches!(
+                synth.switch_output(&device, mono),
+                Err(RealtimeSynthError::ChannelCountMismatch(1))
+            ));
+        }
+
+        synth
+            .switch_output(&device, device.default_output_config().unwrap())
+            .unwrap();
+        assert_eq!(synth.output_stream_params(), params);
+    }
+
+    #[test]
+    fn test_freeze_to_offline() {
+        use xsynth_core::{
+            channel::{ChannelAudioEvent, ControlEvent},
+            soundfont::{test_utils::TestSoundfontDir, SampleSoundfont, SoundfontBase},
+        };
+
+        // Requires an audio output device which can be opened, the copy of the
+        // channel state is also tested without one in `ChannelGroup`
+        let Ok(mut synth) = RealtimeSynth::try_open_with_default_output(Default::default()) else {
+            return;
+        };
+        let params = synth.stream_params();
+
+        let dir = TestSoundfontDir::new("freeze_to_offline");
+        let sine: Vec<f32> = (0..params.sample_rate)
+            .map(|i| (i as f32 * std::f32::consts::TAU / 100.0).sin() * 0.5)
+            .collect();
+        dir.write_wav("sample.wav", params.sample_rate, &sine);
+        let sfz = dir.write_sfz(
+            "test.sfz",
+            "<region> sample=sample.wav ampeg_attack=0 ampeg_release=0.01\n",
+        );
+        let sf: Arc<dyn SoundfontBase> =
+            Arc::new(SampleSoundfont::new(sfz, params, Default::default()).unwrap());
+
+        let config = |event| SynthEvent::Channel(0, ChannelEvent::Config(event));
+        synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+        )));
+        synth.send_event(config(ChannelConfigEvent::SetLayerCount(Some(1))));
+        synth.send_event(config(ChannelConfigEvent::SetTranspose(3)));
+        synth.send_event(SynthEvent::Channel(
+            0,
+            ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(0x0A, 20))),
+        ));
+
+        // Holding the render pipe keeps the output from rendering in between
+        let pipe = synth.data.as_ref().unwrap().render.pipe.clone();
+        let mut pipe = pipe.lock().unwrap();
+        let mut offline = synth.freeze_to_offline();
+        assert_eq!(offline.stream_params(), params);
+
+        let note = |event| SynthEvent::Channel(0, ChannelEvent::Audio(event));
+        let events = [
+            note(ChannelAudioEvent::NoteOn { key: 60, vel: 100 }),
+            note(ChannelAudioEvent::NoteOn { key: 64, vel: 80 }),
+            note(ChannelAudioEvent::NoteOff { key: 60, vel: 64 }),
+        ];
+        let block = 2400 * params.channels.count() as usize;
+
+        let mut realtime = vec![0.0; block * events.len()];
+        let mut bounced = vec![0.0; block * events.len()];
+        for (i, event) in events.into_iter().enumerate() {
+            synth.send_event(event.clone());
+            pipe.read_samples(&mut realtime[i * block..(i + 1) * block]);
+            offline.send_event(event);
+            offline.read_samples(&mut bounced[i * block..(i + 1) * block]);
+        }
+        drop(pipe);
+
+        assert!(realtime.iter().any(|s| s.abs() > 0.1));
+        assert!(realtime
+            .iter()
+            .zip(&bounced)
+            .all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+}
@@ -0,0 +1,121 @@
+use cpal::{SampleFormat, SampleRate, SupportedStreamConfig, SupportedStreamConfigRange};
+
+use crate::StreamConfigPreferences;
+
+/// Selects the supported stream configuration matching the preferences.
+/// Values not specified in the preferences are taken from the default
+/// configuration, and the default sample format is preferred when available.
+///
+/// Returns `None` if none of the supported configurations match.
+pub(crate) fn select_stream_config(
+    supported: impl IntoIterator<Item = SupportedStreamConfigRange>,
+    default: &SupportedStreamConfig,
+    preferences: &StreamConfigPreferences,
+) -> Option<SupportedStreamConfig> {
+    if *preferences == StreamConfigPreferences::default() {
+        return Some(default.clone());
+    }
+
+    let sample_rate = preferences.sample_rate.unwrap_or(default.sample_rate().0);
+    let channels = preferences.channels.unwrap_or(default.channels());
+
+    // Formats in order of preference, all of them are supported by the output stream
+    let formats = [
+        default.sample_format(),
+        SampleFormat::F32,
+        SampleFormat::I16,
+        SampleFormat::U16,
+    ];
+    let format_rank = |format: SampleFormat| formats.iter().position(|f| *f == format);
+
+    supported
+        .into_iter()
+        .filter(|config| {
+            config.channels() == channels
+                && config.min_sample_rate().0 <= sample_rate
+                && sample_rate <= config.max_sample_rate().0
+                && format_rank(config.sample_format()).is_some()
+        })
+        .min_by_key(|config| format_rank(config.sample_format()))
+        .map(|config| config.with_sample_rate(SampleRate(sample_rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    fn supported() -> Vec<SupportedStreamConfigRange> {
+        vec![
+            range(2, 44100, 192000, SampleFormat::I16),
+            range(2, 44100, 192000, SampleFormat::F32),
+            range(1, 44100, 48000, SampleFormat::F32),
+            range(2, 8000, 22050, SampleFormat::U16),
+        ]
+    }
+
+    fn default() -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            2,
+            SampleRate(192000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        )
+    }
+
+    fn select(sample_rate: Option<u32>, channels: Option<u16>) -> Option<SupportedStreamConfig> {
+        let preferences = StreamConfigPreferences {
+            sample_rate,
+            channels,
+        };
+        select_stream_config(supported(), &default(), &preferences)
+    }
+
+    #[test]
+    fn test_no_preferences() {
+        assert_eq!(select(None, None), Some(default()));
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let config = select(Some(48000), None).unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(48000));
+        assert_eq!(config.channels(), 2);
+        assert_eq!(config.sample_format(), SampleFormat::F32);
+
+        let config = select(Some(11025), None).unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(11025));
+        assert_eq!(config.sample_format(), SampleFormat::U16);
+    }
+
+    #[test]
+    fn test_channels() {
+        let config = select(Some(48000), Some(1)).unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(48000));
+        assert_eq!(config.channels(), 1);
+
+        // Mono is only supported up to 48kHz
+        assert_eq!(select(None, Some(1)), None);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(select(Some(4000), None), None);
+        assert_eq!(select(Some(48000), Some(6)), None);
+    }
+}
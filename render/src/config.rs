@@ -4,7 +4,10 @@ use std::path::PathBuf;
 use xsynth_core::{
     channel::ChannelInitOptions,
     channel_group::{ChannelGroupConfig, ParallelismOptions, SynthFormat, ThreadCount},
-    soundfont::{EnvelopeCurveType, EnvelopeOptions, Interpolator, SoundfontInitOptions},
+    effects::EqParams,
+    soundfont::{
+        EnvelopeCurveType, EnvelopeOptions, Interpolator, ResampleQuality, SoundfontInitOptions,
+    },
     AudioStreamParams, ChannelCount,
 };
 
@@ -14,13 +17,109 @@ pub struct XSynthRenderConfig {
 
     pub sf_options: SoundfontInitOptions,
 
+    /// The layer limit of each channel, where one layer is one voice
+    /// per key. `None` means unlimited layers.
+    pub layers: Option<usize>,
+
     pub use_limiter: bool,
+
+    /// Target true peak in dBFS to normalize the output to
+    pub normalize: Option<f32>,
+
+    pub sample_format: OutputSampleFormat,
+
+    /// The channel layout of the output file. The rendered audio is
+    /// downmixed or upmixed to it, with mono downmixes averaging the left
+    /// and right channels. `None` keeps the layout of the render, set in
+    /// `group_options.audio_params`.
+    pub output_channels: Option<ChannelCount>,
+
+    /// Apply TPDF dithering when writing 16-bit output
+    pub dither: bool,
+
+    /// Master 3-band EQ applied to the output, before the limiter
+    pub eq: Option<EqParams>,
+
+    /// Render a seamless loop, by mixing the audio that sounds past the
+    /// end of the render onto its beginning. The whole render is kept in
+    /// memory until it is finalized.
+    pub seamless_loop: bool,
+
+    /// Only render the notes of some MIDI channels
+    pub channel_filter: Option<ChannelFilter>,
+
+    /// Limits the speed of the render to the given factor of realtime, for
+    /// example `1.0` to render no faster than the audio plays, by sleeping
+    /// between the batches. `None` renders as fast as possible.
+    pub max_realtime_factor: Option<f64>,
+
+    /// Ignore the MIDI port events of the MIDI file. Otherwise the channels
+    /// of each port are rendered on their own group of 16 synthesizer
+    /// channels, with channel 10 of each group used for percussion if
+    /// `multi_port_percussion` is enabled in the channel group options.
+    pub ignore_ports: bool,
+}
+
+/// Selects the MIDI channels which are rendered. The channels are
+/// zero-indexed, and can go beyond 15 for multi-port MIDIs.
+///
+/// The note and control events of the filtered out channels are dropped,
+/// while their program changes are still applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelFilter {
+    /// Render only the given channels
+    Only(Vec<u32>),
+
+    /// Render every channel except the given ones
+    Skip(Vec<u32>),
+}
+
+impl XSynthRenderConfig {
+    /// Returns the channel layout of the output file.
+    pub fn output_channel_count(&self) -> ChannelCount {
+        self.output_channels
+            .unwrap_or(self.group_options.audio_params.channels)
+    }
+}
+
+impl ChannelFilter {
+    /// Returns true if the notes of the channel should be rendered.
+    pub fn allows(&self, channel: u32) -> bool {
+        match self {
+            ChannelFilter::Only(channels) => channels.contains(&channel),
+            ChannelFilter::Skip(channels) => !channels.contains(&channel),
+        }
+    }
+}
+
+/// The sample format of the output audio file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputSampleFormat {
+    /// 32-bit floating point samples
+    #[default]
+    Float32,
+
+    /// 24-bit integer samples
+    Int24,
+
+    /// 16-bit integer samples
+    Int16,
+}
+
+impl OutputSampleFormat {
+    /// The size of a sample in bits.
+    pub fn bits_per_sample(&self) -> u16 {
+        match self {
+            OutputSampleFormat::Float32 => 32,
+            OutputSampleFormat::Int24 => 24,
+            OutputSampleFormat::Int16 => 16,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct State {
     pub config: XSynthRenderConfig,
-    pub layers: Option<usize>,
     pub midi: PathBuf,
     pub soundfonts: Vec<PathBuf>,
     pub output: PathBuf,
@@ -67,6 +166,15 @@ impl State {
                         Default: stereo",
                     )
                     .value_parser(audio_channels_parser),
+                Arg::new("output channels")
+                    .long("output-channels")
+                    .help(
+                        "Downmix or upmix the rendered audio to the given channel count\n\
+                        when writing the output file. Mono downmixes average both channels.\n\
+                        Supported: \"mono\" and \"stereo\"\n\
+                        Default: the rendered channel count",
+                    )
+                    .value_parser(audio_channels_parser),
                 Arg::new("layer limit")
                     .short('l')
                     .long("layers")
@@ -84,11 +192,83 @@ impl State {
                     .long("key-threading")
                     .help("Per-key multithreading options.\n".to_owned() + Self::THREADING_HELP)
                     .value_parser(threading_parser),
+                Arg::new("max realtime factor")
+                    .long("max-realtime-factor")
+                    .help(
+                        "Limit the speed of the render to the given factor of realtime,\n\
+                        for example \"4\" to render at most 4 seconds of audio per second,\n\
+                        to reduce the CPU usage. By default there is no limit.",
+                    )
+                    .value_parser(realtime_factor_parser),
                 Arg::new("limiter")
                     .short('L')
                     .long("apply-limiter")
                     .help("Apply an audio limiter to the output audio to prevent clipping.")
                     .action(ArgAction::SetTrue),
+                Arg::new("disable dc blocker")
+                    .long("disable-dc-blocker")
+                    .help("Disables the filter which removes the DC offset from the output audio.")
+                    .action(ArgAction::SetFalse),
+                Arg::new("eq")
+                    .long("eq")
+                    .help(
+                        "Apply a 3-band EQ to the output audio, with the gains in dB of\n\
+                        the low shelf (200Hz), peak (1kHz) and high shelf (5kHz) bands,\n\
+                        for example \"3,0,-2\".",
+                    )
+                    .allow_negative_numbers(true)
+                    .value_parser(eq_parser),
+                Arg::new("normalize")
+                    .short('N')
+                    .long("normalize")
+                    .help(
+                        "Normalize the output audio so that its true peak reaches\n\
+                        the given level in dBFS, for example \"-1.0\".",
+                    )
+                    .allow_negative_numbers(true)
+                    .value_parser(float_parser),
+                Arg::new("bit depth")
+                    .short('b')
+                    .long("bit-depth")
+                    .help(
+                        "The bit depth of the output audio.\n\
+                        Supported: \"16\" (integer), \"24\" (integer) and \"32\" (float)\n\
+                        Default: 32",
+                    )
+                    .value_parser(bit_depth_parser),
+                Arg::new("dither")
+                    .long("dither")
+                    .help("Apply TPDF dithering when writing 16-bit output audio.")
+                    .action(ArgAction::SetTrue),
+                Arg::new("loop")
+                    .long("loop")
+                    .help(
+                        "Render a seamless loop. The audio that sounds past the end of the MIDI,\n\
+                        like the release of the last notes, is mixed onto its beginning.",
+                    )
+                    .action(ArgAction::SetTrue),
+                Arg::new("ignore ports")
+                    .long("ignore-ports")
+                    .help(
+                        "Ignore the MIDI port events of the MIDI, and render the channels\n\
+                        of every port together on the same 16 channels.",
+                    )
+                    .action(ArgAction::SetTrue),
+                Arg::new("only channels")
+                    .long("only-channels")
+                    .help(
+                        "Render only the notes of the given MIDI channels, counting from 1,\n\
+                        for example \"1,4\".",
+                    )
+                    .conflicts_with("skip channels")
+                    .value_parser(channel_list_parser),
+                Arg::new("skip channels")
+                    .long("skip-channels")
+                    .help(
+                        "Render the notes of every MIDI channel except the given ones,\n\
+                        counting from 1, for example \"10\".",
+                    )
+                    .value_parser(channel_list_parser),
                 Arg::new("disable fade out voice killing")
                     .long("disable-fade-out")
                     .help("Disables fade out when killing a voice. This may cause popping.")
@@ -132,6 +312,7 @@ impl State {
                         .get_one("disable fade out voice killing")
                         .copied()
                         .unwrap_or(true),
+                    ..Default::default()
                 },
                 format: SynthFormat::Midi,
                 audio_params: AudioStreamParams::new(
@@ -151,6 +332,13 @@ impl State {
                         .copied()
                         .unwrap_or(ThreadCount::Auto),
                 },
+                dc_blocker: matches
+                    .get_one("disable dc blocker")
+                    .copied()
+                    .unwrap_or(true),
+                multi_port_percussion: true,
+                mpe: None,
+                master_tuning_hz: 440.0,
             },
             sf_options: SoundfontInitOptions {
                 bank: None,
@@ -177,13 +365,38 @@ impl State {
                     .get_one("interpolation")
                     .copied()
                     .unwrap_or(Interpolator::Linear),
+                resample_on_load: true,
+                resample_quality: ResampleQuality::Sinc,
+                random_seed: Some(0),
+                start_trim_ms: 0.0,
+                delay_ms: 0.0,
+                humanize_cents: 0.0,
+                strict_validation: false,
             },
+            layers: matches.get_one("layer limit").copied().unwrap_or(Some(32)),
             use_limiter: matches.get_one("limiter").copied().unwrap_or_default(),
+            normalize: matches.get_one("normalize").copied(),
+            sample_format: matches.get_one("bit depth").copied().unwrap_or_default(),
+            output_channels: matches.get_one("output channels").copied(),
+            dither: matches.get_one("dither").copied().unwrap_or_default(),
+            eq: matches.get_one("eq").copied(),
+            seamless_loop: matches.get_one("loop").copied().unwrap_or_default(),
+            channel_filter: matches
+                .get_one::<Vec<u32>>("only channels")
+                .cloned()
+                .map(ChannelFilter::Only)
+                .or_else(|| {
+                    matches
+                        .get_one::<Vec<u32>>("skip channels")
+                        .cloned()
+                        .map(ChannelFilter::Skip)
+                }),
+            max_realtime_factor: matches.get_one("max realtime factor").copied(),
+            ignore_ports: matches.get_one("ignore ports").copied().unwrap_or_default(),
         };
 
         Self {
             config,
-            layers: matches.get_one("layer limit").copied().unwrap_or(Some(32)),
             midi: PathBuf::from(midi),
             output: PathBuf::from(output),
             soundfonts,
@@ -0,0 +1,13 @@
+mod config;
+pub use config::*;
+
+mod midi;
+pub use midi::*;
+
+mod rendered;
+pub use rendered::*;
+
+mod utils;
+
+mod writer;
+pub use writer::RenderStats;
@@ -0,0 +1,469 @@
+use crate::{
+    config::XSynthRenderConfig,
+    rendered::{RenderError, XSynthRender},
+    utils::get_midi_length,
+};
+
+use xsynth_core::{
+    channel::{
+        ChannelAudioEvent, ChannelConfigEvent, ChannelEvent, ControlEvent,
+        DEFAULT_NOTE_OFF_VELOCITY,
+    },
+    channel_group::SynthEvent,
+    soundfont::{LoadSfError, SampleSoundfont, SoundfontBase},
+};
+
+use midi_toolkit::{
+    events::{Event, MIDIEventEnum},
+    io::MIDIFile,
+    pipe,
+    sequence::{
+        event::{cancel_tempo_events, scale_event_time},
+        TimeCaster,
+    },
+};
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// The minimum length of a rendered batch in seconds. Events within a batch
+/// are applied at their exact sample positions.
+const RENDER_BATCH_LENGTH: f64 = 0.05;
+
+/// Loads the soundfonts in the given paths using the soundfont options
+/// and the audio parameters of the render configuration.
+pub fn load_soundfonts(
+    paths: &[impl AsRef<Path>],
+    config: &XSynthRenderConfig,
+) -> Result<Vec<Arc<dyn SoundfontBase>>, LoadSfError> {
+    paths
+        .iter()
+        .map(|path| {
+            let sf: Arc<dyn SoundfontBase> = Arc::new(SampleSoundfont::new(
+                path.as_ref(),
+                config.group_options.audio_params,
+                config.sf_options,
+            )?);
+            Ok(sf)
+        })
+        .collect()
+}
+
+/// Converts a MIDI event to a SynthEvent, with its channel moved by the
+/// given offset, which is the first synthesizer channel of its port.
+fn convert_event(event: &Event, channel_offset: u32) -> Option<SynthEvent> {
+    let event = match event {
+        Event::NoteOn(e) => SynthEvent::Channel(
+            channel_offset + e.channel as u32,
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOn {
+                key: e.key,
+                vel: e.velocity,
+            }),
+        ),
+        Event::NoteOff(e) => SynthEvent::Channel(
+            channel_offset + e.channel as u32,
+            ChannelEvent::Audio(ChannelAudioEvent::NoteOff {
+                key: e.key,
+                vel: DEFAULT_NOTE_OFF_VELOCITY,
+            }),
+        ),
+        Event::ControlChange(e) => SynthEvent::Channel(
+            channel_offset + e.channel as u32,
+            ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::Raw(
+                e.controller,
+                e.value,
+            ))),
+        ),
+        Event::PitchWheelChange(e) => SynthEvent::Channel(
+            channel_offset + e.channel as u32,
+            ChannelEvent::Audio(ChannelAudioEvent::Control(ControlEvent::PitchBendValue(
+                e.pitch as f32 / 8192.0,
+            ))),
+        ),
+        Event::ProgramChange(e) => SynthEvent::Channel(
+            channel_offset + e.channel as u32,
+            ChannelEvent::Audio(ChannelAudioEvent::ProgramChange(e.program)),
+        ),
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// Renders all the events of a MIDI file using the given synthesizer.
+///
+/// The render can be stopped from another thread by setting `cancel`,
+/// in which case `RenderError::Cancelled` is returned. The synthesizer
+/// still needs to be finalized or aborted afterwards.
+pub fn render_midi(
+    synth: &mut XSynthRender,
+    midi_path: impl AsRef<Path>,
+    cancel: &AtomicBool,
+) -> Result<(), RenderError> {
+    let midi = MIDIFile::open(midi_path, None).map_err(|e| RenderError::Midi(format!("{e:?}")))?;
+
+    synth.set_total_length(get_midi_length(&midi));
+
+    // The port of each track, which is changed by its MIDI port events
+    let mut track_ports = vec![0u32; midi.track_count()];
+    let ignore_ports = synth.ignores_ports();
+
+    let ppq = midi.ppq();
+    let merged = pipe!(
+        midi.iter_all_track_events_merged_batches()
+        |>TimeCaster::<f64>::cast_event_delta()
+        |>cancel_tempo_events(250000)
+        |>scale_event_time(1.0 / ppq as f64)
+    );
+
+    // Parsing errors are forwarded to the render loop, which stops the render
+    let (snd, rcv) = crossbeam_channel::bounded(100);
+
+    thread::spawn(move || {
+        for batch in merged {
+            let is_err = batch.is_err();
+            if snd.send(batch).is_err() || is_err {
+                break;
+            }
+        }
+    });
+
+    let mut batch_time = 0.0;
+
+    for batch in rcv {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(RenderError::Cancelled);
+        }
+        let batch = batch.map_err(|e| RenderError::Midi(e.to_string()))?;
+        batch_time += batch.delta;
+        if batch_time >= RENDER_BATCH_LENGTH {
+            synth.render_batch(batch_time)?;
+            batch_time = 0.0;
+        }
+        for e in batch.iter_events() {
+            let track = e.track as usize;
+            if let Event::MIDIPort(port) = e.as_event() {
+                if !ignore_ports {
+                    track_ports[track] = port.channel as u32;
+                    synth.ensure_channel_count((port.channel as u32 + 1) * 16);
+                }
+                continue;
+            }
+            if let Some(event) = convert_event(e.as_event(), track_ports[track] * 16) {
+                synth.send_event_at(event, batch_time);
+            }
+        }
+    }
+    if batch_time > 0.0 {
+        synth.render_batch(batch_time)?;
+    }
+    synth.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+        ChannelAudioEvent::AllNotesOff,
+    )));
+    synth.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+        ChannelAudioEvent::ResetControl,
+    )));
+
+    Ok(())
+}
+
+/// Renders a MIDI file to memory and returns the interleaved samples of
+/// the audio, without writing any files.
+///
+/// Parameters:
+/// - `midi_path`: The path of the MIDI file to be rendered.
+/// - `soundfonts`: The soundfonts used by all channels. They can be loaded
+///   using `load_soundfonts`.
+/// - `config`: The render configuration. The sample format, output channels
+///   and dither options don't apply, as the samples stay 32-bit floats in
+///   the layout of the render.
+/// - `cancel`: Stops the render when set from another thread.
+pub fn render_midi_to_buffer(
+    midi_path: impl AsRef<Path>,
+    soundfonts: Vec<Arc<dyn SoundfontBase>>,
+    config: XSynthRenderConfig,
+    cancel: &AtomicBool,
+) -> Result<Vec<f32>, RenderError> {
+    let mut synth = XSynthRender::new_in_memory(config);
+    synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+        ChannelConfigEvent::SetSoundfonts(soundfonts),
+    )));
+
+    if let Err(e) = render_midi(&mut synth, midi_path, cancel) {
+        synth.abort();
+        return Err(e);
+    }
+    synth.finalize_to_buffer()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::OutputSampleFormat,
+        rendered::{
+            tests::{test_config, write_test_sfz},
+            RenderSynth,
+        },
+    };
+    use std::sync::Mutex;
+    use xsynth_core::{
+        soundfont::test_utils::TestSoundfontDir, AudioPipe, AudioStreamParams, ChannelCount,
+    };
+
+    /// Writes a MIDI file with a single half second note at 120 BPM.
+    fn write_test_midi(path: &Path) {
+        let mut data = b"MThd\0\0\0\x06\0\0\0\x01\x01\xe0MTrk\0\0\0\x0d".to_vec();
+        data.extend_from_slice(&[
+            0x00, 0x90, 60, 100, // Note on
+            0x83, 0x60, 0x80, 60, 0, // Note off after 480 ticks
+            0x00, 0xff, 0x2f, 0x00, // End of track
+        ]);
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_render_to_buffer() {
+        let dir = TestSoundfontDir::new("render_midi");
+        let sfz = write_test_sfz(&dir);
+        let midi = dir.path().join("test.mid");
+        write_test_midi(&midi);
+
+        let mut config = test_config();
+        config.sample_format = OutputSampleFormat::Float32;
+        let soundfonts = load_soundfonts(&[&sfz], &config).unwrap();
+
+        let samples = render_midi_to_buffer(
+            &midi,
+            soundfonts.clone(),
+            config.clone(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+
+        // The same render written to a file
+        let path = dir.path().join("out.wav");
+        let mut synth = XSynthRender::new(config, path.clone());
+        synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(soundfonts),
+        )));
+        render_midi(&mut synth, &midi, &AtomicBool::new(false)).unwrap();
+        synth.finalize().unwrap();
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let file_samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+
+        // The note and its release, in stereo
+        assert!(samples.len() >= 48000 * 2 / 2);
+        assert!(samples.iter().any(|s| s.abs() > 0.1));
+        assert_eq!(samples, file_samples);
+    }
+
+    #[test]
+    fn test_render_bit_depths() {
+        let dir = TestSoundfontDir::new("render_depth");
+        let sfz = write_test_sfz(&dir);
+        let midi = dir.path().join("test.mid");
+        write_test_midi(&midi);
+        let soundfonts = load_soundfonts(&[&sfz], &test_config()).unwrap();
+
+        let render = |sample_format: OutputSampleFormat, output_channels| {
+            let config = XSynthRenderConfig {
+                sample_format,
+                output_channels,
+                dither: true,
+                ..test_config()
+            };
+            let path = dir.path().join("out.wav");
+            let mut synth = XSynthRender::new(config, path.clone());
+            synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+                ChannelConfigEvent::SetSoundfonts(soundfonts.clone()),
+            )));
+            render_midi(&mut synth, &midi, &AtomicBool::new(false)).unwrap();
+            synth.finalize().unwrap();
+
+            let file_len = std::fs::metadata(&path).unwrap().len();
+            let mut reader = hound::WavReader::open(&path).unwrap();
+            let spec = reader.spec();
+            assert_eq!(spec.bits_per_sample, sample_format.bits_per_sample());
+            let samples: Vec<f32> = match spec.sample_format {
+                hound::SampleFormat::Float => reader.samples::<f32>().map(|s| s.unwrap()).collect(),
+                hound::SampleFormat::Int => {
+                    let scale = (1 << (spec.bits_per_sample - 1)) as f32;
+                    let samples = reader.samples::<i32>();
+                    samples.map(|s| s.unwrap() as f32 / scale).collect()
+                }
+            };
+
+            // Only the header is written besides the samples
+            let data_len = samples.len() as u64 * spec.bits_per_sample as u64 / 8;
+            assert!(
+                file_len > data_len && file_len - data_len <= 80,
+                "{file_len}"
+            );
+
+            let rms =
+                (samples.iter().map(|s| s.powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+            (spec.channels, samples.len(), rms)
+        };
+
+        let (channels, len, rms) = render(OutputSampleFormat::Float32, None);
+        assert_eq!(channels, 2);
+        assert!(rms > 0.01, "{rms}");
+        for format in [OutputSampleFormat::Int24, OutputSampleFormat::Int16] {
+            let (_, format_len, format_rms) = render(format, None);
+            assert_eq!(format_len, len);
+            assert!(
+                (format_rms - rms).abs() < 1e-3,
+                "{format:?}: {format_rms} {rms}"
+            );
+        }
+
+        // The mono downmix of the centered note keeps its level
+        let (channels, mono_len, mono_rms) =
+            render(OutputSampleFormat::Int24, Some(ChannelCount::Mono));
+        assert_eq!(channels, 1);
+        assert_eq!(mono_len, len / 2);
+        assert!((mono_rms - rms).abs() < 1e-3, "{mono_rms} {rms}");
+    }
+
+    #[test]
+    fn test_render_cancelled() {
+        let dir = std::env::temp_dir().join(format!("xsynth_render_cancel_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let midi = dir.join("test.mid");
+        write_test_midi(&midi);
+
+        let result =
+            render_midi_to_buffer(&midi, Vec::new(), test_config(), &AtomicBool::new(true));
+        std::fs::remove_dir_all(dir).ok();
+
+        assert!(matches!(result, Err(RenderError::Cancelled)));
+    }
+
+    /// A synthesizer which records the audio events sent to its channels
+    /// and its channel count.
+    struct RecordingSynth {
+        params: AudioStreamParams,
+        events: Arc<Mutex<Vec<(u32, ChannelAudioEvent)>>>,
+        channel_count: Arc<Mutex<u32>>,
+    }
+
+    impl AudioPipe for RecordingSynth {
+        fn stream_params(&self) -> &AudioStreamParams {
+            &self.params
+        }
+
+        fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+            to.fill(0.0);
+        }
+    }
+
+    impl RenderSynth for RecordingSynth {
+        fn send_event(&mut self, event: SynthEvent) {
+            if let SynthEvent::Channel(channel, ChannelEvent::Audio(event)) = event {
+                self.events.lock().unwrap().push((channel, event));
+            }
+        }
+
+        fn send_event_at_offset(&mut self, event: SynthEvent, _sample_offset: usize) {
+            self.send_event(event);
+        }
+
+        fn voice_count(&self) -> u64 {
+            0
+        }
+
+        fn set_channel_count(&mut self, count: u32) {
+            *self.channel_count.lock().unwrap() = count;
+        }
+    }
+
+    /// Writes a MIDI file with two tracks on different ports, a string
+    /// note on channel 1 of port 1 and a drum note on channel 10 of port 2.
+    fn write_two_port_midi(path: &Path) {
+        let mut data = b"MThd\0\0\0\x06\0\x01\0\x02\x01\xe0".to_vec();
+        data.extend_from_slice(b"MTrk\0\0\0\x15");
+        data.extend_from_slice(&[
+            0x00, 0xff, 0x21, 0x01, 0x00, // Port 1
+            0x00, 0xc0, 48, // Strings
+            0x00, 0x90, 60, 100, // Note on
+            0x83, 0x60, 0x80, 60, 0, // Note off after 480 ticks
+            0x00, 0xff, 0x2f, 0x00, // End of track
+        ]);
+        data.extend_from_slice(b"MTrk\0\0\0\x12");
+        data.extend_from_slice(&[
+            0x00, 0xff, 0x21, 0x01, 0x01, // Port 2
+            0x00, 0x99, 36, 100, // Kick drum
+            0x83, 0x60, 0x89, 36, 0, // Note off after 480 ticks
+            0x00, 0xff, 0x2f, 0x00, // End of track
+        ]);
+        std::fs::write(path, data).unwrap();
+    }
+
+    fn render_ports(midi: &Path, ignore_ports: bool) -> (Vec<(u32, ChannelAudioEvent)>, u32) {
+        let mut config = test_config();
+        config.ignore_ports = ignore_ports;
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let channel_count = Arc::new(Mutex::new(16));
+        let synth = RecordingSynth {
+            params: config.group_options.audio_params,
+            events: events.clone(),
+            channel_count: channel_count.clone(),
+        };
+        let mut synth =
+            XSynthRender::with_synth(config, midi.with_extension("wav"), Box::new(synth));
+        render_midi(&mut synth, midi, &AtomicBool::new(false)).unwrap();
+        synth.abort();
+
+        // The events of different tracks at the same time can be in any order
+        let mut events: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, e)| {
+                matches!(
+                    e,
+                    ChannelAudioEvent::NoteOn { .. } | ChannelAudioEvent::ProgramChange(_)
+                )
+            })
+            .copied()
+            .collect();
+        events.sort_by_key(|(channel, _)| *channel);
+        let channel_count = *channel_count.lock().unwrap();
+        (events, channel_count)
+    }
+
+    #[test]
+    fn test_midi_ports() {
+        let dir = std::env::temp_dir().join(format!("xsynth_render_ports_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let midi = dir.join("ports.mid");
+        write_two_port_midi(&midi);
+
+        // The drums of port 2 go to channel 10 of the second group of
+        // 16 channels, which is a percussion channel
+        let (events, channel_count) = render_ports(&midi, false);
+        assert_eq!(channel_count, 32);
+        assert_eq!(
+            events,
+            vec![
+                (0, ChannelAudioEvent::ProgramChange(48)),
+                (0, ChannelAudioEvent::NoteOn { key: 60, vel: 100 }),
+                (25, ChannelAudioEvent::NoteOn { key: 36, vel: 100 }),
+            ]
+        );
+
+        let (events, channel_count) = render_ports(&midi, true);
+        std::fs::remove_dir_all(dir).ok();
+        assert_eq!(channel_count, 16);
+        assert_eq!(
+            events[2],
+            (9, ChannelAudioEvent::NoteOn { key: 36, vel: 100 })
+        );
+    }
+}
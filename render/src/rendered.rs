@@ -1,25 +1,141 @@
 use xsynth_core::{
+    channel::{ChannelAudioEvent, ChannelConfigEvent, ChannelEvent},
     channel_group::{ChannelGroup, SynthEvent},
-    effects::VolumeLimiter,
-    AudioPipe, AudioStreamParams,
+    effects::{Equalizer, VolumeLimiter},
+    AudioPipe, AudioPipeError, AudioStreamParams,
 };
 
-use std::path::PathBuf;
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+};
+use thiserror::Error;
 
-use crate::{config::XSynthRenderConfig, writer::AudioFileWriter};
+use crate::{
+    config::XSynthRenderConfig,
+    utils::Pacer,
+    writer::{AudioFileWriter, AudioOutput, MemoryWriter, RenderStats},
+};
 
 struct BatchRenderElements {
     output_vec: Vec<f32>,
     missed_samples: f64,
+    position: f64,
+}
+
+/// The progress of a render, passed to the callback set with
+/// `XSynthRender::set_progress_callback`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderProgress {
+    /// Time rendered so far in seconds
+    pub rendered_seconds: f64,
+
+    /// Total length of the render in seconds, as set with
+    /// `XSynthRender::set_total_length`. Zero if it was never set.
+    pub total_seconds: f64,
+
+    /// Active voice count of the MIDI synthesizer
+    pub voice_count: u64,
+
+    /// Seconds of audio rendered per second of wall time since the start
+    /// of the render. See `XSynthRenderConfig::max_realtime_factor`.
+    pub realtime_factor: f64,
+}
+
+type ProgressCallback = Box<dyn FnMut(RenderProgress) + Send>;
+
+/// Errors that can stop a render.
+#[derive(Debug, Clone, Error)]
+pub enum RenderError {
+    /// The synthesizer panicked while rendering
+    #[error("The synthesizer panicked while rendering: {0}")]
+    SynthPanic(String),
+
+    /// The synthesizer returned an error while rendering
+    #[error("The synthesizer failed to render: {0}")]
+    Synth(#[from] AudioPipeError),
+
+    /// The output file couldn't be written
+    #[error("Failed to write the output file: {0}")]
+    Writer(String),
+
+    /// The MIDI file couldn't be loaded or read
+    #[error("Failed to read the MIDI: {0}")]
+    Midi(String),
+
+    /// The render was cancelled
+    #[error("The render was cancelled")]
+    Cancelled,
+}
+
+/// Returns the message of a caught panic.
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// The synthesizer used by XSynthRender to generate the audio.
+pub trait RenderSynth: AudioPipe {
+    /// Sends a SynthEvent to the synthesizer.
+    fn send_event(&mut self, event: SynthEvent);
+
+    /// Sends a SynthEvent to be applied at the given sample offset
+    /// of the next read.
+    fn send_event_at_offset(&mut self, event: SynthEvent, sample_offset: usize);
+
+    /// Returns the active voice count of the synthesizer.
+    fn voice_count(&self) -> u64;
+
+    /// Changes the number of MIDI channels of the synthesizer, for MIDI
+    /// files which use more than one port. Synthesizers with a fixed
+    /// number of channels can ignore it.
+    fn set_channel_count(&mut self, _count: u32) {}
+}
+
+impl RenderSynth for ChannelGroup {
+    fn send_event(&mut self, event: SynthEvent) {
+        ChannelGroup::send_event(self, event);
+    }
+
+    fn send_event_at_offset(&mut self, event: SynthEvent, sample_offset: usize) {
+        ChannelGroup::send_event_at_offset(self, event, sample_offset);
+    }
+
+    fn voice_count(&self) -> u64 {
+        ChannelGroup::voice_count(self)
+    }
+
+    fn set_channel_count(&mut self, count: u32) {
+        ChannelGroup::set_channel_count(self, count);
+    }
 }
 
-/// Represents an XSynth MIDI synthesizer that renders a MIDI to a file.
+/// Represents an XSynth MIDI synthesizer that renders a MIDI to a file,
+/// or to a buffer in memory.
 pub struct XSynthRender {
     config: XSynthRenderConfig,
-    channel_group: ChannelGroup,
-    audio_writer: AudioFileWriter,
+    synth: Box<dyn RenderSynth>,
+    channel_count: u32,
+    audio_writer: AudioOutput,
+    eq: Option<Equalizer>,
     limiter: Option<VolumeLimiter>,
     render_elements: BatchRenderElements,
+
+    /// The audio of the loop, kept until the render is finalized
+    /// when rendering a seamless loop
+    loop_buffer: Option<Vec<f32>>,
+    total_length: f64,
+    progress_callback: Option<ProgressCallback>,
+    pacer: Pacer,
+
+    /// The error which stopped the render, returned by any further render
+    error: Option<RenderError>,
 }
 
 impl XSynthRender {
@@ -27,8 +143,54 @@ impl XSynthRender {
     /// audio output path.
     pub fn new(config: XSynthRenderConfig, out_path: PathBuf) -> Self {
         let channel_group = ChannelGroup::new(config.group_options.clone());
+        Self::with_synth(config, out_path, Box::new(channel_group))
+    }
 
+    /// Initializes a new XSynthRender object which renders the audio of the
+    /// given synthesizer instead of a channel group created from the
+    /// configuration.
+    pub fn with_synth(
+        config: XSynthRenderConfig,
+        out_path: PathBuf,
+        synth: Box<dyn RenderSynth>,
+    ) -> Self {
         let audio_writer = AudioFileWriter::new(config.clone(), out_path);
+        Self::with_writer(config, synth, AudioOutput::File(audio_writer))
+    }
+
+    /// Initializes a new XSynthRender object which keeps the rendered audio
+    /// in memory instead of writing it to a file. The audio is returned by
+    /// `finalize_to_buffer`.
+    ///
+    /// The samples stay 32-bit floats, so the sample format and dither
+    /// options of the configuration don't apply.
+    pub fn new_in_memory(config: XSynthRenderConfig) -> Self {
+        let channel_group = ChannelGroup::new(config.group_options.clone());
+        let audio_writer = MemoryWriter::new(&config);
+        Self::with_writer(
+            config,
+            Box::new(channel_group),
+            AudioOutput::Memory(audio_writer),
+        )
+    }
+
+    fn with_writer(
+        config: XSynthRenderConfig,
+        mut synth: Box<dyn RenderSynth>,
+        audio_writer: AudioOutput,
+    ) -> Self {
+        synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetLayerCount(config.layers),
+        )));
+
+        let audio_params = config.group_options.audio_params;
+        let eq = config.eq.map(|params| {
+            Equalizer::new(
+                audio_params.channels.count(),
+                audio_params.sample_rate,
+                params,
+            )
+        });
 
         let limiter = if config.use_limiter {
             Some(VolumeLimiter::new(
@@ -38,15 +200,27 @@ impl XSynthRender {
             None
         };
 
+        let seamless_loop = config.seamless_loop;
+        let pacer = Pacer::new(config.max_realtime_factor);
+
+        let channel_count = config.group_options.format.channel_count();
         Self {
             config,
-            channel_group,
+            synth,
+            channel_count,
             audio_writer,
+            eq,
             limiter,
             render_elements: BatchRenderElements {
                 output_vec: vec![0.0],
                 missed_samples: 0.0,
+                position: 0.0,
             },
+            loop_buffer: seamless_loop.then(Vec::new),
+            total_length: 0.0,
+            progress_callback: None,
+            pacer,
+            error: None,
         }
     }
 
@@ -55,79 +229,633 @@ impl XSynthRender {
         self.config.group_options.audio_params
     }
 
+    /// Sets the total length of the render in seconds, which is reported
+    /// to the progress callback.
+    pub fn set_total_length(&mut self, seconds: f64) {
+        self.total_length = seconds;
+    }
+
+    /// Sets a callback which is called after each rendered batch with the
+    /// progress of the render.
+    ///
+    /// See the `RenderProgress` documentation for more information.
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(RenderProgress) + Send + 'static) {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    /// Returns true if the MIDI port events should be ignored.
+    pub(crate) fn ignores_ports(&self) -> bool {
+        self.config.ignore_ports
+    }
+
+    /// Returns the number of MIDI channels of the synthesizer.
+    pub fn channel_count(&self) -> u32 {
+        self.channel_count
+    }
+
+    /// Adds MIDI channels to the synthesizer if it has less than the given
+    /// number of channels, for example when a MIDI file uses more than one
+    /// port. The new channels receive the config events previously sent
+    /// to all channels.
+    pub fn ensure_channel_count(&mut self, count: u32) {
+        if count > self.channel_count {
+            self.synth.set_channel_count(count);
+            self.channel_count = count;
+        }
+    }
+
     /// Sends a SynthEvent to the XSynthRender object.
     /// Please see the SynthEvent documentation for more information.
     pub fn send_event(&mut self, event: SynthEvent) {
-        self.channel_group.send_event(event);
+        if self.is_filtered(&event) {
+            return;
+        }
+        self.synth.send_event(event);
+    }
+
+    /// Sends a SynthEvent to the XSynthRender object, to be applied the
+    /// given time in seconds after the start of the next rendered batch.
+    /// This allows rendering multiple events in one batch while keeping
+    /// their timing sample-accurate.
+    pub fn send_event_at(&mut self, event: SynthEvent, time: f64) {
+        if self.is_filtered(&event) {
+            return;
+        }
+        let offset = self.config.group_options.audio_params.sample_rate as f64 * time
+            + self.render_elements.missed_samples;
+        self.synth.send_event_at_offset(event, offset as usize);
+    }
+
+    /// Returns true if the event should be dropped because of the channel
+    /// filter. Only the note and control events are dropped, so that the
+    /// channel state, like the selected program, stays realistic.
+    fn is_filtered(&self, event: &SynthEvent) -> bool {
+        let Some(filter) = &self.config.channel_filter else {
+            return false;
+        };
+
+        match event {
+            SynthEvent::Channel(channel, ChannelEvent::Audio(event)) => {
+                matches!(
+                    event,
+                    ChannelAudioEvent::NoteOn { .. }
+                        | ChannelAudioEvent::NoteOff { .. }
+                        | ChannelAudioEvent::Control(_)
+                ) && !filter.allows(*channel)
+            }
+            _ => false,
+        }
     }
 
     /// Renders audio samples of the specified time to the audio output file.
     ///
-    /// The time should be the delta time of the last sent events.
-    pub fn render_batch(&mut self, event_time: f64) {
+    /// The time should be the delta time of the last sent events, or the
+    /// length of the batch when the events are sent with `send_event_at`.
+    ///
+    /// Returns an error if the synthesizer panicked or the output file
+    /// couldn't be written. Nothing more is rendered after an error, and
+    /// every further call returns it.
+    pub fn render_batch(&mut self, event_time: f64) -> Result<(), RenderError> {
         if event_time > 10.0 {
             // If the time is too large, split it up
             let mut remaining_time = event_time;
             loop {
                 if remaining_time > 10.0 {
-                    self.render_batch(10.0);
+                    self.render_batch(10.0)?;
                     remaining_time -= 10.0;
                 } else {
-                    self.render_batch(remaining_time);
-                    break;
+                    return self.render_batch(remaining_time);
                 }
             }
         } else {
+            self.pacer.start();
             let samples = self.config.group_options.audio_params.sample_rate as f64 * event_time
                 + self.render_elements.missed_samples;
             self.render_elements.missed_samples = samples % 1.0;
             let samples =
                 samples as usize * self.config.group_options.audio_params.channels.count() as usize;
 
-            self.render_elements.output_vec.resize(samples, 0.0);
-            self.channel_group
-                .read_samples(&mut self.render_elements.output_vec);
+            self.read_samples(samples)?;
 
-            if let Some(limiter) = &mut self.limiter {
-                limiter.limit(&mut self.render_elements.output_vec);
+            if let Some(eq) = &mut self.eq {
+                eq.process(&mut self.render_elements.output_vec);
+            }
+            match &mut self.loop_buffer {
+                Some(buffer) => buffer.extend_from_slice(&self.render_elements.output_vec),
+                None => self.write_output()?,
             }
 
-            self.audio_writer
-                .write_samples(&mut self.render_elements.output_vec);
+            self.render_elements.position += event_time;
+            self.pacer.pace(self.render_elements.position);
+            let progress = RenderProgress {
+                rendered_seconds: self.render_elements.position,
+                total_seconds: self.total_length,
+                voice_count: self.voice_count(),
+                realtime_factor: self.pacer.factor(self.render_elements.position),
+            };
+            if let Some(callback) = &mut self.progress_callback {
+                callback(progress);
+            }
+            Ok(())
         }
     }
 
-    /// Finishes the render and finalizes the audio file.
-    pub fn finalize(mut self) {
-        loop {
-            self.render_elements.output_vec.resize(
-                self.config.group_options.audio_params.sample_rate as usize,
-                0.0,
-            );
-            self.channel_group
-                .read_samples(&mut self.render_elements.output_vec);
-
-            if let Some(limiter) = &mut self.limiter {
-                limiter.limit(&mut self.render_elements.output_vec);
+    /// Stores the error which stopped the render and returns it.
+    fn fail(&mut self, error: RenderError) -> RenderError {
+        self.error = Some(error.clone());
+        error
+    }
+
+    /// Reads the given amount of samples from the synthesizer into the
+    /// output samples. Panics of the synthesizer, including the ones of
+    /// its thread pool, are caught and returned as an error along with
+    /// its read errors.
+    fn read_samples(&mut self, samples: usize) -> Result<(), RenderError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        let output = &mut self.render_elements.output_vec;
+        output.resize(samples, 0.0);
+        let synth = &mut self.synth;
+        match panic::catch_unwind(AssertUnwindSafe(|| synth.try_read_samples(output))) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(self.fail(e.into())),
+            Err(e) => Err(self.fail(RenderError::SynthPanic(panic_message(e)))),
+        }
+    }
+
+    /// Applies the limiter to the output samples and writes them to the file.
+    fn write_output(&mut self) -> Result<(), RenderError> {
+        if let Some(limiter) = &mut self.limiter {
+            limiter.limit(&mut self.render_elements.output_vec);
+        }
+
+        self.audio_writer
+            .write_samples(&mut self.render_elements.output_vec)
+            .map_err(|e| self.fail(e))
+    }
+
+    /// Finishes the render and finalizes the audio file. Returns the
+    /// statistics of the written audio.
+    ///
+    /// When rendering a seamless loop, the audio rendered after the end of
+    /// the loop, such as the release of the notes still playing, is mixed
+    /// onto the beginning of the loop instead of being appended.
+    ///
+    /// If the render failed, the error is returned and the output file is
+    /// deleted, unless the error comes from writing it. In that case, the
+    /// audio written until the error is kept.
+    ///
+    /// See the `RenderStats` documentation for more information.
+    pub fn finalize(self) -> Result<RenderStats, RenderError> {
+        self.finish().map(|(stats, _)| stats)
+    }
+
+    /// Finishes a render created with `new_in_memory` and returns the
+    /// interleaved samples of the audio. See `finalize`.
+    ///
+    /// Panics if the render was created with an output file.
+    pub fn finalize_to_buffer(self) -> Result<Vec<f32>, RenderError> {
+        let (_, samples) = self.finish()?;
+        Ok(samples.expect("the render is kept in memory"))
+    }
+
+    fn finish(mut self) -> Result<(RenderStats, Option<Vec<f32>>), RenderError> {
+        match self.render_tail() {
+            Ok(()) => self.audio_writer.finalize(),
+            Err(e) => {
+                self.audio_writer.abort();
+                Err(e)
             }
+        }
+    }
 
-            let mut is_empty = true;
-            for s in &self.render_elements.output_vec {
-                if *s > 0.0001 || *s < -0.0001 {
-                    is_empty = false;
-                    break;
-                }
+    /// Stops the render and deletes the output file, unless writing it
+    /// failed. See `finalize`.
+    pub fn abort(self) {
+        self.audio_writer.abort();
+    }
+
+    /// Renders the audio after the last batch until it becomes silent.
+    fn render_tail(&mut self) -> Result<(), RenderError> {
+        let mut loop_buffer = self.loop_buffer.take();
+        let mut tail_position = 0;
+
+        loop {
+            self.read_samples(self.config.group_options.audio_params.sample_rate as usize)?;
+
+            if let Some(eq) = &mut self.eq {
+                eq.process(&mut self.render_elements.output_vec);
             }
+
+            let is_empty = self
+                .render_elements
+                .output_vec
+                .iter()
+                .all(|s| s.abs() <= 0.0001);
             if is_empty {
                 break;
             }
-            self.audio_writer
-                .write_samples(&mut self.render_elements.output_vec);
+
+            match &mut loop_buffer {
+                // Wrap the tail around the loop, in case it's longer
+                Some(buffer) if !buffer.is_empty() => {
+                    let len = buffer.len();
+                    for s in &self.render_elements.output_vec {
+                        buffer[tail_position % len] += s;
+                        tail_position += 1;
+                    }
+                }
+                _ => self.write_output()?,
+            }
+        }
+
+        if let Some(buffer) = loop_buffer {
+            self.render_elements.output_vec = buffer;
+            self.write_output()?;
         }
+
+        Ok(())
     }
 
     /// Returns the active voice count of the MIDI synthesizer.
     pub fn voice_count(&self) -> u64 {
-        self.channel_group.voice_count()
+        self.synth.voice_count()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{config::ChannelFilter, writer::OutputFile};
+    use std::{
+        fs::File,
+        io::{self, Seek, SeekFrom, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use xsynth_core::{
+        channel::{ChannelConfigEvent, ChannelInitOptions},
+        channel_group::{ChannelGroupConfig, ParallelismOptions, SynthFormat, ThreadCount},
+        soundfont::{test_utils::TestSoundfontDir, SampleSoundfont, SoundfontBase},
+        ChannelCount,
+    };
+
+    pub(crate) fn test_config() -> XSynthRenderConfig {
+        XSynthRenderConfig {
+            group_options: ChannelGroupConfig {
+                channel_init_options: ChannelInitOptions::default(),
+                format: SynthFormat::Midi,
+                audio_params: AudioStreamParams::new(48000, ChannelCount::Stereo),
+                parallelism: ParallelismOptions {
+                    channel: ThreadCount::None,
+                    key: ThreadCount::None,
+                },
+                dc_blocker: true,
+                multi_port_percussion: true,
+                mpe: None,
+                master_tuning_hz: 440.0,
+            },
+            sf_options: Default::default(),
+            layers: Some(32),
+            use_limiter: false,
+            normalize: None,
+            sample_format: Default::default(),
+            output_channels: None,
+            dither: false,
+            eq: None,
+            seamless_loop: false,
+            channel_filter: None,
+            max_realtime_factor: None,
+            ignore_ports: false,
+        }
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let config = test_config();
+        let path = std::env::temp_dir().join(format!("xsynth_render_{}.wav", std::process::id()));
+        let mut synth = XSynthRender::new(config, path.clone());
+        synth.set_total_length(30.0);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        {
+            let reports = reports.clone();
+            synth.set_progress_callback(move |progress| reports.lock().unwrap().push(progress));
+        }
+
+        for time in [0.5, 1.0, 0.25, 12.0] {
+            synth.render_batch(time).unwrap();
+        }
+        synth.finalize().unwrap();
+        std::fs::remove_file(path).ok();
+
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() >= 4);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[1].rendered_seconds > w[0].rendered_seconds));
+        let last = reports.last().unwrap();
+        assert!((last.rendered_seconds - 13.75).abs() < 1e-9);
+        assert_eq!(last.total_seconds, 30.0);
+        assert_eq!(last.voice_count, 0);
+    }
+
+    #[test]
+    fn test_max_realtime_factor() {
+        let render = |max_realtime_factor| {
+            let config = XSynthRenderConfig {
+                max_realtime_factor,
+                ..test_config()
+            };
+            let mut synth = XSynthRender::new_in_memory(config);
+            let factor = Arc::new(Mutex::new(0.0));
+            {
+                let factor = factor.clone();
+                synth.set_progress_callback(move |p| *factor.lock().unwrap() = p.realtime_factor);
+            }
+
+            let start = std::time::Instant::now();
+            for _ in 0..10 {
+                synth.render_batch(0.05).unwrap();
+            }
+            let elapsed = start.elapsed().as_secs_f64();
+            synth.finalize_to_buffer().unwrap();
+            let factor = *factor.lock().unwrap();
+            (elapsed, factor)
+        };
+
+        // Half a second of audio takes at least half a second at realtime
+        let (elapsed, factor) = render(Some(1.0));
+        assert!(elapsed >= 0.49, "{elapsed}");
+        assert!(factor <= 1.0 && factor > 0.9, "{factor}");
+
+        let (elapsed, factor) = render(None);
+        assert!(elapsed < 0.25, "{elapsed}");
+        assert!(factor > 2.0, "{factor}");
+    }
+
+    /// Writes an SFZ soundfont with a constant looped sample in the directory,
+    /// with a release long enough to overrun a loop.
+    pub(crate) fn write_test_sfz(dir: &TestSoundfontDir) -> PathBuf {
+        dir.write_wav("const.wav", 48000, &[0.5; 4800]);
+        dir.write_sfz(
+            "test.sfz",
+            "<region> sample=const.wav pitch_keycenter=60 loop_mode=loop_continuous \
+             loop_start=0 loop_end=4799 ampeg_release=0.2",
+        )
+    }
+
+    fn load_sfz(synth: &mut XSynthRender, sfz: PathBuf) {
+        let sf: Arc<dyn SoundfontBase> = Arc::new(
+            SampleSoundfont::new_sfz(sfz, synth.get_params(), Default::default()).unwrap(),
+        );
+        synth.send_event(SynthEvent::AllChannels(ChannelEvent::Config(
+            ChannelConfigEvent::SetSoundfonts(vec![sf]),
+        )));
+    }
+
+    fn read_samples(path: &PathBuf) -> Vec<f32> {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        reader.samples::<f32>().map(|s| s.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_seamless_loop() {
+        let dir = TestSoundfontDir::new("render_loop");
+        let sfz = write_test_sfz(&dir);
+
+        let mut config = test_config();
+        config.group_options.dc_blocker = false;
+        config.seamless_loop = true;
+        let path = dir.path().join("out.wav");
+        let mut synth = XSynthRender::new(config, path.clone());
+        load_sfz(&mut synth, sfz);
+
+        // The note is still held at the end of the loop
+        synth.send_event_at(
+            SynthEvent::Channel(
+                0,
+                ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key: 60, vel: 127 }),
+            ),
+            0.5,
+        );
+        synth.render_batch(1.0).unwrap();
+        synth.send_event(SynthEvent::AllChannels(ChannelEvent::Audio(
+            ChannelAudioEvent::AllNotesOff,
+        )));
+        synth.finalize().unwrap();
+
+        let samples = read_samples(&path);
+
+        // The release is mixed onto the beginning instead of extending the render
+        assert_eq!(samples.len(), 48000 * 2);
+        let last = samples[samples.len() - 1];
+        assert!(last > 0.1, "{last}");
+        assert!(
+            (samples[1] - last).abs() < last * 0.01,
+            "{} {last}",
+            samples[1]
+        );
+
+        // The release has ended before the note starts again, from 0.3s to 0.45s
+        let silence = &samples[28800..43200];
+        assert!(silence.iter().all(|s| s.abs() < 0.0001));
+    }
+
+    #[test]
+    fn test_channel_filter() {
+        let dir = TestSoundfontDir::new("render_filter");
+        let sfz = write_test_sfz(&dir);
+
+        let render = |filter: Option<ChannelFilter>, channels: &[u32], name: &str| {
+            let mut config = test_config();
+            config.channel_filter = filter;
+            let path = dir.path().join(name);
+            let mut synth = XSynthRender::new(config, path.clone());
+            load_sfz(&mut synth, sfz.clone());
+
+            for (i, &channel) in channels.iter().enumerate() {
+                let key = 60 + i as u8 * 7;
+                let time = 0.1 * i as f64;
+                synth.send_event_at(
+                    SynthEvent::Channel(
+                        channel,
+                        ChannelEvent::Audio(ChannelAudioEvent::ProgramChange(0)),
+                    ),
+                    0.0,
+                );
+                synth.send_event_at(
+                    SynthEvent::Channel(
+                        channel,
+                        ChannelEvent::Audio(ChannelAudioEvent::NoteOn { key, vel: 100 }),
+                    ),
+                    time,
+                );
+                synth.send_event_at(
+                    SynthEvent::Channel(
+                        channel,
+                        ChannelEvent::Audio(ChannelAudioEvent::NoteOff { key, vel: 64 }),
+                    ),
+                    time + 0.3,
+                );
+            }
+            synth.render_batch(0.5).unwrap();
+            synth.finalize().unwrap();
+            read_samples(&path)
+        };
+
+        let reference = render(None, &[0], "reference.wav");
+        let both = render(None, &[0, 3], "both.wav");
+        let only = render(Some(ChannelFilter::Only(vec![0])), &[0, 3], "only.wav");
+        let skip = render(Some(ChannelFilter::Skip(vec![3])), &[0, 3], "skip.wav");
+
+        // Only the notes of the kept channel are rendered
+        assert!(reference.iter().any(|s| s.abs() > 0.1));
+        assert_ne!(both, reference);
+        assert_eq!(only, reference);
+        assert_eq!(skip, reference);
+    }
+
+    /// A synthesizer which panics on its second read.
+    struct PanickingSynth {
+        params: AudioStreamParams,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl AudioPipe for PanickingSynth {
+        fn stream_params(&self) -> &AudioStreamParams {
+            &self.params
+        }
+
+        fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+            if self.reads.fetch_add(1, Ordering::SeqCst) == 1 {
+                panic!("render failure");
+            }
+            to.fill(0.5);
+        }
+    }
+
+    impl RenderSynth for PanickingSynth {
+        fn send_event(&mut self, _event: SynthEvent) {}
+
+        fn send_event_at_offset(&mut self, _event: SynthEvent, _sample_offset: usize) {}
+
+        fn voice_count(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_synth_panic() {
+        let config = test_config();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let synth = PanickingSynth {
+            params: config.group_options.audio_params,
+            reads: reads.clone(),
+        };
+        let path =
+            std::env::temp_dir().join(format!("xsynth_render_panic_{}.wav", std::process::id()));
+        let mut synth = XSynthRender::with_synth(config, path.clone(), Box::new(synth));
+
+        synth.render_batch(0.1).unwrap();
+        let error = synth.render_batch(0.1).unwrap_err();
+        assert!(matches!(&error, RenderError::SynthPanic(m) if m == "render failure"));
+
+        // Nothing is rendered after the error, and the file is deleted
+        assert!(synth.render_batch(0.1).is_err());
+        assert!(synth.finalize().is_err());
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        assert!(!path.exists());
+    }
+
+    /// A synthesizer which always outputs the same value.
+    struct ConstantSynth(AudioStreamParams);
+
+    impl AudioPipe for ConstantSynth {
+        fn stream_params(&self) -> &AudioStreamParams {
+            &self.0
+        }
+
+        fn read_samples_unchecked(&mut self, to: &mut [f32]) {
+            to.fill(0.5);
+        }
+    }
+
+    impl RenderSynth for ConstantSynth {
+        fn send_event(&mut self, _event: SynthEvent) {}
+
+        fn send_event_at_offset(&mut self, _event: SynthEvent, _sample_offset: usize) {}
+
+        fn voice_count(&self) -> u64 {
+            0
+        }
+    }
+
+    /// A file which can't grow beyond a size, like on a full disk.
+    struct FullDiskFile {
+        file: File,
+        limit: u64,
+    }
+
+    impl Write for FullDiskFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let position = self.file.stream_position()?;
+            if position >= self.limit {
+                return Err(io::Error::other("no space left on device"));
+            }
+            let len = buf.len().min((self.limit - position) as usize);
+            self.file.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for FullDiskFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_writer_error() {
+        let config = test_config();
+        let path =
+            std::env::temp_dir().join(format!("xsynth_render_full_{}.wav", std::process::id()));
+        let writer = AudioFileWriter::with_file(config.clone(), path.clone(), |path| {
+            let file = File::create(path)?;
+            Ok(Box::new(FullDiskFile {
+                file,
+                limit: 100_000,
+            }) as Box<dyn OutputFile>)
+        });
+        let synth = Box::new(ConstantSynth(config.group_options.audio_params));
+        let mut synth = XSynthRender::with_writer(config, synth, AudioOutput::File(writer));
+
+        // The samples are queued, so the error is only returned by a batch
+        // if the writer failed before the render finished
+        let error = match (0..100).find_map(|_| synth.render_batch(0.1).err()) {
+            Some(error) => {
+                assert!(synth.finalize().is_err());
+                error
+            }
+            None => synth.finalize().expect_err("the render should fail"),
+        };
+        assert!(
+            matches!(&error, RenderError::Writer(m) if m.contains("no space left")),
+            "{error}"
+        );
+
+        // The audio written until the error is kept in a valid file
+        let samples = read_samples(&path);
+        std::fs::remove_file(&path).ok();
+        assert!(samples.len() > 20_000);
+        assert_eq!(samples.len() % 2, 0);
+        assert!(samples.iter().all(|&s| s == 0.5));
     }
 }
@@ -1,7 +1,17 @@
+use crate::config::OutputSampleFormat;
 use atomic_float::AtomicF64;
-use midi_toolkit::{io::MIDIFile, sequence::event::get_channels_array_statistics};
-use std::sync::{atomic::Ordering, Arc};
-use xsynth_core::{channel_group::ThreadCount, soundfont::Interpolator, ChannelCount};
+use midi_toolkit::{
+    io::{DiskReader, MIDIFile},
+    sequence::event::get_channels_array_statistics,
+};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+use xsynth_core::{
+    channel_group::ThreadCount, effects::EqParams, soundfont::Interpolator, ChannelCount,
+};
 
 #[inline(always)]
 pub fn layers_parser(s: &str) -> Result<Option<usize>, String> {
@@ -38,6 +48,61 @@ pub fn int_parser(s: &str) -> Result<u32, String> {
     s.parse().map_err(|e| format!("{}", e))
 }
 
+#[inline(always)]
+pub fn float_parser(s: &str) -> Result<f32, String> {
+    s.parse().map_err(|e| format!("{}", e))
+}
+
+#[inline(always)]
+pub fn realtime_factor_parser(s: &str) -> Result<f64, String> {
+    let factor: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if factor > 0.0 && factor.is_finite() {
+        Ok(factor)
+    } else {
+        Err("The factor must be a positive number".to_string())
+    }
+}
+
+#[inline(always)]
+pub fn eq_parser(s: &str) -> Result<EqParams, String> {
+    let gains = s
+        .split(',')
+        .map(|g| g.trim().parse::<f32>().map_err(|e| format!("{}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [low, mid, high] = gains[..] else {
+        return Err("Expected the gains of the 3 bands".to_string());
+    };
+
+    let mut params = EqParams::default();
+    params.low.gain = low;
+    params.mid.gain = mid;
+    params.high.gain = high;
+    Ok(params)
+}
+
+/// Parses a list of MIDI channels counting from 1, returning them zero-indexed.
+#[inline(always)]
+pub fn channel_list_parser(s: &str) -> Result<Vec<u32>, String> {
+    s.split(',')
+        .map(
+            |c| match c.trim().parse::<u32>().map_err(|e| format!("{}", e))? {
+                0 => Err("MIDI channels start from 1".to_string()),
+                c => Ok(c - 1),
+            },
+        )
+        .collect()
+}
+
+#[inline(always)]
+pub fn bit_depth_parser(s: &str) -> Result<OutputSampleFormat, String> {
+    match s {
+        "16" => Ok(OutputSampleFormat::Int16),
+        "24" => Ok(OutputSampleFormat::Int24),
+        "32" => Ok(OutputSampleFormat::Float32),
+        _ => Err("Invalid bit depth".to_string()),
+    }
+}
+
 #[inline(always)]
 pub fn interpolation_parser(s: &str) -> Result<Interpolator, String> {
     match s {
@@ -47,8 +112,7 @@ pub fn interpolation_parser(s: &str) -> Result<Interpolator, String> {
     }
 }
 
-pub fn get_midi_length(path: &str) -> f64 {
-    let midi = MIDIFile::open(path, None).unwrap();
+pub fn get_midi_length(midi: &MIDIFile<DiskReader>) -> f64 {
     let parse_length_outer = Arc::new(AtomicF64::new(f64::NAN));
     let ppq = midi.ppq();
     let tracks = midi.iter_all_tracks().collect();
@@ -62,3 +126,48 @@ pub fn get_midi_length(path: &str) -> f64 {
 
     parse_length_outer.load(Ordering::Relaxed)
 }
+
+/// Paces a process which produces audio to run at most a given factor
+/// faster than realtime, using a monotonic clock started by `start`.
+pub struct Pacer {
+    start: Option<Instant>,
+    max_factor: Option<f64>,
+}
+
+impl Pacer {
+    pub fn new(max_factor: Option<f64>) -> Self {
+        Self {
+            start: None,
+            max_factor,
+        }
+    }
+
+    /// Starts the clock, if it wasn't started yet.
+    pub fn start(&mut self) {
+        self.start.get_or_insert_with(Instant::now);
+    }
+
+    /// Sleeps until the given amount of audio in seconds is due at the
+    /// maximum factor, counting from the start of the clock.
+    pub fn pace(&mut self, audio_seconds: f64) {
+        self.start();
+        let (Some(start), Some(max_factor)) = (self.start, self.max_factor) else {
+            return;
+        };
+
+        let due = start + Duration::from_secs_f64(audio_seconds / max_factor);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+
+    /// Returns the achieved factor of realtime for the given amount of
+    /// audio in seconds, or zero if the clock wasn't started.
+    pub fn factor(&self, audio_seconds: f64) -> f64 {
+        match self.start {
+            Some(start) => audio_seconds / start.elapsed().as_secs_f64().max(f64::EPSILON),
+            None => 0.0,
+        }
+    }
+}
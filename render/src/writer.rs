@@ -1,39 +1,663 @@
-use crate::config::XSynthRenderConfig;
+use crate::{
+    config::{OutputSampleFormat, XSynthRenderConfig},
+    rendered::{panic_message, RenderError},
+};
 
-use std::{path::PathBuf, thread};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
 
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
 use hound::{WavSpec, WavWriter};
+use xsynth_core::{helpers::db_to_amp, ChannelCount};
 
-pub struct AudioFileWriter {
-    sender: Sender<Vec<f32>>,
+/// Statistics of the audio written to the output file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// The true peak of the audio as an amplitude, measured with
+    /// 4x oversampling to include the peaks between samples
+    pub true_peak: f32,
+
+    /// The RMS level of the audio as an amplitude
+    pub rms: f32,
+
+    /// Amount of samples exceeding the full scale
+    pub clipped_samples: u64,
 }
 
-impl AudioFileWriter {
-    pub fn new(config: XSynthRenderConfig, path: PathBuf) -> Self {
+impl RenderStats {
+    /// Returns the true peak in dBTP.
+    pub fn true_peak_db(&self) -> f32 {
+        amp_to_db(self.true_peak)
+    }
+
+    /// Returns the RMS level in dBFS.
+    pub fn rms_db(&self) -> f32 {
+        amp_to_db(self.rms)
+    }
+}
+
+fn amp_to_db(amp: f32) -> f32 {
+    20.0 * amp.log10()
+}
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// Measures the true peak of interleaved audio by interpolating
+/// it with a windowed sinc filter.
+struct TruePeakMeter {
+    coefficients: Vec<[f32; TAPS_PER_PHASE]>,
+    history: Vec<[f32; TAPS_PER_PHASE]>,
+    channel: usize,
+    peak: f32,
+}
+
+impl TruePeakMeter {
+    fn new(channels: usize) -> Self {
+        let taps = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (taps - 1) as f32 / 2.0;
+
+        let mut coefficients = vec![[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for (phase, coefficients) in coefficients.iter_mut().enumerate() {
+            for (k, coef) in coefficients.iter_mut().enumerate() {
+                let i = k * OVERSAMPLING + phase;
+                let x = (i as f32 - center) / OVERSAMPLING as f32;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+                };
+                let window =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * (i as f32 + 0.5) / taps as f32).cos();
+                *coef = sinc * window;
+            }
+
+            // Keep the gain of each phase at unity
+            let sum: f32 = coefficients.iter().sum();
+            for coef in coefficients.iter_mut() {
+                *coef /= sum;
+            }
+        }
+
+        Self {
+            coefficients,
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            channel: 0,
+            peak: 0.0,
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        let history = &mut self.history[self.channel];
+        history.copy_within(1.., 0);
+        history[TAPS_PER_PHASE - 1] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+        for coefficients in &self.coefficients {
+            let value: f32 = coefficients
+                .iter()
+                .zip(history.iter().rev())
+                .map(|(c, s)| c * s)
+                .sum();
+            self.peak = self.peak.max(value.abs());
+        }
+
+        self.channel = (self.channel + 1) % self.history.len();
+    }
+}
+
+/// Accumulates the statistics of the written audio.
+struct StatsMeter {
+    true_peak: TruePeakMeter,
+    square_sum: f64,
+    count: u64,
+    clipped_samples: u64,
+}
+
+impl StatsMeter {
+    fn new(channels: usize) -> Self {
+        Self {
+            true_peak: TruePeakMeter::new(channels),
+            square_sum: 0.0,
+            count: 0,
+            clipped_samples: 0,
+        }
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.true_peak.push(sample);
+        self.square_sum += (sample as f64).powi(2);
+        self.count += 1;
+        if sample.abs() > 1.0 {
+            self.clipped_samples += 1;
+        }
+    }
+
+    fn stats(&self) -> RenderStats {
+        RenderStats {
+            true_peak: self.true_peak.peak,
+            rms: (self.square_sum / self.count.max(1) as f64).sqrt() as f32,
+            clipped_samples: self.clipped_samples,
+        }
+    }
+}
+
+/// Triangular probability density function dither with an amplitude
+/// of one 16-bit LSB, using a xorshift generator.
+struct TpdfDither {
+    state: u32,
+}
+
+impl TpdfDither {
+    fn new() -> Self {
+        Self { state: 0x9E3779B9 }
+    }
+
+    fn next_uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    fn next(&mut self) -> f32 {
+        self.next_uniform() - self.next_uniform()
+    }
+}
+
+/// Converts interleaved samples to another channel layout. Mono downmixes
+/// average the left and right channels, so that sounds panned to one side
+/// aren't lost, and mono upmixes copy the samples to both channels.
+fn remix(samples: Vec<f32>, from: ChannelCount, to: ChannelCount) -> Vec<f32> {
+    match (from, to) {
+        (ChannelCount::Stereo, ChannelCount::Mono) => samples
+            .chunks_exact(2)
+            .map(|frame| (frame[0] + frame[1]) / 2.0)
+            .collect(),
+        (ChannelCount::Mono, ChannelCount::Stereo) => {
+            samples.iter().flat_map(|&s| [s, s]).collect()
+        }
+        _ => samples,
+    }
+}
+
+/// The largest value of a 24-bit sample.
+const I24_MAX: f32 = 8388607.0;
+
+/// The file the WAV audio is written to. Implemented for any seekable
+/// writer, so that the tests can simulate failing disks.
+pub(crate) trait OutputFile: Write + Seek + Send {}
+
+impl<T: Write + Seek + Send> OutputFile for T {}
+
+/// Writes the final samples to the WAV file in the configured format.
+struct SampleOutput {
+    writer: WavWriter<BufWriter<Box<dyn OutputFile>>>,
+    format: OutputSampleFormat,
+    dither: Option<TpdfDither>,
+    meter: StatsMeter,
+}
+
+impl SampleOutput {
+    fn new(config: &XSynthRenderConfig, file: Box<dyn OutputFile>) -> hound::Result<Self> {
+        let channels = config.output_channel_count().count();
+        let sample_format = match config.sample_format {
+            OutputSampleFormat::Float32 => hound::SampleFormat::Float,
+            OutputSampleFormat::Int24 | OutputSampleFormat::Int16 => hound::SampleFormat::Int,
+        };
         let spec = WavSpec {
-            channels: config.group_options.audio_params.channels.count(),
+            channels,
             sample_rate: config.group_options.audio_params.sample_rate,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
+            bits_per_sample: config.sample_format.bits_per_sample(),
+            sample_format,
         };
-        let mut writer = WavWriter::create(path, spec).unwrap();
 
-        let (snd, rcv) = crossbeam_channel::unbounded::<Vec<f32>>();
+        // The quantization noise of 24-bit samples is already inaudible
+        let dither = config.dither && config.sample_format == OutputSampleFormat::Int16;
+        Ok(Self {
+            writer: WavWriter::new(BufWriter::new(file), spec)?,
+            format: config.sample_format,
+            dither: dither.then(TpdfDither::new),
+            meter: StatsMeter::new(channels as usize),
+        })
+    }
 
-        thread::spawn(move || {
-            for batch in rcv {
-                for s in batch {
-                    writer.write_sample(s).unwrap();
+    fn write_sample(&mut self, sample: f32) -> hound::Result<()> {
+        self.meter.push(sample);
+        match self.format {
+            OutputSampleFormat::Float32 => self.writer.write_sample(sample),
+            OutputSampleFormat::Int24 => {
+                let value = (sample * I24_MAX).round().clamp(-I24_MAX - 1.0, I24_MAX) as i32;
+                self.writer.write_sample(value)
+            }
+            OutputSampleFormat::Int16 => {
+                let mut value = sample * i16::MAX as f32;
+                if let Some(dither) = &mut self.dither {
+                    value += dither.next();
                 }
+                let value = value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                self.writer.write_sample(value)
             }
-            writer.finalize().unwrap();
+        }
+    }
+
+    fn finalize(self) -> hound::Result<RenderStats> {
+        self.writer.finalize()?;
+        Ok(self.meter.stats())
+    }
+}
+
+/// Streams the samples to a temporary file while measuring the peak,
+/// so that the gain can be applied without keeping the audio in memory.
+struct NormalizingOutput {
+    temp: BufWriter<File>,
+    temp_path: PathBuf,
+    meter: TruePeakMeter,
+    target: f32,
+}
+
+impl NormalizingOutput {
+    fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        self.meter.push(sample);
+        self.temp.write_all(&sample.to_le_bytes())
+    }
+
+    fn finalize(mut self, mut output: SampleOutput) -> hound::Result<RenderStats> {
+        self.temp.flush()?;
+        drop(self.temp);
+
+        let gain = if self.meter.peak > 0.0 {
+            self.target / self.meter.peak
+        } else {
+            1.0
+        };
+
+        let mut reader = BufReader::new(File::open(&self.temp_path)?);
+        let mut bytes = [0; 4];
+        while reader.read_exact(&mut bytes).is_ok() {
+            output.write_sample(f32::from_le_bytes(bytes) * gain)?;
+        }
+
+        std::fs::remove_file(&self.temp_path).ok();
+        output.finalize()
+    }
+}
+
+/// Returns the path of the temporary file used for normalization.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+/// Writes the received samples to the output file in its channel layout,
+/// normalizing them if configured.
+fn write_output(
+    config: &XSynthRenderConfig,
+    path: &Path,
+    file: Box<dyn OutputFile>,
+    rcv: Receiver<Vec<f32>>,
+) -> hound::Result<RenderStats> {
+    let mut output = SampleOutput::new(config, file)?;
+    let channels = config.output_channel_count();
+    let rcv = rcv
+        .into_iter()
+        .map(|batch| remix(batch, config.group_options.audio_params.channels, channels));
+
+    if let Some(target) = config.normalize {
+        let temp_path = temp_path(path);
+
+        let mut normalizer = NormalizingOutput {
+            temp: BufWriter::new(File::create(&temp_path)?),
+            temp_path,
+            meter: TruePeakMeter::new(channels.count() as usize),
+            target: db_to_amp(target),
+        };
+        for batch in rcv {
+            for s in batch {
+                normalizer.write_sample(s)?;
+            }
+        }
+        normalizer.finalize(output)
+    } else {
+        for batch in rcv {
+            for s in batch {
+                output.write_sample(s)?;
+            }
+        }
+        output.finalize()
+    }
+}
+
+/// Makes a WAV file which couldn't be finished valid, by setting the length
+/// in its header to the whole frames that were written.
+fn finalize_partial(path: &Path, frame_size: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.metadata()?.len();
+
+    // Find the data chunk, after the RIFF header and the format chunk
+    let mut position = 12;
+    let mut header = [0; 8];
+    loop {
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header)?;
+        position += 8;
+        if &header[..4] == b"data" {
+            break;
+        }
+        position += u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+    }
+
+    let data_len = file_len.saturating_sub(position) / frame_size * frame_size;
+    file.set_len(position + data_len)?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&((position + data_len - 8) as u32).to_le_bytes())?;
+    file.seek(SeekFrom::Start(position - 4))?;
+    file.write_all(&(data_len as u32).to_le_bytes())?;
+    file.flush()
+}
+
+type WriterResult = Result<RenderStats, RenderError>;
+
+pub struct AudioFileWriter {
+    sender: Option<Sender<Vec<f32>>>,
+    thread: Option<JoinHandle<WriterResult>>,
+    result: Option<WriterResult>,
+    path: PathBuf,
+}
+
+impl AudioFileWriter {
+    pub fn new(config: XSynthRenderConfig, path: PathBuf) -> Self {
+        Self::with_file(config, path, |path| {
+            File::create(path).map(|f| Box::new(f) as Box<dyn OutputFile>)
+        })
+    }
+
+    /// Creates a writer which writes the WAV data to the file returned by
+    /// `create`, instead of creating it at the path.
+    pub(crate) fn with_file(
+        config: XSynthRenderConfig,
+        path: PathBuf,
+        create: impl FnOnce(&Path) -> io::Result<Box<dyn OutputFile>> + Send + 'static,
+    ) -> Self {
+        let (snd, rcv) = crossbeam_channel::unbounded::<Vec<f32>>();
+
+        let thread_path = path.clone();
+        let thread = thread::spawn(move || {
+            let path = thread_path;
+            let result = create(&path)
+                .map_err(hound::Error::from)
+                .and_then(|file| write_output(&config, &path, file, rcv));
+
+            result.map_err(|e| {
+                // Keep the audio written until the error
+                let channels = config.output_channel_count().count() as u64;
+                let sample_size = config.sample_format.bits_per_sample() as u64 / 8;
+                finalize_partial(&path, channels * sample_size).ok();
+                std::fs::remove_file(temp_path(&path)).ok();
+
+                RenderError::Writer(e.to_string())
+            })
         });
 
-        Self { sender: snd }
+        Self {
+            sender: Some(snd),
+            thread: Some(thread),
+            result: None,
+            path,
+        }
+    }
+
+    /// Queues the samples to be written. Returns the error of the writer
+    /// if writing failed.
+    pub fn write_samples(&mut self, samples: &mut Vec<f32>) -> Result<(), RenderError> {
+        let sender = self.sender.as_ref().expect("the writer is still open");
+        if sender.send(std::mem::take(samples)).is_err() {
+            // The writer thread only stops early after an error
+            self.sender = None;
+            self.join()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the writer thread to finish and returns its result.
+    /// If it panicked, the output file is deleted.
+    fn join(&mut self) -> WriterResult {
+        if let Some(thread) = self.thread.take() {
+            let result = thread.join().unwrap_or_else(|e| {
+                remove_output(&self.path);
+                Err(RenderError::Writer(panic_message(e)))
+            });
+            self.result = Some(result);
+        }
+        self.result.clone().unwrap()
+    }
+
+    /// Waits for all the samples to be written, finalizes the file and
+    /// returns the statistics of the written audio.
+    ///
+    /// If writing failed, the error is returned and the audio written
+    /// until the error is kept in the file.
+    pub fn finalize(mut self) -> WriterResult {
+        self.sender = None;
+        self.join()
+    }
+
+    /// Stops writing and deletes the output file. If writing failed,
+    /// the audio written until the error is kept instead.
+    pub fn abort(mut self) {
+        self.sender = None;
+        if self.join().is_ok() {
+            remove_output(&self.path);
+        }
+    }
+}
+
+/// Keeps the rendered samples in memory instead of writing them to a
+/// file. The samples stay 32-bit floats in the layout of the render, so
+/// the sample format, output channels and dither options don't apply,
+/// while the normalization does.
+pub struct MemoryWriter {
+    samples: Vec<f32>,
+    channels: usize,
+    normalize: Option<f32>,
+}
+
+impl MemoryWriter {
+    pub fn new(config: &XSynthRenderConfig) -> Self {
+        Self {
+            samples: Vec::new(),
+            channels: config.group_options.audio_params.channels.count() as usize,
+            normalize: config.normalize,
+        }
     }
 
+    /// Appends the samples to the buffer.
     pub fn write_samples(&mut self, samples: &mut Vec<f32>) {
-        self.sender.send(std::mem::take(samples)).unwrap();
+        self.samples.append(samples);
+    }
+
+    /// Normalizes the samples if configured, and returns them along
+    /// with their statistics.
+    pub fn finalize(mut self) -> (Vec<f32>, RenderStats) {
+        if let Some(target) = self.normalize {
+            let mut meter = TruePeakMeter::new(self.channels);
+            for &s in &self.samples {
+                meter.push(s);
+            }
+            if meter.peak > 0.0 {
+                let gain = db_to_amp(target) / meter.peak;
+                self.samples.iter_mut().for_each(|s| *s *= gain);
+            }
+        }
+
+        let mut meter = StatsMeter::new(self.channels);
+        for &s in &self.samples {
+            meter.push(s);
+        }
+        (self.samples, meter.stats())
+    }
+}
+
+/// The destination of the rendered audio.
+pub enum AudioOutput {
+    File(AudioFileWriter),
+    Memory(MemoryWriter),
+}
+
+impl AudioOutput {
+    /// Queues the samples to be written. Returns the error of the writer
+    /// if writing failed.
+    pub fn write_samples(&mut self, samples: &mut Vec<f32>) -> Result<(), RenderError> {
+        match self {
+            AudioOutput::File(writer) => writer.write_samples(samples),
+            AudioOutput::Memory(writer) => {
+                writer.write_samples(samples);
+                Ok(())
+            }
+        }
+    }
+
+    /// Finishes writing and returns the statistics of the written audio,
+    /// along with the samples if they were kept in memory.
+    pub fn finalize(self) -> Result<(RenderStats, Option<Vec<f32>>), RenderError> {
+        match self {
+            AudioOutput::File(writer) => writer.finalize().map(|stats| (stats, None)),
+            AudioOutput::Memory(writer) => {
+                let (samples, stats) = writer.finalize();
+                Ok((stats, Some(samples)))
+            }
+        }
+    }
+
+    /// Stops writing. See `AudioFileWriter::abort`.
+    pub fn abort(self) {
+        if let AudioOutput::File(writer) = self {
+            writer.abort();
+        }
+    }
+}
+
+/// Deletes the output file and its temporary file, if they exist.
+fn remove_output(path: &Path) {
+    std::fs::remove_file(path).ok();
+    std::fs::remove_file(temp_path(path)).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xsynth_core::{
+        channel::ChannelInitOptions,
+        channel_group::{ChannelGroupConfig, ParallelismOptions, SynthFormat, ThreadCount},
+        AudioStreamParams, ChannelCount,
+    };
+
+    fn write_sine(config: XSynthRenderConfig, name: &str) -> (RenderStats, Vec<i32>) {
+        let path =
+            std::env::temp_dir().join(format!("xsynth_render_{name}_{}.wav", std::process::id()));
+        let mut writer = AudioFileWriter::new(config, path.clone());
+        for _ in 0..10 {
+            let mut samples: Vec<f32> = (0..4800)
+                .map(|i| 0.25 * (i as f32 / 48.0 * std::f32::consts::TAU).sin())
+                .collect();
+            writer.write_samples(&mut samples).unwrap();
+        }
+        let stats = writer.finalize().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 16);
+        assert_eq!(reader.len(), 48000);
+        let samples = reader.samples::<i32>().map(|s| s.unwrap()).collect();
+        std::fs::remove_file(path).ok();
+        (stats, samples)
+    }
+
+    #[test]
+    fn test_normalize_16bit() {
+        let config = XSynthRenderConfig {
+            group_options: ChannelGroupConfig {
+                channel_init_options: ChannelInitOptions::default(),
+                format: SynthFormat::Midi,
+                audio_params: AudioStreamParams::new(48000, ChannelCount::Mono),
+                parallelism: ParallelismOptions {
+                    channel: ThreadCount::None,
+                    key: ThreadCount::None,
+                },
+                dc_blocker: true,
+                multi_port_percussion: true,
+                mpe: None,
+                master_tuning_hz: 440.0,
+            },
+            sf_options: Default::default(),
+            layers: Some(32),
+            use_limiter: false,
+            normalize: None,
+            sample_format: OutputSampleFormat::Int16,
+            output_channels: None,
+            dither: false,
+            eq: None,
+            seamless_loop: false,
+            channel_filter: None,
+            max_realtime_factor: None,
+            ignore_ports: false,
+        };
+
+        let (stats, samples) = write_sine(config.clone(), "plain");
+        assert!((stats.true_peak - 0.25).abs() < 1e-3);
+        assert!((stats.rms - 0.25 / 2.0f32.sqrt()).abs() < 1e-3);
+        assert_eq!(stats.clipped_samples, 0);
+        assert_eq!(samples.iter().max(), Some(&8192));
+
+        let config = XSynthRenderConfig {
+            normalize: Some(-6.0),
+            dither: true,
+            ..config
+        };
+        let (stats, samples) = write_sine(config, "normalized");
+        assert!((stats.true_peak_db() + 6.0).abs() < 0.01);
+        let peak = *samples.iter().max().unwrap() as f32 / i16::MAX as f32;
+        assert!((amp_to_db(peak) + 6.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_true_peak_meter() {
+        // A sine at a quarter of the sample rate with a 45 degree phase
+        // offset never has a sample at its peak
+        let mut meter = TruePeakMeter::new(1);
+        let mut sample_peak = 0.0f32;
+        for i in 0..1000 {
+            let s = (std::f32::consts::PI * (i as f32 / 2.0 + 0.25)).sin();
+            sample_peak = sample_peak.max(s.abs());
+            meter.push(s);
+        }
+        assert!((sample_peak - 0.5f32.sqrt()).abs() < 1e-3);
+        assert!((meter.peak - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_remix() {
+        use ChannelCount::*;
+
+        let stereo = vec![0.5, 0.0, 0.2, -0.2, -1.0, -0.5];
+        assert_eq!(remix(stereo.clone(), Stereo, Mono), [0.25, 0.0, -0.75]);
+        assert_eq!(remix(stereo.clone(), Stereo, Stereo), stereo);
+        assert_eq!(
+            remix(vec![0.5, -0.25], Mono, Stereo),
+            [0.5, 0.5, -0.25, -0.25]
+        );
+    }
+
+    #[test]
+    fn test_tpdf_dither_range() {
+        let mut dither = TpdfDither::new();
+        let values: Vec<f32> = (0..10000).map(|_| dither.next()).collect();
+        assert!(values.iter().all(|v| v.abs() <= 1.0));
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(mean.abs() < 0.02);
     }
 }
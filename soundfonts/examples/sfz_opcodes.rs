@@ -0,0 +1,55 @@
+use std::{path::Path, sync::Arc};
+
+use xsynth_soundfonts::sfz::ast::{parse_file, IncludeMode, SfzAstItem, SfzAstOpcode, SfzHeader};
+
+/// Lists the opcodes which apply to each region of an SFZ file, including
+/// the ones inherited from its headers, with their file and line.
+pub fn main() {
+    let args = std::env::args().collect::<Vec<String>>();
+    let Some(sfz) = args.get(1) else {
+        println!(
+            "Usage: {} [sfz]",
+            std::env::current_exe()
+                .unwrap_or("example".into())
+                .display()
+        );
+        return;
+    };
+
+    // The opcodes grouped by the headers they are under, in file order
+    let mut groups: Vec<(Arc<[SfzHeader]>, Vec<SfzAstOpcode>)> = Vec::new();
+    for item in parse_file(Path::new(sfz), IncludeMode::Resolve) {
+        match item {
+            Ok(SfzAstItem::Opcode(opcode)) => match groups.last_mut() {
+                Some((path, opcodes)) if Arc::ptr_eq(path, &opcode.group_path) => {
+                    opcodes.push(opcode)
+                }
+                _ => groups.push((opcode.group_path.clone(), vec![opcode])),
+            },
+            Ok(_) => {}
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+
+    for (path, opcodes) in groups.iter() {
+        let Some(region) = path.last().filter(|h| h.name == "region") else {
+            continue;
+        };
+        println!("<region> {}", region.location);
+
+        // The opcodes of the parent headers come first
+        let inherited = groups
+            .iter()
+            .filter(|(parent, _)| parent.len() < path.len() && path.starts_with(parent))
+            .flat_map(|(_, opcodes)| opcodes);
+        for opcode in inherited.chain(opcodes) {
+            println!(
+                "  {}={} ({}:{})",
+                opcode.name,
+                opcode.value,
+                opcode.location.file.display(),
+                opcode.location.line
+            );
+        }
+    }
+}
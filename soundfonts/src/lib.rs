@@ -39,6 +39,68 @@ pub enum LoopMode {
 
 /// Converts the sample index of an audio sample array when
 /// it is resampled.
+///
+/// The index is scaled in integer arithmetic and rounded to the nearest
+/// index, with the halfway cases rounded up, so the result is exact for
+/// any index. Indices are left unchanged if `old_sample_rate` is 0.
+pub fn convert_sample_index_u64(idx: u64, old_sample_rate: u32, new_sample_rate: u32) -> u64 {
+    if old_sample_rate == 0 {
+        return idx;
+    }
+    let old_rate = old_sample_rate as u128;
+    let idx = (idx as u128 * new_sample_rate as u128 + old_rate / 2) / old_rate;
+    idx.min(u64::MAX as u128) as u64
+}
+
+/// Converts the sample index of an audio sample array when
+/// it is resampled, like `convert_sample_index_u64` but with 32-bit
+/// indices. Converted indices which don't fit in 32 bits saturate
+/// to `u32::MAX`.
 pub fn convert_sample_index(idx: u32, old_sample_rate: u32, new_sample_rate: u32) -> u32 {
-    (new_sample_rate as f32 * idx as f32 / old_sample_rate as f32).round() as u32
+    convert_sample_index_u64(idx as u64, old_sample_rate, new_sample_rate).min(u32::MAX as u64)
+        as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_sample_index_precision() {
+        // The previous implementation, which used f32 math
+        let convert_f32 = |idx: u32| (48000.0f32 * idx as f32 / 44100.0f32).round() as u32;
+
+        // Below 2^24 both are exact
+        assert_eq!(convert_f32(16_777_215), 18_260_914);
+        assert_eq!(convert_sample_index(16_777_215, 44100, 48000), 18_260_914);
+
+        // Above it, f32 can't represent every index and the result is off by a frame
+        assert_eq!(convert_f32(16_777_219), 18_260_920);
+        assert_eq!(convert_sample_index(16_777_219, 44100, 48000), 18_260_919);
+        assert_eq!(convert_f32(16_777_221), 18_260_920);
+        assert_eq!(convert_sample_index(16_777_221, 44100, 48000), 18_260_921);
+
+        for idx in (1 << 24) - 4..(1 << 24) + 4 {
+            let exact = (idx as u64 * 48000 + 22050) / 44100;
+            assert_eq!(convert_sample_index(idx, 44100, 48000) as u64, exact);
+        }
+
+        // Halfway cases are rounded up
+        assert_eq!(convert_sample_index(1, 2, 1), 1);
+        assert_eq!(convert_sample_index(3, 2, 1), 2);
+    }
+
+    #[test]
+    fn test_convert_sample_index_u64() {
+        assert_eq!(
+            convert_sample_index_u64(1 << 40, 44100, 192000),
+            4_786_989_399_841
+        );
+        assert_eq!(convert_sample_index(u32::MAX, 48000, 96000), u32::MAX);
+        assert_eq!(
+            convert_sample_index_u64(u32::MAX as u64, 48000, 96000),
+            u32::MAX as u64 * 2
+        );
+        assert_eq!(convert_sample_index_u64(100, 0, 48000), 100);
+    }
 }
@@ -1,21 +1,89 @@
+use crate::convert_sample_index_u64;
 use rubato::{
     Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
 };
 use std::sync::Arc;
 
+/// The algorithm used to resample the audio samples of a soundfont
+/// to the output sample rate when it is loaded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ResampleQuality {
+    /// Nearest neighbor resampling. The fastest, but it adds audible
+    /// aliasing and distortion when the sample rates differ a lot.
+    Nearest,
+
+    /// Linear interpolation between the samples. Fast, with less
+    /// distortion than nearest neighbor resampling.
+    Linear,
+
+    /// Windowed sinc resampling, which filters the frequencies above the
+    /// Nyquist frequency of the lower rate. The slowest but cleanest.
+    #[default]
+    Sinc,
+}
+
 /// Resample multiple audio sample vectors
 pub fn resample_vecs(
     vecs: Vec<Vec<f32>>,
     sample_rate: f32,
     new_sample_rate: f32,
+    quality: ResampleQuality,
 ) -> Arc<[Arc<[f32]>]> {
     vecs.into_iter()
-        .map(|samples| resample_vec(samples, sample_rate, new_sample_rate))
+        .map(|samples| resample_vec(samples, sample_rate, new_sample_rate, quality))
         .collect()
 }
 
 /// Resample a single audio sample vector
-pub fn resample_vec(vec: Vec<f32>, sample_rate: f32, new_sample_rate: f32) -> Arc<[f32]> {
+pub fn resample_vec(
+    vec: Vec<f32>,
+    sample_rate: f32,
+    new_sample_rate: f32,
+    quality: ResampleQuality,
+) -> Arc<[f32]> {
+    // Resampling at the same rate would only shift and shorten the sample
+    if sample_rate == new_sample_rate {
+        return vec.into();
+    }
+
+    let ratio = new_sample_rate as f64 / sample_rate as f64;
+
+    // Same rounding as `convert_sample_index`, so the loop points match
+    let len = convert_sample_index_u64(vec.len() as u64, sample_rate as u32, new_sample_rate as u32)
+        as usize;
+    match quality {
+        ResampleQuality::Nearest => resample_interpolated(&vec, ratio, len, |vec, pos| {
+            vec[(pos.round() as usize).min(vec.len() - 1)]
+        }),
+        ResampleQuality::Linear => resample_interpolated(&vec, ratio, len, |vec, pos| {
+            let index = pos as usize;
+            let next = (index + 1).min(vec.len() - 1);
+            let frac = (pos - index as f64) as f32;
+            vec[index] + (vec[next] - vec[index]) * frac
+        }),
+        ResampleQuality::Sinc => resample_sinc(vec, ratio),
+    }
+}
+
+/// Resamples to the given length by reading the input at the position of
+/// each output sample using the given interpolation function.
+fn resample_interpolated(
+    vec: &[f32],
+    ratio: f64,
+    len: usize,
+    interpolate: impl Fn(&[f32], f64) -> f32,
+) -> Arc<[f32]> {
+    if vec.is_empty() {
+        return Arc::new([]);
+    }
+
+    (0..len)
+        .map(|i| interpolate(vec, (i as f64 / ratio).min((vec.len() - 1) as f64)))
+        .collect()
+}
+
+fn resample_sinc(vec: Vec<f32>, ratio: f64) -> Arc<[f32]> {
     let params = SincInterpolationParameters {
         sinc_len: 32,
         f_cutoff: 0.95,
@@ -25,13 +93,6 @@ pub fn resample_vec(vec: Vec<f32>, sample_rate: f32, new_sample_rate: f32) -> Ar
     };
 
     let len = vec.len();
-    let mut resampler = SincFixedIn::<f32>::new(
-        new_sample_rate as f64 / sample_rate as f64,
-        2.0,
-        params,
-        len,
-        1,
-    )
-    .unwrap();
+    let mut resampler = SincFixedIn::<f32>::new(ratio, 2.0, params, len, 1).unwrap();
     resampler.process(&[vec], None).unwrap()[0].clone().into()
 }
@@ -1,4 +1,8 @@
-use crate::{sfz::AmpegEnvelopeParams, LoopMode};
+use crate::{
+    resample::ResampleQuality,
+    sfz::{AmpegEnvelopeParams, PitchegEnvelopeParams},
+    LoopMode,
+};
 use std::{fs::File, ops::RangeInclusive, path::PathBuf, sync::Arc};
 
 use thiserror::Error;
@@ -35,6 +39,12 @@ pub struct Sf2Region {
     pub cutoff: Option<f32>,
     pub resonance: f32,
     pub ampeg_envelope: AmpegEnvelopeParams,
+    pub pitcheg_envelope: PitchegEnvelopeParams,
+    pub mod_env_to_filter_fc: f32,
+    pub mod_lfo_delay: f32,
+    pub mod_lfo_freq: f32,
+    pub mod_lfo_to_filter_fc: f32,
+    pub mod_lfo_to_volume: f32,
     pub fine_tune: i16,
     pub coarse_tune: i16,
 }
@@ -42,15 +52,22 @@ pub struct Sf2Region {
 /// Structure that holds the parameters of an SF2 preset.
 #[derive(Clone, Debug)]
 pub struct Sf2Preset {
+    pub name: String,
     pub bank: u16,
     pub preset: u16,
     pub regions: Vec<Sf2Region>,
 }
 
 /// Parses an SF2 file and returns its presets in a vector.
+///
+/// The samples, loop points and offsets are converted to `sample_rate`,
+/// with the samples resampled using the given quality. If it is `None`,
+/// they are kept at the native sample rate of each sample, which is stored
+/// in `Sf2Region::sample_rate`.
 pub fn load_soundfont(
     sf2_path: impl Into<PathBuf>,
-    sample_rate: u32,
+    sample_rate: Option<u32>,
+    quality: ResampleQuality,
 ) -> Result<Vec<Sf2Preset>, Sf2ParseError> {
     let sf2_path: PathBuf = sf2_path.into();
     let sf2_path: PathBuf = sf2_path
@@ -68,6 +85,7 @@ pub fn load_soundfont(
         sf2.sample_headers,
         sf2.sample_data,
         sample_rate,
+        quality,
     )?;
 
     let instruments = instrument::Sf2Instrument::parse_instruments(sf2.instruments);
@@ -1,10 +1,15 @@
 use super::{instrument::Sf2Instrument, sample::Sf2Sample, zone::Sf2Zone, Sf2Preset, Sf2Region};
-use crate::{convert_sample_index, sfz::AmpegEnvelopeParams, LoopMode};
+use crate::{
+    convert_sample_index,
+    sfz::{AmpegEnvelopeParams, PitchegEnvelopeParams},
+    LoopMode,
+};
 use soundfont::Preset;
 use std::{ops::RangeInclusive, sync::Arc};
 
 #[derive(Clone, Debug)]
 pub struct Sf2ParsedPreset {
+    pub name: String,
     pub bank: u16,
     pub preset: u16,
     pub zones: Vec<Sf2Zone>,
@@ -18,6 +23,7 @@ impl Sf2ParsedPreset {
             let zones = Sf2Zone::parse(preset.zones);
 
             presets_parsed.push(Sf2ParsedPreset {
+                name: preset.header.name,
                 preset: preset.header.preset,
                 bank: preset.header.bank,
                 zones,
@@ -31,12 +37,13 @@ impl Sf2ParsedPreset {
         sample_data: Vec<Sf2Sample>,
         instruments: Vec<Sf2Instrument>,
         presets: Vec<Sf2ParsedPreset>,
-        sample_rate: u32,
+        sample_rate: Option<u32>,
     ) -> Vec<Sf2Preset> {
         let mut out: Vec<Sf2Preset> = Vec::new();
 
         for preset in presets {
             let mut new_preset = Sf2Preset {
+                name: preset.name,
                 preset: preset.preset,
                 bank: preset.bank,
                 regions: Vec::new(),
@@ -51,6 +58,21 @@ impl Sf2ParsedPreset {
                     for subzone in &instrument.regions {
                         if let Some(sample_idx) = subzone.index {
                             let sample = &sample_data[sample_idx as usize];
+                            let convert_index = |idx| match sample_rate {
+                                Some(sample_rate) => {
+                                    convert_sample_index(idx, sample.sample_rate, sample_rate)
+                                }
+                                None => idx,
+                            };
+
+                            let mod_env_to_filter_fc = (zone.mod_env_to_filter_fc.unwrap_or(0)
+                                + subzone.mod_env_to_filter_fc.unwrap_or(0))
+                                as f32;
+                            let mod_lfo_to_filter_fc = (zone.mod_lfo_to_filter_fc.unwrap_or(0)
+                                + subzone.mod_lfo_to_filter_fc.unwrap_or(0))
+                                as f32;
+                            let has_filter_mod =
+                                mod_env_to_filter_fc != 0.0 || mod_lfo_to_filter_fc != 0.0;
 
                             let new_region = Sf2Region {
                                 sample: Arc::new([]),
@@ -79,30 +101,30 @@ impl Sf2ParsedPreset {
                                     let offset = subzone.loop_start_offset.unwrap_or(0) as i32
                                         + (subzone.loop_start_offset_coarse.unwrap_or(0) as i32
                                             * 32768);
-                                    let v = (sample.loop_start as i32 + offset) as u32;
-                                    convert_sample_index(v, sample.sample_rate, sample_rate)
+                                    let v = (sample.loop_start as i32 + offset).max(0) as u32;
+                                    convert_index(v)
                                 },
                                 loop_end: {
                                     let offset = subzone.loop_end_offset.unwrap_or(0) as i32
                                         + (subzone.loop_end_offset_coarse.unwrap_or(0) as i32
                                             * 32768);
-                                    let v = (sample.loop_end as i32 + offset) as u32;
-                                    convert_sample_index(v, sample.sample_rate, sample_rate)
+                                    let v = (sample.loop_end as i32 + offset).max(0) as u32;
+                                    convert_index(v)
                                 },
                                 offset: {
                                     let zone_offset = subzone.offset.unwrap_or(0) as u32
                                         + subzone.offset_coarse.unwrap_or(0) as u32 * 32768;
-                                    convert_sample_index(
-                                        zone_offset,
-                                        sample.sample_rate,
-                                        sample_rate,
-                                    )
+                                    convert_index(zone_offset)
                                 },
-                                cutoff: subzone.cutoff.map(|v| {
-                                    2f32.powf(v as f32 / 1200.0)
-                                        * 8.176
-                                        * 2f32.powf(zone.cutoff.unwrap_or(0) as f32 / 1200.0)
-                                }),
+                                // Modulated filters are enabled even if the default
+                                // cutoff (13500 cents) is used
+                                cutoff: subzone.cutoff.or(has_filter_mod.then_some(13500)).map(
+                                    |v| {
+                                        2f32.powf(v as f32 / 1200.0)
+                                            * 8.176
+                                            * 2f32.powf(zone.cutoff.unwrap_or(0) as f32 / 1200.0)
+                                    },
+                                ),
                                 resonance: zone.resonance.unwrap_or(subzone.resonance.unwrap_or(0))
                                     as f32
                                     / 10.0,
@@ -127,7 +149,41 @@ impl Sf2ParsedPreset {
                                     ampeg_release: subzone.env_release.unwrap_or(0.0)
                                         * zone.env_release.unwrap_or(1.0),
                                     ampeg_vel2release: 0.0,
+                                    ampeg_offveltrack: 0.0,
+                                },
+                                pitcheg_envelope: PitchegEnvelopeParams {
+                                    pitcheg_start: 0.0,
+                                    pitcheg_delay: subzone.mod_env_delay.unwrap_or(0.0)
+                                        * zone.mod_env_delay.unwrap_or(1.0),
+                                    pitcheg_attack: subzone.mod_env_attack.unwrap_or(0.0)
+                                        * zone.mod_env_attack.unwrap_or(1.0),
+                                    pitcheg_hold: subzone.mod_env_hold.unwrap_or(0.0)
+                                        * zone.mod_env_hold.unwrap_or(1.0),
+                                    pitcheg_decay: subzone.mod_env_decay.unwrap_or(0.0)
+                                        * zone.mod_env_decay.unwrap_or(1.0),
+                                    pitcheg_sustain: zone
+                                        .mod_env_sustain
+                                        .unwrap_or(subzone.mod_env_sustain.unwrap_or(100.0)),
+                                    pitcheg_release: subzone.mod_env_release.unwrap_or(0.0)
+                                        * zone.mod_env_release.unwrap_or(1.0),
+                                    pitcheg_depth: (zone.mod_env_to_pitch.unwrap_or(0)
+                                        + subzone.mod_env_to_pitch.unwrap_or(0))
+                                        as f32,
                                 },
+                                mod_env_to_filter_fc,
+                                mod_lfo_delay: subzone.mod_lfo_delay.unwrap_or(0.0)
+                                    * zone.mod_lfo_delay.unwrap_or(1.0),
+                                mod_lfo_freq: 8.176
+                                    * 2f32.powf(
+                                        (subzone.mod_lfo_freq.unwrap_or(0)
+                                            + zone.mod_lfo_freq.unwrap_or(0))
+                                            as f32
+                                            / 1200.0,
+                                    ),
+                                mod_lfo_to_filter_fc,
+                                mod_lfo_to_volume: (zone.mod_lfo_to_volume.unwrap_or(0)
+                                    + subzone.mod_lfo_to_volume.unwrap_or(0))
+                                    as f32,
                             };
 
                             regions.push((new_region, sample.clone()));
@@ -1,5 +1,5 @@
 use super::Sf2ParseError;
-use crate::resample::resample_vec;
+use crate::resample::{resample_vec, ResampleQuality};
 use soundfont::raw::{SampleChunk, SampleData, SampleHeader, SampleLink};
 use std::{
     fs::File,
@@ -32,7 +32,8 @@ impl Sf2Sample {
         file: &mut File,
         headers: Vec<SampleHeader>,
         data: SampleData,
-        sample_rate: u32,
+        sample_rate: Option<u32>,
+        quality: ResampleQuality,
     ) -> Result<Vec<Self>, Sf2ParseError> {
         let smpl = if let Some(chunk) = data.smpl {
             Self::read_chunk(file, chunk).map_err(|_| {
@@ -88,18 +89,19 @@ impl Sf2Sample {
             let sample: Vec<f32> = samples[start as usize..end as usize].into();
 
             let new = Sf2Sample {
-                data: if h.sample_rate != sample_rate || !sample.is_empty() {
-                    resample_vec(sample, h.sample_rate as f32, sample_rate as f32)
-                } else {
-                    sample.into()
+                data: match sample_rate {
+                    Some(sample_rate) if h.sample_rate != sample_rate || !sample.is_empty() => {
+                        resample_vec(sample, h.sample_rate as f32, sample_rate as f32, quality)
+                    }
+                    _ => sample.into(),
                 },
                 link_type: match h.sample_type {
                     SampleLink::LeftSample => -1,
                     SampleLink::RightSample => 1,
                     _ => 0,
                 },
-                loop_start: h.loop_start - start,
-                loop_end: h.loop_end - start,
+                loop_start: h.loop_start.saturating_sub(start),
+                loop_end: h.loop_end.saturating_sub(start),
                 sample_rate: h.sample_rate,
                 origpitch: h.origpitch,
                 pitchadj: h.pitchadj,
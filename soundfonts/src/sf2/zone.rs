@@ -21,6 +21,18 @@ pub struct Sf2Zone {
     pub env_decay: Option<f32>,
     pub env_sustain: Option<f32>,
     pub env_release: Option<f32>,
+    pub mod_env_delay: Option<f32>,
+    pub mod_env_attack: Option<f32>,
+    pub mod_env_hold: Option<f32>,
+    pub mod_env_decay: Option<f32>,
+    pub mod_env_sustain: Option<f32>,
+    pub mod_env_release: Option<f32>,
+    pub mod_env_to_pitch: Option<i16>,
+    pub mod_env_to_filter_fc: Option<i16>,
+    pub mod_lfo_delay: Option<f32>,
+    pub mod_lfo_freq: Option<i16>,
+    pub mod_lfo_to_filter_fc: Option<i16>,
+    pub mod_lfo_to_volume: Option<i16>,
     pub velrange: Option<RangeInclusive<u8>>,
     pub keyrange: Option<RangeInclusive<u8>>,
     pub attenuation: Option<i16>,
@@ -90,13 +102,57 @@ impl Sf2Zone {
                         region.env_release =
                             gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
                     }
+                    GeneratorType::DelayModEnv => {
+                        region.mod_env_delay =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::AttackModEnv => {
+                        region.mod_env_attack =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::HoldModEnv => {
+                        region.mod_env_hold =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::DecayModEnv => {
+                        region.mod_env_decay =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::SustainModEnv => {
+                        region.mod_env_sustain = gen
+                            .amount
+                            .as_i16()
+                            .map(|v| (100.0 - *v as f32 / 10.0).clamp(0.0, 100.0))
+                    }
+                    GeneratorType::ReleaseModEnv => {
+                        region.mod_env_release =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::ModEnvToPitch => {
+                        region.mod_env_to_pitch = gen.amount.as_i16().copied()
+                    }
+                    GeneratorType::ModEnvToFilterFc => {
+                        region.mod_env_to_filter_fc = gen.amount.as_i16().copied()
+                    }
+                    GeneratorType::DelayModLFO => {
+                        region.mod_lfo_delay =
+                            gen.amount.as_i16().map(|v| 2f32.powf(*v as f32 / 1200.0))
+                    }
+                    GeneratorType::FreqModLFO => region.mod_lfo_freq = gen.amount.as_i16().copied(),
+                    GeneratorType::ModLfoToFilterFc => {
+                        region.mod_lfo_to_filter_fc = gen.amount.as_i16().copied()
+                    }
+                    GeneratorType::ModLfoToVolume => {
+                        region.mod_lfo_to_volume = gen.amount.as_i16().copied()
+                    }
+                    // The ranges are bytes in the file, but keys and velocities stop at 127
                     GeneratorType::KeyRange => {
                         let range = gen.amount.as_range().copied();
-                        region.keyrange = range.map(|v| v.low..=v.high)
+                        region.keyrange = range.map(|v| v.low.min(127)..=v.high.min(127))
                     }
                     GeneratorType::VelRange => {
                         let range = gen.amount.as_range().copied();
-                        region.velrange = range.map(|v| v.low..=v.high)
+                        region.velrange = range.map(|v| v.low.min(127)..=v.high.min(127))
                     }
                     GeneratorType::InitialAttenuation => {
                         region.attenuation = gen.amount.as_i16().copied()
@@ -0,0 +1,390 @@
+//! The syntax tree layer of the SFZ parser, for tools like SFZ linters,
+//! editors and converters.
+//!
+//! It reads the headers, opcodes and directives of an SFZ file along with
+//! their location, without parsing the opcode values, which is left to the
+//! caller. `parse_soundfont` is built on top of it.
+//!
+//! Unlike the types of the `grammar` module, the types of this module are
+//! considered stable and only change with the major versions of the crate.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use encoding_rs::UTF_8;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use regex_bnf::FileLocation;
+
+use super::{
+    grammar::{ErrorTolerantToken, TokenKind},
+    SfzParseError,
+};
+
+/// The location of an item in an SFZ file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SfzLocation {
+    /// The path of the file. Included files are joined to the directory
+    /// of the file passed to `parse_file`.
+    pub file: Arc<Path>,
+
+    /// The line number, starting from 1
+    pub line: usize,
+
+    /// The column in bytes, starting from 1
+    pub column: usize,
+}
+
+impl std::fmt::Display for SfzLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+/// A header of an SFZ file, like `<region>` or `<group>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SfzHeader {
+    /// The name of the header, without the angle brackets
+    pub name: String,
+
+    /// The location of the opening angle bracket
+    pub location: SfzLocation,
+}
+
+/// An opcode of an SFZ file, with the headers which it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SfzAstOpcode {
+    /// The headers of the hierarchy which the opcode is under, from the
+    /// outermost level (`<control>`, `<global>`, `<master>`, `<group>`)
+    /// to the innermost one (`<region>`). Each header replaces the last
+    /// header of its level and closes the levels below it. Other headers,
+    /// like `<curve>`, are on the same level as `<region>`.
+    ///
+    /// Opcodes under the same headers share the same path.
+    pub group_path: Arc<[SfzHeader]>,
+
+    /// The name of the opcode, with the defined variables replaced
+    pub name: String,
+
+    /// The unparsed value of the opcode, with the defined variables
+    /// replaced and the surrounding spaces trimmed
+    pub value: String,
+
+    /// The location of the opcode name
+    pub location: SfzLocation,
+}
+
+/// An item of an SFZ file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SfzAstItem {
+    /// A header, like `<region>`
+    Header(SfzHeader),
+
+    /// An opcode, like `sample=piano.wav`
+    Opcode(SfzAstOpcode),
+
+    /// A `#define` directive. The variable is replaced in the opcodes
+    /// and include paths which come after it.
+    Define {
+        variable: String,
+        value: String,
+        location: SfzLocation,
+    },
+
+    /// An `#include` directive, only returned when the includes are kept
+    /// with `IncludeMode::Keep`. The defined variables of the path are
+    /// replaced.
+    Include { path: String, location: SfzLocation },
+}
+
+/// Selects how `parse_file` handles the `#include` directives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncludeMode {
+    /// Parse the included files in place of the directives. Their items
+    /// are located in the included files.
+    #[default]
+    Resolve,
+
+    /// Return the directives as `SfzAstItem::Include` items, without
+    /// reading the included files
+    Keep,
+}
+
+/// Parses the items of an SFZ file, in the order in which they appear.
+/// The parsing continues after errors, which are returned in place of
+/// the items that failed.
+///
+/// Parameters:
+/// - `path`: The path of the SFZ file. The included files are relative
+///   to its directory.
+/// - `includes`: How the `#include` directives are handled. See the
+///   `IncludeMode` documentation for more information.
+pub fn parse_file(path: &Path, includes: IncludeMode) -> Vec<Result<SfzAstItem, SfzParseError>> {
+    let instr_dir = path.parent().unwrap_or(Path::new(""));
+    let mut defines = HashMap::new();
+    let mut items = parse_file_recursive(instr_dir, path, includes, &mut defines);
+    set_group_paths(&mut items);
+    items
+}
+
+/// Parses the items of a file and its includes, with empty group paths.
+fn parse_file_recursive(
+    instr_dir: &Path,
+    file_path: &Path,
+    includes: IncludeMode,
+    defines: &mut HashMap<String, String>,
+) -> Vec<Result<SfzAstItem, SfzParseError>> {
+    let Some(text) = read_file(file_path) else {
+        return vec![Err(SfzParseError::FailedToReadFile(file_path.to_owned()))];
+    };
+
+    let file: Arc<Path> = file_path.into();
+    let no_path: Arc<[SfzHeader]> = Arc::new([]);
+    let location = |at: FileLocation| SfzLocation {
+        file: file.clone(),
+        line: at.line_number,
+        column: at.index - text[..at.index].rfind('\n').map_or(0, |i| i + 1) + 1,
+    };
+
+    let mut items = Vec::new();
+    let mut parsed_includes = HashMap::new();
+
+    for token in ErrorTolerantToken::parse_as_iter(&text) {
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                items.push(Err(SfzParseError::from(e)));
+                continue;
+            }
+        };
+
+        match token.kind {
+            TokenKind::Comment(_) => {}
+            TokenKind::Group(group) => {
+                let mut at = group.name.location;
+                // The location of the opening angle bracket
+                at.index -= 1;
+                items.push(Ok(SfzAstItem::Header(SfzHeader {
+                    name: group.name.text.to_owned(),
+                    location: location(at),
+                })));
+            }
+            TokenKind::Opcode(opcode) => {
+                let name = replace_defines(opcode.name.name.text.trim(), defines);
+                let value = replace_defines(opcode.value.as_string().trim(), defines);
+                items.push(Ok(SfzAstItem::Opcode(SfzAstOpcode {
+                    group_path: no_path.clone(),
+                    name,
+                    value,
+                    location: location(opcode.name.name.location),
+                })));
+            }
+            TokenKind::Include(include) => {
+                let path = replace_defines(&include.path.text.replace('\\', "/"), defines);
+                match includes {
+                    IncludeMode::Keep => items.push(Ok(SfzAstItem::Include {
+                        path,
+                        location: location(include.path.location),
+                    })),
+                    IncludeMode::Resolve => {
+                        // Get the cached items of the included file, or parse them
+                        // if they haven't been parsed yet
+                        if !parsed_includes.contains_key(&path) {
+                            let full_path = instr_dir.join(&path);
+                            let parsed =
+                                parse_file_recursive(instr_dir, &full_path, includes, defines);
+                            parsed_includes.insert(path.clone(), parsed);
+                        }
+                        items.extend_from_slice(&parsed_includes[&path]);
+                    }
+                }
+            }
+            TokenKind::Define(define) => {
+                let variable = define.variable.text.trim().to_owned();
+                let value = define.value.first.value.text.text.trim().to_owned();
+
+                // We clear the include cache here so if the same file is included
+                // it will use the new definition values
+                parsed_includes.clear();
+                defines.insert(variable.clone(), value.clone());
+
+                items.push(Ok(SfzAstItem::Define {
+                    variable,
+                    value,
+                    location: location(define.variable.location),
+                }));
+            }
+        }
+    }
+
+    items
+}
+
+fn read_file(path: &Path) -> Option<String> {
+    let f = File::open(path).ok()?;
+    let mut reader = BufReader::new(
+        DecodeReaderBytesBuilder::new()
+            .encoding(Some(UTF_8))
+            .build(f),
+    );
+    let mut text = String::new();
+    reader.read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+fn replace_defines(text: &str, defines: &HashMap<String, String>) -> String {
+    let mut text = text.to_owned();
+    for (key, replace) in defines.iter() {
+        if text.contains(key) {
+            text = text.replace(key, replace);
+        }
+    }
+    text
+}
+
+/// Returns the level of a header in the SFZ hierarchy, from the outermost.
+fn header_level(name: &str) -> usize {
+    match name {
+        "control" => 0,
+        "global" => 1,
+        "master" => 2,
+        "group" => 3,
+        _ => 4,
+    }
+}
+
+/// Sets the group paths of the opcodes from the headers before them.
+fn set_group_paths(items: &mut [Result<SfzAstItem, SfzParseError>]) {
+    let mut levels: [Option<SfzHeader>; 5] = Default::default();
+    let mut path: Arc<[SfzHeader]> = Arc::new([]);
+
+    for item in items.iter_mut() {
+        match item {
+            Ok(SfzAstItem::Header(header)) => {
+                let level = header_level(&header.name);
+                levels[level] = Some(header.clone());
+                for lower in levels[level + 1..].iter_mut() {
+                    *lower = None;
+                }
+                path = levels.iter().flatten().cloned().collect();
+            }
+            Ok(SfzAstItem::Opcode(opcode)) => opcode.group_path = path.clone(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes an SFZ file which includes another one, using a variable.
+    fn write_test_files(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("xsynth_sfz_ast_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("inc")).unwrap();
+        std::fs::write(
+            dir.join("main.sfz"),
+            "#define $KEY 60\n\
+             <group> lovel=1\n\
+             #include \"inc/regions.sfz\"\n\
+             <region> sample=b.wav key=$KEY\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("inc/regions.sfz"),
+            "// Comment\n\
+             <region> sample=a.wav\n  key=$KEY\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    fn opcodes(items: &[Result<SfzAstItem, SfzParseError>]) -> Vec<&SfzAstOpcode> {
+        items
+            .iter()
+            .filter_map(|item| match item.as_ref().unwrap() {
+                SfzAstItem::Opcode(opcode) => Some(opcode),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn position(location: &SfzLocation) -> (PathBuf, usize, usize) {
+        (location.file.to_path_buf(), location.line, location.column)
+    }
+
+    #[test]
+    fn test_resolved_includes() {
+        let dir = write_test_files("resolved");
+        let main = dir.join("main.sfz");
+        let included = dir.join("inc/regions.sfz");
+        let items = parse_file(&main, IncludeMode::Resolve);
+        std::fs::remove_dir_all(&dir).ok();
+
+        let SfzAstItem::Define { location, .. } = items[0].as_ref().unwrap() else {
+            panic!("expected a define");
+        };
+        assert_eq!(position(location), (main.clone(), 1, 9));
+
+        let opcodes = opcodes(&items);
+        let summary: Vec<_> = opcodes
+            .iter()
+            .map(|o| (o.name.as_str(), o.value.as_str(), position(&o.location)))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("lovel", "1", (main.clone(), 2, 9)),
+                ("sample", "a.wav", (included.clone(), 2, 10)),
+                ("key", "60", (included.clone(), 3, 3)),
+                ("sample", "b.wav", (main.clone(), 4, 10)),
+                ("key", "60", (main.clone(), 4, 23)),
+            ]
+        );
+
+        // The included region is under the group of the including file
+        let path: Vec<_> = opcodes[2]
+            .group_path
+            .iter()
+            .map(|h| (h.name.as_str(), position(&h.location)))
+            .collect();
+        assert_eq!(
+            path,
+            vec![
+                ("group", (main.clone(), 2, 1)),
+                ("region", (included.clone(), 2, 1)),
+            ]
+        );
+        assert!(Arc::ptr_eq(&opcodes[1].group_path, &opcodes[2].group_path));
+        assert_eq!(opcodes[4].group_path[1].location.file.as_ref(), main);
+    }
+
+    #[test]
+    fn test_kept_includes() {
+        let dir = write_test_files("kept");
+        let main = dir.join("main.sfz");
+        let items = parse_file(&main, IncludeMode::Keep);
+        std::fs::remove_dir_all(&dir).ok();
+
+        let includes: Vec<_> = items
+            .iter()
+            .filter_map(|item| match item.as_ref().unwrap() {
+                SfzAstItem::Include { path, location } => Some((path.as_str(), position(location))),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(includes, vec![("inc/regions.sfz", (main.clone(), 3, 11))]);
+
+        // Only the opcodes of the main file are returned
+        let opcodes = opcodes(&items);
+        assert_eq!(opcodes.len(), 3);
+        assert!(opcodes.iter().all(|o| o.location.file.as_ref() == main));
+        assert_eq!(opcodes[2].value, "60");
+    }
+}
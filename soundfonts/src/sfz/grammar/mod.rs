@@ -1,5 +1,13 @@
+//! The grammar of the SFZ format, which parses an SFZ file into tokens.
+//!
+//! The types of this module are generated from the grammar below and follow
+//! its structure, so they may change between minor versions of the crate
+//! when the grammar is changed. The `ast` module offers a stable interface
+//! over them.
+
 #![allow(clippy::manual_strip)]
 #![allow(clippy::uninlined_format_args)]
+#![allow(clippy::enum_variant_names)]
 
 use std::borrow::Cow;
 
@@ -57,6 +65,7 @@ bnf! {
 }
 
 impl<'a> OpcodeValue<'a> {
+    /// Returns the text of the value, including its trailing spaces.
     pub fn as_string(&self) -> Cow<'a, str> {
         if self.rest.is_empty() {
             Cow::Borrowed(self.first.value.text.text)
@@ -71,15 +80,25 @@ impl<'a> OpcodeValue<'a> {
 }
 
 impl<'a> ErrorTolerantToken<'a> {
+    /// Parses the tokens of an SFZ file. The lines which can't be parsed
+    /// are skipped, and the iteration stops after an error which can't be
+    /// recovered from.
     pub fn parse_as_iter(s: &'a str) -> impl Iterator<Item = Result<Token<'a>, ParseError>> {
         let mut parser = StringParser::new(s);
+        let mut failed = false;
         std::iter::from_fn(move || {
+            // The parser doesn't advance after an error, so the iteration stops there
+            if failed {
+                return None;
+            }
+
             let result = Self::parse(parser);
 
             if let Err(e) = result {
                 if parser.is_empty() {
                     return None;
                 } else {
+                    failed = true;
                     return Some(Err(e));
                 }
             }
@@ -4,11 +4,15 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use self::parse::{parse_tokens_resolved, SfzAmpegEnvelope, SfzGroupType, SfzOpcode, SfzToken};
+use self::parse::{
+    parse_tokens_resolved, parse_tokens_resolved_lenient, SfzAmpegEnvelope, SfzGroupType,
+    SfzOpcode, SfzPitchegEnvelope, SfzToken,
+};
 
 use crate::{FilterType, LoopMode};
 
-mod grammar;
+pub mod ast;
+pub mod grammar;
 mod parse;
 pub use parse::{SfzParseError, SfzValidationError};
 
@@ -23,6 +27,10 @@ pub struct AmpegEnvelopeParams {
     pub ampeg_sustain: f32,
     pub ampeg_release: f32,
     pub ampeg_vel2release: f32,
+    /// Note off velocity tracking of the release time, in percent. This is
+    /// an XSynth extension, where 100 halves the release time at velocity
+    /// 127 and 0 disables the tracking.
+    pub ampeg_offveltrack: f32,
 }
 
 impl Default for AmpegEnvelopeParams {
@@ -36,6 +44,7 @@ impl Default for AmpegEnvelopeParams {
             ampeg_sustain: 100.0,
             ampeg_release: 0.01,
             ampeg_vel2release: 0.0,
+            ampeg_offveltrack: 0.0,
         }
     }
 }
@@ -51,6 +60,50 @@ impl AmpegEnvelopeParams {
             SfzAmpegEnvelope::AmpegSustain(val) => self.ampeg_sustain = val,
             SfzAmpegEnvelope::AmpegRelease(val) => self.ampeg_release = val,
             SfzAmpegEnvelope::AmpegVel2Release(val) => self.ampeg_vel2release = val,
+            SfzAmpegEnvelope::AmpegOffVeltrack(val) => self.ampeg_offveltrack = val,
+        }
+    }
+}
+
+/// Structure that holds the opcode parameters of the SFZ's pitch envelope.
+#[derive(Debug, Clone)]
+pub struct PitchegEnvelopeParams {
+    pub pitcheg_start: f32,
+    pub pitcheg_delay: f32,
+    pub pitcheg_attack: f32,
+    pub pitcheg_hold: f32,
+    pub pitcheg_decay: f32,
+    pub pitcheg_sustain: f32,
+    pub pitcheg_release: f32,
+    pub pitcheg_depth: f32,
+}
+
+impl Default for PitchegEnvelopeParams {
+    fn default() -> Self {
+        PitchegEnvelopeParams {
+            pitcheg_start: 0.0,
+            pitcheg_delay: 0.0,
+            pitcheg_attack: 0.0,
+            pitcheg_hold: 0.0,
+            pitcheg_decay: 0.0,
+            pitcheg_sustain: 0.0,
+            pitcheg_release: 0.0,
+            pitcheg_depth: 0.0,
+        }
+    }
+}
+
+impl PitchegEnvelopeParams {
+    fn update_from_flag(&mut self, flag: SfzPitchegEnvelope) {
+        match flag {
+            SfzPitchegEnvelope::PitchegStart(val) => self.pitcheg_start = val,
+            SfzPitchegEnvelope::PitchegDelay(val) => self.pitcheg_delay = val,
+            SfzPitchegEnvelope::PitchegAttack(val) => self.pitcheg_attack = val,
+            SfzPitchegEnvelope::PitchegHold(val) => self.pitcheg_hold = val,
+            SfzPitchegEnvelope::PitchegDecay(val) => self.pitcheg_decay = val,
+            SfzPitchegEnvelope::PitchegSustain(val) => self.pitcheg_sustain = val,
+            SfzPitchegEnvelope::PitchegRelease(val) => self.pitcheg_release = val,
+            SfzPitchegEnvelope::PitchegDepth(val) => self.pitcheg_depth = val,
         }
     }
 }
@@ -61,7 +114,7 @@ pub(crate) struct RegionParamsBuilder {
     hivel: u8,
     lokey: i8,
     hikey: i8,
-    pitch_keycenter: i8,
+    pitch_keycenter: f32,
     volume: i16,
     pan: i8,
     sample: Option<String>,
@@ -70,9 +123,11 @@ pub(crate) struct RegionParamsBuilder {
     loop_start: u32,
     loop_end: u32,
     offset: u32,
+    offset_random: u32,
     cutoff: Option<f32>,
     resonance: f32,
     amp_veltrack: f32,
+    amp_velcurve: Vec<(u8, f32)>,
     amp_keycenter: i8,
     amp_keytrack: f32,
     pan_veltrack: f32,
@@ -83,7 +138,21 @@ pub(crate) struct RegionParamsBuilder {
     fil_keytrack: i16,
     filter_type: FilterType,
     ampeg_envelope: AmpegEnvelopeParams,
+    pitcheg_envelope: PitchegEnvelopeParams,
     tune: i16,
+    transpose: i8,
+    note_offset: i8,
+    pitch_veltrack: i16,
+    pitch_random: f32,
+    amp_random: f32,
+    seq_length: u8,
+    seq_position: u8,
+    xfin_lovel: u8,
+    xfin_hivel: u8,
+    xfout_lovel: u8,
+    xfout_hivel: u8,
+    effect1: f32,
+    effect2: f32,
 }
 
 impl Default for RegionParamsBuilder {
@@ -93,7 +162,7 @@ impl Default for RegionParamsBuilder {
             hivel: 127,
             lokey: 0,
             hikey: 127,
-            pitch_keycenter: 60,
+            pitch_keycenter: 60.0,
             volume: 0,
             pan: 0,
             sample: None,
@@ -102,9 +171,11 @@ impl Default for RegionParamsBuilder {
             loop_start: 0,
             loop_end: 0,
             offset: 0,
+            offset_random: 0,
             cutoff: None,
             resonance: 0.0,
             amp_veltrack: 100.0,
+            amp_velcurve: Vec::new(),
             amp_keycenter: 60,
             amp_keytrack: 0.0,
             pan_veltrack: 0.0,
@@ -115,7 +186,21 @@ impl Default for RegionParamsBuilder {
             fil_keytrack: 0,
             filter_type: FilterType::default(),
             ampeg_envelope: AmpegEnvelopeParams::default(),
+            pitcheg_envelope: PitchegEnvelopeParams::default(),
             tune: 0,
+            transpose: 0,
+            note_offset: 0,
+            pitch_veltrack: 0,
+            pitch_random: 0.0,
+            amp_random: 0.0,
+            seq_length: 1,
+            seq_position: 1,
+            xfin_lovel: 0,
+            xfin_hivel: 0,
+            xfout_lovel: 127,
+            xfout_hivel: 127,
+            effect1: 0.0,
+            effect2: 0.0,
         }
     }
 }
@@ -126,8 +211,8 @@ impl RegionParamsBuilder {
             SfzOpcode::Lovel(val) => self.lovel = val,
             SfzOpcode::Hivel(val) => self.hivel = val,
             SfzOpcode::Key(val) => {
-                self.lokey = val;
-                self.hikey = val;
+                self.lokey = val.floor() as i8;
+                self.hikey = val.floor() as i8;
                 self.pitch_keycenter = val;
             }
             SfzOpcode::Lokey(val) => self.lokey = val,
@@ -140,9 +225,14 @@ impl RegionParamsBuilder {
             SfzOpcode::LoopStart(val) => self.loop_start = val,
             SfzOpcode::LoopEnd(val) => self.loop_end = val,
             SfzOpcode::Offset(val) => self.offset = val,
+            SfzOpcode::OffsetRandom(val) => self.offset_random = val,
             SfzOpcode::Cutoff(val) => self.cutoff = Some(val),
             SfzOpcode::Resonance(val) => self.resonance = val,
             SfzOpcode::AmpVeltrack(val) => self.amp_veltrack = val,
+            SfzOpcode::AmpVelcurve(vel, val) => {
+                self.amp_velcurve.retain(|(v, _)| *v != vel);
+                self.amp_velcurve.push((vel, val));
+            }
             SfzOpcode::AmpKeytrack(val) => self.amp_keytrack = val,
             SfzOpcode::AmpKeycenter(val) => self.amp_keycenter = val,
             SfzOpcode::PanVeltrack(val) => self.pan_veltrack = val,
@@ -154,24 +244,75 @@ impl RegionParamsBuilder {
             SfzOpcode::FilterType(val) => self.filter_type = val,
             SfzOpcode::DefaultPath(val) => self.default_path = Some(val),
             SfzOpcode::AmpegEnvelope(flag) => self.ampeg_envelope.update_from_flag(flag),
+            SfzOpcode::PitchegEnvelope(flag) => self.pitcheg_envelope.update_from_flag(flag),
             SfzOpcode::Tune(val) => self.tune = val,
+            SfzOpcode::Transpose(val) => self.transpose = val,
+            SfzOpcode::NoteOffset(val) => self.note_offset = val,
+            SfzOpcode::PitchVeltrack(val) => self.pitch_veltrack = val,
+            SfzOpcode::PitchRandom(val) => self.pitch_random = val,
+            SfzOpcode::AmpRandom(val) => self.amp_random = val,
+            SfzOpcode::SeqLength(val) => self.seq_length = val,
+            SfzOpcode::SeqPosition(val) => self.seq_position = val,
+            SfzOpcode::XfinLovel(val) => self.xfin_lovel = val,
+            SfzOpcode::XfinHivel(val) => self.xfin_hivel = val,
+            SfzOpcode::XfoutLovel(val) => self.xfout_lovel = val,
+            SfzOpcode::XfoutHivel(val) => self.xfout_hivel = val,
+            SfzOpcode::Effect1(val) => self.effect1 = val,
+            SfzOpcode::Effect2(val) => self.effect2 = val,
+        }
+    }
+
+    /// Applies `note_offset`, which offsets the incoming keys, by moving the
+    /// keys of the region the other way. The key centers are moved along, so
+    /// that the offset keys play exactly like the keys they are offset to.
+    /// Returns false if none of the keys can be played anymore.
+    fn apply_note_offset(&mut self) -> bool {
+        // Key value -1 is used for CC triggered regions, which keep it
+        if self.note_offset == 0 || self.lokey == -1 || self.hikey == -1 {
+            return true;
         }
+
+        let offset = self.note_offset as i16;
+        let lokey = (self.lokey as i16 - offset).max(0);
+        let hikey = (self.hikey as i16 - offset).min(127);
+        if lokey > hikey {
+            return false;
+        }
+        self.lokey = lokey as i8;
+        self.hikey = hikey as i8;
+
+        let shift_center = |key: i8| (key as i16 - offset).clamp(0, 127) as i8;
+        self.pitch_keycenter -= offset as f32;
+        self.amp_keycenter = shift_center(self.amp_keycenter);
+        self.pan_keycenter = shift_center(self.pan_keycenter);
+        self.fil_keycenter = shift_center(self.fil_keycenter);
+        true
     }
 
-    fn build(self, base_path: &Path) -> Option<RegionParams> {
+    /// Builds the region. Returns `None` for regions without a sample or
+    /// whose keys are all moved out of the MIDI range by `note_offset`, and
+    /// an error if the sample file doesn't exist.
+    fn build(mut self, base_path: &Path) -> Result<Option<RegionParams>, SfzParseError> {
+        if !self.apply_note_offset() {
+            return Ok(None);
+        }
+        let Some(sample) = self.sample else {
+            return Ok(None);
+        };
         let relative_sample_path = if let Some(default_path) = self.default_path {
-            PathBuf::from(default_path).join(self.sample?)
+            PathBuf::from(default_path).join(sample)
         } else {
-            self.sample?.into()
+            sample.into()
         };
 
-        let mut sample_path = base_path.join(relative_sample_path);
-        match sample_path.canonicalize() {
-            Ok(path) => sample_path = path,
-            Err(_) => return None,
-        }
+        let sample_path = base_path.join(relative_sample_path);
+        let sample_path = sample_path
+            .canonicalize()
+            .map_err(|_| SfzParseError::MissingSample(sample_path))?;
 
-        Some(RegionParams {
+        self.amp_velcurve.sort_by_key(|(vel, _)| *vel);
+
+        Ok(Some(RegionParams {
             velrange: self.lovel..=self.hivel,
             keyrange: self.lokey..=self.hikey,
             pitch_keycenter: self.pitch_keycenter,
@@ -182,9 +323,11 @@ impl RegionParamsBuilder {
             loop_start: self.loop_start,
             loop_end: self.loop_end,
             offset: self.offset,
+            offset_random: self.offset_random,
             cutoff: self.cutoff,
             resonance: self.resonance,
             amp_veltrack: self.amp_veltrack,
+            amp_velcurve: self.amp_velcurve,
             amp_keycenter: self.amp_keycenter,
             amp_keytrack: self.amp_keytrack,
             pan_veltrack: self.pan_veltrack,
@@ -195,8 +338,19 @@ impl RegionParamsBuilder {
             fil_keytrack: self.fil_keytrack,
             filter_type: self.filter_type,
             ampeg_envelope: self.ampeg_envelope,
+            pitcheg_envelope: self.pitcheg_envelope,
             tune: self.tune,
-        })
+            transpose: self.transpose,
+            pitch_veltrack: self.pitch_veltrack,
+            pitch_random: self.pitch_random,
+            amp_random: self.amp_random,
+            seq_length: self.seq_length,
+            seq_position: self.seq_position,
+            xfin_velrange: self.xfin_lovel..=self.xfin_hivel,
+            xfout_velrange: self.xfout_lovel..=self.xfout_hivel,
+            effect1: self.effect1,
+            effect2: self.effect2,
+        }))
     }
 }
 
@@ -205,7 +359,9 @@ impl RegionParamsBuilder {
 pub struct RegionParams {
     pub velrange: RangeInclusive<u8>,
     pub keyrange: RangeInclusive<i8>,
-    pub pitch_keycenter: i8,
+    /// The key at which the sample plays at its original pitch. May be
+    /// fractional for microtonal key centers.
+    pub pitch_keycenter: f32,
     pub volume: i16,
     pub pan: i8,
     pub sample_path: PathBuf,
@@ -213,9 +369,14 @@ pub struct RegionParams {
     pub loop_start: u32,
     pub loop_end: u32,
     pub offset: u32,
+    /// The maximum amount of samples randomly added to the offset of each voice.
+    pub offset_random: u32,
     pub cutoff: Option<f32>,
     pub resonance: f32,
     pub amp_veltrack: f32,
+    /// Points of the `amp_velcurve_N` opcodes as (velocity, gain) pairs,
+    /// sorted by velocity. Empty if the region has no custom curve.
+    pub amp_velcurve: Vec<(u8, f32)>,
     pub amp_keycenter: i8,
     pub amp_keytrack: f32,
     pub pan_veltrack: f32,
@@ -226,7 +387,34 @@ pub struct RegionParams {
     pub fil_keytrack: i16,
     pub filter_type: FilterType,
     pub ampeg_envelope: AmpegEnvelopeParams,
+    pub pitcheg_envelope: PitchegEnvelopeParams,
     pub tune: i16,
+    /// The transposition of the playback pitch in semitones. Unlike
+    /// `note_offset`, which is applied to the key range when building the
+    /// region, it doesn't change which keys play the region.
+    pub transpose: i8,
+    pub pitch_veltrack: i16,
+    /// The maximum random detune of each voice in cents, in both directions.
+    pub pitch_random: f32,
+    /// The maximum random gain in dB added to each voice.
+    pub amp_random: f32,
+    /// The number of regions in the round-robin sequence of the region.
+    pub seq_length: u8,
+    /// The 1-based position of the region in its round-robin sequence.
+    /// The region only plays on the note-ons at this position.
+    pub seq_position: u8,
+    /// The velocities over which the region fades in, from the
+    /// `xfin_lovel` and `xfin_hivel` opcodes.
+    pub xfin_velrange: RangeInclusive<u8>,
+    /// The velocities over which the region fades out, from the
+    /// `xfout_lovel` and `xfout_hivel` opcodes.
+    pub xfout_velrange: RangeInclusive<u8>,
+    /// The reverb send level of the `effect1` opcode, in percent.
+    /// Not applied yet, as the synthesizer has no effect buses.
+    pub effect1: f32,
+    /// The chorus send level of the `effect2` opcode, in percent.
+    /// Not applied yet, as the synthesizer has no effect buses.
+    pub effect2: f32,
 }
 
 fn get_group_level(group_type: SfzGroupType) -> Option<usize> {
@@ -240,24 +428,52 @@ fn get_group_level(group_type: SfzGroupType) -> Option<usize> {
     }
 }
 
-fn parse_sf_root(tokens: impl Iterator<Item = SfzToken>, base_path: PathBuf) -> Vec<RegionParams> {
+/// Builds the regions from the tokens. Regions containing an erroring token
+/// are skipped, and the errors are returned along with the missing samples.
+fn parse_sf_root(
+    tokens: impl Iterator<Item = Result<SfzToken, SfzParseError>>,
+    base_path: PathBuf,
+) -> (Vec<RegionParams>, Vec<SfzParseError>) {
     let mut current_group = None;
 
     let mut group_data_stack = VecDeque::<RegionParamsBuilder>::new();
 
     let mut regions = Vec::new();
+    let mut errors = Vec::new();
+    let mut region_failed = false;
+
+    let mut build_region = |region: RegionParamsBuilder, failed: bool, errors: &mut Vec<_>| {
+        if failed {
+            return;
+        }
+        match region.build(&base_path) {
+            Ok(Some(built)) => regions.push(built),
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+    };
 
     for token in tokens {
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                errors.push(e);
+                if current_group == Some(SfzGroupType::Region) {
+                    region_failed = true;
+                }
+                continue;
+            }
+        };
+
         match token {
             SfzToken::Group(group) => {
                 if current_group == Some(SfzGroupType::Region) {
                     // Step outside of the current group
                     // Unwrapping is safe because if the group is Region then there's always at least one item
                     let next_region = group_data_stack.pop_back().unwrap();
-                    if let Some(built) = next_region.build(&base_path) {
-                        regions.push(built);
-                    }
+                    build_region(next_region, region_failed, &mut errors);
                 }
+                region_failed = false;
 
                 if let Some(group_level) = get_group_level(group) {
                     current_group = Some(group);
@@ -289,15 +505,16 @@ fn parse_sf_root(tokens: impl Iterator<Item = SfzToken>, base_path: PathBuf) ->
     if current_group == Some(SfzGroupType::Region) {
         // Unwrapping is safe because if the group is Region then there's always at least one item
         let next_region = group_data_stack.pop_back().unwrap();
-        if let Some(built) = next_region.build(&base_path) {
-            regions.push(built);
-        }
+        build_region(next_region, region_failed, &mut errors);
     }
 
-    regions
+    (regions, errors)
 }
 
 /// Parses an SFZ file and returns its regions in a vector.
+///
+/// Returns `SfzParseError::MissingSample` with the path of the first sample
+/// file which doesn't exist. Use `parse_soundfont_lenient` to skip them.
 pub fn parse_soundfont(sfz_path: impl Into<PathBuf>) -> Result<Vec<RegionParams>, SfzParseError> {
     let sfz_path = sfz_path.into();
     let sfz_path: PathBuf = sfz_path
@@ -310,7 +527,96 @@ pub fn parse_soundfont(sfz_path: impl Into<PathBuf>) -> Result<Vec<RegionParams>
     // and therefore it will always have a parent folder. The path is also canonicalized.
     let parent_path = sfz_path.parent().unwrap().into();
 
-    let regions = parse_sf_root(tokens.into_iter(), parent_path);
+    // The tokens are valid, so the only errors are missing samples
+    let (regions, errors) = parse_sf_root(tokens.into_iter().map(Ok), parent_path);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(regions),
+    }
+}
+
+/// Parses an SFZ file without stopping at the first error. Returns the
+/// regions that could be parsed, along with the errors that were found.
+///
+/// Regions containing an error are skipped, and the regions with missing
+/// sample files are reported with `SfzParseError::MissingSample`. For files
+/// which `parse_soundfont` can parse, the same regions are returned.
+pub fn parse_soundfont_lenient(
+    sfz_path: impl Into<PathBuf>,
+) -> (Vec<RegionParams>, Vec<SfzParseError>) {
+    let sfz_path = sfz_path.into();
+    let sfz_path: PathBuf = match sfz_path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return (Vec::new(), vec![SfzParseError::FailedToReadFile(sfz_path)]),
+    };
+
+    let tokens = parse_tokens_resolved_lenient(&sfz_path);
+
+    // Unwrap here is safe because the path is canonicalized and points to a file
+    // (or the error is returned above), so it always has a parent folder.
+    let parent_path = sfz_path.parent().unwrap().into();
+
+    parse_sf_root(tokens.into_iter(), parent_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an SFZ file with the given contents next to an empty sample
+    /// file named `sample.wav`, which the parser only checks for existence.
+    fn write_test_sfz(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xsynth_sfz_{name}_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("sample.wav"), []).unwrap();
+        std::fs::write(dir.join("test.sfz"), contents).unwrap();
+        dir.join("test.sfz")
+    }
 
-    Ok(regions)
+    #[test]
+    fn test_sfz_effect_sends_parsed() {
+        let sfz = write_test_sfz(
+            "sfz_effect_sends",
+            "<region> sample=sample.wav effect1=50 effect2=150\n\
+             <region> sample=sample.wav\n",
+        );
+
+        let regions = parse_soundfont(sfz).unwrap();
+        assert_eq!((regions[0].effect1, regions[0].effect2), (50.0, 100.0));
+        assert_eq!((regions[1].effect1, regions[1].effect2), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_sfz_lenient_parse() {
+        let sfz = write_test_sfz(
+            "sfz_lenient",
+            "<region> sample=sample.wav key=60\n\
+             <region> sample=missing.wav key=61\n\
+             <region> sample=sample.wav key=62\n\
+             #include \"missing.sfz\"\n\
+             <region> sample=sample.wav key=63\n\
+             <region> sample=sample.wav key=64 <",
+        );
+
+        assert!(matches!(
+            parse_soundfont(&sfz),
+            Err(SfzParseError::FailedToReadFile(..))
+        ));
+
+        // The regions with an error are skipped
+        let (regions, errors) = parse_soundfont_lenient(&sfz);
+        let keys: Vec<_> = regions.iter().map(|r| *r.keyrange.start()).collect();
+        assert_eq!(keys, [60, 63]);
+        assert!(
+            matches!(
+                &errors[..],
+                [
+                    SfzParseError::MissingSample(sample),
+                    SfzParseError::FailedToReadFile(include),
+                    SfzParseError::GrammarError(..),
+                ] if sample.ends_with("missing.wav") && include.ends_with("missing.sfz")
+            ),
+            "{errors:?}"
+        );
+    }
 }
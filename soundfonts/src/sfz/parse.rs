@@ -1,18 +1,11 @@
 use std::{
-    borrow::Cow,
-    cell::RefCell,
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, Read},
     ops::RangeInclusive,
     path::{Path, PathBuf},
 };
 
 use crate::{FilterType, LoopMode};
-use encoding_rs::UTF_8;
-use encoding_rs_io::DecodeReaderBytesBuilder;
 
-use super::grammar::{ErrorTolerantToken, Group, Opcode, Token, TokenKind};
+use super::ast::{parse_file, IncludeMode, SfzAstItem};
 use regex_bnf::{FileLocation, ParseError};
 use thiserror::Error;
 
@@ -20,10 +13,10 @@ use thiserror::Error;
 pub enum SfzOpcode {
     Lovel(u8),
     Hivel(u8),
-    Key(i8),
+    Key(f32),
     Lokey(i8),
     Hikey(i8),
-    PitchKeycenter(i8),
+    PitchKeycenter(f32),
     Volume(i16),
     Pan(i8),
     Sample(String),
@@ -31,11 +24,13 @@ pub enum SfzOpcode {
     LoopStart(u32),
     LoopEnd(u32),
     Offset(u32),
+    OffsetRandom(u32),
     Cutoff(f32),
     Resonance(f32),
     AmpKeycenter(i8),
     AmpKeytrack(f32),
     AmpVeltrack(f32),
+    AmpVelcurve(u8, f32),
     PanKeycenter(i8),
     PanKeytrack(f32),
     PanVeltrack(f32),
@@ -45,7 +40,21 @@ pub enum SfzOpcode {
     FilterType(FilterType),
     DefaultPath(String),
     Tune(i16),
+    Transpose(i8),
+    NoteOffset(i8),
+    PitchVeltrack(i16),
+    PitchRandom(f32),
+    AmpRandom(f32),
+    SeqLength(u8),
+    SeqPosition(u8),
+    XfinLovel(u8),
+    XfinHivel(u8),
+    XfoutLovel(u8),
+    XfoutHivel(u8),
+    Effect1(f32),
+    Effect2(f32),
     AmpegEnvelope(SfzAmpegEnvelope),
+    PitchegEnvelope(SfzPitchegEnvelope),
 }
 
 #[derive(Debug, Clone)]
@@ -59,6 +68,20 @@ pub enum SfzAmpegEnvelope {
     AmpegSustain(f32),
     AmpegRelease(f32),
     AmpegVel2Release(f32),
+    AmpegOffVeltrack(f32),
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum SfzPitchegEnvelope {
+    PitchegStart(f32),
+    PitchegDelay(f32),
+    PitchegAttack(f32),
+    PitchegHold(f32),
+    PitchegDecay(f32),
+    PitchegSustain(f32),
+    PitchegRelease(f32),
+    PitchegDepth(f32),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -77,14 +100,6 @@ pub enum SfzToken {
     Opcode(SfzOpcode),
 }
 
-#[derive(Debug, Clone)]
-pub enum SfzTokenWithMeta {
-    Group(SfzGroupType),
-    Opcode(SfzOpcode),
-    Import(String),
-    Define(String, String),
-}
-
 /// Parameters of an error generated while validating an SFZ file.
 #[derive(Error, Debug, Clone)]
 pub struct SfzValidationError {
@@ -116,6 +131,9 @@ pub enum SfzParseError {
 
     #[error("Failed to read file: {0}")]
     FailedToReadFile(PathBuf),
+
+    #[error("Sample file not found: {0}")]
+    MissingSample(PathBuf),
 }
 
 fn parse_key_number(val: &str) -> Option<i8> {
@@ -161,6 +179,16 @@ fn parse_key_number(val: &str) -> Option<i8> {
     }
 }
 
+/// Parses a key number which may be fractional (e.g. `60.5`), for
+/// microtonal key centers. Note names are parsed as whole keys.
+fn parse_fractional_key_number(val: &str) -> Option<f32> {
+    if val.contains('.') {
+        parse_float_in_range(val, -1.0..=127.0)
+    } else {
+        parse_key_number(val).map(f32::from)
+    }
+}
+
 fn parse_u8_in_range(val: &str, range: RangeInclusive<u8>) -> Option<u8> {
     val.parse()
         .ok()
@@ -217,40 +245,22 @@ fn parse_loop_mode(val: &str) -> Option<LoopMode> {
     }
 }
 
-fn parse_sfz_opcode(
-    opcode: Opcode,
-    defines: &RefCell<HashMap<String, String>>,
-) -> Result<Option<SfzOpcode>, SfzValidationError> {
-    let name = opcode.name.name.text;
-    let mut name = Cow::Borrowed(name.trim());
-
-    let val = opcode.value.as_string();
-    let mut val = Cow::Borrowed(val.trim());
-
-    for (key, replace) in defines.borrow().iter() {
-        if val.contains(key) {
-            val = Cow::Owned(val.replace(key, replace));
-        }
-        if name.contains(key) {
-            name = Cow::Owned(name.replace(key, replace));
-        }
-    }
-
+/// Parses the value of an opcode. Returns `None` for unsupported opcodes
+/// and invalid values.
+fn parse_sfz_opcode(name: &str, val: &str) -> Option<SfzOpcode> {
     use SfzAmpegEnvelope::*;
     use SfzOpcode::*;
+    use SfzPitchegEnvelope::*;
 
-    let val = val.as_ref();
-    let name = name.as_ref();
-
-    Ok(match name {
+    match name {
         "lokey" => parse_key_number(val).map(Lokey),
         "hikey" => parse_key_number(val).map(Hikey),
-        "lovel" => parse_u8_in_range(val, 0..=128).map(Lovel),
-        "hivel" => parse_u8_in_range(val, 0..=128).map(Hivel),
+        "lovel" => parse_u8_in_range(val, 0..=127).map(Lovel),
+        "hivel" => parse_u8_in_range(val, 0..=127).map(Hivel),
         "volume" => parse_i16_in_range(val, -144..=6).map(Volume),
         "pan" => parse_i8_in_range(val, -100..=100).map(Pan),
-        "pitch_keycenter" => parse_key_number(val).map(PitchKeycenter),
-        "key" => parse_key_number(val).map(Key),
+        "pitch_keycenter" => parse_fractional_key_number(val).map(PitchKeycenter),
+        "key" => parse_fractional_key_number(val).map(Key),
         "cutoff" => parse_float_in_range(val, 1.0..=100000.0).map(Cutoff),
         "resonance" => parse_float_in_range(val, 0.0..=40.0).map(Resonance),
         "amp_keycenter" => parse_key_number(val).map(AmpKeycenter),
@@ -267,8 +277,22 @@ fn parse_sfz_opcode(
         "loop_start" | "loopstart" => parse_u32_in_range(val, 0..=u32::MAX).map(LoopStart),
         "loop_end" | "loopend" => parse_u32_in_range(val, 0..=u32::MAX).map(LoopEnd),
         "offset" => parse_u32_in_range(val, 0..=u32::MAX).map(Offset),
+        "offset_random" => parse_u32_in_range(val, 0..=u32::MAX).map(OffsetRandom),
         "default_path" => Some(DefaultPath(val.replace('\\', "/"))),
         "tune" => parse_i16_in_range(val, -2400..=2400).map(Tune),
+        "transpose" => parse_i8_in_range(val, -127..=127).map(Transpose),
+        "note_offset" => parse_i8_in_range(val, -127..=127).map(NoteOffset),
+        "pitch_veltrack" => parse_i16_in_range(val, -9600..=9600).map(PitchVeltrack),
+        "pitch_random" => parse_float_in_range(val, 0.0..=9600.0).map(PitchRandom),
+        "amp_random" => parse_float_in_range(val, 0.0..=24.0).map(AmpRandom),
+        "seq_length" => parse_u8_in_range(val, 1..=100).map(SeqLength),
+        "seq_position" => parse_u8_in_range(val, 1..=100).map(SeqPosition),
+        "xfin_lovel" => parse_u8_in_range(val, 0..=127).map(XfinLovel),
+        "xfin_hivel" => parse_u8_in_range(val, 0..=127).map(XfinHivel),
+        "xfout_lovel" => parse_u8_in_range(val, 0..=127).map(XfoutLovel),
+        "xfout_hivel" => parse_u8_in_range(val, 0..=127).map(XfoutHivel),
+        "effect1" => parse_float_in_range(val, 0.0..=100.0).map(Effect1),
+        "effect2" => parse_float_in_range(val, 0.0..=100.0).map(Effect2),
 
         "ampeg_delay" => parse_float_in_range(val, 0.0..=100.0)
             .map(AmpegDelay)
@@ -294,138 +318,81 @@ fn parse_sfz_opcode(
         "ampeg_vel2release" => parse_float_in_range(val, -100.0..=100.0)
             .map(AmpegVel2Release)
             .map(AmpegEnvelope),
+        "ampeg_offveltrack" => parse_float_in_range(val, -100.0..=100.0)
+            .map(AmpegOffVeltrack)
+            .map(AmpegEnvelope),
+
+        "pitcheg_start" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegStart)
+            .map(PitchegEnvelope),
+        "pitcheg_delay" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegDelay)
+            .map(PitchegEnvelope),
+        "pitcheg_attack" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegAttack)
+            .map(PitchegEnvelope),
+        "pitcheg_hold" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegHold)
+            .map(PitchegEnvelope),
+        "pitcheg_decay" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegDecay)
+            .map(PitchegEnvelope),
+        "pitcheg_sustain" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegSustain)
+            .map(PitchegEnvelope),
+        "pitcheg_release" => parse_float_in_range(val, 0.0..=100.0)
+            .map(PitchegRelease)
+            .map(PitchegEnvelope),
+        "pitcheg_depth" => parse_float_in_range(val, -12000.0..=12000.0)
+            .map(PitchegDepth)
+            .map(PitchegEnvelope),
 
         "sample" => Some(Sample(val.replace('\\', "/"))),
 
-        _ => None,
-    })
+        _ => {
+            if let Some(vel) = name.strip_prefix("amp_velcurve_") {
+                parse_u8_in_range(vel, 0..=127).and_then(|vel| {
+                    parse_float_in_range(val, 0.0..=1.0).map(|val| AmpVelcurve(vel, val))
+                })
+            } else {
+                None
+            }
+        }
+    }
 }
 
-fn parse_sfz_group(group: Group) -> Result<SfzGroupType, SfzValidationError> {
-    Ok(match group.name.text {
+fn parse_sfz_group(name: &str) -> SfzGroupType {
+    match name {
         "region" => SfzGroupType::Region,
         "group" => SfzGroupType::Group,
         "master" => SfzGroupType::Master,
         "global" => SfzGroupType::Global,
         "control" => SfzGroupType::Control,
         _ => SfzGroupType::Other,
-    })
-}
-
-fn grammar_token_into_sfz_token(
-    token: Token,
-    defines: &RefCell<HashMap<String, String>>,
-) -> Result<Option<SfzTokenWithMeta>, SfzValidationError> {
-    match token.kind {
-        TokenKind::Comment(_) => Ok(None),
-        TokenKind::Group(group_type) => {
-            Ok(Some(SfzTokenWithMeta::Group(parse_sfz_group(group_type)?)))
-        }
-        TokenKind::Opcode(opcode) => {
-            Ok(parse_sfz_opcode(opcode, defines)?.map(SfzTokenWithMeta::Opcode))
-        }
-        TokenKind::Include(include) => Ok(Some(SfzTokenWithMeta::Import(
-            include.path.text.replace('\\', "/"),
-        ))),
-        TokenKind::Define(define) => {
-            let variable = define.variable.text.to_owned();
-            let value = define.value.first.value.text.text.to_owned();
-            //defines.borrow_mut().insert(variable.clone(), value.clone());
-            Ok(Some(SfzTokenWithMeta::Define(variable, value)))
-        }
     }
 }
 
-pub fn parse_tokens_raw<'a>(
-    input: &'a str,
-    defines: &'a RefCell<HashMap<String, String>>,
-) -> impl 'a + Iterator<Item = Result<SfzTokenWithMeta, SfzParseError>> {
-    let iter = ErrorTolerantToken::parse_as_iter(input);
-
-    iter.filter_map(move |t| match t {
-        Ok(t) => match grammar_token_into_sfz_token(t, defines) {
-            Ok(Some(t)) => Some(Ok(t)),
-            Ok(None) => None,
-            Err(e) => Some(Err(SfzParseError::from(e))),
-        },
-        Err(e) => Some(Err(SfzParseError::from(e))),
-    })
-}
-
-fn parse_tokens_resolved_recursive(
-    instr_path: &Path,
-    file_path: &Path,
-    defines: &RefCell<HashMap<String, String>>,
-) -> Result<Vec<SfzToken>, SfzParseError> {
-    let file_path = file_path
-        .canonicalize()
-        .map_err(|_| SfzParseError::FailedToReadFile(file_path.to_owned()))?;
-
-    let f = File::open(&file_path)
-        .map_err(|_| SfzParseError::FailedToReadFile(file_path.to_owned()))?;
-
-    let mut reader = BufReader::new(
-        DecodeReaderBytesBuilder::new()
-            .encoding(Some(UTF_8))
-            .build(f),
-    );
-    let mut file = String::new();
-
-    reader.read_to_string(&mut file).unwrap();
-
-    // Unwrap here is safe because the path is confirmed to be a file (read above)
-    // and therefore it will always have a parent folder. The path is also canonicalized.
-    let parent_path = instr_path.parent().unwrap();
-
-    let mut tokens = Vec::new();
-
-    let iter = parse_tokens_raw(&file, defines);
-
-    let mut parsed_includes = HashMap::new();
-
-    for t in iter {
-        match t {
-            Ok(t) => match t {
-                SfzTokenWithMeta::Import(mut path) => {
-                    for (key, replace) in defines.borrow().iter() {
-                        if path.contains(key) {
-                            path = path.replace(key, replace);
-                        }
-                    }
-
-                    // Get the cached tokens for this current path, or parse them if they haven't been parsed yet
-                    let parsed_tokens = parsed_includes.entry(path.clone()).or_insert_with(|| {
-                        let full_path = parent_path.join(&path);
-                        parse_tokens_resolved_recursive(instr_path, &full_path, defines)
-                    });
-
-                    if let Ok(parsed_tokens) = parsed_tokens {
-                        tokens.extend_from_slice(parsed_tokens);
-                    } else {
-                        // If we recieved an error, then extact the owned error from the hashmap and return it
-                        return Err(parsed_includes.remove(&path).unwrap().unwrap_err());
-                    }
-                }
-                SfzTokenWithMeta::Group(group) => tokens.push(SfzToken::Group(group)),
-                SfzTokenWithMeta::Opcode(opcode) => tokens.push(SfzToken::Opcode(opcode)),
-                SfzTokenWithMeta::Define(variable, value) => {
-                    // We clear the include cache here so if the same file is included
-                    // it will use the new definition values
-                    parsed_includes.clear();
-
-                    defines
-                        .borrow_mut()
-                        .insert(variable.trim().to_owned(), value.trim().to_owned());
-                }
-            },
-            Err(e) => return Err(e),
-        }
-    }
-
-    Ok(tokens)
+/// Parses the tokens of a file and its includes, stopping at the first error.
+pub fn parse_tokens_resolved(file_path: &Path) -> Result<Vec<SfzToken>, SfzParseError> {
+    parse_tokens_resolved_lenient(file_path)
+        .into_iter()
+        .collect()
 }
 
-pub fn parse_tokens_resolved(file_path: &Path) -> Result<Vec<SfzToken>, SfzParseError> {
-    let defines = RefCell::new(HashMap::new());
-    parse_tokens_resolved_recursive(file_path, file_path, &defines)
+/// Parses the tokens of a file and its includes, with the errors in place
+/// of the tokens that failed.
+pub fn parse_tokens_resolved_lenient(file_path: &Path) -> Vec<Result<SfzToken, SfzParseError>> {
+    parse_file(file_path, IncludeMode::Resolve)
+        .into_iter()
+        .filter_map(|item| match item {
+            Ok(SfzAstItem::Header(header)) => {
+                Some(Ok(SfzToken::Group(parse_sfz_group(&header.name))))
+            }
+            Ok(SfzAstItem::Opcode(opcode)) => {
+                parse_sfz_opcode(&opcode.name, &opcode.value).map(|o| Ok(SfzToken::Opcode(o)))
+            }
+            Ok(SfzAstItem::Define { .. } | SfzAstItem::Include { .. }) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .collect()
 }